@@ -0,0 +1,110 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Adaptive sampling. Every pixel takes at least the samples per pixel of
+//! the renderer, and then keeps taking samples while its estimate is too
+//! noisy, so that flat areas stop early and the budget goes to noisy ones.
+
+use crate::film::Pixel;
+
+/// When pixels stop taking samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    /// Relative error at which a pixel is done: the half width of the 95%
+    /// confidence interval of its mean, over the mean. Pixels darker than 1
+    /// use an absolute error instead, so that black pixels can converge.
+    pub threshold: f64,
+    pub max_spp: u32, // Samples after which a pixel stops anyway
+    pub batch: u32,   // Samples taken between two convergence checks
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self {
+            threshold: 0.05,
+            max_spp: 1024,
+            batch: 8,
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn new(threshold: f64, max_spp: u32) -> Self {
+        Self {
+            threshold,
+            max_spp,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_converged(&self, pixel: &Pixel) -> bool {
+        if pixel.samples < 2 {
+            return false;
+        }
+        let mean = (pixel.sum / pixel.samples as f64).mean().abs();
+        pixel.error() <= self.threshold * mean.max(1.0)
+    }
+
+    /// Whether a pixel that took `sample` samples, out of a minimum of
+    /// `min_spp`, should stop
+    pub fn is_done(&self, pixel: &Pixel, sample: u32, min_spp: u32) -> bool {
+        if sample < min_spp {
+            return false;
+        }
+        sample >= self.max_spp.max(min_spp)
+            || ((sample - min_spp).is_multiple_of(self.batch.max(1)) && self.is_converged(pixel))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Color;
+    use crate::film::NonFinitePolicy;
+
+    #[test]
+    fn convergence() {
+        let adaptive = AdaptiveSampling {
+            threshold: 0.1,
+            max_spp: 64,
+            batch: 4,
+        };
+        let mut flat = Pixel::default();
+        let mut noisy = Pixel::default();
+        for sample in 0..16 {
+            flat.add_sample(Color::repeat(100.0), NonFinitePolicy::Discard);
+            let value = if sample % 2 == 0 { 0.0 } else { 200.0 };
+            noisy.add_sample(Color::repeat(value), NonFinitePolicy::Discard);
+        }
+
+        assert!(adaptive.is_converged(&flat));
+        assert!(!adaptive.is_converged(&noisy));
+        assert!(!adaptive.is_done(&flat, 8, 16));
+        assert!(adaptive.is_done(&flat, 16, 16));
+        assert!(!adaptive.is_done(&flat, 18, 16));
+        assert!(!adaptive.is_done(&noisy, 20, 16));
+        assert!(adaptive.is_done(&noisy, 64, 16));
+
+        // Black pixels converge too
+        let mut black = Pixel::default();
+        black.add_sample(Color::zeros(), NonFinitePolicy::Discard);
+        black.add_sample(Color::zeros(), NonFinitePolicy::Discard);
+        assert!(adaptive.is_converged(&black));
+    }
+}
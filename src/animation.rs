@@ -0,0 +1,676 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Camera and object animation given by keyframes, e.g. exported from a DCC
+//! tool.
+//!
+//! Camera paths can be read from and written to two formats:
+//!
+//! - A JSON list of keyframes:
+//!   `[{ "time": 0, "position": [0, 1, 5], "rotation": [1, 0, 0, 0], "fov": 40 }]`.
+//!   `rotation` is a `[w, x, y, z]` quaternion. It can be replaced by a
+//!   `direction` and a `roll` in degrees. `fov` is the vertical field of view
+//!   in degrees, `horizontal_fov` the horizontal one.
+//! - Nuke `.chan` files, one line per frame: `frame tx ty tz rx ry rz [vfov]`
+//!   with Euler angles in degrees applied in X, Y, Z order. Blender exports
+//!   this format through its Nuke chan add-on.
+//!
+//! Cameras look down their local -z axis with +y up, as in both tools.
+//!
+//! Objects of a scene file are animated with a `keyframes` list in the same
+//! JSON format, where each key has a `time` and optionally a `position`, a
+//! `rotation` quaternion and a uniform `scale`.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::camera::{Camera, CameraConfig, FieldOfView};
+use crate::loader::{field, parse_f64, parse_vec3};
+use crate::shape::{Instance, Shape, Transform};
+
+/// Camera pose and field of view at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraKey {
+    pub time: f64, // Seconds
+    pub position: glm::DVec3,
+    pub rotation: glm::DQuat,     // Camera to world
+    pub fov: Option<FieldOfView>, // Keeps the field of view of the camera if None
+}
+
+impl CameraKey {
+    pub fn new(time: f64, position: glm::DVec3, rotation: glm::DQuat) -> Self {
+        Self {
+            time,
+            position,
+            rotation: glm::quat_normalize(&rotation),
+            fov: None,
+        }
+    }
+
+    /// Key of a camera looking along `direction` and rotated by `roll`
+    /// around it, as given to a `CameraConfig`
+    pub fn looking(time: f64, position: glm::DVec3, direction: glm::DVec3, roll: f64) -> Self {
+        let (u, v, w) = Camera::new(&CameraConfig {
+            position,
+            direction,
+            rotation: roll,
+            ..Default::default()
+        })
+        .basis();
+        let basis = glm::DMat3::from_columns(&[u, v, -w]);
+        Self::new(time, position, glm::mat3_to_quat(&basis))
+    }
+
+    pub fn direction(&self) -> glm::DVec3 {
+        glm::quat_rotate_vec3(&self.rotation, &-glm::DVec3::z())
+    }
+
+    pub fn up(&self) -> glm::DVec3 {
+        glm::quat_rotate_vec3(&self.rotation, &glm::DVec3::y())
+    }
+
+    /// Rotation around the viewing direction, see `CameraConfig::rotation`
+    pub fn roll(&self) -> f64 {
+        let (u, v, _) = Camera::new(&CameraConfig {
+            direction: self.direction(),
+            ..Default::default()
+        })
+        .basis();
+        let up = self.up();
+        up.dot(&u).atan2(up.dot(&v))
+    }
+
+    /// Configuration of a camera at this key. The rest of the settings, like
+    /// the resolution, are taken from `base`.
+    pub fn camera_config(&self, base: &CameraConfig) -> CameraConfig {
+        CameraConfig {
+            position: self.position,
+            direction: self.direction(),
+            // The roll is measured from the default up
+            up: CameraConfig::default().up,
+            rotation: self.roll(),
+            fov: self.fov.unwrap_or(base.fov),
+            ..base.clone()
+        }
+    }
+}
+
+/// Keyframes of a camera, sorted by time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraPath {
+    keys: Vec<CameraKey>,
+}
+
+impl CameraPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a key, replacing the one at the same time if there is one
+    pub fn add_key(&mut self, key: CameraKey) -> &mut Self {
+        insert_key(&mut self.keys, key, |key| key.time);
+        self
+    }
+
+    pub fn keys(&self) -> &[CameraKey] {
+        &self.keys
+    }
+
+    /// Times of the first and last keys
+    pub fn time_range(&self) -> Option<(f64, f64)> {
+        Some((self.keys.first()?.time, self.keys.last()?.time))
+    }
+
+    /// Camera at a time. Positions are interpolated linearly, rotations
+    /// along the shortest arc and fields of view linearly if both keys have
+    /// the same kind. Before the first key and after the last one the camera
+    /// holds still.
+    pub fn sample(&self, time: f64) -> Option<CameraKey> {
+        let (a, b) = match surrounding_keys(&self.keys, time, |key| key.time) {
+            Ok(keys) => keys,
+            Err(key) => return key.copied(),
+        };
+
+        let t = (time - a.time) / (b.time - a.time);
+        let lerp = |x: f64, y: f64| x + t * (y - x);
+        let fov = match (a.fov, b.fov) {
+            (Some(FieldOfView::Vertical(x)), Some(FieldOfView::Vertical(y))) => {
+                Some(FieldOfView::Vertical(lerp(x, y)))
+            }
+            (Some(FieldOfView::Horizontal(x)), Some(FieldOfView::Horizontal(y))) => {
+                Some(FieldOfView::Horizontal(lerp(x, y)))
+            }
+            _ if t < 0.5 => a.fov,
+            _ => b.fov,
+        };
+        Some(CameraKey {
+            time,
+            position: glm::lerp(&a.position, &b.position, t),
+            rotation: slerp(&a.rotation, &b.rotation, t),
+            fov,
+        })
+    }
+
+    /// Times of the frames from the first key to the last one
+    pub fn frame_times(&self, fps: f64) -> Vec<f64> {
+        let Some((start, end)) = self.time_range() else {
+            return Vec::new();
+        };
+        let frames = ((end - start) * fps + 1e-9).floor() as usize + 1;
+        (0..frames).map(|n| start + n as f64 / fps).collect()
+    }
+
+    /// Cameras of the frames from the first key to the last one, with the
+    /// rest of their settings taken from `base`
+    pub fn camera_configs(&self, fps: f64, base: &CameraConfig) -> Vec<CameraConfig> {
+        self.frame_times(fps)
+            .into_iter()
+            .filter_map(|time| self.sample(time))
+            .map(|key| key.camera_config(base))
+            .collect()
+    }
+
+    /// Camera of the frame at `time`, with the rest of its settings taken
+    /// from `base`. It follows the path while the shutter of `base` is open,
+    /// blurring its motion.
+    pub fn camera(&self, time: f64, base: &CameraConfig) -> Option<Camera> {
+        let shutter = base.shutter;
+        let mut camera = Camera::new(&self.sample(time + shutter.open)?.camera_config(base));
+        if shutter.duration() > 0.0 {
+            let close = self.sample(time + shutter.close)?.camera_config(base);
+            camera.set_motion(&close).ok()?;
+        }
+        Some(camera)
+    }
+
+    /// Read a camera path, in the Nuke chan format if the file has a `.chan`
+    /// extension and as a JSON list of keyframes otherwise. Frames of chan
+    /// files are converted to times with `fps`.
+    pub fn load<P: AsRef<Path>>(path: P, fps: f64) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        let result = if is_chan(path) {
+            Self::parse_chan(&source, fps)
+        } else {
+            serde_json::from_str(&source)
+                .map_err(|e| e.to_string())
+                .and_then(|value| Self::from_json(&value))
+        };
+        result.map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Write a camera path in the format given by the extension of the file,
+    /// as `load` reads it
+    pub fn save<P: AsRef<Path>>(&self, path: P, fps: f64) -> Result<(), String> {
+        let path = path.as_ref();
+        let source = if is_chan(path) {
+            self.to_chan(fps)
+        } else {
+            serde_json::to_string_pretty(&self.to_json()).map_err(|e| e.to_string())? + "\n"
+        };
+        fs::write(path, source).map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let mut path = Self::new();
+        let keys = value.as_array().ok_or("A camera path must be an array")?;
+        for (n, key) in keys.iter().enumerate() {
+            path.add_key(parse_key(key).map_err(|e| format!("Key {n}: {e}"))?);
+        }
+        Ok(path)
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.keys
+            .iter()
+            .map(|key| {
+                let q = key.rotation;
+                let mut value = json!({
+                    "time": key.time,
+                    "position": [key.position.x, key.position.y, key.position.z],
+                    "rotation": [q.w, q.i, q.j, q.k],
+                });
+                match key.fov {
+                    Some(FieldOfView::Vertical(fov)) => value["fov"] = fov.to_degrees().into(),
+                    Some(FieldOfView::Horizontal(fov)) => {
+                        value["horizontal_fov"] = fov.to_degrees().into()
+                    }
+                    None => {}
+                }
+                value
+            })
+            .collect()
+    }
+
+    pub fn parse_chan(source: &str, fps: f64) -> Result<Self, String> {
+        let mut path = Self::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let values = line
+                .split_whitespace()
+                .map(|value| value.parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Line {}: {e}", number + 1))?;
+            if values.len() < 7 {
+                return Err(format!(
+                    "Line {}: expected a frame, a position and a rotation",
+                    number + 1
+                ));
+            }
+
+            let angle = |n: usize| values[n].to_radians();
+            let rotation = glm::quat_angle_axis(angle(6), &glm::DVec3::z())
+                * glm::quat_angle_axis(angle(5), &glm::DVec3::y())
+                * glm::quat_angle_axis(angle(4), &glm::DVec3::x());
+            let position = glm::DVec3::new(values[1], values[2], values[3]);
+            let mut key = CameraKey::new(values[0] / fps, position, rotation);
+            key.fov = values
+                .get(7)
+                .map(|fov| FieldOfView::Vertical(fov.to_radians()));
+            path.add_key(key);
+        }
+        Ok(path)
+    }
+
+    /// Write the path as a chan file, one line per key. Horizontal fields of
+    /// view are left out since the format only has vertical ones.
+    pub fn to_chan(&self, fps: f64) -> String {
+        let mut source = String::new();
+        for key in &self.keys {
+            let m = glm::quat_to_mat3(&key.rotation);
+            // Inverse of R = Rz Ry Rx
+            let x = m[(2, 1)].atan2(m[(2, 2)]);
+            let y = (-m[(2, 0)]).clamp(-1.0, 1.0).asin();
+            let z = m[(1, 0)].atan2(m[(0, 0)]);
+            let p = key.position;
+            source += &format!(
+                "{} {} {} {} {} {} {}",
+                key.time * fps,
+                p.x,
+                p.y,
+                p.z,
+                x.to_degrees(),
+                y.to_degrees(),
+                z.to_degrees()
+            );
+            if let Some(FieldOfView::Vertical(fov)) = key.fov {
+                source += &format!(" {}", fov.to_degrees());
+            }
+            source.push('\n');
+        }
+        source
+    }
+}
+
+/// Position, rotation and scale of an object at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjectKey {
+    pub time: f64, // Seconds
+    pub transform: Transform,
+}
+
+/// Keyframes of an object, sorted by time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectPath {
+    keys: Vec<ObjectKey>,
+}
+
+impl ObjectPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a key, replacing the one at the same time if there is one
+    pub fn add_key(&mut self, key: ObjectKey) -> &mut Self {
+        insert_key(&mut self.keys, key, |key| key.time);
+        self
+    }
+
+    pub fn keys(&self) -> &[ObjectKey] {
+        &self.keys
+    }
+
+    /// Transform at a time, interpolated as in `CameraPath::sample`. The
+    /// scale is interpolated linearly.
+    pub fn sample(&self, time: f64) -> Option<Transform> {
+        let (a, b) = match surrounding_keys(&self.keys, time, |key| key.time) {
+            Ok(keys) => keys,
+            Err(key) => return key.map(|key| key.transform),
+        };
+
+        let t = (time - a.time) / (b.time - a.time);
+        let (a, b) = (&a.transform, &b.transform);
+        Some(Transform {
+            translation: glm::lerp(&a.translation, &b.translation, t),
+            rotation: slerp(&a.rotation, &b.rotation, t),
+            scale: a.scale + t * (b.scale - a.scale),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let mut path = Self::new();
+        let keys = value.as_array().ok_or("Keyframes must be an array")?;
+        for (n, key) in keys.iter().enumerate() {
+            path.add_key(parse_object_key(key).map_err(|e| format!("Key {n}: {e}"))?);
+        }
+        Ok(path)
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.keys
+            .iter()
+            .map(|key| {
+                let (p, q) = (key.transform.translation, key.transform.rotation);
+                json!({
+                    "time": key.time,
+                    "position": [p.x, p.y, p.z],
+                    "rotation": [q.w, q.i, q.j, q.k],
+                    "scale": key.transform.scale,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Object of a scene moved by keyframes. Its shape is loaded once and shared
+/// by the instances that place it at each frame.
+pub struct ObjectAnimation {
+    pub object: usize, // Index into `Scene::objects`
+    pub shape: Arc<dyn Shape + Send + Sync>,
+    pub path: ObjectPath,
+    current: Option<Transform>, // Transform of the last instance
+}
+
+impl ObjectAnimation {
+    pub fn new(object: usize, shape: Arc<dyn Shape + Send + Sync>, path: ObjectPath) -> Self {
+        Self {
+            object,
+            shape,
+            path,
+            current: None,
+        }
+    }
+
+    /// Instance of the shape at a time, or None if it hasn't moved since the
+    /// last instance
+    pub fn update(&mut self, time: f64) -> Option<Instance> {
+        let transform = self.path.sample(time)?;
+        if self.current == Some(transform) {
+            return None;
+        }
+        self.current = Some(transform);
+        Some(Instance::new(Arc::clone(&self.shape), transform))
+    }
+}
+
+/// Insert a key in a list sorted by time, replacing the one at the same time
+fn insert_key<K>(keys: &mut Vec<K>, key: K, time: impl Fn(&K) -> f64) {
+    let index = keys.partition_point(|other| time(other) < time(&key));
+    match keys.get_mut(index) {
+        Some(other) if time(other) == time(&key) => *other = key,
+        _ => keys.insert(index, key),
+    }
+}
+
+/// Keys before and after a time, or the key to hold if the time is outside
+/// of them
+fn surrounding_keys<K>(
+    keys: &[K],
+    time: f64,
+    key_time: impl Fn(&K) -> f64,
+) -> Result<(&K, &K), Option<&K>> {
+    let next = keys.partition_point(|key| key_time(key) <= time);
+    match (next.checked_sub(1), keys.get(next)) {
+        (Some(previous), Some(b)) => Ok((&keys[previous], b)),
+        (Some(previous), None) => Err(Some(&keys[previous])),
+        (None, b) => Err(b),
+    }
+}
+
+/// Interpolate rotations along the shortest arc
+fn slerp(a: &glm::DQuat, b: &glm::DQuat, t: f64) -> glm::DQuat {
+    // Quaternions q and -q are the same rotation, take the closest one
+    let b = if glm::quat_dot(a, b) < 0.0 { -b } else { *b };
+    glm::quat_slerp(a, &b, t)
+}
+
+fn is_chan(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "chan")
+}
+
+fn parse_key(value: &Value) -> Result<CameraKey, String> {
+    let time = parse_f64(field(value, "time")?)?;
+    let position = parse_vec3(field(value, "position")?)?;
+    let mut key = match value.get("rotation") {
+        Some(rotation) => CameraKey::new(time, position, parse_rotation(rotation)?),
+        None => {
+            let direction = parse_vec3(field(value, "direction")?)?;
+            if direction.norm() == 0.0 {
+                return Err("'direction' can't be zero".to_string());
+            }
+            let roll = value.get("roll").map_or(Ok(0.0), parse_f64)?;
+            CameraKey::looking(time, position, direction, roll.to_radians())
+        }
+    };
+    if let Some(fov) = value.get("fov") {
+        key.fov = Some(FieldOfView::Vertical(parse_f64(fov)?.to_radians()));
+    } else if let Some(fov) = value.get("horizontal_fov") {
+        key.fov = Some(FieldOfView::Horizontal(parse_f64(fov)?.to_radians()));
+    }
+    Ok(key)
+}
+
+fn parse_object_key(value: &Value) -> Result<ObjectKey, String> {
+    let default = Transform::default();
+    let transform = Transform {
+        translation: value
+            .get("position")
+            .map_or(Ok(default.translation), parse_vec3)?,
+        rotation: value
+            .get("rotation")
+            .map_or(Ok(default.rotation), parse_rotation)?,
+        scale: value.get("scale").map_or(Ok(default.scale), parse_f64)?,
+    };
+    if transform.scale <= 0.0 {
+        return Err("'scale' must be positive".to_string());
+    }
+    Ok(ObjectKey {
+        time: parse_f64(field(value, "time")?)?,
+        transform,
+    })
+}
+
+/// Normalized rotation from a `[w, x, y, z]` quaternion
+fn parse_rotation(value: &Value) -> Result<glm::DQuat, String> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([w, x, y, z]) => {
+            let q = glm::DQuat::new(parse_f64(w)?, parse_f64(x)?, parse_f64(y)?, parse_f64(z)?);
+            if glm::quat_length(&q) == 0.0 {
+                return Err("'rotation' can't be zero".to_string());
+            }
+            Ok(glm::quat_normalize(&q))
+        }
+        _ => Err("'rotation' must be a [w, x, y, z] quaternion".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::Shutter;
+
+    #[cfg(feature = "glam")]
+    #[test]
+    fn key_from_glam() {
+        let position = glam::DVec3::new(1.0, 2.0, 3.0);
+        let rotation = glam::DQuat::from_rotation_y(std::f64::consts::FRAC_PI_2);
+        let key = CameraKey::new(0.0, position.into(), rotation.into());
+        assert_relative_eq!(-glm::DVec3::x(), key.direction(), epsilon = 1e-12);
+        assert_eq!(position, key.position.into());
+    }
+
+    #[test]
+    fn key_matches_camera_config() {
+        let direction = glm::DVec3::new(1.0, -0.5, 2.0).normalize();
+        let key = CameraKey::looking(0.0, glm::DVec3::zeros(), direction, 0.3);
+        assert_relative_eq!(direction, key.direction(), epsilon = 1e-12);
+        assert_relative_eq!(0.3, key.roll(), epsilon = 1e-12);
+
+        let config = key.camera_config(&CameraConfig::default());
+        let expected = Camera::new(&CameraConfig {
+            direction,
+            rotation: 0.3,
+            ..Default::default()
+        });
+        let (u, v, w) = Camera::new(&config).basis();
+        let (eu, ev, ew) = expected.basis();
+        assert_relative_eq!(eu, u, epsilon = 1e-12);
+        assert_relative_eq!(ev, v, epsilon = 1e-12);
+        assert_relative_eq!(ew, w, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn interpolate_keys() {
+        let mut path = CameraPath::new();
+        let mut first = CameraKey::looking(1.0, glm::DVec3::zeros(), -glm::DVec3::z(), 0.0);
+        first.fov = Some(FieldOfView::Vertical(0.5));
+        let mut last =
+            CameraKey::looking(3.0, glm::DVec3::new(2.0, 0.0, 0.0), glm::DVec3::x(), 0.0);
+        last.fov = Some(FieldOfView::Vertical(1.0));
+        path.add_key(last).add_key(first);
+
+        let middle = path.sample(2.0).unwrap();
+        assert_relative_eq!(glm::DVec3::new(1.0, 0.0, 0.0), middle.position);
+        let diagonal = glm::DVec3::new(1.0, 0.0, -1.0).normalize();
+        assert_relative_eq!(diagonal, middle.direction(), epsilon = 1e-12);
+        assert_relative_eq!(glm::DVec3::y(), middle.up(), epsilon = 1e-12);
+        assert_eq!(Some(FieldOfView::Vertical(0.75)), middle.fov);
+
+        // The camera holds still outside the keys
+        assert_eq!(first.position, path.sample(0.0).unwrap().position);
+        assert_eq!(last.position, path.sample(4.0).unwrap().position);
+        assert_eq!(vec![1.0, 1.5, 2.0, 2.5, 3.0], path.frame_times(2.0));
+    }
+
+    #[test]
+    fn blur_along_path() {
+        let mut path = CameraPath::new();
+        path.add_key(CameraKey::looking(
+            0.0,
+            glm::DVec3::zeros(),
+            -glm::DVec3::z(),
+            0.0,
+        ))
+        .add_key(CameraKey::looking(
+            1.0,
+            2.0 * glm::DVec3::x(),
+            -glm::DVec3::z(),
+            0.0,
+        ));
+        let base = CameraConfig {
+            resolution: (8, 8),
+            shutter: Shutter::new(0.0, 0.5).unwrap(),
+            ..Default::default()
+        };
+
+        // Each ray leaves from where the camera is at its time
+        let camera = path.camera(0.25, &base).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let ray = camera.cast_ray(4, 4, &mut rng).unwrap();
+            let expected = glm::DVec3::new(0.5 + 2.0 * ray.time, 0.0, 0.0);
+            assert_relative_eq!(expected, ray.origin, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn chan_and_json_round_trip() {
+        let source = "1 0 1 5 -10 30 5 40\n2 1 2 3 20 -45 0 35\n";
+        let path = CameraPath::parse_chan(source, 24.0).unwrap();
+        assert_eq!(2, path.keys().len());
+        assert_relative_eq!(1.0 / 24.0, path.keys()[0].time);
+
+        // A pure yaw turns the camera around the vertical axis
+        let yaw = CameraPath::parse_chan("0 0 0 0 0 90 0", 24.0).unwrap();
+        assert_relative_eq!(-glm::DVec3::x(), yaw.keys()[0].direction(), epsilon = 1e-12);
+
+        for reloaded in [
+            CameraPath::parse_chan(&path.to_chan(24.0), 24.0).unwrap(),
+            CameraPath::from_json(&path.to_json()).unwrap(),
+        ] {
+            for (key, other) in path.keys().iter().zip(reloaded.keys()) {
+                assert_relative_eq!(key.time, other.time, epsilon = 1e-12);
+                assert_relative_eq!(key.position, other.position, epsilon = 1e-12);
+                assert_relative_eq!(key.direction(), other.direction(), epsilon = 1e-9);
+                assert_relative_eq!(key.up(), other.up(), epsilon = 1e-9);
+                assert_eq!(key.fov.is_some(), other.fov.is_some());
+            }
+        }
+
+        let looking = CameraPath::from_json(&json!([
+            { "time": 0, "position": [0, 0, 0], "direction": [0, 0, 1], "roll": 90 }
+        ]))
+        .unwrap();
+        assert_relative_eq!(
+            glm::DVec3::z(),
+            looking.keys()[0].direction(),
+            epsilon = 1e-12
+        );
+        assert!(CameraPath::from_json(&json!([{ "time": 0 }])).is_err());
+    }
+
+    #[test]
+    fn object_keys() {
+        let path = ObjectPath::from_json(&json!([
+            { "time": 2, "position": [4, 0, 0], "rotation": [0, 0, 1, 0], "scale": 3 },
+            { "time": 0 }
+        ]))
+        .unwrap();
+        assert_eq!(Some(Transform::default()), path.sample(-1.0));
+        let middle = path.sample(1.0).unwrap();
+        assert_relative_eq!(glm::DVec3::new(2.0, 0.0, 0.0), middle.translation);
+        assert_relative_eq!(2.0, middle.scale);
+        assert_relative_eq!(
+            glm::DVec3::new(0.0, 0.0, -1.0),
+            middle.direction(&glm::DVec3::x()),
+            epsilon = 1e-12
+        );
+        assert_eq!(path, ObjectPath::from_json(&path.to_json()).unwrap());
+        assert!(ObjectPath::from_json(&json!([{ "time": 0, "scale": 0 }])).is_err());
+
+        // Instances are only rebuilt when the object moves
+        let sphere = Arc::new(crate::shape::Sphere::new(glm::DVec3::zeros(), 1.0));
+        let mut animation = ObjectAnimation::new(0, sphere, path);
+        assert!(animation.update(0.0).is_some());
+        assert!(animation.update(-1.0).is_none());
+        assert!(animation.update(1.0).is_some());
+        assert!(animation.update(3.0).is_some());
+        assert!(animation.update(4.0).is_none());
+    }
+}
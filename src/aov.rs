@@ -0,0 +1,677 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Arbitrary output variables computed from the geometry at the first hit of
+//! the camera rays: ambient occlusion, curvature and edges. They are meant
+//! for compositing, e.g. dirt in cavities, wear on edges or line art, and as
+//! guides for a denoiser. The edges also make changes in the geometry easy to
+//! spot when comparing renders.
+//!
+//! The world normal, depth and albedo are cheap enough to be collected by the
+//! path tracer from the camera samples of the beauty render, see
+//! `PathTracer::render_buffers`.
+
+use bumpalo::Bump;
+use image::RgbImage;
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::bevel::probe_neighbours;
+use crate::camera::{Camera, DepthMap};
+use crate::color::Color;
+use crate::film::{Film, RawImage};
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::object::Object;
+use crate::render::{get_closest_hit, SURFACE_OFFSET};
+use crate::sampling::{cosine_hemisphere, to_world};
+use crate::scene::Scene;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Fraction of the hemisphere above the hit that is open, 1 for no
+    /// occlusion
+    AmbientOcclusion,
+    /// Convexity of the surface around the hit, from -1 for concave to 1 for
+    /// convex, 0 for flat
+    Curvature,
+    /// Silhouettes and creases, found on the depth and normal of the first
+    /// hits: 1 on the lines and 0 elsewhere
+    Edges,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AovSettings {
+    pub samples_per_pixel: u32,
+    pub occlusion_distance: f64, // Occluders further away are ignored
+    pub occlusion_rays: u32,
+    pub curvature_radius: f64, // Size of the neighbourhood the curvature is measured on
+    pub curvature_probes: u32,
+    pub edge_depth_threshold: f64, // Relative jump in depth that makes a silhouette
+    pub crease_angle: f64,         // [rad] Turn of the normal that makes a crease
+}
+
+impl Default for AovSettings {
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 4,
+            occlusion_distance: 1.0,
+            occlusion_rays: 16,
+            curvature_radius: 0.1,
+            curvature_probes: 16,
+            edge_depth_threshold: 0.1,
+            crease_angle: 30.0_f64.to_radians(),
+        }
+    }
+}
+
+/// Values of an AOV, in scanline order. Pixels that see the background are
+/// left at 1 for the ambient occlusion and 0 for the curvature and edges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AovBuffer {
+    pub aov: Aov,
+    width: u32,
+    height: u32,
+    values: Vec<f64>,
+}
+
+impl AovBuffer {
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn value(&self, i: u32, j: u32) -> f64 {
+        self.values[(j * self.width + i) as usize]
+    }
+
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    /// Grayscale image of the AOV. The curvature is shifted so that flat
+    /// surfaces are middle gray, convex ones brighter and concave ones darker.
+    pub fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |i, j| {
+            let value = match self.aov {
+                Aov::AmbientOcclusion | Aov::Edges => self.value(i, j),
+                Aov::Curvature => 0.5 + 0.5 * self.value(i, j),
+            };
+            let gray = (255.0 * value.clamp(0.0, 1.0)).round() as u8;
+            image::Rgb([gray, gray, gray])
+        })
+    }
+}
+
+impl AovSettings {
+    /// Render an AOV, averaging `samples_per_pixel` jittered camera rays
+    pub fn render(&self, scene: &Scene, camera: &Camera, aov: Aov) -> AovBuffer {
+        let (w, h) = camera.resolution();
+        let samples = self.samples_per_pixel.max(1);
+        let background = match aov {
+            Aov::AmbientOcclusion => 1.0,
+            Aov::Curvature | Aov::Edges => 0.0,
+        };
+        if aov == Aov::Edges {
+            let pixels: Vec<AovPixel> = (0..w * h)
+                .into_par_iter()
+                .map(|index| {
+                    let mut rng = rand::thread_rng();
+                    let mut pixel = AovPixel::default();
+                    for _ in 0..samples {
+                        let offset = (rng.gen::<f64>() - 0.5, rng.gen::<f64>() - 0.5);
+                        let ray = camera
+                            .cast_ray_jittered(index % w, index / w, offset, &mut rng)
+                            .expect("Expected a Ray");
+                        pixel.add_sample(scene, camera, &ray);
+                    }
+                    pixel
+                })
+                .collect();
+            let depths = pixels.iter().map(AovPixel::depth).collect();
+            let depth = DepthMap::new(w, h, depths).expect("One depth per pixel");
+            let normal = aov_image(w, h, &pixels, |pixel| pixel.normal().into());
+            return self.edges(&depth, &normal);
+        }
+
+        let values = (0..w * h)
+            .into_par_iter()
+            .map_init(Bump::new, |scratch, index| {
+                scratch.reset();
+                let mut rng = rand::thread_rng();
+                let (i, j) = (index % w, index / w);
+                let mut sum = 0.0;
+                for _ in 0..samples {
+                    let offset = (rng.gen::<f64>() - 0.5, rng.gen::<f64>() - 0.5);
+                    let ray = camera
+                        .cast_ray_jittered(i, j, offset, &mut rng)
+                        .expect("Expected a Ray");
+                    let Some((record, _, id)) = get_closest_hit(&scene.objects, &ray) else {
+                        sum += background;
+                        continue;
+                    };
+
+                    // Measure on the side of the surface the camera sees
+                    let mut ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+                    if ctx.normal.dot(&ray.direction) > 0.0 {
+                        ctx.normal = -ctx.normal;
+                    }
+                    sum += match aov {
+                        Aov::AmbientOcclusion => ambient_occlusion(
+                            &scene.objects,
+                            &ctx,
+                            self.occlusion_distance,
+                            self.occlusion_rays,
+                            &mut rng,
+                        ),
+                        Aov::Curvature => curvature(
+                            &scene.objects,
+                            &ctx,
+                            self.curvature_radius,
+                            self.curvature_probes,
+                            scratch,
+                            &mut rng,
+                        ),
+                        Aov::Edges => unreachable!("Edges are found on whole buffers"),
+                    };
+                }
+                sum / samples as f64
+            })
+            .collect();
+
+        AovBuffer {
+            aov,
+            width: w,
+            height: h,
+            values,
+        }
+    }
+
+    /// Line art of the silhouettes and creases in the depth and normal
+    /// buffers of a render, e.g. those of `RenderBuffers`, of the same
+    /// resolution. Silhouettes are drawn on the nearer side of jumps in
+    /// depth, and creases where the normal turns by more than the crease
+    /// angle from a pixel to the next one to its right or below.
+    pub fn edges(&self, depth: &DepthMap, normal: &RawImage) -> AovBuffer {
+        let (w, h) = depth.resolution();
+        // The inverse depth of a plane is affine across the image, so its
+        // second difference only picks up jumps and not slanted surfaces
+        let inverse_depth = |i: u32, j: u32| 1.0 / depth.depth(i, j);
+        let unit_normal = |i: u32, j: u32| {
+            glm::DVec3::from(normal.pixel(i, j).map(|c| c as f64)).try_normalize(1e-9)
+        };
+        let min_cos = self.crease_angle.cos();
+
+        let values = (0..w * h)
+            .into_par_iter()
+            .map(|index| {
+                let (i, j) = (index % w, index / w);
+                let center = inverse_depth(i, j);
+                if center == 0.0 {
+                    return 0.0; // Background
+                }
+
+                let horizontal =
+                    (i > 0 && i + 1 < w).then(|| inverse_depth(i - 1, j) + inverse_depth(i + 1, j));
+                let vertical =
+                    (j > 0 && j + 1 < h).then(|| inverse_depth(i, j - 1) + inverse_depth(i, j + 1));
+                let silhouette = [horizontal, vertical]
+                    .into_iter()
+                    .flatten()
+                    .any(|sum| (2.0 * center - sum) / center > self.edge_depth_threshold);
+
+                let crease = unit_normal(i, j).is_some_and(|n| {
+                    [(i + 1, j), (i, j + 1)]
+                        .into_iter()
+                        .filter(|&(x, y)| x < w && y < h)
+                        .filter_map(|(x, y)| unit_normal(x, y))
+                        .any(|neighbour| n.dot(&neighbour) < min_cos)
+                });
+
+                if silhouette || crease {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        AovBuffer {
+            aov: Aov::Edges,
+            width: w,
+            height: h,
+            values,
+        }
+    }
+}
+
+/// Sums of the first hits of the camera samples of a pixel
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct AovPixel {
+    normal: glm::DVec3, // Facing the camera
+    albedo: Color,      // The background color for samples that miss
+    depth: f64,         // Along the viewing axis
+    hits: u32,
+    samples: u32,
+}
+
+impl AovPixel {
+    /// Add the first hit of a camera ray
+    pub(crate) fn add_sample(&mut self, scene: &Scene, camera: &Camera, ray: &Ray) {
+        self.samples += 1;
+        let Some((record, object, id)) = get_closest_hit(&scene.objects, ray) else {
+            self.albedo += scene.background(&ray.direction);
+            return;
+        };
+        let ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+        self.albedo += object.material_at(record.material).albedo(&ctx);
+        self.normal += if ctx.normal.dot(&ray.direction) > 0.0 {
+            -ctx.normal
+        } else {
+            ctx.normal
+        };
+        self.depth += record.ray_t * ray.direction.dot(&camera.direction());
+        self.hits += 1;
+    }
+
+    /// Add the samples of another pass over the same pixel
+    pub(crate) fn merge(&mut self, other: &AovPixel) {
+        self.normal += other.normal;
+        self.albedo += other.albedo;
+        self.depth += other.depth;
+        self.hits += other.hits;
+        self.samples += other.samples;
+    }
+
+    /// Average normal of the samples, shorter on edges. Zero for the
+    /// background.
+    pub fn normal(&self) -> glm::DVec3 {
+        self.normal / self.samples.max(1) as f64
+    }
+
+    pub fn albedo(&self) -> Color {
+        self.albedo / self.samples.max(1) as f64
+    }
+
+    /// Average depth of the samples that hit a surface, infinite if none did
+    pub fn depth(&self) -> f64 {
+        if self.hits == 0 {
+            f64::INFINITY
+        } else {
+            self.depth / self.hits as f64
+        }
+    }
+}
+
+/// Beauty render and the AOVs of the first hits of its camera samples
+pub struct RenderBuffers {
+    pub beauty: Film,
+    pub normal: RawImage, // World normal facing the camera, in [-1, 1]
+    pub depth: DepthMap,  // Along the viewing axis, infinite for the background
+    pub albedo: RawImage, // Color of the surfaces, in [0, 1]
+}
+
+impl RenderBuffers {
+    /// Collect the AOVs of the pixels, given in scanline order
+    pub(crate) fn new(beauty: Film, pixels: &[AovPixel]) -> Self {
+        let (width, height) = beauty.resolution();
+        let normal = aov_image(width, height, pixels, |pixel| pixel.normal().into());
+        let albedo = aov_image(width, height, pixels, |pixel| {
+            (pixel.albedo() / 255.0).into()
+        });
+        let depths = pixels.iter().map(AovPixel::depth).collect();
+        Self {
+            beauty,
+            normal,
+            depth: DepthMap::new(width, height, depths).expect("One depth per pixel"),
+            albedo,
+        }
+    }
+
+    /// Silhouettes and creases of the first hits, see `AovSettings::edges`
+    pub fn edges(&self, settings: &AovSettings) -> AovBuffer {
+        settings.edges(&self.depth, &self.normal)
+    }
+
+    /// The normals, with each coordinate mapped from [-1, 1] to [0, 255]
+    pub fn normal_image(&self) -> RgbImage {
+        to_image(&self.normal, |c| 0.5 + 0.5 * c)
+    }
+
+    pub fn albedo_image(&self) -> RgbImage {
+        to_image(&self.albedo, |c| c)
+    }
+
+    /// Grayscale image of the depth, white at the camera and black at the
+    /// furthest surface and on the background
+    pub fn depth_image(&self) -> RgbImage {
+        let (w, h) = self.depth.resolution();
+        let max = self
+            .depth
+            .depths()
+            .iter()
+            .copied()
+            .filter(|depth| depth.is_finite())
+            .fold(0.0, f64::max);
+        RgbImage::from_fn(w, h, |i, j| {
+            let depth = self.depth.depth(i, j);
+            let value = if depth.is_finite() && max > 0.0 {
+                1.0 - depth / max
+            } else {
+                0.0
+            };
+            let gray = (255.0 * value.clamp(0.0, 1.0)).round() as u8;
+            image::Rgb([gray, gray, gray])
+        })
+    }
+}
+
+/// Albedo and normal images to guide the denoiser, none if the render didn't
+/// collect AOVs
+#[cfg(feature = "oidn")]
+pub(crate) fn denoiser_guide(
+    width: u32,
+    height: u32,
+    pixels: &[AovPixel],
+) -> Option<(RawImage, RawImage)> {
+    if pixels.iter().all(|pixel| pixel.samples == 0) {
+        return None;
+    }
+    let albedo = aov_image(width, height, pixels, |pixel| {
+        (pixel.albedo() / 255.0).into()
+    });
+    let normal = aov_image(width, height, pixels, |pixel| pixel.normal().into());
+    Some((albedo, normal))
+}
+
+/// Raw image of the AOV `f` of the pixels, given in scanline order
+fn aov_image(
+    width: u32,
+    height: u32,
+    pixels: &[AovPixel],
+    f: impl Fn(&AovPixel) -> [f64; 3],
+) -> RawImage {
+    RawImage {
+        width,
+        height,
+        data: pixels
+            .iter()
+            .flat_map(|pixel| f(pixel).map(|c| c as f32))
+            .collect(),
+    }
+}
+
+/// 8-bit image of a raw image whose values are mapped to [0, 1] by `f`
+fn to_image(raw: &RawImage, f: impl Fn(f64) -> f64) -> RgbImage {
+    RgbImage::from_fn(raw.width, raw.height, |i, j| {
+        let rgb = raw.pixel(i, j);
+        image::Rgb(rgb.map(|c| (255.0 * f(c as f64).clamp(0.0, 1.0)).round() as u8))
+    })
+}
+
+/// Fraction of cosine weighted rays from a shading point that travel
+/// `distance` without hitting anything
+pub fn ambient_occlusion(
+    objects: &[Object],
+    ctx: &ShadingContext,
+    distance: f64,
+    rays: u32,
+    rng: &mut impl Rng,
+) -> f64 {
+    let rays = rays.max(1);
+    let open = (0..rays)
+        .filter(|_| {
+            let direction = to_world(&ctx.normal, &cosine_hemisphere(rng).0);
+            let ray = Ray::new(ctx.position + SURFACE_OFFSET * direction, direction);
+            get_closest_hit(objects, &ray).is_none_or(|(record, ..)| record.ray_t > distance)
+        })
+        .count();
+    open as f64 / rays as f64
+}
+
+/// Convexity of the surface within `radius` of a shading point, in [-1, 1].
+/// Around each neighbour found by the probe rays, the normal turns away from
+/// the shading normal on convex surfaces and towards it on concave ones, at
+/// a rate that is the curvature along that direction. The weighted average
+/// is scaled by the radius, so that a sphere of the same radius gives 1.
+/// The neighbours are kept in `scratch`.
+pub fn curvature(
+    objects: &[Object],
+    ctx: &ShadingContext,
+    radius: f64,
+    probes: u32,
+    scratch: &Bump,
+    rng: &mut impl Rng,
+) -> f64 {
+    let mut sum = 0.0;
+    let mut weights = 0.0;
+    for neighbour in probe_neighbours(objects, ctx, radius, probes, scratch, rng) {
+        let offset = neighbour.point - ctx.position;
+        let distance2 = offset.norm_squared();
+        if distance2 < (1e-3 * radius).powi(2) {
+            continue; // Too close to tell
+        }
+        sum += neighbour.weight * (neighbour.normal - ctx.normal).dot(&offset) / distance2;
+        weights += neighbour.weight;
+    }
+
+    if weights > 0.0 {
+        (radius * sum / weights).clamp(-1.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use crate::material::Material;
+    use crate::shape::{Plane, Sphere};
+
+    use super::*;
+
+    fn object(shape: impl crate::shape::Shape + Send + Sync + 'static) -> Object {
+        Object {
+            shape: Box::new(shape),
+            material: Material::default(),
+            materials: Vec::new(),
+        }
+    }
+
+    fn floor_ctx(x: f64) -> ShadingContext {
+        ShadingContext {
+            position: glm::DVec3::new(x, 0.0, 0.0),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::zeros(),
+            object_id: 0,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        }
+    }
+
+    #[test]
+    fn occlusion_in_corners() {
+        let objects = vec![
+            object(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            object(Plane {
+                position: glm::DVec3::new(1.0, 0.0, 0.0),
+                normal: glm::DVec3::x(),
+            }),
+        ];
+        let mut rng = rand::thread_rng();
+
+        // Open floor, far from the wall
+        assert_eq!(
+            1.0,
+            ambient_occlusion(&objects, &floor_ctx(-5.0), 1.0, 64, &mut rng)
+        );
+        // At the foot of the wall, about half of the hemisphere is blocked
+        let corner = ambient_occlusion(&objects, &floor_ctx(0.999), 1.0, 4096, &mut rng);
+        assert_relative_eq!(0.5, corner, epsilon = 0.05);
+    }
+
+    #[test]
+    fn convex_and_concave() {
+        let mut rng = rand::thread_rng();
+        let scratch = Bump::new();
+        let radius = 0.1;
+
+        let floor = vec![object(Plane {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+        })];
+        let flat = curvature(&floor, &floor_ctx(0.0), radius, 64, &scratch, &mut rng);
+        assert_relative_eq!(0.0, flat, epsilon = 1e-9);
+
+        // Top of a unit sphere, seen from outside and from inside
+        let ball = vec![object(Sphere::new(glm::DVec3::new(0.0, -1.0, 0.0), 1.0))];
+        let convex = curvature(&ball, &floor_ctx(0.0), radius, 64, &scratch, &mut rng);
+        assert_relative_eq!(radius, convex, epsilon = 0.02);
+        let inside = ShadingContext {
+            normal: -glm::DVec3::y(),
+            ..floor_ctx(0.0)
+        };
+        let concave = curvature(&ball, &inside, radius, 64, &scratch, &mut rng);
+        assert_relative_eq!(-radius, concave, epsilon = 0.02);
+    }
+
+    #[test]
+    fn render_buffers() {
+        let mut scene = Scene::new();
+        scene.add_object(object(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 1.0)));
+        let camera = Camera::new(&crate::camera::CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 16),
+            ..Default::default()
+        });
+        let settings = AovSettings {
+            samples_per_pixel: 1,
+            ..Default::default()
+        };
+
+        let occlusion = settings.render(&scene, &camera, Aov::AmbientOcclusion);
+        assert_eq!((16, 16), occlusion.resolution());
+        assert!(occlusion.values().iter().all(|v| *v == 1.0));
+
+        let curvature = settings.render(&scene, &camera, Aov::Curvature);
+        assert_eq!(0.0, curvature.value(0, 0)); // Background
+        assert!(curvature.value(8, 8) > 0.05);
+        assert!(curvature.to_image().get_pixel(8, 8)[0] > 128);
+    }
+
+    #[test]
+    fn silhouettes_and_creases() {
+        let settings = AovSettings::default();
+        // Line down the third column of a 6x3 image
+        let line = |buffer: AovBuffer| {
+            for (index, value) in buffer.values().iter().enumerate() {
+                let expected = if index % 6 == 2 { 1.0 } else { 0.0 };
+                assert_eq!(expected, *value, "pixel {index}");
+            }
+        };
+        let normals = |right: [f32; 3]| RawImage {
+            width: 6,
+            height: 3,
+            data: (0..18)
+                .flat_map(|index| {
+                    if index % 6 < 3 {
+                        [0.0, 0.0, -1.0]
+                    } else {
+                        right
+                    }
+                })
+                .collect(),
+        };
+
+        // A step away from the camera is drawn on its near side only
+        let step = (0..18).map(|index| if index % 6 < 3 { 1.0 } else { 2.0 });
+        let step = DepthMap::new(6, 3, step.collect()).unwrap();
+        line(settings.edges(&step, &normals([0.0, 0.0, -1.0])));
+
+        // So is the silhouette against the background
+        let object = (0..18).map(|index| if index % 6 < 3 { 1.0 } else { f64::INFINITY });
+        let object = DepthMap::new(6, 3, object.collect()).unwrap();
+        line(settings.edges(&object, &normals([0.0; 3])));
+
+        // A crease at a constant depth, but not a slanted plane
+        let flat = DepthMap::new(6, 3, vec![1.0; 18]).unwrap();
+        line(settings.edges(&flat, &normals([1.0, 0.0, 0.0])));
+        let slanted = (0..18).map(|index| 1.0 / (1.0 + 0.2 * (index % 6) as f64));
+        let slanted = DepthMap::new(6, 3, slanted.collect()).unwrap();
+        let edges = settings.edges(&slanted, &normals([0.0, 0.0, -1.0]));
+        assert!(edges.values().iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn render_edges() {
+        let mut scene = Scene::new();
+        scene.add_object(object(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 1.0)));
+        let camera = Camera::new(&crate::camera::CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (32, 32),
+            ..Default::default()
+        });
+        let edges = AovSettings::default().render(&scene, &camera, Aov::Edges);
+
+        // An outline around the sphere, and nothing inside or outside of it
+        assert_eq!(0.0, edges.value(0, 0));
+        assert_eq!(0.0, edges.value(16, 16));
+        assert!((0..32).any(|i| edges.value(i, 16) == 1.0));
+        let lines = edges.values().iter().filter(|v| **v == 1.0).count();
+        assert!(lines > 8 && lines < 100, "{lines}");
+    }
+
+    #[test]
+    fn first_hit_buffers() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(0.0, 0.0, 255.0);
+        scene.add_object(Object {
+            material: Material {
+                color: Color::new(255.0, 127.5, 0.0),
+                ..Default::default()
+            },
+            ..object(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 1.0))
+        });
+        let camera = Camera::new(&crate::camera::CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 16),
+            ..Default::default()
+        });
+        let mut renderer = crate::render::PathTracer::new();
+        renderer.samples_per_pixel(4);
+        let buffers = renderer.render_buffers(&scene, &camera);
+        assert_eq!((16, 16), buffers.beauty.resolution());
+
+        // Front of the sphere
+        let normal = buffers.normal.pixel(8, 8);
+        assert!(normal[2] < -0.8);
+        assert_relative_eq!(4.0, buffers.depth.depth(8, 8), epsilon = 0.2);
+        assert_eq!([1.0, 0.5, 0.0], buffers.albedo.pixel(8, 8));
+
+        // Background
+        assert_eq!([0.0; 3], buffers.normal.pixel(0, 0));
+        assert_eq!(f64::INFINITY, buffers.depth.depth(0, 0));
+        assert_eq!([0.0, 0.0, 1.0], buffers.albedo.pixel(0, 0));
+        assert_eq!(0, buffers.depth_image().get_pixel(0, 0)[0]);
+        assert!(buffers.normal_image().get_pixel(8, 8)[2] < 26);
+    }
+}
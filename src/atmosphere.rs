@@ -0,0 +1,276 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Single scattering model of a planetary atmosphere (Nishita et al. 1993),
+//! with Rayleigh scattering by air molecules and Mie scattering by aerosols.
+//! It gives the color of the sky and the aerial perspective: distant
+//! geometry fading into the haze.
+
+use std::f64::consts::PI;
+
+use crate::algebra;
+use crate::color::Color;
+use crate::sun::SunPosition;
+
+const VIEW_SAMPLES: u32 = 16;
+const LIGHT_SAMPLES: u32 = 8;
+const MIE_EXTINCTION_RATIO: f64 = 1.1; // Mie extinction over Mie scattering
+
+/// Atmosphere around a spherical planet. The scene sits on top of the planet
+/// with +y up; lengths of the atmosphere are in meters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atmosphere {
+    pub sun_direction: glm::DVec3, // Towards the sun
+    pub sun_intensity: f64,
+    pub planet_radius: f64,
+    pub atmosphere_radius: f64,
+    pub rayleigh_scattering: Color, // Per meter, at sea level
+    pub rayleigh_height: f64,       // Scale height of the density
+    pub mie_scattering: f64,        // Per meter, at sea level
+    pub mie_height: f64,
+    pub mie_anisotropy: f64,  // Henyey-Greenstein g of the aerosols
+    pub meters_per_unit: f64, // Size of a scene unit
+    pub altitude: f64,        // Height of the scene origin above sea level
+}
+
+impl Default for Atmosphere {
+    /// The Earth, with the sun 30 degrees above the horizon
+    fn default() -> Self {
+        Self {
+            sun_direction: glm::DVec3::new(0.0, 0.5, -0.75_f64.sqrt()),
+            sun_intensity: 20.0,
+            planet_radius: 6_360e3,
+            atmosphere_radius: 6_420e3,
+            rayleigh_scattering: Color::new(5.8e-6, 13.5e-6, 33.1e-6),
+            rayleigh_height: 7_994.0,
+            mie_scattering: 21e-6,
+            mie_height: 1_200.0,
+            mie_anisotropy: 0.76,
+            meters_per_unit: 1.0,
+            altitude: 1.0,
+        }
+    }
+}
+
+/// Light scattered into a ray segment and fraction of the light that goes
+/// through it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scattering {
+    pub inscattered: Color,
+    pub transmittance: Color,
+}
+
+impl Atmosphere {
+    pub fn with_sun(sun: &SunPosition) -> Self {
+        Self {
+            sun_direction: sun.direction(),
+            ..Default::default()
+        }
+    }
+
+    /// Position relative to the center of the planet, in meters
+    fn planet_position(&self, point: &glm::DVec3) -> glm::DVec3 {
+        self.meters_per_unit * point + glm::DVec3::new(0.0, self.planet_radius + self.altitude, 0.0)
+    }
+
+    /// Radiance of the sky along a direction, seen from the scene origin
+    pub fn sky(&self, direction: &glm::DVec3) -> Color {
+        self.scattering(&glm::DVec3::zeros(), direction, f64::INFINITY)
+            .inscattered
+    }
+
+    /// Scattering along a ray from `origin` that travels `distance` scene
+    /// units, or until it leaves the atmosphere or reaches the ground
+    pub fn scattering(
+        &self,
+        origin: &glm::DVec3,
+        direction: &glm::DVec3,
+        distance: f64,
+    ) -> Scattering {
+        let origin = self.planet_position(origin);
+        let direction = direction.normalize();
+        let no_scattering = Scattering {
+            inscattered: Color::zeros(),
+            transmittance: Color::new(1.0, 1.0, 1.0),
+        };
+
+        let Some(exit) = exit_distance(&origin, &direction, self.atmosphere_radius) else {
+            return no_scattering;
+        };
+        let mut length = exit.min(distance * self.meters_per_unit);
+        if let Some(ground) = hit_distance(&origin, &direction, self.planet_radius) {
+            length = length.min(ground);
+        }
+        if length <= 0.0 {
+            return no_scattering;
+        }
+
+        let step = length / VIEW_SAMPLES as f64;
+        let mut depth = (0.0, 0.0); // Optical depths of Rayleigh and Mie
+        let mut rayleigh = Color::zeros();
+        let mut mie = Color::zeros();
+        for n in 0..VIEW_SAMPLES {
+            let point = origin + (n as f64 + 0.5) * step * direction;
+            let (density_r, density_m) = self.densities(&point);
+            depth.0 += density_r * step;
+            depth.1 += density_m * step;
+
+            // Light from the sun reaching the point, unless the planet hides it
+            if hit_distance(&point, &self.sun_direction, self.planet_radius).is_some() {
+                continue;
+            }
+            let Some(sun_exit) = exit_distance(&point, &self.sun_direction, self.atmosphere_radius)
+            else {
+                continue;
+            };
+            let sun_step = sun_exit / LIGHT_SAMPLES as f64;
+            let mut sun_depth = (0.0, 0.0);
+            for k in 0..LIGHT_SAMPLES {
+                let sample = point + (k as f64 + 0.5) * sun_step * self.sun_direction;
+                let (r, m) = self.densities(&sample);
+                sun_depth.0 += r * sun_step;
+                sun_depth.1 += m * sun_step;
+            }
+
+            let attenuation = self.extinction(depth.0 + sun_depth.0, depth.1 + sun_depth.1);
+            rayleigh += density_r * step * attenuation;
+            mie += density_m * step * attenuation;
+        }
+
+        let mu = direction.dot(&self.sun_direction.normalize());
+        let phase_r = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+        let g = self.mie_anisotropy;
+        let phase_m = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + mu * mu))
+            / ((2.0 + g * g) * (1.0 + g * g - 2.0 * g * mu).powf(1.5));
+
+        // Scaled to the 0-255 range of the scene colors
+        let inscattered = 255.0
+            * self.sun_intensity
+            * (phase_r * rayleigh.component_mul(&self.rayleigh_scattering)
+                + phase_m * self.mie_scattering * mie);
+        Scattering {
+            inscattered,
+            transmittance: self.extinction(depth.0, depth.1),
+        }
+    }
+
+    /// Aerial perspective: radiance leaving a surface at `distance` along a
+    /// ray as it reaches the ray origin
+    pub fn apply(
+        &self,
+        radiance: &Color,
+        origin: &glm::DVec3,
+        direction: &glm::DVec3,
+        distance: f64,
+    ) -> Color {
+        let scattering = self.scattering(origin, direction, distance);
+        radiance.component_mul(&scattering.transmittance) + scattering.inscattered
+    }
+
+    /// Relative densities of air and aerosols at a point
+    fn densities(&self, point: &glm::DVec3) -> (f64, f64) {
+        let height = point.norm() - self.planet_radius;
+        (
+            (-height / self.rayleigh_height).exp(),
+            (-height / self.mie_height).exp(),
+        )
+    }
+
+    fn extinction(&self, rayleigh_depth: f64, mie_depth: f64) -> Color {
+        let tau = rayleigh_depth * self.rayleigh_scattering
+            + Color::repeat(MIE_EXTINCTION_RATIO * self.mie_scattering * mie_depth);
+        tau.map(|t| (-t).exp())
+    }
+}
+
+/// Distances where a ray meets a sphere around the origin
+fn sphere_distances(
+    origin: &glm::DVec3,
+    direction: &glm::DVec3,
+    radius: f64,
+) -> Option<(f64, f64)> {
+    algebra::solve_deg2_eq(
+        direction.dot(direction),
+        2.0 * direction.dot(origin),
+        origin.dot(origin) - radius * radius,
+    )
+}
+
+/// Distance at which a ray from inside a sphere leaves it
+fn exit_distance(origin: &glm::DVec3, direction: &glm::DVec3, radius: f64) -> Option<f64> {
+    sphere_distances(origin, direction, radius)
+        .map(|(_, far)| far)
+        .filter(|far| *far > 0.0)
+}
+
+/// Distance at which a ray hits a sphere from outside
+fn hit_distance(origin: &glm::DVec3, direction: &glm::DVec3, radius: f64) -> Option<f64> {
+    sphere_distances(origin, direction, radius)
+        .map(|(near, _)| near)
+        .filter(|near| *near > 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn blue_sky_and_red_sunset() {
+        let atmosphere = Atmosphere::default();
+        let zenith = atmosphere.sky(&glm::DVec3::y());
+        assert!(zenith.z > zenith.y && zenith.y > zenith.x);
+
+        // Towards the sun at sunset, the light crosses much more air, which
+        // scatters the blue away
+        let sunset = Atmosphere {
+            sun_direction: glm::DVec3::new(0.0, 0.02, -1.0).normalize(),
+            ..Default::default()
+        };
+        let horizon = sunset.sky(&glm::DVec3::new(0.0, 0.05, -1.0).normalize());
+        assert!(horizon.x > horizon.z);
+
+        // No light at night
+        let night = Atmosphere {
+            sun_direction: -glm::DVec3::y(),
+            ..Default::default()
+        };
+        assert_eq!(Color::zeros(), night.sky(&glm::DVec3::y()));
+    }
+
+    #[test]
+    fn aerial_perspective() {
+        let atmosphere = Atmosphere::default();
+        let direction = glm::DVec3::new(1.0, 0.0, 0.0);
+        let surface = Color::new(10.0, 10.0, 10.0);
+
+        // Close geometry is barely affected
+        let near = atmosphere.apply(&surface, &glm::DVec3::zeros(), &direction, 1.0);
+        assert_relative_eq!(surface, near, epsilon = 0.01);
+
+        // Far away geometry fades into the sky, blue light is lost first
+        let far = atmosphere.scattering(&glm::DVec3::zeros(), &direction, 30_000.0);
+        assert!(far.transmittance.z < far.transmittance.x);
+        assert!(far.transmittance.x < 1.0);
+        let farther = atmosphere.scattering(&glm::DVec3::zeros(), &direction, 60_000.0);
+        assert!(farther.inscattered.z > far.inscattered.z);
+        assert!(farther.transmittance.x < far.transmittance.x);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Rounded edges at shading time. The normal at a hit is blended with the
+//! normals of the surfaces found within a small radius, so hard edges of a
+//! mesh catch highlights as if they were beveled, without changing the
+//! geometry.
+
+use bumpalo::collections::Vec as BumpVec;
+use bumpalo::Bump;
+use rand::Rng;
+
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::object::Object;
+use crate::render::get_closest_hit;
+use crate::sampling::orthonormal_basis;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bevel {
+    pub radius: f64,  // Distance from the edges where the normal is rounded
+    pub samples: u32, // Probe rays per shading point
+}
+
+impl Default for Bevel {
+    fn default() -> Self {
+        Self {
+            radius: 0.05,
+            samples: 8,
+        }
+    }
+}
+
+impl Bevel {
+    pub fn new(radius: f64) -> Self {
+        Self {
+            radius,
+            ..Default::default()
+        }
+    }
+
+    /// Rounded normal at a shading point: the average of the normals found
+    /// around it, see `probe_neighbours`. The neighbours are kept in
+    /// `scratch`.
+    pub fn normal(
+        &self,
+        objects: &[Object],
+        ctx: &ShadingContext,
+        scratch: &Bump,
+        rng: &mut impl Rng,
+    ) -> glm::DVec3 {
+        let sum = probe_neighbours(objects, ctx, self.radius, self.samples, scratch, rng)
+            .iter()
+            .fold(ctx.normal, |sum, neighbour| {
+                sum + neighbour.weight * neighbour.normal
+            });
+
+        if sum.norm() > 0.0 {
+            sum.normalize()
+        } else {
+            ctx.normal
+        }
+    }
+}
+
+/// Surface point found near a shading point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Neighbour {
+    pub point: glm::DVec3,
+    pub normal: glm::DVec3, // Facing the side of the shading normal
+    pub weight: f64,        // Falls from 1 at the shading point to 0 at the radius
+}
+
+/// Find surface points within `radius` of a shading point. Probe rays go
+/// through random points of a disk around the hit, along the normal or, less
+/// often, along the two tangents so that faces perpendicular to the hit are
+/// found too. The neighbours are allocated in `scratch`, which shading resets
+/// once the path is done.
+pub(crate) fn probe_neighbours<'a>(
+    objects: &[Object],
+    ctx: &ShadingContext,
+    radius: f64,
+    probes: u32,
+    scratch: &'a Bump,
+    rng: &mut impl Rng,
+) -> BumpVec<'a, Neighbour> {
+    let n = ctx.normal;
+    let (t, b) = orthonormal_basis(&n);
+    // Up to two neighbours per probe, one from each end of the chord
+    let mut neighbours = BumpVec::with_capacity_in(2 * probes as usize, scratch);
+
+    for _ in 0..probes {
+        let u: f64 = rng.gen();
+        let (axis, x, y) = if u < 0.5 {
+            (n, t, b)
+        } else if u < 0.75 {
+            (t, b, n)
+        } else {
+            (b, n, t)
+        };
+        let [dx, dy]: [f64; 2] = rng.sample(rand_distr::UnitDisc);
+        let offset = radius * (dx * x + dy * y);
+        let height = radius * (1.0 - dx * dx - dy * dy).max(0.0).sqrt();
+
+        // Probe the chord of the sphere from both ends
+        let center = ctx.position + offset;
+        for direction in [-axis, axis] {
+            let probe = Ray::new(center - height * direction, direction);
+            let Some((record, ..)) = get_closest_hit(objects, &probe) else {
+                continue;
+            };
+            if record.ray_t > 2.0 * height {
+                continue;
+            }
+            let distance = glm::distance(&record.point, &ctx.position);
+            let weight = 1.0 - (distance / radius).powi(2);
+            if weight <= 0.0 {
+                continue;
+            }
+            let normal = if record.normal.dot(&n) < 0.0 {
+                -record.normal
+            } else {
+                record.normal
+            };
+            neighbours.push(Neighbour {
+                point: record.point,
+                normal,
+                weight,
+            });
+        }
+    }
+
+    neighbours
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::material::Material;
+    use crate::shape::Triangle;
+
+    use super::*;
+
+    /// Corner of a box: the top face at y = 0 for x < 0 and the side face at
+    /// x = 0 for y < 0
+    fn box_edge() -> Vec<Object> {
+        let quad = |a, b, c, d| [Triangle::new(a, b, c), Triangle::new(a, c, d)];
+        let top = quad(
+            glm::DVec3::new(-2.0, 0.0, -2.0),
+            glm::DVec3::new(0.0, 0.0, -2.0),
+            glm::DVec3::new(0.0, 0.0, 2.0),
+            glm::DVec3::new(-2.0, 0.0, 2.0),
+        );
+        let side = quad(
+            glm::DVec3::new(0.0, 0.0, -2.0),
+            glm::DVec3::new(0.0, -2.0, -2.0),
+            glm::DVec3::new(0.0, -2.0, 2.0),
+            glm::DVec3::new(0.0, 0.0, 2.0),
+        );
+        top.into_iter()
+            .chain(side)
+            .map(|triangle| Object {
+                shape: Box::new(triangle),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rounds_edges() {
+        let objects = box_edge();
+        let bevel = Bevel {
+            radius: 0.1,
+            samples: 64,
+        };
+        // A single estimate is noisy, renders average many of them
+        let mut rng = StdRng::seed_from_u64(7);
+        let ctx = |x: f64| ShadingContext {
+            position: glm::DVec3::new(x, 0.0, 0.0),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::zeros(),
+            object_id: 0,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        };
+
+        // Away from the edge, the normal of the face
+        let scratch = Bump::new();
+        let flat = bevel.normal(&objects, &ctx(-0.5), &scratch, &mut rng);
+        assert!((flat - glm::DVec3::y()).norm() < 1e-9);
+        // The neighbours went to the arena
+        assert!(scratch.allocated_bytes() >= 2 * 64 * std::mem::size_of::<Neighbour>());
+
+        // Close to the edge, the normal leans towards the side face
+        let edge = bevel.normal(&objects, &ctx(-0.01), &scratch, &mut rng);
+        assert!(edge.x > 0.2 && edge.y > 0.2);
+        assert!(edge.z.abs() < 0.1);
+        assert!((edge.norm() - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Standard deviation of the Gaussian energy filter of void-and-cluster
+const SIGMA: f64 = 1.5;
+
+/// Fraction of pixels set in the initial binary pattern
+const INITIAL_DENSITY: f64 = 0.1;
+
+/// Generalized golden ratio constants of the R2 sequence
+const R2_ALPHA: (f64, f64) = (0.7548776662466927, 0.5698402909980532);
+
+/// Square blue-noise mask, tiled over the image. Each value is a rank in
+/// [0, 1), so that thresholding the mask at any level gives evenly spread
+/// pixels without low frequency clumps.
+///
+/// Sample sequences are decorrelated per pixel by rotating a common low
+/// discrepancy sequence by the mask value, which leaves the residual error of
+/// neighbouring pixels anti-correlated: the noise looks finer at low sample
+/// counts and is easier to filter away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlueNoiseMask {
+    size: u32,
+    values: Vec<f64>,
+}
+
+/// Toroidal Gaussian energy of a set of pixels, updated incrementally
+#[derive(Clone)]
+struct Energy {
+    size: usize,
+    kernel: Vec<f64>, // Indexed by the wrapped (dx, dy) offset
+    energy: Vec<f64>,
+}
+
+impl Energy {
+    fn new(size: usize) -> Self {
+        let mut kernel = vec![0.0; size * size];
+        for dy in 0..size {
+            for dx in 0..size {
+                let x = dx.min(size - dx) as f64;
+                let y = dy.min(size - dy) as f64;
+                kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+        Self {
+            size,
+            kernel,
+            energy: vec![0.0; size * size],
+        }
+    }
+
+    fn toggle(&mut self, index: usize, sign: f64) {
+        let (px, py) = (index % self.size, index / self.size);
+        for y in 0..self.size {
+            let dy = (y + self.size - py) % self.size;
+            for x in 0..self.size {
+                let dx = (x + self.size - px) % self.size;
+                self.energy[y * self.size + x] += sign * self.kernel[dy * self.size + dx];
+            }
+        }
+    }
+
+    /// Set pixel with the highest energy
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|n| pattern[*n])
+            .max_by(|a, b| self.energy[*a].total_cmp(&self.energy[*b]))
+            .unwrap()
+    }
+
+    /// Unset pixel with the lowest energy
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|n| !pattern[*n])
+            .min_by(|a, b| self.energy[*a].total_cmp(&self.energy[*b]))
+            .unwrap()
+    }
+}
+
+impl BlueNoiseMask {
+    /// Generate a `size` × `size` mask with the void-and-cluster method.
+    /// The same seed always gives the same mask.
+    pub fn generate(size: u32, seed: u64) -> Self {
+        assert!(size >= 4, "Blue-noise masks must be at least 4 pixels wide");
+        let n = (size * size) as usize;
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // Random initial pattern, relaxed until its points are evenly spread
+        let ones = ((n as f64 * INITIAL_DENSITY) as usize).max(1);
+        let mut pattern = vec![false; n];
+        let mut energy = Energy::new(size as usize);
+        let mut set = 0;
+        while set < ones {
+            let index = rng.gen_range(0..n);
+            if !pattern[index] {
+                pattern[index] = true;
+                energy.toggle(index, 1.0);
+                set += 1;
+            }
+        }
+
+        loop {
+            let cluster = energy.tightest_cluster(&pattern);
+            pattern[cluster] = false;
+            energy.toggle(cluster, -1.0);
+
+            let void = energy.largest_void(&pattern);
+            pattern[void] = true;
+            energy.toggle(void, 1.0);
+
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; n];
+
+        // Rank the initial points by removing the tightest clusters first
+        let mut removed = pattern.clone();
+        let mut removed_energy = energy.clone();
+        for rank in (0..ones).rev() {
+            let cluster = removed_energy.tightest_cluster(&removed);
+            removed[cluster] = false;
+            removed_energy.toggle(cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+
+        // Then fill the largest voids until the mask is complete
+        for rank in ones..n {
+            let void = energy.largest_void(&pattern);
+            pattern[void] = true;
+            energy.toggle(void, 1.0);
+            ranks[void] = rank;
+        }
+
+        Self {
+            size,
+            values: ranks.iter().map(|r| *r as f64 / n as f64).collect(),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Mask value at pixel (i, j), repeating the mask over the image
+    pub fn value(&self, i: u32, j: u32) -> f64 {
+        let (i, j) = (i % self.size, j % self.size);
+        self.values[(j * self.size + i) as usize]
+    }
+
+    /// Sub-pixel offset in [-0.5, 0.5) of the `index`th sample of pixel (i, j)
+    pub fn pixel_offset(&self, i: u32, j: u32, index: u32) -> (f64, f64) {
+        // The second dimension reads the mask shifted by half a tile, which
+        // keeps both dimensions blue but decorrelated from each other
+        let half = self.size / 2;
+        let shift = (self.value(i, j), self.value(i + half, j + half));
+        let index = index as f64;
+        (
+            (0.5 + R2_ALPHA.0 * index + shift.0).fract() - 0.5,
+            (0.5 + R2_ALPHA.1 * index + shift.1).fract() - 0.5,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mask_is_a_permutation_of_ranks() {
+        let mask = BlueNoiseMask::generate(16, 7);
+        let mut ranks: Vec<usize> = mask.values.iter().map(|v| (v * 256.0) as usize).collect();
+        ranks.sort_unstable();
+        assert_eq!((0..256).collect::<Vec<_>>(), ranks);
+
+        assert_eq!(mask, BlueNoiseMask::generate(16, 7));
+        assert_eq!(mask.value(3, 5), mask.value(3 + 16, 5 + 32));
+    }
+
+    #[test]
+    fn thresholds_are_evenly_spread() {
+        // The brightest 10% of a blue-noise mask don't touch each other,
+        // while white noise of the same density has many adjacent pixels
+        let size = 32;
+        let mask = BlueNoiseMask::generate(size, 1);
+        let points: Vec<(i64, i64)> = (0..size)
+            .flat_map(|j| (0..size).map(move |i| (i, j)))
+            .filter(|(i, j)| mask.value(*i, *j) < 0.1)
+            .map(|(i, j)| (i as i64, j as i64))
+            .collect();
+
+        let wrap = |d: i64| d.abs().min(size as i64 - d.abs());
+        for (n, a) in points.iter().enumerate() {
+            for b in &points[n + 1..] {
+                let (dx, dy) = (wrap(a.0 - b.0), wrap(a.1 - b.1));
+                assert!(dx * dx + dy * dy > 2, "{a:?} and {b:?} are too close");
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_offsets() {
+        let mask = BlueNoiseMask::generate(8, 0);
+        for index in 0..64 {
+            let (x, y) = mask.pixel_offset(5, 9, index);
+            assert!((-0.5..0.5).contains(&x));
+            assert!((-0.5..0.5).contains(&y));
+        }
+
+        // Neighbouring pixels start their sequences at different positions
+        assert_ne!(mask.pixel_offset(0, 0, 0), mask.pixel_offset(1, 0, 0));
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Plots of the directions sampled by a material next to the pdf it claims
+//! for them, to spot mismatches between the sampling routine and the pdf,
+//! which bias the renders.
+//!
+//! The hemisphere around the normal is drawn with the Lambert azimuthal
+//! equal-area projection, so every pixel covers the same solid angle and the
+//! histogram of the samples is directly a density.
+
+use std::f64::consts::SQRT_2;
+
+use image::{Rgb, RgbImage};
+use rand::Rng;
+
+use crate::material::Material;
+use crate::metrics;
+
+const NORMAL: glm::DVec3 = glm::DVec3::new(0.0, 0.0, 1.0);
+const SEPARATOR: u32 = 4;
+
+/// Number of samples and resolution of a sampling plot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingPlot {
+    pub size: u32, // Side of each plot, in pixels
+    pub samples: u32,
+}
+
+impl Default for SamplingPlot {
+    fn default() -> Self {
+        Self {
+            size: 128,
+            samples: 1_000_000,
+        }
+    }
+}
+
+impl SamplingPlot {
+    /// Sample `material` for light leaving towards `view`, a direction in the
+    /// local frame where the normal is the z axis
+    pub fn measure(
+        &self,
+        material: &Material,
+        view: &glm::DVec3,
+        rng: &mut impl Rng,
+    ) -> SamplingDensity {
+        let size = self.size.max(1);
+        let view = view.normalize();
+        let mut density = SamplingDensity {
+            size,
+            sampled: vec![0.0; (size * size) as usize],
+            analytic: vec![0.0; (size * size) as usize],
+        };
+
+        let weight = 1.0 / (self.samples.max(1) as f64 * density.pixel_solid_angle());
+        for _ in 0..self.samples {
+            let direction = material.sample(&NORMAL, &view, rng).0.normalize();
+            if let Some(index) = density.index(&direction) {
+                density.sampled[index] += weight;
+            }
+        }
+        for j in 0..size {
+            for i in 0..size {
+                if let Some(direction) = density.direction(i, j) {
+                    let index = (j * size + i) as usize;
+                    density.analytic[index] = material.pdf(&NORMAL, &view, &direction);
+                }
+            }
+        }
+        density
+    }
+}
+
+/// Density of the sampled directions and pdf of the material over the
+/// projected hemisphere
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingDensity {
+    size: u32,
+    sampled: Vec<f64>,
+    analytic: Vec<f64>,
+}
+
+impl SamplingDensity {
+    fn pixel_solid_angle(&self) -> f64 {
+        (2.0 * SQRT_2 / self.size as f64).powi(2)
+    }
+
+    /// Direction at the center of a pixel, if it is inside the hemisphere
+    fn direction(&self, i: u32, j: u32) -> Option<glm::DVec3> {
+        let scale = 2.0 * SQRT_2 / self.size as f64;
+        let x = (i as f64 + 0.5) * scale - SQRT_2;
+        let y = SQRT_2 - (j as f64 + 0.5) * scale;
+        let r2 = x * x + y * y;
+        if r2 > 2.0 {
+            return None;
+        }
+        let s = (1.0 - r2 / 4.0).sqrt();
+        Some(glm::DVec3::new(x * s, y * s, 1.0 - r2 / 2.0))
+    }
+
+    /// Pixel of a direction, if it is in the upper hemisphere
+    fn index(&self, direction: &glm::DVec3) -> Option<usize> {
+        if direction.z <= 0.0 {
+            return None;
+        }
+        let s = (2.0 / (1.0 + direction.z)).sqrt();
+        let (x, y) = (direction.x * s, direction.y * s);
+        let scale = self.size as f64 / (2.0 * SQRT_2);
+        let i = ((x + SQRT_2) * scale) as u32;
+        let j = ((SQRT_2 - y) * scale) as u32;
+        (i < self.size && j < self.size).then_some((j * self.size + i) as usize)
+    }
+
+    /// Total variation distance between the distribution of the samples and
+    /// the pdf: 0 when they match and 1 when they don't overlap at all
+    pub fn mismatch(&self) -> f64 {
+        let difference: f64 = self
+            .sampled
+            .iter()
+            .zip(&self.analytic)
+            .map(|(sampled, analytic)| (sampled - analytic).abs())
+            .sum();
+        // Samples lost below the horizon are a mismatch too
+        let sampled: f64 = self.sampled.iter().sum::<f64>() * self.pixel_solid_angle();
+        0.5 * (difference * self.pixel_solid_angle() + (1.0 - sampled).max(0.0))
+    }
+
+    /// Sampled density on the left and pdf on the right, both with the same
+    /// scale
+    pub fn to_image(&self) -> RgbImage {
+        let max = self
+            .sampled
+            .iter()
+            .chain(&self.analytic)
+            .fold(0.0, |max: f64, value| max.max(*value));
+        let mut image = RgbImage::from_pixel(2 * self.size + SEPARATOR, self.size, Rgb([0, 0, 0]));
+        for j in 0..self.size {
+            for i in 0..self.size {
+                if self.direction(i, j).is_none() {
+                    continue;
+                }
+                let index = (j * self.size + i) as usize;
+                let color =
+                    |value: f64| metrics::heat_color(if max > 0.0 { value / max } else { 0.0 });
+                image.put_pixel(i, j, color(self.sampled[index]));
+                image.put_pixel(self.size + SEPARATOR + i, j, color(self.analytic[index]));
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::merl::{MerlBrdf, NUM_SAMPLES};
+
+    #[test]
+    fn projection_round_trip() {
+        let density = SamplingDensity {
+            size: 64,
+            sampled: vec![],
+            analytic: vec![],
+        };
+        for (i, j) in [(32, 32), (10, 40), (50, 12), (3, 30)] {
+            let direction = density.direction(i, j).unwrap();
+            assert_relative_eq!(1.0, direction.norm(), epsilon = 1e-12);
+            assert_eq!(Some((j * 64 + i) as usize), density.index(&direction));
+        }
+        assert_eq!(None, density.direction(0, 0));
+        assert_eq!(None, density.index(&-NORMAL));
+    }
+
+    #[test]
+    fn cosine_sampling_matches_its_pdf() {
+        let material = Material {
+            bsdf: Some(Arc::new(
+                MerlBrdf::from_samples(vec![1000.0; 3 * NUM_SAMPLES]).unwrap(),
+            )),
+            ..Default::default()
+        };
+        let plot = SamplingPlot {
+            size: 32,
+            samples: 200_000,
+        };
+        let view = glm::DVec3::new(1.0, 0.0, 1.0);
+        let density = plot.measure(&material, &view, &mut rand::thread_rng());
+        assert!(density.mismatch() < 0.1);
+        assert_eq!((68, 32), density.to_image().dimensions());
+
+        // Lambertian surfaces sample the same distribution
+        let diffuse = plot.measure(&Material::default(), &view, &mut rand::thread_rng());
+        assert!(diffuse.mismatch() < 0.1);
+    }
+}
@@ -17,10 +17,10 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use glm;
-
-use rand::{rngs::ThreadRng, Rng};
+use rand::Rng;
 use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::light::Ray;
 
@@ -36,29 +36,231 @@ impl Default for FieldOfView {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum FocusMode {
     FocalPlane {
         focal_distance: f64, // [m]
         aperture: f64,       // Aperture radius [m]
     },
+    #[default]
     PinHole,
 }
 
-impl Default for FocusMode {
-    fn default() -> Self {
-        Self::PinHole
+/// Distribution of the ray origins over the aperture disc
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ApertureSampling {
+    /// Uniform disc, i.e. an ideal lens
+    #[default]
+    Uniform,
+    /// Gaussian falloff towards the edge of the aperture, producing soft
+    /// bokeh. `sigma` is relative to the aperture radius.
+    Gaussian { sigma: f64 },
+    /// Mechanical vignetting: the aperture is clipped by a second disc that
+    /// shifts with the distance of the pixel to the image center, so that
+    /// bokeh becomes cat-eye shaped towards the corners. `strength` is the
+    /// shift, relative to the aperture radius, at the image corners.
+    CatEye { strength: f64 },
+}
+
+/// Outline of the aperture, cut out of the aperture disc. Out-of-focus
+/// highlights take its shape.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ApertureShape {
+    #[default]
+    Disc,
+    /// Regular polygon formed by the diaphragm blades, with its corners on
+    /// the aperture circle. `rotation` is the angle of a corner from the
+    /// image right, in radians.
+    Polygon { blades: u32, rotation: f64 },
+    /// Arbitrary cut-out, e.g. a star or a heart
+    Mask(Arc<ApertureMask>),
+}
+
+impl ApertureShape {
+    /// Whether the point (x, y) of the unit aperture disc lets light in.
+    /// Masks that are partly transparent let it in at random.
+    fn contains(&self, x: f64, y: f64, rng: &mut impl Rng) -> bool {
+        match self {
+            Self::Disc => true,
+            Self::Polygon { blades, rotation } => {
+                // Distance to the center along the bisector of the closest
+                // corners, against that of the sides
+                let sector = 2.0 * PI / (*blades as f64);
+                let angle = (y.atan2(x) - rotation).rem_euclid(sector) - sector / 2.0;
+                x.hypot(y) * angle.cos() <= (sector / 2.0).cos()
+            }
+            Self::Mask(mask) => rng.gen::<f64>() < mask.transmission(x, y),
+        }
     }
 }
 
-#[derive(Debug)]
+/// Transmission of an aperture over the square around the aperture disc,
+/// relative to its most transparent texel. Only the part inside the disc is
+/// used, and the sampling is most efficient when the cut-out fills it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApertureMask {
+    width: u32,
+    height: u32,
+    transmissions: Vec<f64>, // Scanline order, top row first
+    path: Option<PathBuf>,
+}
+
+impl ApertureMask {
+    pub fn new(width: u32, height: u32, transmissions: Vec<f64>) -> Result<Self, &'static str> {
+        if transmissions.len() != (width * height) as usize {
+            return Err("Aperture mask size doesn't match its resolution");
+        }
+        if !transmissions.iter().all(|t| t.is_finite() && *t >= 0.0) {
+            return Err("Aperture mask transmissions must be finite and not negative");
+        }
+        let max = transmissions.iter().copied().fold(0.0, f64::max);
+        if max == 0.0 {
+            return Err("Aperture mask is opaque");
+        }
+        Ok(Self {
+            width,
+            height,
+            transmissions: transmissions.iter().map(|t| t / max).collect(),
+            path: None,
+        })
+    }
+
+    /// Read a mask from the luminance of an image
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|e| format!("Couldn't load {}: {e}", path.display()))?
+            .to_luma32f();
+        let (width, height) = image.dimensions();
+        let transmissions = image.pixels().map(|luma| luma[0] as f64).collect();
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            ..Self::new(width, height, transmissions)?
+        })
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Image the mask was read from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Relative transmission at the point (x, y) of the unit aperture disc
+    fn transmission(&self, x: f64, y: f64) -> f64 {
+        let i = ((x + 1.0) / 2.0 * self.width as f64) as u32;
+        let j = ((1.0 - y) / 2.0 * self.height as f64) as u32;
+        let (i, j) = (i.min(self.width - 1), j.min(self.height - 1));
+        self.transmissions[(j * self.width + i) as usize]
+    }
+}
+
+/// Mapping from the angle between a ray and the facing direction to the
+/// distance of its pixel to the image center. The fisheye projections see
+/// wider than 180°, e.g. for dome and planetarium content, and fit their
+/// field of view to the width or height of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Projection {
+    /// Straight lines stay straight: r = f tan θ
+    #[default]
+    Perspective,
+    /// Angles are evenly spread over the image: r = f θ
+    Equidistant,
+    /// Areas of the sphere of directions keep their size: r = 2f sin(θ/2)
+    Equisolid,
+    /// Shapes are kept locally: r = 2f tan(θ/2)
+    Stereographic,
+}
+
+impl Projection {
+    pub fn is_fisheye(&self) -> bool {
+        *self != Projection::Perspective
+    }
+
+    /// Distance to the image center, in focal lengths, of the directions at
+    /// angle `theta` from the facing direction
+    fn radius(&self, theta: f64) -> f64 {
+        match self {
+            Projection::Perspective => theta.tan(),
+            Projection::Equidistant => theta,
+            Projection::Equisolid => 2.0 * (theta / 2.0).sin(),
+            Projection::Stereographic => 2.0 * (theta / 2.0).tan(),
+        }
+    }
+
+    /// Inverse of `radius`. Radii beyond the whole sphere of directions look
+    /// straight back.
+    fn angle(&self, radius: f64) -> f64 {
+        match self {
+            Projection::Perspective => radius.atan(),
+            Projection::Equidistant => radius.min(PI),
+            Projection::Equisolid => 2.0 * (radius / 2.0).min(1.0).asin(),
+            Projection::Stereographic => 2.0 * (radius / 2.0).atan(),
+        }
+    }
+}
+
+/// Interval the shutter is open, in seconds from the time of the frame.
+/// Rays are spread uniformly across it, blurring whatever moves meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Shutter {
+    pub open: f64,
+    pub close: f64,
+}
+
+impl Shutter {
+    pub fn new(open: f64, close: f64) -> Result<Self, &'static str> {
+        if !(open.is_finite() && close.is_finite()) || close < open {
+            return Err("The shutter can't close before it opens");
+        }
+        Ok(Self { open, close })
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.close - self.open
+    }
+
+    /// Time at fraction `u` of the interval
+    pub fn time(&self, u: f64) -> f64 {
+        self.open + u * self.duration()
+    }
+}
+
+/// Movement of a camera from the opening of its shutter to the closing
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CameraMotion {
+    translation: glm::DVec3,
+    rotation: glm::DQuat, // Of the camera axes
+}
+
+/// Maximum number of rejection sampling attempts on the aperture
+const MAX_APERTURE_TRIES: usize = 64;
+
+const WORLD_UP: glm::DVec3 = glm::DVec3::new(0.0, 1.0, 0.0);
+
+/// Image "up" references, tried in order when the up vector of a camera is
+/// zero or parallel to its direction. One of the last two always works.
+const FALLBACK_UP: [glm::DVec3; 2] = [
+    glm::DVec3::new(0.0, 0.0, 1.0),
+    glm::DVec3::new(1.0, 0.0, 0.0),
+];
+
+#[derive(Debug, Clone)]
 pub struct CameraConfig {
     pub position: glm::DVec3,
     pub direction: glm::DVec3,
+    pub up: glm::DVec3, // Image "up", needn't be perpendicular to the direction
     pub resolution: (u32, u32),
     pub rotation: f64,
     pub fov: FieldOfView,
     pub focus_mode: FocusMode,
+    pub aperture_sampling: ApertureSampling,
+    pub aperture_shape: ApertureShape,
+    pub anamorphic_squeeze: f64, // Height over width of the aperture, 1 for spherical lenses
+    pub projection: Projection,
+    pub shutter: Shutter,
 }
 
 impl Default for CameraConfig {
@@ -66,14 +268,103 @@ impl Default for CameraConfig {
         Self {
             position: glm::DVec3::zeros(),
             direction: glm::DVec3::zeros(),
+            up: WORLD_UP,
             resolution: (800, 600),
             rotation: 0.0_f64,
             fov: FieldOfView::default(),
             focus_mode: FocusMode::default(),
+            aperture_sampling: ApertureSampling::default(),
+            aperture_shape: ApertureShape::default(),
+            anamorphic_squeeze: 1.0,
+            projection: Projection::default(),
+            shutter: Shutter::default(),
         }
     }
 }
 
+impl CameraConfig {
+    /// Camera at `eye` looking at `target`, with the image "up" towards
+    /// `up`. The other settings are the defaults.
+    pub fn look_at(
+        eye: glm::DVec3,
+        target: glm::DVec3,
+        up: glm::DVec3,
+    ) -> Result<Self, &'static str> {
+        if eye == target {
+            return Err("The camera can't look at its own position");
+        }
+        Ok(Self {
+            position: eye,
+            direction: target - eye,
+            up,
+            ..Default::default()
+        })
+    }
+
+    /// The same camera with its resolution scaled, keeping at least a pixel
+    /// each way
+    pub fn scaled(&self, scale: f64) -> Self {
+        let scale = |size: u32| ((size as f64 * scale).round() as u32).max(1);
+        Self {
+            resolution: (scale(self.resolution.0), scale(self.resolution.1)),
+            ..self.clone()
+        }
+    }
+}
+
+/// Per-pixel depth along the viewing axis, e.g. from `render::render_depth`.
+/// Pixels that see no geometry have infinite depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthMap {
+    width: u32,
+    height: u32,
+    depths: Vec<f64>, // Scanline order
+}
+
+impl DepthMap {
+    pub fn new(width: u32, height: u32, depths: Vec<f64>) -> Result<Self, &'static str> {
+        if depths.len() != (width * height) as usize {
+            return Err("Depth map size doesn't match its resolution");
+        }
+        Ok(Self {
+            width,
+            height,
+            depths,
+        })
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn depth(&self, i: u32, j: u32) -> f64 {
+        self.depths[(j * self.width + i) as usize]
+    }
+
+    pub fn depths(&self) -> &[f64] {
+        &self.depths
+    }
+
+    /// Depth at the pixel of a camera with a possibly different resolution
+    fn depth_scaled(&self, i: u32, j: u32, resolution: (u32, u32)) -> f64 {
+        let x = ((i as u64) * (self.width as u64) / (resolution.0 as u64)) as u32;
+        let y = ((j as u64) * (self.height as u64) / (resolution.1 as u64)) as u32;
+        self.depth(x.min(self.width - 1), y.min(self.height - 1))
+    }
+}
+
+/// Where a thin-lens camera is focused
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FocusDepth {
+    /// The focal distance of the camera configuration
+    #[default]
+    FocalPlane,
+    /// A fixed depth along the viewing axis
+    Depth(f64),
+    /// A per-pixel depth
+    Map(Arc<DepthMap>),
+}
+
 #[derive(Default, Debug)]
 struct CoordinateSystem {
     origin: glm::DVec3,
@@ -89,6 +380,14 @@ pub struct Camera {
     resolution: (u32, u32), // Resolutions (width, height) in pixels
     fov: FieldOfView, // Field of view (Horizontal or Vertical) in radians
     focus_mode: FocusMode,
+    aperture_sampling: ApertureSampling,
+    aperture_shape: ApertureShape,
+    anamorphic_squeeze: f64,
+    focus_depth: FocusDepth,
+    projection: Projection,
+    fisheye_focal_length: f64, // In pixels
+    shutter: Shutter,
+    motion: Option<CameraMotion>,
 
     distance_to_plane: f64,
     first_pixel_pos: glm::DVec3,
@@ -96,18 +395,12 @@ pub struct Camera {
     pixel_height: f64,
 }
 
-impl CoordinateSystem {
-    pub fn new(origin: glm::DVec3, u: glm::DVec3, v: glm::DVec3, w: glm::DVec3) -> Self {
-        Self { origin, u, v, w }
-    }
-}
-
 impl Camera {
     pub fn new(config: &CameraConfig) -> Self {
         let mut camera = Self::default();
         camera.config(config).expect("Couldn't configure camera");
 
-        return camera;
+        camera
     }
 
     pub fn position(&self) -> glm::DVec3 {
@@ -118,6 +411,16 @@ impl Camera {
         self.coordinate_system.w
     }
 
+    /// Camera base vectors (u, v, w): image right, image up and facing
+    /// direction
+    pub fn basis(&self) -> (glm::DVec3, glm::DVec3, glm::DVec3) {
+        (
+            self.coordinate_system.u,
+            self.coordinate_system.v,
+            self.coordinate_system.w,
+        )
+    }
+
     pub fn rotation(&self) -> f64 {
         self.rotation
     }
@@ -126,18 +429,195 @@ impl Camera {
         self.fov
     }
 
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+
+    /// Move the camera while the shutter is open, from where it is at the
+    /// opening to the position and orientation of `close` at the closing.
+    /// The rest of the settings of `close` are ignored.
+    pub fn set_motion(&mut self, close: &CameraConfig) -> Result<(), &'static str> {
+        let mut end = Camera::default();
+        end.config(&CameraConfig {
+            position: close.position,
+            direction: close.direction,
+            up: close.up,
+            rotation: close.rotation,
+            ..Default::default()
+        })?;
+        let frame = |camera: &Camera| {
+            let (u, v, w) = camera.basis();
+            glm::DMat3::from_columns(&[u, v, w])
+        };
+        let rotation = frame(&end) * frame(self).transpose();
+        self.motion = Some(CameraMotion {
+            translation: end.position() - self.position(),
+            rotation: glm::mat3_to_quat(&rotation),
+        });
+        Ok(())
+    }
+
     pub fn resolution(&self) -> (u32, u32) {
         self.resolution
     }
 
-    pub fn config(&mut self, config: &CameraConfig) -> Result<(), &str> {
-        const WORLD_UP: glm::DVec3 = glm::DVec3::new(0.0, 1.0, 0.0);
+    /// Distance from the center of projection to the image plane, in pixels.
+    /// This is the focal length of the pinhole projection matrix, whose
+    /// principal point is the center of the image. Fisheye cameras give the
+    /// f of their projection instead.
+    pub fn focal_length_pixels(&self) -> f64 {
+        if self.projection.is_fisheye() {
+            return self.fisheye_focal_length;
+        }
+        self.distance_to_plane / self.pixel_width
+    }
+
+    /// Override the focal distance of a thin-lens camera, keeping its field
+    /// of view. Has no effect on pinhole cameras.
+    pub fn set_focus(&mut self, focus: FocusDepth) -> Result<(), &'static str> {
+        match &focus {
+            FocusDepth::Depth(depth) if depth.is_nan() || *depth <= 0.0 => {
+                return Err("Focus depth must be positive");
+            }
+            FocusDepth::Map(map) if map.depths.is_empty() => {
+                return Err("Focus map is empty");
+            }
+            _ => self.focus_depth = focus,
+        }
+        Ok(())
+    }
+
+    /// Focus on whatever is visible at pixel (i, j) of a depth map
+    pub fn focus_on_pixel(&mut self, map: &DepthMap, i: u32, j: u32) -> Result<(), &'static str> {
+        if i >= map.width || j >= map.height {
+            return Err("Pixel out of the depth map");
+        }
+
+        let depth = map.depth(i, j);
+        if !depth.is_finite() {
+            return Err("There is nothing to focus on at this pixel");
+        }
+        self.set_focus(FocusDepth::Depth(depth))
+    }
+
+    /// Ray from the center of projection through the center of pixel (i, j),
+    /// ignoring the aperture, as the shutter opens
+    pub fn center_ray(&self, i: u32, j: u32) -> Option<Ray> {
+        if (i >= self.resolution.0) || (j >= self.resolution.1) {
+            return None;
+        }
+
+        let origin = self.coordinate_system.origin;
+        let direction = if self.projection.is_fisheye() {
+            self.fisheye_direction(i, j, (0.0, 0.0))
+        } else {
+            self.pixel_position(i, j, (0.0, 0.0)) - origin
+        };
+        Some(Ray::new(origin, direction).with_time(self.shutter.open))
+    }
+
+    /// Pixel a point is seen through from the center of projection, the
+    /// inverse of `center_ray`. Points behind the camera or outside the
+    /// image give None.
+    pub fn project(&self, point: &glm::DVec3) -> Option<(u32, u32)> {
+        let cs = &self.coordinate_system;
+        let to_point = point - cs.origin;
+        let depth = to_point.dot(&cs.w);
+        let (w, h) = self.resolution;
+        let (x, y) = if self.projection.is_fisheye() {
+            let theta = (depth / to_point.norm()).clamp(-1.0, 1.0).acos();
+            let radius = self.fisheye_focal_length * self.projection.radius(theta);
+            let (x, y) = (to_point.dot(&cs.u), to_point.dot(&cs.v));
+            let across = x.hypot(y).max(f64::MIN_POSITIVE);
+            (
+                (w as f64 / 2.0 + radius * x / across - 0.5).round(),
+                (h as f64 / 2.0 - radius * y / across - 0.5).round(),
+            )
+        } else {
+            if depth <= 0.0 {
+                return None;
+            }
+            let on_plane =
+                to_point * (self.distance_to_plane / depth) + cs.origin - self.first_pixel_pos;
+            (
+                (on_plane.dot(&cs.u) / self.pixel_width).round(),
+                (-on_plane.dot(&cs.v) / self.pixel_height).round(),
+            )
+        };
+        ((0.0..w as f64).contains(&x) && (0.0..h as f64).contains(&y))
+            .then_some((x as u32, y as u32))
+    }
+
+    /// Position of a point of pixel (i, j) on the image plane
+    fn pixel_position(&self, i: u32, j: u32, offset: (f64, f64)) -> glm::DVec3 {
+        let x = (i as f64) + offset.0;
+        let y = (j as f64) + offset.1;
+        self.first_pixel_pos + (x * self.pixel_width * self.coordinate_system.u)
+            - (y * self.pixel_height * self.coordinate_system.v)
+    }
 
+    /// Direction of the ray of a fisheye camera through a point of pixel
+    /// (i, j)
+    fn fisheye_direction(&self, i: u32, j: u32, offset: (f64, f64)) -> glm::DVec3 {
+        let (w, h) = (self.resolution.0 as f64, self.resolution.1 as f64);
+        let x = i as f64 + 0.5 + offset.0 - w / 2.0;
+        let y = h / 2.0 - (j as f64 + 0.5 + offset.1);
+        let radius = x.hypot(y);
+        let theta = self.projection.angle(radius / self.fisheye_focal_length);
+        let cs = &self.coordinate_system;
+        let across = if radius > 0.0 {
+            (x * cs.u + y * cs.v) / radius
+        } else {
+            glm::DVec3::zeros()
+        };
+        theta.cos() * cs.w + theta.sin() * across
+    }
+
+    /// Aim the camera from `eye` at `target`, with the image "up" towards
+    /// `up`, keeping the rest of its settings
+    pub fn look_at(
+        &mut self,
+        eye: glm::DVec3,
+        target: glm::DVec3,
+        up: glm::DVec3,
+    ) -> Result<(), &'static str> {
+        let config = CameraConfig {
+            resolution: self.resolution,
+            rotation: self.rotation,
+            fov: self.fov,
+            focus_mode: self.focus_mode,
+            aperture_sampling: self.aperture_sampling,
+            aperture_shape: self.aperture_shape.clone(),
+            anamorphic_squeeze: self.anamorphic_squeeze,
+            projection: self.projection,
+            shutter: self.shutter,
+            ..CameraConfig::look_at(eye, target, up)?
+        };
+        self.config(&config)
+    }
+
+    pub fn config(&mut self, config: &CameraConfig) -> Result<(), &'static str> {
         if (config.resolution.0 * config.resolution.1) == 0 {
             return Err("The camera resolution cannot be zero");
         }
 
         match config.fov {
+            FieldOfView::Horizontal(alpha) if config.projection.is_fisheye() => {
+                if alpha <= 0.0 || alpha >= 2.0 * PI {
+                    return Err("Fisheye field of view must be in the interval (0, 360)");
+                }
+                self.fov = FieldOfView::Horizontal(alpha);
+            }
+            FieldOfView::Vertical(alpha) if config.projection.is_fisheye() => {
+                if alpha <= 0.0 || alpha >= 2.0 * PI {
+                    return Err("Fisheye field of view must be in the interval (0, 360)");
+                }
+                self.fov = FieldOfView::Vertical(alpha);
+            }
             FieldOfView::Horizontal(mut alpha) => {
                 alpha = alpha.abs();
                 if alpha <= 0.0 || alpha > PI {
@@ -156,11 +636,20 @@ impl Camera {
 
         self.resolution = config.resolution;
         self.rotation = config.rotation;
+        self.shutter = Shutter::new(config.shutter.open, config.shutter.close)?;
+        self.motion = None;
 
         // Set coordinates
         self.coordinate_system.origin = config.position;
         self.coordinate_system.w = config.direction.normalize();
-        self.coordinate_system.u = self.coordinate_system.w.cross(&WORLD_UP).normalize();
+        let w = self.coordinate_system.w;
+        let u = std::iter::once(config.up.normalize())
+            .chain(FALLBACK_UP)
+            .map(|up| w.cross(&up))
+            .find(|u| u.norm() > 1e-9)
+            // Only a zero direction gets past the last fallback
+            .unwrap_or_else(|| w.cross(&FALLBACK_UP[1]));
+        self.coordinate_system.u = u.normalize();
         self.coordinate_system.v = self
             .coordinate_system
             .u
@@ -183,6 +672,21 @@ impl Camera {
             self.coordinate_system.v.normalize_mut();
         }
 
+        // Fisheye cameras have no image plane, and are pinholes
+        self.projection = config.projection;
+        if self.projection.is_fisheye() {
+            if let FocusMode::FocalPlane { .. } = config.focus_mode {
+                return Err("Fisheye cameras can't have a focal plane");
+            }
+            let (half_size, alpha) = match self.fov {
+                FieldOfView::Horizontal(alpha) => (self.resolution.0 as f64 / 2.0, alpha),
+                FieldOfView::Vertical(alpha) => (self.resolution.1 as f64 / 2.0, alpha),
+            };
+            self.fisheye_focal_length = half_size / self.projection.radius(alpha / 2.0);
+            self.focus_mode = FocusMode::PinHole;
+            return Ok(());
+        }
+
         // Calculate distance to plane using fov and focus parameters
         let aspect_ratio: f64 = (self.resolution.0 as f64) / (self.resolution.1 as f64);
         let (sensor_width, sensor_height): (f64, f64) = match config.focus_mode {
@@ -222,6 +726,26 @@ impl Camera {
         };
         self.focus_mode = config.focus_mode;
 
+        match config.aperture_sampling {
+            ApertureSampling::Gaussian { sigma } if sigma <= 0.0 => {
+                return Err("Gaussian aperture sigma must be positive");
+            }
+            ApertureSampling::CatEye { strength } if !(0.0..=1.0).contains(&strength) => {
+                return Err("Cat-eye strength must be in the interval [0, 1]");
+            }
+            _ => self.aperture_sampling = config.aperture_sampling,
+        }
+        match config.aperture_shape {
+            ApertureShape::Polygon { blades, .. } if blades < 3 => {
+                return Err("An aperture polygon needs at least 3 blades");
+            }
+            _ => self.aperture_shape = config.aperture_shape.clone(),
+        }
+        if config.anamorphic_squeeze <= 0.0 {
+            return Err("The anamorphic squeeze must be positive");
+        }
+        self.anamorphic_squeeze = config.anamorphic_squeeze;
+
         // Calculate pixel size
         self.pixel_width = sensor_width / (self.resolution.0 as f64);
         self.pixel_height = sensor_height / (self.resolution.1 as f64);
@@ -232,14 +756,68 @@ impl Camera {
             - (self.coordinate_system.u * ((sensor_width / 2.0) - (self.pixel_width / 2.0)))
             + (self.coordinate_system.v * ((sensor_height / 2.0) - (self.pixel_height / 2.0)));
 
-        return Ok(());
+        Ok(())
+    }
+
+    /// Cast a Ray through the center of pixel (i, j)
+    pub fn cast_ray(&self, i: u32, j: u32, rng: &mut impl Rng) -> Option<Ray> {
+        self.cast_ray_jittered(i, j, (0.0, 0.0), rng)
+    }
+
+    /// Cast a Ray to pixel (i, j), displaced from the pixel center by `offset`.
+    /// The offset is given in pixel units and is expected to be in [-0.5, 0.5].
+    /// The ray leaves at a random time while the shutter is open, and
+    /// stands for a beam that widens by the angle of a pixel.
+    pub fn cast_ray_jittered(
+        &self,
+        i: u32,
+        j: u32,
+        offset: (f64, f64),
+        rng: &mut impl Rng,
+    ) -> Option<Ray> {
+        let ray = self
+            .cast_ray_at_opening(i, j, offset, rng)?
+            .with_spread(1.0 / self.focal_length_pixels());
+        // Instant shutters don't draw a time, keeping the random sequence
+        if self.shutter.duration() == 0.0 {
+            return Some(ray.with_time(self.shutter.open));
+        }
+        Some(self.ray_at(ray, self.shutter.time(rng.gen())))
+    }
+
+    /// Move a ray cast at the opening of the shutter along with the camera,
+    /// to `time`
+    fn ray_at(&self, ray: Ray, time: f64) -> Ray {
+        let Some(motion) = &self.motion else {
+            return ray.with_time(time);
+        };
+        let s = (time - self.shutter.open) / self.shutter.duration();
+        let rotation = glm::quat_slerp(&glm::quat_identity(), &motion.rotation, s);
+        let origin = self.coordinate_system.origin;
+        Ray {
+            origin: origin
+                + s * motion.translation
+                + glm::quat_rotate_vec3(&rotation, &(ray.origin - origin)),
+            direction: glm::quat_rotate_vec3(&rotation, &ray.direction),
+            time,
+            ..ray
+        }
     }
 
-    /// Cast a Ray to pixel (i, j)
-    pub fn cast_ray(&self, i: u32, j: u32, rng: &mut ThreadRng) -> Option<Ray> {
+    fn cast_ray_at_opening(
+        &self,
+        i: u32,
+        j: u32,
+        offset: (f64, f64),
+        rng: &mut impl Rng,
+    ) -> Option<Ray> {
         if (i >= self.resolution.0) || (j >= self.resolution.1) {
             return None;
         }
+        if self.projection.is_fisheye() {
+            let origin = self.coordinate_system.origin;
+            return Some(Ray::new(origin, self.fisheye_direction(i, j, offset)));
+        }
 
         let ray_origin = match self.focus_mode {
             FocusMode::PinHole => self.coordinate_system.origin,
@@ -247,27 +825,106 @@ impl Camera {
                 focal_distance: _,
                 aperture,
             } => {
-                // Uniform sample of the aperture disc
-                let [x, y]: [f64; 2] = rng.sample(rand_distr::UnitDisc);
+                let [x, y] = self.sample_aperture(i, j, rng);
 
                 self.coordinate_system.origin
                     + aperture * (x * self.coordinate_system.u + y * self.coordinate_system.v)
             }
         };
 
-        let pixel_position = self.first_pixel_pos
-            + ((i as f64) * self.pixel_width * self.coordinate_system.u)
-            - ((j as f64) * self.pixel_height * self.coordinate_system.v);
-        let ray_direction = pixel_position - ray_origin;
+        let pixel_position = self.pixel_position(i, j, offset);
+
+        // The image plane lies at the focal distance. Move the target point
+        // along the chief ray when focusing elsewhere.
+        let focus_scale = match (&self.focus_mode, &self.focus_depth) {
+            (FocusMode::PinHole, _) | (_, FocusDepth::FocalPlane) => 1.0,
+            (_, FocusDepth::Depth(depth)) => depth / self.distance_to_plane,
+            (_, FocusDepth::Map(map)) => {
+                let depth = map.depth_scaled(i, j, self.resolution);
+                if depth.is_finite() {
+                    depth / self.distance_to_plane
+                } else {
+                    1.0
+                }
+            }
+        };
+        let origin = self.coordinate_system.origin;
+        let focus_point = origin + focus_scale * (pixel_position - origin);
+        let ray_direction = focus_point - ray_origin;
 
         Some(Ray::new(ray_origin, ray_direction))
     }
+
+    /// Sample a point on the aperture for pixel (i, j), in camera (u, v)
+    /// coordinates relative to the aperture radius
+    fn sample_aperture(&self, i: u32, j: u32, rng: &mut impl Rng) -> [f64; 2] {
+        for _ in 0..MAX_APERTURE_TRIES {
+            if let Some([x, y]) = self.propose_aperture(i, j, rng) {
+                if self.aperture_shape.contains(x, y, rng) {
+                    return [x / self.anamorphic_squeeze, y];
+                }
+            }
+        }
+        [0.0, 0.0]
+    }
+
+    /// Candidate point on the unit aperture disc for pixel (i, j), drawn from
+    /// the aperture sampling, or None if the sampling rejects it
+    fn propose_aperture(&self, i: u32, j: u32, rng: &mut impl Rng) -> Option<[f64; 2]> {
+        match self.aperture_sampling {
+            ApertureSampling::Uniform => Some(rng.sample(rand_distr::UnitDisc)),
+            ApertureSampling::Gaussian { sigma } => {
+                let normal = rand_distr::Normal::new(0.0, sigma).unwrap();
+                let (x, y): (f64, f64) = (rng.sample(normal), rng.sample(normal));
+                (x * x + y * y <= 1.0).then_some([x, y])
+            }
+            ApertureSampling::CatEye { strength } => {
+                // Pixel position relative to the image center, in [-1, 1]
+                // along the diagonal
+                let (w, h) = (self.resolution.0 as f64, self.resolution.1 as f64);
+                let pu = ((i as f64) + 0.5 - w / 2.0) / (w / 2.0);
+                let pv = -((j as f64) + 0.5 - h / 2.0) / (h / 2.0);
+                let shift = strength / std::f64::consts::SQRT_2;
+                let (cu, cv) = (-shift * pu, -shift * pv);
+
+                // Uniform sample of the intersection of both discs
+                let [x, y]: [f64; 2] = rng.sample(rand_distr::UnitDisc);
+                ((x - cu).powi(2) + (y - cv).powi(2) <= 1.0).then_some([x, y])
+            }
+        }
+    }
+}
+
+/// Pair of parallel cameras separated horizontally by the interocular
+/// distance, for stereoscopic rendering
+#[derive(Debug)]
+pub struct StereoRig {
+    pub left: Camera,
+    pub right: Camera,
+}
+
+impl StereoRig {
+    /// Build a rig centered on the camera described by `config`
+    pub fn new(config: &CameraConfig, interocular_distance: f64) -> Self {
+        let (u, _, _) = Camera::new(config).basis();
+        let offset = 0.5 * interocular_distance * u;
+
+        Self {
+            left: Camera::new(&CameraConfig {
+                position: config.position - offset,
+                ..config.clone()
+            }),
+            right: Camera::new(&CameraConfig {
+                position: config.position + offset,
+                ..config.clone()
+            }),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
-    use rand::{rngs, SeedableRng};
 
     use super::*;
 
@@ -280,6 +937,12 @@ mod tests {
             rotation: 0.0_f64,
             fov: FieldOfView::Horizontal(90f64.to_radians()),
             focus_mode: FocusMode::PinHole,
+            aperture_sampling: ApertureSampling::Uniform,
+            aperture_shape: ApertureShape::Disc,
+            anamorphic_squeeze: 1.0,
+            projection: Projection::Perspective,
+            up: glm::DVec3::y(),
+            shutter: Shutter::default(),
         };
         let camera = Camera::new(&config);
 
@@ -292,6 +955,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn project_points() {
+        let camera = Camera::new(&CameraConfig {
+            position: glm::DVec3::new(1.0, 2.0, 3.0),
+            direction: glm::DVec3::new(1.0, -0.5, 2.0),
+            resolution: (80, 60),
+            rotation: 0.3,
+            fov: FieldOfView::Vertical(50f64.to_radians()),
+            focus_mode: FocusMode::FocalPlane {
+                focal_distance: 4.0,
+                aperture: 0.1,
+            },
+            ..Default::default()
+        });
+        for (i, j) in [(0, 0), (79, 0), (40, 30), (13, 59)] {
+            let ray = camera.center_ray(i, j).unwrap();
+            let point = ray.origin + 7.5 * ray.direction;
+            assert_eq!(Some((i, j)), camera.project(&point));
+        }
+        let behind = camera.position() - camera.direction();
+        assert_eq!(None, camera.project(&behind));
+    }
+
     #[test]
     fn sample_aperture() {
         let aperture: f64 = 0.1;
@@ -305,6 +991,12 @@ mod tests {
                 focal_distance: 1.0,
                 aperture,
             },
+            aperture_sampling: ApertureSampling::Uniform,
+            aperture_shape: ApertureShape::Disc,
+            anamorphic_squeeze: 1.0,
+            projection: Projection::Perspective,
+            up: glm::DVec3::y(),
+            shutter: Shutter::default(),
         };
         let camera = Camera::new(&config);
 
@@ -322,6 +1014,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn jittered_ray_stays_inside_pixel() {
+        let config = CameraConfig {
+            position: glm::DVec3::zeros(),
+            direction: glm::DVec3::z(),
+            resolution: (2, 2),
+            rotation: 0.0_f64,
+            fov: FieldOfView::Horizontal(90f64.to_radians()),
+            focus_mode: FocusMode::PinHole,
+            aperture_sampling: ApertureSampling::Uniform,
+            aperture_shape: ApertureShape::Disc,
+            anamorphic_squeeze: 1.0,
+            projection: Projection::Perspective,
+            up: glm::DVec3::y(),
+            shutter: Shutter::default(),
+        };
+        let camera = Camera::new(&config);
+
+        let mut rng = rand::thread_rng();
+        let center = camera.cast_ray(0, 0, &mut rng).unwrap();
+        let left = camera
+            .cast_ray_jittered(0, 0, (-0.5, 0.0), &mut rng)
+            .unwrap();
+        let right = camera
+            .cast_ray_jittered(0, 0, (0.5, 0.0), &mut rng)
+            .unwrap();
+
+        // Pixel (0, 0) spans the left half of the image. The camera u axis
+        // points towards -x when looking along +z, so it covers x in [0, 1]
+        assert_relative_eq!(1.0, left.direction.x / left.direction.z, epsilon = 1e-12);
+        assert_relative_eq!(
+            0.5,
+            center.direction.x / center.direction.z,
+            epsilon = 1e-12
+        );
+        assert_relative_eq!(0.0, right.direction.x / right.direction.z, epsilon = 1e-12);
+    }
+
     #[test]
     fn default_camera_config() {
         let default_config = CameraConfig::default();
@@ -331,5 +1061,345 @@ mod tests {
         assert_eq!(default_config.rotation, 0.0);
         assert_eq!(default_config.fov, FieldOfView::default());
         assert_eq!(default_config.focus_mode, FocusMode::default());
+        assert_eq!(default_config.aperture_sampling, ApertureSampling::Uniform);
+    }
+
+    fn aperture_camera(aperture_sampling: ApertureSampling) -> Camera {
+        Camera::new(&CameraConfig {
+            position: glm::DVec3::zeros(),
+            direction: glm::DVec3::z(),
+            resolution: (100, 100),
+            focus_mode: FocusMode::FocalPlane {
+                focal_distance: 1.0,
+                aperture: 1.0,
+            },
+            aperture_sampling,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn gaussian_aperture_concentrates_samples() {
+        let camera = aperture_camera(ApertureSampling::Gaussian { sigma: 0.3 });
+
+        let mut rng = rand::thread_rng();
+        let n = 10000;
+        let mut inner = 0;
+        for _ in 0..n {
+            let origin = camera.cast_ray(50, 50, &mut rng).unwrap().origin;
+            assert!(origin.norm() <= 1.0);
+            if origin.norm() < 0.5 {
+                inner += 1;
+            }
+        }
+
+        // A uniform disc would put 25% of the samples within half the radius
+        assert!(inner as f64 / n as f64 > 0.6);
+    }
+
+    #[test]
+    fn cat_eye_clips_corner_aperture() {
+        let camera = aperture_camera(ApertureSampling::CatEye { strength: 1.0 });
+
+        let mut rng = rand::thread_rng();
+        let (u, v) = (camera.coordinate_system.u, camera.coordinate_system.v);
+        let mut max_towards_corner: f64 = 0.0;
+        for _ in 0..10000 {
+            let origin = camera.cast_ray(0, 0, &mut rng).unwrap().origin;
+            assert!(origin.norm() <= 1.0);
+            // Top left pixel: the clipping disc shifts towards +u, -v
+            let towards_corner = (-origin.dot(&u) + origin.dot(&v)) / 2.0_f64.sqrt();
+            max_towards_corner = max_towards_corner.max(towards_corner);
+        }
+
+        // At the corner the clipping disc is shifted by the full strength,
+        // leaving no aperture towards the corner itself
+        assert!(max_towards_corner < 0.05);
+    }
+
+    #[test]
+    fn invalid_aperture_sampling() {
+        let mut camera = Camera::default();
+        let config = CameraConfig {
+            direction: glm::DVec3::z(),
+            aperture_sampling: ApertureSampling::CatEye { strength: 2.0 },
+            ..Default::default()
+        };
+        assert!(camera.config(&config).is_err());
+
+        let config = CameraConfig {
+            direction: glm::DVec3::z(),
+            aperture_sampling: ApertureSampling::Gaussian { sigma: 0.0 },
+            ..Default::default()
+        };
+        assert!(camera.config(&config).is_err());
+
+        let config = CameraConfig {
+            direction: glm::DVec3::z(),
+            aperture_shape: ApertureShape::Polygon {
+                blades: 2,
+                rotation: 0.0,
+            },
+            ..Default::default()
+        };
+        assert!(camera.config(&config).is_err());
+
+        let config = CameraConfig {
+            direction: glm::DVec3::z(),
+            anamorphic_squeeze: 0.0,
+            ..Default::default()
+        };
+        assert!(camera.config(&config).is_err());
+
+        assert!(ApertureMask::new(2, 2, vec![1.0]).is_err());
+        assert!(ApertureMask::new(2, 1, vec![0.0, 0.0]).is_err());
+        assert!(ApertureMask::new(2, 1, vec![1.0, -1.0]).is_err());
+    }
+
+    #[test]
+    fn aperture_shapes() {
+        // Aperture points of a shape, in units of the aperture radius
+        let sample = |aperture_shape: ApertureShape, anamorphic_squeeze: f64| {
+            let camera = Camera::new(&CameraConfig {
+                direction: glm::DVec3::z(),
+                resolution: (100, 100),
+                focus_mode: FocusMode::FocalPlane {
+                    focal_distance: 1.0,
+                    aperture: 1.0,
+                },
+                aperture_shape,
+                anamorphic_squeeze,
+                ..Default::default()
+            });
+            let mut rng = rand::thread_rng();
+            let (u, v) = (camera.coordinate_system.u, camera.coordinate_system.v);
+            (0..10000)
+                .map(|_| {
+                    let origin = camera.cast_ray(50, 50, &mut rng).unwrap().origin;
+                    (origin.dot(&u), origin.dot(&v))
+                })
+                .collect::<Vec<_>>()
+        };
+        let max = |points: &[(f64, f64)], f: fn(&(f64, f64)) -> f64| {
+            points.iter().map(f).fold(f64::MIN, f64::max)
+        };
+
+        // Hexagon with corners at the right and left: flat top and bottom
+        let hexagon = ApertureShape::Polygon {
+            blades: 6,
+            rotation: 0.0,
+        };
+        let points = sample(hexagon.clone(), 1.0);
+        assert!(max(&points, |(x, _)| x.abs()) > 0.95);
+        assert!(max(&points, |(_, y)| y.abs()) <= (PI / 3.0).sin() + 1e-9);
+
+        // Anamorphic lenses squeeze it horizontally
+        let points = sample(hexagon, 2.0);
+        assert!(max(&points, |(x, _)| x.abs()) <= 0.5 + 1e-9);
+        assert!(max(&points, |(_, y)| y.abs()) > 0.8);
+
+        // Only the left half of the mask lets light in
+        let mask = ApertureMask::new(2, 1, vec![1.0, 0.0]).unwrap();
+        let points = sample(ApertureShape::Mask(Arc::new(mask)), 1.0);
+        assert!(max(&points, |(x, _)| *x) <= 1e-9);
+        assert!(max(&points, |(x, _)| -x) > 0.95);
+    }
+
+    #[test]
+    fn focus_at_depth() {
+        let mut camera = aperture_camera(ApertureSampling::Uniform);
+        camera.set_focus(FocusDepth::Depth(7.0)).unwrap();
+
+        // Rays through the same pixel converge on the plane z = 7
+        let mut rng = rand::thread_rng();
+        let expected = camera.center_ray(20, 70).unwrap();
+        let expected = expected.point_at(7.0 / expected.direction.z);
+        for _ in 0..100 {
+            let ray = camera.cast_ray(20, 70, &mut rng).unwrap();
+            let t = (7.0 - ray.origin.z) / ray.direction.z;
+            assert_relative_eq!(expected, ray.point_at(t), epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn focus_from_map() {
+        let mut camera = aperture_camera(ApertureSampling::Uniform);
+        let depths = (0..100 * 100)
+            .map(|n| if n < 5000 { 2.0 } else { 9.0 })
+            .collect();
+        let map = Arc::new(DepthMap::new(100, 100, depths).unwrap());
+        camera.set_focus(FocusDepth::Map(map.clone())).unwrap();
+
+        let mut rng = rand::thread_rng();
+        for (j, depth) in [(10, 2.0), (90, 9.0)] {
+            let expected = camera.center_ray(50, j).unwrap();
+            let expected = expected.point_at(depth / expected.direction.z);
+            let ray = camera.cast_ray(50, j, &mut rng).unwrap();
+            let t = (depth - ray.origin.z) / ray.direction.z;
+            assert_relative_eq!(expected, ray.point_at(t), epsilon = 1e-9);
+        }
+
+        assert!(camera.focus_on_pixel(&map, 50, 90).is_ok());
+        assert_eq!(FocusDepth::Depth(9.0), camera.focus_depth);
+        assert!(camera.focus_on_pixel(&map, 100, 0).is_err());
+    }
+
+    #[test]
+    fn look_straight_up_and_down() {
+        for direction in [glm::DVec3::y(), -glm::DVec3::y()] {
+            let camera = Camera::new(&CameraConfig {
+                direction,
+                ..Default::default()
+            });
+            let (u, v, w) = camera.basis();
+            assert!(u.iter().chain(v.iter()).all(|c| c.is_finite()));
+            assert_relative_eq!(0.0, u.dot(&w), epsilon = 1e-12);
+            assert_relative_eq!(0.0, v.dot(&w), epsilon = 1e-12);
+            assert_relative_eq!(0.0, u.dot(&v), epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn look_at_with_up() {
+        let mut camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (64, 32),
+            ..Default::default()
+        });
+        let eye = glm::DVec3::new(1.0, 2.0, 3.0);
+        let target = glm::DVec3::new(1.0, 2.0, -5.0);
+        camera.look_at(eye, target, glm::DVec3::x()).unwrap();
+
+        let (u, v, w) = camera.basis();
+        assert_eq!(eye, camera.position());
+        assert_eq!((64, 32), camera.resolution());
+        assert_relative_eq!(-glm::DVec3::z(), w, epsilon = 1e-12);
+        assert_relative_eq!(glm::DVec3::x(), v, epsilon = 1e-12);
+        assert_relative_eq!(-glm::DVec3::y(), u, epsilon = 1e-12);
+
+        // A degenerate up still gives an orthonormal basis
+        for up in [glm::DVec3::zeros(), glm::DVec3::new(0.0, 0.0, 2.0)] {
+            camera.look_at(eye, target, up).unwrap();
+            let (u, v, w) = camera.basis();
+            assert_relative_eq!(1.0, u.norm(), epsilon = 1e-12);
+            assert_relative_eq!(1.0, v.norm(), epsilon = 1e-12);
+            assert_relative_eq!(0.0, u.dot(&w), epsilon = 1e-12);
+            assert_relative_eq!(0.0, v.dot(&w), epsilon = 1e-12);
+        }
+
+        assert!(camera.look_at(eye, eye, glm::DVec3::y()).is_err());
+        assert!(CameraConfig::look_at(eye, eye, glm::DVec3::y()).is_err());
+    }
+
+    #[test]
+    fn motion_blur() {
+        let open = CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 12),
+            rotation: 0.1,
+            shutter: Shutter::new(0.0, 0.5).unwrap(),
+            ..Default::default()
+        };
+        let close = CameraConfig {
+            position: glm::DVec3::new(2.0, 0.0, 0.0),
+            direction: glm::DVec3::new(1.0, 0.2, 1.0),
+            rotation: 0.3,
+            ..open.clone()
+        };
+        let mut camera = Camera::new(&open);
+        camera.set_motion(&close).unwrap();
+
+        // At the closing the rays are those of the camera where it ends up
+        let end = Camera::new(&close);
+        for (i, j) in [(0, 0), (3, 7), (15, 11)] {
+            let ray = camera.ray_at(camera.center_ray(i, j).unwrap(), 0.5);
+            let expected = end.center_ray(i, j).unwrap();
+            assert_relative_eq!(expected.origin, ray.origin, epsilon = 1e-9);
+            assert_relative_eq!(expected.direction, ray.direction, epsilon = 1e-9);
+            assert_eq!(0.5, ray.time);
+        }
+        let ray = camera.ray_at(camera.center_ray(3, 7).unwrap(), 0.25);
+        assert_relative_eq!(glm::DVec3::x(), ray.origin, epsilon = 1e-9);
+
+        let mut rng = rand::thread_rng();
+        let times: Vec<f64> = (0..100)
+            .map(|_| camera.cast_ray(3, 7, &mut rng).unwrap().time)
+            .collect();
+        assert!(times.iter().all(|time| (0.0..=0.5).contains(time)));
+        assert!(times.iter().any(|time| *time != times[0]));
+
+        // Instant shutters keep every ray at the same time
+        let still = Camera::new(&CameraConfig {
+            shutter: Shutter::default(),
+            ..close
+        });
+        assert_eq!(0.0, still.cast_ray(3, 7, &mut rng).unwrap().time);
+        assert!(Shutter::new(0.5, 0.0).is_err());
+    }
+
+    #[test]
+    fn fisheye_projections() {
+        for projection in [
+            Projection::Equidistant,
+            Projection::Equisolid,
+            Projection::Stereographic,
+        ] {
+            let config = CameraConfig {
+                direction: glm::DVec3::y(),
+                resolution: (101, 101),
+                rotation: 0.2,
+                fov: FieldOfView::Vertical(180f64.to_radians()),
+                projection,
+                ..Default::default()
+            };
+            let camera = Camera::new(&config);
+            let angle = |i, j| {
+                let ray = camera.center_ray(i, j).unwrap();
+                let cos = ray.direction.normalize().dot(&camera.direction());
+                cos.clamp(-1.0, 1.0).acos()
+            };
+            // The middle looks ahead, the edges at right angles and the
+            // corners behind
+            assert_relative_eq!(0.0, angle(50, 50), epsilon = 1e-6);
+            assert_relative_eq!(PI / 2.0, angle(50, 0), epsilon = 0.02);
+            assert!(angle(0, 0) > PI / 2.0);
+
+            for (i, j) in [(0, 0), (100, 0), (50, 50), (13, 70)] {
+                let ray = camera.center_ray(i, j).unwrap();
+                assert_eq!(Some((i, j)), camera.project(&ray.point_at(3.0)));
+            }
+
+            let lens = CameraConfig {
+                focus_mode: FocusMode::FocalPlane {
+                    focal_distance: 1.0,
+                    aperture: 0.1,
+                },
+                ..config
+            };
+            assert!(Camera::default().config(&lens).is_err());
+        }
+    }
+
+    #[test]
+    fn stereo_rig_separation() {
+        let config = CameraConfig {
+            position: glm::DVec3::new(1.0, 2.0, 3.0),
+            direction: glm::DVec3::z(),
+            ..Default::default()
+        };
+        let rig = StereoRig::new(&config, 0.065);
+
+        let (u, _, _) = rig.left.basis();
+        assert_relative_eq!(
+            0.065,
+            (rig.right.position() - rig.left.position()).dot(&u),
+            epsilon = 1e-12
+        );
+        assert_relative_eq!(
+            config.position,
+            (rig.left.position() + rig.right.position()) / 2.0,
+            epsilon = 1e-12
+        );
+        assert_eq!(rig.left.direction(), rig.right.direction());
     }
 }
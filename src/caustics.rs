@@ -0,0 +1,412 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::Film;
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::object::Object;
+use crate::render::{get_closest_hit, SURFACE_OFFSET};
+use crate::sampling::{self, RngStream};
+use crate::scene::Scene;
+
+/// Approximate caustics with a photon map restricted to specular paths.
+///
+/// Photons are shot from the emitters and followed only through specular
+/// surfaces. They are stored where they land on the first diffuse surface,
+/// and photons that don't hit any specular surface are dropped. The density
+/// of the stored photons is then added on top of the path-traced image.
+///
+/// Only emitters whose shape can be sampled, like spheres and triangles,
+/// shoot photons, from the side their outward normal points to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CausticsPass {
+    pub photons: u32,           // Photons shot from all emitters
+    pub radius: f64,            // Gathering radius
+    pub max_depth: u32,         // Maximum number of specular bounces
+    pub samples_per_pixel: u32, // Camera rays used to gather each pixel
+}
+
+impl Default for CausticsPass {
+    fn default() -> Self {
+        Self {
+            photons: 200_000,
+            radius: 0.5,
+            max_depth: 8,
+            samples_per_pixel: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Photon {
+    pub position: glm::DVec3,
+    pub power: Color,
+}
+
+/// Photons on diffuse surfaces, hashed in a grid of cells as large as the
+/// gathering radius
+pub struct PhotonMap {
+    radius: f64,
+    cells: HashMap<(i64, i64, i64), Vec<Photon>>,
+}
+
+impl PhotonMap {
+    pub fn new(radius: f64, photons: Vec<Photon>) -> Self {
+        let mut cells: HashMap<_, Vec<Photon>> = HashMap::new();
+        for photon in photons {
+            cells
+                .entry(Self::cell(radius, &photon.position))
+                .or_default()
+                .push(photon);
+        }
+        Self { radius, cells }
+    }
+
+    fn cell(radius: f64, position: &glm::DVec3) -> (i64, i64, i64) {
+        let c = position / radius;
+        (c.x.floor() as i64, c.y.floor() as i64, c.z.floor() as i64)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Total power of the photons within the gathering radius of a point
+    pub fn gather(&self, position: &glm::DVec3) -> Color {
+        let (x, y, z) = Self::cell(self.radius, position);
+        let r2 = self.radius * self.radius;
+        let mut power = Color::zeros();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let Some(photons) = self.cells.get(&(x + dx, y + dy, z + dz)) else {
+                        continue;
+                    };
+                    for photon in photons {
+                        if (photon.position - position).norm_squared() <= r2 {
+                            power += photon.power;
+                        }
+                    }
+                }
+            }
+        }
+        power
+    }
+
+    /// Reflected radiance of a diffuse surface with the given reflectance
+    pub fn radiance(&self, position: &glm::DVec3, reflectance: &Color) -> Color {
+        let density = self.gather(position) / (PI * self.radius * self.radius);
+        reflectance.component_mul(&density) / PI
+    }
+}
+
+/// Emissive object that shoots photons, with its share of the photon budget
+struct Emitter {
+    object_id: usize,
+    flux: Color,
+    photons: u32,
+}
+
+impl CausticsPass {
+    /// Whether the pass shoots the light of an object as photons: objects
+    /// whose own material emits over all of their surface, which can be
+    /// sampled. The light of planes or of emissive face materials is left
+    /// to the paths.
+    pub(crate) fn emits_photons(object: &Object) -> bool {
+        object.material.emittance > 0.0
+            && object.materials.is_empty()
+            && object.shape.sample_surface(glm::DVec2::zeros()).is_some()
+    }
+
+    fn emitters(&self, scene: &Scene) -> Vec<Emitter> {
+        let mut emitters: Vec<Emitter> = scene
+            .objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| Self::emits_photons(object))
+            .filter_map(|(object_id, object)| {
+                let sample = object.shape.sample_surface(glm::DVec2::zeros())?;
+                let radiance = object.material.emittance * object.material.color;
+                Some(Emitter {
+                    object_id,
+                    flux: PI * sample.area * radiance,
+                    photons: 0,
+                })
+            })
+            .collect();
+
+        let total: f64 = emitters.iter().map(|e| e.flux.sum()).sum();
+        if total > 0.0 {
+            for emitter in &mut emitters {
+                emitter.photons = (self.photons as f64 * emitter.flux.sum() / total).round() as u32;
+            }
+        }
+        emitters
+    }
+
+    /// Shoot the photons and keep those that reach a diffuse surface
+    /// through at least one specular bounce. The same seed shoots the same
+    /// photons.
+    pub fn build_photon_map(&self, scene: &Scene, seed: u64) -> PhotonMap {
+        let photons = self
+            .emitters(scene)
+            .iter()
+            .enumerate()
+            .filter(|(_, emitter)| emitter.photons > 0)
+            .flat_map(|(index, emitter)| {
+                let power = emitter.flux / (emitter.photons as f64);
+                let stream = RngStream::Photons(index as u32);
+                (0..emitter.photons)
+                    .into_par_iter()
+                    .map(|n| {
+                        let mut rng = sampling::sample_rng(seed, stream, (n, 0), 0);
+                        self.trace_photon(scene, emitter.object_id, power, &mut rng)
+                    })
+                    .flatten()
+                    .collect::<Vec<Photon>>()
+            })
+            .collect();
+
+        PhotonMap::new(self.radius, photons)
+    }
+
+    fn trace_photon(
+        &self,
+        scene: &Scene,
+        object_id: usize,
+        mut power: Color,
+        rng: &mut impl Rng,
+    ) -> Option<Photon> {
+        let shape = &scene.objects[object_id].shape;
+        let sample = shape.sample_surface(glm::DVec2::new(rng.gen(), rng.gen()))?;
+        let (local, _) = sampling::cosine_hemisphere(rng);
+        let direction = sampling::to_world(&sample.normal, &local);
+        let mut ray = Ray::new(sample.point + SURFACE_OFFSET * direction, direction);
+
+        let mut inside = false;
+        for depth in 0..=self.max_depth {
+            let (record, object, id) = get_closest_hit(&scene.objects, &ray)?;
+            // The light reaches the camera through the first surface it hits
+            if depth == 0 && !scene.illuminates(object_id, id) {
+                return None;
+            }
+            let material = object.material_at(record.material);
+            let ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+
+            match material
+                .surface()
+                .scatter(&record.normal, &ray.direction, inside, rng)
+            {
+                Some((direction, transmitted)) => {
+                    inside ^= transmitted;
+                    power = power.component_mul(&(material.albedo(&ctx) / 255.0));
+                    ray = Ray::new(record.point + SURFACE_OFFSET * direction, direction);
+                }
+                // Only photons that went through a specular chain are caustics
+                None if depth > 0 => {
+                    return Some(Photon {
+                        position: record.point,
+                        power,
+                    })
+                }
+                None => return None,
+            }
+        }
+
+        None
+    }
+
+    /// Follow a camera ray through specular surfaces and estimate the caustic
+    /// radiance on the first diffuse surface
+    fn gather_ray(&self, scene: &Scene, map: &PhotonMap, ray: Ray, rng: &mut impl Rng) -> Color {
+        let mut ray = ray;
+        let mut weight = Color::new(1.0, 1.0, 1.0);
+        let mut inside = false;
+
+        for _ in 0..=self.max_depth {
+            let Some((record, object, id)) = get_closest_hit(&scene.objects, &ray) else {
+                return Color::zeros();
+            };
+            let material = object.material_at(record.material);
+            let albedo = material
+                .albedo(&ShadingContext::new(&record, id).viewed_along(&ray.direction))
+                / 255.0;
+
+            match material
+                .surface()
+                .scatter(&record.normal, &ray.direction, inside, rng)
+            {
+                Some((direction, transmitted)) => {
+                    inside ^= transmitted;
+                    weight = weight.component_mul(&albedo);
+                    ray = Ray::new(record.point + SURFACE_OFFSET * direction, direction);
+                }
+                None => return weight.component_mul(&map.radiance(&record.point, &albedo)),
+            }
+        }
+
+        Color::zeros()
+    }
+
+    /// Add the caustics seen by the camera on top of a rendered film
+    pub fn render(&self, scene: &Scene, camera: &Camera, seed: u64, film: &mut Film) {
+        let map = self.build_photon_map(scene, seed);
+        if map.is_empty() {
+            return;
+        }
+
+        let (w, h) = camera.resolution();
+        let spp = self.samples_per_pixel.max(1);
+        let overlay: Vec<Color> = (0..w * h)
+            .into_par_iter()
+            .map(|n| {
+                let (i, j) = (n % w, n / w);
+                let mut color = Color::zeros();
+                for sample in 0..spp {
+                    let mut rng = sampling::sample_rng(seed, RngStream::Gather, (i, j), sample);
+                    let ray = camera.cast_ray(i, j, &mut rng).expect("Expected a Ray");
+                    color += self.gather_ray(scene, &map, ray, &mut rng);
+                }
+                color / (spp as f64)
+            })
+            .collect();
+
+        film.update_pixels(|index, pixel| pixel.overlay += overlay[index]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::material::{Bsdf, Material, Specular};
+    use crate::shape::{Plane, Sphere};
+
+    fn glass_ball_scene(specular: Option<Specular>) -> Scene {
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 10.0, 0.0), 1.0)),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    emittance: 1.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                // Focal length of a ball lens: n * r / (2 * (n - 1)) = 1.5
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 2.5, 0.0), 1.0)),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    bsdf: specular.map(|specular| Arc::new(specular) as Arc<dyn Bsdf>),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 1.0, 0.0),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(128.0, 128.0, 128.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        scene
+    }
+
+    #[test]
+    fn glass_ball_focuses_light() {
+        let pass = CausticsPass {
+            photons: 20_000,
+            radius: 0.2,
+            ..Default::default()
+        };
+        let scene = glass_ball_scene(Some(Specular::Dielectric { ior: 1.5 }));
+        let map = pass.build_photon_map(&scene, 1);
+
+        // Photons land on the floor, or a few on the light after a reflection
+        assert!(!map.is_empty());
+        for photons in map.cells.values() {
+            for photon in photons {
+                let on_floor = (photon.position.y - 1.0).abs() < 1e-9;
+                let on_light =
+                    ((photon.position - glm::DVec3::new(0.0, 10.0, 0.0)).norm() - 1.0).abs() < 1e-6;
+                assert!(on_floor || on_light);
+            }
+        }
+
+        let focus = map.gather(&glm::DVec3::new(0.0, 1.0, 0.0));
+        let side = map.gather(&glm::DVec3::new(3.0, 1.0, 0.0));
+        assert!(focus.x > 0.0);
+        assert_eq!(Color::zeros(), side);
+    }
+
+    #[test]
+    fn photons_only_leave_sampled_emitters() {
+        let light = Material {
+            emittance: 1.0,
+            ..Default::default()
+        };
+        let sphere = || Box::new(Sphere::new(glm::DVec3::zeros(), 1.0));
+        let object = |shape, materials| Object {
+            shape,
+            material: light.clone(),
+            materials,
+        };
+
+        assert!(CausticsPass::emits_photons(&object(sphere(), Vec::new())));
+        // Planes can't be sampled, and photons would leave the faces of
+        // other materials with the light of the object
+        let plane = Box::new(Plane {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+        });
+        assert!(!CausticsPass::emits_photons(&object(plane, Vec::new())));
+        let faces = vec![Material::default()];
+        assert!(!CausticsPass::emits_photons(&object(sphere(), faces)));
+    }
+
+    #[test]
+    fn diffuse_scene_has_no_caustics() {
+        let pass = CausticsPass {
+            photons: 1000,
+            ..Default::default()
+        };
+        let map = pass.build_photon_map(&glass_ball_scene(None), 1);
+        assert!(map.is_empty());
+        assert_eq!(0, map.len());
+    }
+}
@@ -17,9 +17,69 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+/// Linear radiance, in units where 255 is the white of the output image
 pub type Color = glm::DVec3;
 
+/// Relative luminance of a linear sRGB color
+pub fn luminance(color: &Color) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// Encode a linear value in [0, 1] with the sRGB transfer function
+pub fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        12.92 * linear.max(0.0)
+    } else {
+        1.055 * linear.min(1.0).powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decode an sRGB encoded value in [0, 1] back to linear
+pub fn srgb_decode(encoded: f64) -> f64 {
+    if encoded <= 0.04045 {
+        encoded.max(0.0) / 12.92
+    } else {
+        ((encoded.min(1.0) + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Rotate the hue of a color by an angle in degrees, around the axis of
+/// grays. Channels that would turn negative are clipped to 0.
+pub fn rotate_hue(color: &Color, degrees: f64) -> Color {
+    let axis = glm::DVec3::repeat(1.0).normalize();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let rotated = color * cos + axis.cross(color) * sin + axis * axis.dot(color) * (1.0 - cos);
+    rotated.map(|channel| channel.max(0.0))
+}
+
 #[cfg(test)]
 mod test {
+    use approx::assert_relative_eq;
+
     use super::*;
+
+    #[test]
+    fn srgb_round_trip() {
+        for value in [0.0, 0.001, 0.0031308, 0.2, 0.5, 1.0] {
+            assert_relative_eq!(value, srgb_decode(srgb_encode(value)), epsilon = 1e-12);
+        }
+        // Mid grey is encoded well above half, which is why linear output looks dark
+        assert!((srgb_encode(0.18) - 0.4614).abs() < 1e-3);
+        assert_relative_eq!(1.0, srgb_encode(2.0));
+    }
+
+    #[test]
+    fn hue_rotation() {
+        let red = Color::new(255.0, 0.0, 0.0);
+        assert_relative_eq!(
+            Color::new(0.0, 255.0, 0.0),
+            rotate_hue(&red, 120.0),
+            epsilon = 1e-9
+        );
+        assert_relative_eq!(red, rotate_hue(&red, 360.0), epsilon = 1e-9);
+
+        // Grays have no hue to rotate
+        let gray = Color::repeat(128.0);
+        assert_relative_eq!(gray, rotate_hue(&gray, 37.0), epsilon = 1e-9);
+    }
 }
@@ -0,0 +1,323 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// In-memory storage of texture tiles, trading quality for memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFormat {
+    /// 3 bytes per texel, lossless
+    #[default]
+    Rgb8,
+    /// 256 colors per tile and 1 byte per texel. Lossless for tiles with few
+    /// colors, e.g. flat artwork and masks.
+    Palette,
+    /// Block compression in the style of BC1/DXT1: 4×4 blocks of two
+    /// endpoint colors and 2 bit indices, 0.5 bytes per texel. Lossy, fits
+    /// smooth photographic textures.
+    Bc1,
+}
+
+impl FromStr for TextureFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb8" => Ok(TextureFormat::Rgb8),
+            "palette" => Ok(TextureFormat::Palette),
+            "bc1" => Ok(TextureFormat::Bc1),
+            _ => Err(format!("Unknown texture format '{s}'")),
+        }
+    }
+}
+
+const BLOCK_SIZE: usize = 4;
+
+/// Square tile of RGB texels stored in one of the texture formats
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompressedTile {
+    Rgb8(Vec<u8>),
+    Palette {
+        colors: Vec<[u8; 3]>,
+        indices: Vec<u8>,
+    },
+    Bc1(Vec<u8>), // 8 bytes per block, blocks in scanline order
+}
+
+impl CompressedTile {
+    /// Compress a tile of `size`×`size` texels, given as packed RGB bytes
+    pub fn encode(format: TextureFormat, texels: &[u8], size: usize) -> Self {
+        debug_assert_eq!(3 * size * size, texels.len());
+        let texels: Vec<[u8; 3]> = texels
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+
+        match format {
+            TextureFormat::Rgb8 => CompressedTile::Rgb8(texels.concat()),
+            TextureFormat::Palette => {
+                let (colors, indices) = median_cut(&texels, 256);
+                CompressedTile::Palette { colors, indices }
+            }
+            TextureFormat::Bc1 => CompressedTile::Bc1(encode_bc1(&texels, size)),
+        }
+    }
+
+    /// Decode a single texel of a tile of `size`×`size` texels
+    pub fn texel(&self, x: usize, y: usize, size: usize) -> [u8; 3] {
+        match self {
+            CompressedTile::Rgb8(bytes) => {
+                let offset = 3 * (y * size + x);
+                [bytes[offset], bytes[offset + 1], bytes[offset + 2]]
+            }
+            CompressedTile::Palette { colors, indices } => colors[indices[y * size + x] as usize],
+            CompressedTile::Bc1(blocks) => {
+                let blocks_across = size.div_ceil(BLOCK_SIZE);
+                let block = (y / BLOCK_SIZE) * blocks_across + x / BLOCK_SIZE;
+                let bytes = &blocks[8 * block..8 * block + 8];
+                let endpoints = [
+                    unpack_565(u16::from_le_bytes([bytes[0], bytes[1]])),
+                    unpack_565(u16::from_le_bytes([bytes[2], bytes[3]])),
+                ];
+                let bits = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+                let texel = (y % BLOCK_SIZE) * BLOCK_SIZE + x % BLOCK_SIZE;
+                bc1_palette(endpoints)[(bits >> (2 * texel)) as usize & 3]
+            }
+        }
+    }
+
+    /// Bytes used by the tile data
+    pub fn memory_size(&self) -> usize {
+        match self {
+            CompressedTile::Rgb8(bytes) | CompressedTile::Bc1(bytes) => bytes.len(),
+            CompressedTile::Palette { colors, indices } => 3 * colors.len() + indices.len(),
+        }
+    }
+}
+
+/// Reduce colors to at most `max_colors` by splitting the color box with the
+/// widest channel range at its median, until there are enough boxes. The
+/// range of each box is found once, when the box is made, so each split only
+/// scans the texels of the box it splits.
+///
+/// Returns the palette and the palette index of each color.
+fn median_cut(texels: &[[u8; 3]], max_colors: usize) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let mut order: Vec<usize> = (0..texels.len()).collect();
+
+    // Widest channel range of a box, and its channel
+    let widest = |range: &Range<usize>, order: &[usize]| {
+        let mut min = [u8::MAX; 3];
+        let mut max = [u8::MIN; 3];
+        for &i in &order[range.clone()] {
+            for channel in 0..3 {
+                min[channel] = min[channel].min(texels[i][channel]);
+                max[channel] = max[channel].max(texels[i][channel]);
+            }
+        }
+        (0..3)
+            .map(|channel| (max[channel].saturating_sub(min[channel]), channel))
+            .max()
+            .unwrap()
+    };
+
+    // Ranges of `order`, with their widest channel range
+    let all = 0..texels.len();
+    let mut boxes = vec![(all.clone(), widest(&all, &order))];
+
+    while boxes.len() < max_colors {
+        let Some((index, (range, (_, channel)))) = boxes
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, (_, (extent, _)))| *extent > 0)
+            .max_by_key(|(_, (_, (extent, _)))| *extent)
+        else {
+            break; // Every box holds a single color
+        };
+
+        order[range.clone()].sort_unstable_by_key(|&i| texels[i][channel]);
+        let mut mid = range.start + range.len() / 2;
+        // Keep equal values on one side, so both halves are non-empty and
+        // single colors are never split
+        let value = texels[order[mid]][channel];
+        while mid > range.start && texels[order[mid - 1]][channel] == value {
+            mid -= 1;
+        }
+        if mid == range.start {
+            while texels[order[mid]][channel] == value {
+                mid += 1;
+            }
+        }
+        let (low, high) = (range.start..mid, mid..range.end);
+        boxes[index] = (low.clone(), widest(&low, &order));
+        boxes.push((high.clone(), widest(&high, &order)));
+    }
+
+    let mut colors = Vec::with_capacity(boxes.len());
+    let mut indices = vec![0_u8; texels.len()];
+    for (palette_index, (range, _)) in boxes.iter().enumerate() {
+        let mut sum = [0_usize; 3];
+        for &i in &order[range.clone()] {
+            for (total, value) in sum.iter_mut().zip(texels[i]) {
+                *total += value as usize;
+            }
+            indices[i] = palette_index as u8;
+        }
+        let n = range.len();
+        colors.push(sum.map(|total| ((total + n / 2) / n) as u8));
+    }
+    (colors, indices)
+}
+
+fn pack_565([r, g, b]: [u8; 3]) -> u16 {
+    let quantize = |value: u8, max: u32| ((value as u32 * max + 127) / 255) as u16;
+    (quantize(r, 31) << 11) | (quantize(g, 63) << 5) | quantize(b, 31)
+}
+
+fn unpack_565(value: u16) -> [u8; 3] {
+    let r = (value >> 11) & 0x1f;
+    let g = (value >> 5) & 0x3f;
+    let b = value & 0x1f;
+    // Replicate the high bits so that full intensity maps to 255
+    [
+        ((r << 3) | (r >> 2)) as u8,
+        ((g << 2) | (g >> 4)) as u8,
+        ((b << 3) | (b >> 2)) as u8,
+    ]
+}
+
+/// The two endpoints and the two colors between them
+fn bc1_palette([a, b]: [[u8; 3]; 2]) -> [[u8; 3]; 4] {
+    let mix =
+        |wa: u16, wb: u16| [0, 1, 2].map(|c| ((wa * a[c] as u16 + wb * b[c] as u16 + 1) / 3) as u8);
+    [a, b, mix(2, 1), mix(1, 2)]
+}
+
+fn distance2(a: [u8; 3], b: [u8; 3]) -> i32 {
+    (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2)).sum()
+}
+
+fn encode_bc1(texels: &[[u8; 3]], size: usize) -> Vec<u8> {
+    let blocks_across = size.div_ceil(BLOCK_SIZE);
+    let mut blocks = Vec::with_capacity(8 * blocks_across * blocks_across);
+
+    for by in 0..blocks_across {
+        for bx in 0..blocks_across {
+            let block: Vec<[u8; 3]> = (0..BLOCK_SIZE * BLOCK_SIZE)
+                .map(|i| {
+                    let x = (bx * BLOCK_SIZE + i % BLOCK_SIZE).min(size - 1);
+                    let y = (by * BLOCK_SIZE + i / BLOCK_SIZE).min(size - 1);
+                    texels[y * size + x]
+                })
+                .collect();
+
+            // The darkest and brightest texels approximate the principal
+            // axis of the block colors
+            let luma = |rgb: &[u8; 3]| 2 * rgb[0] as u32 + 5 * rgb[1] as u32 + rgb[2] as u32;
+            let endpoints = [
+                pack_565(*block.iter().min_by_key(|rgb| luma(rgb)).unwrap()),
+                pack_565(*block.iter().max_by_key(|rgb| luma(rgb)).unwrap()),
+            ];
+            let palette = bc1_palette(endpoints.map(unpack_565));
+
+            let bits = block.iter().enumerate().fold(0_u32, |bits, (i, rgb)| {
+                let nearest = (0..4).min_by_key(|&j| distance2(palette[j], *rgb)).unwrap();
+                bits | (nearest as u32) << (2 * i)
+            });
+
+            blocks.extend(endpoints[0].to_le_bytes());
+            blocks.extend(endpoints[1].to_le_bytes());
+            blocks.extend(bits.to_le_bytes());
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIZE: usize = 16;
+
+    fn decode(tile: &CompressedTile) -> Vec<[u8; 3]> {
+        (0..SIZE * SIZE)
+            .map(|i| tile.texel(i % SIZE, i / SIZE, SIZE))
+            .collect()
+    }
+
+    fn max_error(a: &[[u8; 3]], b: &[[u8; 3]]) -> i32 {
+        a.iter()
+            .zip(b)
+            .flat_map(|(a, b)| (0..3).map(move |c| (a[c] as i32 - b[c] as i32).abs()))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn formats_roundtrip() {
+        // Smooth gradient with more than 256 colors
+        let texels: Vec<[u8; 3]> = (0..SIZE * SIZE)
+            .map(|i| {
+                [
+                    (16 * (i % SIZE)) as u8,
+                    (16 * (i / SIZE)) as u8,
+                    (8 * (i % SIZE + i / SIZE)) as u8,
+                ]
+            })
+            .collect();
+        let bytes = texels.concat();
+
+        let rgb = CompressedTile::encode(TextureFormat::Rgb8, &bytes, SIZE);
+        assert_eq!(texels, decode(&rgb));
+        assert_eq!(3 * SIZE * SIZE, rgb.memory_size());
+
+        let palette = CompressedTile::encode(TextureFormat::Palette, &bytes, SIZE);
+        assert_eq!(256 * 3 + SIZE * SIZE, palette.memory_size());
+        assert!(max_error(&texels, &decode(&palette)) < 64);
+
+        let bc1 = CompressedTile::encode(TextureFormat::Bc1, &bytes, SIZE);
+        assert_eq!(SIZE * SIZE / 2, bc1.memory_size());
+        assert!(max_error(&texels, &decode(&bc1)) < 64);
+    }
+
+    #[test]
+    fn few_colors_are_exact() {
+        let colors = [[255, 0, 0], [0, 128, 255], [1, 2, 3]];
+        let texels: Vec<[u8; 3]> = (0..SIZE * SIZE).map(|i| colors[i * 7 % 3]).collect();
+
+        let palette = CompressedTile::encode(TextureFormat::Palette, &texels.concat(), SIZE);
+        assert_eq!(texels, decode(&palette));
+        assert!(matches!(&palette, CompressedTile::Palette { colors, .. } if colors.len() == 3));
+
+        // Flat blocks survive block compression up to 565 quantization
+        let flat = vec![[200, 100, 50]; SIZE * SIZE];
+        let bc1 = CompressedTile::encode(TextureFormat::Bc1, &flat.concat(), SIZE);
+        assert!(max_error(&flat, &decode(&bc1)) <= 4);
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!(Ok(TextureFormat::Bc1), "bc1".parse());
+        assert!("dxt5".parse::<TextureFormat>().is_err());
+    }
+}
@@ -0,0 +1,385 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::bluenoise::BlueNoiseMask;
+use crate::film::{Metering, ToneMapper};
+use crate::render::{PathTracer, TILE_SIZE};
+use crate::sampler::SamplerKind;
+use crate::texture;
+
+/// Renderer settings that can come from several sources. Each unset field
+/// falls back to the next source, in this order of precedence:
+///
+/// 1. Command line flags
+/// 2. The `"settings"` object of the scene file
+/// 3. The user config file, `~/.config/light/config.toml` on Linux
+/// 4. The built-in defaults of each command
+///
+/// Example config file:
+///
+/// ```toml
+/// threads = 8
+/// samples_per_pixel = 64
+/// max_depth = 6
+/// output_dir = "/home/me/renders"
+/// tone_mapper = "reinhard"
+/// auto_exposure = "center"
+/// tile_size = 64
+/// sampler = "sobol"
+/// blue_noise = true
+/// memory_budget = 16384
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples_per_pixel: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tone_mapper: Option<ToneMapper>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_exposure: Option<Metering>, // Meter the film and correct its exposure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>, // Seed of the random numbers, for reproducible renders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampler: Option<SamplerKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blue_noise: Option<bool>, // Spread the sample offsets with a blue-noise mask
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_budget: Option<usize>, // MiB the scene and film may take before a render
+}
+
+/// Named quality levels of a render, bundling the parameters that trade
+/// time for quality. Explicit flags override them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Seconds: a noisy, small image to check the framing and lighting
+    Draft,
+    /// Minutes: a clean enough image to judge materials
+    Preview,
+    /// The full resolution image, without shortcuts that bias it
+    Final,
+}
+
+impl Preset {
+    /// Samples per pixel and depth, as settings between the command line
+    /// flags and the scene file
+    pub fn settings(&self) -> Settings {
+        let (samples_per_pixel, max_depth) = match self {
+            Preset::Draft => (4, 3),
+            Preset::Preview => (32, 5),
+            Preset::Final => (512, 12),
+        };
+        Settings {
+            samples_per_pixel: Some(samples_per_pixel),
+            max_depth: Some(max_depth),
+            ..Default::default()
+        }
+    }
+
+    /// Limit of the radiance of the indirect light of each sample, see
+    /// `PathTracer::indirect_clamp`
+    pub fn indirect_clamp(&self) -> Option<f64> {
+        match self {
+            Preset::Draft => Some(1000.0),
+            Preset::Preview => Some(4000.0),
+            Preset::Final => None,
+        }
+    }
+
+    /// Whether the image goes through the denoiser, when there is one
+    pub fn denoise(&self) -> bool {
+        *self != Preset::Final
+    }
+
+    /// Fraction of the resolution of the camera that is rendered
+    pub fn resolution_scale(&self) -> f64 {
+        match self {
+            Preset::Draft => 0.25,
+            Preset::Preview => 0.5,
+            Preset::Final => 1.0,
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "draft" => Ok(Preset::Draft),
+            "preview" => Ok(Preset::Preview),
+            "final" => Ok(Preset::Final),
+            _ => Err(format!("Unknown preset '{s}'")),
+        }
+    }
+}
+
+/// Side of the blue-noise mask made from the settings
+pub const BLUE_NOISE_SIZE: u32 = 64;
+
+impl Settings {
+    /// Fill the unset fields with those of a lower precedence source
+    pub fn or(self, fallback: &Settings) -> Settings {
+        Settings {
+            threads: self.threads.or(fallback.threads),
+            samples_per_pixel: self.samples_per_pixel.or(fallback.samples_per_pixel),
+            max_depth: self.max_depth.or(fallback.max_depth),
+            output_dir: self.output_dir.or_else(|| fallback.output_dir.clone()),
+            tone_mapper: self.tone_mapper.or(fallback.tone_mapper),
+            auto_exposure: self.auto_exposure.or(fallback.auto_exposure),
+            seed: self.seed.or(fallback.seed),
+            tile_size: self.tile_size.or(fallback.tile_size),
+            sampler: self.sampler.or(fallback.sampler),
+            blue_noise: self.blue_noise.or(fallback.blue_noise),
+            memory_budget: self.memory_budget.or(fallback.memory_budget),
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, String> {
+        toml::from_str(source).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        Settings::deserialize(value).map_err(|e| format!("Invalid settings: {e}"))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        Self::from_toml(&source).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Location of the user config file, if the platform has a config
+    /// directory
+    pub fn user_config_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("light").join("config.toml"))
+    }
+
+    /// Load the user config file. A missing file is the same as an empty one.
+    pub fn load_user_config() -> Result<Self, String> {
+        match Self::user_config_path() {
+            Some(path) if path.exists() => Self::load(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Set the sampling settings that are given on a renderer
+    pub fn apply(&self, renderer: &mut PathTracer) {
+        if let Some(spp) = self.samples_per_pixel {
+            renderer.samples_per_pixel(spp);
+        }
+        if let Some(depth) = self.max_depth {
+            renderer.max_depth(depth);
+        }
+        if let Some(size) = self.tile_size {
+            renderer.tile_size(size);
+        }
+        if let Some(sampler) = self.sampler {
+            renderer.sampler(sampler);
+        }
+        if self.blue_noise.is_some() {
+            renderer.blue_noise(self.blue_noise_mask());
+        }
+    }
+
+    /// Size the global thread pool. This only works before anything is
+    /// rendered.
+    pub fn init_threads(&self) -> Result<(), String> {
+        match self.threads {
+            Some(threads) => rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build_global()
+                .map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Path of an output file, inside the output directory if there is one
+    pub fn output_path<P: AsRef<Path>>(&self, file: P) -> PathBuf {
+        match &self.output_dir {
+            Some(dir) => dir.join(file),
+            None => file.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn tone_mapper(&self) -> ToneMapper {
+        self.tone_mapper.unwrap_or_default()
+    }
+
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size.unwrap_or(TILE_SIZE)
+    }
+
+    pub fn sampler(&self) -> SamplerKind {
+        self.sampler.unwrap_or_default()
+    }
+
+    /// Budget of the texture cache, in bytes: its share of the memory
+    /// budget, or the default budget without one
+    pub fn texture_budget(&self) -> usize {
+        self.memory_budget.map_or(texture::DEFAULT_BUDGET, |mib| {
+            texture::budget_share(mib << 20)
+        })
+    }
+
+    /// Blue-noise mask for the sample offsets, if enabled. The mask is
+    /// generated from the seed, so seeded renders stay reproducible.
+    pub fn blue_noise_mask(&self) -> Option<Arc<BlueNoiseMask>> {
+        self.blue_noise.unwrap_or(false).then(|| {
+            Arc::new(BlueNoiseMask::generate(
+                BLUE_NOISE_SIZE,
+                self.seed.unwrap_or(0),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config_file() {
+        let settings = Settings::from_toml(
+            r#"
+                threads = 4
+                samples_per_pixel = 128
+                output_dir = "renders"
+                tone_mapper = "reinhard"
+                auto_exposure = "average"
+                seed = 42
+                tile_size = 16
+                sampler = "halton"
+                blue_noise = true
+                memory_budget = 4096
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Settings {
+                threads: Some(4),
+                samples_per_pixel: Some(128),
+                max_depth: None,
+                output_dir: Some(PathBuf::from("renders")),
+                tone_mapper: Some(ToneMapper::Reinhard),
+                auto_exposure: Some(Metering::Average),
+                seed: Some(42),
+                tile_size: Some(16),
+                sampler: Some(SamplerKind::Halton),
+                blue_noise: Some(true),
+                memory_budget: Some(4096),
+            },
+            settings
+        );
+        assert_eq!(
+            PathBuf::from("renders/out.png"),
+            settings.output_path("out.png")
+        );
+
+        assert!(Settings::from_toml("spp = 3").is_err());
+        assert!(Settings::from_toml(r#"tone_mapper = "filmic""#).is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let cli = Settings {
+            samples_per_pixel: Some(8),
+            ..Default::default()
+        };
+        let scene = Settings {
+            samples_per_pixel: Some(64),
+            max_depth: Some(3),
+            ..Default::default()
+        };
+        let user = Settings {
+            max_depth: Some(10),
+            threads: Some(2),
+            tone_mapper: Some(ToneMapper::Reinhard),
+            ..Default::default()
+        };
+
+        let settings = cli.or(&scene).or(&user);
+        assert_eq!(Some(8), settings.samples_per_pixel);
+        assert_eq!(Some(3), settings.max_depth);
+        assert_eq!(Some(2), settings.threads);
+        assert_eq!(ToneMapper::Reinhard, settings.tone_mapper());
+        assert_eq!(None, settings.output_dir);
+        assert_eq!(TILE_SIZE, settings.tile_size());
+        assert!(settings.blue_noise_mask().is_none());
+    }
+
+    #[test]
+    fn presets() {
+        let cli = Settings {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let scene = Settings {
+            samples_per_pixel: Some(64),
+            ..Default::default()
+        };
+        let settings = cli.or(&Preset::Draft.settings()).or(&scene);
+        assert_eq!(Some(4), settings.samples_per_pixel);
+        assert_eq!(Some(2), settings.max_depth);
+
+        let parse = |name: &str| name.parse::<Preset>().unwrap();
+        let (draft, preview, last) = (parse("draft"), parse("preview"), parse("final"));
+        assert!(draft.settings().samples_per_pixel < preview.settings().samples_per_pixel);
+        assert!(preview.resolution_scale() < last.resolution_scale());
+        assert_eq!(None, last.indirect_clamp());
+        assert!("best".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn blue_noise_follows_the_seed() {
+        let settings = Settings {
+            blue_noise: Some(true),
+            seed: Some(3),
+            ..Default::default()
+        };
+        let mask = settings.blue_noise_mask().unwrap();
+        assert_eq!(BlueNoiseMask::generate(BLUE_NOISE_SIZE, 3), *mask);
+
+        let mut renderer = PathTracer::new();
+        settings.apply(&mut renderer);
+        assert_eq!(
+            mask.pixel_offset(1, 2, 3),
+            renderer.pixel_offset(1, 2, 3, 0)
+        );
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Fake contact shadows for the quick previews: an object on a turntable
+//! over an invisible floor floats unless something darkens the floor under
+//! it, and without global illumination its own crevices look flat.
+//!
+//! A grounded object rests on an invisible horizontal floor at the bottom of
+//! its bounds. The background seen through that floor is darkened by how
+//! much the object hides the sky from it, and, in the geometry preview, the
+//! surface of the object by its ambient occlusion.
+
+use rand::Rng;
+
+use crate::aov::ambient_occlusion;
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::sampling::{cosine_hemisphere, to_world};
+use crate::scene::Scene;
+
+/// Occlusion rays per shaded point
+pub const RAYS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContactShadow {
+    pub object: usize, // Index of the grounded object in the scene
+    pub distance: f64, // Occluders further away don't darken
+    pub strength: f64, // Darkening of fully occluded points, in [0, 1]
+}
+
+impl ContactShadow {
+    pub fn new(object: usize) -> Self {
+        Self {
+            object,
+            distance: 1.0,
+            strength: 0.8,
+        }
+    }
+
+    /// Height of the invisible floor, none for unbounded objects
+    pub fn floor(&self, scene: &Scene) -> Option<f64> {
+        let bounds = scene.objects[self.object].shape.bounds();
+        bounds.is_finite().then_some(bounds.min.y)
+    }
+
+    /// Darkening factor at a point of the floor, from the fraction of
+    /// cosine weighted rays that the object stops
+    fn floor_shade(&self, scene: &Scene, point: &glm::DVec3, rng: &mut impl Rng) -> f64 {
+        let shape = &scene.objects[self.object].shape;
+        let blocked = (0..RAYS)
+            .filter(|_| {
+                let direction = to_world(&glm::DVec3::y(), &cosine_hemisphere(rng).0);
+                shape
+                    .intersect(&Ray::new(*point, direction))
+                    .is_some_and(|record| record.ray_t < self.distance)
+            })
+            .count();
+        1.0 - self.strength * blocked as f64 / RAYS as f64
+    }
+}
+
+/// Factor that darkens what a camera ray sees: the floors of the grounded
+/// objects that the ray crosses before its hit, if any, and the surface of
+/// a grounded object that it hits
+pub fn attenuation(
+    scene: &Scene,
+    ray: &Ray,
+    hit: Option<&ShadingContext>,
+    rng: &mut impl Rng,
+) -> f64 {
+    let distance = hit.map_or(f64::INFINITY, |ctx| {
+        glm::distance(&ray.origin, &ctx.position)
+    });
+    let mut factor = floor_attenuation(scene, ray, distance, rng);
+    if let Some(ctx) = hit {
+        if let Some(shadow) = scene.contact_shadow(ctx.object_id) {
+            let open = ambient_occlusion(&scene.objects, ctx, shadow.distance, RAYS, rng);
+            factor *= 1.0 - shadow.strength * (1.0 - open);
+        }
+    }
+    factor
+}
+
+/// Darkening of the floors that a ray crosses from above before `distance`.
+/// Integrators with global illumination shade the surfaces themselves and
+/// only need this.
+pub fn floor_attenuation(scene: &Scene, ray: &Ray, distance: f64, rng: &mut impl Rng) -> f64 {
+    let mut factor = 1.0;
+    for shadow in &scene.contact_shadows {
+        let Some(floor) = shadow.floor(scene) else {
+            continue;
+        };
+        let t = (floor - ray.origin.y) / ray.direction.y;
+        if ray.origin.y > floor && t > 0.0 && t < distance {
+            factor *= shadow.floor_shade(scene, &ray.point_at(t), rng);
+        }
+    }
+    factor
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::Sphere;
+
+    #[test]
+    fn shadow_under_a_ball() {
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 1.0, 0.0), 1.0)),
+            material: Material::default(),
+            materials: Vec::new(),
+        });
+        scene.contact_shadows.push(ContactShadow {
+            strength: 1.0,
+            ..ContactShadow::new(0)
+        });
+        assert_eq!(Some(0.0), scene.contact_shadows[0].floor(&scene));
+        let mut rng = rand::thread_rng();
+        let down = |x: f64| {
+            Ray::new(
+                glm::DVec3::new(x, 5.0, -5.0),
+                glm::DVec3::new(0.0, -5.0, 5.0),
+            )
+        };
+
+        // Next to the point of contact the floor is dark, far away it isn't
+        let near = (0..20)
+            .map(|_| attenuation(&scene, &down(0.5), None, &mut rng))
+            .sum::<f64>();
+        assert!(near / 20.0 < 0.6);
+        assert_eq!(1.0, attenuation(&scene, &down(5.0), None, &mut rng));
+        // Rays looking up never cross the floor
+        let up = Ray::new(glm::DVec3::new(5.0, 0.5, 0.0), glm::DVec3::y());
+        assert_eq!(1.0, attenuation(&scene, &up, None, &mut rng));
+    }
+}
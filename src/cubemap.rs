@@ -0,0 +1,331 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use image::RgbImage;
+
+use crate::camera::{Camera, CameraConfig, FieldOfView, FocusMode};
+use crate::color::Color;
+use crate::render::PathTracer;
+use crate::scene::Scene;
+
+pub const NUM_FACES: usize = 6;
+
+/// Header of the cube map file format, followed by the face size as a
+/// little endian u32 and the faces as little endian f32 RGB triplets
+const MAGIC: &[u8; 4] = b"LCUB";
+
+/// Facing direction of each face: +x, -x, +y, -y, +z, -z
+pub const FACE_DIRECTIONS: [glm::DVec3; NUM_FACES] = [
+    glm::DVec3::new(1.0, 0.0, 0.0),
+    glm::DVec3::new(-1.0, 0.0, 0.0),
+    glm::DVec3::new(0.0, 1.0, 0.0),
+    glm::DVec3::new(0.0, -1.0, 0.0),
+    glm::DVec3::new(0.0, 0.0, 1.0),
+    glm::DVec3::new(0.0, 0.0, -1.0),
+];
+
+/// Environment captured on the six faces of a cube, usable as image based
+/// lighting
+#[derive(Debug, Clone)]
+pub struct CubeMap {
+    size: u32,
+    faces: Vec<Vec<Color>>,                           // Scanline order
+    bases: Vec<(glm::DVec3, glm::DVec3, glm::DVec3)>, // (u, v, w) of each face camera
+}
+
+impl CubeMap {
+    /// Cameras for the six faces: square images with a 90° field of view
+    pub fn face_cameras(position: glm::DVec3, size: u32) -> Vec<Camera> {
+        FACE_DIRECTIONS
+            .iter()
+            .map(|direction| {
+                Camera::new(&CameraConfig {
+                    position,
+                    direction: *direction,
+                    resolution: (size, size),
+                    fov: FieldOfView::Vertical(90.0_f64.to_radians()),
+                    focus_mode: FocusMode::PinHole,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    /// Render the environment seen from `position`. All faces are rendered
+    /// with the same renderer settings, so that there are no seams in noise
+    /// level between them.
+    pub fn render(renderer: &PathTracer, scene: &Scene, position: glm::DVec3, size: u32) -> Self {
+        let faces = Self::face_cameras(position, size)
+            .iter()
+            .map(|camera| {
+                let film = renderer.render_film(scene, camera);
+                film.pixels().map(|pixel| pixel.color()).collect()
+            })
+            .collect();
+
+        Self::from_faces(size, faces).expect("Rendered faces match the cube map size")
+    }
+
+    /// Bytes `render` takes: the six faces, and the film of the face being
+    /// rendered
+    pub fn render_memory(renderer: &PathTracer, size: u32) -> usize {
+        let face = &Self::face_cameras(glm::DVec3::zeros(), size)[0];
+        NUM_FACES * (size as usize).pow(2) * std::mem::size_of::<Color>()
+            + renderer.film_memory(face, false)
+    }
+
+    /// Build from six square faces of `size` × `size` pixels, in the order
+    /// of `FACE_DIRECTIONS` and with the orientation of `face_cameras`
+    pub fn from_faces(size: u32, faces: Vec<Vec<Color>>) -> Result<Self, &'static str> {
+        if size == 0 {
+            return Err("The cube map size cannot be zero");
+        }
+        if faces.len() != NUM_FACES {
+            return Err("A cube map needs exactly six faces");
+        }
+        if faces
+            .iter()
+            .any(|face| face.len() != (size * size) as usize)
+        {
+            return Err("Cube map faces must be square images of the cube map size");
+        }
+
+        let bases = Self::face_cameras(glm::DVec3::zeros(), 1)
+            .iter()
+            .map(|camera| camera.basis())
+            .collect();
+
+        Ok(Self { size, faces, bases })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Memory used by the texels of all faces, in bytes
+    pub fn memory_size(&self) -> usize {
+        self.faces.iter().map(Vec::len).sum::<usize>() * std::mem::size_of::<Color>()
+    }
+
+    pub fn face(&self, index: usize) -> &[Color] {
+        &self.faces[index]
+    }
+
+    fn texel(&self, face: usize, i: i64, j: i64) -> Color {
+        let max = (self.size - 1) as i64;
+        let (i, j) = (i.clamp(0, max), j.clamp(0, max));
+        self.faces[face][(j * (self.size as i64) + i) as usize]
+    }
+
+    /// Radiance arriving from `direction`, bilinearly interpolated
+    pub fn sample(&self, direction: &glm::DVec3) -> Color {
+        let face = (0..NUM_FACES)
+            .max_by(|a, b| {
+                let da = direction.dot(&self.bases[*a].2);
+                let db = direction.dot(&self.bases[*b].2);
+                da.total_cmp(&db)
+            })
+            .unwrap();
+
+        // Project on the face plane: x, y in [-1, 1]
+        let (u, v, w) = &self.bases[face];
+        let depth = direction.dot(w);
+        let (x, y) = (direction.dot(u) / depth, direction.dot(v) / depth);
+
+        // Continuous pixel coordinates, pixel centers at integer + 0.5
+        let size = self.size as f64;
+        let px = (x + 1.0) / 2.0 * size - 0.5;
+        let py = (1.0 - y) / 2.0 * size - 0.5;
+        let (i, j) = (px.floor(), py.floor());
+        let (tx, ty) = (px - i, py - j);
+        let (i, j) = (i as i64, j as i64);
+
+        let top = (1.0 - tx) * self.texel(face, i, j) + tx * self.texel(face, i + 1, j);
+        let bottom = (1.0 - tx) * self.texel(face, i, j + 1) + tx * self.texel(face, i + 1, j + 1);
+        (1.0 - ty) * top + ty * bottom
+    }
+
+    pub fn face_image(&self, face: usize) -> RgbImage {
+        let mut image = RgbImage::new(self.size, self.size);
+        for (color, rgb) in self.faces[face].iter().zip(image.pixels_mut()) {
+            rgb[0] = color.x.clamp(0.0, 255.0) as u8;
+            rgb[1] = color.y.clamp(0.0, 255.0) as u8;
+            rgb[2] = color.z.clamp(0.0, 255.0) as u8;
+        }
+        image
+    }
+
+    /// Write the cube map in its own binary format, keeping the full
+    /// dynamic range of the faces
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(8 + NUM_FACES * self.faces[0].len() * 12);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        for color in self.faces.iter().flatten() {
+            for channel in color.iter() {
+                bytes.extend_from_slice(&(*channel as f32).to_le_bytes());
+            }
+        }
+        writer.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+
+        if bytes.len() < 8 || &bytes[0..4] != MAGIC {
+            return Err("Not a cube map file".to_string());
+        }
+        let size = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let face_len = (size as usize) * (size as usize);
+        let data = &bytes[8..];
+        if data.len() != NUM_FACES * face_len * 12 {
+            return Err("Truncated cube map file".to_string());
+        }
+
+        let colors: Vec<Color> = data
+            .chunks_exact(12)
+            .map(|texel| {
+                let channel =
+                    |k: usize| f32::from_le_bytes(texel[4 * k..4 * k + 4].try_into().unwrap());
+                Color::new(channel(0) as f64, channel(1) as f64, channel(2) as f64)
+            })
+            .collect();
+        let faces = colors
+            .chunks(face_len.max(1))
+            .map(<[Color]>::to_vec)
+            .collect();
+
+        Self::from_faces(size, faces).map_err(str::to_string)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut file = fs::File::create(path)
+            .map_err(|e| format!("Couldn't create {}: {e}", path.display()))?;
+        self.write(&mut file)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut file =
+            fs::File::open(path).map_err(|e| format!("Couldn't open {}: {e}", path.display()))?;
+        Self::read(&mut file).map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::light::Ray;
+
+    fn face_color(face: usize) -> Color {
+        Color::new(face as f64, 10.0 * face as f64, 100.0)
+    }
+
+    #[test]
+    fn sample_face_centers() {
+        let faces = (0..NUM_FACES)
+            .map(|face| vec![face_color(face); 16])
+            .collect();
+        let cube_map = CubeMap::from_faces(4, faces).unwrap();
+
+        for (face, direction) in FACE_DIRECTIONS.iter().enumerate() {
+            assert_eq!(face_color(face), cube_map.sample(direction));
+            assert_eq!(face_color(face), cube_map.sample(&(3.0 * direction)));
+        }
+    }
+
+    #[test]
+    fn sample_matches_face_cameras() {
+        // Encode the pixel index in each texel and check that a camera ray
+        // through a pixel center reads back that same texel
+        let size = 8;
+        let faces = (0..NUM_FACES)
+            .map(|face| {
+                (0..size * size)
+                    .map(|n| Color::new(face as f64, n as f64, 0.0))
+                    .collect()
+            })
+            .collect();
+        let cube_map = CubeMap::from_faces(size, faces).unwrap();
+
+        for (face, camera) in CubeMap::face_cameras(glm::DVec3::zeros(), size)
+            .iter()
+            .enumerate()
+        {
+            for (i, j) in [(0, 0), (3, 5), (7, 7), (6, 1)] {
+                let ray: Ray = camera.center_ray(i, j).unwrap();
+                let color = cube_map.sample(&ray.direction);
+                assert_relative_eq!(face as f64, color.x, epsilon = 1e-9);
+                assert_relative_eq!((j * size + i) as f64, color.y, epsilon = 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn render_empty_scene() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 2.0, 3.0);
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(1);
+
+        let cube_map = CubeMap::render(&renderer, &scene, glm::DVec3::zeros(), 4);
+        assert_eq!(
+            Color::new(1.0, 2.0, 3.0),
+            cube_map.sample(&glm::DVec3::new(0.3, -0.2, 0.9))
+        );
+    }
+
+    #[test]
+    fn write_and_read() {
+        let faces = (0..NUM_FACES)
+            .map(|face| {
+                (0..4)
+                    .map(|n| Color::new(face as f64, n as f64, 1000.5))
+                    .collect()
+            })
+            .collect();
+        let cube_map = CubeMap::from_faces(2, faces).unwrap();
+
+        let mut bytes = Vec::new();
+        cube_map.write(&mut bytes).unwrap();
+        let read = CubeMap::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(2, read.size());
+        for face in 0..NUM_FACES {
+            assert_eq!(cube_map.face(face), read.face(face));
+        }
+
+        assert!(CubeMap::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(CubeMap::read(&mut &b"nope"[..]).is_err());
+    }
+
+    #[test]
+    fn invalid_faces() {
+        assert!(CubeMap::from_faces(2, vec![vec![Color::zeros(); 4]; 5]).is_err());
+        assert!(CubeMap::from_faces(2, vec![vec![Color::zeros(); 3]; 6]).is_err());
+        assert!(CubeMap::from_faces(0, vec![vec![]; 6]).is_err());
+    }
+}
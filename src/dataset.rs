@@ -0,0 +1,407 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Synthetic training data: renders together with per-pixel ground truth and
+//! a machine-readable manifest describing every file.
+//!
+//! Each view of a frame is written as:
+//!
+//! - `<frame>_rgb.png`: the render
+//! - `<frame>_depth.exr`: depth along the viewing axis, 32 bit float, 0 where
+//!   there is no geometry
+//! - `<frame>_normal.png`: world space normals facing the camera, mapped
+//!   from [-1, 1] to [0, 255], black where there is no geometry
+//! - `<frame>_instances.png`: 16 bit instance ids, the object index plus one,
+//!   0 for the background
+//!
+//! Stereo datasets hold a left and a right view per frame and a
+//! `<frame>_disparity.exr` of the left view, in pixels.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use exr::prelude::{
+    AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec,
+    WritableImage,
+};
+use image::{ImageBuffer, Luma, RgbImage};
+use rayon::prelude::*;
+use serde_json::{json, Value};
+
+use crate::camera::{Camera, CameraConfig, StereoRig};
+use crate::film::ToneMapper;
+use crate::output;
+use crate::render::{get_closest_hit, PathTracer};
+use crate::scene::Scene;
+
+/// Ground truth seen through the pixel centers of a camera, in scanline
+/// order
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroundTruth {
+    pub width: u32,
+    pub height: u32,
+    pub depths: Vec<f64>, // Along the viewing axis, infinite for the background
+    pub normals: Vec<glm::DVec3>, // World space, facing the camera, zero for the background
+    pub instances: Vec<u32>, // Object index plus one, 0 for the background
+}
+
+impl GroundTruth {
+    pub fn render(scene: &Scene, camera: &Camera) -> Self {
+        let (width, height) = camera.resolution();
+        let pixels: Vec<(f64, glm::DVec3, u32)> = (0..width * height)
+            .into_par_iter()
+            .map(|index| {
+                let ray = camera
+                    .center_ray(index % width, index / width)
+                    .expect("Expected a Ray");
+                match get_closest_hit(&scene.objects, &ray) {
+                    Some((record, _, id)) => {
+                        let normal = if record.normal.dot(&ray.direction) > 0.0 {
+                            -record.normal
+                        } else {
+                            record.normal
+                        };
+                        let depth = record.ray_t * ray.direction.dot(&camera.direction());
+                        (depth, normal, id as u32 + 1)
+                    }
+                    None => (f64::INFINITY, glm::DVec3::zeros(), 0),
+                }
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            depths: pixels.iter().map(|pixel| pixel.0).collect(),
+            normals: pixels.iter().map(|pixel| pixel.1).collect(),
+            instances: pixels.iter().map(|pixel| pixel.2).collect(),
+        }
+    }
+
+    /// Horizontal shift of each pixel between the views of a stereo rig
+    /// whose cameras are `baseline` apart, for a view with this focal length
+    pub fn disparity(&self, focal_length_pixels: f64, baseline: f64) -> Vec<f64> {
+        self.depths
+            .iter()
+            .map(|depth| focal_length_pixels * baseline / depth)
+            .collect()
+    }
+
+    fn normal_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |i, j| {
+            let normal = self.normals[(j * self.width + i) as usize];
+            if normal == glm::DVec3::zeros() {
+                return image::Rgb([0, 0, 0]);
+            }
+            image::Rgb([normal.x, normal.y, normal.z].map(|c| (127.5 * (c + 1.0)).round() as u8))
+        })
+    }
+
+    fn instance_image(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        ImageBuffer::from_fn(self.width, self.height, |i, j| {
+            let id = self.instances[(j * self.width + i) as usize];
+            Luma([id.min(u16::MAX as u32) as u16])
+        })
+    }
+}
+
+/// Writes the frames of a dataset into a directory, see the module
+/// documentation for the files of each frame
+pub struct DatasetWriter {
+    directory: PathBuf,
+    baseline: Option<f64>, // Distance between the cameras of stereo datasets
+    tone_mapper: ToneMapper,
+    frames: Vec<Value>,
+}
+
+impl DatasetWriter {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            baseline: None,
+            tone_mapper: ToneMapper::default(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Render each frame from a pair of cameras `baseline` apart
+    pub fn stereo(&mut self, baseline: f64) -> &mut Self {
+        self.baseline = Some(baseline);
+        self
+    }
+
+    pub fn tone_mapper(&mut self, tone_mapper: ToneMapper) -> &mut Self {
+        self.tone_mapper = tone_mapper;
+        self
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Bytes a frame takes while it's written: the film of a view and its
+    /// ground truth, which stereo rigs keep for both views along with their
+    /// disparity
+    pub fn frame_memory(&self, renderer: &PathTracer, config: &CameraConfig) -> usize {
+        let camera = Camera::new(config);
+        let (width, height) = camera.resolution();
+        let pixels = width as usize * height as usize;
+        let truth = pixels
+            * (std::mem::size_of::<f64>()
+                + std::mem::size_of::<glm::DVec3>()
+                + std::mem::size_of::<u32>());
+        let film = renderer.film_memory(&camera, false);
+        match self.baseline {
+            None => film + truth,
+            Some(_) => film + 2 * truth + pixels * std::mem::size_of::<f64>(),
+        }
+    }
+
+    /// Render and write the next frame, seen from the camera described by
+    /// `config` or from a stereo rig centered on it
+    pub fn write_frame(
+        &mut self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        config: &CameraConfig,
+    ) -> Result<(), String> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("{}: {e}", self.directory.display()))?;
+        let name = format!("frame_{:04}", self.frames.len() + 1);
+
+        let mut frame = json!({ "index": self.frames.len() });
+        match self.baseline {
+            None => {
+                let camera = Camera::new(config);
+                let (view, _) = self.write_view(renderer, scene, &camera, &name)?;
+                frame["views"] = json!({ "center": view });
+            }
+            Some(baseline) => {
+                let rig = StereoRig::new(config, baseline);
+                let left_name = format!("{name}_left");
+                let (left, truth) = self.write_view(renderer, scene, &rig.left, &left_name)?;
+                let (right, _) =
+                    self.write_view(renderer, scene, &rig.right, &format!("{name}_right"))?;
+
+                let disparity = truth.disparity(rig.left.focal_length_pixels(), baseline);
+                let file = format!("{name}_disparity.exr");
+                write_float_image(&self.directory.join(&file), &truth, &disparity)?;
+                frame["views"] = json!({ "left": left, "right": right });
+                frame["disparity"] = file.into();
+            }
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    fn write_view(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+        name: &str,
+    ) -> Result<(Value, GroundTruth), String> {
+        let files = ["rgb.png", "depth.exr", "normal.png", "instances.png"]
+            .map(|suffix| format!("{name}_{suffix}"));
+        let path = |n: usize| self.directory.join(&files[n]);
+
+        output::render_png(renderer, scene, camera, path(0), self.tone_mapper)?;
+        let truth = GroundTruth::render(scene, camera);
+        write_float_image(&path(1), &truth, &truth.depths)?;
+        truth
+            .normal_image()
+            .save(path(2))
+            .map_err(|e| format!("{}: {e}", path(2).display()))?;
+        truth
+            .instance_image()
+            .save(path(3))
+            .map_err(|e| format!("{}: {e}", path(3).display()))?;
+
+        let vector = |v: glm::DVec3| json!([v.x, v.y, v.z]);
+        let (_, up, _) = camera.basis();
+        let focal_length = camera.focal_length_pixels();
+        let view = json!({
+            "rgb": files[0],
+            "depth": files[1],
+            "normal": files[2],
+            "instances": files[3],
+            "camera": {
+                "position": vector(camera.position()),
+                "direction": vector(camera.direction()),
+                "up": vector(up),
+                "intrinsics": {
+                    "fx": focal_length,
+                    "fy": focal_length,
+                    "cx": 0.5 * truth.width as f64,
+                    "cy": 0.5 * truth.height as f64,
+                },
+            },
+        });
+        Ok((view, truth))
+    }
+
+    /// Write `manifest.json`, listing the frames and the objects behind the
+    /// instance ids. Returns its path.
+    pub fn finish(self, scene: &Scene) -> Result<PathBuf, String> {
+        let instances: Vec<Value> = scene
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| {
+                json!({
+                    "id": index + 1,
+                    "object": index,
+                    "kind": object.shape.kind(),
+                })
+            })
+            .collect();
+        let manifest = json!({
+            "stereo_baseline": self.baseline,
+            "depth_units": "scene units along the viewing axis",
+            "instances": instances,
+            "frames": self.frames,
+        });
+
+        let path = self.directory.join("manifest.json");
+        let source = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+        fs::write(&path, source + "\n").map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Write one value per pixel as a single channel 32 bit float EXR. Values
+/// that aren't finite, like the depth of the background, are written as 0.
+fn write_float_image(path: &Path, truth: &GroundTruth, values: &[f64]) -> Result<(), String> {
+    let samples = values
+        .iter()
+        .map(|value| {
+            if value.is_finite() {
+                *value as f32
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let channels = AnyChannels::sort(SmallVec::from_vec(vec![AnyChannel::new(
+        "Y",
+        FlatSamples::F32(samples),
+    )]));
+    let layer = Layer::new(
+        (truth.width as usize, truth.height as usize),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        channels,
+    );
+    Image::from_layer(layer)
+        .write()
+        .to_file(path)
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::FieldOfView;
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::{Plane, Sphere};
+
+    fn wall_and_ball() -> Scene {
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 0.0, 10.0),
+                    normal: glm::DVec3::z(),
+                }),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 1.0)),
+                material: Material::default(),
+                materials: Vec::new(),
+            });
+        scene
+    }
+
+    fn config() -> CameraConfig {
+        CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 8),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ground_truth_of_a_view() {
+        let truth = GroundTruth::render(&wall_and_ball(), &Camera::new(&config()));
+        let pixel = |i: u32, j: u32| (j * truth.width + i) as usize;
+
+        // The ball covers the center of the image, the wall the corners
+        let center = pixel(8, 4);
+        assert_eq!(2, truth.instances[center]);
+        assert!(truth.normals[center].z < -0.9);
+        assert!(truth.depths[center] < 5.0);
+        let corner = pixel(0, 0);
+        assert_eq!(1, truth.instances[corner]);
+        assert_relative_eq!(10.0, truth.depths[corner], epsilon = 1e-9);
+        assert_eq!(-glm::DVec3::z(), truth.normals[corner]);
+
+        // A 90° view 16 pixels wide has a focal length of 8 pixels
+        assert_relative_eq!(8.0, Camera::new(&config()).focal_length_pixels());
+        assert_relative_eq!(0.8, truth.disparity(8.0, 1.0)[corner]);
+    }
+
+    #[test]
+    fn write_stereo_dataset() {
+        let dir = std::env::temp_dir().join(format!("light-dataset-{}", std::process::id()));
+        let scene = wall_and_ball();
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(1).max_depth(1);
+
+        let mut writer = DatasetWriter::new(&dir);
+        writer.stereo(0.5);
+        writer.write_frame(&renderer, &scene, &config()).unwrap();
+        writer.write_frame(&renderer, &scene, &config()).unwrap();
+        assert_eq!(2, writer.num_frames());
+        let manifest_path = writer.finish(&scene).unwrap();
+
+        let manifest: Value =
+            serde_json::from_str(&fs::read_to_string(manifest_path).unwrap()).unwrap();
+        assert_eq!(2, manifest["frames"].as_array().unwrap().len());
+        assert_eq!("plane", manifest["instances"][0]["kind"]);
+        let left = &manifest["frames"][1]["views"]["left"];
+        let fx = left["camera"]["intrinsics"]["fx"].as_f64().unwrap();
+        assert_relative_eq!(8.0, fx, epsilon = 1e-9);
+        for file in ["rgb", "depth", "normal", "instances"] {
+            assert!(dir.join(left[file].as_str().unwrap()).exists());
+        }
+
+        let instances = image::open(dir.join(left["instances"].as_str().unwrap())).unwrap();
+        assert_eq!(2, instances.into_luma16().get_pixel(8, 4)[0]);
+        let disparity = manifest["frames"][0]["disparity"].as_str().unwrap();
+        assert!(dir.join(disparity).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Denoising with Intel Open Image Denoise, behind the `oidn` feature.
+//!
+//! The library is called through its C API and linked from the system, e.g.
+//! from the `OpenImageDenoise` package of the distribution. The ray tracing
+//! filter denoises the radiance much better with the albedo and normal of
+//! the first hits to tell noise from texture and edges.
+
+use std::ffi::{c_char, c_void, CStr};
+use std::ptr;
+
+use crate::film::RawImage;
+
+type Device = *mut c_void;
+type Filter = *mut c_void;
+
+const DEVICE_TYPE_DEFAULT: i32 = 0;
+const FORMAT_FLOAT3: i32 = 3;
+const ERROR_NONE: i32 = 0;
+
+#[link(name = "OpenImageDenoise")]
+extern "C" {
+    fn oidnNewDevice(device_type: i32) -> Device;
+    fn oidnCommitDevice(device: Device);
+    fn oidnGetDeviceError(device: Device, message: *mut *const c_char) -> i32;
+    fn oidnReleaseDevice(device: Device);
+    fn oidnNewFilter(device: Device, filter_type: *const c_char) -> Filter;
+    fn oidnSetSharedFilterImage(
+        filter: Filter,
+        name: *const c_char,
+        data: *mut c_void,
+        format: i32,
+        width: usize,
+        height: usize,
+        byte_offset: usize,
+        pixel_byte_stride: usize,
+        row_byte_stride: usize,
+    );
+    fn oidnSetFilterBool(filter: Filter, name: *const c_char, value: bool);
+    fn oidnCommitFilter(filter: Filter);
+    fn oidnExecuteFilter(filter: Filter);
+    fn oidnReleaseFilter(filter: Filter);
+}
+
+/// Device and filter, released when dropped
+struct Denoiser {
+    device: Device,
+    filter: Filter,
+}
+
+impl Denoiser {
+    fn new() -> Result<Self, String> {
+        // SAFETY: the handles are checked before use and owned by the result
+        unsafe {
+            let device = oidnNewDevice(DEVICE_TYPE_DEFAULT);
+            if device.is_null() {
+                return Err("Couldn't create an Open Image Denoise device".to_string());
+            }
+            let mut denoiser = Self {
+                device,
+                filter: ptr::null_mut(),
+            };
+            oidnCommitDevice(device);
+            denoiser.check()?;
+            denoiser.filter = oidnNewFilter(device, c"RT".as_ptr());
+            denoiser.check()?;
+            Ok(denoiser)
+        }
+    }
+
+    /// The error of the last call to the device, if any
+    fn check(&self) -> Result<(), String> {
+        let mut message = ptr::null();
+        // SAFETY: the device is valid, and the message lives until the next
+        // call to it
+        unsafe {
+            if oidnGetDeviceError(self.device, &mut message) == ERROR_NONE {
+                return Ok(());
+            }
+            Err(if message.is_null() {
+                "Open Image Denoise failed".to_string()
+            } else {
+                format!(
+                    "Open Image Denoise: {}",
+                    CStr::from_ptr(message).to_string_lossy()
+                )
+            })
+        }
+    }
+
+    /// Share an image with the filter. It isn't written to unless it is the
+    /// output.
+    fn set_image(&self, name: &CStr, image: &RawImage, data: *mut f32) {
+        let pixel = RawImage::CHANNELS * std::mem::size_of::<f32>();
+        // SAFETY: `data` holds the pixels of `image`, which outlive the
+        // execution of the filter
+        unsafe {
+            oidnSetSharedFilterImage(
+                self.filter,
+                name.as_ptr(),
+                data.cast(),
+                FORMAT_FLOAT3,
+                image.width as usize,
+                image.height as usize,
+                0,
+                pixel,
+                pixel * image.width as usize,
+            );
+        }
+    }
+}
+
+impl Drop for Denoiser {
+    fn drop(&mut self) {
+        // SAFETY: the handles were created by `new` and aren't used again
+        unsafe {
+            if !self.filter.is_null() {
+                oidnReleaseFilter(self.filter);
+            }
+            oidnReleaseDevice(self.device);
+        }
+    }
+}
+
+/// Denoise the radiance of a film, in the units of the film colors. The
+/// albedo, in [0, 1], and the normals, in [-1, 1], of the first hits guide
+/// the filter when given.
+pub fn denoise(
+    radiance: &RawImage,
+    guide: Option<(&RawImage, &RawImage)>,
+) -> Result<RawImage, String> {
+    if let Some((albedo, normal)) = guide {
+        let size = (radiance.width, radiance.height);
+        if (albedo.width, albedo.height) != size || (normal.width, normal.height) != size {
+            return Err("The AOVs don't match the size of the image".to_string());
+        }
+    }
+
+    // The filter expects radiance around 1 for a white surface
+    let mut color: Vec<f32> = radiance.data.iter().map(|c| c / 255.0).collect();
+    let mut output = RawImage {
+        width: radiance.width,
+        height: radiance.height,
+        data: vec![0.0; radiance.data.len()],
+    };
+
+    let denoiser = Denoiser::new()?;
+    denoiser.set_image(c"color", radiance, color.as_mut_ptr());
+    denoiser.set_image(c"output", radiance, output.data.as_mut_ptr());
+    if let Some((albedo, normal)) = guide {
+        denoiser.set_image(c"albedo", albedo, albedo.data.as_ptr().cast_mut());
+        denoiser.set_image(c"normal", normal, normal.data.as_ptr().cast_mut());
+    }
+    // SAFETY: the filter is valid and its images are alive
+    unsafe {
+        oidnSetFilterBool(denoiser.filter, c"hdr".as_ptr(), true);
+        oidnCommitFilter(denoiser.filter);
+        denoiser.check()?;
+        oidnExecuteFilter(denoiser.filter);
+        denoiser.check()?;
+    }
+
+    for c in &mut output.data {
+        *c *= 255.0;
+    }
+    Ok(output)
+}
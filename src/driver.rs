@@ -0,0 +1,141 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Built-in drivers of material parameters. A driver measures a geometric
+//! attribute of the shading point, e.g. its height, and a [`Blend`] maps it
+//! to a weight that mixes the color of the material with another one. This
+//! covers snow on the top of a model or worn edges without writing a
+//! procedural expression.
+
+use crate::color::Color;
+use crate::material::ShadingContext;
+
+/// Attribute of a shading point that drives a blend
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Driver {
+    /// Cosine between the normal and the view direction: 1 when the surface
+    /// faces the viewer and 0 at grazing angles, as in Fresnel effects
+    FacingRatio,
+    /// World height of the point
+    Height,
+    /// Distance of the point to a fixed point
+    Distance { point: glm::DVec3 },
+}
+
+impl Driver {
+    pub fn value(&self, ctx: &ShadingContext) -> f64 {
+        match self {
+            Driver::FacingRatio => ctx.normal.dot(&ctx.view).abs(),
+            Driver::Height => ctx.position.y,
+            Driver::Distance { point } => glm::distance(&ctx.position, point),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Driver::FacingRatio => "facing_ratio",
+            Driver::Height => "height",
+            Driver::Distance { .. } => "distance",
+        }
+    }
+}
+
+/// Mix towards `color` as the driver goes from `from` to `to`. The transition
+/// is smooth, and `from` can be larger than `to` to blend the other way,
+/// e.g. from 1 to 0 for edges seen at grazing angles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blend {
+    pub driver: Driver,
+    pub from: f64,
+    pub to: f64,
+    pub color: Color,
+}
+
+impl Blend {
+    /// Weight of `color` at a shading point, in [0, 1]
+    pub fn weight(&self, ctx: &ShadingContext) -> f64 {
+        let value = self.driver.value(ctx);
+        if self.from == self.to {
+            return if value >= self.to { 1.0 } else { 0.0 };
+        }
+        let t = ((value - self.from) / (self.to - self.from)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Color blended with `base`, the color the material would have otherwise
+    pub fn apply(&self, base: &Color, ctx: &ShadingContext) -> Color {
+        base.lerp(&self.color, self.weight(ctx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn ctx(position: glm::DVec3, view: glm::DVec3) -> ShadingContext {
+        ShadingContext {
+            position,
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::zeros(),
+            object_id: 0,
+            view,
+            footprint: 0.0,
+        }
+    }
+
+    #[test]
+    fn drivers() {
+        let head_on = ctx(glm::DVec3::new(3.0, 2.0, 4.0), -glm::DVec3::y());
+        let grazing = ctx(glm::DVec3::zeros(), glm::DVec3::x());
+        assert_relative_eq!(1.0, Driver::FacingRatio.value(&head_on));
+        assert_relative_eq!(0.0, Driver::FacingRatio.value(&grazing));
+        assert_relative_eq!(2.0, Driver::Height.value(&head_on));
+        let distance = Driver::Distance {
+            point: glm::DVec3::new(0.0, 2.0, 0.0),
+        };
+        assert_relative_eq!(5.0, distance.value(&head_on));
+    }
+
+    #[test]
+    fn snow_above_a_height() {
+        let snow = Blend {
+            driver: Driver::Height,
+            from: 1.0,
+            to: 2.0,
+            color: Color::new(255.0, 255.0, 255.0),
+        };
+        let rock = Color::new(100.0, 80.0, 60.0);
+        let at = |y: f64| ctx(glm::DVec3::new(0.0, y, 0.0), -glm::DVec3::y());
+
+        assert_eq!(rock, snow.apply(&rock, &at(0.0)));
+        assert_eq!(snow.color, snow.apply(&rock, &at(3.0)));
+        assert_relative_eq!(0.5, snow.weight(&at(1.5)));
+
+        // Blending the other way round
+        let worn = Blend {
+            from: 2.0,
+            to: 1.0,
+            ..snow
+        };
+        assert_eq!(snow.color, worn.apply(&rock, &at(0.0)));
+        assert_eq!(rock, worn.apply(&rock, &at(3.0)));
+    }
+}
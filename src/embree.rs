@@ -0,0 +1,307 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Intersection of meshes with Intel Embree, behind the `embree` feature.
+//!
+//! The library is called through its C API and linked from the system, e.g.
+//! from the `embree` package of the distribution. Embree only finds the
+//! closest triangle, in single precision; its distance is then computed
+//! again in double precision and the hit shaded from the mesh like its own
+//! traversal does, so that renders with either backend can be compared.
+//! Meshes keep their native hierarchy for everything else, e.g. sampling
+//! lights.
+
+use std::ffi::{c_char, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex, Weak};
+
+use serde_json::Value;
+
+use crate::light::Ray;
+use crate::shape::{Bounds, HitRecord, Mesh, Shape, SurfaceSample};
+
+type DeviceHandle = *mut c_void;
+type Scene = *mut c_void;
+type Geometry = *mut c_void;
+
+const ERROR_NONE: i32 = 0;
+const GEOMETRY_TYPE_TRIANGLE: i32 = 0;
+const BUFFER_TYPE_INDEX: i32 = 0;
+const BUFFER_TYPE_VERTEX: i32 = 1;
+const FORMAT_UINT3: i32 = 0x5003;
+const FORMAT_FLOAT3: i32 = 0x9003;
+const INVALID_GEOMETRY_ID: u32 = u32::MAX;
+
+/// Times a ray is traced again past the surface it leaves before giving up
+const MAX_SELF_HITS: usize = 4;
+
+/// `RTCRay` followed by `RTCHit`, the layout `rtcIntersect1` reads and writes
+#[repr(C, align(16))]
+struct RayHit {
+    org: [f32; 3],
+    tnear: f32,
+    dir: [f32; 3],
+    time: f32,
+    tfar: f32,
+    mask: u32,
+    id: u32,
+    flags: u32,
+    ng: [f32; 3],
+    u: f32,
+    v: f32,
+    prim_id: u32,
+    geom_id: u32,
+    inst_id: u32,
+    inst_prim_id: u32,
+}
+
+#[link(name = "embree4")]
+extern "C" {
+    fn rtcNewDevice(config: *const c_char) -> DeviceHandle;
+    fn rtcGetDeviceError(device: DeviceHandle) -> i32;
+    fn rtcReleaseDevice(device: DeviceHandle);
+    fn rtcNewScene(device: DeviceHandle) -> Scene;
+    fn rtcCommitScene(scene: Scene);
+    fn rtcReleaseScene(scene: Scene);
+    fn rtcNewGeometry(device: DeviceHandle, geometry_type: i32) -> Geometry;
+    fn rtcSetNewGeometryBuffer(
+        geometry: Geometry,
+        buffer_type: i32,
+        slot: u32,
+        format: i32,
+        byte_stride: usize,
+        item_count: usize,
+    ) -> *mut c_void;
+    fn rtcCommitGeometry(geometry: Geometry);
+    fn rtcAttachGeometry(scene: Scene, geometry: Geometry) -> u32;
+    fn rtcReleaseGeometry(geometry: Geometry);
+    fn rtcIntersect1(scene: Scene, ray_hit: *mut RayHit, arguments: *mut c_void);
+}
+
+/// The Embree device, shared by all meshes and released with the last one
+struct Device(DeviceHandle);
+
+// SAFETY: Embree devices can be used from any number of threads at once
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+impl Device {
+    fn shared() -> Result<Arc<Self>, String> {
+        static SHARED: Mutex<Weak<Device>> = Mutex::new(Weak::new());
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(device) = shared.upgrade() {
+            return Ok(device);
+        }
+        // SAFETY: a null configuration selects the defaults
+        let handle = unsafe { rtcNewDevice(ptr::null()) };
+        if handle.is_null() {
+            return Err("Couldn't create an Embree device".to_string());
+        }
+        let device = Arc::new(Self(handle));
+        *shared = Arc::downgrade(&device);
+        Ok(device)
+    }
+
+    /// The error of the last call to the device, if any
+    fn check(&self) -> Result<(), String> {
+        // SAFETY: the device is valid
+        match unsafe { rtcGetDeviceError(self.0) } {
+            ERROR_NONE => Ok(()),
+            code => Err(format!("Embree failed with error {code}")),
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        // SAFETY: the last reference to the device is gone
+        unsafe { rtcReleaseDevice(self.0) }
+    }
+}
+
+/// A mesh whose rays are traced by Embree
+pub struct EmbreeMesh {
+    mesh: Mesh,
+    scene: Scene,
+    device: Arc<Device>,
+}
+
+// SAFETY: committed Embree scenes are immutable and can be traversed from
+// any number of threads at once
+unsafe impl Send for EmbreeMesh {}
+unsafe impl Sync for EmbreeMesh {}
+
+impl EmbreeMesh {
+    /// Hand the triangles of a mesh to Embree, which builds its own
+    /// hierarchy over them
+    pub fn new(mesh: Mesh) -> Result<Self, String> {
+        let device = Device::shared()?;
+        // SAFETY: the handles are checked before use and owned by the result,
+        // and the buffers are filled within the sizes they were created with
+        unsafe {
+            let embree = Self {
+                mesh,
+                scene: rtcNewScene(device.0),
+                device,
+            };
+            embree.device.check()?;
+
+            let geometry = rtcNewGeometry(embree.device.0, GEOMETRY_TYPE_TRIANGLE);
+            embree.device.check()?;
+            let positions = embree.mesh.positions();
+            let vertices = rtcSetNewGeometryBuffer(
+                geometry,
+                BUFFER_TYPE_VERTEX,
+                0,
+                FORMAT_FLOAT3,
+                3 * std::mem::size_of::<f32>(),
+                positions.len(),
+            )
+            .cast::<f32>();
+            let faces = embree.mesh.faces();
+            let indices = rtcSetNewGeometryBuffer(
+                geometry,
+                BUFFER_TYPE_INDEX,
+                0,
+                FORMAT_UINT3,
+                3 * std::mem::size_of::<u32>(),
+                faces.len(),
+            )
+            .cast::<u32>();
+            if vertices.is_null() || indices.is_null() {
+                rtcReleaseGeometry(geometry);
+                embree.device.check()?;
+                return Err("Embree couldn't allocate the buffers of a mesh".to_string());
+            }
+            for (n, position) in positions.iter().enumerate() {
+                let position = [position.x, position.y, position.z].map(|x| x as f32);
+                ptr::copy_nonoverlapping(position.as_ptr(), vertices.add(3 * n), 3);
+            }
+            for (n, face) in faces.iter().enumerate() {
+                ptr::copy_nonoverlapping(face.positions.as_ptr(), indices.add(3 * n), 3);
+            }
+
+            rtcCommitGeometry(geometry);
+            rtcAttachGeometry(embree.scene, geometry);
+            // The scene keeps its own reference
+            rtcReleaseGeometry(geometry);
+            rtcCommitScene(embree.scene);
+            embree.device.check()?;
+            Ok(embree)
+        }
+    }
+
+    /// Closest face hit by a ray past tnear in single precision, with its
+    /// distance and barycentric coordinates
+    fn closest_face(&self, ray: &Ray, tnear: f32) -> Option<(usize, f32, f32, f32)> {
+        let mut ray_hit = RayHit {
+            org: [ray.origin.x, ray.origin.y, ray.origin.z].map(|x| x as f32),
+            tnear,
+            dir: [ray.direction.x, ray.direction.y, ray.direction.z].map(|x| x as f32),
+            time: 0.0,
+            tfar: f32::INFINITY,
+            mask: u32::MAX,
+            id: 0,
+            flags: 0,
+            ng: [0.0; 3],
+            u: 0.0,
+            v: 0.0,
+            prim_id: INVALID_GEOMETRY_ID,
+            geom_id: INVALID_GEOMETRY_ID,
+            inst_id: INVALID_GEOMETRY_ID,
+            inst_prim_id: INVALID_GEOMETRY_ID,
+        };
+        // SAFETY: the scene is committed and the ray lives through the call
+        unsafe {
+            rtcIntersect1(self.scene, &mut ray_hit, ptr::null_mut());
+        }
+        (ray_hit.geom_id != INVALID_GEOMETRY_ID).then_some((
+            ray_hit.prim_id as usize,
+            ray_hit.tfar,
+            ray_hit.u,
+            ray_hit.v,
+        ))
+    }
+}
+
+impl Drop for EmbreeMesh {
+    fn drop(&mut self) {
+        // SAFETY: the scene was created by `new` and isn't used again. The
+        // device is released after it.
+        unsafe {
+            if !self.scene.is_null() {
+                rtcReleaseScene(self.scene);
+            }
+        }
+    }
+}
+
+impl Shape for EmbreeMesh {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        // The origin of a ray leaving a surface is rounded off it in single
+        // precision, so Embree can hit the surface again. The distance in
+        // double precision tells these hits apart, and the ray is traced
+        // again past them.
+        let mut tnear = 0.0;
+        for _ in 0..MAX_SELF_HITS {
+            let (n, t, u, v) = self.closest_face(ray, tnear)?;
+            match self.mesh.face_distance(ray, n) {
+                Some(distance) if distance > f64::EPSILON => {
+                    return Some(self.mesh.face_hit(ray, n, distance, u as f64, v as f64));
+                }
+                _ => tnear = t.next_up(),
+            }
+        }
+        None
+    }
+
+    fn kind(&self) -> &'static str {
+        "embree mesh"
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.mesh.bounds()
+    }
+
+    fn centroid(&self) -> Option<glm::DVec3> {
+        self.mesh.centroid()
+    }
+
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        self.mesh.sample_surface(u)
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.mesh.triangle_count()
+    }
+
+    fn min_triangle_area(&self) -> Option<f64> {
+        self.mesh.min_triangle_area()
+    }
+
+    /// Memory of the mesh, without the hierarchy of Embree
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self) + self.mesh.memory_size() - std::mem::size_of::<Mesh>()
+    }
+
+    /// Saved as the mesh it traces
+    fn to_json(&self) -> Option<Value> {
+        self.mesh.to_json()
+    }
+}
@@ -0,0 +1,483 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A small expression language to drive material parameters from hit
+//! attributes, e.g. `mix(0.2, 0.9, smoothstep(0, 10, y))` for altitude-based
+//! coloring.
+//!
+//! Available variables are the hit position (`x`, `y`, `z`), the normal
+//! (`nx`, `ny`, `nz`), the surface coordinates (`u`, `v`) and the index of
+//! the object in the scene (`id`), plus the constant `pi`.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::color::Color;
+use crate::material::ShadingContext;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variable {
+    X,
+    Y,
+    Z,
+    Nx,
+    Ny,
+    Nz,
+    U,
+    V,
+    Id,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+/// Largest number of arguments taken by a function
+const MAX_ARITY: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Abs,
+    Sqrt,
+    Floor,
+    Fract,
+    Min,
+    Max,
+    Pow,
+    Step,
+    Clamp,
+    Mix,
+    Smoothstep,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        let function = match name {
+            "sin" => Self::Sin,
+            "cos" => Self::Cos,
+            "tan" => Self::Tan,
+            "abs" => Self::Abs,
+            "sqrt" => Self::Sqrt,
+            "floor" => Self::Floor,
+            "fract" => Self::Fract,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "pow" => Self::Pow,
+            "step" => Self::Step,
+            "clamp" => Self::Clamp,
+            "mix" => Self::Mix,
+            "smoothstep" => Self::Smoothstep,
+            _ => return None,
+        };
+        Some(function)
+    }
+
+    /// Number of arguments, at most `MAX_ARITY`
+    fn arity(&self) -> usize {
+        match self {
+            Self::Sin
+            | Self::Cos
+            | Self::Tan
+            | Self::Abs
+            | Self::Sqrt
+            | Self::Floor
+            | Self::Fract => 1,
+            Self::Min | Self::Max | Self::Pow | Self::Step => 2,
+            Self::Clamp | Self::Mix | Self::Smoothstep => 3,
+        }
+    }
+
+    fn apply(&self, args: &[f64]) -> f64 {
+        match self {
+            Self::Sin => args[0].sin(),
+            Self::Cos => args[0].cos(),
+            Self::Tan => args[0].tan(),
+            Self::Abs => args[0].abs(),
+            Self::Sqrt => args[0].sqrt(),
+            Self::Floor => args[0].floor(),
+            Self::Fract => args[0] - args[0].floor(),
+            Self::Min => args[0].min(args[1]),
+            Self::Max => args[0].max(args[1]),
+            Self::Pow => args[0].powf(args[1]),
+            Self::Step => {
+                if args[1] < args[0] {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+            Self::Clamp => args[0].max(args[1]).min(args[2]),
+            Self::Mix => args[0] + (args[1] - args[0]) * args[2],
+            Self::Smoothstep => {
+                let t = ((args[2] - args[0]) / (args[1] - args[0])).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Variable(Variable),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars: Peekable<Chars> = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut literal = String::new();
+                while let Some(&c) = chars.peek() {
+                    let exponent_sign = (c == '-' || c == '+') && literal.ends_with(['e', 'E']);
+                    if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                        literal.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let value = literal
+                    .parse()
+                    .map_err(|_| format!("Invalid number '{literal}'"))?;
+                tokens.push(Token::Number(value));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            '+' | '-' | '*' | '/' | '^' => {
+                tokens.push(Token::Op(c));
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                chars.next();
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                chars.next();
+            }
+            _ => return Err(format!("Unexpected character '{c}'")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser. Grammar, from lowest to highest precedence:
+///
+/// ```text
+/// sum     := product (('+' | '-') product)*
+/// product := unary (('*' | '/') unary)*
+/// unary   := '-' unary | power
+/// power   := primary ('^' unary)?
+/// primary := number | variable | function '(' sum (',' sum)* ')' | '(' sum ')'
+/// ```
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {expected:?}, found {token:?}")),
+            None => Err(format!("Expected {expected:?}, found end of expression")),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.product()?;
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek() {
+            let op = if *op == '+' {
+                BinaryOp::Add
+            } else {
+                BinaryOp::Sub
+            };
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.product()?));
+        }
+        Ok(lhs)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op @ ('*' | '/'))) = self.peek() {
+            let op = if *op == '*' {
+                BinaryOp::Mul
+            } else {
+                BinaryOp::Div
+            };
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if let Some(Token::Op('-')) = self.peek() {
+            self.next();
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.primary()?;
+        if let Some(Token::Op('^')) = self.peek() {
+            self.next();
+            let exponent = self.unary()?;
+            return Ok(Expr::Binary(
+                BinaryOp::Pow,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::LParen) => {
+                let expr = self.sum()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                if let Some(Token::LParen) = self.peek() {
+                    self.next();
+                    let function = Function::from_name(&name)
+                        .ok_or_else(|| format!("Unknown function '{name}'"))?;
+
+                    let mut args = vec![self.sum()?];
+                    while let Some(Token::Comma) = self.peek() {
+                        self.next();
+                        args.push(self.sum()?);
+                    }
+                    self.expect(Token::RParen)?;
+
+                    if args.len() != function.arity() {
+                        return Err(format!(
+                            "Function '{name}' takes {} arguments, {} given",
+                            function.arity(),
+                            args.len()
+                        ));
+                    }
+                    return Ok(Expr::Call(function, args));
+                }
+
+                let variable = match name.as_str() {
+                    "x" => Variable::X,
+                    "y" => Variable::Y,
+                    "z" => Variable::Z,
+                    "nx" => Variable::Nx,
+                    "ny" => Variable::Ny,
+                    "nz" => Variable::Nz,
+                    "u" => Variable::U,
+                    "v" => Variable::V,
+                    "id" => Variable::Id,
+                    "pi" => return Ok(Expr::Number(std::f64::consts::PI)),
+                    _ => return Err(format!("Unknown variable '{name}'")),
+                };
+                Ok(Expr::Variable(variable))
+            }
+            Some(token) => Err(format!("Unexpected token {token:?}")),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+
+        let expr = parser.sum()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected token {token:?}"));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, ctx: &ShadingContext) -> f64 {
+        match self {
+            Self::Number(value) => *value,
+            Self::Variable(variable) => match variable {
+                Variable::X => ctx.position.x,
+                Variable::Y => ctx.position.y,
+                Variable::Z => ctx.position.z,
+                Variable::Nx => ctx.normal.x,
+                Variable::Ny => ctx.normal.y,
+                Variable::Nz => ctx.normal.z,
+                Variable::U => ctx.uv.x,
+                Variable::V => ctx.uv.y,
+                Variable::Id => ctx.object_id as f64,
+            },
+            Self::Neg(expr) => -expr.eval(ctx),
+            Self::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(ctx), rhs.eval(ctx));
+                match op {
+                    BinaryOp::Add => lhs + rhs,
+                    BinaryOp::Sub => lhs - rhs,
+                    BinaryOp::Mul => lhs * rhs,
+                    BinaryOp::Div => lhs / rhs,
+                    BinaryOp::Pow => lhs.powf(rhs),
+                }
+            }
+            Self::Call(function, args) => {
+                // Evaluated on the stack, this runs for every shading point
+                let mut values = [0.0; MAX_ARITY];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = arg.eval(ctx);
+                }
+                function.apply(&values[..args.len()])
+            }
+        }
+    }
+}
+
+/// Color given by one expression per channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorExpr {
+    pub channels: [Expr; 3],
+}
+
+impl ColorExpr {
+    pub fn parse(r: &str, g: &str, b: &str) -> Result<Self, String> {
+        Ok(Self {
+            channels: [Expr::parse(r)?, Expr::parse(g)?, Expr::parse(b)?],
+        })
+    }
+
+    pub fn eval(&self, ctx: &ShadingContext) -> Color {
+        Color::new(
+            self.channels[0].eval(ctx),
+            self.channels[1].eval(ctx),
+            self.channels[2].eval(ctx),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn context() -> ShadingContext {
+        ShadingContext {
+            position: glm::DVec3::new(1.0, 2.0, 3.0),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::new(0.25, 0.75),
+            object_id: 7,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        }
+    }
+
+    fn eval(source: &str) -> f64 {
+        Expr::parse(source).unwrap().eval(&context())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_relative_eq!(7.0, eval("1 + 2 * 3"));
+        assert_relative_eq!(9.0, eval("(1 + 2) * 3"));
+        assert_relative_eq!(-4.0, eval("-2 ^ 2"));
+        assert_relative_eq!(512.0, eval("2 ^ 3 ^ 2"));
+        assert_relative_eq!(1.0, eval("8 / 4 / 2"));
+        assert_relative_eq!(2.5e-3, eval("2.5e-3"));
+    }
+
+    #[test]
+    fn variables_and_functions() {
+        assert_relative_eq!(6.0, eval("x + y + z"));
+        assert_relative_eq!(1.0, eval("ny"));
+        assert_relative_eq!(1.0, eval("u + v"));
+        assert_relative_eq!(7.0, eval("id"));
+        assert_relative_eq!(0.5, eval("mix(0, 1, 0.5)"));
+        assert_relative_eq!(1.0, eval("clamp(y, 0, 1)"));
+        assert_relative_eq!(0.5, eval("smoothstep(0, 4, y)"));
+        assert_relative_eq!(0.0, eval("sin(pi)"), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Expr::parse("1 +").is_err());
+        assert!(Expr::parse("foo(1)").is_err());
+        assert!(Expr::parse("mix(1, 2)").is_err());
+        assert!(Expr::parse("w").is_err());
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert!(Expr::parse("1 $ 2").is_err());
+    }
+}
@@ -0,0 +1,1007 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+
+use crate::color::{self, Color};
+use crate::tile::Tile;
+
+/// Maximum number of offending pixels listed in a report
+const MAX_REPORTED_PIXELS: usize = 32;
+
+/// Mapping from film radiance to 8 bit pixel values. The film holds linear
+/// radiance, which is compressed to [0, 1] by the tone curve and then
+/// encoded with the sRGB transfer function.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToneMapper {
+    /// Cut values above 255
+    #[default]
+    Clamp,
+    /// Compress highlights smoothly with c / (1 + c), in units of 255
+    Reinhard,
+    /// Filmic curve of the ACES reference rendering transform, after the
+    /// fit by Krzysztof Narkowicz
+    Aces,
+}
+
+impl ToneMapper {
+    /// Linear value in [0, 1] of a film value
+    pub fn curve(&self, value: f64) -> f64 {
+        let x = value.max(0.0) / 255.0;
+        match self {
+            ToneMapper::Clamp => x.min(1.0),
+            ToneMapper::Reinhard => x / (1.0 + x),
+            ToneMapper::Aces => {
+                let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                (x * (a * x + b) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+            }
+        }
+    }
+
+    pub fn map(&self, value: f64) -> u8 {
+        (255.0 * color::srgb_encode(self.curve(value))).round() as u8
+    }
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            _ => Err(format!("Unknown tone mapper '{s}'")),
+        }
+    }
+}
+
+/// How auto exposure weighs the pixels when it meters a film
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metering {
+    /// All the pixels alike
+    Average,
+    /// Mostly the middle of the image, fading towards the corners
+    Center,
+}
+
+impl FromStr for Metering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(Metering::Average),
+            "center" => Ok(Metering::Center),
+            _ => Err(format!("Unknown metering '{s}'")),
+        }
+    }
+}
+
+/// Film value that metering maps the log-average luminance to, middle grey
+const EXPOSURE_KEY: f64 = 0.18 * 255.0;
+
+/// Limit of the exposure correction, in stops
+const MAX_EV: f64 = 16.0;
+
+/// Image as plain RGB floats in scanline order, for consumers that don't
+/// work with the `image` crate
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>,
+}
+
+impl RawImage {
+    /// Floats per pixel
+    pub const CHANNELS: usize = 3;
+
+    /// Floats per row
+    pub fn stride(&self) -> usize {
+        Self::CHANNELS * self.width as usize
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn pixel(&self, i: u32, j: u32) -> [f32; 3] {
+        let offset = j as usize * self.stride() + Self::CHANNELS * i as usize;
+        [
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+        ]
+    }
+}
+
+/// What to do with samples that contain NaN or infinite values
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NonFinitePolicy {
+    /// Drop the sample, so it doesn't count towards the pixel average
+    #[default]
+    Discard,
+    /// Keep the sample, replacing NaN with 0 and infinities with ±limit
+    Clamp(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleStatus {
+    Accepted,
+    Discarded,
+    Clamped,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pixel {
+    pub sum: Color,
+    pub sum_sq: Color,  // Sum of the squares of the samples, for their variance
+    pub samples: u32,   // Accepted samples
+    pub discarded: u32, // Non-finite samples that were dropped
+    pub clamped: u32,   // Non-finite samples that were clamped
+    pub overlay: Color, // Radiance added on top of the samples, e.g. caustics
+}
+
+impl Pixel {
+    pub fn add_sample(&mut self, color: Color, policy: NonFinitePolicy) -> SampleStatus {
+        if color.iter().all(|c| c.is_finite()) {
+            self.accept(color);
+            return SampleStatus::Accepted;
+        }
+
+        match policy {
+            NonFinitePolicy::Discard => {
+                self.discarded += 1;
+                SampleStatus::Discarded
+            }
+            NonFinitePolicy::Clamp(limit) => {
+                self.accept(color.map(|c| {
+                    if c.is_nan() {
+                        0.0
+                    } else {
+                        c.clamp(-limit, limit)
+                    }
+                }));
+                self.clamped += 1;
+                SampleStatus::Clamped
+            }
+        }
+    }
+
+    fn accept(&mut self, color: Color) {
+        self.sum += color;
+        self.sum_sq += color.component_mul(&color);
+        self.samples += 1;
+    }
+
+    /// Add the samples and overlay of another pixel
+    pub fn merge(&mut self, other: &Pixel) {
+        self.sum += other.sum;
+        self.sum_sq += other.sum_sq;
+        self.samples += other.samples;
+        self.discarded += other.discarded;
+        self.clamped += other.clamped;
+        self.overlay += other.overlay;
+    }
+
+    /// Average of the accepted samples, plus the overlay
+    pub fn color(&self) -> Color {
+        if self.samples == 0 {
+            self.overlay
+        } else {
+            self.sum / (self.samples as f64) + self.overlay
+        }
+    }
+
+    /// Unbiased variance of the accepted samples, zero with less than two
+    pub fn variance(&self) -> Color {
+        if self.samples < 2 {
+            return Color::zeros();
+        }
+        let n = self.samples as f64;
+        let mean = self.sum / n;
+        ((self.sum_sq - self.sum.component_mul(&mean)) / (n - 1.0)).map(|v| v.max(0.0))
+    }
+
+    /// Half width of the 95% confidence interval of the mean of the samples,
+    /// averaged over the color channels. Infinite with less than two samples.
+    pub fn error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        1.96 * (self.variance() / self.samples as f64)
+            .map(f64::sqrt)
+            .mean()
+    }
+}
+
+/// Summary of the non-finite samples found in a render
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NonFiniteReport {
+    pub discarded: u64,
+    pub clamped: u64,
+    pub pixels: Vec<(u32, u32)>, // First offending pixels, in scanline order
+}
+
+impl NonFiniteReport {
+    pub fn total(&self) -> u64 {
+        self.discarded + self.clamped
+    }
+}
+
+impl fmt::Display for NonFiniteReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} non-finite samples ({} discarded, {} clamped)",
+            self.total(),
+            self.discarded,
+            self.clamped
+        )?;
+        if !self.pixels.is_empty() {
+            write!(f, " at pixels {:?}", self.pixels)?;
+            if (self.pixels.len() as u64) < self.total() {
+                write!(f, "...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Numeric precision of the film storage. Reduced precision saves memory on
+/// very large renders, e.g. an 8K film takes 2.9 GB in f64, 1.6 GB in f32
+/// and 2 GB in compensated f32.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FilmPrecision {
+    #[default]
+    F64,
+    /// Sums drift when many passes are merged into the same film, since
+    /// small contributions get rounded away against a large sum
+    F32,
+    /// f32 with Kahan summation, which keeps merged sums close to f64 at the
+    /// cost of a compensation term per channel
+    KahanF32,
+}
+
+impl FilmPrecision {
+    /// Bytes taken by each pixel of a film
+    pub fn pixel_size(&self) -> usize {
+        match self {
+            FilmPrecision::F64 => std::mem::size_of::<Pixel>(),
+            FilmPrecision::F32 => std::mem::size_of::<PixelF32>(),
+            FilmPrecision::KahanF32 => std::mem::size_of::<PixelKahan>(),
+        }
+    }
+}
+
+/// Storage of a pixel in the film
+trait StoredPixel: Copy + Default {
+    fn load(&self) -> Pixel;
+    fn store(&mut self, pixel: &Pixel);
+    fn merge(&mut self, pixel: &Pixel);
+}
+
+impl StoredPixel for Pixel {
+    fn load(&self) -> Pixel {
+        *self
+    }
+
+    fn store(&mut self, pixel: &Pixel) {
+        *self = *pixel;
+    }
+
+    fn merge(&mut self, pixel: &Pixel) {
+        Pixel::merge(self, pixel);
+    }
+}
+
+fn to_f32(color: &Color) -> [f32; 3] {
+    [color.x as f32, color.y as f32, color.z as f32]
+}
+
+fn from_f32(color: &[f32; 3]) -> Color {
+    Color::new(color[0] as f64, color[1] as f64, color[2] as f64)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelF32 {
+    sum: [f32; 3],
+    sum_sq: [f32; 3],
+    samples: u32,
+    discarded: u32,
+    clamped: u32,
+    overlay: [f32; 3],
+}
+
+impl PixelF32 {
+    fn merge_counts(&mut self, pixel: &Pixel) {
+        self.samples += pixel.samples;
+        self.discarded += pixel.discarded;
+        self.clamped += pixel.clamped;
+    }
+}
+
+impl StoredPixel for PixelF32 {
+    fn load(&self) -> Pixel {
+        Pixel {
+            sum: from_f32(&self.sum),
+            sum_sq: from_f32(&self.sum_sq),
+            samples: self.samples,
+            discarded: self.discarded,
+            clamped: self.clamped,
+            overlay: from_f32(&self.overlay),
+        }
+    }
+
+    fn store(&mut self, pixel: &Pixel) {
+        *self = Self {
+            sum: to_f32(&pixel.sum),
+            sum_sq: to_f32(&pixel.sum_sq),
+            samples: pixel.samples,
+            discarded: pixel.discarded,
+            clamped: pixel.clamped,
+            overlay: to_f32(&pixel.overlay),
+        };
+    }
+
+    fn merge(&mut self, pixel: &Pixel) {
+        for c in 0..3 {
+            self.sum[c] += pixel.sum[c] as f32;
+            self.sum_sq[c] += pixel.sum_sq[c] as f32;
+            self.overlay[c] += pixel.overlay[c] as f32;
+        }
+        self.merge_counts(pixel);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelKahan {
+    pixel: PixelF32,
+    compensation: [f32; 3], // Low order bits lost by the sum
+}
+
+impl StoredPixel for PixelKahan {
+    fn load(&self) -> Pixel {
+        let mut pixel = self.pixel.load();
+        pixel.sum -= from_f32(&self.compensation);
+        pixel
+    }
+
+    fn store(&mut self, pixel: &Pixel) {
+        self.pixel.store(pixel);
+        self.compensation = [0.0; 3];
+    }
+
+    fn merge(&mut self, pixel: &Pixel) {
+        for c in 0..3 {
+            let sum = self.pixel.sum[c];
+            let y = pixel.sum[c] - self.compensation[c] as f64;
+            let t = sum + y as f32;
+            self.compensation[c] = ((t as f64 - sum as f64) - y) as f32;
+            self.pixel.sum[c] = t;
+            self.pixel.sum_sq[c] += pixel.sum_sq[c] as f32;
+            self.pixel.overlay[c] += pixel.overlay[c] as f32;
+        }
+        self.pixel.merge_counts(pixel);
+    }
+}
+
+#[derive(Clone)]
+enum Storage {
+    F64(Vec<Pixel>),
+    F32(Vec<PixelF32>),
+    KahanF32(Vec<PixelKahan>),
+}
+
+/// Run an expression on the pixel vector, whatever its precision
+macro_rules! with_storage {
+    ($storage:expr, $pixels:ident => $body:expr) => {
+        match $storage {
+            Storage::F64($pixels) => $body,
+            Storage::F32($pixels) => $body,
+            Storage::KahanF32($pixels) => $body,
+        }
+    };
+}
+
+/// Accumulation buffer for radiance samples
+#[derive(Clone)]
+pub struct Film {
+    width: u32,
+    height: u32,
+    storage: Storage,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::with_precision(width, height, FilmPrecision::default())
+    }
+
+    pub fn with_precision(width: u32, height: u32, precision: FilmPrecision) -> Self {
+        let len = width as usize * height as usize;
+        let storage = match precision {
+            FilmPrecision::F64 => Storage::F64(vec![Default::default(); len]),
+            FilmPrecision::F32 => Storage::F32(vec![Default::default(); len]),
+            FilmPrecision::KahanF32 => Storage::KahanF32(vec![Default::default(); len]),
+        };
+        Self {
+            width,
+            height,
+            storage,
+        }
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn precision(&self) -> FilmPrecision {
+        match self.storage {
+            Storage::F64(_) => FilmPrecision::F64,
+            Storage::F32(_) => FilmPrecision::F32,
+            Storage::KahanF32(_) => FilmPrecision::KahanF32,
+        }
+    }
+
+    /// Bytes taken by the pixels
+    pub fn memory_size(&self) -> usize {
+        with_storage!(&self.storage, pixels => std::mem::size_of_val(pixels.as_slice()))
+    }
+
+    fn index(&self, i: u32, j: u32) -> usize {
+        (j * self.width + i) as usize
+    }
+
+    fn pixel_at(&self, index: usize) -> Pixel {
+        with_storage!(&self.storage, pixels => pixels[index].load())
+    }
+
+    pub fn pixel(&self, i: u32, j: u32) -> Pixel {
+        self.pixel_at(self.index(i, j))
+    }
+
+    /// Pixels in scanline order
+    pub fn pixels(&self) -> impl Iterator<Item = Pixel> + '_ {
+        (0..(self.width * self.height) as usize).map(|index| self.pixel_at(index))
+    }
+
+    /// Modify every pixel. `f` gets the pixel index in scanline order.
+    pub fn update_pixels<F: FnMut(usize, &mut Pixel)>(&mut self, mut f: F) {
+        with_storage!(&mut self.storage, pixels => {
+            for (index, stored) in pixels.iter_mut().enumerate() {
+                let mut pixel = stored.load();
+                f(index, &mut pixel);
+                stored.store(&pixel);
+            }
+        })
+    }
+
+    /// Replace the pixels that are more than `threshold` times as bright as
+    /// all of their neighbours by the average of the neighbours. Removes the
+    /// fireflies that would take too many samples to average out, along
+    /// with any detail one pixel wide. Returns the number of pixels replaced.
+    pub fn reject_outliers(&mut self, threshold: f64) -> usize {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let pixels: Vec<Pixel> = self.pixels().collect();
+        let luminance: Vec<f64> = pixels
+            .iter()
+            .map(|pixel| color::luminance(&pixel.color()))
+            .collect();
+
+        let mut replaced = Vec::new();
+        for (index, pixel) in pixels.iter().enumerate() {
+            let (i, j) = (index as i64 % w, index as i64 / w);
+            let neighbours: Vec<usize> = (-1..=1)
+                .flat_map(|dj| (-1..=1).map(move |di| (i + di, j + dj)))
+                .filter(|&(x, y)| (x, y) != (i, j) && (0..w).contains(&x) && (0..h).contains(&y))
+                .map(|(x, y)| (y * w + x) as usize)
+                .collect();
+            let brightest = neighbours.iter().map(|&n| luminance[n]).fold(0.0, f64::max);
+            if pixel.samples == 0
+                || neighbours.is_empty()
+                || luminance[index] <= threshold * brightest
+            {
+                continue;
+            }
+
+            let mean = neighbours
+                .iter()
+                .map(|&n| &pixels[n])
+                .filter(|neighbour| neighbour.samples > 0)
+                .map(|neighbour| neighbour.sum / neighbour.samples as f64)
+                .sum::<Color>()
+                / neighbours.len() as f64;
+            replaced.push((index, mean));
+        }
+
+        let count = replaced.len();
+        let mut replaced = replaced.into_iter().peekable();
+        self.update_pixels(|index, pixel| {
+            if let Some((_, mean)) = replaced.next_if(|(i, _)| *i == index) {
+                // The samples are replaced by as many of the average
+                let n = pixel.samples as f64;
+                pixel.sum = mean * n;
+                pixel.sum_sq = mean.component_mul(&mean) * n;
+            }
+        });
+        count
+    }
+
+    /// Exposure correction, in stops, that brings the log-average luminance of
+    /// the film to middle grey. Zero for a black film.
+    pub fn meter(&self, metering: Metering) -> f64 {
+        let (w, h) = (self.width as f64, self.height as f64);
+        let (mut log_sum, mut weights) = (0.0, 0.0);
+        for (index, pixel) in self.pixels().enumerate() {
+            let luminance = color::luminance(&pixel.color());
+            if luminance <= 0.0 {
+                continue;
+            }
+            let weight = match metering {
+                Metering::Average => 1.0,
+                Metering::Center => {
+                    // Distance to the center, 1 at the corners
+                    let x = ((index % self.width as usize) as f64 + 0.5) / w - 0.5;
+                    let y = ((index / self.width as usize) as f64 + 0.5) / h - 0.5;
+                    (-4.0 * (x * x + y * y)).exp()
+                }
+            };
+            log_sum += weight * luminance.ln();
+            weights += weight;
+        }
+        if weights == 0.0 {
+            return 0.0;
+        }
+        let average = (log_sum / weights).exp();
+        (EXPOSURE_KEY / average).log2().clamp(-MAX_EV, MAX_EV)
+    }
+
+    /// Scale the radiance of the film by `2^ev`
+    pub fn expose(&mut self, ev: f64) {
+        let gain = ev.exp2();
+        self.update_pixels(|_, pixel| {
+            pixel.sum *= gain;
+            pixel.sum_sq *= gain * gain;
+            pixel.overlay *= gain;
+        });
+    }
+
+    /// Replace the colors of the pixels by those of an image of the same
+    /// size, e.g. a denoised one, keeping their sample counts
+    pub fn replace_colors(&mut self, image: &RawImage) {
+        assert_eq!(self.resolution(), (image.width, image.height));
+        self.update_pixels(|index, pixel| {
+            let (i, j) = (index as u32 % image.width, index as u32 / image.width);
+            let color = Color::from(image.pixel(i, j).map(f64::from));
+            let n = pixel.samples as f64;
+            pixel.sum = color * n;
+            pixel.sum_sq = color.component_mul(&color) * n;
+            pixel.overlay = if pixel.samples == 0 {
+                color
+            } else {
+                Color::zeros()
+            };
+        });
+    }
+
+    /// Estimated relative error of the image: the half widths of the 95%
+    /// confidence intervals of the pixels, see `Pixel::error`, over their
+    /// values, summed over the image so that dark pixels don't dominate.
+    /// Infinite until every pixel has two samples.
+    pub fn relative_error(&self) -> f64 {
+        let (mut error, mut value) = (0.0, 0.0);
+        for pixel in self.pixels() {
+            if pixel.samples < 2 {
+                return f64::INFINITY;
+            }
+            error += pixel.error();
+            value += (pixel.sum / pixel.samples as f64).mean().abs();
+        }
+        if error == 0.0 {
+            0.0
+        } else {
+            error / value
+        }
+    }
+
+    /// Film with the variance of the mean of each pixel, its squared
+    /// standard error, as a single sample. Written with
+    /// `output::write_radiance` it makes a variance AOV.
+    pub fn variance_of_mean(&self) -> Film {
+        let mut film = Film::new(self.width, self.height);
+        for (index, pixel) in self.pixels().enumerate() {
+            let index = index as u32;
+            let variance = pixel.variance() / pixel.samples.max(1) as f64;
+            film.add_sample(
+                index % self.width,
+                index / self.width,
+                variance,
+                NonFinitePolicy::default(),
+            );
+        }
+        film
+    }
+
+    /// Add a sample to a pixel
+    pub fn add_sample(
+        &mut self,
+        i: u32,
+        j: u32,
+        color: Color,
+        policy: NonFinitePolicy,
+    ) -> SampleStatus {
+        let mut pixel = Pixel::default();
+        let status = pixel.add_sample(color, policy);
+        let index = self.index(i, j);
+        with_storage!(&mut self.storage, pixels => pixels[index].merge(&pixel));
+        status
+    }
+
+    /// Add the pixels of a rendered tile, given in scanline order, to the
+    /// film. Merging several passes accumulates their samples.
+    pub fn merge_tile(&mut self, tile: &Tile, pixels: &[Pixel]) {
+        assert_eq!(tile.num_pixels(), pixels.len());
+        let width = self.width;
+        with_storage!(&mut self.storage, stored => {
+            for ((i, j), pixel) in tile.pixels().zip(pixels) {
+                stored[(j * width + i) as usize].merge(pixel);
+            }
+        })
+    }
+
+    /// Add the samples of another film of the same resolution
+    pub fn merge(&mut self, other: &Film) {
+        assert_eq!(self.resolution(), other.resolution());
+        self.update_pixels(|index, pixel| pixel.merge(&other.pixel_at(index)));
+    }
+
+    pub fn non_finite_report(&self) -> NonFiniteReport {
+        let mut report = NonFiniteReport::default();
+        for (index, pixel) in self.pixels().enumerate() {
+            report.discarded += pixel.discarded as u64;
+            report.clamped += pixel.clamped as u64;
+            if (pixel.discarded + pixel.clamped) > 0 && report.pixels.len() < MAX_REPORTED_PIXELS {
+                let index = index as u32;
+                report.pixels.push((index % self.width, index / self.width));
+            }
+        }
+        report
+    }
+
+    /// Radiance of the pixels, in the units of the film colors
+    pub fn to_raw(&self) -> RawImage {
+        self.to_raw_by(|value| value as f32)
+    }
+
+    /// Tone mapped and sRGB encoded pixels, in [0, 1]. Same as `to_image_with`
+    /// without the quantization to 8 bits.
+    pub fn to_raw_with(&self, tone_mapper: ToneMapper) -> RawImage {
+        self.to_raw_by(|value| color::srgb_encode(tone_mapper.curve(value)) as f32)
+    }
+
+    fn to_raw_by<F: Fn(f64) -> f32>(&self, f: F) -> RawImage {
+        let data = self
+            .pixels()
+            .flat_map(|pixel| {
+                let color = pixel.color();
+                [f(color.x), f(color.y), f(color.z)]
+            })
+            .collect();
+        RawImage {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        self.to_image_with(ToneMapper::default())
+    }
+
+    pub fn to_image_with(&self, tone_mapper: ToneMapper) -> RgbImage {
+        let mut image = RgbImage::new(self.width, self.height);
+        for (pixel, rgb) in self.pixels().zip(image.pixels_mut()) {
+            let color = pixel.color();
+            rgb[0] = tone_mapper.map(color.x);
+            rgb[1] = tone_mapper.map(color.y);
+            rgb[2] = tone_mapper.map(color.z);
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn discard_non_finite_samples() {
+        let mut pixel = Pixel::default();
+        let policy = NonFinitePolicy::Discard;
+
+        assert_eq!(
+            SampleStatus::Accepted,
+            pixel.add_sample(Color::new(1.0, 2.0, 3.0), policy)
+        );
+        assert_eq!(
+            SampleStatus::Discarded,
+            pixel.add_sample(Color::new(f64::NAN, 0.0, 0.0), policy)
+        );
+        assert_eq!(
+            SampleStatus::Discarded,
+            pixel.add_sample(Color::new(0.0, f64::INFINITY, 0.0), policy)
+        );
+        assert_eq!(
+            SampleStatus::Accepted,
+            pixel.add_sample(Color::new(3.0, 2.0, 1.0), policy)
+        );
+
+        assert_eq!(Color::new(2.0, 2.0, 2.0), pixel.color());
+        assert_eq!(2, pixel.discarded);
+    }
+
+    #[test]
+    fn sample_variance() {
+        let mut pixel = Pixel::default();
+        assert_eq!(f64::INFINITY, pixel.error());
+        for value in [1.0, 3.0, 5.0, 7.0] {
+            pixel.add_sample(Color::new(value, 4.0, 0.0), NonFinitePolicy::Discard);
+        }
+        // Discarded samples don't count
+        pixel.add_sample(Color::new(f64::NAN, 0.0, 0.0), NonFinitePolicy::Discard);
+
+        assert_eq!(Color::new(20.0 / 3.0, 0.0, 0.0), pixel.variance());
+        let error = 1.96 * (20.0 / 3.0 / 4.0_f64).sqrt() / 3.0;
+        assert!((pixel.error() - error).abs() < 1e-12);
+
+        // Merged pixels have the variance of all their samples
+        let mut half = Pixel::default();
+        for value in [1.0, 3.0] {
+            half.add_sample(Color::new(value, 4.0, 0.0), NonFinitePolicy::Discard);
+        }
+        let mut other = Pixel::default();
+        for value in [5.0, 7.0] {
+            other.add_sample(Color::new(value, 4.0, 0.0), NonFinitePolicy::Discard);
+        }
+        half.merge(&other);
+        assert_eq!(pixel.variance(), half.variance());
+    }
+
+    #[test]
+    fn clamp_non_finite_samples() {
+        let mut pixel = Pixel::default();
+        let policy = NonFinitePolicy::Clamp(100.0);
+
+        pixel.add_sample(
+            Color::new(f64::NAN, f64::INFINITY, f64::NEG_INFINITY),
+            policy,
+        );
+        assert_eq!(Color::new(0.0, 100.0, -100.0), pixel.color());
+        assert_eq!(1, pixel.clamped);
+    }
+
+    #[test]
+    fn outlier_rejection() {
+        let mut film = Film::new(4, 3);
+        for j in 0..3 {
+            for i in 0..4 {
+                // The right half is brighter, but it's not an outlier
+                let value = if i < 2 { 1.0 } else { 3.0 };
+                film.add_sample(i, j, Color::repeat(value), NonFinitePolicy::Discard);
+                film.add_sample(i, j, Color::repeat(value), NonFinitePolicy::Discard);
+            }
+        }
+        film.add_sample(1, 1, Color::repeat(1000.0), NonFinitePolicy::Discard);
+
+        assert_eq!(1, film.reject_outliers(10.0));
+        let pixel = film.pixel(1, 1);
+        assert_eq!(3, pixel.samples);
+        assert_relative_eq!(Color::repeat(1.75), pixel.color(), epsilon = 1e-12);
+        assert_eq!(Color::zeros(), pixel.variance());
+        assert_eq!(Color::repeat(3.0), film.pixel(2, 1).color());
+
+        // Nothing else stands out
+        assert_eq!(0, film.reject_outliers(10.0));
+    }
+
+    #[test]
+    fn auto_exposure() {
+        let mut film = Film::new(5, 5);
+        for (i, j) in (0..5).flat_map(|j| (0..5).map(move |i| (i, j))) {
+            let color = if (i, j) == (2, 2) { 100.0 } else { 0.1 };
+            film.add_sample(i, j, Color::repeat(color), NonFinitePolicy::Discard);
+        }
+        // The bright center counts for more
+        let average = film.meter(Metering::Average);
+        let center = film.meter(Metering::Center);
+        assert!(center < average);
+
+        film.expose(average);
+        assert_relative_eq!(0.0, film.meter(Metering::Average), epsilon = 1e-9);
+        assert_eq!(0.0, Film::new(2, 2).meter(Metering::Average));
+    }
+
+    #[test]
+    fn relative_error() {
+        let mut film = Film::new(2, 1);
+        film.add_sample(0, 0, Color::repeat(10.0), NonFinitePolicy::Discard);
+        assert_eq!(f64::INFINITY, film.relative_error());
+
+        for sample in [30.0, 10.0, 30.0] {
+            film.add_sample(0, 0, Color::repeat(sample), NonFinitePolicy::Discard);
+        }
+        for _ in 0..4 {
+            film.add_sample(1, 0, Color::repeat(60.0), NonFinitePolicy::Discard);
+        }
+        // The flat pixel has no error, the other one's is spread over both
+        let variance = 400.0 / 3.0;
+        let error = 1.96 * (variance / 4.0_f64).sqrt();
+        assert_relative_eq!(error / 80.0, film.relative_error(), epsilon = 1e-12);
+
+        let variances = film.variance_of_mean();
+        assert_relative_eq!(Color::repeat(variance / 4.0), variances.pixel(0, 0).color());
+        assert_eq!(Color::zeros(), variances.pixel(1, 0).color());
+    }
+
+    #[test]
+    fn replace_colors() {
+        let mut film = Film::new(2, 1);
+        for sample in [10.0, 30.0] {
+            film.add_sample(0, 0, Color::repeat(sample), NonFinitePolicy::Discard);
+        }
+        let denoised = RawImage {
+            width: 2,
+            height: 1,
+            data: vec![25.0, 25.0, 25.0, 5.0, 6.0, 7.0],
+        };
+        film.replace_colors(&denoised);
+
+        let pixel = film.pixel(0, 0);
+        assert_eq!(2, pixel.samples);
+        assert_relative_eq!(Color::repeat(25.0), pixel.color());
+        assert_eq!(Color::zeros(), pixel.variance());
+        // Pixels without samples take the color too
+        assert_eq!(Color::new(5.0, 6.0, 7.0), film.pixel(1, 0).color());
+    }
+
+    #[test]
+    fn report_locates_pixels() {
+        let mut film = Film::new(4, 3);
+        film.add_sample(
+            2,
+            1,
+            Color::new(f64::NAN, 0.0, 0.0),
+            NonFinitePolicy::Discard,
+        );
+        film.add_sample(
+            0,
+            2,
+            Color::new(f64::NAN, 0.0, 0.0),
+            NonFinitePolicy::Clamp(1.0),
+        );
+        film.add_sample(1, 1, Color::new(1.0, 1.0, 1.0), NonFinitePolicy::Discard);
+
+        let report = film.non_finite_report();
+        assert_eq!(1, report.discarded);
+        assert_eq!(1, report.clamped);
+        assert_eq!(vec![(2, 1), (0, 2)], report.pixels);
+
+        // A pixel with only discarded samples stays black instead of NaN
+        assert_eq!(Color::zeros(), film.pixel(2, 1).color());
+    }
+
+    #[test]
+    fn reduced_precision() {
+        let tile = Tile {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let mut sample = Pixel::default();
+        sample.add_sample(Color::new(0.1, 1.0, 10.0), NonFinitePolicy::Discard);
+
+        // Merge many single sample passes into each film
+        let passes = 1_000_000;
+        let mean = |precision| {
+            let mut film = Film::with_precision(1, 1, precision);
+            for _ in 0..passes {
+                film.merge_tile(&tile, &[sample]);
+            }
+            assert_eq!(passes, film.pixel(0, 0).samples);
+            film.pixel(0, 0).color()
+        };
+        let error = |precision| {
+            let color = mean(precision);
+            ((color - sample.sum).component_div(&sample.sum))
+                .abs()
+                .max()
+        };
+
+        assert!(error(FilmPrecision::F64) < 1e-9);
+        // Plain f32 sums drift by about 1% after a million passes, while
+        // Kahan summation stays as accurate as f64
+        assert!(error(FilmPrecision::F32) > 1e-3);
+        assert!(error(FilmPrecision::KahanF32) < 1e-9);
+
+        assert_eq!(88, Film::new(1, 1).memory_size());
+        assert_eq!(
+            48,
+            Film::with_precision(1, 1, FilmPrecision::F32).memory_size()
+        );
+        assert_eq!(
+            60,
+            Film::with_precision(1, 1, FilmPrecision::KahanF32).memory_size()
+        );
+        let film = Film::with_precision(4, 3, FilmPrecision::KahanF32);
+        assert_eq!(
+            12 * FilmPrecision::KahanF32.pixel_size(),
+            film.memory_size()
+        );
+    }
+
+    #[test]
+    fn tone_mappers() {
+        for tone_mapper in [ToneMapper::Clamp, ToneMapper::Reinhard, ToneMapper::Aces] {
+            assert_eq!(0, tone_mapper.map(-10.0));
+            let values: Vec<u8> = [10.0, 50.0, 255.0, 1000.0]
+                .iter()
+                .map(|&value| tone_mapper.map(value))
+                .collect();
+            assert!(values.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        // Values are sRGB encoded, so dark tones are lifted
+        assert_eq!(128, ToneMapper::Clamp.map(0.2158 * 255.0));
+        assert_eq!(255, ToneMapper::Clamp.map(1000.0));
+        assert_eq!(
+            ToneMapper::Clamp.map(127.5),
+            ToneMapper::Reinhard.map(255.0)
+        );
+        assert!(ToneMapper::Aces.map(1000.0) > 250);
+        assert_eq!(Ok(ToneMapper::Aces), "aces".parse());
+    }
+
+    #[test]
+    fn raw_images() {
+        let mut film = Film::new(3, 2);
+        let color = Color::new(510.0, 127.5, 0.0);
+        film.add_sample(2, 1, color, NonFinitePolicy::default());
+
+        let raw = film.to_raw();
+        assert_eq!((9, 18), (raw.stride(), raw.as_slice().len()));
+        assert_eq!([510.0, 127.5, 0.0], raw.pixel(2, 1));
+        assert_eq!([0.0; 3], raw.pixel(1, 1));
+
+        let image = film.to_image_with(ToneMapper::Reinhard);
+        let display = film.to_raw_with(ToneMapper::Reinhard);
+        for c in 0..3 {
+            let value = display.pixel(2, 1)[c];
+            assert!((0.0..=1.0).contains(&value));
+            assert_eq!(image.get_pixel(2, 1)[c], (255.0 * value).round() as u8);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Hit filters: predicates that reject some of the hits on a shape while
+//! rays are traced, e.g. to cut holes in a surface with a pattern. A
+//! rejected hit lets the ray go on to the next hit on the same shape, or to
+//! whatever is behind it.
+
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::expr::Expr;
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::render::SURFACE_OFFSET;
+use crate::shape::{Bounds, HitRecord, Shape, SurfaceSample, VisibleSample};
+
+/// Decides whether a hit along a ray is kept
+pub type HitFilter = Arc<dyn Fn(&Ray, &HitRecord) -> bool + Send + Sync>;
+
+/// Largest number of hits rejected along a ray before the shape is
+/// considered missed
+pub const MAX_REJECTIONS: usize = 64;
+
+/// Shape whose hits go through a filter
+pub struct FilteredShape {
+    pub shape: Box<dyn Shape + Send + Sync>,
+    pub filter: HitFilter,
+}
+
+impl FilteredShape {
+    pub fn new(shape: Box<dyn Shape + Send + Sync>, filter: HitFilter) -> Self {
+        Self { shape, filter }
+    }
+}
+
+impl Shape for FilteredShape {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let mut offset = 0.0;
+        let mut probe = Ray {
+            origin: ray.origin,
+            direction: ray.direction,
+            time: ray.time,
+            footprint: ray.footprint,
+            spread: ray.spread,
+        };
+        for _ in 0..=MAX_REJECTIONS {
+            let hit = self.shape.intersect(&probe)?;
+            let hit = HitRecord {
+                ray_t: offset + hit.ray_t,
+                ..hit
+            };
+            if (self.filter)(ray, &hit) {
+                return Some(hit);
+            }
+
+            // Go on from just past the rejected hit
+            offset = hit.ray_t + SURFACE_OFFSET;
+            probe.origin = ray.point_at(offset);
+        }
+        None
+    }
+
+    fn kind(&self) -> &'static str {
+        self.shape.kind()
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.shape.bounds()
+    }
+
+    fn centroid(&self) -> Option<glm::DVec3> {
+        self.shape.centroid()
+    }
+
+    /// Samples of the whole surface, including the parts the filter rejects
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        self.shape.sample_surface(u)
+    }
+
+    fn sample_from(&self, origin: &glm::DVec3, u: glm::DVec2) -> Option<VisibleSample> {
+        self.shape.sample_from(origin, u)
+    }
+
+    fn pdf_from(&self, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+        self.shape.pdf_from(origin, point, normal)
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.shape.triangle_count()
+    }
+
+    fn min_triangle_area(&self) -> Option<f64> {
+        self.shape.min_triangle_area()
+    }
+
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self) + self.shape.memory_size()
+    }
+
+    /// Filters are code and can't be written to a scene file
+    fn to_json(&self) -> Option<Value> {
+        None
+    }
+
+    fn warning(&self) -> Option<String> {
+        self.shape.warning()
+    }
+}
+
+/// Filter that cuts out the points of an object where a pattern is
+/// positive. The pattern sees the hits as the object with the given index.
+pub fn cutout(pattern: Expr, object_id: usize) -> HitFilter {
+    Arc::new(move |ray, hit| {
+        let ctx = ShadingContext::new(hit, object_id).viewed_along(&ray.direction);
+        pattern.eval(&ctx) <= 0.0
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use crate::shape::{Plane, Sphere};
+
+    use super::*;
+
+    #[test]
+    fn rejected_hits_let_rays_through() {
+        // Only the far half of the sphere is kept
+        let sphere = FilteredShape::new(
+            Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+            Arc::new(|_, hit| hit.point.z > 0.0),
+        );
+        let ray = Ray::new(glm::DVec3::new(0.0, 0.0, -5.0), glm::DVec3::z());
+        let hit = sphere.intersect(&ray).unwrap();
+        assert_relative_eq!(6.0, hit.ray_t, epsilon = 1e-9);
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 1.0), hit.point, epsilon = 1e-9);
+
+        let never = FilteredShape::new(
+            Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+            Arc::new(|_, _| false),
+        );
+        assert!(never.intersect(&ray).is_none());
+        assert_eq!("sphere", never.kind());
+        assert!(never.to_json().is_none());
+    }
+
+    #[test]
+    fn cutout_pattern() {
+        // Holes in the floor where the stripes of x are in their first half
+        let pattern = Expr::parse("0.5 - fract(x)").unwrap();
+        let floor = FilteredShape::new(
+            Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            cutout(pattern, 0),
+        );
+        let down = |x| Ray::new(glm::DVec3::new(x, 1.0, 0.0), -glm::DVec3::y());
+        assert!(floor.intersect(&down(0.25)).is_none());
+        assert_relative_eq!(1.0, floor.intersect(&down(0.75)).unwrap().ray_t);
+    }
+}
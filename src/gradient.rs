@@ -0,0 +1,283 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use bumpalo::Bump;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::Film;
+use crate::render::PathTracer;
+use crate::scene::Scene;
+
+/// Experimental gradient-domain path tracing.
+///
+/// Besides the usual (primal) estimate of each pixel, the renderer estimates
+/// the finite differences to the right and bottom neighbours by tracing the
+/// neighbour with the same random numbers as the base path. Most of the noise
+/// cancels out in the differences of these correlated paths, and the final
+/// image is reconstructed from the primal image and the gradients with a
+/// screened Poisson solve.
+///
+/// Paths are shifted to the neighbours by replaying their random numbers,
+/// which works best for smooth, diffuse lighting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradientDomain {
+    pub alpha: f64,      // Weight of the primal image in the reconstruction
+    pub iterations: u32, // Iterations of the Poisson solver
+}
+
+impl Default for GradientDomain {
+    fn default() -> Self {
+        Self {
+            alpha: 0.2,
+            iterations: 50,
+        }
+    }
+}
+
+/// Primal image and forward differences, in scanline order
+pub struct GradientBuffers {
+    width: u32,
+    height: u32,
+    pub primal: Vec<Color>,
+    pub dx: Vec<Color>, // I(i + 1, j) - I(i, j)
+    pub dy: Vec<Color>, // I(i, j + 1) - I(i, j)
+}
+
+impl GradientBuffers {
+    pub fn new(width: u32, height: u32) -> Self {
+        let len = (width * height) as usize;
+        Self {
+            width,
+            height,
+            primal: vec![Color::zeros(); len],
+            dx: vec![Color::zeros(); len],
+            dy: vec![Color::zeros(); len],
+        }
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Find the image that best fits the gradients while staying close to the
+    /// primal image, weighted by `alpha`, with Jacobi iterations starting from
+    /// the primal image
+    pub fn reconstruct(&self, alpha: f64, iterations: u32) -> Vec<Color> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let mut image = self.primal.clone();
+        let mut next = image.clone();
+
+        for _ in 0..iterations {
+            for j in 0..h {
+                for i in 0..w {
+                    let n = j * w + i;
+                    let mut sum = alpha * self.primal[n];
+                    let mut weight = alpha;
+
+                    if i + 1 < w {
+                        sum += image[n + 1] - self.dx[n];
+                        weight += 1.0;
+                    }
+                    if i > 0 {
+                        sum += image[n - 1] + self.dx[n - 1];
+                        weight += 1.0;
+                    }
+                    if j + 1 < h {
+                        sum += image[n + w] - self.dy[n];
+                        weight += 1.0;
+                    }
+                    if j > 0 {
+                        sum += image[n - w] + self.dy[n - w];
+                        weight += 1.0;
+                    }
+
+                    next[n] = sum / weight;
+                }
+            }
+            std::mem::swap(&mut image, &mut next);
+        }
+
+        image
+    }
+}
+
+/// Running mean of the finite samples of an estimate
+#[derive(Default)]
+struct Estimate {
+    sum: Color,
+    samples: u32,
+}
+
+impl Estimate {
+    fn add(&mut self, color: Color) {
+        if color.iter().all(|c| c.is_finite()) {
+            self.sum += color;
+            self.samples += 1;
+        }
+    }
+
+    fn mean(&self) -> Color {
+        if self.samples == 0 {
+            Color::zeros()
+        } else {
+            self.sum / (self.samples as f64)
+        }
+    }
+}
+
+impl GradientDomain {
+    /// Estimate the primal image and the gradients with `spp` samples each
+    pub fn render_buffers(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+        spp: u32,
+    ) -> GradientBuffers {
+        let (w, h) = camera.resolution();
+        let spp = spp.max(1);
+        let seed = renderer.render_seed();
+
+        let estimates: Vec<(Color, Color, Color)> = (0..w * h)
+            .into_par_iter()
+            .map_init(Bump::new, |scratch, n| {
+                let (i, j) = (n % w, n / w);
+                let mut primal = Estimate::default();
+                let mut dx = Estimate::default();
+                let mut dy = Estimate::default();
+                for sample in 0..spp {
+                    let replay = StdRng::seed_from_u64(
+                        seed.wrapping_add(n as u64 * spp as u64 + sample as u64),
+                    );
+                    let offset = renderer.pixel_offset(i, j, sample, seed);
+                    let mut trace = |i, j| {
+                        let mut rng = replay.clone();
+                        let ray = camera
+                            .cast_ray_jittered(i, j, offset, &mut rng)
+                            .expect("Expected a Ray");
+                        renderer.trace_path(scene, &ray, None, scratch, &mut rng)
+                    };
+
+                    let base = trace(i, j);
+                    primal.add(base);
+                    if i + 1 < w {
+                        dx.add(trace(i + 1, j) - base);
+                    }
+                    if j + 1 < h {
+                        dy.add(trace(i, j + 1) - base);
+                    }
+                }
+                (primal.mean(), dx.mean(), dy.mean())
+            })
+            .collect();
+
+        let mut buffers = GradientBuffers::new(w, h);
+        for (n, (primal, dx, dy)) in estimates.into_iter().enumerate() {
+            buffers.primal[n] = primal;
+            buffers.dx[n] = dx;
+            buffers.dy[n] = dy;
+        }
+        buffers
+    }
+
+    /// Render the gradients and reconstruct them into a film. Every pixel
+    /// counts as a single sample of the reconstructed color.
+    pub fn render(&self, renderer: &PathTracer, scene: &Scene, camera: &Camera, spp: u32) -> Film {
+        let buffers = self.render_buffers(renderer, scene, camera, spp);
+        let image = buffers.reconstruct(self.alpha, self.iterations);
+
+        let (w, h) = camera.resolution();
+        let mut film = Film::with_precision(w, h, renderer.film_precision);
+        film.update_pixels(|index, pixel| {
+            pixel.sum = image[index];
+            pixel.samples = 1;
+        });
+        film
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::CameraConfig;
+
+    fn ramp(w: u32, h: u32) -> GradientBuffers {
+        let mut buffers = GradientBuffers::new(w, h);
+        for j in 0..h {
+            for i in 0..w {
+                let n = (j * w + i) as usize;
+                buffers.primal[n] = Color::new(i as f64, 2.0 * j as f64, 10.0);
+                buffers.dx[n] = Color::new(1.0, 0.0, 0.0);
+                buffers.dy[n] = Color::new(0.0, 2.0, 0.0);
+            }
+        }
+        buffers
+    }
+
+    #[test]
+    fn consistent_data_is_a_fixed_point() {
+        let buffers = ramp(8, 6);
+        let image = buffers.reconstruct(0.2, 20);
+        for (expected, color) in buffers.primal.iter().zip(image) {
+            assert_relative_eq!(*expected, color, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn gradients_remove_primal_noise() {
+        let truth = ramp(16, 16);
+        let mut noisy = ramp(16, 16);
+        for (n, color) in noisy.primal.iter_mut().enumerate() {
+            // Checkerboard noise, the worst case for a pixel estimate
+            let (i, j) = (n % 16, n / 16);
+            *color += Color::repeat(if (i + j) % 2 == 0 { 5.0 } else { -5.0 });
+        }
+
+        let error = |image: &[Color]| -> f64 {
+            image
+                .iter()
+                .zip(&truth.primal)
+                .map(|(a, b)| (a - b).norm_squared())
+                .sum()
+        };
+        let image = noisy.reconstruct(0.2, 100);
+        assert!(error(&image) < 0.01 * error(&noisy.primal));
+    }
+
+    #[test]
+    fn render_background() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 2.0, 3.0);
+        let camera = Camera::new(&CameraConfig {
+            resolution: (6, 4),
+            ..Default::default()
+        });
+
+        let film = GradientDomain::default().render(&PathTracer::new(), &scene, &camera, 2);
+        for pixel in film.pixels() {
+            assert_relative_eq!(Color::new(1.0, 2.0, 3.0), pixel.color(), epsilon = 1e-9);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Ground plane with procedural micro-displacement, for gravel or sand
+//! floors that would otherwise need huge meshes. The displaced surface is
+//! found at intersection time by marching the ray through the thin layer
+//! above the plane that the displacement can reach.
+
+use serde_json::{json, Value};
+
+use crate::light::Ray;
+use crate::sampling::orthonormal_basis;
+use crate::shape::{HitRecord, Shape, NO_MATERIAL};
+use crate::volume::NoiseField;
+
+/// Default number of ray marching steps through the displaced layer
+pub const DEFAULT_STEPS: u32 = 64;
+
+/// Bisection steps that refine a hit once the march has bracketed it
+const REFINE_STEPS: u32 = 16;
+
+/// Infinite plane whose surface is raised along its normal by fractal
+/// noise. The noise frequency is in cells per scene unit. Seen from below,
+/// it is the flat plane.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplacedPlane {
+    pub position: glm::DVec3,
+    pub normal: glm::DVec3, // Unit length
+    pub displacement: NoiseField,
+    pub checker: Option<f64>, // Side of the squares of a checker pattern
+    pub steps: u32,           // Ray marching steps through the displaced layer
+}
+
+impl DisplacedPlane {
+    pub fn new(position: glm::DVec3, normal: glm::DVec3, displacement: NoiseField) -> Self {
+        Self {
+            position,
+            normal: normal.normalize(),
+            displacement,
+            checker: None,
+            steps: DEFAULT_STEPS,
+        }
+    }
+
+    /// Height of a point above the plane
+    fn height(&self, p: &glm::DVec3) -> f64 {
+        (p - self.position).dot(&self.normal)
+    }
+
+    /// Height of the displaced surface above the projection of a point on
+    /// the plane
+    pub fn surface_height(&self, p: &glm::DVec3) -> f64 {
+        self.displacement.value(&(p - self.height(p) * self.normal))
+    }
+
+    fn is_above(&self, p: &glm::DVec3) -> bool {
+        self.height(p) > self.surface_height(p)
+    }
+
+    /// Normal of the displaced surface, from the slope of the noise
+    fn surface_normal(
+        &self,
+        p: &glm::DVec3,
+        tangent: &glm::DVec3,
+        bitangent: &glm::DVec3,
+    ) -> glm::DVec3 {
+        let finest =
+            self.displacement.frequency * 2_f64.powi(self.displacement.octaves.max(1) as i32 - 1);
+        let eps = 1e-2 / finest.max(f64::EPSILON);
+        let slope = |axis: &glm::DVec3| {
+            (self.surface_height(&(p + eps * axis)) - self.surface_height(&(p - eps * axis)))
+                / (2.0 * eps)
+        };
+        (self.normal - slope(tangent) * tangent - slope(bitangent) * bitangent).normalize()
+    }
+
+    fn hit(&self, ray: &Ray, t: f64, normal: glm::DVec3) -> HitRecord {
+        let point = ray.point_at(t);
+        let (tangent, bitangent) = orthonormal_basis(&self.normal);
+        let offset = point - self.position;
+        let uv = glm::DVec2::new(offset.dot(&tangent), offset.dot(&bitangent));
+        let material = match self.checker {
+            Some(size)
+                if ((uv.x / size).floor() + (uv.y / size).floor()).rem_euclid(2.0) == 1.0 =>
+            {
+                0
+            }
+            Some(_) => NO_MATERIAL,
+            None => 0,
+        };
+        HitRecord {
+            ray_t: t,
+            point,
+            normal,
+            uv,
+            uv_density: 1.0, // The uv are distances on the plane
+            material,
+        }
+    }
+}
+
+impl Shape for DisplacedPlane {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let height = self.height(&ray.origin);
+        let rate = ray.direction.dot(&self.normal);
+        if height < 0.0 {
+            return (rate > 0.0).then(|| self.hit(ray, -height / rate, self.normal));
+        }
+
+        // Part of the ray inside the layer between the plane and the highest
+        // bumps
+        let top = self.displacement.amplitude;
+        let start = if height > top {
+            if rate >= 0.0 {
+                return None;
+            }
+            (top - height) / rate
+        } else {
+            0.0
+        };
+        let end = if rate < 0.0 {
+            -height / rate
+        } else if rate > 0.0 {
+            (top - height) / rate
+        } else {
+            return None;
+        };
+
+        // March until the ray goes below the surface, and then bisect the
+        // last step to find where it does. The top of the layer is never
+        // below the surface and its bottom never above it, whatever the
+        // rounding errors.
+        let steps = self.steps.max(1);
+        let step = (end - start) / steps as f64;
+        let mut was_above = height > top || self.is_above(&ray.origin);
+        for k in 1..=steps {
+            let t = start + k as f64 * step;
+            let is_above = (k < steps || rate > 0.0) && self.is_above(&ray.point_at(t));
+            if was_above && !is_above {
+                let (mut above, mut below) = (t - step, t);
+                for _ in 0..REFINE_STEPS {
+                    let mid = 0.5 * (above + below);
+                    if self.is_above(&ray.point_at(mid)) {
+                        above = mid;
+                    } else {
+                        below = mid;
+                    }
+                }
+                // Keep the hit on the outer side, so that rays leaving it
+                // don't start inside the ground
+                let (tangent, bitangent) = orthonormal_basis(&self.normal);
+                let normal = self.surface_normal(&ray.point_at(above), &tangent, &bitangent);
+                return Some(self.hit(ray, above, normal));
+            }
+            was_above = is_above;
+        }
+        None
+    }
+
+    fn kind(&self) -> &'static str {
+        "displaced_plane"
+    }
+
+    fn to_json(&self) -> Option<Value> {
+        let mut value = json!({
+            "type": "displaced_plane",
+            "position": [self.position.x, self.position.y, self.position.z],
+            "normal": [self.normal.x, self.normal.y, self.normal.z],
+            "displacement": {
+                "amplitude": self.displacement.amplitude,
+                "frequency": self.displacement.frequency,
+                "octaves": self.displacement.octaves,
+                "seed": self.displacement.seed,
+            },
+            "steps": self.steps,
+        });
+        if let Some(size) = self.checker {
+            value["checker"] = size.into();
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::shape::Plane;
+
+    fn gravel() -> DisplacedPlane {
+        DisplacedPlane::new(
+            glm::DVec3::zeros(),
+            glm::DVec3::y(),
+            NoiseField {
+                amplitude: 0.1,
+                frequency: 4.0,
+                octaves: 3,
+                seed: 7,
+            },
+        )
+    }
+
+    #[test]
+    fn rays_find_the_displaced_surface() {
+        let floor = gravel();
+        let mut bumpy = false;
+        for k in 0..20 {
+            let x = 0.173 * k as f64;
+            for direction in [-glm::DVec3::y(), glm::DVec3::new(1.0, -0.3, 0.2)] {
+                let ray = Ray::new(glm::DVec3::new(x, 1.0, -0.5), direction);
+                let hit = floor.intersect(&ray).unwrap();
+                let surface = floor.surface_height(&hit.point);
+                assert_relative_eq!(surface, hit.point.y, epsilon = 1e-4);
+                assert!((0.0..=0.1).contains(&hit.point.y));
+                assert_relative_eq!(1.0, hit.normal.norm(), epsilon = 1e-9);
+                assert!(hit.normal.y > 0.0);
+                bumpy |= hit.normal.y < 0.999;
+            }
+        }
+        assert!(bumpy);
+
+        // Leaving the surface doesn't hit it again right away
+        let hit = floor
+            .intersect(&Ray::new(glm::DVec3::new(0.3, 1.0, 0.2), -glm::DVec3::y()))
+            .unwrap();
+        let up = Ray::new(hit.point + 1e-6 * glm::DVec3::y(), glm::DVec3::y());
+        assert!(floor.intersect(&up).is_none());
+        assert!(floor
+            .intersect(&Ray::new(glm::DVec3::y(), glm::DVec3::x()))
+            .is_none());
+
+        // Seen from below, the flat plane
+        let below = Ray::new(glm::DVec3::new(0.0, -1.0, 0.0), glm::DVec3::y());
+        assert_relative_eq!(1.0, floor.intersect(&below).unwrap().ray_t);
+    }
+
+    #[test]
+    fn flat_displacement_is_a_plane() {
+        let mut floor = gravel();
+        floor.displacement.amplitude = 0.0;
+        floor.checker = Some(1.0);
+        let plane = Plane {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+        };
+
+        let ray = Ray::new(
+            glm::DVec3::new(0.5, 2.0, 0.5),
+            glm::DVec3::new(0.3, -1.0, 0.1),
+        );
+        let hit = floor.intersect(&ray).unwrap();
+        let expected = plane.intersect(&ray).unwrap();
+        assert_relative_eq!(expected.point, hit.point, epsilon = 1e-9);
+        assert_relative_eq!(expected.normal, hit.normal, epsilon = 1e-9);
+
+        // Neighbouring squares of the checker alternate between the materials
+        let shifted = Ray::new(ray.origin + glm::DVec3::new(1.0, 0.0, 0.0), ray.direction);
+        let other = floor.intersect(&shifted).unwrap();
+        assert_ne!(hit.material, other.material);
+        assert!([0, NO_MATERIAL].contains(&hit.material));
+        assert!([0, NO_MATERIAL].contains(&other.material));
+    }
+}
@@ -0,0 +1,537 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::f64::consts::PI;
+
+use bumpalo::Bump;
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::camera::Camera;
+use crate::color::{self, Color};
+use crate::render::PathTracer;
+use crate::sampling::{self, RngStream};
+use crate::scene::Scene;
+
+/// Maximum depth of the spatial binary tree
+const MAX_SPATIAL_DEPTH: u32 = 24;
+
+/// Maximum depth of the directional quadtrees
+const MAX_DIRECTIONAL_DEPTH: u32 = 12;
+
+/// Experimental path guiding with a spatial-directional tree (SD-tree).
+///
+/// The image is first rendered in a few short training passes with 1, 2, 4...
+/// samples per pixel. Every diffuse bounce records the radiance it brings
+/// back, and after each pass the records are fitted by a binary tree over
+/// space whose leaves hold quadtrees over the sphere of directions. The next
+/// pass samples bounces from this distribution, mixed with the cosine-weighted
+/// BSDF distribution, so that paths are sent where light actually comes from,
+/// like the gap of a door.
+///
+/// Guided bounces treat surfaces as Lambertian. Specular materials and those
+/// with their own BSDF keep their own sampling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathGuiding {
+    pub training_iterations: u32,
+    pub spatial_threshold: usize, // Records above which a spatial leaf is split
+    pub flux_threshold: f64,      // Fraction of the flux above which a quadrant is split
+    pub guide_probability: f64,   // Probability of sampling from the guiding distribution
+}
+
+impl Default for PathGuiding {
+    fn default() -> Self {
+        Self {
+            training_iterations: 4,
+            spatial_threshold: 4000,
+            flux_threshold: 0.01,
+            guide_probability: 0.5,
+        }
+    }
+}
+
+/// Radiance arriving at a path vertex from a direction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadianceSample {
+    pub position: glm::DVec3,
+    pub direction: glm::DVec3,
+    pub value: f64, // Luminance of the radiance over the pdf of the direction
+}
+
+/// Area preserving map from directions to the unit square
+fn to_square(direction: &glm::DVec3) -> glm::DVec2 {
+    let cos_theta = direction.z.clamp(-1.0, 1.0);
+    let phi = direction.y.atan2(direction.x);
+    glm::DVec2::new(
+        ((cos_theta + 1.0) / 2.0).min(1.0 - f64::EPSILON),
+        (phi / (2.0 * PI)).rem_euclid(1.0),
+    )
+}
+
+fn to_direction(p: &glm::DVec2) -> glm::DVec3 {
+    let cos_theta = 2.0 * p.x - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * p.y;
+    glm::DVec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Quadrant of a point of the unit square, moving the point to the
+/// coordinates of the quadrant
+fn quadrant(p: &mut glm::DVec2) -> usize {
+    let qx = (p.x >= 0.5) as usize;
+    let qy = (p.y >= 0.5) as usize;
+    p.x = (2.0 * p.x - qx as f64).clamp(0.0, 1.0 - f64::EPSILON);
+    p.y = (2.0 * p.y - qy as f64).clamp(0.0, 1.0 - f64::EPSILON);
+    2 * qy + qx
+}
+
+#[derive(Debug, Clone, Default)]
+struct QuadNode {
+    sums: [f64; 4],
+    children: [usize; 4], // 0 for leaves, the root is never a child
+}
+
+impl QuadNode {
+    fn total(&self) -> f64 {
+        self.sums.iter().sum()
+    }
+}
+
+/// Distribution over the sphere of directions, as a quadtree on the square
+/// of `to_square` that is finer where more radiance comes from
+#[derive(Debug, Clone)]
+pub struct DirectionalTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl DirectionalTree {
+    fn build(samples: &[RadianceSample], flux_threshold: f64) -> Self {
+        let points: Vec<(glm::DVec2, f64)> = samples
+            .iter()
+            .map(|s| (to_square(&s.direction), s.value))
+            .collect();
+        let total: f64 = points.iter().map(|p| p.1).sum();
+
+        let mut tree = Self { nodes: Vec::new() };
+        tree.build_node(points, total * flux_threshold, 0);
+        tree
+    }
+
+    fn build_node(&mut self, points: Vec<(glm::DVec2, f64)>, split_flux: f64, depth: u32) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(QuadNode::default());
+
+        let mut quadrants: [Vec<(glm::DVec2, f64)>; 4] = Default::default();
+        for (mut p, value) in points {
+            let q = quadrant(&mut p);
+            self.nodes[index].sums[q] += value;
+            quadrants[q].push((p, value));
+        }
+
+        for (q, points) in quadrants.into_iter().enumerate() {
+            let flux = self.nodes[index].sums[q];
+            if depth + 1 < MAX_DIRECTIONAL_DEPTH && flux > split_flux && points.len() > 1 {
+                let child = self.build_node(points, split_flux, depth + 1);
+                self.nodes[index].children[q] = child;
+            }
+        }
+
+        index
+    }
+
+    /// Density with respect to solid angle
+    pub fn pdf(&self, direction: &glm::DVec3) -> f64 {
+        let mut p = to_square(direction);
+        let mut pdf = 1.0;
+        let mut node = &self.nodes[0];
+        loop {
+            let total = node.total();
+            if total <= 0.0 {
+                break;
+            }
+            let q = quadrant(&mut p);
+            pdf *= 4.0 * node.sums[q] / total;
+            match node.children[q] {
+                0 => break,
+                child => node = &self.nodes[child],
+            }
+        }
+        pdf / (4.0 * PI)
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> glm::DVec3 {
+        let mut origin = glm::DVec2::zeros();
+        let mut scale = 1.0;
+        let mut node = &self.nodes[0];
+        loop {
+            let total = node.total();
+            if total <= 0.0 {
+                break;
+            }
+
+            let mut u = rng.gen::<f64>() * total;
+            let mut q = 0;
+            while q < 3 && u >= node.sums[q] {
+                u -= node.sums[q];
+                q += 1;
+            }
+
+            scale *= 0.5;
+            origin += scale * glm::DVec2::new((q % 2) as f64, (q / 2) as f64);
+            match node.children[q] {
+                0 => break,
+                child => node = &self.nodes[child],
+            }
+        }
+
+        to_direction(&(origin + scale * glm::DVec2::new(rng.gen(), rng.gen())))
+    }
+}
+
+enum SpatialNode {
+    Leaf(DirectionalTree),
+    Split {
+        axis: usize,
+        position: f64,
+        children: Box<[SpatialNode; 2]>,
+    },
+}
+
+/// Binary tree over space with a directional distribution in each leaf
+pub struct SdTree {
+    root: SpatialNode,
+}
+
+impl SdTree {
+    pub fn build(samples: Vec<RadianceSample>, guiding: &PathGuiding) -> Self {
+        let mut min = glm::DVec3::repeat(f64::INFINITY);
+        let mut max = glm::DVec3::repeat(f64::NEG_INFINITY);
+        for sample in &samples {
+            min = min.inf(&sample.position);
+            max = max.sup(&sample.position);
+        }
+
+        Self {
+            root: Self::build_node(samples, min, max, guiding, 0),
+        }
+    }
+
+    fn build_node(
+        samples: Vec<RadianceSample>,
+        min: glm::DVec3,
+        max: glm::DVec3,
+        guiding: &PathGuiding,
+        depth: u32,
+    ) -> SpatialNode {
+        if samples.len() <= guiding.spatial_threshold || depth >= MAX_SPATIAL_DEPTH {
+            return SpatialNode::Leaf(DirectionalTree::build(&samples, guiding.flux_threshold));
+        }
+
+        let axis = (max - min).imax();
+        let position = 0.5 * (min[axis] + max[axis]);
+        let (below, above) = samples
+            .into_iter()
+            .partition(|s: &RadianceSample| s.position[axis] < position);
+
+        let mut below_max = max;
+        below_max[axis] = position;
+        let mut above_min = min;
+        above_min[axis] = position;
+
+        SpatialNode::Split {
+            axis,
+            position,
+            children: Box::new([
+                Self::build_node(below, min, below_max, guiding, depth + 1),
+                Self::build_node(above, above_min, max, guiding, depth + 1),
+            ]),
+        }
+    }
+
+    /// Directional distribution of the region containing a point
+    pub fn lookup(&self, position: &glm::DVec3) -> &DirectionalTree {
+        let mut node = &self.root;
+        loop {
+            match node {
+                SpatialNode::Leaf(tree) => return tree,
+                SpatialNode::Split {
+                    axis,
+                    position: split,
+                    children,
+                } => node = &children[(position[*axis] >= *split) as usize],
+            }
+        }
+    }
+}
+
+/// Guided bounce of the current path, waiting for the radiance that comes
+/// back along the rest of the path
+struct PendingVertex {
+    position: glm::DVec3,
+    direction: glm::DVec3,
+    pdf: f64,
+    throughput: Color, // Product of the scattering weights after this bounce
+    radiance: Color,   // Incoming radiance gathered so far
+}
+
+/// Guiding state of a path tracing worker
+pub(crate) struct Guide<'a> {
+    tree: Option<&'a SdTree>,
+    probability: f64,
+    samples: Option<Vec<RadianceSample>>, // Recorded while training
+    pending: Vec<PendingVertex>,          // Reused from path to path
+}
+
+impl<'a> Guide<'a> {
+    pub fn new(tree: Option<&'a SdTree>, probability: f64) -> Self {
+        Self {
+            tree,
+            probability,
+            samples: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Sample the bounce of a diffuse surface whose normal faces the incoming
+    /// ray. Returns the direction and its pdf for the mixture of the cosine
+    /// and guiding distributions.
+    pub fn sample_bounce(
+        &self,
+        position: &glm::DVec3,
+        normal: &glm::DVec3,
+        rng: &mut impl Rng,
+    ) -> (glm::DVec3, f64) {
+        let Some(tree) = self.tree.map(|tree| tree.lookup(position)) else {
+            let (local, pdf) = sampling::cosine_hemisphere(rng);
+            return (sampling::to_world(normal, &local), pdf);
+        };
+
+        let direction = if rng.gen::<f64>() < self.probability {
+            tree.sample(rng)
+        } else {
+            let (local, _) = sampling::cosine_hemisphere(rng);
+            sampling::to_world(normal, &local)
+        };
+        (direction, self.pdf(position, normal, &direction))
+    }
+
+    /// Density, with respect to solid angle, of `sample_bounce` returning
+    /// `direction`
+    pub fn pdf(&self, position: &glm::DVec3, normal: &glm::DVec3, direction: &glm::DVec3) -> f64 {
+        let cosine_pdf = direction.dot(normal).max(0.0) / PI;
+        match self.tree.map(|tree| tree.lookup(position)) {
+            Some(tree) => {
+                (1.0 - self.probability) * cosine_pdf + self.probability * tree.pdf(direction)
+            }
+            None => cosine_pdf,
+        }
+    }
+
+    /// Start gathering the radiance arriving at a guided bounce. Only done
+    /// while training.
+    pub fn add_vertex(&mut self, position: &glm::DVec3, direction: &glm::DVec3, pdf: f64) {
+        if self.samples.is_some() {
+            self.pending.push(PendingVertex {
+                position: *position,
+                direction: *direction,
+                pdf,
+                throughput: Color::new(1.0, 1.0, 1.0),
+                radiance: Color::zeros(),
+            });
+        }
+    }
+
+    /// Radiance emitted towards the path, before any later scattering
+    pub fn add_radiance(&mut self, radiance: &Color) {
+        for vertex in &mut self.pending {
+            vertex.radiance += vertex.throughput.component_mul(radiance);
+        }
+    }
+
+    /// Scattering weight of a bounce further along the path
+    pub fn attenuate(&mut self, weight: &Color) {
+        for vertex in &mut self.pending {
+            vertex.throughput = vertex.throughput.component_mul(weight);
+        }
+    }
+
+    /// Record the radiance that came back to each guided bounce of the path
+    pub fn finish_path(&mut self) {
+        let Some(samples) = &mut self.samples else {
+            return;
+        };
+        for vertex in self.pending.drain(..) {
+            let value = color::luminance(&vertex.radiance) / vertex.pdf;
+            if value.is_finite() && value > 0.0 {
+                samples.push(RadianceSample {
+                    position: vertex.position,
+                    direction: vertex.direction,
+                    value,
+                });
+            }
+        }
+    }
+}
+
+impl PathGuiding {
+    /// Run the training passes and return the last fitted distribution, if
+    /// any radiance was recorded. The paths are drawn from `seed`.
+    pub fn train(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+        seed: u64,
+    ) -> Option<SdTree> {
+        let (w, h) = camera.resolution();
+        let mut tree: Option<SdTree> = None;
+
+        for iteration in 0..self.training_iterations {
+            let spp = 1 << iteration.min(16);
+            let samples: Vec<RadianceSample> = (0..w * h)
+                .into_par_iter()
+                .map_init(Bump::new, |scratch, n| {
+                    let (i, j) = (n % w, n / w);
+                    let mut guide = Guide::new(tree.as_ref(), self.guide_probability);
+                    guide.samples = Some(Vec::new());
+                    for sample in 0..spp {
+                        let stream = RngStream::Training(iteration);
+                        let mut rng = sampling::sample_rng(seed, stream, (i, j), sample);
+                        let offset = renderer.pixel_offset(i, j, sample, seed);
+                        let ray = camera
+                            .cast_ray_jittered(i, j, offset, &mut rng)
+                            .expect("Expected a Ray");
+                        renderer.trace_path(scene, &ray, Some(&mut guide), scratch, &mut rng);
+                    }
+                    guide.samples.unwrap_or_default()
+                })
+                .flatten()
+                .collect();
+
+            if samples.is_empty() {
+                break;
+            }
+            tree = Some(SdTree::build(samples, self));
+        }
+
+        tree
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn samples_towards(
+        direction: glm::DVec3,
+        position: glm::DVec3,
+        n: usize,
+    ) -> Vec<RadianceSample> {
+        (0..n)
+            .map(|_| RadianceSample {
+                position,
+                direction: direction.normalize(),
+                value: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn square_mapping() {
+        for direction in [
+            glm::DVec3::new(1.0, 2.0, 3.0).normalize(),
+            glm::DVec3::new(-1.0, -0.5, 0.1).normalize(),
+            -glm::DVec3::y(),
+        ] {
+            assert_relative_eq!(
+                direction,
+                to_direction(&to_square(&direction)),
+                epsilon = 1e-9
+            );
+        }
+    }
+
+    #[test]
+    fn directional_tree_learns_a_lobe() {
+        let lobe = glm::DVec3::new(0.2, 1.0, 0.3).normalize();
+        let mut samples = samples_towards(lobe, glm::DVec3::zeros(), 200);
+        samples.extend(samples_towards(-lobe, glm::DVec3::zeros(), 2));
+        let tree = DirectionalTree::build(&samples, 0.01);
+
+        assert!(tree.pdf(&lobe) > 100.0 / (4.0 * PI));
+        assert!(tree.pdf(&glm::DVec3::x()) < 1.0 / (4.0 * PI));
+
+        // Most samples point to the lobe
+        let mut rng = StdRng::seed_from_u64(3);
+        let hits = (0..1000)
+            .filter(|_| tree.sample(&mut rng).dot(&lobe) > 0.95)
+            .count();
+        assert!(hits > 900);
+    }
+
+    #[test]
+    fn directional_pdf_is_normalized() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let samples: Vec<RadianceSample> = (0..500)
+            .map(|_| {
+                let direction = to_direction(&glm::DVec2::new(rng.gen(), rng.gen()));
+                RadianceSample {
+                    position: glm::DVec3::zeros(),
+                    direction,
+                    value: if direction.z > 0.0 { 10.0 } else { 1.0 },
+                }
+            })
+            .collect();
+        let tree = DirectionalTree::build(&samples, 0.01);
+
+        let n = 100_000;
+        let integral: f64 = (0..n)
+            .map(|_| {
+                let p = glm::DVec2::new(rng.gen(), rng.gen());
+                tree.pdf(&to_direction(&p)) * 4.0 * PI
+            })
+            .sum::<f64>()
+            / n as f64;
+        assert_relative_eq!(1.0, integral, epsilon = 0.05);
+    }
+
+    #[test]
+    fn spatial_tree_separates_regions() {
+        let mut samples = samples_towards(glm::DVec3::x(), glm::DVec3::new(-10.0, 0.0, 0.0), 50);
+        samples.extend(samples_towards(
+            glm::DVec3::z(),
+            glm::DVec3::new(10.0, 0.0, 0.0),
+            50,
+        ));
+        let guiding = PathGuiding {
+            spatial_threshold: 60,
+            ..Default::default()
+        };
+        let tree = SdTree::build(samples, &guiding);
+
+        let left = tree.lookup(&glm::DVec3::new(-9.0, 0.0, 0.0));
+        let right = tree.lookup(&glm::DVec3::new(9.0, 0.0, 0.0));
+        assert!(left.pdf(&glm::DVec3::x()) > left.pdf(&glm::DVec3::z()));
+        assert!(right.pdf(&glm::DVec3::z()) > right.pdf(&glm::DVec3::x()));
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::material::{Material, ShadingContext, Specular};
+use crate::render;
+use crate::scene::Scene;
+
+/// What the camera sees through a pixel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pick {
+    pub object_id: usize, // Index of the object in the scene
+    pub shape: &'static str,
+    pub material: String,
+    pub albedo: Color, // Surface color at the hit
+    pub emittance: f64,
+    pub distance: f64,
+    pub position: glm::DVec3,
+    pub normal: glm::DVec3,
+    pub uv: glm::DVec2,
+}
+
+/// Find the object seen through the center of pixel (i, j). Returns None if
+/// the pixel shows the background.
+pub fn pick(scene: &Scene, camera: &Camera, (i, j): (u32, u32)) -> Result<Option<Pick>, String> {
+    let (w, h) = camera.resolution();
+    let ray = camera
+        .center_ray(i, j)
+        .ok_or_else(|| format!("Pixel ({i}, {j}) is outside of the {w}×{h} image"))?;
+
+    Ok(
+        render::get_closest_hit(&scene.objects, &ray).map(|(record, object, id)| {
+            let ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+            let material = object.material_at(record.material);
+            Pick {
+                object_id: id,
+                shape: object.shape.kind(),
+                material: describe(material),
+                albedo: material.albedo(&ctx),
+                emittance: material.emittance,
+                distance: record.ray_t,
+                position: record.point,
+                normal: record.normal,
+                uv: record.uv,
+            }
+        }),
+    )
+}
+
+fn describe(material: &Material) -> String {
+    match material.specular() {
+        Some(Specular::Mirror) => return "mirror".to_string(),
+        Some(Specular::Dielectric { ior }) => return format!("dielectric, ior {ior}"),
+        Some(Specular::Metal { roughness }) => return format!("metal, roughness {roughness}"),
+        None => {}
+    }
+    if let Some(bsdf) = &material.bsdf {
+        bsdf.kind().to_string()
+    } else if material.procedural.is_some() {
+        "procedural".to_string()
+    } else if material.texture.is_some() {
+        "textured".to_string()
+    } else {
+        "diffuse".to_string()
+    }
+}
+
+impl Pick {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "object": self.object_id,
+            "shape": self.shape,
+            "material": {
+                "type": self.material,
+                "albedo": [self.albedo.x, self.albedo.y, self.albedo.z],
+                "emittance": self.emittance,
+            },
+            "distance": self.distance,
+            "position": [self.position.x, self.position.y, self.position.z],
+            "normal": [self.normal.x, self.normal.y, self.normal.z],
+            "uv": [self.uv.x, self.uv.y],
+        })
+    }
+}
+
+impl fmt::Display for Pick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vector = |v: &glm::DVec3| format!("({:.4}, {:.4}, {:.4})", v.x, v.y, v.z);
+        writeln!(f, "Object:    {} ({})", self.object_id, self.shape)?;
+        writeln!(f, "Material:  {}", self.material)?;
+        writeln!(f, "Albedo:    {}", vector(&self.albedo))?;
+        writeln!(f, "Emittance: {}", self.emittance)?;
+        writeln!(f, "Distance:  {:.4}", self.distance)?;
+        writeln!(f, "Position:  {}", vector(&self.position))?;
+        writeln!(f, "Normal:    {}", vector(&self.normal))?;
+        write!(f, "UV:        ({:.4}, {:.4})", self.uv.x, self.uv.y)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView, FocusMode};
+    use crate::object::Object;
+    use crate::shape::{Plane, Sphere};
+
+    #[test]
+    fn pick_objects() {
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 0.0, 10.0),
+                    normal: -glm::DVec3::z(),
+                }),
+                material: Material {
+                    color: Color::new(1.0, 2.0, 3.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 1.0)),
+                material: Material {
+                    bsdf: Some(Arc::new(Specular::Dielectric { ior: 1.5 })),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        let camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (101, 101),
+            fov: FieldOfView::Horizontal(60.0_f64.to_radians()),
+            focus_mode: FocusMode::PinHole,
+            ..Default::default()
+        });
+
+        // The sphere hides the wall in the center of the image
+        let center = pick(&scene, &camera, (50, 50)).unwrap().unwrap();
+        assert_eq!(1, center.object_id);
+        assert_eq!("sphere", center.shape);
+        assert_eq!("dielectric, ior 1.5", center.material);
+        assert_relative_eq!(4.0, center.distance, epsilon = 1e-9);
+        assert_relative_eq!(
+            glm::DVec3::new(0.0, 0.0, 4.0),
+            center.position,
+            epsilon = 1e-9
+        );
+
+        let corner = pick(&scene, &camera, (0, 0)).unwrap().unwrap();
+        assert_eq!(0, corner.object_id);
+        assert_eq!("plane", corner.shape);
+        assert_eq!(Color::new(1.0, 2.0, 3.0), corner.albedo);
+        assert_eq!("diffuse", corner.to_json()["material"]["type"]);
+
+        // A wide aperture doesn't move the picked point
+        let camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (101, 101),
+            fov: FieldOfView::Horizontal(60.0_f64.to_radians()),
+            focus_mode: FocusMode::FocalPlane {
+                focal_distance: 10.0,
+                aperture: 0.9,
+            },
+            ..Default::default()
+        });
+        for _ in 0..4 {
+            let center = pick(&scene, &camera, (50, 50)).unwrap().unwrap();
+            assert_relative_eq!(4.0, center.distance, epsilon = 1e-9);
+        }
+
+        assert!(pick(&scene, &camera, (101, 0)).is_err());
+        assert_eq!(None, pick(&Scene::new(), &camera, (50, 50)).unwrap());
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Irradiance caching for quick previews of the global illumination.
+//!
+//! The indirect irradiance of diffuse surfaces changes slowly, so it is only
+//! integrated over the hemisphere at a sparse set of points and interpolated
+//! in between, following Ward et al. Each record is valid around its point
+//! as far as the surfaces that its hemisphere sees are, so records are dense
+//! in corners and sparse on open surfaces.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use bumpalo::Bump;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::contact;
+use crate::film::Film;
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::render::{get_closest_hit, PathState, PathTracer, SURFACE_OFFSET};
+use crate::sampling::{self, cosine_hemisphere, to_world, RngStream};
+use crate::scene::Scene;
+
+/// Spacing of the pixels whose first hits are cached in the first round.
+/// Each round halves it, caching the hits that no record covers yet.
+const FIRST_SPACING: u32 = 16;
+
+/// Preview rendering that takes the indirect light of diffuse surfaces from
+/// an irradiance cache, and samples their direct light. Specular surfaces are
+/// path traced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrradianceCaching {
+    pub accuracy: f64,       // Larger values reuse records further away
+    pub rays: u32,           // Hemisphere rays per record
+    pub min_spacing: f64,    // Bounds of the validity radius of the records
+    pub max_spacing: f64,    // in world units, before the accuracy
+    pub direct_samples: u32, // Light samples, or paths on specular surfaces, per pixel
+}
+
+impl Default for IrradianceCaching {
+    fn default() -> Self {
+        Self {
+            accuracy: 0.25,
+            rays: 128,
+            min_spacing: 0.05,
+            max_spacing: 2.0,
+            direct_samples: 16,
+        }
+    }
+}
+
+/// Indirect irradiance integrated at a point of a surface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrradianceRecord {
+    pub position: glm::DVec3,
+    pub normal: glm::DVec3,
+    pub irradiance: Color,
+    pub radius: f64, // Harmonic mean distance to the surfaces seen from the point
+}
+
+/// Records hashed in a grid of cells as large as the largest region where a
+/// record is valid
+pub struct IrradianceCache {
+    accuracy: f64,
+    cell_size: f64,
+    cells: HashMap<(i64, i64, i64), Vec<IrradianceRecord>>,
+}
+
+impl IrradianceCache {
+    pub fn new(accuracy: f64, max_radius: f64) -> Self {
+        Self {
+            accuracy,
+            cell_size: accuracy * max_radius,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: &glm::DVec3) -> (i64, i64, i64) {
+        let c = position / self.cell_size;
+        (c.x.floor() as i64, c.y.floor() as i64, c.z.floor() as i64)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn insert(&mut self, record: IrradianceRecord) {
+        self.cells
+            .entry(self.cell(&record.position))
+            .or_default()
+            .push(record);
+    }
+
+    /// Irradiance interpolated from the records valid at a point, weighted
+    /// by how little the point moved and the normal turned from theirs.
+    /// None if no record is valid there.
+    pub fn interpolate(&self, position: &glm::DVec3, normal: &glm::DVec3) -> Option<Color> {
+        let (x, y, z) = self.cell(position);
+        let mut irradiance = Color::zeros();
+        let mut weights = 0.0;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let Some(records) = self.cells.get(&(x + dx, y + dy, z + dz)) else {
+                        continue;
+                    };
+                    for record in records {
+                        let offset = position - record.position;
+                        // Records behind the point don't see what it sees
+                        if offset.dot(&record.normal) < -0.05 * record.radius {
+                            continue;
+                        }
+                        let error = offset.norm() / record.radius
+                            + (1.0 - normal.dot(&record.normal)).max(0.0).sqrt();
+                        if error < self.accuracy {
+                            let weight = 1.0 / error.max(1e-9);
+                            irradiance += weight * record.irradiance;
+                            weights += weight;
+                        }
+                    }
+                }
+            }
+        }
+        (weights > 0.0).then(|| irradiance / weights)
+    }
+}
+
+/// First hit of the ray through the center of a pixel
+struct PixelHit {
+    ray: Ray,
+    ctx: ShadingContext,
+    material: usize, // Index of the material in the object
+}
+
+impl PixelHit {
+    fn is_diffuse(&self, scene: &Scene) -> bool {
+        let material = scene.objects[self.ctx.object_id].material_at(self.material);
+        material.bsdf.is_none()
+    }
+}
+
+impl IrradianceCaching {
+    /// Render a preview with one ray through the center of each pixel
+    pub fn render(&self, renderer: &PathTracer, scene: &Scene, camera: &Camera) -> Film {
+        let (w, h) = camera.resolution();
+        let seed = renderer.render_seed();
+        let hits: Vec<Option<PixelHit>> = (0..w * h)
+            .into_par_iter()
+            .map(|index| {
+                let ray = camera.center_ray(index % w, index / w)?;
+                let (record, _, id) = get_closest_hit(&scene.objects, &ray)?;
+                let mut ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+                if ctx.normal.dot(&ray.direction) > 0.0 {
+                    ctx.normal = -ctx.normal;
+                }
+                Some(PixelHit {
+                    ray,
+                    ctx,
+                    material: record.material,
+                })
+            })
+            .collect();
+
+        // Coarse to fine rounds of records at the diffuse hits that the
+        // previous rounds don't cover
+        let mut cache = IrradianceCache::new(self.accuracy, self.max_spacing);
+        let mut spacing = FIRST_SPACING;
+        while spacing > 0 {
+            let pending: Vec<u32> = (0..w * h)
+                .filter(|index| index % w % spacing == 0 && index / w % spacing == 0)
+                .filter(|&index| {
+                    hits[index as usize].as_ref().is_some_and(|hit| {
+                        hit.is_diffuse(scene)
+                            && cache
+                                .interpolate(&hit.ctx.position, &hit.ctx.normal)
+                                .is_none()
+                    })
+                })
+                .collect();
+            let records: Vec<IrradianceRecord> = pending
+                .into_par_iter()
+                .filter_map(|index| {
+                    let hit = hits[index as usize].as_ref()?;
+                    Some(self.record(renderer, scene, &hit.ctx, seed, index))
+                })
+                .collect();
+            for record in records {
+                cache.insert(record);
+            }
+            spacing /= 2;
+        }
+
+        let colors: Vec<Color> = (0..w * h)
+            .into_par_iter()
+            .map(|index| match &hits[index as usize] {
+                None => {
+                    let Some(ray) = camera.center_ray(index % w, index / w) else {
+                        return Color::zeros();
+                    };
+                    let mut rng = sampling::sample_rng(seed, RngStream::Camera, (index, 0), 0);
+                    contact::floor_attenuation(scene, &ray, f64::INFINITY, &mut rng)
+                        * scene.background(&ray.direction)
+                }
+                Some(hit) => self.shade(renderer, scene, &cache, hit, seed, index),
+            })
+            .collect();
+
+        let mut film = Film::with_precision(w, h, renderer.film_precision);
+        for (index, color) in (0..w * h).zip(colors) {
+            film.add_sample(index % w, index / w, color, renderer.non_finite_policy);
+        }
+        film
+    }
+
+    /// Integrate the indirect irradiance over the hemisphere of a point with
+    /// cosine weighted rays
+    fn record(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        ctx: &ShadingContext,
+        seed: u64,
+        index: u32,
+    ) -> IrradianceRecord {
+        let mut rng = sampling::sample_rng(seed, RngStream::IrradianceCache, (index, 0), 0);
+        let rays = self.rays.max(1);
+        let mut scratch = Bump::new();
+        let mut radiance = Color::zeros();
+        let mut inverse_distances = 0.0;
+        for _ in 0..rays {
+            let direction = to_world(&ctx.normal, &cosine_hemisphere(&mut rng).0);
+            let ray = Ray::new(ctx.position + SURFACE_OFFSET * direction, direction);
+            if let Some((record, ..)) = get_closest_hit(&scene.objects, &ray) {
+                inverse_distances += 1.0 / record.ray_t;
+            }
+            radiance += renderer.trace_indirect(scene, &ray, &mut scratch, &mut rng);
+        }
+        IrradianceRecord {
+            position: ctx.position,
+            normal: ctx.normal,
+            // The pdf of the rays cancels the cosine, leaving π
+            irradiance: PI * radiance / rays as f64,
+            radius: (rays as f64 / inverse_distances).clamp(self.min_spacing, self.max_spacing),
+        }
+    }
+
+    /// Radiance of a pixel: sampled direct light and cached indirect light
+    /// on diffuse surfaces, paths on the others
+    fn shade(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        cache: &IrradianceCache,
+        hit: &PixelHit,
+        seed: u64,
+        index: u32,
+    ) -> Color {
+        let mut rng = sampling::sample_rng(seed, RngStream::Camera, (index, 0), 0);
+        let samples = self.direct_samples.max(1);
+        if !hit.is_diffuse(scene) {
+            let mut scratch = Bump::new();
+            return (0..samples)
+                .map(|_| renderer.trace_path(scene, &hit.ray, None, &mut scratch, &mut rng))
+                .sum::<Color>()
+                / samples as f64;
+        }
+
+        let material = scene.objects[hit.ctx.object_id].material_at(hit.material);
+        let state = PathState::default();
+        let direct = (0..samples)
+            .filter_map(|_| renderer.sample_light(scene, material, &hit.ctx, state, &mut rng))
+            .map(|light| light.reflected(&hit.ray, material, &hit.ctx, None))
+            .sum::<Color>()
+            / samples as f64;
+        let irradiance = cache
+            .interpolate(&hit.ctx.position, &hit.ctx.normal)
+            .unwrap_or_else(|| {
+                self.record(renderer, scene, &hit.ctx, seed, index)
+                    .irradiance
+            });
+        let albedo = material.albedo(&hit.ctx) / 255.0;
+        renderer.emitted(scene, material, &hit.ctx, state)
+            + direct
+            + albedo.component_mul(&irradiance) / PI
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView};
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::Plane;
+
+    #[test]
+    fn interpolation() {
+        let mut cache = IrradianceCache::new(0.5, 1.0);
+        assert_eq!(
+            None,
+            cache.interpolate(&glm::DVec3::zeros(), &glm::DVec3::y())
+        );
+        for (x, value) in [(0.0, 10.0), (0.2, 20.0)] {
+            cache.insert(IrradianceRecord {
+                position: glm::DVec3::new(x, 0.0, 0.0),
+                normal: glm::DVec3::y(),
+                irradiance: Color::repeat(value),
+                radius: 1.0,
+            });
+        }
+        assert_eq!(2, cache.len());
+
+        // The closer record weighs more
+        let near_first = cache
+            .interpolate(&glm::DVec3::new(0.05, 0.0, 0.0), &glm::DVec3::y())
+            .unwrap();
+        assert!(near_first.x > 10.0 && near_first.x < 15.0);
+        let between = cache
+            .interpolate(&glm::DVec3::new(0.1, 0.0, 0.0), &glm::DVec3::y())
+            .unwrap();
+        assert_relative_eq!(15.0, between.x, epsilon = 1e-9);
+
+        // Too far away or facing elsewhere
+        assert_eq!(
+            None,
+            cache.interpolate(&glm::DVec3::new(0.8, 0.0, 0.0), &glm::DVec3::y())
+        );
+        assert_eq!(
+            None,
+            cache.interpolate(&glm::DVec3::zeros(), &glm::DVec3::x())
+        );
+    }
+
+    #[test]
+    fn floor_under_sky() {
+        // Every direction above the floor sees the sky, so the cached
+        // irradiance is exact and the floor reflects albedo × sky
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(10.0, 20.0, 40.0);
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: Material {
+                color: Color::new(255.0, 127.5, 51.0),
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        let camera = Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 1.0, 0.0),
+            direction: glm::DVec3::new(0.0, -1.0, 0.5),
+            resolution: (32, 32),
+            fov: FieldOfView::Horizontal(60.0_f64.to_radians()),
+            ..Default::default()
+        });
+        let mut renderer = PathTracer::new();
+        renderer.irradiance_caching(Some(IrradianceCaching {
+            rays: 16,
+            ..Default::default()
+        }));
+        let film = renderer.render_film(&scene, &camera);
+        for pixel in film.pixels() {
+            assert_relative_eq!(Color::new(10.0, 10.0, 8.0), pixel.color(), epsilon = 1e-9);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! light is a path tracer. A render is described by a [`Scene`], made of
+//! [`Object`]s that pair a shape with a [`Material`], and a [`Camera`]. The
+//! [`PathTracer`] renders them into an image, or into a [`film::Film`] that
+//! keeps the radiance of every pixel.
+//!
+//! ```no_run
+//! use light::shape::Sphere;
+//! use light::{Camera, CameraConfig, Color, Material, Object, PathTracer, Scene};
+//!
+//! let mut scene = Scene::new();
+//! scene.add_object(Object {
+//!     shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, -5.0), 1.0)),
+//!     material: Material {
+//!         color: Color::new(200.0, 50.0, 50.0),
+//!         ..Default::default()
+//!     },
+//!     materials: Vec::new(),
+//! });
+//! let camera = Camera::new(&CameraConfig {
+//!     direction: -glm::DVec3::z(),
+//!     resolution: (320, 240),
+//!     ..Default::default()
+//! });
+//!
+//! let mut renderer = PathTracer::new();
+//! renderer.samples_per_pixel(64).max_depth(5);
+//! renderer.render(&scene, &camera).save("sphere.png").unwrap();
+//! ```
+//!
+//! Scenes can also be read from files with [`loader::FileLoader`].
+
+pub mod adaptive;
+pub mod algebra;
+pub mod animation;
+pub mod aov;
+pub mod atmosphere;
+pub mod bevel;
+pub mod bluenoise;
+pub mod bsdfplot;
+pub mod camera;
+pub mod caustics;
+pub mod color;
+pub mod compression;
+pub mod config;
+pub mod contact;
+pub mod cubemap;
+pub mod dataset;
+#[cfg(feature = "oidn")]
+pub mod denoise;
+pub mod driver;
+#[cfg(feature = "embree")]
+pub mod embree;
+pub mod expr;
+pub mod film;
+pub mod filter;
+pub mod font;
+pub mod gradient;
+pub mod ground;
+pub mod guiding;
+pub mod inspect;
+pub mod irradiance;
+pub mod light;
+pub mod linking;
+pub mod loader;
+pub mod material;
+pub mod merl;
+pub mod metrics;
+pub mod mtl;
+pub mod object;
+pub mod output;
+pub mod preprocess;
+pub mod preview;
+pub mod progressive;
+pub mod ramp;
+pub mod randomize;
+pub mod reference;
+pub mod render;
+pub mod roi;
+pub mod sampler;
+pub mod sampling;
+pub mod scene;
+pub mod server;
+pub mod shape;
+pub mod spectrum;
+pub mod streaming;
+pub mod sun;
+pub mod temporal;
+pub mod texture;
+pub mod tile;
+pub mod variance;
+pub mod volume;
+pub mod wavefront;
+pub mod xray;
+
+pub use camera::{Camera, CameraConfig};
+pub use color::Color;
+pub use material::Material;
+pub use object::Object;
+pub use render::PathTracer;
+pub use scene::Scene;
+pub use shape::Shape;
@@ -17,25 +17,58 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use glm;
-
 #[derive(Debug, PartialEq)]
 pub struct Ray {
     pub origin: glm::DVec3,
     pub direction: glm::DVec3,
+    pub time: f64,      // Seconds, within the shutter interval of the camera
+    pub footprint: f64, // Width of the beam the ray stands for, at its origin
+    pub spread: f64,    // Growth of the width per unit of distance
 }
 
 impl Ray {
     pub fn new(origin: glm::DVec3, direction: glm::DVec3) -> Self {
         Self {
-            origin: origin,
+            origin,
             direction: direction.normalize(),
+            time: 0.0,
+            footprint: 0.0,
+            spread: 0.0,
         }
     }
 
+    /// The same ray at another time
+    pub fn with_time(self, time: f64) -> Self {
+        Self { time, ..self }
+    }
+
+    /// The same ray standing for a beam that widens by `spread` per unit of
+    /// distance, e.g. the angle a pixel covers
+    pub fn with_spread(self, spread: f64) -> Self {
+        Self { spread, ..self }
+    }
+
     pub fn point_at(&self, t: f64) -> glm::DVec3 {
         self.origin + (t * self.direction)
     }
+
+    /// Width of the beam at distance t, which grows linearly like a cone
+    pub fn footprint_at(&self, t: f64) -> f64 {
+        self.footprint + t * self.spread
+    }
+
+    /// Ray that carries on from `origin` along `direction` when this one
+    /// reaches it, e.g. after a bounce. It keeps the time of this ray and
+    /// the beam, which is as wide as this one is at `origin` and keeps
+    /// widening at the same rate, ignoring the curvature of the surface.
+    pub fn continued(&self, origin: glm::DVec3, direction: glm::DVec3) -> Self {
+        Self {
+            time: self.time,
+            footprint: self.footprint_at((origin - self.origin).norm()),
+            spread: self.spread,
+            ..Self::new(origin, direction)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -56,4 +89,17 @@ mod test {
             ray.point_at(1.0)
         );
     }
+
+    #[test]
+    fn beam_widens_along_bounces() {
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::new(0.0, 0.0, -1.0))
+            .with_time(0.5)
+            .with_spread(0.01);
+        assert_relative_eq!(0.02, ray.footprint_at(2.0));
+
+        let bounce = ray.continued(ray.point_at(2.0), glm::DVec3::new(0.0, 1.0, 0.0));
+        assert_eq!(0.5, bounce.time);
+        assert_relative_eq!(0.02, bounce.footprint);
+        assert_relative_eq!(0.03, bounce.footprint_at(1.0));
+    }
 }
@@ -0,0 +1,67 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Light linking, a lighting control that breaks physics on purpose: a light
+//! can be made to illuminate only some objects, and to be blocked only by
+//! some objects, e.g. to add a rim light to a character without lighting the
+//! set behind it.
+//!
+//! Lights are found both by sampling them and by the bounces. Restricting
+//! the shadows of a light only changes its shadow rays, so such lights are
+//! only found by sampling them and don't show in the bounces off diffuse
+//! surfaces.
+
+/// Objects affected by an emissive object, given by their index in
+/// `Scene::objects`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LightLink {
+    pub light: usize,
+    pub illuminates: Option<Vec<usize>>, // Objects lit by the light, all if None
+    pub shadowed_by: Option<Vec<usize>>, // Objects that block the light, all if None
+}
+
+impl LightLink {
+    pub fn new(light: usize) -> Self {
+        Self {
+            light,
+            ..Default::default()
+        }
+    }
+
+    pub fn illuminates(&self, object: usize) -> bool {
+        self.illuminates
+            .as_ref()
+            .is_none_or(|objects| objects.contains(&object))
+    }
+
+    pub fn is_shadowed_by(&self, object: usize) -> bool {
+        self.shadowed_by
+            .as_ref()
+            .is_none_or(|objects| objects.contains(&object))
+    }
+
+    /// Largest object index the link refers to
+    pub fn max_index(&self) -> usize {
+        [&self.illuminates, &self.shadowed_by]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .fold(self.light, |max, &object| max.max(object))
+    }
+}
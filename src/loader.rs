@@ -0,0 +1,2341 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::animation::{ObjectAnimation, ObjectPath};
+use crate::atmosphere::Atmosphere;
+use crate::bevel::Bevel;
+use crate::camera::{
+    ApertureMask, ApertureShape, CameraConfig, FieldOfView, FocusMode, Projection, Shutter,
+};
+use crate::color::Color;
+use crate::compression::TextureFormat;
+use crate::config::Settings;
+use crate::contact::ContactShadow;
+use crate::cubemap::CubeMap;
+use crate::driver::{Blend, Driver};
+#[cfg(feature = "embree")]
+use crate::embree::EmbreeMesh;
+use crate::expr::Expr;
+use crate::filter;
+use crate::ground::DisplacedPlane;
+use crate::linking::LightLink;
+use crate::material::{Material, Specular};
+use crate::merl::MerlBrdf;
+use crate::mtl::MtlLibrary;
+use crate::object::Object;
+use crate::randomize::MaterialJitter;
+use crate::scene::Scene;
+use crate::shape::{
+    Bounds, LevelOfDetail, Mesh, MeshFace, Plane, Shape, Sphere, Triangle, NO_MATERIAL,
+};
+use crate::streaming::{LazyMesh, MeshResidency};
+use crate::sun::SunPosition;
+use crate::texture::{Texture, TextureCache};
+use crate::volume::{BlackbodyEmission, NoiseField, ScalarField, Volume, VoxelGrid};
+
+/// Vertical field of view of the cameras that don't give one, in degrees
+const DEFAULT_FOV: f64 = 40.0;
+
+pub type ShapeFactory =
+    Box<dyn Fn(&Value) -> Result<Box<dyn Shape + Send + Sync>, String> + Send + Sync>;
+pub type MaterialFactory = Box<dyn Fn(&Value) -> Result<Material, String> + Send + Sync>;
+
+/// Named constructors for the shapes and materials that can appear in a
+/// scene file. Library users can register their own types here to extend
+/// the scene format.
+pub struct Registry {
+    shapes: HashMap<String, ShapeFactory>,
+    materials: HashMap<String, MaterialFactory>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_shape("sphere", |value| {
+            Ok(Box::new(Sphere::new(
+                parse_vec3(field(value, "center")?)?,
+                parse_f64(field(value, "radius")?)?,
+            )))
+        });
+        registry.register_shape("plane", |value| {
+            let normal = parse_vec3(field(value, "normal")?)?;
+            if normal.norm() == 0.0 {
+                return Err("'normal' can't be zero".to_string());
+            }
+            Ok(Box::new(Plane {
+                position: parse_vec3(field(value, "position")?)?,
+                normal: normal.normalize(),
+            }))
+        });
+        registry.register_shape("displaced_plane", |value| {
+            Ok(Box::new(parse_displaced_plane(value)?))
+        });
+        registry.register_shape("triangle", |value| {
+            match field(value, "vertices")?.as_array().map(Vec::as_slice) {
+                Some([a, b, c]) => Ok(Box::new(Triangle::new(
+                    parse_vec3(a)?,
+                    parse_vec3(b)?,
+                    parse_vec3(c)?,
+                ))),
+                _ => Err("'vertices' must be an array of 3 points".to_string()),
+            }
+        });
+        registry.register_shape("mesh", |value| Ok(Box::new(parse_mesh(value)?)));
+
+        registry.register_material("diffuse", parse_diffuse);
+        registry.register_material("mirror", |value| {
+            Ok(Material {
+                bsdf: Some(Arc::new(Specular::Mirror)),
+                ..parse_diffuse(value)?
+            })
+        });
+        registry.register_material("metal", |value| {
+            let roughness = value.get("roughness").map_or(Ok(0.0), parse_f64)?;
+            if !(0.0..=1.0).contains(&roughness) {
+                return Err("'roughness' must be in [0, 1]".to_string());
+            }
+            Ok(Material {
+                bsdf: Some(Arc::new(Specular::Metal { roughness })),
+                ..parse_diffuse(value)?
+            })
+        });
+        registry.register_material("dielectric", |value| {
+            let ior = value.get("ior").map_or(Ok(1.5), parse_f64)?;
+            if ior <= 0.0 {
+                return Err("'ior' must be positive".to_string());
+            }
+            Ok(Material {
+                bsdf: Some(Arc::new(Specular::Dielectric { ior })),
+                absorption: value
+                    .get("absorption")
+                    .map_or(Ok(Color::zeros()), parse_color)?,
+                ..parse_diffuse(value)?
+            })
+        });
+        registry
+    }
+}
+
+impl Registry {
+    /// Registry with the built-in types
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry without any type
+    pub fn empty() -> Self {
+        Self {
+            shapes: HashMap::new(),
+            materials: HashMap::new(),
+        }
+    }
+
+    pub fn register_shape<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&Value) -> Result<Box<dyn Shape + Send + Sync>, String> + Send + Sync + 'static,
+    {
+        self.shapes.insert(name.to_string(), Box::new(factory));
+        self
+    }
+
+    pub fn register_material<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&Value) -> Result<Material, String> + Send + Sync + 'static,
+    {
+        self.materials.insert(name.to_string(), Box::new(factory));
+        self
+    }
+
+    pub fn create_shape(&self, value: &Value) -> Result<Box<dyn Shape + Send + Sync>, String> {
+        let name = type_name(value)?;
+        // Levels of detail are made of other shapes of the registry
+        if name == "lod" {
+            return self
+                .create_level_of_detail(value)
+                .map_err(|e| format!("Invalid shape 'lod': {e}"));
+        }
+        let factory = self
+            .shapes
+            .get(name)
+            .ok_or_else(|| format!("Unknown shape type '{name}'"))?;
+        factory(value).map_err(|e| format!("Invalid shape '{name}': {e}"))
+    }
+
+    fn create_level_of_detail(
+        &self,
+        value: &Value,
+    ) -> Result<Box<dyn Shape + Send + Sync>, String> {
+        let mut lod = LevelOfDetail::new(self.create_shape(field(value, "coarsest")?)?);
+        for level in field(value, "levels")?
+            .as_array()
+            .ok_or("'levels' must be an array")?
+        {
+            let max_distance = parse_f64(field(level, "max_distance")?)?;
+            lod.add_level(max_distance, self.create_shape(field(level, "shape")?)?);
+        }
+        Ok(Box::new(lod))
+    }
+
+    pub fn create_material(&self, value: &Value) -> Result<Material, String> {
+        let name = type_name(value)?;
+        let factory = self
+            .materials
+            .get(name)
+            .ok_or_else(|| format!("Unknown material type '{name}'"))?;
+        factory(value).map_err(|e| format!("Invalid material '{name}': {e}"))
+    }
+}
+
+/// Syntax of a scene description. All of them describe the same schema, so
+/// the keys and values of a scene are the same in every format.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SceneFormat {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl SceneFormat {
+    /// Format given by the extension of a file. Files with other extensions
+    /// are read as JSON.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_lowercase).as_deref() {
+            Some("toml") => Self::Toml,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+
+    /// Parse a scene description into the JSON values read by the loader
+    pub fn parse(&self, source: &str) -> Result<Value, String> {
+        match self {
+            Self::Json => serde_json::from_str(source).map_err(|e| e.to_string()),
+            Self::Toml => toml::from_str(source).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::from_str(source).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Write the JSON values of a scene description in this format
+    pub fn serialize(&self, root: &Value) -> Result<String, String> {
+        match self {
+            Self::Json => serde_json::to_string_pretty(root)
+                .map(|source| source + "\n")
+                .map_err(|e| e.to_string()),
+            Self::Toml => toml::to_string_pretty(root).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::to_string(root).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Loads scenes from JSON, TOML and YAML files
+#[derive(Default)]
+pub struct FileLoader {
+    registry: Registry,
+    textures: Arc<TextureCache>,
+    lazy_mesh_size: Option<u64>, // Bytes
+    meshes: Arc<MeshResidency>,
+    embree: bool,
+    asset_root: Option<PathBuf>,
+}
+
+impl FileLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_registry(registry: Registry) -> Self {
+        Self {
+            registry,
+            ..Default::default()
+        }
+    }
+
+    /// Cache where the textures of loaded scenes are kept
+    pub fn texture_cache(&mut self, cache: Arc<TextureCache>) -> &mut Self {
+        self.textures = cache;
+        self
+    }
+
+    /// Load OBJ models of at least `min_size` bytes lazily, the first time
+    /// a ray reaches them, see `LazyMesh`. Objects can also ask for it with
+    /// `"lazy": true`, or refuse it with `"lazy": false`.
+    pub fn lazy_meshes(&mut self, min_size: Option<u64>) -> &mut Self {
+        self.lazy_mesh_size = min_size;
+        self
+    }
+
+    /// Budget shared by the lazy meshes of loaded scenes, see `MeshResidency`
+    pub fn mesh_residency(&mut self, residency: Arc<MeshResidency>) -> &mut Self {
+        self.meshes = residency;
+        self
+    }
+
+    /// Trace the OBJ models that are loaded right away with Embree instead
+    /// of their own hierarchy, see `EmbreeMesh`. They fail to load if the
+    /// crate is built without the `embree` feature.
+    pub fn embree(&mut self, embree: bool) -> &mut Self {
+        self.embree = embree;
+        self
+    }
+
+    /// Only read the files named by scenes from inside a directory, e.g.
+    /// for scenes sent over the network. Scenes that aren't loaded from a
+    /// file name them relative to it, and paths that leave it are refused.
+    pub fn asset_root(&mut self, root: Option<PathBuf>) -> &mut Self {
+        self.asset_root = root;
+        self
+    }
+
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        &mut self.registry
+    }
+
+    /// Load a scene in the format given by the extension of the file, see
+    /// `SceneFormat::from_path`
+    pub fn load_scene<P: AsRef<Path>>(&self, path: P) -> Result<Scene, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        let root = SceneFormat::from_path(path)
+            .parse(&source)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        self.parse_scene_in(&root, path.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Parse a JSON scene. Relative paths are resolved from the asset root,
+    /// or the working directory if there is none.
+    pub fn parse_scene(&self, source: &str) -> Result<Scene, String> {
+        self.parse_scene_as(source, SceneFormat::Json)
+    }
+
+    /// Parse a scene in any format. Relative paths are resolved from the
+    /// asset root, or the working directory if there is none.
+    pub fn parse_scene_as(&self, source: &str, format: SceneFormat) -> Result<Scene, String> {
+        let base_dir = self.asset_root.as_deref().unwrap_or(Path::new(""));
+        self.parse_scene_in(&format.parse(source)?, base_dir)
+    }
+
+    /// Path of a file named by a scene in `base_dir`, see `asset_root`
+    fn asset_path(&self, base_dir: &Path, path: &str) -> Result<PathBuf, String> {
+        confine(base_dir.join(path), self.asset_root.as_deref())
+    }
+
+    fn parse_scene_in(&self, root: &Value, base_dir: &Path) -> Result<Scene, String> {
+        let mut scene = Scene::new();
+        if let Some(background) = root.get("background") {
+            scene.background_color = parse_vec3(background)?;
+        }
+        if let Some(settings) = root.get("settings") {
+            scene.settings = Settings::from_json(settings)?;
+        }
+        if let Some(environment) = root.get("environment") {
+            let path = environment
+                .as_str()
+                .ok_or("'environment' must be a path to a cube map")?;
+            scene.environment = Some(Arc::new(CubeMap::load(self.asset_path(base_dir, path)?)?));
+        }
+
+        if let Some(atmosphere) = root.get("atmosphere") {
+            scene.atmosphere =
+                Some(parse_atmosphere(atmosphere).map_err(|e| format!("Invalid atmosphere: {e}"))?);
+        }
+
+        if let Some(volumes) = root.get("volumes") {
+            for volume in volumes.as_array().ok_or("'volumes' must be an array")? {
+                scene
+                    .volumes
+                    .push(parse_volume(volume).map_err(|e| format!("Invalid volume: {e}"))?);
+            }
+        }
+
+        if let Some(cameras) = root.get("cameras") {
+            for camera in cameras.as_array().ok_or("'cameras' must be an array")? {
+                scene.cameras.push(
+                    parse_camera_in(camera, base_dir, self.asset_root.as_deref())
+                        .map_err(|e| format!("Invalid camera: {e}"))?,
+                );
+            }
+        }
+
+        let objects = match root.get("objects") {
+            Some(objects) => objects
+                .as_array()
+                .ok_or("'objects' must be an array")?
+                .as_slice(),
+            None => &[],
+        };
+
+        for object in objects {
+            let mut model = self.parse_object(object, base_dir, &mut scene.warnings)?;
+            // Copies of an object with the same jitter still vary, by index
+            if let Some(jitter) = object.get("jitter") {
+                parse_jitter(jitter)
+                    .map_err(|e| format!("Invalid jitter: {e}"))?
+                    .apply(&mut model, scene.objects.len());
+            }
+            // Holes where a pattern is positive, e.g. "0.5 - fract(4 * u)"
+            if let Some(pattern) = object.get("cutout") {
+                let pattern = pattern.as_str().ok_or("'cutout' must be an expression")?;
+                let pattern = Expr::parse(pattern).map_err(|e| format!("Invalid cutout: {e}"))?;
+                model = model.with_filter(filter::cutout(pattern, scene.objects.len()));
+            }
+            if let Some(keyframes) = object.get("keyframes") {
+                let path = ObjectPath::from_json(keyframes)
+                    .map_err(|e| format!("Invalid keyframes: {e}"))?;
+                let mut animation =
+                    ObjectAnimation::new(scene.objects.len(), Arc::from(model.shape), path);
+                model.shape = Box::new(animation.update(0.0).ok_or("'keyframes' can't be empty")?);
+                scene.animations.push(animation);
+            }
+            let link = parse_light_link(object, scene.objects.len())
+                .map_err(|e| format!("Invalid light link: {e}"))?;
+            if link.illuminates.is_some() || link.shadowed_by.is_some() {
+                scene.light_links.push(link);
+            }
+            if let Some(shadow) = object.get("contact_shadow") {
+                let shadow = parse_contact_shadow(shadow, scene.objects.len())
+                    .map_err(|e| format!("Invalid contact shadow: {e}"))?;
+                scene.contact_shadows.extend(shadow);
+            }
+            scene.add_object(model);
+        }
+        if let Some(link) = scene
+            .light_links
+            .iter()
+            .find(|link| link.max_index() >= scene.objects.len())
+        {
+            return Err(format!(
+                "Invalid light link: object {} links to an object that doesn't exist",
+                link.light
+            ));
+        }
+
+        Ok(scene)
+    }
+
+    fn parse_object(
+        &self,
+        object: &Value,
+        base_dir: &Path,
+        warnings: &mut Vec<String>,
+    ) -> Result<Object, String> {
+        // A whole model, shaded with the materials of its MTL files
+        if let Some(path) = object.get("obj") {
+            let path = path.as_str().ok_or("'obj' must be a path to an OBJ file")?;
+            let path = self.asset_path(base_dir, path)?;
+            let lazy = match object.get("lazy") {
+                Some(lazy) => lazy.as_bool().ok_or("'lazy' must be true or false")?,
+                None => self.lazy_mesh_size.is_some_and(|min_size| {
+                    fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= min_size)
+                }),
+            };
+            let (mut model, obj_warnings) = if lazy {
+                self.load_lazy_obj(&path)?
+            } else {
+                self.load_obj(&path)?
+            };
+            if let Some(value) = object.get("material") {
+                model.material = self.parse_material(value, base_dir)?;
+            }
+            warnings.extend(obj_warnings);
+            return Ok(model);
+        }
+
+        let shape = self.registry.create_shape(field(object, "shape")?)?;
+        let material = match object.get("material") {
+            Some(value) => self.parse_material(value, base_dir)?,
+            None => Material::default(),
+        };
+        // Faces of a shape can pick one of several materials by index
+        let materials = match object.get("materials") {
+            Some(values) => values
+                .as_array()
+                .ok_or("'materials' must be an array")?
+                .iter()
+                .map(|value| self.parse_material(value, base_dir))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(Object {
+            shape,
+            material,
+            materials,
+        })
+    }
+
+    /// Load a Wavefront OBJ file as one object. The faces are shaded with the
+    /// materials of the MTL files it names, or the default material if they
+    /// have none. Returns the object and warnings about the materials that
+    /// couldn't be found.
+    pub fn load_obj<P: AsRef<Path>>(&self, path: P) -> Result<(Object, Vec<String>), String> {
+        let path = path.as_ref();
+        let mesh = Mesh::from_obj(path)?;
+        let (materials, warnings) =
+            self.obj_materials(path, mesh.material_libraries(), mesh.material_names())?;
+        let object = Object {
+            shape: self.mesh_shape(mesh)?,
+            material: Material::default(),
+            materials,
+        };
+        Ok((object, warnings))
+    }
+
+    /// Shape of a loaded model, traced by Embree if asked to
+    fn mesh_shape(&self, mesh: Mesh) -> Result<Box<dyn Shape + Send + Sync>, String> {
+        if self.embree {
+            #[cfg(feature = "embree")]
+            return Ok(Box::new(EmbreeMesh::new(mesh)?));
+            #[cfg(not(feature = "embree"))]
+            return Err("Embree needs a build with the embree feature".to_string());
+        }
+        Ok(Box::new(mesh))
+    }
+
+    /// Like `load_obj`, but the geometry stays on disk until a ray reaches
+    /// the bounds of the model, see `LazyMesh`
+    pub fn load_lazy_obj<P: AsRef<Path>>(&self, path: P) -> Result<(Object, Vec<String>), String> {
+        let path = path.as_ref();
+        let mesh = LazyMesh::open(path, self.meshes.clone())?;
+        let (materials, warnings) =
+            self.obj_materials(path, mesh.material_libraries(), mesh.material_names())?;
+        let object = Object {
+            shape: Box::new(mesh),
+            material: Material::default(),
+            materials,
+        };
+        Ok((object, warnings))
+    }
+
+    /// Materials named by an OBJ file, by index, from the MTL files it
+    /// refers to, and warnings about the ones that couldn't be found
+    fn obj_materials(
+        &self,
+        path: &Path,
+        libraries: &[String],
+        names: &[String],
+    ) -> Result<(Vec<Material>, Vec<String>), String> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut library = MtlLibrary::default();
+        for file in libraries {
+            let loaded = confine(dir.join(file), self.asset_root.as_deref())
+                .and_then(|file| MtlLibrary::load(file, self.asset_root.as_deref(), &self.textures))
+                .map_err(|e| format!("{}: {e}", path.display()))?;
+            library.materials.extend(loaded.materials);
+            library.warnings.extend(loaded.warnings);
+        }
+
+        let mut warnings = library.warnings;
+        let materials = names
+            .iter()
+            .map(|name| {
+                library.materials.remove(name).unwrap_or_else(|| {
+                    warnings.push(format!("{}: unknown material '{name}'", path.display()));
+                    Material::default()
+                })
+            })
+            .collect();
+        Ok((materials, warnings))
+    }
+
+    fn parse_material(&self, value: &Value, base_dir: &Path) -> Result<Material, String> {
+        let mut material = self.registry.create_material(value)?;
+        // Any material can take its color from an image...
+        if let Some(path) = value.get("texture") {
+            let path = path
+                .as_str()
+                .ok_or("'texture' must be a path to an image")?;
+            let format = match value.get("texture_format") {
+                Some(format) => serde_json::from_value(format.clone())
+                    .map_err(|e| format!("Invalid 'texture_format': {e}"))?,
+                None => TextureFormat::default(),
+            };
+            let id = self
+                .textures
+                .add_with_format(self.asset_path(base_dir, path)?, format)?;
+            material.texture = Some(Texture::new(self.textures.clone(), id));
+        }
+        // and have its edges rounded
+        if let Some(radius) = value.get("bevel") {
+            material.bevel = Some(Bevel::new(parse_f64(radius)?));
+        }
+        // or mix in another color by where it is seen
+        if let Some(blend) = value.get("blend") {
+            material.blend = Some(parse_blend(blend).map_err(|e| format!("blend: {e}"))?);
+        }
+        // or scatter light as a measured BRDF from the MERL database, which
+        // ignores its color and texture
+        if let Some(path) = value.get("measured") {
+            let path = path.as_str().ok_or("'measured' must be a path")?;
+            let path = self.asset_path(base_dir, path)?;
+            let brdf = MerlBrdf::from_file(&path)
+                .map_err(|e| format!("Couldn't load {}: {e}", path.display()))?;
+            material.bsdf = Some(Arc::new(brdf));
+        }
+        Ok(material)
+    }
+}
+
+/// Scene file that receives a baked environment light. It is read before
+/// the cube map is rendered, so that a file that can't be updated fails
+/// early.
+pub struct EnvironmentTarget {
+    path: PathBuf,
+    format: SceneFormat,
+    root: Value,
+}
+
+impl EnvironmentTarget {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let format = SceneFormat::from_path(path);
+        let root = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))
+            .and_then(|source| format.parse(&source))
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        if !root.is_object() {
+            return Err(format!("{}: the scene must be an object", path.display()));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            root,
+        })
+    }
+
+    /// Set the environment light of the scene to a cube map, keeping the
+    /// rest of the file as it is. The path is stored relative to the scene
+    /// file when the cube map is inside its directory, and absolute
+    /// otherwise.
+    pub fn register<P: AsRef<Path>>(mut self, environment_path: P) -> Result<(), String> {
+        let scene_dir = self.path.parent().unwrap_or(Path::new(""));
+        self.root["environment"] = relative_path(environment_path.as_ref(), scene_dir).into();
+        let source = self.format.serialize(&self.root)?;
+        fs::write(&self.path, source)
+            .map_err(|e| format!("Couldn't write {}: {e}", self.path.display()))
+    }
+}
+
+/// Write a scene in the scene file format, so that it can be edited and
+/// loaded again with `FileLoader::load_scene`.
+///
+/// Texture paths are written relative to the scene file when possible and
+/// the environment cube map is saved next to it, as `<scene name>.cube`.
+/// Shapes and materials the format can't describe, like measured BRDFs,
+/// make the whole scene fail instead of being left out.
+pub fn save_scene<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<(), String> {
+    let path = path.as_ref();
+    let scene_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut root = json!({ "background": vec3_to_json(&scene.background_color) });
+    if scene.settings != Settings::default() {
+        root["settings"] = serde_json::to_value(&scene.settings).map_err(|e| e.to_string())?;
+    }
+    if let Some(environment) = &scene.environment {
+        let stem = path
+            .file_stem()
+            .map_or("scene".into(), |s| s.to_string_lossy());
+        let file = format!("{stem}.cube");
+        environment.save(scene_dir.join(&file))?;
+        root["environment"] = file.into();
+    }
+    if let Some(atmosphere) = &scene.atmosphere {
+        root["atmosphere"] = atmosphere_to_json(atmosphere);
+    }
+    if !scene.volumes.is_empty() {
+        root["volumes"] = scene.volumes.iter().map(volume_to_json).collect();
+    }
+
+    if !scene.cameras.is_empty() {
+        root["cameras"] = scene
+            .cameras
+            .iter()
+            .map(|camera| camera_to_json(camera, scene_dir))
+            .collect::<Result<Vec<_>, String>>()?
+            .into();
+    }
+
+    let objects = scene
+        .objects
+        .iter()
+        .enumerate()
+        .map(|(n, object)| {
+            let mut value = object_to_json(object, scene.animation(n), scene_dir)
+                .map_err(|e| format!("Object {n}: {e}"))?;
+            if let Some(link) = scene.light_link(n) {
+                light_link_to_json(link, &mut value);
+            }
+            if let Some(shadow) = scene.contact_shadow(n) {
+                value["contact_shadow"] =
+                    json!({ "distance": shadow.distance, "strength": shadow.strength });
+            }
+            Ok(value)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    root["objects"] = objects.into();
+
+    let source = serde_json::to_string_pretty(&root).map_err(|e| e.to_string())?;
+    fs::write(path, source + "\n").map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+}
+
+fn camera_to_json(config: &CameraConfig, scene_dir: &Path) -> Result<Value, String> {
+    let mut value = json!({
+        "position": vec3_to_json(&config.position),
+        "direction": vec3_to_json(&config.direction),
+        "resolution": [config.resolution.0, config.resolution.1],
+        "rotation": config.rotation.to_degrees(),
+        "fov": match config.fov {
+            FieldOfView::Vertical(fov) => json!(fov.to_degrees()),
+            FieldOfView::Horizontal(fov) => json!({ "horizontal": fov.to_degrees() }),
+        },
+    });
+    if let FocusMode::FocalPlane {
+        focal_distance,
+        aperture,
+    } = config.focus_mode
+    {
+        let mut focus = json!({ "distance": focal_distance, "aperture": aperture });
+        match &config.aperture_shape {
+            ApertureShape::Disc => {}
+            ApertureShape::Polygon { blades, rotation } => {
+                focus["blades"] = json!(blades);
+                focus["blade_rotation"] = json!(rotation.to_degrees());
+            }
+            ApertureShape::Mask(mask) => {
+                let path = mask
+                    .path()
+                    .ok_or("Aperture masks that weren't read from a file can't be saved")?;
+                focus["mask"] = relative_path(path, scene_dir).into();
+            }
+        }
+        if config.anamorphic_squeeze != 1.0 {
+            focus["squeeze"] = json!(config.anamorphic_squeeze);
+        }
+        value["focus"] = focus;
+    }
+    let projection = match config.projection {
+        Projection::Perspective => None,
+        Projection::Equidistant => Some("equidistant"),
+        Projection::Equisolid => Some("equisolid"),
+        Projection::Stereographic => Some("stereographic"),
+    };
+    if let Some(projection) = projection {
+        value["projection"] = json!(projection);
+    }
+    if config.up != glm::DVec3::y() {
+        value["up"] = vec3_to_json(&config.up);
+    }
+    if config.shutter != Shutter::default() {
+        value["shutter"] = json!([config.shutter.open, config.shutter.close]);
+    }
+    Ok(value)
+}
+
+/// Animated objects are saved with the shape they share between frames and
+/// their keyframes
+fn object_to_json(
+    object: &Object,
+    animation: Option<&ObjectAnimation>,
+    scene_dir: &Path,
+) -> Result<Value, String> {
+    let shape = animation.map_or(object.shape.as_ref(), |animation| animation.shape.as_ref());
+    let mut value = json!({
+        "shape": shape
+            .to_json()
+            .ok_or_else(|| format!("Shapes of kind '{}' can't be saved", shape.kind()))?,
+        "material": material_to_json(&object.material, scene_dir)?,
+    });
+    if let Some(animation) = animation {
+        value["keyframes"] = animation.path.to_json();
+    }
+    if !object.materials.is_empty() {
+        value["materials"] = object
+            .materials
+            .iter()
+            .map(|material| material_to_json(material, scene_dir))
+            .collect::<Result<Vec<_>, _>>()?
+            .into();
+    }
+    Ok(value)
+}
+
+fn light_link_to_json(link: &LightLink, value: &mut Value) {
+    if let Some(objects) = &link.illuminates {
+        value["illuminates"] = json!(objects);
+    }
+    if let Some(objects) = &link.shadowed_by {
+        value["shadowed_by"] = json!(objects);
+    }
+}
+
+/// Describe a material with the built-in material types
+fn material_to_json(material: &Material, scene_dir: &Path) -> Result<Value, String> {
+    if let Some(bsdf) = material
+        .bsdf
+        .as_ref()
+        .filter(|bsdf| bsdf.specular().is_none())
+    {
+        return Err(format!(
+            "Materials with a {} BSDF can't be saved",
+            bsdf.kind()
+        ));
+    }
+    if material.procedural.is_some() {
+        return Err("Procedural materials can't be saved".to_string());
+    }
+
+    let mut value = json!({
+        "type": "diffuse",
+        "color": vec3_to_json(&material.color),
+        "emittance": material.emittance,
+    });
+    match material.specular() {
+        None => {}
+        Some(Specular::Mirror) => value["type"] = "mirror".into(),
+        Some(Specular::Dielectric { ior }) => {
+            value["type"] = "dielectric".into();
+            value["ior"] = ior.into();
+            value["absorption"] = vec3_to_json(&material.absorption);
+        }
+        Some(Specular::Metal { roughness }) => {
+            value["type"] = "metal".into();
+            value["roughness"] = roughness.into();
+        }
+    }
+    if let Some(texture) = &material.texture {
+        let (path, format) = texture.source();
+        value["texture"] = relative_path(&path, scene_dir).into();
+        value["texture_format"] = serde_json::to_value(format).map_err(|e| e.to_string())?;
+    }
+    if let Some(bevel) = &material.bevel {
+        value["bevel"] = bevel.radius.into();
+    }
+    if let Some(blend) = &material.blend {
+        value["blend"] = blend_to_json(blend);
+    }
+    Ok(value)
+}
+
+fn blend_to_json(blend: &Blend) -> Value {
+    let mut value = json!({
+        "driver": blend.driver.name(),
+        "from": blend.from,
+        "to": blend.to,
+        "color": vec3_to_json(&blend.color),
+    });
+    if let Driver::Distance { point } = &blend.driver {
+        value["point"] = vec3_to_json(point);
+    }
+    value
+}
+
+/// Only the settings that `parse_atmosphere` reads are written
+fn atmosphere_to_json(atmosphere: &Atmosphere) -> Value {
+    json!({
+        "sun_direction": vec3_to_json(&atmosphere.sun_direction),
+        "intensity": atmosphere.sun_intensity,
+        "scale": atmosphere.meters_per_unit,
+        "altitude": atmosphere.altitude,
+        "haze": atmosphere.mie_scattering / Atmosphere::default().mie_scattering,
+    })
+}
+
+fn volume_to_json(volume: &Volume) -> Value {
+    let mut value = json!({
+        "min": vec3_to_json(&volume.bounds.min),
+        "max": vec3_to_json(&volume.bounds.max),
+        "density": field_to_json(&volume.density),
+        "color": vec3_to_json(&volume.color),
+        "anisotropy": volume.anisotropy,
+    });
+    if let Some(emission) = &volume.emission {
+        value["emission"] = json!({
+            "temperature": field_to_json(&emission.temperature),
+            "intensity": emission.intensity,
+        });
+    }
+    value
+}
+
+fn field_to_json(field: &ScalarField) -> Value {
+    match field {
+        ScalarField::Constant(value) => (*value).into(),
+        ScalarField::Noise(noise) => json!({
+            "type": "noise",
+            "amplitude": noise.amplitude,
+            "frequency": noise.frequency,
+            "octaves": noise.octaves,
+            "seed": noise.seed,
+        }),
+        ScalarField::Grid(grid) => json!({
+            "type": "grid",
+            "resolution": grid.resolution(),
+            "values": grid.values(),
+        }),
+    }
+}
+
+/// A path, if it is inside `root` when there is one. Links and `..` are
+/// followed to tell, so the file must exist.
+pub(crate) fn confine(path: PathBuf, root: Option<&Path>) -> Result<PathBuf, String> {
+    let Some(root) = root else {
+        return Ok(path);
+    };
+    let canonical = |path: &Path| {
+        fs::canonicalize(path).map_err(|e| format!("Couldn't find {}: {e}", path.display()))
+    };
+    if canonical(&path)?.starts_with(canonical(root)?) {
+        Ok(path)
+    } else {
+        Err(format!(
+            "{} is outside of {}",
+            path.display(),
+            root.display()
+        ))
+    }
+}
+
+/// Path of a file relative to a directory, or the whole path if it isn't
+/// inside it
+fn relative_path(path: &Path, dir: &Path) -> String {
+    let canonical = |path: &Path| {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    };
+    let (path, dir) = (canonical(path), canonical(dir));
+    path.strip_prefix(&dir)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Parse a material with a `color` and an `emittance`, white and 0 if they
+/// aren't given
+fn parse_diffuse(value: &Value) -> Result<Material, String> {
+    Ok(Material {
+        color: value
+            .get("color")
+            .map_or(Ok(Color::repeat(255.0)), parse_color)?,
+        emittance: value.get("emittance").map_or(Ok(0.0), parse_f64)?,
+        ..Default::default()
+    })
+}
+
+/// Parse an indexed triangle mesh: `positions` and `triangles` of indices
+/// into them. Faces can also index vertex `normals` with `normal_triangles`
+/// and `uvs` with `uv_triangles`, and pick one of the materials of the
+/// object with `face_materials`. Faces without them hold nulls.
+fn parse_mesh(value: &Value) -> Result<Mesh, String> {
+    let positions = parse_array(field(value, "positions")?, parse_vec3)?;
+    let triangles: Vec<[u32; 3]> = parse_typed(value, "triangles")?;
+    let mut faces: Vec<MeshFace> = triangles.into_iter().map(MeshFace::new).collect();
+
+    let per_face = |name: &str| -> Result<Option<Vec<Option<[u32; 3]>>>, String> {
+        if value.get(name).is_none() {
+            return Ok(None);
+        }
+        let indices: Vec<Option<[u32; 3]>> = parse_typed(value, name)?;
+        if indices.len() != faces.len() {
+            return Err(format!("'{name}' must have one entry per triangle"));
+        }
+        Ok(Some(indices))
+    };
+    let normal_triangles = per_face("normal_triangles")?;
+    let uv_triangles = per_face("uv_triangles")?;
+    for (n, face) in faces.iter_mut().enumerate() {
+        face.normals = normal_triangles.as_ref().and_then(|indices| indices[n]);
+        face.uvs = uv_triangles.as_ref().and_then(|indices| indices[n]);
+    }
+    if value.get("face_materials").is_some() {
+        let materials: Vec<Option<usize>> = parse_typed(value, "face_materials")?;
+        if materials.len() != faces.len() {
+            return Err("'face_materials' must have one entry per triangle".to_string());
+        }
+        for (face, material) in faces.iter_mut().zip(materials) {
+            face.material = material.unwrap_or(NO_MATERIAL);
+        }
+    }
+
+    let normals = match value.get("normals") {
+        Some(normals) => parse_array(normals, parse_vec3)?,
+        None => Vec::new(),
+    };
+    let uvs = match value.get("uvs") {
+        Some(uvs) => parse_array(uvs, |uv| match uv.as_array().map(Vec::as_slice) {
+            Some([u, v]) => Ok(glm::DVec2::new(parse_f64(u)?, parse_f64(v)?)),
+            _ => Err(format!("Expected an array of 2 numbers, found {uv}")),
+        })?,
+        None => Vec::new(),
+    };
+    Mesh::with_attributes(positions, normals, uvs, faces)
+}
+
+/// Parse a volume: a box given by its `min` and `max` corners, filled with a
+/// medium of constant `density` or a density field. Volumes with an
+/// `emission` glow with the blackbody color of their temperature.
+/// Parse a camera: `{"position": [x, y, z], "direction": [x, y, z],
+/// "resolution": [w, h], "fov": 40, "rotation": 0, "focus": {"distance": d,
+/// "aperture": r}}`. The field of view is vertical, or `{"horizontal": fov}`,
+/// and angles are in degrees. Only the position and direction are required;
+/// cameras without `focus` are pinholes. The aperture of a lens is a disc,
+/// a polygon of `"blades": n` turned by `"blade_rotation"`, or the cut-out
+/// of an image `"mask"`, and is `"squeeze"` times taller than wide.
+pub fn parse_camera(value: &Value) -> Result<CameraConfig, String> {
+    parse_camera_in(value, Path::new(""), None)
+}
+
+/// Like `parse_camera`, but the aperture mask must be inside `root`, which
+/// relative paths start from
+pub fn parse_camera_within(value: &Value, root: &Path) -> Result<CameraConfig, String> {
+    parse_camera_in(value, root, Some(root))
+}
+
+/// Parse a camera whose aperture mask is relative to `base_dir`, and inside
+/// `root` if given
+fn parse_camera_in(
+    value: &Value,
+    base_dir: &Path,
+    root: Option<&Path>,
+) -> Result<CameraConfig, String> {
+    let position = parse_vec3(field(value, "position")?)?;
+    let up = match value.get("up") {
+        Some(up) => parse_vec3(up)?,
+        None => glm::DVec3::y(),
+    };
+    let mut config = match (value.get("direction"), value.get("target")) {
+        (Some(direction), None) => {
+            let direction = parse_vec3(direction)?;
+            if direction.norm() == 0.0 {
+                return Err("'direction' can't be zero".to_string());
+            }
+            CameraConfig {
+                position,
+                direction,
+                up,
+                ..Default::default()
+            }
+        }
+        (None, Some(target)) => CameraConfig::look_at(position, parse_vec3(target)?, up)?,
+        _ => return Err("A camera needs either a 'direction' or a 'target'".to_string()),
+    };
+    config.fov = FieldOfView::Vertical(DEFAULT_FOV.to_radians());
+    if let Some(resolution) = value.get("resolution") {
+        let resolution: Vec<u32> =
+            serde_json::from_value(resolution.clone()).map_err(|e| format!("resolution: {e}"))?;
+        config.resolution = match resolution[..] {
+            [0, _] | [_, 0] => return Err("The resolution can't be zero".to_string()),
+            [width, height] => (width, height),
+            _ => return Err("'resolution' must be [width, height]".to_string()),
+        };
+    }
+    if let Some(rotation) = value.get("rotation") {
+        config.rotation = parse_f64(rotation)?.to_radians();
+    }
+    if let Some(projection) = value.get("projection") {
+        config.projection = match projection.as_str() {
+            Some("perspective") => Projection::Perspective,
+            Some("equidistant") => Projection::Equidistant,
+            Some("equisolid") => Projection::Equisolid,
+            Some("stereographic") => Projection::Stereographic,
+            _ => return Err(format!("Unknown projection {projection}")),
+        };
+    }
+    if let Some(fov) = value.get("fov") {
+        let (degrees, fov): (f64, fn(f64) -> FieldOfView) = match fov.get("horizontal") {
+            Some(horizontal) => (parse_f64(horizontal)?, FieldOfView::Horizontal),
+            None => (parse_f64(fov)?, FieldOfView::Vertical),
+        };
+        // Fisheyes see behind them
+        if config.projection.is_fisheye() {
+            if !(degrees > 0.0 && degrees < 360.0) {
+                return Err("A fisheye 'fov' must be between 0 and 360 degrees".to_string());
+            }
+        } else if !(degrees > 0.0 && degrees < 180.0) {
+            return Err("'fov' must be between 0 and 180 degrees".to_string());
+        }
+        config.fov = fov(degrees.to_radians());
+    }
+    if let Some(focus) = value.get("focus") {
+        let focal_distance = parse_f64(field(focus, "distance")?)?;
+        let aperture = parse_f64(field(focus, "aperture")?)?;
+        if focal_distance <= 0.0 || aperture < 0.0 {
+            return Err("The focus distance must be positive and the aperture not negative".into());
+        }
+        config.focus_mode = FocusMode::FocalPlane {
+            focal_distance,
+            aperture,
+        };
+        config.aperture_shape = match (focus.get("blades"), focus.get("mask")) {
+            (None, None) => ApertureShape::Disc,
+            (Some(blades), None) => ApertureShape::Polygon {
+                blades: match blades.as_u64() {
+                    Some(blades @ 3..) => blades as u32,
+                    _ => return Err("'blades' must be an integer of at least 3".to_string()),
+                },
+                rotation: focus
+                    .get("blade_rotation")
+                    .map_or(Ok(0.0), parse_f64)?
+                    .to_radians(),
+            },
+            (None, Some(mask)) => {
+                let path = mask.as_str().ok_or("'mask' must be a path to an image")?;
+                let path = confine(base_dir.join(path), root)?;
+                ApertureShape::Mask(Arc::new(ApertureMask::load(path)?))
+            }
+            _ => return Err("An aperture has either 'blades' or a 'mask'".to_string()),
+        };
+        if let Some(squeeze) = focus.get("squeeze") {
+            config.anamorphic_squeeze = parse_f64(squeeze)?;
+            if config.anamorphic_squeeze <= 0.0 {
+                return Err("'squeeze' must be positive".to_string());
+            }
+        }
+    }
+    if config.projection.is_fisheye() && config.focus_mode != FocusMode::PinHole {
+        return Err("Fisheye cameras can't have a focal plane".to_string());
+    }
+    if let Some(shutter) = value.get("shutter") {
+        config.shutter = match shutter.as_array().map(Vec::as_slice) {
+            Some([open, close]) => Shutter::new(parse_f64(open)?, parse_f64(close)?)?,
+            _ => return Err("'shutter' must be [open, close]".to_string()),
+        };
+    }
+    Ok(config)
+}
+
+/// Objects lit and shadowed by the light of object `light`, given by the
+/// `illuminates` and `shadowed_by` lists of object indices
+fn parse_light_link(value: &Value, light: usize) -> Result<LightLink, String> {
+    let indices = |name: &str| -> Result<Option<Vec<usize>>, String> {
+        value
+            .get(name)
+            .map(|_| parse_typed::<Vec<usize>>(value, name))
+            .transpose()
+    };
+    Ok(LightLink {
+        light,
+        illuminates: indices("illuminates")?,
+        shadowed_by: indices("shadowed_by")?,
+    })
+}
+
+/// Parse the contact shadow of object `object`, either `true` for the
+/// defaults or the occlusion `distance` and `strength`
+fn parse_contact_shadow(value: &Value, object: usize) -> Result<Option<ContactShadow>, String> {
+    let defaults = ContactShadow::new(object);
+    if let Value::Bool(grounded) = value {
+        return Ok(grounded.then_some(defaults));
+    }
+    let shadow = ContactShadow {
+        distance: value
+            .get("distance")
+            .map_or(Ok(defaults.distance), parse_f64)?,
+        strength: value
+            .get("strength")
+            .map_or(Ok(defaults.strength), parse_f64)?,
+        ..defaults
+    };
+    if shadow.distance <= 0.0 {
+        return Err("'distance' must be positive".to_string());
+    }
+    if !(0.0..=1.0).contains(&shadow.strength) {
+        return Err("'strength' must be in [0, 1]".to_string());
+    }
+    Ok(Some(shadow))
+}
+
+/// Parse a blend. `driver` is one of `facing_ratio`, `height` or `distance`,
+/// which also takes the `point` distances are measured from.
+fn parse_blend(value: &Value) -> Result<Blend, String> {
+    let driver = match field(value, "driver")?.as_str() {
+        Some("facing_ratio") => Driver::FacingRatio,
+        Some("height") => Driver::Height,
+        Some("distance") => Driver::Distance {
+            point: parse_vec3(field(value, "point")?)?,
+        },
+        _ => return Err(format!("Unknown driver {}", value["driver"])),
+    };
+    Ok(Blend {
+        driver,
+        from: parse_f64(field(value, "from")?)?,
+        to: parse_f64(field(value, "to")?)?,
+        color: parse_color(field(value, "color")?)?,
+    })
+}
+
+/// Parse the variation of the materials of an object. The hue is in degrees.
+fn parse_jitter(value: &Value) -> Result<MaterialJitter, String> {
+    let jitter = MaterialJitter {
+        seed: value.get("seed").map_or(Ok(0.0), parse_f64)? as u64,
+        hue: value.get("hue").map_or(Ok(0.0), parse_f64)?,
+        brightness: value.get("brightness").map_or(Ok(0.0), parse_f64)?,
+        roughness: value.get("roughness").map_or(Ok(0.0), parse_f64)?,
+    };
+    if !(0.0..=180.0).contains(&jitter.hue) {
+        return Err("'hue' must be in [0, 180]".to_string());
+    }
+    if !(0.0..=1.0).contains(&jitter.brightness) {
+        return Err("'brightness' must be in [0, 1]".to_string());
+    }
+    if !(0.0..=1.0).contains(&jitter.roughness) {
+        return Err("'roughness' must be in [0, 1]".to_string());
+    }
+    Ok(jitter)
+}
+
+fn parse_volume(value: &Value) -> Result<Volume, String> {
+    let bounds = Bounds {
+        min: parse_vec3(field(value, "min")?)?,
+        max: parse_vec3(field(value, "max")?)?,
+    };
+    if bounds.is_empty() || bounds.size().min() <= 0.0 {
+        return Err("'min' must be below 'max'".to_string());
+    }
+
+    let density = parse_field(field(value, "density")?).map_err(|e| format!("density: {e}"))?;
+    let mut volume = Volume::new(bounds, density);
+    if let Some(color) = value.get("color") {
+        volume.color = parse_color(color)?;
+    }
+    if let Some(anisotropy) = value.get("anisotropy") {
+        volume.anisotropy = parse_f64(anisotropy)?.clamp(-0.99, 0.99);
+    }
+    if let Some(emission) = value.get("emission") {
+        let temperature = parse_field(field(emission, "temperature")?)
+            .map_err(|e| format!("temperature: {e}"))?;
+        let intensity = emission.get("intensity").map_or(Ok(1.0), parse_f64)?;
+        volume.emission = Some(BlackbodyEmission::new(temperature, intensity));
+    }
+    Ok(volume)
+}
+
+/// Parse an atmosphere. The sun is given by a `sun_direction` vector or by
+/// its `sun_elevation` and `sun_azimuth` in degrees. `scale` is the size of a
+/// scene unit in meters and `haze` scales the amount of aerosols.
+fn parse_atmosphere(value: &Value) -> Result<Atmosphere, String> {
+    let mut atmosphere = Atmosphere::default();
+    if let Some(direction) = value.get("sun_direction") {
+        let direction = parse_vec3(direction)?;
+        if direction.norm() == 0.0 {
+            return Err("'sun_direction' can't be zero".to_string());
+        }
+        atmosphere.sun_direction = direction.normalize();
+    } else if let Some(elevation) = value.get("sun_elevation") {
+        let sun = SunPosition {
+            azimuth: value
+                .get("sun_azimuth")
+                .map_or(Ok(180.0), parse_f64)?
+                .to_radians(),
+            elevation: parse_f64(elevation)?.to_radians(),
+        };
+        atmosphere.sun_direction = sun.direction();
+    }
+    if let Some(intensity) = value.get("intensity") {
+        atmosphere.sun_intensity = parse_f64(intensity)?;
+    }
+    if let Some(scale) = value.get("scale") {
+        atmosphere.meters_per_unit = parse_f64(scale)?;
+        if atmosphere.meters_per_unit <= 0.0 {
+            return Err("'scale' must be positive".to_string());
+        }
+    }
+    if let Some(altitude) = value.get("altitude") {
+        atmosphere.altitude = parse_f64(altitude)?.max(0.0);
+    }
+    if let Some(haze) = value.get("haze") {
+        atmosphere.mie_scattering *= parse_f64(haze)?.max(0.0);
+    }
+    Ok(atmosphere)
+}
+
+/// Parse a scalar field: a number for a constant field, or a noise or voxel
+/// grid description
+fn parse_field(value: &Value) -> Result<ScalarField, String> {
+    let field_value = match value.as_f64() {
+        Some(value) => ScalarField::Constant(value),
+        None => match type_name(value)? {
+            "noise" => ScalarField::Noise(parse_noise(value)?),
+            "grid" => {
+                let resolution = parse_typed(value, "resolution")?;
+                let values = parse_typed(value, "values")?;
+                ScalarField::Grid(VoxelGrid::new(resolution, values)?)
+            }
+            name => return Err(format!("Unknown field type '{name}'")),
+        },
+    };
+    let max = field_value.max_value();
+    if max.is_nan() || max < 0.0 {
+        return Err("Values must be positive".to_string());
+    }
+    Ok(field_value)
+}
+
+fn parse_noise(value: &Value) -> Result<NoiseField, String> {
+    Ok(NoiseField {
+        amplitude: parse_f64(field(value, "amplitude")?)?,
+        frequency: value.get("frequency").map_or(Ok(4.0), parse_f64)?,
+        octaves: value.get("octaves").map_or(Ok(4.0), parse_f64)? as u32,
+        seed: value.get("seed").map_or(Ok(0.0), parse_f64)? as u32,
+    })
+}
+
+/// Parse a plane displaced by noise. The noise frequency is in cells per
+/// scene unit.
+fn parse_displaced_plane(value: &Value) -> Result<DisplacedPlane, String> {
+    let normal = parse_vec3(field(value, "normal")?)?;
+    if normal.norm() == 0.0 {
+        return Err("'normal' can't be zero".to_string());
+    }
+    let displacement = parse_noise(field(value, "displacement")?)?;
+    if displacement.amplitude.is_nan() || displacement.amplitude < 0.0 {
+        return Err("The displacement amplitude must be positive".to_string());
+    }
+    let mut plane =
+        DisplacedPlane::new(parse_vec3(field(value, "position")?)?, normal, displacement);
+    if let Some(size) = value.get("checker") {
+        let size = parse_f64(size)?;
+        if size <= 0.0 {
+            return Err("'checker' must be positive".to_string());
+        }
+        plane.checker = Some(size);
+    }
+    if let Some(steps) = value.get("steps") {
+        plane.steps = parse_f64(steps)?.max(1.0) as u32;
+    }
+    Ok(plane)
+}
+
+/// Deserialize a mandatory field of a JSON object
+fn parse_typed<T: DeserializeOwned>(value: &Value, name: &str) -> Result<T, String> {
+    serde_json::from_value(field(value, name)?.clone())
+        .map_err(|e| format!("Invalid '{name}': {e}"))
+}
+
+fn parse_array<T, F>(value: &Value, parse: F) -> Result<Vec<T>, String>
+where
+    F: Fn(&Value) -> Result<T, String>,
+{
+    value
+        .as_array()
+        .ok_or_else(|| format!("Expected an array, found {value}"))?
+        .iter()
+        .map(parse)
+        .collect()
+}
+
+fn type_name(value: &Value) -> Result<&str, String> {
+    field(value, "type")?
+        .as_str()
+        .ok_or_else(|| "'type' must be a string".to_string())
+}
+
+/// Get a mandatory field of a JSON object
+pub fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value
+        .get(name)
+        .ok_or_else(|| format!("Missing field '{name}'"))
+}
+
+pub fn parse_f64(value: &Value) -> Result<f64, String> {
+    value
+        .as_f64()
+        .ok_or_else(|| format!("Expected a number, found {value}"))
+}
+
+/// Parse a vector from a `[x, y, z]` array
+pub fn parse_vec3(value: &Value) -> Result<glm::DVec3, String> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([x, y, z]) => Ok(glm::DVec3::new(parse_f64(x)?, parse_f64(y)?, parse_f64(z)?)),
+        _ => Err(format!("Expected an array of 3 numbers, found {value}")),
+    }
+}
+
+pub fn parse_color(value: &Value) -> Result<Color, String> {
+    parse_vec3(value)
+}
+
+fn vec3_to_json(v: &glm::DVec3) -> Value {
+    json!([v.x, v.y, v.z])
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use crate::ground::DEFAULT_STEPS;
+    use crate::light::Ray;
+    use crate::material::WATER_IOR;
+    use crate::shape::HitRecord;
+
+    use super::*;
+
+    #[test]
+    fn load_spheres() {
+        let scene = FileLoader::new()
+            .parse_scene(
+                r#"{
+                    "background": [10, 20, 30],
+                    "objects": [
+                        { "shape": { "type": "sphere", "center": [0, 0, 5], "radius": 1 } },
+                        { "shape": { "type": "sphere", "center": [0, 0, -5], "radius": 2 } }
+                    ]
+                }"#,
+            )
+            .unwrap();
+
+        assert_eq!(Color::new(10.0, 20.0, 30.0), scene.background_color);
+        assert_eq!(2, scene.objects.len());
+        assert_eq!(Settings::default(), scene.settings);
+
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let hit = scene.objects[0].shape.intersect(&ray).unwrap();
+        assert_eq!(4.0, hit.ray_t);
+    }
+
+    #[test]
+    fn load_toml_and_yaml_scenes() {
+        let json = r#"{
+            "background": [10, 20, 30],
+            "cameras": [{ "position": [0, 1, 0], "direction": [0, 0, 1] }],
+            "objects": [{
+                "shape": { "type": "sphere", "center": [0, 0, 5], "radius": 1 },
+                "material": { "type": "diffuse", "color": [255, 0, 0] }
+            }]
+        }"#;
+        let toml = r#"
+            # Vectors are arrays and objects are tables
+            background = [10, 20, 30]
+
+            [[cameras]]
+            position = [0, 1, 0]
+            direction = [0, 0, 1]
+
+            [[objects]]
+            shape = { type = "sphere", center = [0, 0, 5], radius = 1 }
+            material = { type = "diffuse", color = [255, 0, 0] }
+        "#;
+        let yaml = "
+            # Same scene as YAML
+            background: [10, 20, 30]
+            cameras:
+              - { position: [0, 1, 0], direction: [0, 0, 1] }
+            objects:
+              - shape: { type: sphere, center: [0, 0, 5], radius: 1 }
+                material:
+                  type: diffuse
+                  color: [255, 0, 0]
+        ";
+
+        let loader = FileLoader::new();
+        let expected = loader.parse_scene(json).unwrap();
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        for (source, format) in [(toml, SceneFormat::Toml), (yaml, SceneFormat::Yaml)] {
+            let scene = loader.parse_scene_as(source, format).unwrap();
+            assert_eq!(expected.background_color, scene.background_color);
+            assert_eq!(expected.cameras[0].position, scene.cameras[0].position);
+            assert_eq!(
+                expected.objects[0].material.color,
+                scene.objects[0].material.color
+            );
+            let hit = scene.objects[0].shape.intersect(&ray).unwrap();
+            assert_eq!(4.0, hit.ray_t);
+        }
+
+        assert_eq!(
+            SceneFormat::Toml,
+            SceneFormat::from_path(Path::new("a.toml"))
+        );
+        assert_eq!(
+            SceneFormat::Yaml,
+            SceneFormat::from_path(Path::new("a.YML"))
+        );
+        assert_eq!(
+            SceneFormat::Json,
+            SceneFormat::from_path(Path::new("a.scene"))
+        );
+        assert!(loader
+            .parse_scene_as("objects = [", SceneFormat::Toml)
+            .is_err());
+    }
+
+    #[test]
+    fn load_cameras_and_levels_of_detail() {
+        let mut scene = FileLoader::new()
+            .parse_scene(
+                r#"{
+                    "cameras": [
+                        { "position": [0, 1, 0], "direction": [0, 0, 2] },
+                        {
+                            "position": [0, 0, 0], "direction": [1, 0, 0], "resolution": [64, 32],
+                            "fov": { "horizontal": 90 }, "rotation": 10,
+                            "focus": {
+                                "distance": 5, "aperture": 0.1,
+                                "blades": 7, "blade_rotation": 90, "squeeze": 2
+                            }
+                        },
+                        {
+                            "position": [0, 0, 0], "target": [0, 3, 0], "up": [0, 0, -1],
+                            "projection": "equidistant", "fov": 200, "shutter": [-0.01, 0.01]
+                        }
+                    ],
+                    "objects": [{
+                        "shape": {
+                            "type": "lod",
+                            "coarsest": { "type": "sphere", "center": [0, 0, 5], "radius": 1 },
+                            "levels": [{
+                                "max_distance": 10,
+                                "shape": { "type": "sphere", "center": [0, 0, 5], "radius": 1.01 }
+                            }]
+                        }
+                    }]
+                }"#,
+            )
+            .unwrap();
+
+        let [pinhole, lens, dome] = &scene.cameras[..] else {
+            panic!("Expected 3 cameras, found {}", scene.cameras.len());
+        };
+        assert_eq!(glm::DVec3::y(), pinhole.position);
+        assert_eq!((800, 600), pinhole.resolution);
+        assert_eq!(FocusMode::PinHole, pinhole.focus_mode);
+        assert_eq!(FieldOfView::Vertical(DEFAULT_FOV.to_radians()), pinhole.fov);
+        assert_eq!((64, 32), lens.resolution);
+        assert_eq!(FieldOfView::Horizontal(90.0_f64.to_radians()), lens.fov);
+        assert_relative_eq!(10.0_f64.to_radians(), lens.rotation);
+        assert_eq!(
+            FocusMode::FocalPlane {
+                focal_distance: 5.0,
+                aperture: 0.1
+            },
+            lens.focus_mode
+        );
+        assert_eq!(
+            ApertureShape::Polygon {
+                blades: 7,
+                rotation: 90.0_f64.to_radians()
+            },
+            lens.aperture_shape
+        );
+        assert_eq!(2.0, lens.anamorphic_squeeze);
+        assert_eq!(ApertureShape::Disc, pinhole.aperture_shape);
+        assert_eq!(glm::DVec3::y(), lens.up);
+        assert_eq!(glm::DVec3::new(0.0, 3.0, 0.0), dome.direction);
+        assert_eq!(-glm::DVec3::z(), dome.up);
+        assert_eq!(Projection::Equidistant, dome.projection);
+        assert_eq!(Shutter::new(-0.01, 0.01).unwrap(), dome.shutter);
+        assert_eq!(Shutter::default(), lens.shutter);
+        assert_eq!(FieldOfView::Vertical(200.0_f64.to_radians()), dome.fov);
+
+        // The finer level is picked close to the camera
+        scene.set_viewpoint(&glm::DVec3::zeros());
+        let shape = &scene.objects[0].shape;
+        assert_eq!("level of detail", shape.kind());
+        let hit = shape
+            .intersect(&Ray::new(glm::DVec3::zeros(), glm::DVec3::z()))
+            .unwrap();
+        assert_relative_eq!(5.0 - 1.01, hit.ray_t, epsilon = 1e-9);
+
+        for camera in [
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 0] }"#,
+            r#"{ "position": [0, 0, 0] }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "shutter": [1, 0] }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "shutter": 0.5 }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "target": [0, 0, 1] }"#,
+            r#"{ "position": [1, 2, 3], "target": [1, 2, 3] }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "fov": 180 }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "resolution": [0, 10] }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "focus": { "distance": 1 } }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "focus": { "distance": 1, "aperture": 0.1, "blades": 2 } }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "focus": { "distance": 1, "aperture": 0.1, "blades": 5, "mask": "a.png" } }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "focus": { "distance": 1, "aperture": 0.1, "mask": "missing.png" } }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "focus": { "distance": 1, "aperture": 0.1, "squeeze": 0 } }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "projection": "fisheye" }"#,
+            r#"{ "position": [0, 0, 0], "direction": [0, 0, 1], "projection": "equisolid", "fov": 360 }"#,
+        ] {
+            let source = format!(r#"{{ "cameras": [{camera}] }}"#);
+            let error = FileLoader::new().parse_scene(&source).err().unwrap();
+            assert!(error.starts_with("Invalid camera"), "{error}");
+        }
+    }
+
+    #[test]
+    fn animate_keyframed_objects() {
+        let mut scene = FileLoader::new()
+            .parse_scene(
+                r#"{
+                    "objects": [
+                        { "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 } },
+                        {
+                            "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 },
+                            "keyframes": [
+                                { "time": 0, "position": [0, 0, 10] },
+                                { "time": 2, "position": [0, 0, 20], "scale": 2 }
+                            ]
+                        }
+                    ]
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(1, scene.animations.len());
+        let ray = Ray::new(glm::DVec3::new(0.0, 0.0, 30.0), -glm::DVec3::z());
+        let hit = |scene: &Scene| scene.objects[1].shape.intersect(&ray).unwrap().ray_t;
+        assert_relative_eq!(19.0, hit(&scene), epsilon = 1e-9);
+
+        assert_eq!(1, scene.set_time(1.0));
+        assert_relative_eq!(13.5, hit(&scene), epsilon = 1e-9);
+        assert_eq!(0, scene.set_time(1.0));
+        assert_eq!(1, scene.set_time(2.0));
+        assert_eq!(0, scene.set_time(3.0));
+        assert_relative_eq!(8.0, hit(&scene), epsilon = 1e-9);
+
+        let dir = std::env::temp_dir().join(format!("light-loader-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("animated.json");
+        scene.save(&path).unwrap();
+        let mut loaded = FileLoader::new().load_scene(&path).unwrap();
+        assert_eq!(scene.animations[0].path, loaded.animations[0].path);
+        loaded.set_time(2.0);
+        assert_relative_eq!(8.0, hit(&loaded), epsilon = 1e-9);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let error = FileLoader::new()
+            .parse_scene(r#"{ "objects": [{ "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 }, "keyframes": [] }] }"#)
+            .err()
+            .unwrap();
+        assert_eq!("'keyframes' can't be empty", error);
+    }
+
+    #[test]
+    fn load_light_links() {
+        let source = r#"{
+            "objects": [
+                { "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 } },
+                {
+                    "shape": { "type": "sphere", "center": [0, 5, 0], "radius": 1 },
+                    "material": { "type": "diffuse", "color": [255, 255, 255], "emittance": 5 },
+                    "illuminates": [0],
+                    "shadowed_by": []
+                }
+            ]
+        }"#;
+        let scene = FileLoader::new().parse_scene(source).unwrap();
+        let link = LightLink {
+            light: 1,
+            illuminates: Some(vec![0]),
+            shadowed_by: Some(Vec::new()),
+        };
+        assert_eq!(vec![link], scene.light_links);
+        assert!(scene.illuminates(1, 0));
+        assert!(!scene.illuminates(1, 2));
+        assert!(!scene.is_shadowed_by(1, 0));
+        assert!(scene.is_shadowed_by(0, 1));
+
+        let dir = std::env::temp_dir().join(format!("light-loader-links-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("linked.json");
+        scene.save(&path).unwrap();
+        let loaded = FileLoader::new().load_scene(&path).unwrap();
+        assert_eq!(scene.light_links, loaded.light_links);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dangling = source.replace(r#""illuminates": [0]"#, r#""illuminates": [2]"#);
+        let error = FileLoader::new().parse_scene(&dangling).err().unwrap();
+        assert!(error.starts_with("Invalid light link"), "{error}");
+    }
+
+    #[test]
+    fn load_material_blend() {
+        let source = r#"{
+            "objects": [{
+                "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 },
+                "material": {
+                    "type": "diffuse",
+                    "color": [100, 80, 60],
+                    "blend": { "driver": "height", "from": 0.5, "to": 0.8, "color": [255, 255, 255] }
+                }
+            }]
+        }"#;
+        let scene = FileLoader::new().parse_scene(source).unwrap();
+        let snow = Blend {
+            driver: Driver::Height,
+            from: 0.5,
+            to: 0.8,
+            color: Color::repeat(255.0),
+        };
+        assert_eq!(Some(snow), scene.objects[0].material.blend);
+
+        let unknown = source.replace(r#""height""#, r#""slope""#);
+        let error = FileLoader::new().parse_scene(&unknown).err().unwrap();
+        assert_eq!("blend: Unknown driver \"slope\"", error);
+    }
+
+    #[test]
+    fn load_material_jitter() {
+        let copy = |x: i32| {
+            format!(
+                r#"{{
+                    "shape": {{ "type": "sphere", "center": [{x}, 0, 0], "radius": 0.4 }},
+                    "material": {{ "type": "metal", "color": [180, 120, 60], "roughness": 0.3 }},
+                    "jitter": {{ "seed": 9, "hue": 15, "brightness": 0.2, "roughness": 0.1 }}
+                }}"#
+            )
+        };
+        let source = format!(r#"{{ "objects": [{}, {}] }}"#, copy(0), copy(1));
+        let scene = FileLoader::new().parse_scene(&source).unwrap();
+        let (first, second) = (&scene.objects[0].material, &scene.objects[1].material);
+        assert_ne!(first.color, second.color);
+        assert_ne!(first.specular(), second.specular());
+
+        // Loading again gives the same variation
+        let again = FileLoader::new().parse_scene(&source).unwrap();
+        assert_eq!(first.color, again.objects[0].material.color);
+
+        let wide = source.replace(r#""hue": 15"#, r#""hue": 270"#);
+        let error = FileLoader::new().parse_scene(&wide).err().unwrap();
+        assert_eq!("Invalid jitter: 'hue' must be in [0, 180]", error);
+    }
+
+    #[test]
+    fn load_cutout() {
+        let source = r#"{
+            "objects": [{
+                "shape": { "type": "plane", "position": [0, 0, 0], "normal": [0, 1, 0] },
+                "cutout": "0.5 - fract(x)"
+            }]
+        }"#;
+        let scene = FileLoader::new().parse_scene(source).unwrap();
+        let down = |x| Ray::new(glm::DVec3::new(x, 1.0, 0.0), -glm::DVec3::y());
+        assert!(scene.objects[0].shape.intersect(&down(1.25)).is_none());
+        assert!(scene.objects[0].shape.intersect(&down(1.75)).is_some());
+
+        let invalid = source.replace("fract(x)", "fract(x");
+        let error = FileLoader::new().parse_scene(&invalid).err().unwrap();
+        assert!(error.starts_with("Invalid cutout: "));
+    }
+
+    #[test]
+    fn load_contact_shadows() {
+        let source = r#"{
+            "objects": [
+                {
+                    "shape": { "type": "sphere", "center": [0, 1, 0], "radius": 1 },
+                    "contact_shadow": { "distance": 2, "strength": 0.5 }
+                },
+                {
+                    "shape": { "type": "sphere", "center": [3, 1, 0], "radius": 1 },
+                    "contact_shadow": true
+                },
+                {
+                    "shape": { "type": "sphere", "center": [6, 1, 0], "radius": 1 },
+                    "contact_shadow": false
+                }
+            ]
+        }"#;
+        let scene = FileLoader::new().parse_scene(source).unwrap();
+        let shadow = ContactShadow {
+            distance: 2.0,
+            strength: 0.5,
+            ..ContactShadow::new(0)
+        };
+        assert_eq!(vec![shadow, ContactShadow::new(1)], scene.contact_shadows);
+        assert!(scene.contact_shadow(2).is_none());
+
+        let dir = std::env::temp_dir().join(format!("light-loader-contact-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("grounded.json");
+        scene.save(&path).unwrap();
+        let loaded = FileLoader::new().load_scene(&path).unwrap();
+        assert_eq!(scene.contact_shadows, loaded.contact_shadows);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let strong = source.replace(r#""strength": 0.5"#, r#""strength": 2"#);
+        let error = FileLoader::new().parse_scene(&strong).err().unwrap();
+        assert_eq!(
+            "Invalid contact shadow: 'strength' must be in [0, 1]",
+            error
+        );
+    }
+
+    #[test]
+    fn register_custom_types() {
+        let mut loader = FileLoader::new();
+        loader
+            .registry_mut()
+            .register_shape("unit_sphere", |_| {
+                Ok(Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)))
+            })
+            .register_material("glowing", |value| {
+                Ok(Material {
+                    color: parse_color(field(value, "tint")?)?,
+                    emittance: 10.0,
+                    ..Default::default()
+                })
+            });
+
+        let scene = loader
+            .parse_scene(
+                r#"{
+                    "objects": [{
+                        "shape": { "type": "unit_sphere" },
+                        "material": { "type": "glowing", "tint": [1, 0.5, 0] },
+                        "materials": [{ "type": "glowing", "tint": [0, 0, 1] }]
+                    }]
+                }"#,
+            )
+            .unwrap();
+
+        assert_eq!(Color::new(1.0, 0.5, 0.0), scene.objects[0].material.color);
+        assert_eq!(10.0, scene.objects[0].material.emittance);
+        assert_eq!(
+            Color::new(0.0, 0.0, 1.0),
+            scene.objects[0].material_at(0).color
+        );
+        assert_eq!(
+            Color::new(1.0, 0.5, 0.0),
+            scene.objects[0].material_at(1).color
+        );
+    }
+
+    #[test]
+    fn load_displaced_plane() {
+        let source = r#"{
+            "objects": [{
+                "shape": {
+                    "type": "displaced_plane",
+                    "position": [0, 0, 0],
+                    "normal": [0, 2, 0],
+                    "displacement": { "amplitude": 0.1, "frequency": 2 },
+                    "checker": 1
+                }
+            }]
+        }"#;
+        let scene = FileLoader::new().parse_scene(source).unwrap();
+        let floor = &scene.objects[0].shape;
+        assert_eq!("displaced_plane", floor.kind());
+        let shape = floor.to_json().unwrap();
+        assert_eq!(json!([0.0, 1.0, 0.0]), shape["normal"]);
+        assert_eq!(4, shape["displacement"]["octaves"]);
+        assert_eq!(DEFAULT_STEPS, shape["steps"]);
+
+        let sunken = source.replace(r#""amplitude": 0.1"#, r#""amplitude": -0.1"#);
+        let error = FileLoader::new().parse_scene(&sunken).err().unwrap();
+        assert!(
+            error.ends_with("The displacement amplitude must be positive"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn report_unknown_types() {
+        let loader = FileLoader::with_registry(Registry::empty());
+        let result = loader.parse_scene(
+            r#"{ "objects": [{ "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 } }] }"#,
+        );
+        assert_eq!(
+            Some("Unknown shape type 'sphere'".to_string()),
+            result.err()
+        );
+
+        let result = FileLoader::new().parse_scene(
+            r#"{ "objects": [{ "shape": { "type": "sphere", "center": [0, 0], "radius": 1 } }] }"#,
+        );
+        assert!(result.err().unwrap().starts_with("Invalid shape 'sphere'"));
+    }
+
+    #[test]
+    fn load_textured_material() {
+        let dir = std::env::temp_dir().join(format!("light-loader-tex-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([10, 20, 30]))
+            .save(dir.join("flat.png"))
+            .unwrap();
+        let scene_path = dir.join("textured.json");
+        fs::write(
+            &scene_path,
+            r#"{
+                "objects": [{
+                    "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 },
+                    "material": { "type": "plain", "texture": "flat.png", "texture_format": "palette", "bevel": 0.02 }
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let mut loader = FileLoader::new();
+        loader
+            .texture_cache(Arc::new(TextureCache::with_directory(
+                1 << 20,
+                dir.join("cache"),
+            )))
+            .registry_mut()
+            .register_material("plain", |_| Ok(Material::default()));
+        let scene = loader.load_scene(&scene_path).unwrap();
+
+        assert_eq!(Some(Bevel::new(0.02)), scene.objects[0].material.bevel);
+        let ctx = crate::material::ShadingContext {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::new(0.3, 0.8),
+            object_id: 0,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        };
+        assert_eq!(
+            Color::new(10.0, 20.0, 30.0),
+            scene.objects[0].material.albedo(&ctx)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_measured_material() {
+        let dir = std::env::temp_dir().join(format!("light-loader-merl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut bytes: Vec<u8> = [90_i32, 90, 180]
+            .iter()
+            .flat_map(|dim| dim.to_le_bytes())
+            .collect();
+        bytes.extend((0..3 * crate::merl::NUM_SAMPLES).flat_map(|_| 1500.0_f64.to_le_bytes()));
+        fs::write(dir.join("gray.binary"), bytes).unwrap();
+        fs::write(
+            dir.join("scene.json"),
+            r#"{ "objects": [{
+                "shape": { "type": "sphere", "center": [0, 0, 0], "radius": 1 },
+                "material": { "type": "diffuse", "measured": "gray.binary" }
+            }] }"#,
+        )
+        .unwrap();
+
+        let scene = FileLoader::new()
+            .load_scene(dir.join("scene.json"))
+            .unwrap();
+        let material = &scene.objects[0].material;
+        assert_eq!("measured", material.surface().kind());
+        let result = scene.save(dir.join("saved.json"));
+        assert!(result.unwrap_err().contains("measured BSDF"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn register_baked_environment() {
+        let dir = std::env::temp_dir().join(format!("light-loader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let scene_path = dir.join("hero.json");
+        let environment_path = dir.join("backdrop.cube");
+
+        fs::write(&scene_path, r#"{ "background": [1, 2, 3], "objects": [] }"#).unwrap();
+        let faces = vec![vec![Color::new(7.0, 8.0, 9.0)]; crate::cubemap::NUM_FACES];
+        CubeMap::from_faces(1, faces)
+            .unwrap()
+            .save(&environment_path)
+            .unwrap();
+
+        EnvironmentTarget::open(&scene_path)
+            .unwrap()
+            .register(&environment_path)
+            .unwrap();
+        let source = fs::read_to_string(&scene_path).unwrap();
+        assert!(source.contains(r#""environment": "backdrop.cube""#));
+
+        let scene = FileLoader::new().load_scene(&scene_path).unwrap();
+        assert_eq!(Color::new(1.0, 2.0, 3.0), scene.background_color);
+        assert_eq!(
+            Color::new(7.0, 8.0, 9.0),
+            scene.background(&glm::DVec3::y())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn register_environment_outside_scene_directory() {
+        let dir = std::env::temp_dir().join(format!("light-environment-{}", std::process::id()));
+        fs::create_dir_all(dir.join("scenes")).unwrap();
+        let scene_path = dir.join("scenes").join("hero.json");
+        let environment_path = dir.join("environment.cube");
+
+        fs::write(&scene_path, r#"{ "background": [1, 2, 3], "objects": [] }"#).unwrap();
+        let faces = vec![vec![Color::new(7.0, 8.0, 9.0)]; crate::cubemap::NUM_FACES];
+        CubeMap::from_faces(1, faces)
+            .unwrap()
+            .save(&environment_path)
+            .unwrap();
+
+        EnvironmentTarget::open(&scene_path)
+            .unwrap()
+            .register(&environment_path)
+            .unwrap();
+        let scene = FileLoader::new().load_scene(&scene_path).unwrap();
+        assert_eq!(Color::new(1.0, 2.0, 3.0), scene.background_color);
+        assert_eq!(
+            Color::new(7.0, 8.0, 9.0),
+            scene.background(&glm::DVec3::y())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn register_environment_in_format_of_scene() {
+        let dir = std::env::temp_dir().join(format!("light-formats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let environment_path = dir.join("backdrop.cube");
+        let faces = vec![vec![Color::new(7.0, 8.0, 9.0)]; crate::cubemap::NUM_FACES];
+        CubeMap::from_faces(1, faces)
+            .unwrap()
+            .save(&environment_path)
+            .unwrap();
+
+        for (file, source) in [
+            ("hero.toml", "background = [1, 2, 3]\nobjects = []\n"),
+            ("hero.yaml", "background: [1, 2, 3]\nobjects: []\n"),
+        ] {
+            let scene_path = dir.join(file);
+            fs::write(&scene_path, source).unwrap();
+            let target = EnvironmentTarget::open(&scene_path).unwrap();
+            target.register(&environment_path).unwrap();
+
+            let scene = FileLoader::new().load_scene(&scene_path).unwrap();
+            assert_eq!(Color::new(1.0, 2.0, 3.0), scene.background_color);
+            assert_eq!(
+                Color::new(7.0, 8.0, 9.0),
+                scene.background(&glm::DVec3::y())
+            );
+        }
+
+        // Files that can't be updated fail before anything is rendered
+        let scene_path = dir.join("broken.toml");
+        fs::write(&scene_path, r#"{ "objects": [] }"#).unwrap();
+        assert!(EnvironmentTarget::open(&scene_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_volumes() {
+        let scene = FileLoader::new()
+            .parse_scene(
+                r#"{
+                    "volumes": [
+                        { "min": [0, 0, 0], "max": [1, 1, 1], "density": 0.5, "color": [200, 200, 200] },
+                        {
+                            "min": [-1, -1, -1], "max": [0, 0, 0], "anisotropy": 0.7,
+                            "density": { "type": "grid", "resolution": [2, 1, 1], "values": [0, 3] }
+                        },
+                        {
+                            "min": [0, 0, 0], "max": [4, 1, 4],
+                            "density": { "type": "noise", "amplitude": 2, "octaves": 3 },
+                            "emission": { "temperature": 1500, "intensity": 2 }
+                        }
+                    ]
+                }"#,
+            )
+            .unwrap();
+
+        assert_eq!(3, scene.volumes.len());
+        assert_eq!(ScalarField::Constant(0.5), scene.volumes[0].density);
+        assert_eq!(Color::new(200.0, 200.0, 200.0), scene.volumes[0].color);
+        assert_eq!(0.7, scene.volumes[1].anisotropy);
+        assert_eq!(3.0, scene.volumes[1].density.max_value());
+        assert_eq!(2.0, scene.volumes[2].density.max_value());
+        assert!(scene.volumes[1].emission.is_none());
+        let emission = scene.volumes[2].emission.as_ref().unwrap();
+        assert_eq!(ScalarField::Constant(1500.0), emission.temperature);
+        assert_eq!(2.0, emission.intensity);
+
+        let loader = FileLoader::new();
+        for invalid in [
+            r#"{ "volumes": [{ "min": [0, 0, 0], "max": [1, 0, 1], "density": 1 }] }"#,
+            r#"{ "volumes": [{ "min": [0, 0, 0], "max": [1, 1, 1], "density": -1 }] }"#,
+            r#"{ "volumes": [{ "min": [0, 0, 0], "max": [1, 1, 1], "density": { "type": "grid", "resolution": [2, 2, 2], "values": [1] } }] }"#,
+        ] {
+            assert!(loader.parse_scene(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn load_atmosphere() {
+        let loader = FileLoader::new();
+        assert!(loader.parse_scene("{}").unwrap().atmosphere.is_none());
+
+        let scene = loader
+            .parse_scene(r#"{ "atmosphere": { "sun_elevation": 90, "scale": 1000, "haze": 2 } }"#)
+            .unwrap();
+        let atmosphere = scene.atmosphere.unwrap();
+        assert!((atmosphere.sun_direction - glm::DVec3::y()).norm() < 1e-12);
+        assert_eq!(1000.0, atmosphere.meters_per_unit);
+        assert_eq!(
+            2.0 * Atmosphere::default().mie_scattering,
+            atmosphere.mie_scattering
+        );
+
+        let scene = loader
+            .parse_scene(r#"{ "atmosphere": { "sun_direction": [0, 0, -2] } }"#)
+            .unwrap();
+        assert_eq!(-glm::DVec3::z(), scene.atmosphere.unwrap().sun_direction);
+        assert!(loader
+            .parse_scene(r#"{ "atmosphere": { "scale": 0 } }"#)
+            .is_err());
+    }
+
+    #[test]
+    fn load_obj_model() {
+        let dir = std::env::temp_dir().join(format!("light-loader-obj-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("model.obj"),
+            "mtllib model.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             f 1 2 3\nusemtl red\nf 1 3 4\nusemtl gold\nf 1 2 4\n",
+        )
+        .unwrap();
+        fs::write(dir.join("model.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+        fs::write(
+            dir.join("scene.json"),
+            r#"{ "objects": [{ "obj": "model.obj" }] }"#,
+        )
+        .unwrap();
+
+        let scene = FileLoader::new()
+            .load_scene(dir.join("scene.json"))
+            .unwrap();
+        assert_eq!(1, scene.objects.len());
+        let model = &scene.objects[0];
+        assert_eq!("mesh", model.shape.kind());
+        assert_eq!(3, model.shape.triangle_count());
+        assert_eq!(Color::new(255.0, 0.0, 0.0), model.material_at(0).color);
+        assert_eq!(Material::default().color, model.material_at(1).color);
+        assert_eq!(1, scene.warnings.len());
+        assert!(scene.warnings[0].contains("'gold'"));
+
+        // Large models, or the ones that ask for it, are loaded lazily
+        let lazy_kind = |loader: &FileLoader, object: &str| {
+            fs::write(
+                dir.join("scene.json"),
+                format!(r#"{{ "objects": [{{ "obj": "model.obj"{object} }}] }}"#),
+            )
+            .unwrap();
+            let scene = loader.load_scene(dir.join("scene.json")).unwrap();
+            let model = &scene.objects[0];
+            assert_eq!(Color::new(255.0, 0.0, 0.0), model.material_at(0).color);
+            model.shape.kind()
+        };
+        let mut loader = FileLoader::new();
+        assert_eq!("lazy mesh", lazy_kind(&loader, r#", "lazy": true"#));
+        loader.lazy_meshes(Some(1 << 20));
+        assert_eq!("mesh", lazy_kind(&loader, ""));
+        loader.lazy_meshes(Some(0));
+        assert_eq!("lazy mesh", lazy_kind(&loader, ""));
+        assert_eq!("mesh", lazy_kind(&loader, r#", "lazy": false"#));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_reload_scene() {
+        let dir = std::env::temp_dir().join(format!("light-loader-save-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved.json");
+
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 2.0, 3.0);
+        scene.settings.samples_per_pixel = Some(16);
+        scene.atmosphere = Some(Atmosphere {
+            altitude: 50.0,
+            ..Default::default()
+        });
+        let density = ScalarField::Grid(VoxelGrid::new([2, 1, 1], vec![0.5, 1.5]).unwrap());
+        let bounds = Bounds {
+            min: glm::DVec3::zeros(),
+            max: glm::DVec3::repeat(1.0),
+        };
+        scene.volumes.push(Volume::new(bounds, density));
+        let mask = dir.join("bokeh.png");
+        image::GrayImage::from_raw(2, 1, vec![255, 0])
+            .unwrap()
+            .save(&mask)
+            .unwrap();
+        scene.cameras.push(CameraConfig {
+            position: glm::DVec3::new(1.0, 2.0, 3.0),
+            direction: -glm::DVec3::z(),
+            resolution: (64, 48),
+            fov: FieldOfView::Horizontal(1.0),
+            focus_mode: FocusMode::FocalPlane {
+                focal_distance: 4.0,
+                aperture: 0.1,
+            },
+            aperture_shape: ApertureShape::Mask(Arc::new(ApertureMask::load(&mask).unwrap())),
+            anamorphic_squeeze: 1.5,
+            up: glm::DVec3::x(),
+            shutter: Shutter::new(0.0, 0.02).unwrap(),
+            ..Default::default()
+        });
+
+        let corners = vec![
+            glm::DVec3::zeros(),
+            glm::DVec3::x(),
+            glm::DVec3::new(1.0, 1.0, 0.0),
+            glm::DVec3::y(),
+        ];
+        let mut faces = vec![MeshFace::new([0, 1, 2]), MeshFace::new([0, 2, 3])];
+        faces[1].material = 0;
+        faces[1].normals = Some([0, 0, 0]);
+        let mesh = Mesh::with_attributes(corners, vec![glm::DVec3::z()], Vec::new(), faces);
+        scene
+            .add_object(Object {
+                shape: Box::new(mesh.unwrap()),
+                material: Material::default(),
+                materials: vec![Material::water(Color::new(0.0, 100.0, 200.0), 2.0)],
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 5.0), 2.0)),
+                material: Material {
+                    bevel: Some(Bevel::new(0.25)),
+                    blend: Some(Blend {
+                        driver: Driver::Distance {
+                            point: glm::DVec3::new(1.0, 2.0, 3.0),
+                        },
+                        from: 4.0,
+                        to: 2.0,
+                        color: Color::new(90.0, 60.0, 30.0),
+                    }),
+                    ..Material::metal(Color::repeat(200.0), 0.4)
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, -1.0, 0.0),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(10.0, 20.0, 30.0),
+                    emittance: 2.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        let mut lod = LevelOfDetail::new(Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)));
+        lod.add_level(10.0, Box::new(Sphere::new(glm::DVec3::zeros(), 1.01)));
+        let mut gravel = DisplacedPlane::new(
+            glm::DVec3::new(0.0, -2.0, 0.0),
+            glm::DVec3::y(),
+            NoiseField {
+                amplitude: 0.05,
+                frequency: 8.0,
+                octaves: 3,
+                seed: 2,
+            },
+        );
+        gravel.checker = Some(0.5);
+        scene
+            .add_object(Object {
+                shape: Box::new(lod),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(gravel),
+                material: Material::default(),
+                materials: Vec::new(),
+            });
+        scene.save(&path).unwrap();
+
+        let loaded = FileLoader::new().load_scene(&path).unwrap();
+        assert_eq!(scene.background_color, loaded.background_color);
+        assert_eq!(scene.settings, loaded.settings);
+        assert_eq!(50.0, loaded.atmosphere.unwrap().altitude);
+        assert_eq!(1, loaded.volumes.len());
+        assert_eq!(
+            scene.volumes[0].density.value(&glm::DVec3::repeat(0.5)),
+            loaded.volumes[0].density.value(&glm::DVec3::repeat(0.5))
+        );
+        let camera = &loaded.cameras[0];
+        assert_eq!(scene.cameras[0].position, camera.position);
+        assert_eq!((64, 48), camera.resolution);
+        assert_eq!(scene.cameras[0].focus_mode, camera.focus_mode);
+        assert_eq!(scene.cameras[0].up, camera.up);
+        assert_eq!(scene.cameras[0].shutter, camera.shutter);
+        assert_eq!(scene.cameras[0].aperture_shape, camera.aperture_shape);
+        assert_eq!(
+            scene.cameras[0].anamorphic_squeeze,
+            camera.anamorphic_squeeze
+        );
+        match camera.fov {
+            FieldOfView::Horizontal(fov) => assert_relative_eq!(1.0, fov, epsilon = 1e-12),
+            fov => panic!("Expected a horizontal field of view, found {fov:?}"),
+        }
+        assert_eq!(5, loaded.objects.len());
+        for (saved, loaded) in scene.objects.iter().zip(&loaded.objects) {
+            assert_eq!(saved.shape.to_json(), loaded.shape.to_json());
+            assert_eq!(saved.materials.len(), loaded.materials.len());
+        }
+
+        let mesh = &loaded.objects[0];
+        let ray = Ray::new(glm::DVec3::new(0.2, 0.7, 1.0), -glm::DVec3::z());
+        let hit = mesh.shape.intersect(&ray).unwrap();
+        assert_eq!(glm::DVec3::z(), hit.normal);
+        let water = mesh.material_at(hit.material);
+        assert_eq!(
+            Some(Specular::Dielectric { ior: WATER_IOR }),
+            water.specular()
+        );
+        assert_eq!(scene.objects[0].materials[0].absorption, water.absorption);
+        let sphere = &loaded.objects[1].material;
+        assert_eq!(Some(Specular::Metal { roughness: 0.4 }), sphere.specular());
+        assert_eq!(0.25, sphere.bevel.unwrap().radius);
+        assert_eq!(scene.objects[1].material.blend, sphere.blend);
+        assert_eq!(
+            Color::new(10.0, 20.0, 30.0),
+            loaded.objects[2].material.color
+        );
+        assert_eq!(2.0, loaded.objects[2].material.emittance);
+
+        // Shapes the format can't describe fail the whole scene
+        struct Unsaveable;
+        impl Shape for Unsaveable {
+            fn intersect(&self, _ray: &Ray) -> Option<HitRecord> {
+                None
+            }
+        }
+        scene.add_object(Object {
+            shape: Box::new(Unsaveable),
+            material: Material::default(),
+            materials: Vec::new(),
+        });
+        let error = scene.save(dir.join("unsaveable.json")).unwrap_err();
+        assert!(error.starts_with("Object 5"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
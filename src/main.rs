@@ -17,25 +17,1032 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-mod algebra;
-mod camera;
-mod color;
-mod light;
-mod material;
-mod object;
-mod render;
-mod scene;
-mod shape;
-
-use camera::{Camera, CameraConfig, FieldOfView, FocusMode};
-use color::Color;
-use material::Material;
-use object::Object;
-use render::PathTracer;
-use scene::Scene;
-use shape::{Plane, Sphere, Triangle};
-
-fn main() {
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+use light::adaptive::AdaptiveSampling;
+use light::animation::CameraPath;
+use light::aov::{Aov, AovSettings};
+use light::bluenoise::BlueNoiseMask;
+use light::camera::{Camera, CameraConfig, FieldOfView, FocusMode, Shutter};
+use light::color::Color;
+use light::config::{Preset, Settings};
+use light::cubemap::CubeMap;
+use light::dataset::DatasetWriter;
+use light::film::{Film, Metering, ToneMapper};
+use light::irradiance::IrradianceCaching;
+use light::loader::FileLoader;
+use light::material::Material;
+use light::object::Object;
+use light::output::{RadianceFormat, Sidecars};
+use light::preprocess::PreprocessReport;
+use light::progressive::{Progressive, MAX_TARGET_ERROR_PASSES};
+use light::render::{GeometryShading, PathTracer};
+use light::sampler::SamplerKind;
+use light::scene::Scene;
+use light::server::{JobLimits, RenderServer};
+use light::shape::{Plane, Sphere, Triangle};
+use light::streaming::MeshResidency;
+use light::temporal::{TemporalAccumulator, TemporalReuse};
+use light::texture::TextureCache;
+use light::tile::TileOrder;
+use light::{inspect, loader, metrics, output, render, sampling, xray};
+
+#[derive(Parser)]
+#[command(version, about = "A path tracer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Config file to read instead of ~/.config/light/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Number of render threads
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// Directory where rendered images are written
+    #[arg(long, global = true)]
+    output_dir: Option<PathBuf>,
+    /// Tone mapper used to write images: clamp, reinhard or aces
+    #[arg(long, global = true)]
+    tone_mapper: Option<ToneMapper>,
+    /// Correct the exposure of images before tone mapping them, metering
+    /// the whole image (average) or mostly its middle (center)
+    #[arg(long, global = true, value_name = "METERING")]
+    auto_exposure: Option<Metering>,
+    /// Seed of the random numbers. Renders with the same seed are identical.
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Side of the square tiles the image is split into, in pixels
+    #[arg(long, global = true)]
+    tile_size: Option<u32>,
+    /// Source of the random numbers of the samples: random, halton or sobol
+    #[arg(long, global = true)]
+    sampler: Option<SamplerKind>,
+    /// Spread the sample offsets of each pixel with a blue-noise mask, so
+    /// that the noise of low sample counts is finer and easier to denoise
+    #[arg(long, global = true)]
+    blue_noise: bool,
+    /// Stop before rendering when the scene and the film would take more
+    /// than this many MiB. A quarter of it, up to 256 MiB, holds the tiles
+    /// of the textures.
+    #[arg(long, global = true, value_name = "MIB")]
+    memory_budget: Option<usize>,
+    /// Write a quick preview with this many samples per pixel next to each
+    /// image, as <NAME>.preview.jpg, before rendering it
+    #[arg(long, global = true)]
+    preview_samples: Option<u32>,
+    /// Write a thumbnail with this longest side next to each image, as
+    /// <NAME>.thumb.jpg
+    #[arg(long, global = true)]
+    thumbnail: Option<u32>,
+}
+
+impl Cli {
+    /// Sidecar images requested by the command line flags
+    fn sidecars(&self) -> Sidecars {
+        Sidecars {
+            preview_samples: self.preview_samples,
+            thumbnail_size: self.thumbnail,
+        }
+    }
+
+    /// Settings given by the command line flags
+    fn settings(&self) -> Settings {
+        Settings {
+            threads: self.threads,
+            output_dir: self.output_dir.clone(),
+            tone_mapper: self.tone_mapper,
+            auto_exposure: self.auto_exposure,
+            seed: self.seed,
+            tile_size: self.tile_size,
+            sampler: self.sampler,
+            blue_noise: self.blue_noise.then_some(true),
+            memory_budget: self.memory_budget,
+            ..Default::default()
+        }
+    }
+
+    /// Settings of the user config file
+    fn user_settings(&self) -> Result<Settings, String> {
+        match &self.config {
+            Some(path) => Settings::load(path),
+            None => Settings::load_user_config(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render the environment seen from a point of a scene into a cube map
+    /// and use it as the environment light of another scene
+    BakeEnv {
+        /// Scene to render the environment from
+        source: PathBuf,
+        /// Scene file that receives the environment light
+        target: PathBuf,
+        /// Cube map file to write
+        #[arg(short, long, default_value = "environment.cube")]
+        output: PathBuf,
+        /// Point of view inside the source scene
+        #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true, default_values_t = [0.0, 0.0, 0.0])]
+        position: Vec<f64>,
+        /// Resolution of each cube face
+        #[arg(long, default_value_t = 256)]
+        size: u32,
+        /// Samples per pixel of each face [default: 64]
+        #[arg(long)]
+        samples_per_pixel: Option<u32>,
+        /// Maximum number of bounces per path [default: 5]
+        #[arg(long)]
+        max_depth: Option<u32>,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Render a scene file with one of its cameras
+    Render {
+        scene: PathBuf,
+        /// Index of the camera in the scene file
+        #[arg(long, default_value_t = 0)]
+        camera: usize,
+        /// Samples per pixel [default: 16]
+        #[arg(long)]
+        samples_per_pixel: Option<u32>,
+        /// Maximum number of bounces per path [default: 5]
+        #[arg(long)]
+        max_depth: Option<u32>,
+        /// Image to write. The radiance is written without tone mapping to
+        /// .exr and .hdr files, and next to other images, e.g. render.exr
+        /// for render.png, to tone map them again with `tonemap`.
+        #[arg(short, long, default_value = "render.png")]
+        output: PathBuf,
+        /// Render progressively in this many passes of the samples per pixel
+        #[arg(long)]
+        passes: Option<u32>,
+        /// Render progressively until the estimated relative error of the
+        /// image is below this fraction, e.g. 0.02, in at most --passes
+        /// passes [default: 256]
+        #[arg(long, value_name = "FRACTION")]
+        target_error: Option<f64>,
+        /// Save the progressive render to this file, and resume from it if it
+        /// exists
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+        /// Minimum seconds between two checkpoints
+        #[arg(long, default_value_t = 60)]
+        checkpoint_interval: u64,
+        /// Keep sampling noisy pixels until the 95% confidence interval of
+        /// their value is within this fraction of it, e.g. 0.05
+        #[arg(long, value_name = "THRESHOLD")]
+        adaptive: Option<f64>,
+        /// Samples per pixel at which adaptive sampling stops
+        #[arg(long, default_value_t = 1024)]
+        max_samples_per_pixel: u32,
+        /// Sample the light scattered by volumes straight from the lights at
+        /// equi-angular distances, for shafts of light around small lights
+        #[arg(long)]
+        equiangular: bool,
+        /// Split camera paths into this many bounces at their first hit, for
+        /// less noisy glossy reflections
+        #[arg(long, default_value_t = 1, value_name = "SAMPLES")]
+        first_bounce_samples: u32,
+        /// Limit the radiance of each sample from light that bounced more
+        /// than once to this channel value, e.g. 1000, to remove fireflies
+        #[arg(long, value_name = "LIMIT")]
+        clamp_indirect: Option<f64>,
+        /// Replace the pixels that are more than this many times as bright
+        /// as all of their neighbours, e.g. 10
+        #[arg(long, value_name = "THRESHOLD")]
+        reject_outliers: Option<f64>,
+        /// Also write the world normal, depth, albedo and edges of the first
+        /// hits, e.g. render.normal.png next to render.png
+        #[arg(long)]
+        aovs: bool,
+        /// Also write the variance of the mean of each pixel, e.g.
+        /// render.variance.exr next to render.png
+        #[arg(long)]
+        variance: bool,
+        /// Quick preview: interpolate the indirect light of diffuse surfaces
+        /// from an irradiance cache instead of tracing paths
+        #[arg(long)]
+        irradiance_cache: bool,
+        /// Run the finished image through Open Image Denoise
+        #[cfg(feature = "oidn")]
+        #[arg(long)]
+        denoise: bool,
+        /// Quality level: draft, preview or final. Sets the samples per
+        /// pixel, depth, indirect clamp, denoising and resolution scale that
+        /// aren't given by other flags.
+        #[arg(long)]
+        preset: Option<Preset>,
+        /// Render this fraction of the camera resolution, e.g. 0.5
+        #[arg(long, value_name = "SCALE")]
+        resolution_scale: Option<f64>,
+        /// Leave the OBJ models of at least this many MiB on disk until a ray
+        /// reaches them, for scenes that don't fit in memory
+        #[arg(long, value_name = "MIB")]
+        lazy_meshes: Option<u64>,
+        /// Keep at most this many MiB of lazy meshes loaded, unloading the
+        /// ones rays left the longest ago
+        #[arg(long, value_name = "MIB")]
+        lazy_mesh_budget: Option<usize>,
+        /// Trace the OBJ models that are loaded right away with Intel Embree
+        /// instead of their own hierarchy. Needs a build with the embree
+        /// feature.
+        #[arg(long)]
+        embree: bool,
+    },
+    /// Render a scene along a camera path, one image per frame. Objects with
+    /// keyframes in the scene file move with the frames.
+    Animate {
+        scene: PathBuf,
+        /// Camera path: a JSON list of keyframes or a Nuke .chan file
+        camera_path: PathBuf,
+        /// Frames per second
+        #[arg(long, default_value_t = 24.0)]
+        fps: f64,
+        /// Image resolution
+        #[arg(long, num_args = 2, value_names = ["WIDTH", "HEIGHT"], default_values_t = [800, 600])]
+        resolution: Vec<u32>,
+        /// Vertical field of view of the keys that don't have one, in degrees
+        #[arg(long, default_value_t = 40.0)]
+        fov: f64,
+        /// Samples per pixel [default: 16]
+        #[arg(long)]
+        samples_per_pixel: Option<u32>,
+        /// Maximum number of bounces per path [default: 5]
+        #[arg(long)]
+        max_depth: Option<u32>,
+        /// Reuse up to this many samples per pixel from the previous frame,
+        /// where the camera still sees the same surfaces
+        #[arg(long)]
+        temporal_history: Option<u32>,
+        /// Keep the shutter open for this part of each frame, in degrees of a
+        /// rotary shutter, e.g. 180, to blur the motion of the camera. Frames
+        /// with temporal history aren't blurred.
+        #[arg(long, value_name = "DEGREES")]
+        shutter_angle: Option<f64>,
+        /// Name of the frames, which are written as <NAME>_0001.png, ...
+        #[arg(short, long, default_value = "frame")]
+        output: String,
+        /// Trace the OBJ models that are loaded right away with Intel Embree
+        /// instead of their own hierarchy. Needs a build with the embree
+        /// feature.
+        #[arg(long)]
+        embree: bool,
+    },
+    /// Render a dataset for machine learning along a camera path: images
+    /// with their depth, normals and instance ids, and a JSON manifest
+    Dataset {
+        scene: PathBuf,
+        /// Camera path: a JSON list of keyframes or a Nuke .chan file
+        camera_path: PathBuf,
+        /// Frames per second
+        #[arg(long, default_value_t = 24.0)]
+        fps: f64,
+        /// Image resolution
+        #[arg(long, num_args = 2, value_names = ["WIDTH", "HEIGHT"], default_values_t = [640, 480])]
+        resolution: Vec<u32>,
+        /// Vertical field of view of the keys that don't have one, in degrees
+        #[arg(long, default_value_t = 40.0)]
+        fov: f64,
+        /// Render stereo pairs with this distance between the cameras, and
+        /// their disparity
+        #[arg(long, value_name = "BASELINE")]
+        stereo: Option<f64>,
+        /// Samples per pixel [default: 16]
+        #[arg(long)]
+        samples_per_pixel: Option<u32>,
+        /// Maximum number of bounces per path [default: 5]
+        #[arg(long)]
+        max_depth: Option<u32>,
+        /// Directory of the dataset
+        #[arg(short, long, default_value = "dataset")]
+        output: PathBuf,
+    },
+    /// Render an X-ray view of a scene with one of its cameras: the more
+    /// surfaces a pixel sees through, the brighter it is
+    Xray {
+        scene: PathBuf,
+        /// Index of the camera in the scene file
+        #[arg(long, default_value_t = 0)]
+        camera: usize,
+        /// Fraction of the rays absorbed by each surface
+        #[arg(long, default_value_t = 0.25)]
+        density: f64,
+        /// Show pixels that cross an odd number of surfaces, which are holes
+        /// in closed meshes, in red
+        #[arg(long)]
+        highlight_odd: bool,
+        #[arg(short, long, default_value = "xray.png")]
+        output: PathBuf,
+    },
+    /// Print an overview of a scene file
+    Info {
+        scene: PathBuf,
+        /// Print the overview as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report the object seen through a pixel of a camera
+    Pick {
+        scene: PathBuf,
+        /// Index of the camera in the scene file
+        #[arg(long, default_value_t = 0)]
+        camera: usize,
+        /// Pixel to inspect, as X,Y
+        #[arg(long, value_parser = parse_pixel)]
+        pixel: (u32, u32),
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Compare a render with a reference image using PSNR, SSIM and FLIP
+    Compare {
+        reference: PathBuf,
+        test: PathBuf,
+        /// Write an image of the FLIP error to this file
+        #[arg(long)]
+        heatmap: Option<PathBuf>,
+        /// Print the metrics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Tone map the radiance written by a render again, with another tone
+    /// mapper or exposure, without rendering the scene
+    Tonemap {
+        /// Radiance file, .exr or .hdr
+        radiance: PathBuf,
+        /// Tone mapper: clamp, reinhard or aces [default: --tone-mapper]
+        #[arg(long)]
+        operator: Option<ToneMapper>,
+        /// Exposure correction in stops, added to the auto exposure
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        ev: f64,
+        /// Image to write [default: the radiance file as .png]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run a render server that takes jobs over HTTP, see `server`
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: String,
+        /// Directory the textures, models and other files named by the
+        /// scenes of jobs are read from. Files outside of it are refused.
+        #[arg(long, default_value = ".")]
+        assets: PathBuf,
+        /// Largest width or height of the images of jobs
+        #[arg(long, default_value_t = 16384)]
+        max_resolution: u32,
+    },
+    /// Generate a shell completion script
+    Completions { shell: Shell },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = cli.user_settings().and_then(|user| run(cli, user));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli, user: Settings) -> Result<(), String> {
+    let flags = cli.settings();
+    let sidecars = cli.sidecars();
+    match cli.command {
+        Some(Command::BakeEnv {
+            source,
+            target,
+            output,
+            position,
+            size,
+            samples_per_pixel,
+            max_depth,
+            json,
+        }) => {
+            let textures = Arc::new(TextureCache::default());
+            let mut scene = FileLoader::new()
+                .texture_cache(textures.clone())
+                .load_scene(&source)?;
+            print_warnings(&scene);
+            let flags = Settings {
+                samples_per_pixel,
+                max_depth,
+                ..flags
+            };
+            let settings = flags.or(&scene.settings).or(&user);
+            settings.init_threads()?;
+            textures.set_budget(settings.texture_budget());
+            let samples_per_pixel = settings.samples_per_pixel.unwrap_or(64);
+            let max_depth = settings.max_depth.unwrap_or(5);
+
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(samples_per_pixel)
+                .max_depth(max_depth)
+                .seed(settings.seed)
+                .tile_size(settings.tile_size())
+                .sampler(settings.sampler())
+                .blue_noise(settings.blue_noise_mask());
+            check_memory(
+                &scene,
+                &textures,
+                CubeMap::render_memory(&renderer, size),
+                &settings,
+            )?;
+            let output = settings.output_path(output);
+            let position = glm::DVec3::new(position[0], position[1], position[2]);
+            scene.set_viewpoint(&position);
+            let start = Instant::now();
+            bake_environment(&renderer, &scene, &target, &output, position, size).map(|()| {
+                let seconds = start.elapsed().as_secs_f64();
+                if json {
+                    let result = serde_json::json!({
+                        "source": source,
+                        "target": target,
+                        "output": output,
+                        "size": size,
+                        "samples_per_pixel": samples_per_pixel,
+                        "max_depth": max_depth,
+                        "seconds": seconds,
+                    });
+                    println!("{result}");
+                } else {
+                    println!(
+                        "Baked {} into {} for {} in {seconds:.1} s",
+                        source.display(),
+                        output.display(),
+                        target.display()
+                    );
+                }
+            })
+        }
+        Some(Command::Render {
+            scene,
+            camera,
+            samples_per_pixel,
+            max_depth,
+            output,
+            passes,
+            target_error,
+            checkpoint,
+            checkpoint_interval,
+            adaptive,
+            max_samples_per_pixel,
+            equiangular,
+            first_bounce_samples,
+            clamp_indirect,
+            reject_outliers,
+            aovs,
+            variance,
+            irradiance_cache,
+            #[cfg(feature = "oidn")]
+            denoise,
+            preset,
+            resolution_scale,
+            lazy_meshes,
+            lazy_mesh_budget,
+            embree,
+        }) => {
+            let textures = Arc::new(TextureCache::default());
+            let mut scene = FileLoader::new()
+                .texture_cache(textures.clone())
+                .lazy_meshes(lazy_meshes.map(|mib| mib << 20))
+                .mesh_residency(Arc::new(
+                    lazy_mesh_budget
+                        .map_or_else(MeshResidency::default, |mib| MeshResidency::new(mib << 20)),
+                ))
+                .embree(embree)
+                .load_scene(&scene)?;
+            print_warnings(&scene);
+            let config = scene.cameras.get(camera).ok_or_else(|| {
+                format!(
+                    "The scene has {} cameras, no camera {camera}",
+                    scene.cameras.len()
+                )
+            })?;
+            let resolution_scale =
+                resolution_scale.unwrap_or(preset.map_or(1.0, |preset| preset.resolution_scale()));
+            if resolution_scale <= 0.0 {
+                return Err("The resolution scale must be positive".to_string());
+            }
+            let camera = Camera::new(&config.scaled(resolution_scale));
+            scene.set_viewpoint(&camera.position());
+            let flags = Settings {
+                samples_per_pixel,
+                max_depth,
+                ..flags
+            };
+            let preset_settings = preset.map(|preset| preset.settings()).unwrap_or_default();
+            let settings = flags.or(&preset_settings).or(&scene.settings).or(&user);
+            settings.init_threads()?;
+            textures.set_budget(settings.texture_budget());
+
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(settings.samples_per_pixel.unwrap_or(16))
+                .max_depth(settings.max_depth.unwrap_or(5))
+                .tone_mapper(settings.tone_mapper())
+                .seed(settings.seed)
+                .tile_size(settings.tile_size())
+                .sampler(settings.sampler())
+                .blue_noise(settings.blue_noise_mask())
+                .equiangular_sampling(equiangular)
+                .first_bounce_samples(first_bounce_samples)
+                .indirect_clamp(
+                    clamp_indirect.or(preset.and_then(|preset| preset.indirect_clamp())),
+                )
+                .outlier_rejection(reject_outliers)
+                .irradiance_caching(irradiance_cache.then(IrradianceCaching::default))
+                .adaptive_sampling(
+                    adaptive
+                        .map(|threshold| AdaptiveSampling::new(threshold, max_samples_per_pixel)),
+                );
+            #[cfg(feature = "oidn")]
+            renderer.denoise(denoise || preset.is_some_and(|preset| preset.denoise()));
+            let output = settings.output_path(output);
+            if target_error.is_some_and(|target| target <= 0.0) {
+                return Err("The target error must be positive".to_string());
+            }
+            let progressive = (passes.is_some() || checkpoint.is_some() || target_error.is_some())
+                .then(|| Progressive {
+                    passes: passes.unwrap_or(if target_error.is_some() {
+                        MAX_TARGET_ERROR_PASSES
+                    } else {
+                        1
+                    }),
+                    checkpoint,
+                    interval: Duration::from_secs(checkpoint_interval),
+                    resume: true,
+                    target_error,
+                });
+            if aovs && progressive.is_some() {
+                return Err("AOVs can't be rendered progressively".to_string());
+            }
+            check_memory(
+                &scene,
+                &textures,
+                renderer.film_memory(&camera, aovs),
+                &settings,
+            )?;
+            let render_film = || match &progressive {
+                Some(progressive) => {
+                    renderer.render_progressive(&scene, &camera, progressive, &mut |pass, film| {
+                        match progressive.target_error {
+                            Some(_) => eprintln!(
+                                "Pass {pass}/{}, error {:.2}%",
+                                progressive.passes,
+                                100.0 * film.relative_error()
+                            ),
+                            None => eprintln!("Pass {pass}/{}", progressive.passes),
+                        }
+                    })
+                }
+                None if aovs => {
+                    let buffers = renderer.render_buffers(&scene, &camera);
+                    for (image, extension) in [
+                        (buffers.normal_image(), "normal.png"),
+                        (buffers.depth_image(), "depth.png"),
+                        (buffers.albedo_image(), "albedo.png"),
+                        (
+                            buffers.edges(&AovSettings::default()).to_image(),
+                            "edges.png",
+                        ),
+                    ] {
+                        let path = output.with_extension(extension);
+                        image
+                            .save(&path)
+                            .map_err(|e| format!("{}: {e}", path.display()))?;
+                    }
+                    Ok(buffers.beauty)
+                }
+                None => Ok(renderer.render_film(&scene, &camera)),
+            };
+            if RadianceFormat::from_path(&output).is_ok() {
+                let film = render_film()?;
+                print_render_warnings(&scene, &textures);
+                report_error(&film, &output, variance)?;
+                return output::write_radiance(&film, &output);
+            }
+            sidecars.write_preview(&renderer, &scene, &camera, &output)?;
+            let mut film = render_film()?;
+            print_render_warnings(&scene, &textures);
+            report_error(&film, &output, variance)?;
+            output::write_radiance(&film, output.with_extension("exr"))?;
+            auto_expose(&mut film, &settings);
+            let image = film.to_image_with(settings.tone_mapper());
+            image
+                .save(&output)
+                .map_err(|e| format!("{}: {e}", output.display()))?;
+            sidecars.write_thumbnail(&image, &output)?;
+            Ok(())
+        }
+        Some(Command::Animate {
+            scene,
+            camera_path,
+            fps,
+            resolution,
+            fov,
+            samples_per_pixel,
+            max_depth,
+            temporal_history,
+            shutter_angle,
+            output,
+            embree,
+        }) => {
+            if fps <= 0.0 {
+                return Err("The frame rate must be positive".to_string());
+            }
+            let shutter = match shutter_angle {
+                Some(angle) if !(0.0..=360.0).contains(&angle) => {
+                    return Err("The shutter angle must be between 0 and 360 degrees".to_string())
+                }
+                Some(angle) => Shutter::new(0.0, angle / 360.0 / fps)?,
+                None => Shutter::default(),
+            };
+            let textures = Arc::new(TextureCache::default());
+            let mut scene = FileLoader::new()
+                .texture_cache(textures.clone())
+                .embree(embree)
+                .load_scene(&scene)?;
+            print_warnings(&scene);
+            let path = CameraPath::load(&camera_path, fps)?;
+            let flags = Settings {
+                samples_per_pixel,
+                max_depth,
+                ..flags
+            };
+            let settings = flags.or(&scene.settings).or(&user);
+            settings.init_threads()?;
+            textures.set_budget(settings.texture_budget());
+
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(settings.samples_per_pixel.unwrap_or(16))
+                .max_depth(settings.max_depth.unwrap_or(5))
+                .tone_mapper(settings.tone_mapper())
+                .tile_size(settings.tile_size())
+                .sampler(settings.sampler())
+                .blue_noise(settings.blue_noise_mask());
+            let base = CameraConfig {
+                resolution: (resolution[0], resolution[1]),
+                fov: FieldOfView::Vertical(fov.to_radians()),
+                shutter,
+                ..Default::default()
+            };
+            let frame_camera = Camera::new(&base);
+            let frame_memory = match temporal_history {
+                Some(_) => TemporalAccumulator::frame_memory(&renderer, &frame_camera),
+                None => renderer.film_memory(&frame_camera, false),
+            };
+            check_memory(&scene, &textures, frame_memory, &settings)?;
+            let mut temporal = temporal_history.map(|max_history| {
+                TemporalAccumulator::new(TemporalReuse {
+                    max_history,
+                    ..Default::default()
+                })
+            });
+            let cameras = path.camera_configs(fps, &base);
+            for (n, (time, config)) in path.frame_times(fps).into_iter().zip(&cameras).enumerate() {
+                scene.set_time(time);
+                // New samples for every frame, or the temporal history would
+                // pile up the same noise
+                renderer.seed(
+                    settings
+                        .seed
+                        .map(|seed| sampling::frame_seed(seed, n as u32)),
+                );
+                let frame = settings.output_path(format!("{output}_{:04}.png", n + 1));
+                let camera = path
+                    .camera(time, &base)
+                    .unwrap_or_else(|| Camera::new(config));
+                scene.set_viewpoint(&camera.position());
+                sidecars.write_preview(&renderer, &scene, &camera, &frame)?;
+                // The history of the temporal accumulation stays unexposed
+                let mut film = match &mut temporal {
+                    Some(temporal) => temporal.render_frame(&renderer, &scene, config).clone(),
+                    None => renderer.render_film(&scene, &camera),
+                };
+                auto_expose(&mut film, &settings);
+                let image = film.to_image_with(settings.tone_mapper());
+                image
+                    .save(&frame)
+                    .map_err(|e| format!("{}: {e}", frame.display()))?;
+                sidecars.write_thumbnail(&image, &frame)?;
+                println!("Frame {}/{}: {}", n + 1, cameras.len(), frame.display());
+            }
+            Ok(())
+        }
+        Some(Command::Dataset {
+            scene,
+            camera_path,
+            fps,
+            resolution,
+            fov,
+            stereo,
+            samples_per_pixel,
+            max_depth,
+            output,
+        }) => {
+            if fps <= 0.0 {
+                return Err("The frame rate must be positive".to_string());
+            }
+            let textures = Arc::new(TextureCache::default());
+            let mut scene = FileLoader::new()
+                .texture_cache(textures.clone())
+                .load_scene(&scene)?;
+            print_warnings(&scene);
+            let path = CameraPath::load(&camera_path, fps)?;
+            let flags = Settings {
+                samples_per_pixel,
+                max_depth,
+                ..flags
+            };
+            let settings = flags.or(&scene.settings).or(&user);
+            settings.init_threads()?;
+            textures.set_budget(settings.texture_budget());
+
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(settings.samples_per_pixel.unwrap_or(16))
+                .max_depth(settings.max_depth.unwrap_or(5))
+                .tile_size(settings.tile_size())
+                .sampler(settings.sampler())
+                .blue_noise(settings.blue_noise_mask());
+            let base = CameraConfig {
+                resolution: (resolution[0], resolution[1]),
+                fov: FieldOfView::Vertical(fov.to_radians()),
+                ..Default::default()
+            };
+            let mut writer = DatasetWriter::new(settings.output_path(output));
+            writer.tone_mapper(settings.tone_mapper());
+            if let Some(baseline) = stereo {
+                writer.stereo(baseline);
+            }
+            check_memory(
+                &scene,
+                &textures,
+                writer.frame_memory(&renderer, &base),
+                &settings,
+            )?;
+            let cameras = path.camera_configs(fps, &base);
+            for (n, (time, config)) in path.frame_times(fps).into_iter().zip(&cameras).enumerate() {
+                scene.set_time(time);
+                renderer.seed(
+                    settings
+                        .seed
+                        .map(|seed| sampling::frame_seed(seed, n as u32)),
+                );
+                scene.set_viewpoint(&config.position);
+                writer.write_frame(&renderer, &scene, config)?;
+                println!("Frame {}/{}", n + 1, cameras.len());
+            }
+            let manifest = writer.finish(&scene)?;
+            println!("Manifest: {}", manifest.display());
+            Ok(())
+        }
+        Some(Command::Xray {
+            scene,
+            camera,
+            density,
+            highlight_odd,
+            output,
+        }) => {
+            let mut scene = FileLoader::new().load_scene(&scene)?;
+            print_warnings(&scene);
+            let config = scene.cameras.get(camera).ok_or_else(|| {
+                format!(
+                    "The scene has {} cameras, no camera {camera}",
+                    scene.cameras.len()
+                )
+            })?;
+            let settings = flags.or(&scene.settings).or(&user);
+            settings.init_threads()?;
+
+            let camera = Camera::new(config);
+            scene.set_viewpoint(&camera.position());
+            let crossings = xray::render_xray(&scene, &camera);
+            let odd = crossings.odd_pixels();
+            if odd > 0 {
+                eprintln!("{odd} pixels cross an odd number of surfaces");
+            }
+            let output = settings.output_path(output);
+            crossings
+                .to_image(density, highlight_odd)
+                .save(&output)
+                .map_err(|e| format!("{}: {e}", output.display()))
+        }
+        Some(Command::Info { scene, json }) => FileLoader::new().load_scene(&scene).map(|loaded| {
+            let summary = loaded.summary();
+            let report = PreprocessReport::new(&loaded);
+            if json {
+                let mut info = summary.to_json();
+                info["scene"] = serde_json::json!(scene);
+                info["preprocess"] = report.to_json();
+                println!("{info}");
+            } else {
+                println!("Scene:       {}", scene.display());
+                println!("{summary}");
+                println!("{report}");
+            }
+        }),
+        Some(Command::Pick {
+            scene,
+            camera,
+            pixel,
+            json,
+        }) => {
+            let mut scene = FileLoader::new().load_scene(&scene)?;
+            let config = scene.cameras.get(camera).ok_or_else(|| {
+                format!(
+                    "The scene has {} cameras, no camera {camera}",
+                    scene.cameras.len()
+                )
+            })?;
+            let camera = Camera::new(config);
+            scene.set_viewpoint(&camera.position());
+            let picked = inspect::pick(&scene, &camera, pixel)?;
+            match (picked, json) {
+                (Some(picked), true) => println!("{}", picked.to_json()),
+                (Some(picked), false) => println!("{picked}"),
+                (None, true) => println!("null"),
+                (None, false) => println!("Background"),
+            }
+            Ok(())
+        }
+        Some(Command::Compare {
+            reference,
+            test,
+            heatmap,
+            json,
+        }) => {
+            let open = |path: &Path| {
+                image::open(path)
+                    .map(|image| image.into_rgb8())
+                    .map_err(|e| format!("{}: {e}", path.display()))
+            };
+            let comparison = metrics::compare(&open(&reference)?, &open(&test)?)?;
+            if let Some(heatmap) = heatmap {
+                let heatmap_path = flags.or(&user).output_path(heatmap);
+                comparison
+                    .flip_map
+                    .heatmap()
+                    .save(&heatmap_path)
+                    .map_err(|e| format!("{}: {e}", heatmap_path.display()))?;
+            }
+            if json {
+                println!("{}", comparison.to_json());
+            } else {
+                println!("{comparison}");
+            }
+            Ok(())
+        }
+        Some(Command::Tonemap {
+            radiance,
+            operator,
+            ev,
+            output,
+        }) => {
+            let settings = flags.or(&user);
+            let mut film = output::read_radiance(&radiance)?;
+            auto_expose(&mut film, &settings);
+            film.expose(ev);
+            let output = output.unwrap_or_else(|| radiance.with_extension("png"));
+            let output = settings.output_path(output);
+            film.to_image_with(operator.unwrap_or(settings.tone_mapper()))
+                .save(&output)
+                .map_err(|e| format!("{}: {e}", output.display()))
+        }
+        Some(Command::Serve {
+            address,
+            assets,
+            max_resolution,
+        }) => {
+            let settings = flags.or(&user);
+            settings.init_threads()?;
+            let mut limits = JobLimits {
+                max_resolution,
+                asset_root: assets,
+                ..Default::default()
+            };
+            if let Some(budget) = settings.memory_budget {
+                limits.memory_budget = budget << 20;
+            }
+            let server = RenderServer::with_limits(settings.tone_mapper(), limits);
+            println!("Listening on http://{address}");
+            server.serve(&address)
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), "light", &mut std::io::stdout());
+            Ok(())
+        }
+        None => {
+            let settings = flags.or(&user);
+            settings.init_threads()?;
+            render_demo(&settings, sidecars)
+        }
+    }
+}
+
+/// Parse a pixel given as X,Y
+fn parse_pixel(value: &str) -> Result<(u32, u32), String> {
+    let (x, y) = value.split_once(',').ok_or("Expected a pixel as X,Y")?;
+    let coordinate = |c: &str| c.trim().parse::<u32>().map_err(|e| format!("{c}: {e}"));
+    Ok((coordinate(x)?, coordinate(y)?))
+}
+
+/// Correct the exposure of a film before it is tone mapped, if the settings
+/// ask for it
+fn auto_expose(film: &mut Film, settings: &Settings) {
+    if let Some(metering) = settings.auto_exposure {
+        let ev = film.meter(metering);
+        film.expose(ev);
+        eprintln!("Auto exposure: {ev:+.2} EV");
+    }
+}
+
+/// Report the estimated relative error of a render, and write the variance
+/// of its pixels next to `output` if asked to
+fn report_error(film: &Film, output: &Path, variance: bool) -> Result<(), String> {
+    let error = film.relative_error();
+    if error.is_finite() {
+        eprintln!("Estimated relative error: {:.2}%", 100.0 * error);
+    }
+    if variance {
+        let path = output.with_extension("variance.exr");
+        output::write_radiance(&film.variance_of_mean(), path)?;
+    }
+    Ok(())
+}
+
+/// Report potential problems of a scene before spending time on rendering it
+/// Problems found during the render: shapes that couldn't be loaded, and
+/// textures that couldn't be read and were black
+fn print_render_warnings(scene: &Scene, textures: &TextureCache) {
+    for object in &scene.objects {
+        if let Some(warning) = object.shape.warning() {
+            eprintln!("Warning: {warning}");
+        }
+    }
+    if let Some(error) = textures.error() {
+        let failures = textures.stats().failures;
+        eprintln!("Warning: {error} ({failures} texel lookups failed)");
+    }
+}
+
+fn print_warnings(scene: &Scene) {
+    for warning in &scene.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    for warning in PreprocessReport::new(scene).warnings {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+/// Stop before rendering when the scene, the tiles its textures can keep
+/// resident and `film_memory` bytes of films go over the memory budget of the
+/// settings
+fn check_memory(
+    scene: &Scene,
+    textures: &TextureCache,
+    film_memory: usize,
+    settings: &Settings,
+) -> Result<(), String> {
+    match settings.memory_budget {
+        Some(budget) => PreprocessReport::new(scene)
+            .texture_cache(textures)
+            .check_memory(film_memory, budget << 20),
+        None => Ok(()),
+    }
+}
+
+fn bake_environment(
+    renderer: &PathTracer,
+    scene: &Scene,
+    target: &Path,
+    output: &Path,
+    position: glm::DVec3,
+    size: u32,
+) -> Result<(), String> {
+    let target = loader::EnvironmentTarget::open(target)?;
+    let cube_map = CubeMap::render(renderer, scene, position, size);
+    cube_map.save(output)?;
+    target.register(output)
+}
+
+fn render_demo(settings: &Settings, sidecars: Sidecars) -> Result<(), String> {
     println!("light!");
 
     let mut scene = Scene::new();
@@ -46,6 +1053,7 @@ fn main() {
                 color: Color::new(255.0, 0.0, 0.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(-0.0, 10.0, 50.0), 10.0)),
@@ -53,6 +1061,7 @@ fn main() {
                 color: Color::new(0.0, 255.0, 0.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(30.0, 10.0, 50.0), 10.0)),
@@ -60,6 +1069,7 @@ fn main() {
                 color: Color::new(0.0, 0.0, 255.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Triangle::new(
@@ -71,6 +1081,7 @@ fn main() {
                 color: Color::new(255.0, 255.0, 0.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Triangle::new(
@@ -82,6 +1093,7 @@ fn main() {
                 color: Color::new(255.0, 0.0, 255.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Triangle::new(
@@ -93,6 +1105,7 @@ fn main() {
                 color: Color::new(0.0, 255.0, 255.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(-10.0, 2.0, 8.0), 2.0)),
@@ -100,6 +1113,7 @@ fn main() {
                 color: Color::new(0.0, 255.0, 185.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 2.0, 10.0), 2.0)),
@@ -107,6 +1121,7 @@ fn main() {
                 color: Color::new(255.0, 185.0, 0.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(10.0, 2.0, 8.0), 2.0)),
@@ -114,6 +1129,7 @@ fn main() {
                 color: Color::new(185.0, 255.0, 0.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Plane {
@@ -124,15 +1140,20 @@ fn main() {
                 color: Color::new(128.0, 128.0, 128.0),
                 ..Default::default()
             },
+            materials: Vec::new(),
         })
         .add_object(Object {
             shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 100.0, 0.0), 1.0)),
             material: Material {
                 color: Color::new(255.0, 255.0, 255.0),
                 emittance: 1.0,
+                ..Default::default()
             },
+            materials: Vec::new(),
         });
 
+    print_warnings(&scene);
+
     let aperture_camera = Camera::new(&CameraConfig {
         position: glm::DVec3::new(0.0, 10.0, 00.0),
         direction: glm::DVec3::new(0.0, -10.0, 50.0),
@@ -143,6 +1164,7 @@ fn main() {
             focal_distance: 50.0,
             aperture: 0.3,
         },
+        ..Default::default()
     });
 
     let pinhole_camera = Camera::new(&CameraConfig {
@@ -152,17 +1174,62 @@ fn main() {
         rotation: 0.0_f64.to_radians(),
         fov: FieldOfView::Horizontal(100.0_f64.to_radians()),
         focus_mode: FocusMode::PinHole,
+        ..Default::default()
     });
 
+    let blue_noise = Arc::new(BlueNoiseMask::generate(64, 0));
     let mut renderer = PathTracer::new();
-    renderer.samples_per_pixel(32).max_depth(5);
+    renderer
+        .samples_per_pixel(settings.samples_per_pixel.unwrap_or(32))
+        .max_depth(settings.max_depth.unwrap_or(5))
+        .tile_order(TileOrder::Spiral)
+        .blue_noise(Some(blue_noise.clone()))
+        .tone_mapper(settings.tone_mapper())
+        .seed(settings.seed)
+        .tile_size(settings.tile_size())
+        .sampler(settings.sampler());
 
-    let render_image = renderer.render(&scene, &aperture_camera);
-    let geo_image = render::render_geometry(&scene, &pinhole_camera);
+    let output_dir = settings.output_dir.clone().unwrap_or("target".into());
+    let output_path = output_dir.join("output.png");
+    sidecars.write_preview(&renderer, &scene, &aperture_camera, &output_path)?;
+    let mut film = renderer.render_film(&scene, &aperture_camera);
+    let report = film.non_finite_report();
+    if report.total() > 0 {
+        println!("Warning: {report}");
+    }
+    // The radiance before exposure and tone mapping, for external
+    // post-processing
+    for name in ["output.exr", "output.hdr"] {
+        output::write_radiance(&film, output_dir.join(name)).expect("Expected to save file");
+    }
+    auto_expose(&mut film, settings);
+    let render_image = film.to_image_with(settings.tone_mapper());
+    let geo_image = render::render_geometry(
+        &scene,
+        &pinhole_camera,
+        4,
+        GeometryShading::FacingRatio,
+        Some(&blue_noise),
+    );
     geo_image
-        .save_with_format("target/output_geo.png", image::ImageFormat::Png)
+        .save_with_format(output_dir.join("output_geo.png"), image::ImageFormat::Png)
         .expect("Expected to save file");
     render_image
-        .save_with_format("target/output.png", image::ImageFormat::Png)
+        .save_with_format(&output_path, image::ImageFormat::Png)
         .expect("Expected to save file");
+    sidecars.write_thumbnail(&render_image, &output_path)?;
+
+    let aov_settings = AovSettings::default();
+    for (aov, name) in [
+        (Aov::AmbientOcclusion, "output_ao.png"),
+        (Aov::Curvature, "output_curvature.png"),
+        (Aov::Edges, "output_edges.png"),
+    ] {
+        aov_settings
+            .render(&scene, &pinhole_camera, aov)
+            .to_image()
+            .save_with_format(output_dir.join(name), image::ImageFormat::Png)
+            .expect("Expected to save file");
+    }
+    Ok(())
 }
@@ -17,34 +17,629 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use rand::rngs::ThreadRng;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::Arc;
 
+use rand::{Rng, RngCore};
+
+use crate::bevel::Bevel;
 use crate::color::Color;
+use crate::driver::Blend;
+use crate::expr::ColorExpr;
+use crate::sampling;
+use crate::shape::HitRecord;
+use crate::spectrum::Illuminant;
+use crate::texture::Texture;
+
+/// Hit attributes available to materials when shading a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadingContext {
+    pub position: glm::DVec3,
+    pub normal: glm::DVec3,
+    pub uv: glm::DVec2,
+    pub object_id: usize, // Index of the object in the scene
+    pub view: glm::DVec3, // Direction of the ray that found the point
+    pub footprint: f64,   // Width of the beam of the ray, in uv units
+}
+
+impl ShadingContext {
+    pub fn new(record: &HitRecord, object_id: usize) -> Self {
+        Self {
+            position: record.point,
+            normal: record.normal,
+            uv: record.uv,
+            object_id,
+            view: -record.normal,
+            footprint: 0.0,
+        }
+    }
+
+    /// Set the direction the point is seen along. Points are seen head-on
+    /// unless told otherwise.
+    pub fn viewed_along(mut self, direction: &glm::DVec3) -> Self {
+        self.view = *direction;
+        self
+    }
+
+    /// Set the width of the beam of the ray that found the point, in uv
+    /// units, see `Ray::footprint_at`. Textures are sampled at their finest
+    /// level unless told otherwise.
+    pub fn with_footprint(mut self, footprint: f64) -> Self {
+        self.footprint = footprint;
+        self
+    }
+}
+
+/// Smooth surfaces, which scatter light along a single direction or, for
+/// rough metals, close to it. They are tinted by the color of the material,
+/// white being lossless.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Specular {
+    Mirror,
+    /// Boundary of a transparent medium, e.g. glass or water
+    Dielectric {
+        ior: f64,
+    },
+    /// Metal whose reflections are blurred by microfacets, e.g. brushed
+    /// steel. A roughness of 0 is a mirror and 1 is close to diffuse.
+    Metal {
+        roughness: f64,
+    },
+}
+
+impl Specular {
+    /// Scatter light travelling along `direction`, see `Bsdf::scatter`. `u`
+    /// holds two uniform numbers: `u.x` chooses between reflection and
+    /// refraction, and rough metals use both to pick a microfacet.
+    pub fn deflect(
+        &self,
+        normal: &glm::DVec3,
+        direction: &glm::DVec3,
+        inside: bool,
+        u: glm::DVec2,
+    ) -> (glm::DVec3, bool) {
+        // Normal facing the incoming light
+        let n = if normal.dot(direction) > 0.0 {
+            -normal
+        } else {
+            *normal
+        };
+        let cos_i = -direction.dot(&n);
+        let reflected = direction + 2.0 * cos_i * n;
+
+        match *self {
+            Specular::Mirror => (reflected, false),
+            Specular::Dielectric { ior } => {
+                let eta = if inside { ior } else { 1.0 / ior };
+                let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+                if sin2_t > 1.0 {
+                    return (reflected, false); // Total internal reflection
+                }
+
+                // Schlick's approximation of the Fresnel reflectance
+                let r0 = ((1.0 - ior) / (1.0 + ior)).powi(2);
+                let reflectance = r0 + (1.0 - r0) * (1.0 - cos_i).powi(5);
+                if u.x < reflectance {
+                    return (reflected, false);
+                }
+
+                let cos_t = (1.0 - sin2_t).sqrt();
+                (
+                    (eta * direction + (eta * cos_i - cos_t) * n).normalize(),
+                    true,
+                )
+            }
+            Specular::Metal { roughness } => {
+                // Reflect on a microfacet normal drawn from the GGX
+                // distribution, with the usual alpha = roughness²
+                let alpha = roughness * roughness;
+                let tan2_m = alpha * alpha * u.x / (1.0 - u.x).max(f64::EPSILON);
+                let cos_m = 1.0 / (1.0 + tan2_m).sqrt();
+                let sin_m = (1.0 - cos_m * cos_m).max(0.0).sqrt();
+                let phi = 2.0 * PI * u.y;
+                let local = glm::DVec3::new(sin_m * phi.cos(), sin_m * phi.sin(), cos_m);
+                let m = sampling::to_world(&n, &local);
+
+                let reflected = direction - 2.0 * direction.dot(&m) * m;
+                // Reflections into the surface are folded back out of it
+                let below = reflected.dot(&n);
+                if below < 0.0 {
+                    ((reflected - 2.0 * below * n).normalize(), false)
+                } else {
+                    (reflected.normalize(), false)
+                }
+            }
+        }
+    }
+}
+
+/// Model of how a surface scatters light. Materials use a Lambertian surface
+/// unless they are given another model, e.g. a measured BRDF or a `Specular`
+/// surface.
+pub trait Bsdf: fmt::Debug + Send + Sync {
+    /// Reflectance for light arriving along `vin` and leaving along `vout`,
+    /// both pointing away from the surface, for a surface colored `albedo`
+    fn eval(
+        &self,
+        normal: &glm::DVec3,
+        albedo: &Color,
+        vin: &glm::DVec3,
+        vout: &glm::DVec3,
+    ) -> Color;
+
+    /// Sample the direction `vin` light arrives from, for light leaving
+    /// along `vout`. Returns the direction and its pdf.
+    fn sample(
+        &self,
+        normal: &glm::DVec3,
+        vout: &glm::DVec3,
+        rng: &mut dyn RngCore,
+    ) -> (glm::DVec3, f64);
+
+    /// Density, with respect to solid angle, of `sample` returning `vin`
+    fn pdf(&self, normal: &glm::DVec3, vout: &glm::DVec3, vin: &glm::DVec3) -> f64;
+
+    /// Name of the model, for reports
+    fn kind(&self) -> &'static str;
+
+    /// Estimated memory used by the model, in bytes
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    /// Scatter light travelling along `direction` on a smooth surface, which
+    /// sends it along a single direction that `sample` can't be weighted
+    /// against. `inside` tells whether the light travels inside the medium.
+    ///
+    /// Returns the new direction and whether the light went through the
+    /// surface, or None for surfaces that scatter light over the hemisphere,
+    /// without drawing any random number.
+    fn scatter(
+        &self,
+        _normal: &glm::DVec3,
+        _direction: &glm::DVec3,
+        _inside: bool,
+        _rng: &mut dyn RngCore,
+    ) -> Option<(glm::DVec3, bool)> {
+        None
+    }
+
+    /// The model of smooth surfaces, which scatter light with `scatter`
+    fn specular(&self) -> Option<Specular> {
+        None
+    }
+}
+
+/// Smooth surfaces send light into a single direction, or close to it: a
+/// spike of reflectance that `eval` and `pdf` leave out. Light follows
+/// `scatter` instead, and lights aren't sampled from them.
+impl Bsdf for Specular {
+    fn eval(
+        &self,
+        _normal: &glm::DVec3,
+        _albedo: &Color,
+        _vin: &glm::DVec3,
+        _vout: &glm::DVec3,
+    ) -> Color {
+        Color::zeros()
+    }
+
+    /// The direction of the spike, with an infinite pdf
+    fn sample(
+        &self,
+        normal: &glm::DVec3,
+        vout: &glm::DVec3,
+        rng: &mut dyn RngCore,
+    ) -> (glm::DVec3, f64) {
+        let inside = normal.dot(vout) < 0.0;
+        let u = glm::DVec2::new(rng.gen(), rng.gen());
+        (self.deflect(normal, &-vout, inside, u).0, f64::INFINITY)
+    }
+
+    fn pdf(&self, _normal: &glm::DVec3, _vout: &glm::DVec3, _vin: &glm::DVec3) -> f64 {
+        0.0
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Specular::Mirror => "mirror",
+            Specular::Dielectric { .. } => "dielectric",
+            Specular::Metal { .. } => "metal",
+        }
+    }
+
+    fn scatter(
+        &self,
+        normal: &glm::DVec3,
+        direction: &glm::DVec3,
+        inside: bool,
+        rng: &mut dyn RngCore,
+    ) -> Option<(glm::DVec3, bool)> {
+        let u = glm::DVec2::new(rng.gen(), rng.gen());
+        Some(self.deflect(normal, direction, inside, u))
+    }
+
+    fn specular(&self) -> Option<Specular> {
+        Some(*self)
+    }
+}
+
+/// Surface that scatters its albedo evenly in all directions on the side the
+/// light comes from. Directions are sampled from a cosine-weighted
+/// distribution on the side of `vout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lambertian;
+
+impl Bsdf for Lambertian {
+    fn eval(
+        &self,
+        normal: &glm::DVec3,
+        albedo: &Color,
+        vin: &glm::DVec3,
+        vout: &glm::DVec3,
+    ) -> Color {
+        if normal.dot(vin) * normal.dot(vout) <= 0.0 {
+            return Color::zeros();
+        }
+        albedo / (255.0 * PI)
+    }
+
+    fn sample(
+        &self,
+        normal: &glm::DVec3,
+        vout: &glm::DVec3,
+        mut rng: &mut dyn RngCore,
+    ) -> (glm::DVec3, f64) {
+        let (local, pdf) = sampling::cosine_hemisphere(&mut rng);
+        (sampling::to_world(&facing(normal, vout), &local), pdf)
+    }
+
+    fn pdf(&self, normal: &glm::DVec3, vout: &glm::DVec3, vin: &glm::DVec3) -> f64 {
+        facing(normal, vout).dot(vin).max(0.0) / PI
+    }
+
+    fn kind(&self) -> &'static str {
+        "lambertian"
+    }
+}
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Material {
     pub color: Color,
     pub emittance: f64,
+    pub bsdf: Option<Arc<dyn Bsdf>>, // Scattering model, Lambertian if None
+    pub procedural: Option<Arc<ColorExpr>>, // Procedural color, overrides the color
+    pub texture: Option<Texture>,    // Image color, overrides the color
+    pub absorption: Color, // Absorption coefficient of the medium behind a dielectric surface
+    pub bevel: Option<Bevel>, // Rounded edges at shading time
+    pub blend: Option<Blend>, // Color mixed in by a geometric attribute
+}
+
+/// Index of refraction of water
+pub const WATER_IOR: f64 = 1.333;
+
+/// Normal on the side of the surface `v` points to
+pub(crate) fn facing(normal: &glm::DVec3, v: &glm::DVec3) -> glm::DVec3 {
+    if normal.dot(v) < 0.0 {
+        -normal
+    } else {
+        *normal
+    }
 }
 
 impl Material {
-    pub fn bsdf(&self, normal: &glm::DVec3, vin: &glm::DVec3, vout: &glm::DVec3) -> Color {
-        // TODO
-        self.color
+    /// Emissive material whose color is given by a physical illuminant,
+    /// e.g. a 3200 K tungsten lamp
+    pub fn emitter(illuminant: Illuminant, emittance: f64) -> Self {
+        Self {
+            color: 255.0 * illuminant.color(),
+            emittance,
+            ..Default::default()
+        }
+    }
+
+    /// Metal tinted by `color`. A roughness of 0 gives a polished mirror,
+    /// higher ones brushed or sandblasted finishes.
+    pub fn metal(color: Color, roughness: f64) -> Self {
+        Self {
+            color,
+            bsdf: Some(Arc::new(Specular::Metal {
+                roughness: roughness.clamp(0.0, 1.0),
+            })),
+            ..Default::default()
+        }
     }
 
-    pub fn sample_bounce(
+    /// Water surface. Light that goes through it is tinted by the water
+    /// below, more the deeper it goes: white light turns `tint` after
+    /// travelling `depth` scene units through the water.
+    pub fn water(tint: Color, depth: f64) -> Self {
+        Self {
+            color: Color::new(255.0, 255.0, 255.0),
+            bsdf: Some(Arc::new(Specular::Dielectric { ior: WATER_IOR })),
+            absorption: tint.map(|c| -(c.clamp(1e-3, 255.0) / 255.0).ln() / depth),
+            ..Default::default()
+        }
+    }
+
+    /// Fraction of the light that goes through `distance` units of the
+    /// medium behind the surface (Beer-Lambert law)
+    pub fn transmittance(absorption: &Color, distance: f64) -> Color {
+        absorption.map(|a| if a > 0.0 { (-a * distance).exp() } else { 1.0 })
+    }
+
+    /// Surface color at a shading point, evaluating the procedural color or
+    /// the texture if there is one, and then the blend
+    pub fn albedo(&self, ctx: &ShadingContext) -> Color {
+        let base = if let Some(procedural) = &self.procedural {
+            procedural.eval(ctx)
+        } else {
+            match &self.texture {
+                Some(texture) => texture.sample(ctx.uv, texture.lod(ctx.footprint)),
+                None => self.color,
+            }
+        };
+        match &self.blend {
+            Some(blend) => blend.apply(&base, ctx),
+            None => base,
+        }
+    }
+
+    /// Scattering model of the surface
+    pub fn surface(&self) -> &dyn Bsdf {
+        self.bsdf.as_deref().unwrap_or(&Lambertian)
+    }
+
+    /// Model of the surface if it's smooth, see `Bsdf::specular`
+    pub fn specular(&self) -> Option<Specular> {
+        self.surface().specular()
+    }
+
+    /// Reflectance for light arriving along `vin` and leaving along `vout`,
+    /// both pointing away from the surface, see `Bsdf::eval`
+    pub fn eval(&self, ctx: &ShadingContext, vin: &glm::DVec3, vout: &glm::DVec3) -> Color {
+        self.surface()
+            .eval(&ctx.normal, &self.albedo(ctx), vin, vout)
+    }
+
+    /// Sample the direction `vin` light arrives from, for light leaving
+    /// along `vout`. Returns the direction and its pdf.
+    pub fn sample(
         &self,
         normal: &glm::DVec3,
-        vin: &glm::DVec3,
-        rng: &mut ThreadRng,
-    ) -> glm::DVec3 {
-        // TODO:
-        vin - (2.0 * normal) * (normal.dot(vin))
+        vout: &glm::DVec3,
+        rng: &mut impl Rng,
+    ) -> (glm::DVec3, f64) {
+        self.surface().sample(normal, vout, rng)
+    }
+
+    /// Density, with respect to solid angle, of `sample` returning `vin`
+    pub fn pdf(&self, normal: &glm::DVec3, vout: &glm::DVec3, vin: &glm::DVec3) -> f64 {
+        self.surface().pdf(normal, vout, vin)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use approx::assert_relative_eq;
+    use rand::SeedableRng;
+
     use super::*;
+
+    #[test]
+    fn textures_blur_with_the_footprint() {
+        let dir = std::env::temp_dir().join(format!("light-footprint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stripes.png");
+        image::RgbImage::from_fn(64, 64, |x, _| image::Rgb([255 * (x % 2 == 0) as u8; 3]))
+            .save(&path)
+            .unwrap();
+        let cache = Arc::new(crate::texture::TextureCache::with_directory(
+            1 << 20,
+            dir.join("cache"),
+        ));
+        let material = Material {
+            texture: Some(Texture::new(cache.clone(), cache.add(&path).unwrap())),
+            ..Default::default()
+        };
+        let record = HitRecord {
+            uv: glm::DVec2::new(0.5 / 64.0, 0.5),
+            ..Default::default()
+        };
+
+        // Up close, a single stripe. From afar, the stripes average out.
+        let near = ShadingContext::new(&record, 0);
+        assert_eq!(Color::repeat(255.0), material.albedo(&near));
+        let far = near.with_footprint(4.0 / 64.0);
+        assert_relative_eq!(Color::repeat(127.5), material.albedo(&far), epsilon = 1.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn procedural_albedo_overrides_color() {
+        let material = Material {
+            color: Color::new(1.0, 2.0, 3.0),
+            procedural: Some(Arc::new(ColorExpr::parse("y * 10", "id", "u").unwrap())),
+            ..Default::default()
+        };
+        let ctx = ShadingContext {
+            position: glm::DVec3::new(0.0, 2.0, 0.0),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::new(0.5, 0.0),
+            object_id: 3,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        };
+
+        assert_eq!(Color::new(20.0, 3.0, 0.5), material.albedo(&ctx));
+        assert_eq!(
+            Color::new(1.0, 2.0, 3.0),
+            Material {
+                color: Color::new(1.0, 2.0, 3.0),
+                ..Default::default()
+            }
+            .albedo(&ctx)
+        );
+    }
+
+    #[test]
+    fn water_tint_at_depth() {
+        let tint = Color::new(50.0, 180.0, 220.0);
+        let water = Material::water(tint, 2.0);
+        assert_eq!(
+            Some(Specular::Dielectric { ior: WATER_IOR }),
+            water.specular()
+        );
+
+        let at_depth = 255.0 * Material::transmittance(&water.absorption, 2.0);
+        assert_relative_eq!(tint, at_depth, epsilon = 1e-9);
+        assert_eq!(
+            Color::new(1.0, 1.0, 1.0),
+            Material::transmittance(&Color::zeros(), f64::INFINITY)
+        );
+    }
+
+    #[test]
+    fn lambertian_bounces() {
+        let material = Material {
+            color: Color::new(255.0, 51.0, 0.0),
+            ..Default::default()
+        };
+        let ctx = ShadingContext {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+            uv: glm::DVec2::zeros(),
+            object_id: 0,
+            view: -glm::DVec3::y(),
+            footprint: 0.0,
+        };
+        let mut rng = rand::thread_rng();
+
+        // Bounces stay on the side of the viewer, whichever way the normal
+        // points, and come with their pdf
+        for vout in [
+            glm::DVec3::new(0.6, 0.8, 0.0),
+            glm::DVec3::new(0.0, -0.6, 0.8),
+        ] {
+            for _ in 0..100 {
+                let (vin, pdf) = material.sample(&ctx.normal, &vout, &mut rng);
+                assert!(vin.y * vout.y > 0.0);
+                assert_relative_eq!(1.0, vin.norm(), epsilon = 1e-12);
+                assert_relative_eq!(material.pdf(&ctx.normal, &vout, &vin), pdf, epsilon = 1e-12);
+
+                // The estimator weight is the albedo
+                let weight = material.eval(&ctx, &vin, &vout) * vin.y.abs() / pdf;
+                assert_relative_eq!(Color::new(1.0, 0.2, 0.0), weight, epsilon = 1e-9);
+            }
+        }
+
+        // No light goes through
+        let below = glm::DVec3::new(0.0, -1.0, 0.0);
+        assert_eq!(
+            Color::zeros(),
+            material.eval(&ctx, &below, &glm::DVec3::y())
+        );
+    }
+
+    #[test]
+    fn specular_mirror() {
+        let normal = glm::DVec3::y();
+        let direction = glm::DVec3::new(1.0, -1.0, 0.0).normalize();
+        let expected = glm::DVec3::new(1.0, 1.0, 0.0).normalize();
+
+        assert_eq!(
+            (expected, false),
+            Specular::Mirror.deflect(&normal, &direction, false, glm::DVec2::repeat(0.5))
+        );
+        // The orientation of the normal doesn't matter
+        assert_eq!(
+            (expected, false),
+            Specular::Mirror.deflect(&-normal, &direction, false, glm::DVec2::repeat(0.5))
+        );
+
+        // Sampled as a spike, which rough surfaces don't have
+        let mirror = Material::metal(Color::repeat(255.0), 0.0);
+        let mut rng = rand::thread_rng();
+        let (vin, pdf) = mirror.sample(&normal, &-direction, &mut rng);
+        assert_relative_eq!(expected, vin);
+        assert_eq!(f64::INFINITY, pdf);
+        assert_eq!(0.0, mirror.pdf(&normal, &-direction, &vin));
+        assert!(Material::default()
+            .surface()
+            .scatter(&normal, &direction, false, &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn specular_refraction() {
+        let glass = Specular::Dielectric { ior: 1.5 };
+        let normal = glm::DVec3::y();
+        let theta_i = 30.0_f64.to_radians();
+        let direction = glm::DVec3::new(theta_i.sin(), -theta_i.cos(), 0.0);
+
+        // Snell's law
+        let (refracted, transmitted) =
+            glass.deflect(&normal, &direction, false, glm::DVec2::repeat(0.99));
+        assert!(transmitted);
+        assert_relative_eq!(theta_i.sin() / 1.5, refracted.x, epsilon = 1e-12);
+        assert!(refracted.y < 0.0);
+
+        // Leaving the medium, the path is reversible
+        let (back, transmitted) =
+            glass.deflect(&normal, &-refracted, true, glm::DVec2::repeat(0.99));
+        assert!(transmitted);
+        assert_relative_eq!(-direction, back, epsilon = 1e-12);
+
+        // Total internal reflection beyond the critical angle
+        let grazing = glm::DVec3::new(0.9, 0.1, 0.0).normalize();
+        let (reflected, transmitted) =
+            glass.deflect(&normal, &grazing, true, glm::DVec2::repeat(0.99));
+        assert!(!transmitted);
+        assert_relative_eq!(glm::DVec3::new(0.9, -0.1, 0.0).normalize(), reflected);
+
+        // Low random numbers pick the Fresnel reflection
+        assert!(
+            !glass
+                .deflect(&normal, &direction, false, glm::DVec2::zeros())
+                .1
+        );
+    }
+
+    #[test]
+    fn rough_metal() {
+        let normal = glm::DVec3::y();
+        let direction = glm::DVec3::new(1.0, -1.0, 0.0).normalize();
+        let mirrored = glm::DVec3::new(1.0, 1.0, 0.0).normalize();
+
+        // Smooth metal is a mirror
+        let smooth = Specular::Metal { roughness: 0.0 };
+        let (reflected, transmitted) =
+            smooth.deflect(&normal, &direction, false, glm::DVec2::new(0.3, 0.7));
+        assert!(!transmitted);
+        assert_relative_eq!(mirrored, reflected, epsilon = 1e-12);
+
+        // Rougher metals spread the reflections further from the mirror
+        // direction, always above the surface
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        let samples: Vec<glm::DVec2> = (0..1000)
+            .map(|_| glm::DVec2::new(rng.gen(), rng.gen()))
+            .collect();
+        let spread = |roughness: f64| {
+            let metal = Specular::Metal { roughness };
+            samples
+                .iter()
+                .map(|u| {
+                    let (reflected, _) = metal.deflect(&normal, &direction, false, *u);
+                    assert!(reflected.y >= 0.0);
+                    assert_relative_eq!(1.0, reflected.norm(), epsilon = 1e-12);
+                    1.0 - reflected.dot(&mirrored)
+                })
+                .sum::<f64>()
+                / samples.len() as f64
+        };
+        let (brushed, rough) = (spread(0.3), spread(0.8));
+        assert!(0.0 < brushed && brushed < rough);
+    }
 }
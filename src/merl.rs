@@ -0,0 +1,308 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::f64::consts::{FRAC_PI_2, PI};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use rand::Rng;
+
+use crate::color::Color;
+use crate::material::{facing, Bsdf};
+use crate::sampling;
+
+const THETA_HALF_RES: usize = 90;
+const THETA_DIFF_RES: usize = 90;
+const PHI_DIFF_RES: usize = 180;
+/// Entries of each color channel of a MERL table
+pub const NUM_SAMPLES: usize = THETA_HALF_RES * THETA_DIFF_RES * PHI_DIFF_RES;
+
+/// Channel scale factors of the MERL database
+const CHANNEL_SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+/// Isotropic BRDF measured by Matusik et al. (MERL 100 database), stored in
+/// the Rusinkiewicz half/difference angle parametrization
+pub struct MerlBrdf {
+    samples: Vec<f64>, // Three consecutive channel blocks (r, g, b)
+}
+
+impl fmt::Debug for MerlBrdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MerlBrdf")
+            .field("theta_half", &THETA_HALF_RES)
+            .field("theta_diff", &THETA_DIFF_RES)
+            .field("phi_diff", &PHI_DIFF_RES)
+            .finish()
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl MerlBrdf {
+    /// Load a `.binary` file from the MERL database
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut dims = [0_i32; 3];
+        let mut word = [0_u8; 4];
+        for dim in dims.iter_mut() {
+            reader.read_exact(&mut word)?;
+            *dim = i32::from_le_bytes(word);
+        }
+
+        if dims
+            != [
+                THETA_HALF_RES as i32,
+                THETA_DIFF_RES as i32,
+                PHI_DIFF_RES as i32,
+            ]
+        {
+            return Err(invalid_data("Unexpected MERL BRDF dimensions"));
+        }
+
+        let mut bytes = vec![0_u8; 3 * NUM_SAMPLES * 8];
+        reader.read_exact(&mut bytes)?;
+        let samples = bytes
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Self::from_samples(samples)
+    }
+
+    /// Build from raw samples, laid out as in the MERL binary format
+    pub fn from_samples(samples: Vec<f64>) -> io::Result<Self> {
+        if samples.len() != 3 * NUM_SAMPLES {
+            return Err(invalid_data("Wrong number of MERL BRDF samples"));
+        }
+        Ok(Self { samples })
+    }
+
+    /// Memory used by the samples, in bytes
+    pub fn memory_size(&self) -> usize {
+        self.samples.len() * std::mem::size_of::<f64>()
+    }
+
+    fn sample(&self, channel: usize, theta_half: usize, theta_diff: usize, phi_diff: usize) -> f64 {
+        let index = phi_diff + PHI_DIFF_RES * (theta_diff + THETA_DIFF_RES * theta_half);
+        // Negative values mark missing measurements
+        self.samples[channel * NUM_SAMPLES + index].max(0.0) * CHANNEL_SCALE[channel]
+    }
+
+    /// Evaluate the BRDF for local directions (z being the surface normal)
+    pub fn eval_local(&self, wi: &glm::DVec3, wo: &glm::DVec3) -> Color {
+        if wi.z <= 0.0 || wo.z <= 0.0 {
+            return Color::zeros();
+        }
+
+        let (theta_half, theta_diff, phi_diff) = half_diff_angles(wi, wo);
+
+        // Continuous table coordinates. The theta_half axis is non-linear
+        // to concentrate samples around the specular peak.
+        let x = ((theta_half / FRAC_PI_2).max(0.0).sqrt() * THETA_HALF_RES as f64 - 0.5)
+            .clamp(0.0, (THETA_HALF_RES - 1) as f64);
+        let y = (theta_diff / FRAC_PI_2 * THETA_DIFF_RES as f64 - 0.5)
+            .clamp(0.0, (THETA_DIFF_RES - 1) as f64);
+        let z = (phi_diff / PI * PHI_DIFF_RES as f64 - 0.5).clamp(0.0, (PHI_DIFF_RES - 1) as f64);
+
+        let (x0, y0, z0) = (x as usize, y as usize, z as usize);
+        let (x1, y1, z1) = (
+            (x0 + 1).min(THETA_HALF_RES - 1),
+            (y0 + 1).min(THETA_DIFF_RES - 1),
+            (z0 + 1).min(PHI_DIFF_RES - 1),
+        );
+        let (tx, ty, tz) = (x - x0 as f64, y - y0 as f64, z - z0 as f64);
+
+        // Trilinear interpolation
+        let mut color = Color::zeros();
+        for channel in 0..3 {
+            let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+            let c00 = lerp(
+                self.sample(channel, x0, y0, z0),
+                self.sample(channel, x1, y0, z0),
+                tx,
+            );
+            let c10 = lerp(
+                self.sample(channel, x0, y1, z0),
+                self.sample(channel, x1, y1, z0),
+                tx,
+            );
+            let c01 = lerp(
+                self.sample(channel, x0, y0, z1),
+                self.sample(channel, x1, y0, z1),
+                tx,
+            );
+            let c11 = lerp(
+                self.sample(channel, x0, y1, z1),
+                self.sample(channel, x1, y1, z1),
+                tx,
+            );
+            color[channel] = lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz);
+        }
+
+        color
+    }
+
+    /// Evaluate the BRDF for world directions around `normal`
+    pub fn eval(&self, normal: &glm::DVec3, wi: &glm::DVec3, wo: &glm::DVec3) -> Color {
+        self.eval_local(
+            &sampling::to_local(normal, wi),
+            &sampling::to_local(normal, wo),
+        )
+    }
+
+    /// Sample an incident direction for outgoing direction `wo`. Returns the
+    /// direction and its pdf. Directions are drawn from a cosine-weighted
+    /// hemisphere, which is unbiased for any measured lobe.
+    pub fn sample_direction(
+        &self,
+        normal: &glm::DVec3,
+        _wo: &glm::DVec3,
+        rng: &mut impl Rng,
+    ) -> (glm::DVec3, f64) {
+        let (local, pdf) = sampling::cosine_hemisphere(rng);
+        (sampling::to_world(normal, &local), pdf)
+    }
+}
+
+/// Measured BRDFs ignore the color and the texture of the material: the
+/// table already holds the absolute reflectance of the measured sample, and
+/// scaling it by an albedo would no longer match the measurement. Both
+/// sides of a surface reflect like the measured one, as the normal is
+/// turned towards `vout` like for the other models.
+impl Bsdf for MerlBrdf {
+    fn eval(
+        &self,
+        normal: &glm::DVec3,
+        _albedo: &Color,
+        vin: &glm::DVec3,
+        vout: &glm::DVec3,
+    ) -> Color {
+        MerlBrdf::eval(self, &facing(normal, vout), vin, vout)
+    }
+
+    fn sample(
+        &self,
+        normal: &glm::DVec3,
+        vout: &glm::DVec3,
+        mut rng: &mut dyn rand::RngCore,
+    ) -> (glm::DVec3, f64) {
+        self.sample_direction(&facing(normal, vout), vout, &mut rng)
+    }
+
+    fn pdf(&self, normal: &glm::DVec3, vout: &glm::DVec3, vin: &glm::DVec3) -> f64 {
+        facing(normal, vout).dot(vin).max(0.0) / PI
+    }
+
+    fn kind(&self) -> &'static str {
+        "measured"
+    }
+
+    fn memory_size(&self) -> usize {
+        MerlBrdf::memory_size(self)
+    }
+}
+
+/// Convert a pair of local directions to the Rusinkiewicz angles
+/// (theta_half, theta_diff, phi_diff). Reciprocity folds phi_diff to [0, pi).
+fn half_diff_angles(wi: &glm::DVec3, wo: &glm::DVec3) -> (f64, f64, f64) {
+    let half = (wi + wo).normalize();
+    let theta_half = half.z.clamp(-1.0, 1.0).acos();
+    let phi_half = half.y.atan2(half.x);
+
+    // Rotate wi so that the half vector becomes the z axis
+    let diff = glm::rotate_vec3(
+        &glm::rotate_vec3(wi, -phi_half, &glm::DVec3::z()),
+        -theta_half,
+        &glm::DVec3::y(),
+    );
+    let theta_diff = diff.z.clamp(-1.0, 1.0).acos();
+    let phi_diff = diff.y.atan2(diff.x).rem_euclid(PI);
+
+    (theta_half, theta_diff, phi_diff)
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn half_diff_of_mirror_directions() {
+        let wi = glm::DVec3::new(0.5, 0.0, 1.0).normalize();
+        let wo = glm::DVec3::new(-0.5, 0.0, 1.0).normalize();
+        let (theta_half, theta_diff, _) = half_diff_angles(&wi, &wo);
+
+        assert_relative_eq!(0.0, theta_half, epsilon = 1e-12);
+        assert_relative_eq!(wi.z.acos(), theta_diff, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn constant_brdf() {
+        let brdf = MerlBrdf::from_samples(vec![1500.0; 3 * NUM_SAMPLES]).unwrap();
+        let normal = glm::DVec3::y();
+        let wi = glm::DVec3::new(0.3, 0.8, 0.1).normalize();
+        let wo = glm::DVec3::new(-0.6, 0.5, 0.2).normalize();
+
+        assert_relative_eq!(
+            Color::new(1.0, 1.15, 1.66),
+            brdf.eval(&normal, &wi, &wo),
+            epsilon = 1e-12
+        );
+        assert_eq!(Color::zeros(), brdf.eval(&normal, &-wi, &wo));
+    }
+
+    #[test]
+    fn seen_from_below() {
+        let brdf = MerlBrdf::from_samples(vec![1500.0; 3 * NUM_SAMPLES]).unwrap();
+        let bsdf: &dyn Bsdf = &brdf;
+        let normal = glm::DVec3::y();
+        let vout = glm::DVec3::new(0.2, -0.9, 0.1).normalize();
+        let albedo = Color::zeros();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..16 {
+            let (vin, pdf) = bsdf.sample(&normal, &vout, &mut rng);
+            assert!(vin.y < 0.0);
+            assert_relative_eq!(pdf, bsdf.pdf(&normal, &vout, &vin), epsilon = 1e-12);
+            assert_relative_eq!(
+                Color::new(1.0, 1.15, 1.66),
+                bsdf.eval(&normal, &albedo, &vin, &vout),
+                epsilon = 1e-12
+            );
+        }
+    }
+
+    #[test]
+    fn reject_wrong_dimensions() {
+        let mut bytes = Vec::new();
+        for dim in [90_i32, 90, 360] {
+            bytes.extend_from_slice(&dim.to_le_bytes());
+        }
+        assert!(MerlBrdf::from_reader(bytes.as_slice()).is_err());
+        assert!(MerlBrdf::from_samples(vec![0.0; 10]).is_err());
+    }
+}
@@ -0,0 +1,529 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Error metrics between a reference image and a test image: PSNR, SSIM and
+//! FLIP, the perceptual difference metric of Andersson et al. (2020).
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use image::{Rgb, RgbImage};
+
+const PEAK: f64 = 255.0;
+
+// SSIM, Wang et al. (2004)
+const SSIM_SIGMA: f64 = 1.5;
+const SSIM_C1: f64 = (0.01 * PEAK) * (0.01 * PEAK);
+const SSIM_C2: f64 = (0.03 * PEAK) * (0.03 * PEAK);
+
+// FLIP
+/// Pixels per degree of visual angle of a 0.7 m wide 4K monitor seen from
+/// 0.7 m
+pub const DEFAULT_PIXELS_PER_DEGREE: f64 = 67.0;
+const FLIP_QC: f64 = 0.7;
+const FLIP_QF: f64 = 0.5;
+const FLIP_PC: f64 = 0.4;
+const FLIP_PT: f64 = 0.95;
+const FEATURE_WIDTH: f64 = 0.082; // Degrees
+/// Contrast sensitivity of the achromatic, red-green and blue-yellow
+/// channels, as sums of two Gaussians with parameters (a, b)
+const CSF: [[(f64, f64); 2]; 3] = [
+    [(1.0, 0.0047), (0.0, 1e-5)],
+    [(1.0, 0.0053), (0.0, 1e-5)],
+    [(34.1, 0.04), (13.5, 0.025)],
+];
+const WHITE: [f64; 3] = [0.950456, 1.0, 1.088754]; // D65, XYZ
+
+/// Per-pixel error in [0, 1]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorMap {
+    width: u32,
+    height: u32,
+    values: Vec<f64>,
+}
+
+impl ErrorMap {
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn value(&self, i: u32, j: u32) -> f64 {
+        self.values[(j * self.width + i) as usize]
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.values.iter().sum::<f64>() / self.values.len().max(1) as f64
+    }
+
+    /// Error image going from black (no error) through red and yellow to
+    /// white (maximum error)
+    pub fn heatmap(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |i, j| heat_color(self.value(i, j)))
+    }
+}
+
+/// Color of a value in [0, 1] in the black, red, yellow and white heatmaps
+pub fn heat_color(value: f64) -> Rgb<u8> {
+    let t = 3.0 * value.clamp(0.0, 1.0);
+    let channel = |start: f64| (255.0 * (t - start).clamp(0.0, 1.0)).round() as u8;
+    Rgb([channel(0.0), channel(1.0), channel(2.0)])
+}
+
+/// Error metrics of a test image against a reference
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub psnr: f64, // dB, infinite for identical images
+    pub ssim: f64, // 1 for identical images
+    pub flip: f64, // Mean of the FLIP map, 0 for identical images
+    pub flip_map: ErrorMap,
+}
+
+impl Comparison {
+    pub fn to_json(&self) -> serde_json::Value {
+        // JSON has no infinity
+        let psnr = self.psnr.is_finite().then_some(self.psnr);
+        serde_json::json!({
+            "psnr": psnr,
+            "ssim": self.ssim,
+            "flip": self.flip,
+        })
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "PSNR: {:.2} dB", self.psnr)?;
+        writeln!(f, "SSIM: {:.4}", self.ssim)?;
+        write!(f, "FLIP: {:.4}", self.flip)
+    }
+}
+
+/// Compare a test image with a reference using all the metrics
+pub fn compare(reference: &RgbImage, test: &RgbImage) -> Result<Comparison, String> {
+    let flip_map = flip(reference, test, DEFAULT_PIXELS_PER_DEGREE)?;
+    Ok(Comparison {
+        psnr: psnr(reference, test)?,
+        ssim: ssim(reference, test)?,
+        flip: flip_map.mean(),
+        flip_map,
+    })
+}
+
+fn check_dimensions(reference: &RgbImage, test: &RgbImage) -> Result<(), String> {
+    if reference.dimensions() != test.dimensions() {
+        return Err(format!(
+            "The images have different sizes: {:?} and {:?}",
+            reference.dimensions(),
+            test.dimensions()
+        ));
+    }
+    Ok(())
+}
+
+/// Peak signal to noise ratio, in dB
+pub fn psnr(reference: &RgbImage, test: &RgbImage) -> Result<f64, String> {
+    check_dimensions(reference, test)?;
+    let samples = reference.as_raw().len().max(1) as f64;
+    let mse = reference
+        .as_raw()
+        .iter()
+        .zip(test.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum::<f64>()
+        / samples;
+    Ok(10.0 * (PEAK * PEAK / mse).log10())
+}
+
+/// Mean structural similarity of the luminance, with a Gaussian window
+pub fn ssim(reference: &RgbImage, test: &RgbImage) -> Result<f64, String> {
+    check_dimensions(reference, test)?;
+    let (w, h) = reference.dimensions();
+    let luma = |image: &RgbImage| -> Vec<f64> {
+        image
+            .pixels()
+            .map(|p| 0.2126 * p[0] as f64 + 0.7152 * p[1] as f64 + 0.0722 * p[2] as f64)
+            .collect()
+    };
+    let (a, b) = (luma(reference), luma(test));
+    let kernel = normalized(gaussian(SSIM_SIGMA, (3.0 * SSIM_SIGMA).ceil() as i32));
+    let blur = |plane: &[f64]| convolve2(plane, w, h, &kernel, &kernel);
+    let product =
+        |x: &[f64], y: &[f64]| -> Vec<f64> { x.iter().zip(y).map(|(x, y)| x * y).collect() };
+
+    let (mu_a, mu_b) = (blur(&a), blur(&b));
+    let (aa, bb, ab) = (
+        blur(&product(&a, &a)),
+        blur(&product(&b, &b)),
+        blur(&product(&a, &b)),
+    );
+    let total: f64 = (0..a.len())
+        .map(|n| {
+            let var_a = aa[n] - mu_a[n] * mu_a[n];
+            let var_b = bb[n] - mu_b[n] * mu_b[n];
+            let covar = ab[n] - mu_a[n] * mu_b[n];
+            (2.0 * mu_a[n] * mu_b[n] + SSIM_C1) * (2.0 * covar + SSIM_C2)
+                / ((mu_a[n] * mu_a[n] + mu_b[n] * mu_b[n] + SSIM_C1) * (var_a + var_b + SSIM_C2))
+        })
+        .sum();
+    Ok(total / a.len().max(1) as f64)
+}
+
+/// LDR-FLIP error map for an observer that sees `pixels_per_degree` pixels
+/// per degree of visual angle
+pub fn flip(
+    reference: &RgbImage,
+    test: &RgbImage,
+    pixels_per_degree: f64,
+) -> Result<ErrorMap, String> {
+    check_dimensions(reference, test)?;
+    let (w, h) = reference.dimensions();
+    let (reference, test) = (Opponent::new(reference), Opponent::new(test));
+
+    let color = color_difference(&reference, &test, w, h, pixels_per_degree);
+    let feature = feature_difference(&reference, &test, w, h, pixels_per_degree);
+    let values = color
+        .iter()
+        .zip(&feature)
+        .map(|(color, feature)| color.powf(1.0 - feature))
+        .collect();
+
+    Ok(ErrorMap {
+        width: w,
+        height: h,
+        values,
+    })
+}
+
+/// Image in the YCxCz opponent color space, one plane per channel
+struct Opponent {
+    planes: [Vec<f64>; 3],
+}
+
+impl Opponent {
+    fn new(image: &RgbImage) -> Self {
+        let mut planes: [Vec<f64>; 3] = Default::default();
+        for pixel in image.pixels() {
+            let linear = pixel.0.map(|c| srgb_to_linear(c as f64 / 255.0));
+            let ycxcz = xyz_to_ycxcz(linear_to_xyz(linear));
+            for c in 0..3 {
+                planes[c].push(ycxcz[c]);
+            }
+        }
+        Self { planes }
+    }
+
+    /// Normalized luminance, in [0, 1]
+    fn luminance(&self) -> Vec<f64> {
+        self.planes[0].iter().map(|y| (y + 16.0) / 116.0).collect()
+    }
+}
+
+/// Color error after filtering both images with the contrast sensitivity of
+/// the eye
+fn color_difference(
+    reference: &Opponent,
+    test: &Opponent,
+    w: u32,
+    h: u32,
+    pixels_per_degree: f64,
+) -> Vec<f64> {
+    let filtered_lab = |image: &Opponent| -> Vec<[f64; 3]> {
+        let filtered: Vec<Vec<f64>> = (0..3)
+            .map(|c| csf_filter(&image.planes[c], w, h, &CSF[c], pixels_per_degree))
+            .collect();
+        (0..filtered[0].len())
+            .map(|n| {
+                let ycxcz = [filtered[0][n], filtered[1][n], filtered[2][n]];
+                let linear = xyz_to_linear(ycxcz_to_xyz(ycxcz)).map(|c| c.clamp(0.0, 1.0));
+                hunt(xyz_to_lab(linear_to_xyz(linear)))
+            })
+            .collect()
+    };
+
+    let green = hunt(xyz_to_lab(linear_to_xyz([0.0, 1.0, 0.0])));
+    let blue = hunt(xyz_to_lab(linear_to_xyz([0.0, 0.0, 1.0])));
+    let max = hyab(&green, &blue).powf(FLIP_QC);
+
+    filtered_lab(reference)
+        .iter()
+        .zip(&filtered_lab(test))
+        .map(|(a, b)| {
+            // Compress the large errors, which are all equally noticeable
+            let error = hyab(a, b).powf(FLIP_QC);
+            if error < FLIP_PC * max {
+                error * FLIP_PT / (FLIP_PC * max)
+            } else {
+                FLIP_PT + (error - FLIP_PC * max) / (max - FLIP_PC * max) * (1.0 - FLIP_PT)
+            }
+        })
+        .collect()
+}
+
+/// Difference of the edges and points of the luminance
+fn feature_difference(
+    reference: &Opponent,
+    test: &Opponent,
+    w: u32,
+    h: u32,
+    pixels_per_degree: f64,
+) -> Vec<f64> {
+    let sigma = 0.5 * FEATURE_WIDTH * pixels_per_degree;
+    let radius = (3.0 * sigma).ceil() as i32;
+    let g = gaussian(sigma, radius);
+    let smooth = normalized(g.clone());
+    let edge = balanced(
+        g.iter()
+            .zip(-radius..=radius)
+            .map(|(g, x)| -(x as f64) * g)
+            .collect(),
+    );
+    let point = balanced(
+        g.iter()
+            .zip(-radius..=radius)
+            .map(|(g, x)| ((x * x) as f64 / (sigma * sigma) - 1.0) * g)
+            .collect(),
+    );
+
+    let features = |image: &Opponent| -> (Vec<f64>, Vec<f64>) {
+        let luminance = image.luminance();
+        let magnitude = |kernel: &[f64]| -> Vec<f64> {
+            let x = convolve2(&luminance, w, h, kernel, &smooth);
+            let y = convolve2(&luminance, w, h, &smooth, kernel);
+            x.iter().zip(&y).map(|(x, y)| x.hypot(*y)).collect()
+        };
+        (magnitude(&edge), magnitude(&point))
+    };
+
+    let (reference_edges, reference_points) = features(reference);
+    let (test_edges, test_points) = features(test);
+    (0..reference_edges.len())
+        .map(|n| {
+            let edges = (reference_edges[n] - test_edges[n]).abs();
+            let points = (reference_points[n] - test_points[n]).abs();
+            (edges.max(points) / 2.0_f64.sqrt()).powf(FLIP_QF)
+        })
+        .collect()
+}
+
+/// Filter a plane with a contrast sensitivity function made of two Gaussians
+fn csf_filter(
+    plane: &[f64],
+    w: u32,
+    h: u32,
+    csf: &[(f64, f64); 2],
+    pixels_per_degree: f64,
+) -> Vec<f64> {
+    let max_b = csf.iter().map(|&(_, b)| b).fold(0.0, f64::max);
+    let radius = (3.0 * (max_b / (2.0 * PI * PI)).sqrt() * pixels_per_degree).ceil() as i32;
+
+    let mut result = vec![0.0; plane.len()];
+    let mut total = 0.0;
+    for &(a, b) in csf.iter().filter(|(a, _)| *a > 0.0) {
+        let kernel: Vec<f64> = (-radius..=radius)
+            .map(|x| {
+                let degrees = x as f64 / pixels_per_degree;
+                (-PI * PI * degrees * degrees / b).exp()
+            })
+            .collect();
+        // 2D weight of the Gaussian, which is separable
+        let weight = a * (PI / b).sqrt();
+        total += weight * kernel.iter().sum::<f64>().powi(2);
+        for (result, value) in result
+            .iter_mut()
+            .zip(convolve2(plane, w, h, &kernel, &kernel))
+        {
+            *result += weight * value;
+        }
+    }
+    result.iter().map(|value| value / total).collect()
+}
+
+/// Samples of an unnormalized Gaussian, from -radius to radius
+fn gaussian(sigma: f64, radius: i32) -> Vec<f64> {
+    (-radius..=radius)
+        .map(|x| (-((x * x) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect()
+}
+
+fn normalized(kernel: Vec<f64>) -> Vec<f64> {
+    let sum: f64 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+/// Scale the positive weights to sum 1 and the negative ones to sum -1
+fn balanced(kernel: Vec<f64>) -> Vec<f64> {
+    let positive: f64 = kernel.iter().filter(|k| **k > 0.0).sum();
+    let negative: f64 = -kernel.iter().filter(|k| **k < 0.0).sum::<f64>();
+    kernel
+        .iter()
+        .map(|&k| if k > 0.0 { k / positive } else { k / negative })
+        .collect()
+}
+
+/// Separable convolution with a horizontal and a vertical kernel, repeating
+/// the edge pixels
+fn convolve2(plane: &[f64], w: u32, h: u32, horizontal: &[f64], vertical: &[f64]) -> Vec<f64> {
+    let (w, h) = (w as i32, h as i32);
+    let pass = |input: &[f64], kernel: &[f64], dx: i32, dy: i32| -> Vec<f64> {
+        let radius = (kernel.len() / 2) as i32;
+        (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                (-radius..=radius)
+                    .zip(kernel)
+                    .map(|(k, weight)| {
+                        let sx = (x + k * dx).clamp(0, w - 1);
+                        let sy = (y + k * dy).clamp(0, h - 1);
+                        weight * input[(sy * w + sx) as usize]
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    pass(&pass(plane, horizontal, 1, 0), vertical, 0, 1)
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_xyz([r, g, b]: [f64; 3]) -> [f64; 3] {
+    [
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        0.0193339 * r + 0.1191920 * g + 0.9503041 * b,
+    ]
+}
+
+fn xyz_to_linear([x, y, z]: [f64; 3]) -> [f64; 3] {
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+}
+
+fn xyz_to_ycxcz([x, y, z]: [f64; 3]) -> [f64; 3] {
+    let [x, y, z] = [x / WHITE[0], y / WHITE[1], z / WHITE[2]];
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+fn ycxcz_to_xyz([yc, cx, cz]: [f64; 3]) -> [f64; 3] {
+    let y = (yc + 16.0) / 116.0;
+    [
+        (cx / 500.0 + y) * WHITE[0],
+        y * WHITE[1],
+        (y - cz / 200.0) * WHITE[2],
+    ]
+}
+
+fn xyz_to_lab([x, y, z]: [f64; 3]) -> [f64; 3] {
+    let delta: f64 = 6.0 / 29.0;
+    let f = |t: f64| {
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+    let [fx, fy, fz] = [f(x / WHITE[0]), f(y / WHITE[1]), f(z / WHITE[2])];
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Hunt effect: colors look less saturated when they are darker
+fn hunt([l, a, b]: [f64; 3]) -> [f64; 3] {
+    [l, 0.01 * l * a, 0.01 * l * b]
+}
+
+/// Color distance of the HyAB metric, better for large differences than the
+/// Euclidean distance
+fn hyab(x: &[f64; 3], y: &[f64; 3]) -> f64 {
+    (x[0] - y[0]).abs() + (x[1] - y[1]).hypot(x[2] - y[2])
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(32, 24, |i, j| Rgb([(8 * i) as u8, (10 * j) as u8, 128]))
+    }
+
+    fn noisy(image: &RgbImage, amplitude: i32) -> RgbImage {
+        let mut noisy = image.clone();
+        for (n, value) in noisy.iter_mut().enumerate() {
+            let offset = if n % 7 < 3 { amplitude } else { -amplitude };
+            *value = (*value as i32 + offset).clamp(0, 255) as u8;
+        }
+        noisy
+    }
+
+    #[test]
+    fn identical_images() {
+        let image = gradient();
+        let comparison = compare(&image, &image).unwrap();
+        assert_eq!(f64::INFINITY, comparison.psnr);
+        assert_relative_eq!(1.0, comparison.ssim, epsilon = 1e-9);
+        assert_relative_eq!(0.0, comparison.flip, epsilon = 1e-9);
+        assert_eq!(
+            Rgb([0, 0, 0]),
+            *comparison.flip_map.heatmap().get_pixel(5, 5)
+        );
+        assert_eq!(serde_json::Value::Null, comparison.to_json()["psnr"]);
+
+        assert!(compare(&image, &RgbImage::new(2, 2)).is_err());
+    }
+
+    #[test]
+    fn known_psnr() {
+        let black = RgbImage::new(4, 4);
+        let gray = RgbImage::from_pixel(4, 4, Rgb([10, 10, 10]));
+        assert_relative_eq!(
+            10.0 * (255.0_f64 * 255.0 / 100.0).log10(),
+            psnr(&black, &gray).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors_grow_with_noise() {
+        let image = gradient();
+        let (small, large) = (noisy(&image, 4), noisy(&image, 40));
+        let small = compare(&image, &small).unwrap();
+        let large = compare(&image, &large).unwrap();
+
+        assert!(small.psnr > large.psnr);
+        assert!(small.ssim > large.ssim);
+        assert!(small.ssim < 1.0);
+        assert!(small.flip < large.flip);
+        assert!(small.flip > 0.0);
+
+        // Black against white is about the largest error
+        let black = RgbImage::new(32, 24);
+        let white = RgbImage::from_pixel(32, 24, Rgb([255, 255, 255]));
+        let flip = flip(&black, &white, DEFAULT_PIXELS_PER_DEGREE).unwrap();
+        assert!(flip.mean() > 0.9);
+        assert!(flip.mean() > large.flip);
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Wavefront MTL material libraries, the companions of OBJ files. The
+//! statements are mapped onto the materials of the crate as well as they
+//! fit: diffuse color and texture, mirrors, glass and emitters.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::color::Color;
+use crate::loader::confine;
+use crate::material::{Material, Specular};
+use crate::texture::{Texture, TextureCache};
+
+const DEFAULT_IOR: f64 = 1.5;
+
+/// Materials of an MTL file by name, and the problems found while loading
+/// them that didn't stop it, e.g. missing texture files
+#[derive(Debug, Default)]
+pub struct MtlLibrary {
+    pub materials: HashMap<String, Material>,
+    pub warnings: Vec<String>,
+}
+
+/// Statements of one `newmtl` block
+#[derive(Debug, Default)]
+struct MtlEntry {
+    diffuse: Option<Color>,      // Kd
+    specular: Option<Color>,     // Ks
+    emission: Option<Color>,     // Ke
+    transmission: Option<Color>, // Tf
+    dissolve: Option<f64>,       // d, or 1 - Tr
+    ior: Option<f64>,            // Ni
+    illum: Option<u32>,
+    diffuse_map: Option<String>, // map_Kd
+}
+
+impl MtlEntry {
+    fn to_material(&self) -> Material {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let mut material = Material {
+            color: 255.0 * self.diffuse.unwrap_or(white),
+            ..Default::default()
+        };
+
+        if let Some(emission) = self.emission.filter(|e| e.max() > 0.0) {
+            material.color = 255.0 * emission / emission.max();
+            material.emittance = emission.max();
+            return material;
+        }
+
+        // Illumination models 4, 6, 7 and 9 are transparent, 3 and 5 mirrors
+        let transparent =
+            matches!(self.illum, Some(4 | 6 | 7 | 9)) || self.dissolve.is_some_and(|d| d < 1.0);
+        if transparent {
+            material.bsdf = Some(Arc::new(Specular::Dielectric {
+                ior: self.ior.filter(|ior| *ior >= 1.0).unwrap_or(DEFAULT_IOR),
+            }));
+            material.color = 255.0 * self.transmission.unwrap_or(white);
+        } else if matches!(self.illum, Some(3 | 5)) {
+            material.bsdf = Some(Arc::new(Specular::Mirror));
+            material.color = 255.0 * self.specular.filter(|s| s.max() > 0.0).unwrap_or(white);
+        }
+        material
+    }
+}
+
+impl MtlLibrary {
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        root: Option<&Path>,
+        textures: &Arc<TextureCache>,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        Self::parse(
+            &source,
+            path.parent().unwrap_or(Path::new("")),
+            root,
+            textures,
+        )
+    }
+
+    /// Parse an MTL source. Texture paths are relative to `base_dir`, and
+    /// the ones outside of `root`, if given, are left out like missing ones.
+    pub fn parse(
+        source: &str,
+        base_dir: &Path,
+        root: Option<&Path>,
+        textures: &Arc<TextureCache>,
+    ) -> Result<Self, String> {
+        let mut entries: Vec<(String, MtlEntry)> = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut tokens = line.split_whitespace();
+            let Some(keyword) = tokens.next() else {
+                continue;
+            };
+            let args: Vec<&str> = tokens.collect();
+            let error = |e: String| format!("Line {}: {e}", number + 1);
+
+            if keyword == "newmtl" {
+                let name = args.join(" ");
+                if name.is_empty() {
+                    return Err(error("Missing material name".to_string()));
+                }
+                entries.push((name, MtlEntry::default()));
+                continue;
+            }
+            let Some((_, entry)) = entries.last_mut() else {
+                return Err(error(format!("'{keyword}' before any 'newmtl'")));
+            };
+            match keyword {
+                "Kd" => entry.diffuse = Some(parse_color(&args).map_err(error)?),
+                "Ks" => entry.specular = Some(parse_color(&args).map_err(error)?),
+                "Ke" => entry.emission = Some(parse_color(&args).map_err(error)?),
+                "Tf" => entry.transmission = Some(parse_color(&args).map_err(error)?),
+                "d" => entry.dissolve = Some(parse_number(&args).map_err(error)?),
+                "Tr" => entry.dissolve = Some(1.0 - parse_number(&args).map_err(error)?),
+                "Ni" => entry.ior = Some(parse_number(&args).map_err(error)?),
+                "illum" => entry.illum = Some(parse_number(&args).map_err(error)? as u32),
+                // Options like `-s 2 2 1` come before the file name
+                "map_Kd" => entry.diffuse_map = args.last().map(|file| file.replace('\\', "/")),
+                _ => {} // Ns, Ka, bump maps... have no counterpart
+            }
+        }
+
+        let mut library = Self::default();
+        for (name, entry) in entries {
+            let mut material = entry.to_material();
+            if let Some(file) = &entry.diffuse_map {
+                match confine(base_dir.join(file), root).and_then(|file| textures.add(file)) {
+                    Ok(id) => material.texture = Some(Texture::new(textures.clone(), id)),
+                    Err(e) => library
+                        .warnings
+                        .push(format!("Material '{name}' has no texture: {e}")),
+                }
+            }
+            library.materials.insert(name, material);
+        }
+        Ok(library)
+    }
+}
+
+fn parse_number(args: &[&str]) -> Result<f64, String> {
+    match args {
+        [value, ..] => value
+            .parse()
+            .map_err(|_| format!("Expected a number, found '{value}'")),
+        [] => Err("Missing value".to_string()),
+    }
+}
+
+/// Parse `r g b`, or a single value for gray
+fn parse_color(args: &[&str]) -> Result<Color, String> {
+    let values = args
+        .iter()
+        .take(3)
+        .map(|value| parse_number(&[value]))
+        .collect::<Result<Vec<_>, _>>()?;
+    match values.as_slice() {
+        [gray] => Ok(Color::repeat(*gray)),
+        [r, g, b] => Ok(Color::new(*r, *g, *b)),
+        _ => Err(format!("Expected a color, found '{}'", args.join(" "))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_materials() {
+        let source = r#"
+            # Exported by some tool
+            newmtl red paint
+            Ka 0 0 0
+            Kd 0.8 0.1 0.1
+            Ns 10
+
+            newmtl chrome
+            Ks 0.9 0.9 0.9
+            illum 3
+
+            newmtl glass
+            Tf 0.9 1 0.9
+            Ni 1.45
+            d 0.1
+
+            newmtl lamp
+            Kd 1 1 1
+            Ke 4 2 0
+        "#;
+        let library = MtlLibrary::parse(
+            source,
+            Path::new(""),
+            None,
+            &Arc::new(TextureCache::default()),
+        )
+        .unwrap();
+        assert_eq!(4, library.materials.len());
+
+        let red = &library.materials["red paint"];
+        assert_eq!(Color::new(204.0, 25.5, 25.5), red.color);
+        assert_eq!(None, red.specular());
+
+        let chrome = &library.materials["chrome"];
+        assert_eq!(Some(Specular::Mirror), chrome.specular());
+        assert_eq!(Color::repeat(0.9 * 255.0), chrome.color);
+
+        let glass = &library.materials["glass"];
+        assert_eq!(Some(Specular::Dielectric { ior: 1.45 }), glass.specular());
+        assert_eq!(Color::new(229.5, 255.0, 229.5), glass.color);
+
+        let lamp = &library.materials["lamp"];
+        assert_eq!(4.0, lamp.emittance);
+        assert_eq!(Color::new(255.0, 127.5, 0.0), lamp.color);
+
+        let textures = Arc::new(TextureCache::default());
+        assert!(MtlLibrary::parse("Kd 1 1 1", Path::new(""), None, &textures).is_err());
+        assert!(MtlLibrary::parse("newmtl a\nKd red", Path::new(""), None, &textures).is_err());
+    }
+
+    #[test]
+    fn diffuse_texture() {
+        let dir = std::env::temp_dir().join(format!("light-mtl-{}", std::process::id()));
+        fs::create_dir_all(dir.join("maps")).unwrap();
+        image::RgbImage::from_pixel(4, 4, image::Rgb([10, 20, 30]))
+            .save(dir.join("maps/wood.png"))
+            .unwrap();
+        fs::write(
+            dir.join("model.mtl"),
+            "newmtl wood\nmap_Kd -s 2 2 1 maps\\wood.png\nnewmtl lost\nmap_Kd missing.png\n",
+        )
+        .unwrap();
+
+        let textures = Arc::new(TextureCache::with_directory(1 << 20, dir.join("cache")));
+        let library = MtlLibrary::load(dir.join("model.mtl"), None, &textures).unwrap();
+        let wood = &library.materials["wood"];
+        let texture = wood.texture.as_ref().unwrap();
+        assert_eq!(
+            Color::new(10.0, 20.0, 30.0),
+            texture.sample(glm::DVec2::zeros(), 0.0)
+        );
+
+        // Missing textures don't stop the loading
+        assert!(library.materials["lost"].texture.is_none());
+        assert_eq!(1, library.warnings.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
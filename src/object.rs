@@ -17,16 +17,95 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::light::Ray;
+use crate::filter::{FilteredShape, HitFilter};
 use crate::material::Material;
-use crate::shape::{HitRecord, Shape};
+use crate::shape::{Bounds, Shape};
 
 pub struct Object {
-    pub shape: Box<dyn Shape + Sync>,
+    pub shape: Box<dyn Shape + Send + Sync>,
     pub material: Material,
+    pub materials: Vec<Material>, // Per-face materials, indexed by `HitRecord::material`
+}
+
+impl Object {
+    /// Material of the faces with the given index. Faces whose index has no
+    /// entry in `materials` use `material`.
+    pub fn material_at(&self, index: usize) -> &Material {
+        self.materials.get(index).unwrap_or(&self.material)
+    }
+
+    /// Every material the object can be shaded with
+    pub fn all_materials(&self) -> impl Iterator<Item = &Material> {
+        std::iter::once(&self.material).chain(&self.materials)
+    }
+
+    /// The same object, with its hits going through a filter. Filters of an
+    /// object stack up: a hit must pass them all.
+    pub fn with_filter(self, filter: HitFilter) -> Self {
+        Self {
+            shape: Box::new(FilteredShape::new(self.shape, filter)),
+            ..self
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self.shape.bounds()
+    }
+
+    /// Center of mass of the surface, None for unbounded shapes
+    pub fn centroid(&self) -> Option<glm::DVec3> {
+        self.shape.centroid()
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::color::Color;
+    use crate::light::Ray;
+    use crate::shape::{HitRecord, Plane};
+
     use super::*;
+
+    /// Floor whose two halves, x < 0 and x >= 0, are different faces
+    struct SplitFloor(Plane);
+
+    impl Shape for SplitFloor {
+        fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+            let hit = self.0.intersect(ray)?;
+            Some(HitRecord {
+                material: (hit.point.x >= 0.0) as usize,
+                ..hit
+            })
+        }
+    }
+
+    #[test]
+    fn per_face_materials() {
+        let plain = |r| Material {
+            color: Color::new(r, 0.0, 0.0),
+            ..Default::default()
+        };
+        let mut object = Object {
+            shape: Box::new(SplitFloor(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            })),
+            material: plain(1.0),
+            materials: Vec::new(),
+        };
+        let shade = |object: &Object, x| {
+            let ray = Ray::new(glm::DVec3::new(x, 1.0, 0.0), -glm::DVec3::y());
+            let hit = object.shape.intersect(&ray).unwrap();
+            object.material_at(hit.material).color.x
+        };
+
+        // Without per-face materials, the whole shape uses the same one
+        assert_eq!(1.0, shade(&object, -1.0));
+        assert_eq!(1.0, shade(&object, 1.0));
+
+        object.materials = vec![plain(2.0), plain(3.0)];
+        assert_eq!(2.0, shade(&object, -1.0));
+        assert_eq!(3.0, shade(&object, 1.0));
+        assert_eq!(3, object.all_materials().count());
+    }
 }
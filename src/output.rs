@@ -0,0 +1,553 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Image writers that receive the tiles of a render as they finish, so that
+//! images larger than the available memory can be rendered, and writers of
+//! the raw radiance of a film for external post-processing.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use exr::block::writer::ChunksWriter;
+use exr::block::{BlockIndex, UncompressedBlock};
+use exr::math::{RoundingMode, Vec2};
+use exr::meta::attribute::{
+    ChannelDescription, Compression, LevelMode, LineOrder, SampleType, TileDescription,
+};
+use exr::meta::header::Header;
+use exr::meta::{BlockDescription, MetaData};
+use exr::prelude::SmallVec;
+use image::codecs::hdr::HdrEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::{imageops, Rgb, RgbImage};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::{Film, NonFinitePolicy, Pixel, ToneMapper};
+use crate::render::PathTracer;
+use crate::scene::Scene;
+use crate::tile::Tile;
+
+/// Destination of the tiles of a render
+pub trait TileSink {
+    /// Receive the pixels of a finished tile, in scanline order
+    fn write_tile(&mut self, tile: &Tile, pixels: &[Pixel]) -> Result<(), String>;
+}
+
+/// Rows of tiles waiting for their missing tiles
+struct Strip {
+    rows: Vec<u8>, // RGB, scanline order
+    missing_pixels: usize,
+}
+
+/// PNG writer that encodes the image strip by strip, a strip being a row of
+/// tiles. Strips are written in order as soon as all their tiles arrive, so
+/// with the scanline tile order only about one strip is held in memory. The
+/// strips follow the tiles, whatever their size.
+pub struct PngStreamWriter<W: Write + 'static> {
+    writer: png::StreamWriter<'static, W>,
+    width: u32,
+    height: u32,
+    tone_mapper: ToneMapper,
+    next_row: u32,
+    strips: HashMap<u32, Strip>, // By first row
+}
+
+impl<W: Write + 'static> PngStreamWriter<W> {
+    pub fn new(
+        writer: W,
+        width: u32,
+        height: u32,
+        tone_mapper: ToneMapper,
+    ) -> Result<Self, String> {
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let writer = encoder
+            .write_header()
+            .and_then(|writer| writer.into_stream_writer())
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            writer,
+            width,
+            height,
+            tone_mapper,
+            next_row: 0,
+            strips: HashMap::new(),
+        })
+    }
+
+    /// Complete the file. Fails if some tiles are missing.
+    pub fn finish(self) -> Result<(), String> {
+        if self.next_row < self.height {
+            return Err(format!(
+                "Missing tiles from row {} of the image",
+                self.next_row
+            ));
+        }
+        self.writer.finish().map_err(|e| e.to_string())
+    }
+}
+
+impl<W: Write + 'static> TileSink for PngStreamWriter<W> {
+    fn write_tile(&mut self, tile: &Tile, pixels: &[Pixel]) -> Result<(), String> {
+        let strip_len = (3 * self.width * tile.height) as usize;
+        let strip = self.strips.entry(tile.y).or_insert_with(|| Strip {
+            rows: vec![0; strip_len],
+            missing_pixels: strip_len / 3,
+        });
+
+        for ((i, j), pixel) in tile.pixels().zip(pixels) {
+            let color = pixel.color();
+            let offset = 3 * ((j - tile.y) * self.width + i) as usize;
+            for c in 0..3 {
+                strip.rows[offset + c] = self.tone_mapper.map(color[c]);
+            }
+        }
+        strip.missing_pixels -= pixels.len();
+
+        while self
+            .strips
+            .get(&self.next_row)
+            .is_some_and(|strip| strip.missing_pixels == 0)
+        {
+            let strip = self.strips.remove(&self.next_row).unwrap();
+            self.writer
+                .write_all(&strip.rows)
+                .map_err(|e| e.to_string())?;
+            self.next_row += (strip.rows.len() / (3 * self.width as usize)) as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the tiles of an EXR file, as they arrive
+struct ExrSink<'a, W: Write + Seek> {
+    meta: &'a MetaData,
+    chunks: &'a mut exr::block::writer::ChunkWriter<W>,
+    tiles_across: usize,
+    tile_size: u32,
+}
+
+impl<W: Write + Seek> TileSink for ExrSink<'_, W> {
+    fn write_tile(&mut self, tile: &Tile, pixels: &[Pixel]) -> Result<(), String> {
+        let index = BlockIndex {
+            layer: 0,
+            pixel_position: Vec2(tile.x as usize, tile.y as usize),
+            pixel_size: Vec2(tile.width as usize, tile.height as usize),
+            level: Vec2(0, 0),
+        };
+        let channels = &self.meta.headers[0].channels;
+        let block = UncompressedBlock::from_lines(channels, index, |line| {
+            let row = (line.location.position.y() - tile.y as usize) * tile.width as usize;
+            // Channels are sorted by name: B, G, R
+            let channel = 2 - line.location.channel;
+            line.write_samples(|x| pixels[row + x].color()[channel] as f32)
+                .expect("Line matches the tile width");
+        });
+
+        let chunk = block
+            .compress_to_chunk(&self.meta.headers)
+            .map_err(|e| e.to_string())?;
+        let chunk_index = (tile.y / self.tile_size) as usize * self.tiles_across
+            + (tile.x / self.tile_size) as usize;
+        self.chunks
+            .write_chunk(chunk_index, chunk)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Render into a tiled, 32 bit float EXR file. Tiles go to the file as
+/// soon as they are rendered, so the image never has to fit in memory.
+pub fn render_exr<P: AsRef<Path>>(
+    renderer: &PathTracer,
+    scene: &Scene,
+    camera: &Camera,
+    path: P,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let (w, h) = camera.resolution();
+    let tile_size = renderer.tile_size;
+    let channels = ["B", "G", "R"]
+        .map(|name| ChannelDescription::new(name, SampleType::F32, true))
+        .into_iter()
+        .collect();
+    let header = Header::new("light".into(), (w as usize, h as usize), channels).with_encoding(
+        Compression::ZIP16,
+        BlockDescription::Tiles(TileDescription {
+            tile_size: Vec2(tile_size as usize, tile_size as usize),
+            level_mode: LevelMode::Singular,
+            rounding_mode: RoundingMode::Down,
+        }),
+        LineOrder::Unspecified,
+    );
+
+    let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    exr::block::write(
+        BufWriter::new(file),
+        SmallVec::from_vec(vec![header]),
+        true,
+        |meta, chunks| {
+            let mut sink = ExrSink {
+                meta: &meta,
+                chunks,
+                tiles_across: w.div_ceil(tile_size) as usize,
+                tile_size,
+            };
+            renderer
+                .render_to_sink(scene, camera, &mut sink)
+                .map_err(|e| exr::error::Error::Io(io::Error::other(e)))
+        },
+    )
+    .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Render into a PNG file, streaming the finished rows of tiles to it
+pub fn render_png<P: AsRef<Path>>(
+    renderer: &PathTracer,
+    scene: &Scene,
+    camera: &Camera,
+    path: P,
+    tone_mapper: ToneMapper,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    let (w, h) = camera.resolution();
+    let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let mut writer = PngStreamWriter::new(BufWriter::new(file), w, h, tone_mapper)?;
+    renderer.render_to_sink(scene, camera, &mut writer)?;
+    writer.finish()
+}
+
+/// File format of the raw, not tone mapped, radiance of a film
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RadianceFormat {
+    /// OpenEXR, 32 bit float per channel
+    Exr,
+    /// Radiance RGBE, a shared 8 bit exponent per pixel
+    Hdr,
+}
+
+impl RadianceFormat {
+    /// Format given by the extension of a file
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_lowercase).as_deref() {
+            Some("exr") => Ok(Self::Exr),
+            Some("hdr") => Ok(Self::Hdr),
+            _ => Err(format!(
+                "{}: Unknown radiance format, use .exr or .hdr",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Write the radiance of a film without tone mapping, in the format given
+/// by the extension of the path. Values are the film colors, so 255 is the
+/// white of the tone mapped images.
+pub fn write_radiance<P: AsRef<Path>>(film: &Film, path: P) -> Result<(), String> {
+    let path = path.as_ref();
+    let (w, h) = film.resolution();
+    match RadianceFormat::from_path(path)? {
+        RadianceFormat::Exr => {
+            exr::prelude::write_rgb_file(path, w as usize, h as usize, |i, j| {
+                let color = film.pixel(i as u32, j as u32).color();
+                (color.x as f32, color.y as f32, color.z as f32)
+            })
+            .map_err(|e| format!("{}: {e}", path.display()))
+        }
+        RadianceFormat::Hdr => {
+            let pixels: Vec<Rgb<f32>> = film
+                .pixels()
+                .map(|pixel| {
+                    let color = pixel.color().map(|c| c.max(0.0));
+                    Rgb([color.x as f32, color.y as f32, color.z as f32])
+                })
+                .collect();
+            let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            HdrEncoder::new(BufWriter::new(file))
+                .encode(&pixels, w as usize, h as usize)
+                .map_err(|e| format!("{}: {e}", path.display()))
+        }
+    }
+}
+
+/// Read the radiance written by `write_radiance` into a film with one
+/// sample per pixel, e.g. to tone map it again
+pub fn read_radiance<P: AsRef<Path>>(path: P) -> Result<Film, String> {
+    let path = path.as_ref();
+    RadianceFormat::from_path(path)?;
+    let image = image::open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?
+        .into_rgb32f();
+    let (w, h) = image.dimensions();
+    let mut film = Film::new(w, h);
+    for (i, j, rgb) in image.enumerate_pixels() {
+        let color = Color::new(rgb[0] as f64, rgb[1] as f64, rgb[2] as f64);
+        film.add_sample(i, j, color, NonFinitePolicy::default());
+    }
+    Ok(film)
+}
+
+/// Quality of the JPEG sidecar images
+const SIDECAR_QUALITY: u8 = 85;
+
+/// Small JPEG images written next to a render, for dashboards that want to
+/// show it without reading the main output: a quick low sample count
+/// preview, written before the render starts, and a thumbnail of the final
+/// image. For `frame.png` they are `frame.preview.jpg` and `frame.thumb.jpg`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sidecars {
+    pub preview_samples: Option<u32>, // Samples per pixel of the preview
+    pub thumbnail_size: Option<u32>,  // Longest side of the thumbnail, in pixels
+}
+
+impl Sidecars {
+    /// Render and write the preview of the image that goes to `path`, if
+    /// enabled. Returns the path of the preview.
+    pub fn write_preview<P: AsRef<Path>>(
+        &self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+        path: P,
+    ) -> Result<Option<PathBuf>, String> {
+        let Some(samples) = self.preview_samples else {
+            return Ok(None);
+        };
+        let mut preview = renderer.clone();
+        preview.samples_per_pixel(samples);
+        let path = path.as_ref().with_extension("preview.jpg");
+        write_jpeg(&preview.render(scene, camera), &path)?;
+        Ok(Some(path))
+    }
+
+    /// Write the thumbnail of the image written to `path`, if enabled.
+    /// Returns the path of the thumbnail.
+    pub fn write_thumbnail<P: AsRef<Path>>(
+        &self,
+        image: &RgbImage,
+        path: P,
+    ) -> Result<Option<PathBuf>, String> {
+        let Some(size) = self.thumbnail_size else {
+            return Ok(None);
+        };
+        let (w, h) = image.dimensions();
+        let scale = size as f64 / w.max(h) as f64;
+        let thumbnail = if scale < 1.0 {
+            let side = |length: u32| ((length as f64 * scale).round() as u32).max(1);
+            imageops::thumbnail(image, side(w), side(h))
+        } else {
+            image.clone()
+        };
+        let path = path.as_ref().with_extension("thumb.jpg");
+        write_jpeg(&thumbnail, &path)?;
+        Ok(Some(path))
+    }
+}
+
+fn write_jpeg(image: &RgbImage, path: &Path) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    JpegEncoder::new_with_quality(BufWriter::new(file), SIDECAR_QUALITY)
+        .encode_image(image)
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView, FocusMode};
+    use crate::material::{Material, Specular};
+    use crate::object::Object;
+    use crate::shape::{Plane, Sphere};
+    use crate::tile::TileOrder;
+
+    /// Scene made of mirrors, so that renders are repeatable
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(10.0, 20.0, 30.0);
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 1.0, 5.0), 1.0)),
+                material: Material {
+                    color: Color::new(200.0, 100.0, 0.0),
+                    emittance: 1.0,
+                    bsdf: Some(Arc::new(Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(0.5, 0.5, 0.5),
+                    bsdf: Some(Arc::new(Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        scene
+    }
+
+    fn camera() -> Camera {
+        // Not a multiple of the tile size, to get partial tiles
+        Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 1.0, 0.0),
+            direction: glm::DVec3::z(),
+            resolution: (70, 45),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            focus_mode: FocusMode::PinHole,
+            ..Default::default()
+        })
+    }
+
+    fn test_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("light-output-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn stream_png() {
+        let (scene, camera) = (scene(), camera());
+        let mut renderer = PathTracer::new();
+        // Out of order tiles must be buffered until their strip is complete
+        renderer
+            .samples_per_pixel(1)
+            .max_depth(2)
+            .tile_order(TileOrder::Spiral);
+
+        let path = test_dir().join("stream.png");
+        for tile_size in [32, 20] {
+            renderer.tile_size(tile_size);
+            render_png(&renderer, &scene, &camera, &path, ToneMapper::Clamp).unwrap();
+            let expected = renderer.render(&scene, &camera);
+            assert_eq!(expected, image::open(&path).unwrap().into_rgb8());
+        }
+
+        // A missing tile is reported
+        let mut writer = PngStreamWriter::new(Vec::new(), 40, 40, ToneMapper::Clamp).unwrap();
+        let tile = Tile {
+            x: 0,
+            y: 0,
+            width: 32,
+            height: 32,
+        };
+        writer
+            .write_tile(&tile, &[Pixel::default(); 32 * 32])
+            .unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn stream_exr() {
+        let (scene, camera) = (scene(), camera());
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(1).max_depth(2).tile_size(16);
+
+        let path = test_dir().join("stream.exr");
+        render_exr(&renderer, &scene, &camera, &path).unwrap();
+        let film = renderer.render_film(&scene, &camera);
+
+        let image = image::open(&path).unwrap().into_rgb32f();
+        assert_eq!((70, 45), image.dimensions());
+        for (pixel, rgb) in film.pixels().zip(image.pixels()) {
+            let color = pixel.color();
+            assert_eq!([color.x as f32, color.y as f32, color.z as f32], rgb.0);
+        }
+    }
+
+    #[test]
+    fn radiance_files() {
+        let (scene, camera) = (scene(), camera());
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(1).max_depth(2);
+        let film = renderer.render_film(&scene, &camera);
+
+        let exr = test_dir().join("radiance.exr");
+        write_radiance(&film, &exr).unwrap();
+        let image = image::open(&exr).unwrap().into_rgb32f();
+        assert_eq!((70, 45), image.dimensions());
+        for (pixel, rgb) in film.pixels().zip(image.pixels()) {
+            let color = pixel.color();
+            assert_eq!([color.x as f32, color.y as f32, color.z as f32], rgb.0);
+        }
+        // Reading it back gives the same tone mapped image
+        let read = read_radiance(&exr).unwrap();
+        assert_eq!(film.to_image(), read.to_image());
+
+        // RGBE keeps about 1% of relative precision
+        let hdr = test_dir().join("radiance.HDR");
+        write_radiance(&film, &hdr).unwrap();
+        let image = image::open(&hdr).unwrap().into_rgb32f();
+        assert_eq!((70, 45), image.dimensions());
+        for (pixel, rgb) in film.pixels().zip(image.pixels()) {
+            let color = pixel.color();
+            let peak = color.max() as f32;
+            for c in 0..3 {
+                assert!((color[c] as f32 - rgb[c]).abs() <= 0.01 * peak);
+            }
+        }
+
+        assert!(write_radiance(&film, test_dir().join("radiance.png")).is_err());
+        assert!(read_radiance(test_dir().join("radiance.png")).is_err());
+    }
+
+    #[test]
+    fn sidecar_images() {
+        let (scene, camera) = (scene(), camera());
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(4).max_depth(2);
+        let path = test_dir().join("sidecars.png");
+
+        assert_eq!(
+            Ok(None),
+            Sidecars::default().write_thumbnail(&RgbImage::new(8, 8), &path)
+        );
+        let sidecars = Sidecars {
+            preview_samples: Some(1),
+            thumbnail_size: Some(20),
+        };
+        let preview = sidecars
+            .write_preview(&renderer, &scene, &camera, &path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(test_dir().join("sidecars.preview.jpg"), preview);
+        assert_eq!(
+            (70, 45),
+            image::open(&preview).unwrap().to_rgb8().dimensions()
+        );
+
+        let image = renderer.render(&scene, &camera);
+        let thumbnail = sidecars.write_thumbnail(&image, &path).unwrap().unwrap();
+        assert_eq!(test_dir().join("sidecars.thumb.jpg"), thumbnail);
+        assert_eq!(
+            (20, 13),
+            image::open(&thumbnail).unwrap().to_rgb8().dimensions()
+        );
+    }
+}
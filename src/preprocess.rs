@@ -0,0 +1,348 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::scene::Scene;
+use crate::texture::TextureCache;
+
+/// Textures above this size are reported, in bytes
+const HUGE_TEXTURE_SIZE: usize = 256 << 20;
+/// Triangles below this area are reported. They are likely degenerate and
+/// prone to precision problems in the intersection test.
+const TINY_TRIANGLE_AREA: f64 = 1e-8;
+/// Largest objects listed when a scene is over its memory budget
+const MAX_REPORTED_OBJECTS: usize = 5;
+
+/// Geometry statistics of a single scene object
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectReport {
+    pub triangles: usize,
+    pub memory: usize, // Bytes
+}
+
+/// Potential problem found while preprocessing a scene
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    HugeTexture { name: String, memory: usize },
+    TinyTriangle { object: usize, area: f64 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::HugeTexture { name, memory } => {
+                write!(f, "{name} uses {}", Bytes(*memory))
+            }
+            Warning::TinyTriangle { object, area } => {
+                write!(f, "object {object} has a triangle of area {area:e}")
+            }
+        }
+    }
+}
+
+/// Statistics and warnings gathered from a scene before rendering it, to
+/// catch expensive or broken scenes early
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreprocessReport {
+    pub objects: Vec<ObjectReport>, // In scene order
+    pub texture_memory: usize,      // Bytes
+    pub warnings: Vec<Warning>,
+}
+
+impl PreprocessReport {
+    pub fn new(scene: &Scene) -> Self {
+        let mut warnings = Vec::new();
+        let mut textures = Vec::new();
+
+        let objects = scene
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| {
+                if let Some(area) = object.shape.min_triangle_area() {
+                    if area < TINY_TRIANGLE_AREA {
+                        warnings.push(Warning::TinyTriangle {
+                            object: index,
+                            area,
+                        });
+                    }
+                }
+                ObjectReport {
+                    triangles: object.shape.triangle_count(),
+                    memory: object.shape.memory_size(),
+                }
+            })
+            .collect();
+
+        // Materials can share a BSDF, count each one once
+        let mut seen = HashSet::new();
+        for (index, object) in scene.objects.iter().enumerate() {
+            for material in object.all_materials() {
+                if let Some(bsdf) = &material.bsdf {
+                    if seen.insert(Arc::as_ptr(bsdf) as *const ()) {
+                        textures.push((
+                            format!("{} BSDF of object {index}", bsdf.kind()),
+                            bsdf.memory_size(),
+                        ));
+                    }
+                }
+            }
+        }
+        if let Some(environment) = &scene.environment {
+            textures.push((
+                "environment cube map".to_string(),
+                environment.memory_size(),
+            ));
+        }
+
+        warnings.extend(
+            textures
+                .iter()
+                .filter(|(_, memory)| *memory > HUGE_TEXTURE_SIZE)
+                .map(|(name, memory)| Warning::HugeTexture {
+                    name: name.clone(),
+                    memory: *memory,
+                }),
+        );
+
+        Self {
+            objects,
+            texture_memory: textures.iter().map(|(_, memory)| memory).sum(),
+            warnings,
+        }
+    }
+
+    /// Count the tiles that a texture cache of the scene can keep resident
+    /// as texture memory
+    pub fn texture_cache(&mut self, cache: &TextureCache) -> &mut Self {
+        self.texture_memory += cache.max_resident();
+        self
+    }
+
+    pub fn triangles(&self) -> usize {
+        self.objects.iter().map(|object| object.triangles).sum()
+    }
+
+    pub fn geometry_memory(&self) -> usize {
+        self.objects.iter().map(|object| object.memory).sum()
+    }
+
+    /// Check that the scene and a film of `film_memory` bytes fit in a soft
+    /// memory budget, in bytes. Fails with where the memory goes, so that a
+    /// scene too big for the machine stops before rendering instead of
+    /// getting killed halfway through.
+    pub fn check_memory(&self, film_memory: usize, budget: usize) -> Result<(), String> {
+        let total = self.geometry_memory() + self.texture_memory + film_memory;
+        if total <= budget {
+            return Ok(());
+        }
+
+        let mut largest: Vec<(usize, &ObjectReport)> = self.objects.iter().enumerate().collect();
+        largest.sort_by_key(|(_, object)| std::cmp::Reverse(object.memory));
+        let mut report = format!(
+            "The render needs about {}, over the memory budget of {}\n\
+             Geometry:    {}\n\
+             Textures:    {}\n\
+             Film:        {}",
+            Bytes(total),
+            Bytes(budget),
+            Bytes(self.geometry_memory()),
+            Bytes(self.texture_memory),
+            Bytes(film_memory),
+        );
+        for (index, object) in largest.iter().take(MAX_REPORTED_OBJECTS) {
+            report += &format!(
+                "\n  object {index:<4} {:>8} triangles {:>10}",
+                object.triangles,
+                Bytes(object.memory).to_string()
+            );
+        }
+        Err(report)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "objects": self
+                .objects
+                .iter()
+                .map(|object| json!({ "triangles": object.triangles, "memory": object.memory }))
+                .collect::<Vec<_>>(),
+            "triangles": self.triangles(),
+            "geometry_memory": self.geometry_memory(),
+            "texture_memory": self.texture_memory,
+            "warnings": self
+                .warnings
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl fmt::Display for PreprocessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Triangles:   {}", self.triangles())?;
+        for (index, object) in self.objects.iter().enumerate() {
+            writeln!(
+                f,
+                "  object {index:<4} {:>8} triangles {:>10}",
+                object.triangles,
+                Bytes(object.memory).to_string()
+            )?;
+        }
+        writeln!(f, "Geometry:    {}", Bytes(self.geometry_memory()))?;
+        write!(f, "Textures:    {}", Bytes(self.texture_memory))?;
+        for warning in &self.warnings {
+            write!(f, "\nWarning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Human readable memory size
+pub(crate) struct Bytes(pub(crate) usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{value:.1} {}", UNITS[unit])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cubemap::CubeMap;
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::{LevelOfDetail, Sphere, Triangle};
+
+    #[test]
+    fn counts_and_warnings() {
+        let triangle = |size: f64| {
+            Box::new(Triangle::new(
+                glm::DVec3::zeros(),
+                glm::DVec3::new(size, 0.0, 0.0),
+                glm::DVec3::new(0.0, size, 0.0),
+            ))
+        };
+        let mut lod = LevelOfDetail::new(triangle(1.0));
+        lod.add_level(10.0, triangle(1e-5));
+
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: triangle(1.0),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(lod),
+                material: Material::default(),
+                materials: Vec::new(),
+            });
+
+        let report = PreprocessReport::new(&scene);
+        assert_eq!(
+            vec![0, 1, 2],
+            report
+                .objects
+                .iter()
+                .map(|object| object.triangles)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(3, report.triangles());
+        assert!(report.objects[2].memory > report.objects[1].memory);
+        assert_eq!(0, report.texture_memory);
+        assert!(matches!(
+            report.warnings[..],
+            [Warning::TinyTriangle { object: 2, area }] if area < TINY_TRIANGLE_AREA
+        ));
+
+        let size = 16;
+        let faces = vec![vec![Default::default(); size * size]; 6];
+        scene.environment = Some(Arc::new(CubeMap::from_faces(size as u32, faces).unwrap()));
+        let report = PreprocessReport::new(&scene);
+        assert_eq!(6 * size * size * 24, report.texture_memory);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+            material: Material::default(),
+            materials: Vec::new(),
+        });
+        let report = PreprocessReport::new(&scene);
+        let needed = report.geometry_memory() + (3 << 20);
+        assert_eq!(Ok(()), report.check_memory(3 << 20, needed));
+
+        let error = report.check_memory(3 << 20, needed - 1).unwrap_err();
+        assert!(
+            error.starts_with("The render needs about 3.0 MiB"),
+            "{error}"
+        );
+        assert!(error.contains("Film:        3.0 MiB"));
+        assert!(error.contains("object 0"));
+
+        // Texture caches count their budget once they hold a texture
+        let dir = std::env::temp_dir().join(format!("light-budget-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = TextureCache::with_directory(1 << 20, dir.join("cache"));
+        let mut report = PreprocessReport::new(&scene);
+        assert_eq!(0, report.texture_cache(&cache).texture_memory);
+        let path = dir.join("gray.png");
+        image::RgbImage::new(8, 8).save(&path).unwrap();
+        cache.add(&path).unwrap();
+        let error = report
+            .texture_cache(&cache)
+            .check_memory(3 << 20, needed)
+            .unwrap_err();
+        assert!(error.contains("Textures:    1.0 MiB"), "{error}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn format_bytes() {
+        assert_eq!("12 B", Bytes(12).to_string());
+        assert_eq!("1.5 KiB", Bytes(1536).to_string());
+        assert_eq!("256.0 MiB", Bytes(HUGE_TEXTURE_SIZE).to_string());
+    }
+}
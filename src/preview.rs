@@ -0,0 +1,156 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use image::RgbImage;
+
+use crate::camera::{Camera, CameraConfig, FieldOfView, FocusMode};
+use crate::color::Color;
+use crate::material::Material;
+use crate::object::Object;
+use crate::render::PathTracer;
+use crate::scene::Scene;
+use crate::shape::{Plane, Sphere, Triangle};
+
+const PREVIEW_RESOLUTION: (u32, u32) = (400, 300);
+const BALL_CENTER: glm::DVec3 = glm::DVec3::new(0.0, 1.0, 0.0);
+const BALL_RADIUS: f64 = 1.0;
+const CAMERA_DISTANCE: f64 = 5.0;
+const CAMERA_HEIGHT: f64 = 2.0;
+
+/// Build the standard shaderball scene: a ball with the given material over a
+/// neutral floor and backdrop, lit by a square area light.
+pub fn shaderball_scene(material: Material) -> Scene {
+    let mut scene = Scene::new();
+    scene.background_color = Color::new(20.0, 20.0, 20.0);
+
+    let backdrop = || Material {
+        color: Color::new(128.0, 128.0, 128.0),
+        ..Default::default()
+    };
+    let light = || Material {
+        color: Color::new(255.0, 255.0, 255.0),
+        emittance: 4.0,
+        ..Default::default()
+    };
+
+    // Square area light above the ball, made of two triangles
+    let (a, b, c, d) = (
+        glm::DVec3::new(-1.5, 5.0, -1.5),
+        glm::DVec3::new(1.5, 5.0, -1.5),
+        glm::DVec3::new(1.5, 5.0, 1.5),
+        glm::DVec3::new(-1.5, 5.0, 1.5),
+    );
+
+    scene
+        .add_object(Object {
+            shape: Box::new(Sphere::new(BALL_CENTER, BALL_RADIUS)),
+            material,
+            materials: Vec::new(),
+        })
+        .add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: backdrop(),
+            materials: Vec::new(),
+        })
+        .add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::new(0.0, 0.0, -4.0),
+                normal: glm::DVec3::z(),
+            }),
+            material: backdrop(),
+            materials: Vec::new(),
+        })
+        .add_object(Object {
+            shape: Box::new(Triangle::new(a, b, c)),
+            material: light(),
+            materials: Vec::new(),
+        })
+        .add_object(Object {
+            shape: Box::new(Triangle::new(a, c, d)),
+            material: light(),
+            materials: Vec::new(),
+        });
+
+    scene
+}
+
+/// Camera orbiting the shaderball. `angle` is the turntable rotation, in
+/// radians, around the vertical axis through the ball.
+pub fn shaderball_camera(angle: f64, resolution: (u32, u32)) -> Camera {
+    let position = BALL_CENTER
+        + glm::DVec3::new(
+            CAMERA_DISTANCE * angle.sin(),
+            CAMERA_HEIGHT,
+            CAMERA_DISTANCE * angle.cos(),
+        );
+
+    Camera::new(&CameraConfig {
+        position,
+        direction: BALL_CENTER - position,
+        resolution,
+        fov: FieldOfView::Vertical(40.0_f64.to_radians()),
+        focus_mode: FocusMode::PinHole,
+        ..Default::default()
+    })
+}
+
+/// Render the given material on the standard shaderball scene
+pub fn render_material_preview(material: Material) -> RgbImage {
+    let scene = shaderball_scene(material);
+    let camera = shaderball_camera(0.0, PREVIEW_RESOLUTION);
+
+    let mut renderer = PathTracer::new();
+    renderer.samples_per_pixel(32).max_depth(4);
+    renderer.render(&scene, &camera)
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn turntable_camera_faces_ball() {
+        for angle in [0.0_f64, 90.0, 180.0, 270.0] {
+            let camera = shaderball_camera(angle.to_radians(), (32, 32));
+            let to_ball = (BALL_CENTER - camera.position()).normalize();
+            assert_relative_eq!(to_ball, camera.direction(), epsilon = 1e-12);
+            assert_relative_eq!(
+                CAMERA_DISTANCE.hypot(CAMERA_HEIGHT),
+                glm::distance(&BALL_CENTER, &camera.position()),
+                epsilon = 1e-12
+            );
+        }
+    }
+
+    #[test]
+    fn preview_ball_is_the_first_object() {
+        let material = Material {
+            color: Color::new(255.0, 0.0, 0.0),
+            ..Default::default()
+        };
+        let scene = shaderball_scene(material);
+        assert_eq!(5, scene.objects.len());
+        assert_eq!(Color::new(255.0, 0.0, 0.0), scene.objects[0].material.color);
+    }
+}
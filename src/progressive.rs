@@ -0,0 +1,242 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Progressive rendering. The image is rendered in passes that add their
+//! samples to the same film, which is saved to a checkpoint file from time to
+//! time. A render that crashes or gets interrupted resumes from its last
+//! checkpoint instead of starting over.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::color::Color;
+use crate::film::{Film, FilmPrecision, Pixel};
+
+/// Header of the checkpoint file format, followed by the width and height as
+/// little endian u32, the seed as a little endian u64, the number of passes
+/// as a little endian u32 and the pixels in scanline order. Each pixel is its
+/// sum and sum of squares as six little endian f64 and its sample, discarded
+/// and clamped counts as little endian u32.
+const MAGIC: &[u8; 4] = b"LCKP";
+const HEADER_SIZE: usize = 24;
+const PIXEL_SIZE: usize = 60;
+
+/// Passes of a render with a target error, unless it gives how many
+pub const MAX_TARGET_ERROR_PASSES: u32 = 256;
+
+/// Settings of a progressive render
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progressive {
+    /// Passes of the whole render, each one with the samples per pixel of the
+    /// renderer
+    pub passes: u32,
+    /// File the accumulated passes are saved to
+    pub checkpoint: Option<PathBuf>,
+    /// Minimum time between two checkpoints. The last pass is always saved.
+    pub interval: Duration,
+    /// Continue from the checkpoint file if there is one, instead of
+    /// overwriting it
+    pub resume: bool,
+    /// Stop before all the passes are done once the estimated relative error
+    /// of the image is below this fraction, see `Film::relative_error`
+    pub target_error: Option<f64>,
+}
+
+impl Default for Progressive {
+    fn default() -> Self {
+        Self {
+            passes: 1,
+            checkpoint: None,
+            interval: Duration::from_secs(60),
+            resume: true,
+            target_error: None,
+        }
+    }
+}
+
+impl Progressive {
+    pub fn new(passes: u32) -> Self {
+        Self {
+            passes,
+            ..Default::default()
+        }
+    }
+
+    /// Checkpoint to continue from, if resuming and the file exists
+    pub(crate) fn restore(&self, precision: FilmPrecision) -> Result<Option<Checkpoint>, String> {
+        match &self.checkpoint {
+            Some(path) if self.resume && path.exists() => {
+                Checkpoint::load_with_precision(path, precision).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// State of a progressive render after some passes. The film holds the
+/// samples of the passes, without the caustics pass, which is added once the
+/// render is done.
+pub struct Checkpoint {
+    pub seed: u64,   // Seed of the whole render, each pass derives its own
+    pub passes: u32, // Passes accumulated in the film
+    pub film: Film,
+}
+
+impl Checkpoint {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        let (width, height) = self.film.resolution();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + PIXEL_SIZE * (width * height) as usize);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.passes.to_le_bytes());
+        for pixel in self.film.pixels() {
+            for channel in pixel.sum.iter().chain(&pixel.sum_sq) {
+                bytes.extend_from_slice(&channel.to_le_bytes());
+            }
+            for count in [pixel.samples, pixel.discarded, pixel.clamped] {
+                bytes.extend_from_slice(&count.to_le_bytes());
+            }
+        }
+        writer.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    /// Read a checkpoint into a film of the given precision
+    pub fn read<R: Read>(reader: &mut R, precision: FilmPrecision) -> Result<Self, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Err("Not a checkpoint file".to_string());
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let (width, height) = (u32_at(4), u32_at(8));
+        let seed = u64::from_le_bytes(bytes[12..20].try_into().unwrap());
+        let passes = u32_at(20);
+        let data = &bytes[HEADER_SIZE..];
+        if data.len() != PIXEL_SIZE * (width as usize) * (height as usize) {
+            return Err("Truncated checkpoint file".to_string());
+        }
+
+        let pixels: Vec<Pixel> = data
+            .chunks_exact(PIXEL_SIZE)
+            .map(|chunk| {
+                let f64_at =
+                    |k: usize| f64::from_le_bytes(chunk[8 * k..8 * k + 8].try_into().unwrap());
+                let u32_at = |k: usize| {
+                    u32::from_le_bytes(chunk[48 + 4 * k..52 + 4 * k].try_into().unwrap())
+                };
+                Pixel {
+                    sum: Color::new(f64_at(0), f64_at(1), f64_at(2)),
+                    sum_sq: Color::new(f64_at(3), f64_at(4), f64_at(5)),
+                    samples: u32_at(0),
+                    discarded: u32_at(1),
+                    clamped: u32_at(2),
+                    overlay: Color::zeros(),
+                }
+            })
+            .collect();
+        let mut film = Film::with_precision(width, height, precision);
+        film.update_pixels(|index, pixel| *pixel = pixels[index]);
+
+        Ok(Self { seed, passes, film })
+    }
+
+    /// Save the checkpoint. The file is written next to its destination and
+    /// then moved over it, so that a crash while saving keeps the previous
+    /// checkpoint.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        let mut file = fs::File::create(&partial)
+            .map_err(|e| format!("Couldn't create {}: {e}", partial.display()))?;
+        self.write(&mut file)?;
+        file.sync_all()
+            .map_err(|e| format!("Couldn't write {}: {e}", partial.display()))?;
+        fs::rename(&partial, path).map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::load_with_precision(path, FilmPrecision::default())
+    }
+
+    pub fn load_with_precision<P: AsRef<Path>>(
+        path: P,
+        precision: FilmPrecision,
+    ) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut file =
+            fs::File::open(path).map_err(|e| format!("Couldn't open {}: {e}", path.display()))?;
+        Self::read(&mut file, precision).map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::film::NonFinitePolicy;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut film = Film::new(3, 2);
+        film.add_sample(0, 0, Color::new(1.0, 2.0, 3.0), NonFinitePolicy::Discard);
+        film.add_sample(0, 0, Color::new(0.5, 0.25, 0.125), NonFinitePolicy::Discard);
+        film.add_sample(
+            2,
+            1,
+            Color::new(f64::NAN, 0.0, 0.0),
+            NonFinitePolicy::Discard,
+        );
+        film.add_sample(
+            1,
+            1,
+            Color::new(f64::NAN, 0.0, 0.0),
+            NonFinitePolicy::Clamp(1.0),
+        );
+        let checkpoint = Checkpoint {
+            seed: 42,
+            passes: 3,
+            film,
+        };
+
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes).unwrap();
+        let restored = Checkpoint::read(&mut bytes.as_slice(), FilmPrecision::F64).unwrap();
+        assert_eq!((42, 3), (restored.seed, restored.passes));
+        assert_eq!((3, 2), restored.film.resolution());
+        for (saved, loaded) in checkpoint.film.pixels().zip(restored.film.pixels()) {
+            assert_eq!((saved.sum, saved.sum_sq), (loaded.sum, loaded.sum_sq));
+            assert_eq!(
+                (saved.samples, saved.discarded, saved.clamped),
+                (loaded.samples, loaded.discarded, loaded.clamped)
+            );
+        }
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert_eq!(
+            Some("Truncated checkpoint file".to_string()),
+            Checkpoint::read(&mut &truncated[..], FilmPrecision::F64).err()
+        );
+        assert!(Checkpoint::read(&mut &b"LCUB"[..], FilmPrecision::F64).is_err());
+    }
+}
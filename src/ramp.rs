@@ -0,0 +1,207 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use image::{Rgb, RgbImage};
+
+use crate::font;
+use crate::material::Material;
+use crate::preview;
+use crate::render::PathTracer;
+
+const LABEL_SCALE: u32 = 2;
+const LABEL_COLOR: Rgb<u8> = Rgb([230, 230, 230]);
+const BACKGROUND: Rgb<u8> = Rgb([40, 40, 40]);
+const SPACING: u32 = 4;
+
+/// Material parameter swept along one axis of a ramp, from `start` to `end`
+/// in `steps` evenly spaced values
+#[derive(Debug, Clone, PartialEq)]
+pub struct RampAxis {
+    pub name: String,
+    pub start: f64,
+    pub end: f64,
+    pub steps: u32,
+}
+
+impl RampAxis {
+    pub fn new(name: &str, start: f64, end: f64, steps: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end,
+            steps: steps.max(1),
+        }
+    }
+
+    pub fn value(&self, step: u32) -> f64 {
+        if self.steps <= 1 {
+            return self.start;
+        }
+        let t = step as f64 / (self.steps - 1) as f64;
+        self.start + t * (self.end - self.start)
+    }
+
+    fn label(&self, step: u32) -> String {
+        format!("{:.2}", self.value(step))
+    }
+}
+
+/// Grid of shaderballs sweeping two material parameters, one along the
+/// columns and one along the rows, with the parameter values written next to
+/// the grid. Useful to see at a glance how a BSDF reacts to its parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterRamp {
+    pub columns: RampAxis,
+    pub rows: RampAxis,
+    pub cell_size: u32, // Side of each shaderball image, in pixels
+    pub samples_per_pixel: u32,
+}
+
+impl ParameterRamp {
+    pub fn new(columns: RampAxis, rows: RampAxis) -> Self {
+        Self {
+            columns,
+            rows,
+            cell_size: 128,
+            samples_per_pixel: 16,
+        }
+    }
+
+    /// Render the grid. `material` builds the material of each ball from
+    /// its column and row parameters.
+    pub fn render<F>(&self, material: F) -> RgbImage
+    where
+        F: Fn(f64, f64) -> Material,
+    {
+        let line_height = font::GLYPH_HEIGHT * LABEL_SCALE + SPACING;
+        let left = (0..self.rows.steps)
+            .map(|row| font::text_width(&self.rows.label(row), LABEL_SCALE))
+            .max()
+            .unwrap_or(0)
+            + 2 * SPACING;
+        let top = 2 * line_height + SPACING;
+        let pitch = self.cell_size + SPACING;
+
+        let width = left + self.columns.steps * pitch;
+        let height = top + self.rows.steps * pitch;
+        let mut image = RgbImage::from_pixel(width, height, BACKGROUND);
+
+        let title = format!("X: {}   Y: {}", self.columns.name, self.rows.name);
+        font::draw_text(
+            &mut image,
+            SPACING,
+            SPACING,
+            &title,
+            LABEL_SCALE,
+            LABEL_COLOR,
+        );
+
+        let mut renderer = PathTracer::new();
+        renderer
+            .samples_per_pixel(self.samples_per_pixel)
+            .max_depth(4);
+        let camera = preview::shaderball_camera(0.0, (self.cell_size, self.cell_size));
+
+        for column in 0..self.columns.steps {
+            let x = left + column * pitch;
+            let label = self.columns.label(column);
+            font::draw_text(
+                &mut image,
+                x,
+                SPACING + line_height,
+                &label,
+                LABEL_SCALE,
+                LABEL_COLOR,
+            );
+        }
+
+        for row in 0..self.rows.steps {
+            let y = top + row * pitch;
+            let label = self.rows.label(row);
+            let label_y = y + self
+                .cell_size
+                .saturating_sub(font::GLYPH_HEIGHT * LABEL_SCALE)
+                / 2;
+            font::draw_text(
+                &mut image,
+                SPACING,
+                label_y,
+                &label,
+                LABEL_SCALE,
+                LABEL_COLOR,
+            );
+
+            for column in 0..self.columns.steps {
+                let x = left + column * pitch;
+                let scene = preview::shaderball_scene(material(
+                    self.columns.value(column),
+                    self.rows.value(row),
+                ));
+                let cell = renderer.render(&scene, &camera);
+                for (i, j, rgb) in cell.enumerate_pixels() {
+                    image.put_pixel(x + i, y + j, *rgb);
+                }
+            }
+        }
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::Color;
+
+    #[test]
+    fn axis_values() {
+        let axis = RampAxis::new("roughness", 0.0, 1.0, 5);
+        assert_eq!(0.0, axis.value(0));
+        assert_eq!(0.25, axis.value(1));
+        assert_eq!(1.0, axis.value(4));
+        assert_eq!("0.75", axis.label(3));
+
+        assert_eq!(2.0, RampAxis::new("single", 2.0, 3.0, 1).value(0));
+    }
+
+    #[test]
+    fn ramp_layout() {
+        let mut ramp = ParameterRamp::new(
+            RampAxis::new("red", 0.0, 255.0, 3),
+            RampAxis::new("emittance", 0.0, 1.0, 2),
+        );
+        ramp.cell_size = 8;
+        ramp.samples_per_pixel = 1;
+
+        let image = ramp.render(|red, emittance| Material {
+            color: Color::new(red, 0.0, 0.0),
+            emittance,
+            ..Default::default()
+        });
+
+        let pitch = 8 + SPACING;
+        let left = font::text_width("1.00", LABEL_SCALE) + 2 * SPACING;
+        assert_eq!(left + 3 * pitch, image.width());
+        assert_eq!(
+            2 * pitch + 2 * (5 * LABEL_SCALE + SPACING) + SPACING,
+            image.height()
+        );
+        assert!(image.pixels().any(|p| *p == LABEL_COLOR));
+    }
+}
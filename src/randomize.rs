@@ -0,0 +1,372 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Domain randomization: varied frames of synthetic data from one base scene.
+//!
+//! Each frame draws its perturbations from a generator seeded with the seed
+//! of the randomizer and the frame number, so any frame of a dataset can be
+//! reproduced on its own. Perturbations always start from the base scene and
+//! never accumulate from frame to frame.
+//!
+//! Material jitter varies the materials of the objects of a single scene
+//! instead, so that rows of copies of an object don't look identical. Each
+//! object draws its variation from the seed and its own index.
+
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::camera::CameraConfig;
+use crate::color::rotate_hue;
+use crate::material::{Material, Specular};
+use crate::object::Object;
+use crate::scene::Scene;
+use crate::shape::Bounds;
+use crate::spectrum::Illuminant;
+
+/// Camera placed anywhere inside a box, looking at a target
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraBounds {
+    pub positions: Bounds,
+    pub target: glm::DVec3,
+    pub target_jitter: f64, // Largest offset of the target along each axis
+}
+
+/// Materials of an object in the base scene
+#[derive(Debug, Clone)]
+struct BaseMaterials {
+    material: Material,
+    materials: Vec<Material>,
+}
+
+impl BaseMaterials {
+    fn is_emissive(&self) -> bool {
+        std::iter::once(&self.material)
+            .chain(&self.materials)
+            .any(|material| material.emittance > 0.0)
+    }
+}
+
+pub struct DomainRandomizer {
+    seed: u64,
+    base: Vec<BaseMaterials>,
+    light_intensity: f64,                  // Relative jitter of the emittance
+    light_temperature: Option<(f64, f64)>, // Range of blackbody temperatures [K]
+    material_pool: Vec<Material>,
+    swap_probability: f64,
+    camera: Option<CameraBounds>,
+}
+
+impl DomainRandomizer {
+    /// Randomizer of the given scene. The materials of its objects are kept
+    /// as the base every frame starts from.
+    pub fn new(scene: &Scene, seed: u64) -> Self {
+        Self {
+            seed,
+            base: scene
+                .objects
+                .iter()
+                .map(|object| BaseMaterials {
+                    material: object.material.clone(),
+                    materials: object.materials.clone(),
+                })
+                .collect(),
+            light_intensity: 0.0,
+            light_temperature: None,
+            material_pool: Vec::new(),
+            swap_probability: 0.0,
+            camera: None,
+        }
+    }
+
+    /// Scale the emittance of every light by a factor drawn from
+    /// [1 - jitter, 1 + jitter]
+    pub fn light_intensity(&mut self, jitter: f64) -> &mut Self {
+        self.light_intensity = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Color the lights as blackbodies with a temperature drawn from the
+    /// given range, in Kelvin
+    pub fn light_temperature(&mut self, range: Option<(f64, f64)>) -> &mut Self {
+        self.light_temperature = range;
+        self
+    }
+
+    /// Replace the material of each object that doesn't emit light with one
+    /// of the pool, with the given probability
+    pub fn material_pool(&mut self, pool: Vec<Material>, probability: f64) -> &mut Self {
+        self.material_pool = pool;
+        self.swap_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Move the camera to a random position within bounds
+    pub fn camera_bounds(&mut self, bounds: Option<CameraBounds>) -> &mut Self {
+        self.camera = bounds;
+        self
+    }
+
+    /// Generator of the perturbations of a frame
+    fn rng(&self, frame: u64) -> StdRng {
+        StdRng::seed_from_u64(self.seed ^ frame.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    /// Perturb the base scene for a frame, and the camera if it is
+    /// randomized. `scene` must be the scene the randomizer was made from,
+    /// or one of its randomized frames.
+    pub fn apply(
+        &self,
+        scene: &mut Scene,
+        camera: &CameraConfig,
+        frame: u64,
+    ) -> Result<CameraConfig, String> {
+        if scene.objects.len() != self.base.len() {
+            return Err(format!(
+                "The scene has {} objects, but the base scene had {}",
+                scene.objects.len(),
+                self.base.len()
+            ));
+        }
+
+        let mut rng = self.rng(frame);
+        for (object, base) in scene.objects.iter_mut().zip(&self.base) {
+            object.material = base.material.clone();
+            object.materials = base.materials.clone();
+
+            if base.is_emissive() {
+                let scale = 1.0 + self.light_intensity * rng.gen_range(-1.0..=1.0);
+                let color = self.light_temperature.map(|(min, max)| {
+                    let temperature = rng.gen_range(min.min(max)..=max.max(min));
+                    255.0 * Illuminant::Blackbody(temperature).color()
+                });
+                let lights = std::iter::once(&mut object.material).chain(&mut object.materials);
+                for light in lights.filter(|material| material.emittance > 0.0) {
+                    light.emittance *= scale;
+                    if let Some(color) = color {
+                        light.color = color;
+                    }
+                }
+            } else if !self.material_pool.is_empty() && rng.gen_bool(self.swap_probability) {
+                let material = &self.material_pool[rng.gen_range(0..self.material_pool.len())];
+                object.material = material.clone();
+                object.materials.clear();
+            }
+        }
+
+        let Some(bounds) = &self.camera else {
+            return Ok(camera.clone());
+        };
+        let mut point = |bounds: &Bounds| {
+            glm::DVec3::from_fn(|i, _| {
+                let (min, max) = (bounds.min[i], bounds.max[i]);
+                if min < max {
+                    rng.gen_range(min..max)
+                } else {
+                    min
+                }
+            })
+        };
+        let position = point(&bounds.positions);
+        let jitter = glm::DVec3::repeat(bounds.target_jitter.abs());
+        let target = point(&Bounds {
+            min: bounds.target - jitter,
+            max: bounds.target + jitter,
+        });
+        Ok(CameraConfig {
+            position,
+            direction: target - position,
+            ..camera.clone()
+        })
+    }
+}
+
+/// Per-object variation of the parameters of a material
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MaterialJitter {
+    pub seed: u64,
+    pub hue: f64,        // Largest rotation of the hue, in degrees
+    pub brightness: f64, // Relative jitter of the color
+    pub roughness: f64,  // Largest change of the roughness of metals
+}
+
+impl MaterialJitter {
+    /// Vary the materials of the object with the given index in its scene.
+    /// All the materials of the object vary alike.
+    pub fn apply(&self, object: &mut Object, index: usize) {
+        let mut rng =
+            StdRng::seed_from_u64(self.seed ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let hue = self.hue * rng.gen_range(-1.0..=1.0);
+        let brightness = 1.0 + self.brightness * rng.gen_range(-1.0..=1.0);
+        let roughness = self.roughness * rng.gen_range(-1.0..=1.0);
+
+        for material in std::iter::once(&mut object.material).chain(&mut object.materials) {
+            material.color = rotate_hue(&material.color, hue) * brightness;
+            if let Some(Specular::Metal { roughness: base }) = material.specular() {
+                material.bsdf = Some(Arc::new(Specular::Metal {
+                    roughness: (base + roughness).clamp(0.0, 1.0),
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::color::Color;
+    use crate::shape::{Plane, Sphere};
+
+    use super::*;
+
+    fn scene() -> Scene {
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(128.0, 128.0, 128.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 5.0, 0.0), 1.0)),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    emittance: 2.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        scene
+    }
+
+    #[test]
+    fn frames_are_reproducible() {
+        let mut scene = scene();
+        let mut randomizer = DomainRandomizer::new(&scene, 42);
+        randomizer
+            .light_intensity(0.5)
+            .light_temperature(Some((2700.0, 6500.0)))
+            .material_pool(vec![Material::metal(Color::repeat(200.0), 0.3)], 1.0)
+            .camera_bounds(Some(CameraBounds {
+                positions: Bounds {
+                    min: glm::DVec3::new(-1.0, 1.0, 4.0),
+                    max: glm::DVec3::new(1.0, 2.0, 6.0),
+                },
+                target: glm::DVec3::zeros(),
+                target_jitter: 0.1,
+            }));
+        let base = CameraConfig::default();
+
+        let camera = randomizer.apply(&mut scene, &base, 3).unwrap();
+        let light = (
+            scene.objects[1].material.emittance,
+            scene.objects[1].material.color,
+        );
+        assert!((1.0..=3.0).contains(&light.0));
+        assert!(scene.objects[0].material.specular().is_some());
+        assert!(randomizer
+            .camera
+            .unwrap()
+            .positions
+            .contains(&camera.position));
+
+        // Other frames differ, and going back to a frame gives it again
+        // instead of perturbing the perturbed scene
+        let other = randomizer.apply(&mut scene, &base, 4).unwrap();
+        assert_ne!(camera.position, other.position);
+        assert_ne!(light.0, scene.objects[1].material.emittance);
+        let again = randomizer.apply(&mut scene, &base, 3).unwrap();
+        assert_eq!(camera.position, again.position);
+        assert_eq!(camera.direction, again.direction);
+        assert_eq!(
+            light,
+            (
+                scene.objects[1].material.emittance,
+                scene.objects[1].material.color
+            )
+        );
+    }
+
+    #[test]
+    fn lights_are_never_swapped() {
+        let mut scene = scene();
+        let mut randomizer = DomainRandomizer::new(&scene, 7);
+        randomizer.material_pool(vec![Material::default()], 1.0);
+        let camera = randomizer
+            .apply(&mut scene, &CameraConfig::default(), 0)
+            .unwrap();
+
+        // Without any other perturbation, the lights and camera are untouched
+        assert_eq!(2.0, scene.objects[1].material.emittance);
+        assert_eq!(Color::new(0.0, 0.0, 0.0), scene.objects[0].material.color);
+        assert_eq!(CameraConfig::default().position, camera.position);
+
+        scene.objects.pop();
+        assert!(randomizer
+            .apply(&mut scene, &CameraConfig::default(), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn copies_vary_by_index() {
+        let object = || Object {
+            shape: Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+            material: Material::metal(Color::new(200.0, 100.0, 50.0), 0.5),
+            materials: Vec::new(),
+        };
+        let jitter = MaterialJitter {
+            seed: 3,
+            hue: 20.0,
+            brightness: 0.1,
+            roughness: 0.2,
+        };
+        let varied: Vec<Material> = (0..4)
+            .map(|i| {
+                let mut copy = object();
+                jitter.apply(&mut copy, i);
+                copy.material
+            })
+            .collect();
+
+        for material in &varied {
+            let Some(Specular::Metal { roughness }) = material.specular() else {
+                panic!("The jitter changed the kind of material");
+            };
+            assert!((0.3..=0.7).contains(&roughness));
+        }
+        assert_ne!(varied[0].color, varied[1].color);
+
+        // The same seed and index always give the same variation
+        let mut again = object();
+        jitter.apply(&mut again, 2);
+        assert_eq!(varied[2].color, again.material.color);
+        assert_eq!(varied[2].specular(), again.material.specular());
+
+        // Without jitter, the material is untouched
+        let mut plain = object();
+        MaterialJitter::default().apply(&mut plain, 1);
+        assert_eq!(Color::new(200.0, 100.0, 50.0), plain.material.color);
+    }
+}
@@ -0,0 +1,233 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Ground truth renders, used as the baseline of the error metrics. They take
+//! a very high number of samples per pixel, so they are rendered in passes
+//! and the accumulated film is saved after every pass, to resume the render
+//! if it is interrupted.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::{Film, FilmPrecision, NonFinitePolicy, Pixel};
+use crate::render::PathTracer;
+use crate::scene::Scene;
+
+const MAGIC: &[u8; 4] = b"LREF";
+const HEADER_SIZE: usize = 16;
+const PIXEL_SIZE: usize = 9 * 8 + 3 * 4;
+
+/// Settings of a ground truth render
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceRender {
+    pub samples_per_pixel: u32,
+    pub samples_per_pass: u32, // Samples between checkpoints
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl Default for ReferenceRender {
+    fn default() -> Self {
+        Self {
+            samples_per_pixel: 65536,
+            samples_per_pass: 1024,
+            checkpoint: None,
+        }
+    }
+}
+
+impl ReferenceRender {
+    /// Configure a path tracer for ground truth: exactly the same number of
+    /// samples for every pixel, without clamping, without the biased or
+    /// approximate passes and with a double precision film. The samples per
+    /// pixel are set for each pass.
+    pub fn configure(renderer: &mut PathTracer) {
+        renderer
+            .non_finite_policy(NonFinitePolicy::Discard)
+            .priority_sampling(None)
+            .adaptive_sampling(None)
+            .caustics(None)
+            .blue_noise(None)
+            .gradient_domain(None)
+            .path_guiding(None)
+            .film_precision(FilmPrecision::F64);
+    }
+
+    /// Render the reference image, resuming from the checkpoint if there is
+    /// one. `on_pass` is called after every pass with the film so far.
+    pub fn render<F>(
+        &self,
+        renderer: &mut PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+        mut on_pass: F,
+    ) -> Result<Film, String>
+    where
+        F: FnMut(&Film, u32),
+    {
+        Self::configure(renderer);
+        let (w, h) = camera.resolution();
+        let (mut film, mut done) = match &self.checkpoint {
+            Some(path) if path.exists() => load_checkpoint(path)?,
+            _ => (Film::new(w, h), 0),
+        };
+        if film.resolution() != (w, h) {
+            return Err("The checkpoint has a different resolution".to_string());
+        }
+
+        let pass = self.samples_per_pass.max(1);
+        while done < self.samples_per_pixel {
+            let spp = pass.min(self.samples_per_pixel - done);
+            film.merge(&renderer.samples_per_pixel(spp).render_film(scene, camera));
+            done += spp;
+
+            if let Some(path) = &self.checkpoint {
+                save_checkpoint(&film, done, path)?;
+            }
+            on_pass(&film, done);
+        }
+        Ok(film)
+    }
+}
+
+fn save_checkpoint(film: &Film, samples_per_pixel: u32, path: &Path) -> Result<(), String> {
+    let (w, h) = film.resolution();
+    let mut bytes = MAGIC.to_vec();
+    for value in [w, h, samples_per_pixel] {
+        bytes.extend(value.to_le_bytes());
+    }
+    for pixel in film.pixels() {
+        for value in pixel.sum.iter().chain(&pixel.overlay).chain(&pixel.sum_sq) {
+            bytes.extend(value.to_le_bytes());
+        }
+        for value in [pixel.samples, pixel.discarded, pixel.clamped] {
+            bytes.extend(value.to_le_bytes());
+        }
+    }
+
+    // Write a new file and rename it, not to lose the checkpoint if the
+    // program is interrupted while writing
+    let partial = path.with_extension("partial");
+    fs::File::create(&partial)
+        .and_then(|mut file| file.write_all(&bytes))
+        .and_then(|()| fs::rename(&partial, path))
+        .map_err(|e| format!("Couldn't write {}: {e}", path.display()))
+}
+
+/// Returns the film and its samples per pixel
+fn load_checkpoint(path: &Path) -> Result<(Film, u32), String> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| format!("Couldn't open {}: {e}", path.display()))?;
+
+    if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+        return Err(format!("{} is not a reference checkpoint", path.display()));
+    }
+    let word = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let (w, h, samples_per_pixel) = (word(4), word(8), word(12));
+    let data = &bytes[HEADER_SIZE..];
+    if data.len() != (w * h) as usize * PIXEL_SIZE {
+        return Err(format!("{} is truncated", path.display()));
+    }
+
+    let mut film = Film::new(w, h);
+    film.update_pixels(|index, pixel| {
+        let data = &data[index * PIXEL_SIZE..(index + 1) * PIXEL_SIZE];
+        let float = |k: usize| f64::from_le_bytes(data[8 * k..8 * k + 8].try_into().unwrap());
+        let int = |k: usize| u32::from_le_bytes(data[72 + 4 * k..76 + 4 * k].try_into().unwrap());
+        *pixel = Pixel {
+            sum: Color::new(float(0), float(1), float(2)),
+            overlay: Color::new(float(3), float(4), float(5)),
+            sum_sq: Color::new(float(6), float(7), float(8)),
+            samples: int(0),
+            discarded: int(1),
+            clamped: int(2),
+        };
+    });
+    Ok((film, samples_per_pixel))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView, FocusMode};
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::Sphere;
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(50.0, 60.0, 70.0);
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 4.0), 1.0)),
+            material: Material {
+                color: Color::new(255.0, 128.0, 0.0),
+                emittance: 1.0,
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        let camera = Camera::new(&CameraConfig {
+            resolution: (12, 8),
+            fov: FieldOfView::Horizontal(60.0_f64.to_radians()),
+            focus_mode: FocusMode::FocalPlane {
+                focal_distance: 4.0,
+                aperture: 0.2,
+            },
+            ..Default::default()
+        });
+        let checkpoint = std::env::temp_dir().join(format!("light-{}.ref", std::process::id()));
+        let _ = fs::remove_file(&checkpoint);
+
+        let mut renderer = PathTracer::new();
+        let mut reference = ReferenceRender {
+            samples_per_pixel: 5,
+            samples_per_pass: 2,
+            checkpoint: Some(checkpoint.clone()),
+        };
+        let mut passes = Vec::new();
+        let film = reference
+            .render(&mut renderer, &scene, &camera, |_, spp| passes.push(spp))
+            .unwrap();
+        assert_eq!(vec![2, 4, 5], passes);
+        assert!(film.pixels().all(|pixel| pixel.samples == 5));
+
+        // The checkpoint keeps the exact film
+        let (saved, spp) = load_checkpoint(&checkpoint).unwrap();
+        assert_eq!(5, spp);
+        for (a, b) in film.pixels().zip(saved.pixels()) {
+            assert_eq!(a.sum, b.sum);
+            assert_eq!(a.samples, b.samples);
+        }
+
+        // Only the missing samples are rendered when resuming
+        reference.samples_per_pixel = 8;
+        passes.clear();
+        let resumed = reference
+            .render(&mut renderer, &scene, &camera, |_, spp| passes.push(spp))
+            .unwrap();
+        assert_eq!(vec![7, 8], passes);
+        assert!(resumed.pixels().all(|pixel| pixel.samples == 8));
+        fs::remove_file(&checkpoint).unwrap();
+    }
+}
@@ -17,68 +17,467 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use image::RgbImage;
-use rand::rngs::ThreadRng;
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use bumpalo::collections::Vec as BumpVec;
+use bumpalo::Bump;
+use image::{Rgb, RgbImage};
+use rand::rngs::{StdRng, ThreadRng};
+use rand::{Rng, SeedableRng};
 use rand_distr::num_traits::AsPrimitive;
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
+use crate::adaptive::AdaptiveSampling;
+#[cfg(feature = "oidn")]
+use crate::aov::denoiser_guide;
+use crate::aov::{AovPixel, RenderBuffers};
+use crate::bluenoise::BlueNoiseMask;
+use crate::camera::{Camera, DepthMap};
+use crate::caustics::CausticsPass;
 use crate::color::Color;
+use crate::contact;
+#[cfg(feature = "oidn")]
+use crate::denoise;
+use crate::film::{Film, FilmPrecision, NonFinitePolicy, Pixel, RawImage, ToneMapper};
+use crate::gradient::GradientDomain;
+use crate::guiding::{Guide, PathGuiding};
+use crate::irradiance::IrradianceCaching;
 use crate::light::Ray;
+use crate::material::{Material, ShadingContext};
 use crate::object::Object;
+use crate::output::TileSink;
+use crate::progressive::{Checkpoint, Progressive};
+use crate::roi::PrioritySampling;
+use crate::sampler::{PixelSampler, SamplerKind};
+use crate::sampling::{self, RngStream};
+use crate::scene::Scene;
 use crate::shape::HitRecord;
-use crate::{camera::Camera, scene::Scene};
+use crate::tile::{self, Tile, TileOrder};
+use crate::volume::{self, Volume};
+use crate::wavefront;
+
+/// Shading applied by the geometry preview
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum GeometryShading {
+    /// Flat material color
+    #[default]
+    Flat,
+    /// Material color scaled by the facing ratio |N·V|, so that silhouettes
+    /// and curvature remain readable
+    FacingRatio,
+}
 
-pub fn render_geometry(scene: &Scene, camera: &Camera) -> RgbImage {
+/// Render a quick preview of the scene geometry without light transport.
+/// Each pixel is sampled `samples` times with random sub-pixel jitter, or
+/// with offsets spread by a blue-noise mask if there is one.
+pub fn render_geometry(
+    scene: &Scene,
+    camera: &Camera,
+    samples: u32,
+    shading: GeometryShading,
+    blue_noise: Option<&BlueNoiseMask>,
+) -> RgbImage {
     let (w, h) = camera.resolution();
-    let mut image = image::RgbImage::new(w, h);
+    let samples = samples.max(1);
 
-    image
-        .enumerate_pixels_mut()
-        .par_bridge()
-        .for_each(|(i, j, rgb)| {
-            let mut rng = rand::thread_rng();
-            let ray = camera.cast_ray(i, j, &mut rng).expect("Expected a Ray");
+    let render_pixel = |i: u32, j: u32, rng: &mut ThreadRng| {
+        let mut color = Color::zeros();
 
-            let closest_hit = get_closest_hit(&scene.objects, &ray);
+        for sample in 0..samples {
+            let offset = match blue_noise {
+                _ if samples == 1 => (0.0, 0.0),
+                Some(mask) => mask.pixel_offset(i, j, sample),
+                None => (rng.gen::<f64>() - 0.5, rng.gen::<f64>() - 0.5),
+            };
+            let ray = camera
+                .cast_ray_jittered(i, j, offset, rng)
+                .expect("Expected a Ray");
 
-            // Indirect
-            let color = match closest_hit {
-                None => scene.background_color,
-                Some((record, object)) => object.material.color,
+            let hit = get_closest_hit(&scene.objects, &ray).map(|(record, object, id)| {
+                let ctx = ShadingContext::new(&record, id).viewed_along(&ray.direction);
+                (ctx, object.material_at(record.material).albedo(&ctx))
+            });
+            let seen = match &hit {
+                None => scene.background(&ray.direction),
+                Some((_, albedo)) if shading == GeometryShading::Flat => *albedo,
+                Some((ctx, albedo)) => ctx.normal.dot(&ray.direction).abs() * albedo,
             };
+            let ctx = hit.as_ref().map(|(ctx, _)| ctx);
+            color += contact::attenuation(scene, &ray, ctx, rng) * seen;
+        }
+        color /= samples as f64;
+        Rgb([color.x.as_(), color.y.as_(), color.z.as_()])
+    };
 
-            rgb[0] = color.x.as_();
-            rgb[1] = color.y.as_();
-            rgb[2] = color.z.as_();
-        });
+    let tiles = tile::generate_tiles(w, h, TILE_SIZE, TileOrder::default());
+    let rendered: Vec<(Tile, Vec<Rgb<u8>>)> = tiles
+        .into_par_iter()
+        .map(|tile| {
+            let mut rng = rand::thread_rng();
+            let pixels = tile
+                .pixels()
+                .map(|(i, j)| render_pixel(i, j, &mut rng))
+                .collect();
+            (tile, pixels)
+        })
+        .collect();
 
+    let mut image = RgbImage::new(w, h);
+    for (tile, pixels) in rendered {
+        for ((i, j), rgb) in tile.pixels().zip(pixels) {
+            image.put_pixel(i, j, rgb);
+        }
+    }
     image
 }
 
-fn get_closest_hit<'a>(objects: &'a Vec<Object>, ray: &Ray) -> Option<(HitRecord, &'a Object)> {
+/// Render the depth, along the viewing axis, of the closest surface at the
+/// center of each pixel. Pixels that see no geometry get infinite depth.
+pub fn render_depth(scene: &Scene, camera: &Camera) -> DepthMap {
+    let (w, h) = camera.resolution();
+    let depths = (0..w * h)
+        .into_par_iter()
+        .map(|index| {
+            let ray = camera
+                .center_ray(index % w, index / w)
+                .expect("Expected a Ray");
+            match get_closest_hit(&scene.objects, &ray) {
+                Some((record, _, _)) => record.ray_t * ray.direction.dot(&camera.direction()),
+                None => f64::INFINITY,
+            }
+        })
+        .collect();
+
+    DepthMap::new(w, h, depths).expect("Depth map matches the camera resolution")
+}
+
+/// Find the closest hit along a ray. Returns the hit, the object and its index.
+pub(crate) fn get_closest_hit<'a>(
+    objects: &'a [Object],
+    ray: &Ray,
+) -> Option<(HitRecord, &'a Object, usize)> {
+    get_closest_hit_where(objects, ray, |_| true)
+}
+
+/// Find the closest hit along a ray among the objects whose index passes
+/// `filter`
+pub(crate) fn get_closest_hit_where<'a>(
+    objects: &'a [Object],
+    ray: &Ray,
+    filter: impl Fn(usize) -> bool,
+) -> Option<(HitRecord, &'a Object, usize)> {
     let mut closest_hit = HitRecord::new();
     let mut obj = None;
 
-    for object in objects {
-        let hit = object.shape.intersect(&ray);
+    for (id, object) in objects.iter().enumerate() {
+        if !filter(id) {
+            continue;
+        }
+        let hit = object.shape.intersect(ray);
         if hit.is_none() {
             continue;
         }
 
         let hit = hit.unwrap();
         if hit.ray_t < closest_hit.ray_t {
-            let hit = hit;
             closest_hit = hit;
-            obj = Some(object);
+            obj = Some((object, id));
         }
     }
 
-    Some((closest_hit, obj?))
+    let (object, id) = obj?;
+    Some((closest_hit, object, id))
+}
+
+/// Default side of the square tiles the image is split into, in pixels
+pub const TILE_SIZE: u32 = 32;
+
+/// Distance by which scattered rays are moved off the surface, so that they
+/// don't hit it again because of rounding errors
+pub(crate) const SURFACE_OFFSET: f64 = 1e-6;
+
+/// What a render worker keeps from pixel to pixel
+struct Worker<'a> {
+    guide: Option<Guide<'a>>,
+    scratch: Bump, // Transient data of a path, see `PathTracer::trace_path`
+}
+
+/// State carried along a path
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PathState {
+    depth: u32,
+    inside: bool, // Travelling inside a dielectric
+    diffuse_bounces: u32,
+    specular_chain: bool,    // Only specular bounces since the last diffuse one
+    absorption: Color,       // Absorption coefficient of the medium the path is in
+    bounce_pdf: Option<f64>, // Pdf of the last bounce if lights were also sampled there
+    receiver: Option<usize>, // Object the path last scattered off, for light linking
+    lights_sampled: bool,    // The last vertex sampled the lights and the bounces don't
+}
+
+impl PathState {
+    fn after_specular(self, transmitted: bool) -> Self {
+        Self {
+            depth: self.depth + 1,
+            inside: self.inside ^ transmitted,
+            specular_chain: self.diffuse_bounces > 0,
+            bounce_pdf: None,
+            lights_sampled: false,
+            ..self
+        }
+    }
+
+    /// Go through a specular surface, entering or leaving the medium behind
+    /// it when the light is transmitted
+    fn through_surface(self, transmitted: bool, material: &Material) -> Self {
+        let state = self.after_specular(transmitted);
+        match (transmitted, state.inside) {
+            (true, true) => Self {
+                absorption: material.absorption,
+                ..state
+            },
+            (true, false) => Self {
+                absorption: Color::zeros(),
+                ..state
+            },
+            (false, _) => state,
+        }
+    }
+
+    /// Bounces of the path so far
+    pub(crate) fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Fraction of the light that reaches the end of a segment of the path
+    pub(crate) fn transmittance(&self, distance: f64) -> Color {
+        Material::transmittance(&self.absorption, distance)
+    }
+
+    fn after_diffuse(self) -> Self {
+        Self {
+            depth: self.depth + 1,
+            diffuse_bounces: self.diffuse_bounces + 1,
+            specular_chain: false,
+            bounce_pdf: None,
+            lights_sampled: false,
+            ..self
+        }
+    }
+
+    /// Diffuse bounce at a surface where lights were sampled too, in a
+    /// direction sampled with the given pdf
+    fn after_surface_bounce(self, pdf: f64) -> Self {
+        Self {
+            bounce_pdf: Some(pdf),
+            ..self.after_diffuse()
+        }
+    }
+
+    /// Light reaching the camera through one diffuse bounce and a chain of
+    /// specular ones, which is what the caustics pass estimates
+    fn is_caustic(&self) -> bool {
+        self.diffuse_bounces == 1 && self.specular_chain
+    }
+}
+
+/// Continuation of a path at a surface hit
+pub(crate) struct Bounce {
+    pub ray: Ray,
+    pub weight: Color, // Scattering weight, multiplies the path throughput
+    pub state: PathState,
+    pub guided_pdf: Option<f64>, // Pdf of the direction, if sampled by the guide
 }
 
+/// Point sampled on a light and visible from the surface being shaded
+pub(crate) struct LightSample {
+    direction: glm::DVec3, // From the surface to the light
+    radiance: Color,       // Arriving at the surface
+    pdf: f64,              // With respect to solid angle
+    mis: bool,             // Whether the bounces can find the light too
+}
+
+impl LightSample {
+    /// Light reflected towards the path, weighted against finding the same
+    /// light with the bounce
+    pub(crate) fn reflected(
+        &self,
+        ray: &Ray,
+        material: &Material,
+        ctx: &ShadingContext,
+        guide: Option<&Guide>,
+    ) -> Color {
+        let vout = -ray.direction;
+        let bsdf = material.eval(ctx, &self.direction, &vout);
+        if bsdf == Color::zeros() {
+            return Color::zeros();
+        }
+
+        let bounce_pdf = match guide.filter(|_| material.bsdf.is_none()) {
+            Some(guide) => guide.pdf(&ctx.position, &facing(&ctx.normal, ray), &self.direction),
+            None => material.pdf(&ctx.normal, &vout, &self.direction),
+        };
+        let weight = if self.mis {
+            sampling::power_heuristic(self.pdf, bounce_pdf)
+        } else {
+            1.0
+        };
+        let cos_theta = ctx.normal.dot(&self.direction).abs();
+        bsdf.component_mul(&self.radiance) * (weight * cos_theta / self.pdf)
+    }
+}
+
+/// Whether light sampling can pick an object: it has an emissive material
+/// and a shape whose surface can be sampled. Emissive planes are only found
+/// by the bounces.
+fn is_light(object: &Object) -> bool {
+    object
+        .all_materials()
+        .any(|material| material.emittance > 0.0)
+        && object.shape.sample_surface(glm::DVec2::zeros()).is_some()
+}
+
+/// Replace the colors of a film by the denoised ones. Renders that don't
+/// collect AOVs are denoised without them.
+#[cfg(feature = "oidn")]
+fn denoise_film(mut film: Film, aovs: &[AovPixel]) -> Result<Film, String> {
+    let (w, h) = film.resolution();
+    let guide = denoiser_guide(w, h, aovs);
+    let guide = guide.as_ref().map(|(albedo, normal)| (albedo, normal));
+    film.replace_colors(&denoise::denoise(&film.to_raw(), guide)?);
+    Ok(film)
+}
+
+/// One of the lights, all of them being equally likely, and the number of
+/// lights
+fn pick_light(scene: &Scene, rng: &mut impl Rng) -> Option<(usize, usize)> {
+    let lights = scene
+        .objects
+        .iter()
+        .enumerate()
+        .filter(|(_, object)| is_light(object));
+    let count = lights.clone().count();
+    if count == 0 {
+        return None;
+    }
+    let (id, _) = lights.clone().nth(rng.gen_range(0..count))?;
+    Some((id, count))
+}
+
+/// Density, with respect to solid angle at `origin`, of sampling `point` of
+/// a light, whose normal is `normal`
+fn light_pdf(
+    scene: &Scene,
+    object: &Object,
+    origin: &glm::DVec3,
+    point: &glm::DVec3,
+    normal: &glm::DVec3,
+) -> f64 {
+    let lights = scene
+        .objects
+        .iter()
+        .filter(|object| is_light(object))
+        .count();
+    object.shape.pdf_from(origin, point, normal) / lights as f64
+}
+
+/// Weight of the emission of object `id` found by a bounce, against finding
+/// the same light by sampling it from the previous surface. Zero if the light
+/// isn't linked to that surface, or if the lights were sampled there without
+/// weighting the bounces.
+pub(crate) fn emission_weight(
+    scene: &Scene,
+    ray: &Ray,
+    record: &HitRecord,
+    id: usize,
+    state: PathState,
+) -> f64 {
+    if state
+        .receiver
+        .is_some_and(|receiver| !scene.illuminates(id, receiver))
+    {
+        return 0.0;
+    }
+    let object = &scene.objects[id];
+    if state.lights_sampled && is_light(object) {
+        return 0.0;
+    }
+    match state.bounce_pdf {
+        // Lights with linked shadows are only found by sampling them
+        Some(_) if is_light(object) && has_linked_shadows(scene, id) => 0.0,
+        Some(pdf) if is_light(object) => {
+            let light_pdf = light_pdf(scene, object, &ray.origin, &record.point, &record.normal);
+            sampling::power_heuristic(pdf, light_pdf)
+        }
+        _ => 1.0,
+    }
+}
+
+/// Whether only some objects block the light of object `light`
+fn has_linked_shadows(scene: &Scene, light: usize) -> bool {
+    scene
+        .light_link(light)
+        .is_some_and(|link| link.shadowed_by.is_some())
+}
+
+/// Normal on the side of the surface the ray comes from
+fn facing(normal: &glm::DVec3, ray: &Ray) -> glm::DVec3 {
+    if normal.dot(&ray.direction) > 0.0 {
+        -normal
+    } else {
+        *normal
+    }
+}
+
+/// Organization of the render loop
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RenderLoop {
+    /// Each worker traces whole paths, one after the other
+    #[default]
+    Megakernel,
+    /// Experimental: all the paths of a tile advance together, one bounce at
+    /// a time, with intersection and shading as separate phases over large
+    /// ray queues. Path guiding is not supported in this mode.
+    Wavefront,
+}
+
+/// Callback with the number of finished tiles and the total number of tiles
+pub type TileProgress = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// Callback with a finished tile, its pixels and their AOVs
+type TileWithAovs<'a> = dyn Fn(&Tile, &[Pixel], &[AovPixel]) + Sync + 'a;
+
+#[derive(Clone)]
 pub struct PathTracer {
-    spp: u32,
+    pub(crate) spp: u32,
     max_depth: u32,
+    pub(crate) non_finite_policy: NonFinitePolicy,
+    pub(crate) tile_size: u32,
+    tile_order: TileOrder,
+    progress: Option<TileProgress>,
+    priority: Option<PrioritySampling>,
+    adaptive: Option<AdaptiveSampling>,
+    caustics: Option<CausticsPass>,
+    blue_noise: Option<Arc<BlueNoiseMask>>,
+    gradient_domain: Option<GradientDomain>,
+    path_guiding: Option<PathGuiding>,
+    render_loop: RenderLoop,
+    sampler: SamplerKind,
+    equiangular: bool,
+    first_bounce_samples: u32,
+    indirect_clamp: Option<f64>,
+    outlier_rejection: Option<f64>,
+    irradiance_caching: Option<IrradianceCaching>,
+    aovs: bool, // Collect the AOVs of the camera samples
+    #[cfg(feature = "oidn")]
+    denoise: bool,
+    pub(crate) film_precision: FilmPrecision,
+    tone_mapper: ToneMapper,
+    seed: Option<u64>,
 }
 
 impl Default for PathTracer {
@@ -86,6 +485,29 @@ impl Default for PathTracer {
         Self {
             spp: 16,
             max_depth: 5,
+            non_finite_policy: NonFinitePolicy::default(),
+            tile_size: TILE_SIZE,
+            tile_order: TileOrder::default(),
+            progress: None,
+            priority: None,
+            adaptive: None,
+            caustics: None,
+            blue_noise: None,
+            gradient_domain: None,
+            path_guiding: None,
+            render_loop: RenderLoop::default(),
+            sampler: SamplerKind::default(),
+            equiangular: false,
+            first_bounce_samples: 1,
+            indirect_clamp: None,
+            outlier_rejection: None,
+            irradiance_caching: None,
+            aovs: false,
+            #[cfg(feature = "oidn")]
+            denoise: false,
+            film_precision: FilmPrecision::default(),
+            tone_mapper: ToneMapper::default(),
+            seed: None,
         }
     }
 }
@@ -105,53 +527,1855 @@ impl PathTracer {
         self
     }
 
+    /// How samples with NaN or infinite radiance are handled
+    pub fn non_finite_policy(&mut self, policy: NonFinitePolicy) -> &mut Self {
+        self.non_finite_policy = policy;
+        self
+    }
+
+    /// Mapping of the rendered radiance to the pixels of `render`
+    pub fn tone_mapper(&mut self, tone_mapper: ToneMapper) -> &mut Self {
+        self.tone_mapper = tone_mapper;
+        self
+    }
+
+    /// Seed of the random numbers. Renders with the same seed and settings
+    /// produce the same image regardless of the number of threads. Without
+    /// one, every render draws a new seed.
+    pub fn seed(&mut self, seed: Option<u64>) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Seed of one render, see `seed`
+    pub(crate) fn render_seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| rand::thread_rng().gen())
+    }
+
     pub fn render(&self, scene: &Scene, camera: &Camera) -> RgbImage {
+        self.render_film(scene, camera)
+            .to_image_with(self.tone_mapper)
+    }
+
+    /// Render into plain floats, tone mapped like `render` but not
+    /// quantized. See `Film::to_raw` for the radiance itself.
+    pub fn render_raw(&self, scene: &Scene, camera: &Camera) -> RawImage {
+        self.render_film(scene, camera)
+            .to_raw_with(self.tone_mapper)
+    }
+
+    /// Side of the square tiles the image is split into. Small tiles balance
+    /// the work better between threads, large ones have less overhead.
+    pub fn tile_size(&mut self, size: u32) -> &mut Self {
+        self.tile_size = size.max(1);
+        self
+    }
+
+    /// Order in which image tiles are scheduled. Tiles are always picked
+    /// in this order, whatever the number of threads.
+    pub fn tile_order(&mut self, order: TileOrder) -> &mut Self {
+        self.tile_order = order;
+        self
+    }
+
+    /// Report the progress of renders as their tiles finish. The callback is
+    /// called from the render threads.
+    pub fn progress(&mut self, progress: Option<TileProgress>) -> &mut Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Spend a higher sampling budget on a region of interest
+    pub fn priority_sampling(&mut self, priority: Option<PrioritySampling>) -> &mut Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Keep sampling noisy pixels beyond the samples per pixel, until they
+    /// converge. The wavefront render loop doesn't adapt.
+    pub fn adaptive_sampling(&mut self, adaptive: Option<AdaptiveSampling>) -> &mut Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Estimate caustics with a separate photon pass instead of relying on
+    /// paths finding the lights through specular surfaces
+    pub fn caustics(&mut self, caustics: Option<CausticsPass>) -> &mut Self {
+        self.caustics = caustics;
+        self
+    }
+
+    /// Spread the sub-pixel sample positions with a blue-noise mask.
+    /// Without a mask, all samples go through the pixel centers.
+    pub fn blue_noise(&mut self, mask: Option<Arc<BlueNoiseMask>>) -> &mut Self {
+        self.blue_noise = mask;
+        self
+    }
+
+    /// Experimental: render finite differences between neighbouring pixels
+    /// and reconstruct the image from them. Priority sampling doesn't apply
+    /// in this mode.
+    pub fn gradient_domain(&mut self, gradient: Option<GradientDomain>) -> &mut Self {
+        self.gradient_domain = gradient;
+        self
+    }
+
+    /// Experimental: learn where light comes from in a few training passes
+    /// and sample diffuse bounces accordingly
+    pub fn path_guiding(&mut self, guiding: Option<PathGuiding>) -> &mut Self {
+        self.path_guiding = guiding;
+        self
+    }
+
+    pub fn render_loop(&mut self, render_loop: RenderLoop) -> &mut Self {
+        self.render_loop = render_loop;
+        self
+    }
+
+    /// Source of the random numbers of the camera paths. Low-discrepancy
+    /// sequences converge faster than random numbers at the same number of
+    /// samples. Path guiding and gradient-domain renders always use random
+    /// numbers.
+    pub fn sampler(&mut self, sampler: SamplerKind) -> &mut Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Also estimate the light that the media scatter straight from the lights,
+    /// at points sampled equi-angularly along the rays. Shafts of light in
+    /// volumes lit by small lights converge with far fewer samples.
+    pub fn equiangular_sampling(&mut self, enabled: bool) -> &mut Self {
+        self.equiangular = enabled;
+        self
+    }
+
+    /// Split camera paths into this many bounces at their first surface hit,
+    /// each carrying its share of the path, and go on with a single bounce
+    /// at the next hits. Glossy reflections seen by the camera get less noisy
+    /// for less than the cost of as many camera samples, since the first hit
+    /// and its direct light are shared. Guided paths and the wavefront render
+    /// loop don't split.
+    pub fn first_bounce_samples(&mut self, samples: u32) -> &mut Self {
+        self.first_bounce_samples = samples.max(1);
+        self
+    }
+
+    /// Limit the radiance that a sample collects from light that bounced more
+    /// than once, to the given maximum channel value. Removes the fireflies
+    /// of paths that find small bright lights through specular surfaces, at
+    /// the cost of darkening them.
+    pub fn indirect_clamp(&mut self, limit: Option<f64>) -> &mut Self {
+        self.indirect_clamp = limit;
+        self
+    }
+
+    /// Replace the pixels of the film that are more than `threshold` times as
+    /// bright as all of their neighbours once the render is done. See
+    /// [`Film::reject_outliers`].
+    pub fn outlier_rejection(&mut self, threshold: Option<f64>) -> &mut Self {
+        self.outlier_rejection = threshold;
+        self
+    }
+
+    /// Run the finished film through Open Image Denoise, guided by the albedo
+    /// and normals of the camera samples. Progressive renders denoise the
+    /// film they return, not the one of each pass.
+    ///
+    /// # Panics
+    ///
+    /// `render_film` panics if the denoiser fails, e.g. when there is no
+    /// device to run it on. `render_progressive` returns the error instead.
+    #[cfg(feature = "oidn")]
+    pub fn denoise(&mut self, denoise: bool) -> &mut Self {
+        self.denoise = denoise;
+        self
+    }
+
+    /// Whether the finished film goes through the denoiser
+    fn denoises(&self) -> bool {
+        #[cfg(feature = "oidn")]
+        return self.denoise;
+        #[cfg(not(feature = "oidn"))]
+        false
+    }
+
+    /// Preview quality: render with the indirect light of diffuse surfaces
+    /// interpolated from an irradiance cache, which is smooth and quick but
+    /// biased. Only the first hits of the pixel centers are shaded.
+    pub fn irradiance_caching(&mut self, caching: Option<IrradianceCaching>) -> &mut Self {
+        self.irradiance_caching = caching;
+        self
+    }
+
+    /// Precision of the film, reduce it to save memory on huge images
+    pub fn film_precision(&mut self, precision: FilmPrecision) -> &mut Self {
+        self.film_precision = precision;
+        self
+    }
+
+    /// Bytes of the film of a render with `camera`, along with the AOVs
+    /// collected for `render_buffers` or the denoiser
+    pub fn film_memory(&self, camera: &Camera, buffers: bool) -> usize {
+        let (w, h) = camera.resolution();
+        let pixels = (w as usize) * (h as usize);
+        let mut pixel = self.film_precision.pixel_size();
+        if buffers || self.denoises() {
+            // The AOVs of the samples and the images made of them
+            pixel += std::mem::size_of::<AovPixel>() + 2 * 3 * std::mem::size_of::<f32>();
+            pixel += std::mem::size_of::<f64>();
+        }
+        pixels * pixel
+    }
+
+    /// Render into a film, which keeps the per-pixel sample statistics
+    pub fn render_film(&self, scene: &Scene, camera: &Camera) -> Film {
+        if self.denoises() {
+            // The denoiser needs the AOVs
+            return self.render_buffers(scene, camera).beauty;
+        }
+        self.render_film_with(scene, camera, &|_, _| {})
+    }
+
+    /// Render the film, handing the AOVs of each tile to `on_aovs`
+    fn render_film_with(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        on_aovs: &(dyn Fn(&Tile, &[AovPixel]) + Sync),
+    ) -> Film {
+        if let Some(gradient) = &self.gradient_domain {
+            let mut film = gradient.render(self, scene, camera, self.spp);
+            if let Some(caustics) = &self.caustics {
+                caustics.render(scene, camera, self.render_seed(), &mut film);
+            }
+            return film;
+        }
+        if let Some(caching) = &self.irradiance_caching {
+            return caching.render(self, scene, camera);
+        }
+
+        let (w, h) = camera.resolution();
+        let film = Mutex::new(Film::with_precision(w, h, self.film_precision));
+        self.render_tiles_with_aovs(scene, camera, &|tile, pixels, aovs| {
+            film.lock().unwrap().merge_tile(tile, pixels);
+            on_aovs(tile, aovs);
+        });
+
+        let mut film = film.into_inner().unwrap();
+        if let Some(threshold) = self.outlier_rejection {
+            film.reject_outliers(threshold);
+        }
+        if let Some(caustics) = &self.caustics {
+            caustics.render(scene, camera, self.render_seed(), &mut film);
+        }
+        film
+    }
+
+    /// Render the image along with the world normal, depth and albedo seen by
+    /// its camera samples, e.g. to guide a denoiser. The wavefront render
+    /// loop, gradient-domain renders and irradiance caching leave the AOVs
+    /// empty.
+    pub fn render_buffers(&self, scene: &Scene, camera: &Camera) -> RenderBuffers {
+        let (w, h) = camera.resolution();
+        let mut renderer = self.clone();
+        renderer.aovs = true;
+        let aovs = Mutex::new(vec![AovPixel::default(); (w * h) as usize]);
+        let beauty = renderer.render_film_with(scene, camera, &|tile, tile_aovs| {
+            let mut aovs = aovs.lock().unwrap();
+            for ((i, j), pixel) in tile.pixels().zip(tile_aovs) {
+                aovs[(j * w + i) as usize] = *pixel;
+            }
+        });
+        let aovs = aovs.into_inner().unwrap();
+        #[cfg(feature = "oidn")]
+        let beauty = if self.denoise {
+            denoise_film(beauty, &aovs).unwrap_or_else(|e| panic!("{e}"))
+        } else {
+            beauty
+        };
+        RenderBuffers::new(beauty, &aovs)
+    }
+
+    /// Render in passes of `samples_per_pixel` samples that add up in the
+    /// same film, saving it to the checkpoint file from time to time.
+    /// `on_pass` gets the number of passes done and the film after each
+    /// pass. Renders with a target error stop early once they reach it. A
+    /// resumed render takes the seed of its checkpoint, so that it ends up as
+    /// if it had never stopped. Gradient-domain rendering doesn't apply in
+    /// this mode.
+    pub fn render_progressive(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        progressive: &Progressive,
+        on_pass: &mut dyn FnMut(u32, &Film),
+    ) -> Result<Film, String> {
+        if self.gradient_domain.is_some() {
+            return Err("Gradient-domain renders can't be progressive".to_string());
+        }
+
         let (w, h) = camera.resolution();
-        let mut image = image::RgbImage::new(w, h);
-
-        image
-            .enumerate_pixels_mut()
-            .par_bridge()
-            .for_each(|(i, j, rgb)| {
-                let mut rng = rand::thread_rng();
-                let mut color = Color::zeros();
-                for n in 0..self.spp {
-                    let ray = camera.cast_ray(i, j, &mut rng).expect("Expected a Ray");
-                    color += self.trace_ray(&scene, &ray, 0, &mut rng);
+        let mut checkpoint = match progressive.restore(self.film_precision)? {
+            Some(checkpoint) if checkpoint.film.resolution() != (w, h) => {
+                let (cw, ch) = checkpoint.film.resolution();
+                return Err(format!(
+                    "The checkpoint is a {cw}×{ch} render, the camera renders {w}×{h}"
+                ));
+            }
+            Some(checkpoint) => checkpoint,
+            None => Checkpoint {
+                seed: self.render_seed(),
+                passes: 0,
+                film: Film::with_precision(w, h, self.film_precision),
+            },
+        };
+
+        // For the denoiser. Those of the passes done before a resumed render
+        // stopped are lost.
+        let pixels = if self.denoises() { w * h } else { 0 };
+        let aovs = Mutex::new(vec![AovPixel::default(); pixels as usize]);
+        let mut saved = Instant::now();
+        let converged = |film: &Film| {
+            progressive
+                .target_error
+                .is_some_and(|target| film.relative_error() <= target)
+        };
+        while checkpoint.passes < progressive.passes && !converged(&checkpoint.film) {
+            // Every pass draws new samples
+            let mut pass = self.clone();
+            pass.seed = Some(
+                sampling::sample_rng(
+                    checkpoint.seed,
+                    RngStream::Pass(checkpoint.passes),
+                    (0, 0),
+                    0,
+                )
+                .gen(),
+            );
+            pass.aovs = self.denoises();
+            let film = Mutex::new(&mut checkpoint.film);
+            pass.render_tiles_with_aovs(scene, camera, &|tile, pixels, tile_aovs| {
+                film.lock().unwrap().merge_tile(tile, pixels);
+                let mut aovs = aovs.lock().unwrap();
+                for ((i, j), pixel) in tile.pixels().zip(tile_aovs) {
+                    aovs[(j * w + i) as usize].merge(pixel);
                 }
+            });
+            checkpoint.passes += 1;
+            on_pass(checkpoint.passes, &checkpoint.film);
 
-                rgb[0] += (color.x / self.spp as f64).min(255.0) as u8;
-                rgb[1] += (color.y / self.spp as f64).min(255.0) as u8;
-                rgb[2] += (color.z / self.spp as f64).min(255.0) as u8;
+            if let Some(path) = &progressive.checkpoint {
+                if checkpoint.passes == progressive.passes
+                    || converged(&checkpoint.film)
+                    || saved.elapsed() >= progressive.interval
+                {
+                    checkpoint.save(path)?;
+                    saved = Instant::now();
+                }
+            }
+        }
+
+        let mut film = checkpoint.film;
+        if let Some(threshold) = self.outlier_rejection {
+            film.reject_outliers(threshold);
+        }
+        if let Some(caustics) = &self.caustics {
+            caustics.render(scene, camera, checkpoint.seed, &mut film);
+        }
+        #[cfg(feature = "oidn")]
+        if self.denoise {
+            film = denoise_film(film, &aovs.into_inner().unwrap())?;
+        }
+        Ok(film)
+    }
+
+    /// Render tile by tile, handing each finished tile to a sink instead of
+    /// keeping the whole image in memory. Gradient-domain rendering and the
+    /// caustics pass need the whole image and don't apply here.
+    pub fn render_to_sink(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        sink: &mut dyn TileSink,
+    ) -> Result<(), String> {
+        // Bounded, so that a slow sink holds back the workers instead of
+        // piling up finished tiles
+        let (sender, receiver) = mpsc::sync_channel(rayon::current_num_threads());
+
+        thread::scope(move |s| {
+            s.spawn(move || {
+                self.render_tiles_with(scene, camera, &|tile, pixels| {
+                    // Sending fails once the sink has failed, ignore the rest
+                    let _ = sender.send((*tile, pixels.to_vec()));
+                })
             });
+            for (tile, pixels) in receiver {
+                sink.write_tile(&tile, &pixels)?;
+            }
+            Ok(())
+        })
+    }
 
-        image
+    /// Render all the tiles, calling `on_tile` for each one as soon as it is
+    /// done. Tiles are scheduled in the tile order, but may finish in any
+    /// order.
+    pub(crate) fn render_tiles_with(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        on_tile: &(dyn Fn(&Tile, &[Pixel]) + Sync),
+    ) {
+        self.render_tiles_with_aovs(scene, camera, &|tile, pixels, _| on_tile(tile, pixels))
     }
 
-    fn trace_ray(&self, scene: &Scene, ray: &Ray, counter: u32, rng: &mut ThreadRng) -> Color {
-        let closest_hit = get_closest_hit(&scene.objects, &ray);
+    /// Like `render_tiles_with`, also handing over the AOVs of the pixels,
+    /// which are empty unless they are collected
+    fn render_tiles_with_aovs(&self, scene: &Scene, camera: &Camera, on_tile: &TileWithAovs<'_>) {
+        let (w, h) = camera.resolution();
+        let tiles = tile::generate_tiles(w, h, self.tile_size, self.tile_order);
+        let seed = self.render_seed();
+        let finished = AtomicUsize::new(0);
+        let on_tile = |tile: &Tile, pixels: &[Pixel], aovs: &[AovPixel]| {
+            on_tile(tile, pixels, aovs);
+            let finished = finished.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(progress) = &self.progress {
+                progress(finished, tiles.len());
+            }
+        };
+        let sample_counts = self.priority.map(|priority| {
+            priority.sample_counts(scene, camera, self.spp, &mut StdRng::seed_from_u64(seed))
+        });
 
-        // Indirect
-        match closest_hit {
-            None => scene.background_color,
-            Some((record, object)) => {
-                let material = &object.material;
-                let vout = &-ray.direction;
-                let vin = material
-                    .sample_bounce(&record.normal, vout, rng)
-                    .normalize();
+        if self.render_loop == RenderLoop::Wavefront {
+            for tile in &tiles {
+                let counts = sample_counts.as_deref();
+                let pixels = wavefront::render_tile(self, scene, camera, tile, counts, seed);
+                let aovs = vec![AovPixel::default(); if self.aovs { pixels.len() } else { 0 }];
+                on_tile(tile, &pixels, &aovs);
+            }
+            return;
+        }
 
-                let mut color = material.emittance * material.color;
+        let next_tile = AtomicUsize::new(0);
+        let guide_tree = self
+            .path_guiding
+            .and_then(|guiding| guiding.train(self, scene, camera, seed));
 
-                if counter < self.max_depth {
-                    let new_ray = Ray::new(record.point, vin);
-                    color += material
-                        .bsdf(&record.normal, &vin, vout)
-                        .component_mul(&self.trace_ray(scene, &new_ray, counter + 1, rng));
+        // Each worker pulls the next tile in order until there are none left.
+        // Transient data lives in a per-worker arena that is reset after each
+        // tile, so the hot loop doesn't go through the global allocator.
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| {
+                let mut worker = Worker {
+                    guide: self
+                        .path_guiding
+                        .map(|guiding| Guide::new(guide_tree.as_ref(), guiding.guide_probability)),
+                    scratch: Bump::new(),
+                };
+                let mut arena = Bump::new();
+                while let Some(tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                    let mut pixels = BumpVec::with_capacity_in(tile.num_pixels(), &arena);
+                    let mut aovs = BumpVec::new_in(&arena);
+                    for (i, j) in tile.pixels() {
+                        let spp = match &sample_counts {
+                            Some(counts) => counts[(j * w + i) as usize],
+                            None => self.spp,
+                        };
+                        let (pixel, aov) =
+                            self.render_pixel(scene, camera, (i, j), spp, &mut worker, seed);
+                        pixels.push(pixel);
+                        if self.aovs {
+                            aovs.push(aov);
+                        }
+                    }
+                    on_tile(tile, &pixels, &aovs);
+                    drop(pixels);
+                    drop(aovs);
+                    arena.reset();
                 }
+            });
+    }
 
-                color
+    fn render_pixel(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        (i, j): (u32, u32),
+        spp: u32,
+        worker: &mut Worker,
+        seed: u64,
+    ) -> (Pixel, AovPixel) {
+        let max_spp = self
+            .adaptive
+            .map_or(spp, |adaptive| adaptive.max_spp.max(spp));
+        let mut pixel = Pixel::default();
+        let mut aovs = AovPixel::default();
+        for sample in 0..max_spp {
+            if self
+                .adaptive
+                .is_some_and(|adaptive| adaptive.is_done(&pixel, sample, spp))
+            {
+                break;
+            }
+            let (mut rng, offset) = self.start_sample((i, j), sample, seed);
+            let ray = camera
+                .cast_ray_jittered(i, j, offset, &mut rng)
+                .expect("Expected a Ray");
+            let color = self.trace_path(
+                scene,
+                &ray,
+                worker.guide.as_mut(),
+                &mut worker.scratch,
+                &mut rng,
+            );
+            pixel.add_sample(color, self.non_finite_policy);
+            if self.aovs {
+                aovs.add_sample(scene, camera, &ray);
             }
         }
+        (pixel, aovs)
+    }
+
+    /// Random numbers of the `sample`th sample of a pixel, and its sub-pixel
+    /// position. Low-discrepancy samplers place the samples with their first
+    /// two dimensions, unless there is a blue noise mask.
+    pub(crate) fn start_sample(
+        &self,
+        (i, j): (u32, u32),
+        sample: u32,
+        seed: u64,
+    ) -> (PixelSampler, (f64, f64)) {
+        let mut sampler = PixelSampler::new(self.sampler, seed, (i, j), sample);
+        let offset = match self.sampler {
+            SamplerKind::Halton | SamplerKind::Sobol if self.blue_noise.is_none() => {
+                (sampler.gen::<f64>() - 0.5, sampler.gen::<f64>() - 0.5)
+            }
+            _ => self.pixel_offset(i, j, sample, seed),
+        };
+        (sampler, offset)
+    }
+
+    /// Sub-pixel position of the `sample`th sample of pixel (i, j) in the
+    /// render drawn from `seed`. Without a blue noise mask, the samples are
+    /// stratified over the pixel in a pattern that changes with the seed,
+    /// except for single sample renders that go through the pixel centers.
+    pub(crate) fn pixel_offset(&self, i: u32, j: u32, sample: u32, seed: u64) -> (f64, f64) {
+        match &self.blue_noise {
+            Some(mask) => mask.pixel_offset(i, j, sample),
+            None if self.spp <= 1 && self.adaptive.is_none() => (0.0, 0.0),
+            None => {
+                let pattern = i.wrapping_mul(0x8da6_b343)
+                    ^ j.wrapping_mul(0xd816_3841)
+                    ^ (seed ^ seed >> 32) as u32;
+                sampling::stratified_offset(sample, self.spp, pattern)
+            }
+        }
+    }
+
+    /// Radiance arriving at the camera along a ray. The transient data of
+    /// the bounces is allocated in `scratch`, which is reset when the path
+    /// ends.
+    pub(crate) fn trace_path(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        guide: Option<&mut Guide>,
+        scratch: &mut Bump,
+        rng: &mut impl Rng,
+    ) -> Color {
+        let path = (PathState::default(), Color::new(1.0, 1.0, 1.0));
+        let radiance = self.trace_path_from(scene, ray, path, guide, scratch, rng);
+        scratch.reset();
+        radiance
+    }
+
+    /// Indirect light arriving at a diffuse surface along a ray leaving it,
+    /// for a surface whose direct light is sampled separately: the emission
+    /// of the lights that the ray hits first is left out. `scratch` is used
+    /// as in `trace_path`.
+    pub(crate) fn trace_indirect(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        scratch: &mut Bump,
+        rng: &mut impl Rng,
+    ) -> Color {
+        let state = PathState {
+            lights_sampled: true,
+            ..PathState::default().after_diffuse()
+        };
+        let path = (state, Color::new(1.0, 1.0, 1.0));
+        let radiance = self.trace_path_from(scene, ray, path, None, scratch, rng);
+        scratch.reset();
+        radiance
+    }
+
+    /// Radiance arriving along a ray, at the vertex of a path in `state`,
+    /// weighted by the `throughput` of the path so far
+    fn trace_path_from(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        (mut state, mut throughput): (PathState, Color),
+        mut guide: Option<&mut Guide>,
+        scratch: &Bump,
+        rng: &mut impl Rng,
+    ) -> Color {
+        let mut radiance = Color::zeros();
+        let mut ray = ray.continued(ray.origin, ray.direction);
+
+        loop {
+            let hit = get_closest_hit(&scene.objects, &ray);
+            let distance = hit
+                .as_ref()
+                .map_or(f64::INFINITY, |(record, ..)| record.ray_t);
+            let inscattered = self.inscattered_light(scene, &ray, distance, state, rng);
+            radiance += self.contribution(&throughput, &inscattered, state.depth + 1);
+            if let Some(guide) = guide.as_deref_mut() {
+                guide.add_radiance(&inscattered);
+            }
+
+            if let Some(collision) = volume::sample_collision(&scene.volumes, &ray, distance, rng) {
+                let volume = &scene.volumes[collision.volume];
+                let emitted = volume.emitted(&ray.point_at(collision.distance));
+                radiance += self.contribution(&throughput, &emitted, state.depth);
+                if let Some(guide) = guide.as_deref_mut() {
+                    guide.add_radiance(&emitted);
+                }
+
+                let Some(bounce) =
+                    self.scatter_in_volume(&ray, volume, collision.distance, state, rng)
+                else {
+                    break;
+                };
+                if let Some(guide) = guide.as_deref_mut() {
+                    guide.attenuate(&bounce.weight);
+                }
+                throughput = throughput.component_mul(&bounce.weight);
+                ray = bounce.ray;
+                state = bounce.state;
+                continue;
+            }
+
+            let Some((record, object, id)) = hit else {
+                let background = scene.background(&ray.direction);
+                radiance += self.contribution(&throughput, &background, state.depth);
+                if let Some(guide) = guide.as_deref_mut() {
+                    guide.add_radiance(&background);
+                }
+                break;
+            };
+
+            // Light absorbed by the medium the path went through, e.g. water
+            let absorbed = state.transmittance(record.ray_t);
+            throughput = throughput.component_mul(&absorbed);
+            if let Some(guide) = guide.as_deref_mut() {
+                guide.attenuate(&absorbed);
+            }
+
+            // Aerial perspective: the air between the surface and the previous
+            // vertex dims what is behind it and adds the light of the sky
+            if let Some(atmosphere) = &scene.atmosphere {
+                let air = atmosphere.scattering(&ray.origin, &ray.direction, record.ray_t);
+                radiance += self.contribution(&throughput, &air.inscattered, state.depth + 1);
+                throughput = throughput.component_mul(&air.transmittance);
+                if let Some(guide) = guide.as_deref_mut() {
+                    guide.add_radiance(&air.inscattered);
+                    guide.attenuate(&air.transmittance);
+                }
+            }
+
+            let material = object.material_at(record.material);
+            let mut ctx = ShadingContext::new(&record, id)
+                .viewed_along(&ray.direction)
+                .with_footprint(ray.footprint_at(record.ray_t) * record.uv_density);
+            if let Some(bevel) = &material.bevel {
+                ctx.normal = bevel.normal(&scene.objects, &ctx, scratch, rng);
+            }
+
+            let emitted = self.emitted(scene, material, &ctx, state)
+                * emission_weight(scene, &ray, &record, id, state);
+            radiance += self.contribution(&throughput, &emitted, state.depth);
+            if let Some(guide) = guide.as_deref_mut() {
+                guide.add_radiance(&emitted);
+            }
+
+            if let Some(light) = self.sample_light(scene, material, &ctx, state, rng) {
+                let direct = light.reflected(&ray, material, &ctx, guide.as_deref());
+                radiance += self.contribution(&throughput, &direct, state.depth + 1);
+                if let Some(guide) = guide.as_deref_mut() {
+                    guide.add_radiance(&direct);
+                }
+            }
+
+            // Splitting: the bounces share the hit and its direct light
+            let splits = self.first_bounce_samples;
+            if state.depth == 0 && splits > 1 && guide.is_none() {
+                for _ in 0..splits {
+                    if let Some(bounce) = self.scatter(&ray, material, &ctx, state, None, rng) {
+                        let throughput = throughput.component_mul(&bounce.weight);
+                        radiance += self.trace_path_from(
+                            scene,
+                            &bounce.ray,
+                            (bounce.state, throughput),
+                            None,
+                            scratch,
+                            rng,
+                        ) / splits as f64;
+                    }
+                }
+                break;
+            }
+
+            let Some(bounce) = self.scatter(&ray, material, &ctx, state, guide.as_deref(), rng)
+            else {
+                break;
+            };
+            if let Some(guide) = guide.as_deref_mut() {
+                guide.attenuate(&bounce.weight);
+                if let Some(pdf) = bounce.guided_pdf {
+                    guide.add_vertex(&ctx.position, &bounce.ray.direction, pdf);
+                }
+            }
+            throughput = throughput.component_mul(&bounce.weight);
+            ray = bounce.ray;
+            state = bounce.state;
+        }
+
+        if let Some(guide) = guide {
+            guide.finish_path();
+        }
+        radiance
+    }
+
+    /// Radiance that a path with `throughput` collects from light scattered
+    /// `bounces` times on its way to the camera, limited to the indirect
+    /// clamp when it has bounced more than once
+    pub(crate) fn contribution(&self, throughput: &Color, radiance: &Color, bounces: u32) -> Color {
+        let contribution = throughput.component_mul(radiance);
+        match self.indirect_clamp {
+            Some(limit) if bounces > 1 && contribution.max() > limit => {
+                contribution * (limit / contribution.max())
+            }
+            _ => contribution,
+        }
+    }
+
+    /// Light emitted by a surface towards the path. Caustic paths don't
+    /// collect the emission of the objects whose photons the caustics pass
+    /// shoots, see `CausticsPass::emits_photons`.
+    pub(crate) fn emitted(
+        &self,
+        scene: &Scene,
+        material: &Material,
+        ctx: &ShadingContext,
+        state: PathState,
+    ) -> Color {
+        if self.caustics.is_some()
+            && state.is_caustic()
+            && CausticsPass::emits_photons(&scene.objects[ctx.object_id])
+        {
+            Color::zeros()
+        } else {
+            material.emittance * material.albedo(ctx)
+        }
+    }
+
+    /// Sample a point on one of the lights, all of them being equally likely,
+    /// and trace a shadow ray to it. Nothing is sampled from specular
+    /// surfaces, at the end of the path, when the point is hidden or when the
+    /// light isn't linked to the surface.
+    pub(crate) fn sample_light(
+        &self,
+        scene: &Scene,
+        material: &Material,
+        ctx: &ShadingContext,
+        state: PathState,
+        rng: &mut impl Rng,
+    ) -> Option<LightSample> {
+        if state.depth >= self.max_depth || material.specular().is_some() {
+            return None;
+        }
+        let (light_id, count) = pick_light(scene, rng)?;
+        if !scene.illuminates(light_id, ctx.object_id) {
+            return None;
+        }
+        self.sample_light_from(scene, light_id, count, &ctx.position, state, rng)
+    }
+
+    /// Sample a point of light `light_id`, one of `count` lights, as seen
+    /// from `position`, and trace a shadow ray to it
+    fn sample_light_from(
+        &self,
+        scene: &Scene,
+        light_id: usize,
+        count: usize,
+        position: &glm::DVec3,
+        state: PathState,
+        rng: &mut impl Rng,
+    ) -> Option<LightSample> {
+        let sample = scene.objects[light_id]
+            .shape
+            .sample_from(position, glm::DVec2::new(rng.gen(), rng.gen()))?;
+
+        let to_light = sample.point - position;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+        let cos_light = sample.normal.dot(&direction);
+        if distance <= SURFACE_OFFSET || cos_light == 0.0 {
+            return None;
+        }
+
+        let ray = Ray::new(position + SURFACE_OFFSET * direction, direction);
+        let (record, object, id) = get_closest_hit_where(&scene.objects, &ray, |id| {
+            id == light_id || scene.is_shadowed_by(light_id, id)
+        })?;
+        if id != light_id || glm::distance(&record.point, &sample.point) > 1e-6 * (1.0 + distance) {
+            return None;
+        }
+
+        let emitted = self.emitted(
+            scene,
+            object.material_at(record.material),
+            &ShadingContext::new(&record, id).viewed_along(&ray.direction),
+            state.after_diffuse(),
+        );
+        let mut radiance = emitted.component_mul(&state.transmittance(record.ray_t));
+        if let Some(atmosphere) = &scene.atmosphere {
+            let air = atmosphere.scattering(&ray.origin, &ray.direction, record.ray_t);
+            radiance = radiance.component_mul(&air.transmittance);
+        }
+        radiance *= volume::transmittance(&scene.volumes, &ray, record.ray_t, rng);
+
+        Some(LightSample {
+            direction,
+            radiance,
+            pdf: sample.pdf / count as f64,
+            mis: !has_linked_shadows(scene, light_id),
+        })
+    }
+
+    /// Light scattered towards the path by the media along the ray, before
+    /// `distance`, straight from one of the lights. The point of the ray is
+    /// sampled equi-angularly around the light and the light is sampled from
+    /// there. Only estimated with equi-angular sampling.
+    pub(crate) fn inscattered_light(
+        &self,
+        scene: &Scene,
+        ray: &Ray,
+        distance: f64,
+        state: PathState,
+        rng: &mut impl Rng,
+    ) -> Color {
+        if !self.equiangular || state.depth >= self.max_depth {
+            return Color::zeros();
+        }
+        let Some((near, far)) = volume::segment(&scene.volumes, ray, distance) else {
+            return Color::zeros();
+        };
+        let Some((light_id, count)) = pick_light(scene, rng) else {
+            return Color::zeros();
+        };
+        let Some(center) = scene.objects[light_id].shape.centroid() else {
+            return Color::zeros();
+        };
+
+        let (t, distance_pdf) = volume::equiangular_distance(ray, &center, near, far, rng.gen());
+        let point = ray.point_at(t);
+        let Some(light) = self.sample_light_from(scene, light_id, count, &point, state, rng) else {
+            return Color::zeros();
+        };
+        let scattered = scene
+            .volumes
+            .iter()
+            .map(|volume| {
+                volume.scattering_at(&point) * volume.phase(&ray.direction, &light.direction)
+            })
+            .sum::<Color>();
+        if scattered == Color::zeros() {
+            return Color::zeros();
+        }
+
+        let transmittance =
+            state.transmittance(t) * volume::transmittance(&scene.volumes, ray, t, rng);
+        scattered
+            .component_mul(&light.radiance)
+            .component_mul(&transmittance)
+            / (distance_pdf * light.pdf)
+    }
+
+    /// Continue a path that collided with a medium at `distance`, or end it
+    pub(crate) fn scatter_in_volume(
+        &self,
+        ray: &Ray,
+        volume: &Volume,
+        distance: f64,
+        state: PathState,
+        rng: &mut impl Rng,
+    ) -> Option<Bounce> {
+        if state.depth >= self.max_depth {
+            return None;
+        }
+        let state = PathState {
+            receiver: None,
+            ..state
+        };
+        let direction = volume.sample_phase(&ray.direction, rng);
+        Some(Bounce {
+            ray: ray.continued(ray.point_at(distance), direction),
+            weight: volume.color / 255.0,
+            state: PathState {
+                lights_sampled: self.equiangular,
+                ..state.after_diffuse()
+            },
+            guided_pdf: None,
+        })
+    }
+
+    /// Continue a path at a surface hit, or end it
+    pub(crate) fn scatter(
+        &self,
+        ray: &Ray,
+        material: &Material,
+        ctx: &ShadingContext,
+        state: PathState,
+        guide: Option<&Guide>,
+        rng: &mut impl Rng,
+    ) -> Option<Bounce> {
+        if state.depth >= self.max_depth {
+            return None;
+        }
+        let state = PathState {
+            receiver: Some(ctx.object_id),
+            ..state
+        };
+
+        if let Some((direction, transmitted)) =
+            material
+                .surface()
+                .scatter(&ctx.normal, &ray.direction, state.inside, rng)
+        {
+            return Some(Bounce {
+                ray: ray.continued(ctx.position + SURFACE_OFFSET * direction, direction),
+                weight: material.albedo(ctx) / 255.0,
+                state: state.through_surface(transmitted, material),
+                guided_pdf: None,
+            });
+        }
+
+        // Lambertian bounce sampled from the guiding distribution
+        if let Some(guide) = guide.filter(|_| material.bsdf.is_none()) {
+            let normal = facing(&ctx.normal, ray);
+            let (direction, pdf) = guide.sample_bounce(&ctx.position, &normal, rng);
+            let cos_theta = direction.dot(&normal);
+            if cos_theta <= 0.0 || pdf <= 0.0 {
+                return None;
+            }
+            return Some(Bounce {
+                ray: ray.continued(ctx.position + SURFACE_OFFSET * direction, direction),
+                weight: (cos_theta / (PI * pdf)) * material.albedo(ctx) / 255.0,
+                state: state.after_surface_bounce(pdf),
+                guided_pdf: Some(pdf),
+            });
+        }
+
+        let vout = -ray.direction;
+        let (vin, pdf) = material.sample(&ctx.normal, &vout, rng);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let cos_theta = ctx.normal.dot(&vin).abs();
+        Some(Bounce {
+            ray: ray.continued(ctx.position + SURFACE_OFFSET * vin, vin),
+            weight: material.eval(ctx, &vin, &vout) * (cos_theta / pdf),
+            state: state.after_surface_bounce(pdf),
+            guided_pdf: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView};
+    use crate::linking::LightLink;
+    use crate::material::{Bsdf, Lambertian, Material};
+    use crate::object::Object;
+    use crate::shape::{Plane, Sphere};
+
+    #[test]
+    fn depth_of_a_wall() {
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::new(0.0, 0.0, 10.0),
+                normal: glm::DVec3::z(),
+            }),
+            material: Material::default(),
+            materials: Vec::new(),
+        });
+        let camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 8),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            ..Default::default()
+        });
+
+        let depth = render_depth(&scene, &camera);
+        assert_eq!((16, 8), depth.resolution());
+        for d in depth.depths() {
+            assert_relative_eq!(10.0, *d, epsilon = 1e-9);
+        }
+
+        let behind = Camera::new(&CameraConfig {
+            direction: -glm::DVec3::z(),
+            resolution: (4, 4),
+            ..Default::default()
+        });
+        assert!(render_depth(&scene, &behind)
+            .depths()
+            .iter()
+            .all(|d| d.is_infinite()));
+    }
+
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        let scene = crate::preview::shaderball_scene(Material::default());
+        let camera = crate::preview::shaderball_camera(0.0, (24, 16));
+        let render = |renderer: &PathTracer, threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| renderer.render_film(&scene, &camera).to_raw())
+        };
+
+        for render_loop in [RenderLoop::Megakernel, RenderLoop::Wavefront] {
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(4)
+                .render_loop(render_loop)
+                .seed(Some(9));
+            let image = render(&renderer, 1);
+            assert_eq!(image, render(&renderer, 3));
+
+            renderer.seed(Some(10));
+            assert_ne!(image, render(&renderer, 1));
+        }
+    }
+
+    #[test]
+    fn large_sphere_light() {
+        // A floor lit by a sphere of radiance L subtending a half-angle α
+        // reflects ρ L sin²α straight below it
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::repeat(127.5),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 2.0, 0.0), 1.5)),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    emittance: 1.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        let camera = Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 1.0, -6.0),
+            direction: glm::DVec3::new(0.0, -1.0, 6.0),
+            resolution: (1, 1),
+            fov: FieldOfView::Horizontal(0.1_f64.to_radians()),
+            ..Default::default()
+        });
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(1024).max_depth(1).seed(Some(4));
+        let pixel = renderer.render_film(&scene, &camera).pixel(0, 0);
+
+        let expected = 0.5 * 255.0 * 0.75_f64.powi(2);
+        assert_relative_eq!(expected, pixel.color().x, max_relative = 0.01);
+    }
+
+    #[test]
+    fn pixel_offsets_change_between_renders() {
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(4);
+        let offsets = |seed| {
+            (0..4)
+                .map(|sample| renderer.pixel_offset(3, 5, sample, seed))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(offsets(1), offsets(1));
+        // Passes of a progressive render sample other points of the pixel
+        assert!(offsets(1).iter().all(|offset| !offsets(2).contains(offset)));
+    }
+
+    #[test]
+    fn edges_are_anti_aliased() {
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 10.0), 4.0)),
+            material: Material {
+                color: Color::repeat(255.0),
+                emittance: 1.0,
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        let camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (16, 16),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            ..Default::default()
+        });
+        let partial = |spp| {
+            let mut renderer = PathTracer::new();
+            renderer.samples_per_pixel(spp).seed(Some(2));
+            let film = renderer.render_film(&scene, &camera);
+            film.pixels()
+                .filter(|pixel| (10.0..245.0).contains(&pixel.color().x))
+                .count()
+        };
+
+        // Pixels on the outline of the sphere are partly covered
+        assert_eq!(0, partial(1));
+        assert!(partial(16) > 8);
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_converge_faster() {
+        let scene = crate::preview::shaderball_scene(Material {
+            color: Color::new(200.0, 100.0, 50.0),
+            ..Default::default()
+        });
+        let camera = crate::preview::shaderball_camera(0.0, (12, 8));
+        let render = |sampler, spp, seed| {
+            let mut renderer = PathTracer::new();
+            renderer
+                .samples_per_pixel(spp)
+                .max_depth(2)
+                .sampler(sampler)
+                .seed(Some(seed));
+            renderer.render_film(&scene, &camera)
+        };
+        let reference = render(SamplerKind::Random, 256, 1);
+        let error = |sampler| {
+            let film = render(sampler, 16, 2);
+            film.pixels()
+                .zip(reference.pixels())
+                .map(|(pixel, truth)| (pixel.color() - truth.color()).norm_squared())
+                .sum::<f64>()
+        };
+
+        let random = error(SamplerKind::Random);
+        assert!(error(SamplerKind::Halton) < random);
+        assert!(error(SamplerKind::Sobol) < random);
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_noise() {
+        let mut scene = crate::preview::shaderball_scene(Material {
+            color: Color::new(200.0, 100.0, 50.0),
+            ..Default::default()
+        });
+        // Without the backdrop, the background shows above the horizon
+        scene.objects.remove(2);
+        let camera = crate::preview::shaderball_camera(0.0, (24, 16));
+        let mut renderer = PathTracer::new();
+        renderer
+            .samples_per_pixel(8)
+            .seed(Some(1))
+            .adaptive_sampling(Some(AdaptiveSampling::new(0.05, 256)));
+        let film = renderer.render_film(&scene, &camera);
+
+        let samples: Vec<u32> = film.pixels().map(|pixel| pixel.samples).collect();
+        assert!(samples.iter().all(|&n| (8..=256).contains(&n)));
+        // The background is flat and stops right away, the lit ball doesn't
+        assert_eq!(8, *samples.iter().min().unwrap());
+        assert!(*samples.iter().max().unwrap() > 8);
+        for pixel in film.pixels().filter(|pixel| pixel.samples < 256) {
+            assert!(AdaptiveSampling::new(0.05, 256).is_converged(&pixel));
+        }
+    }
+
+    #[test]
+    fn tile_sizes_and_progress() {
+        let scene = crate::preview::shaderball_scene(Material {
+            color: Color::new(200.0, 100.0, 50.0),
+            ..Default::default()
+        });
+        let camera = crate::preview::shaderball_camera(0.0, (24, 16));
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let progress = finished.clone();
+        let mut renderer = PathTracer::new();
+        renderer
+            .samples_per_pixel(2)
+            .seed(Some(5))
+            .progress(Some(Arc::new(move |done, total| {
+                progress.lock().unwrap().push((done, total))
+            })));
+        let image = renderer.render_film(&scene, &camera).to_raw();
+        assert_eq!(vec![(1, 1)], *finished.lock().unwrap());
+
+        // The tiles don't change the samples of the pixels
+        finished.lock().unwrap().clear();
+        renderer.tile_size(10);
+        assert_eq!(image, renderer.render_film(&scene, &camera).to_raw());
+        let mut finished = finished.lock().unwrap().clone();
+        finished.sort();
+        assert_eq!((1..=6).map(|done| (done, 6)).collect::<Vec<_>>(), finished);
+    }
+
+    #[test]
+    fn resume_progressive_renders() {
+        let scene = crate::preview::shaderball_scene(Material {
+            color: Color::new(200.0, 100.0, 50.0),
+            ..Default::default()
+        });
+        let camera = crate::preview::shaderball_camera(0.0, (24, 16));
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(2).seed(Some(3));
+
+        let dir = std::env::temp_dir().join(format!("light-progressive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut progressive = Progressive {
+            passes: 2,
+            checkpoint: Some(dir.join("render.checkpoint")),
+            interval: std::time::Duration::ZERO,
+            resume: true,
+            target_error: None,
+        };
+        let mut passes = Vec::new();
+        renderer
+            .render_progressive(&scene, &camera, &progressive, &mut |pass, _| {
+                passes.push(pass)
+            })
+            .unwrap();
+        assert_eq!(vec![1, 2], passes);
+
+        // Resuming only renders the missing passes, and ends up with the
+        // image of an uninterrupted render, even with another seed
+        progressive.passes = 4;
+        passes.clear();
+        renderer.seed(Some(4));
+        let resumed = renderer
+            .render_progressive(&scene, &camera, &progressive, &mut |pass, _| {
+                passes.push(pass)
+            })
+            .unwrap();
+        assert_eq!(vec![3, 4], passes);
+        assert_eq!(8, resumed.pixel(10, 10).samples);
+
+        renderer.seed(Some(3));
+        let uninterrupted = Progressive {
+            checkpoint: None,
+            ..progressive.clone()
+        };
+        let film = renderer
+            .render_progressive(&scene, &camera, &uninterrupted, &mut |_, _| {})
+            .unwrap();
+        assert_eq!(film.to_raw(), resumed.to_raw());
+        assert_ne!(
+            film.to_raw(),
+            renderer.render_film(&scene, &camera).to_raw()
+        );
+
+        let other_camera = crate::preview::shaderball_camera(0.0, (8, 8));
+        let error = renderer
+            .render_progressive(&scene, &other_camera, &progressive, &mut |_, _| {})
+            .err()
+            .unwrap();
+        assert!(
+            error.starts_with("The checkpoint is a 24×16 render"),
+            "{error}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn caustic_paths() {
+        let camera = PathState::default();
+        assert!(!camera.after_specular(false).is_caustic());
+        assert!(!camera.after_diffuse().is_caustic());
+
+        let caustic = camera.after_diffuse().after_specular(true);
+        assert!(caustic.inside);
+        assert!(caustic.is_caustic());
+        assert!(caustic.after_specular(true).is_caustic());
+        assert!(!caustic.after_specular(true).inside);
+        assert!(!caustic.after_diffuse().after_specular(false).is_caustic());
+    }
+
+    #[test]
+    fn guided_render_is_unbiased() {
+        // A diffuse floor under a uniform sky reflects albedo × sky
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: Material {
+                color: Color::new(100.0, 50.0, 10.0),
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        let camera = Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 5.0, 0.0),
+            direction: -glm::DVec3::y(),
+            resolution: (8, 8),
+            ..Default::default()
+        });
+
+        let mut renderer = PathTracer::new();
+        renderer
+            .samples_per_pixel(64)
+            .max_depth(1)
+            .path_guiding(Some(PathGuiding {
+                training_iterations: 3,
+                spatial_threshold: 100,
+                ..Default::default()
+            }));
+        let film = renderer.render_film(&scene, &camera);
+
+        let mean: Color =
+            film.pixels().map(|p| p.color()).sum::<Color>() / film.pixels().count() as f64;
+        assert_relative_eq!(
+            Color::new(100.0, 50.0, 10.0) / 255.0,
+            mean,
+            max_relative = 0.03
+        );
+    }
+
+    #[test]
+    fn linked_lights() {
+        // A floor lit by a small light, with a ball between them hiding the
+        // light from the origin
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::repeat(200.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 5.0, 0.0), 0.5)),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    emittance: 20.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 2.5, 0.0), 1.0)),
+                material: Material {
+                    color: Color::repeat(200.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut mean_radiance = |scene: &Scene, x: f64| {
+            let ray = Ray::new(
+                glm::DVec3::new(x + 3.0, 0.5, 0.0),
+                glm::DVec3::new(-1.0, -1.0 / 6.0, 0.0),
+            );
+            (0..20_000)
+                .map(|_| renderer.trace_path(scene, &ray, None, &mut Bump::new(), &mut rng))
+                .sum::<Color>()
+                / 20_000.0
+        };
+
+        let lit = mean_radiance(&scene, 4.0);
+        assert!(lit.x > 0.0);
+        assert_eq!(Color::zeros(), mean_radiance(&scene, 0.0));
+
+        // Lighting only the ball
+        let mut link = LightLink::new(1);
+        link.illuminates = Some(vec![2]);
+        scene.light_links = vec![link];
+        assert_eq!(Color::zeros(), mean_radiance(&scene, 4.0));
+
+        // The ball no longer blocks the light
+        let mut link = LightLink::new(1);
+        link.shadowed_by = Some(Vec::new());
+        scene.light_links = vec![link];
+        assert!(mean_radiance(&scene, 0.0).x > lit.x);
+
+        // Lights with linked shadows are only found by sampling them, which
+        // gives the same light where nothing is in the way
+        scene.light_links[0].shadowed_by = Some(vec![0, 2]);
+        assert_relative_eq!(lit, mean_radiance(&scene, 4.0), max_relative = 0.05);
+        assert_eq!(Color::zeros(), mean_radiance(&scene, 0.0));
+    }
+
+    #[test]
+    fn lambertian_floor_under_sky() {
+        // Every direction above the floor sees the sky, so the cosine
+        // weighted bounces return albedo × sky exactly
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(10.0, 20.0, 40.0);
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: Material {
+                color: Color::new(255.0, 127.5, 51.0),
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let mut rng = rand::thread_rng();
+        for origin in [
+            glm::DVec3::new(0.0, 1.0, 0.0),
+            glm::DVec3::new(0.0, -1.0, 0.0),
+        ] {
+            let ray = Ray::new(origin, glm::DVec3::new(0.3, -origin.y, 0.1));
+            let color = renderer.trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng);
+            assert_relative_eq!(Color::new(10.0, 10.0, 8.0), color, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn small_light_over_floor() {
+        // A white floor under a sphere of radius r, at height h over the
+        // shaded point, reflects L (r / h)² where L is the sphere radiance
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 2.0, 0.0), 0.1)),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    emittance: 1.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let ray = Ray::new(
+            glm::DVec3::new(0.5, 0.5, 0.0),
+            glm::DVec3::new(-1.0, -1.0, 0.0),
+        );
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 2000;
+        let mean = (0..n)
+            .map(|_| renderer.trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng))
+            .sum::<Color>()
+            / n as f64;
+        assert_relative_eq!(255.0 * 0.05_f64.powi(2), mean.x, max_relative = 0.02);
+    }
+
+    #[test]
+    fn custom_bsdf_under_sky() {
+        // Gray surface that ignores the color of its material
+        #[derive(Debug)]
+        struct Gray;
+        impl Bsdf for Gray {
+            fn eval(
+                &self,
+                normal: &glm::DVec3,
+                _albedo: &Color,
+                vin: &glm::DVec3,
+                vout: &glm::DVec3,
+            ) -> Color {
+                Lambertian.eval(normal, &Color::repeat(127.5), vin, vout)
+            }
+            fn sample(
+                &self,
+                normal: &glm::DVec3,
+                vout: &glm::DVec3,
+                rng: &mut dyn rand::RngCore,
+            ) -> (glm::DVec3, f64) {
+                Lambertian.sample(normal, vout, rng)
+            }
+            fn pdf(&self, normal: &glm::DVec3, vout: &glm::DVec3, vin: &glm::DVec3) -> f64 {
+                Lambertian.pdf(normal, vout, vin)
+            }
+            fn kind(&self) -> &'static str {
+                "gray"
+            }
+        }
+
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(10.0, 20.0, 40.0);
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: Material {
+                color: Color::new(255.0, 0.0, 0.0),
+                bsdf: Some(Arc::new(Gray)),
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let ray = Ray::new(glm::DVec3::y(), glm::DVec3::new(0.3, -1.0, 0.1));
+        let color = renderer.trace_path(
+            &scene,
+            &ray,
+            None,
+            &mut Bump::new(),
+            &mut rand::thread_rng(),
+        );
+        assert_relative_eq!(Color::new(5.0, 10.0, 20.0), color, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn split_first_bounce() {
+        // A rough metal floor reflects a light above it
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    bsdf: Some(Arc::new(crate::material::Specular::Metal {
+                        roughness: 0.5,
+                    })),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(1.0, 1.0, 0.0), 0.5)),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    emittance: 10.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        let ray = Ray::new(
+            glm::DVec3::new(-1.0, 1.0, 0.0),
+            glm::DVec3::new(1.0, -1.0, 0.0),
+        );
+        let trace = |renderer: &PathTracer| {
+            let mut rng = StdRng::seed_from_u64(3);
+            let n = 4000;
+            let samples: Vec<f64> = (0..n)
+                .map(|_| {
+                    renderer
+                        .trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng)
+                        .x
+                })
+                .collect();
+            let mean = samples.iter().sum::<f64>() / n as f64;
+            let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+            (mean, variance)
+        };
+        let mut renderer = PathTracer::new();
+        let (mean, variance) = trace(&renderer);
+        renderer.first_bounce_samples(8);
+        let (split_mean, split_variance) = trace(&renderer);
+
+        // Same estimate, with less noise per camera sample
+        assert!(mean > 0.0);
+        assert_relative_eq!(mean, split_mean, max_relative = 0.1);
+        assert!(split_variance < 0.5 * variance);
+    }
+
+    #[test]
+    fn deep_paths_between_mirrors() {
+        // Two facing mirrors trap the path until the maximum depth, which is
+        // far beyond what a recursive tracer could handle on the stack
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        for z in [-1.0, 1.0] {
+            scene.add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 0.0, z),
+                    normal: glm::DVec3::z(),
+                }),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    bsdf: Some(Arc::new(crate::material::Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        }
+
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(200_000);
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let color = renderer.trace_path(
+            &scene,
+            &ray,
+            None,
+            &mut Bump::new(),
+            &mut rand::thread_rng(),
+        );
+        assert_eq!(Color::zeros(), color);
+    }
+
+    #[test]
+    fn absorbing_volume() {
+        // A black medium in front of the background only lets through the
+        // light that doesn't collide with it
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(100.0, 100.0, 100.0);
+        let mut fog = Volume::new(
+            crate::shape::Bounds {
+                min: glm::DVec3::new(-10.0, -10.0, 1.0),
+                max: glm::DVec3::new(10.0, 10.0, 2.0),
+            },
+            volume::ScalarField::Constant(0.8),
+        );
+        fog.color = Color::zeros();
+        scene.volumes.push(fog);
+
+        let renderer = PathTracer::new();
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let mut rng = rand::thread_rng();
+        let n = 20_000;
+        let mean = (0..n)
+            .map(|_| renderer.trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng))
+            .sum::<Color>()
+            / n as f64;
+        assert_relative_eq!(100.0 * (-0.8_f64).exp(), mean.x, epsilon = 1.5);
+
+        // When it glows, the light absorbed from the background is replaced
+        // by the emission
+        let emission = volume::BlackbodyEmission::new(volume::ScalarField::Constant(1500.0), 1.0);
+        let glow = emission.radiance(&glm::DVec3::zeros());
+        scene.volumes[0].emission = Some(emission);
+        let mean = (0..n)
+            .map(|_| renderer.trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng))
+            .sum::<Color>()
+            / n as f64;
+        let transmittance = (-0.8_f64).exp();
+        assert_relative_eq!(
+            100.0 * transmittance + (1.0 - transmittance) * glow.x,
+            mean.x,
+            epsilon = 0.02 * glow.x
+        );
+    }
+
+    #[test]
+    fn equiangular_volume_lighting() {
+        // A ray through a slab of fog passing below a small light. With one
+        // bounce, the fog only scatters the light coming straight from it.
+        let density = 0.5;
+        let (radius, height) = (0.2, 1.5);
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::new(0.0, height, 2.0), radius)),
+            material: Material {
+                color: Color::repeat(255.0),
+                emittance: 1.0,
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        scene.volumes.push(Volume::new(
+            crate::shape::Bounds {
+                min: glm::DVec3::new(-1.0, -0.5, 1.0),
+                max: glm::DVec3::new(1.0, 0.5, 3.0),
+            },
+            volume::ScalarField::Constant(density),
+        ));
+
+        // Single scattering along the ray, the light being small enough that
+        // the fog it crosses is the same towards all of its points
+        let steps = 2000;
+        let expected = (0..steps)
+            .map(|k| {
+                let z = 2.0 * (k as f64 + 0.5) / steps as f64;
+                let distance = (height * height + (z - 1.0).powi(2)).sqrt();
+                let solid_angle = 2.0 * PI * (1.0 - (1.0 - (radius / distance).powi(2)).sqrt());
+                let in_fog = z + 0.5 * distance / height;
+                density * (-density * in_fog).exp() * 255.0 * solid_angle / (4.0 * PI) * 2.0
+                    / steps as f64
+            })
+            .sum::<f64>();
+
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 20_000;
+        let estimate = |renderer: &PathTracer, rng: &mut StdRng| {
+            let samples: Vec<f64> = (0..n)
+                .map(|_| {
+                    renderer
+                        .trace_path(&scene, &ray, None, &mut Bump::new(), rng)
+                        .x
+                })
+                .collect();
+            let mean = samples.iter().sum::<f64>() / n as f64;
+            let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+            (mean, variance)
+        };
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let (_, free_flight_variance) = estimate(&renderer, &mut rng);
+        renderer.equiangular_sampling(true);
+        let (mean, variance) = estimate(&renderer, &mut rng);
+
+        assert_relative_eq!(expected, mean, max_relative = 0.03);
+        assert!(variance < 0.01 * free_flight_variance);
+    }
+
+    #[test]
+    fn indirect_clamp() {
+        // A small bright light between a mirror floor and a white ceiling.
+        // The ceiling is lit directly, but its reflection is indirect light.
+        let mut scene = Scene::new();
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    bsdf: Some(Arc::new(crate::material::Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 2.0, 0.0),
+                    normal: -glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 1.0, 0.0), 0.2)),
+                material: Material {
+                    color: Color::repeat(255.0),
+                    emittance: 50.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        let limit = 10.0;
+        let trace = |renderer: &PathTracer, ray: &Ray| {
+            let mut rng = StdRng::seed_from_u64(5);
+            (0..200)
+                .map(|_| {
+                    renderer
+                        .trace_path(&scene, ray, None, &mut Bump::new(), &mut rng)
+                        .max()
+                })
+                .collect::<Vec<f64>>()
+        };
+        let up = Ray::new(glm::DVec3::new(1.0, 1.0, 0.0), glm::DVec3::y());
+        let down = Ray::new(glm::DVec3::new(1.0, 1.0, 0.0), -glm::DVec3::y());
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let direct = trace(&renderer, &up);
+        renderer.max_depth(2);
+        let indirect = trace(&renderer, &down);
+        assert!(indirect.iter().any(|&value| value > 2.0 * limit));
+
+        // Both contributions of the reflection are clamped, the direct light
+        // isn't
+        renderer.indirect_clamp(Some(limit));
+        assert!(trace(&renderer, &down)
+            .iter()
+            .all(|&value| value <= 2.0 * limit + 1e-9));
+        renderer.max_depth(1);
+        assert_eq!(direct, trace(&renderer, &up));
+    }
+
+    #[test]
+    fn water_absorption() {
+        // Looking straight down through water at a white emissive floor, the
+        // light is tinted by the water it crosses
+        let mut scene = Scene::new();
+        let tint = Color::new(50.0, 150.0, 200.0);
+        scene
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 0.0, 1.0),
+                    normal: glm::DVec3::z(),
+                }),
+                material: Material::water(tint, 1.0),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::new(0.0, 0.0, 3.0),
+                    normal: glm::DVec3::z(),
+                }),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    emittance: 1.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        // The path ends at the floor
+        let mut renderer = PathTracer::new();
+        renderer.max_depth(1);
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let mut rng = rand::thread_rng();
+        let n = 20_000;
+        let mean = (0..n)
+            .map(|_| renderer.trace_path(&scene, &ray, None, &mut Bump::new(), &mut rng))
+            .sum::<Color>()
+            / n as f64;
+
+        // Two units of water, minus the Fresnel reflection at the surface
+        let r0 = ((1.0 - crate::material::WATER_IOR) / (1.0 + crate::material::WATER_IOR)).powi(2);
+        let expected = (1.0 - r0) * tint.component_mul(&tint) / 255.0;
+        assert_relative_eq!(expected, mean, max_relative = 0.03);
     }
 }
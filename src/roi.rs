@@ -0,0 +1,238 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use rand::Rng;
+
+use crate::camera::Camera;
+use crate::render;
+use crate::scene::Scene;
+
+/// Part of the image that deserves a higher sampling budget
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionOfInterest {
+    /// Rectangle in pixel coordinates
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Pixels where the given object (index in the scene) is directly visible
+    Object(usize),
+}
+
+/// Region of interest and how much more it is sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrioritySampling {
+    pub region: RegionOfInterest,
+    pub spp_multiplier: f64, // Sampling budget multiplier inside the region
+    pub falloff: u32,        // Width of the transition band around the region [px]
+}
+
+impl PrioritySampling {
+    /// Per-pixel sample count, in scanline order. Pixels inside the region
+    /// get `spp * spp_multiplier` samples, blending linearly to `spp` over
+    /// the falloff band.
+    pub fn sample_counts(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        spp: u32,
+        rng: &mut impl Rng,
+    ) -> Vec<u32> {
+        let (w, h) = camera.resolution();
+        let mask = region_mask(&self.region, scene, camera, rng);
+        let distances = distance_transform(&mask, w, h);
+
+        distances
+            .iter()
+            .map(|distance| {
+                let weight = if self.falloff == 0 {
+                    if *distance == 0.0 {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                } else {
+                    (1.0 - distance / (self.falloff as f64)).max(0.0)
+                };
+                let multiplier = 1.0 + (self.spp_multiplier - 1.0) * weight;
+                ((spp as f64) * multiplier).round().max(1.0) as u32
+            })
+            .collect()
+    }
+}
+
+fn region_mask(
+    region: &RegionOfInterest,
+    scene: &Scene,
+    camera: &Camera,
+    rng: &mut impl Rng,
+) -> Vec<bool> {
+    let (w, h) = camera.resolution();
+    let mut mask = vec![false; (w * h) as usize];
+
+    for j in 0..h {
+        for i in 0..w {
+            mask[(j * w + i) as usize] = match *region {
+                RegionOfInterest::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                } => (x..x + width).contains(&i) && (y..y + height).contains(&j),
+                RegionOfInterest::Object(id) => {
+                    let ray = camera.cast_ray(i, j, rng).expect("Expected a Ray");
+                    matches!(
+                        render::get_closest_hit(&scene.objects, &ray),
+                        Some((_, _, hit_id)) if hit_id == id
+                    )
+                }
+            };
+        }
+    }
+
+    mask
+}
+
+/// Approximate Euclidean distance from every pixel to the closest pixel set
+/// in the mask, using a two-pass chamfer transform
+fn distance_transform(mask: &[bool], w: u32, h: u32) -> Vec<f64> {
+    const ORTHOGONAL: f64 = 1.0;
+    const DIAGONAL: f64 = std::f64::consts::SQRT_2;
+
+    let (w, h) = (w as usize, h as usize);
+    let mut distance: Vec<f64> = mask
+        .iter()
+        .map(|inside| if *inside { 0.0 } else { f64::INFINITY })
+        .collect();
+
+    // Forward pass: top-left neighbours
+    for j in 0..h {
+        for i in 0..w {
+            let mut d = distance[j * w + i];
+            if i > 0 {
+                d = d.min(distance[j * w + i - 1] + ORTHOGONAL);
+            }
+            if j > 0 {
+                d = d.min(distance[(j - 1) * w + i] + ORTHOGONAL);
+                if i > 0 {
+                    d = d.min(distance[(j - 1) * w + i - 1] + DIAGONAL);
+                }
+                if i + 1 < w {
+                    d = d.min(distance[(j - 1) * w + i + 1] + DIAGONAL);
+                }
+            }
+            distance[j * w + i] = d;
+        }
+    }
+
+    // Backward pass: bottom-right neighbours
+    for j in (0..h).rev() {
+        for i in (0..w).rev() {
+            let mut d = distance[j * w + i];
+            if i + 1 < w {
+                d = d.min(distance[j * w + i + 1] + ORTHOGONAL);
+            }
+            if j + 1 < h {
+                d = d.min(distance[(j + 1) * w + i] + ORTHOGONAL);
+                if i + 1 < w {
+                    d = d.min(distance[(j + 1) * w + i + 1] + DIAGONAL);
+                }
+                if i > 0 {
+                    d = d.min(distance[(j + 1) * w + i - 1] + DIAGONAL);
+                }
+            }
+            distance[j * w + i] = d;
+        }
+    }
+
+    distance
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView};
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::Sphere;
+
+    fn camera(resolution: (u32, u32)) -> Camera {
+        Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution,
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn distance_to_single_pixel() {
+        let mut mask = vec![false; 25];
+        mask[12] = true;
+        let distance = distance_transform(&mask, 5, 5);
+
+        assert_eq!(0.0, distance[12]);
+        assert_eq!(1.0, distance[13]);
+        assert_eq!(2.0, distance[14]);
+        assert_relative_eq!(std::f64::consts::SQRT_2, distance[18]);
+    }
+
+    #[test]
+    fn rect_priority_blends_to_base_spp() {
+        let priority = PrioritySampling {
+            region: RegionOfInterest::Rect {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 10,
+            },
+            spp_multiplier: 5.0,
+            falloff: 4,
+        };
+        let counts =
+            priority.sample_counts(&Scene::new(), &camera((10, 10)), 4, &mut rand::thread_rng());
+
+        let row: Vec<u32> = counts[..10].to_vec();
+        assert_eq!(vec![20, 20, 16, 12, 8, 4, 4, 4, 4, 4], row);
+    }
+
+    #[test]
+    fn object_priority() {
+        let mut scene = Scene::new();
+        scene.add_object(Object {
+            shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 0.0, 10.0), 2.0)),
+            material: Material::default(),
+            materials: Vec::new(),
+        });
+
+        let priority = PrioritySampling {
+            region: RegionOfInterest::Object(0),
+            spp_multiplier: 2.0,
+            falloff: 0,
+        };
+        let counts = priority.sample_counts(&scene, &camera((20, 20)), 8, &mut rand::thread_rng());
+
+        assert_eq!(16, counts[10 * 20 + 10]); // Center
+        assert_eq!(8, counts[0]); // Corner
+    }
+}
@@ -0,0 +1,300 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Samplers: where the random numbers of the samples of a pixel come from.
+//!
+//! Low-discrepancy samplers give the `n`th sample of a pixel the `n`th point
+//! of a sequence, with one dimension for each random number the path draws
+//! in turn, so that the samples of a pixel cover the space of paths more
+//! evenly than independent numbers do. Every pixel scrambles the sequence in
+//! its own way, which turns the structured error into noise that doesn't
+//! repeat from pixel to pixel. Dimensions past the end of the sequence are
+//! pseudo-random.
+
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::sampling::{self, RngStream};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    /// Independent pseudo-random numbers
+    #[default]
+    Random,
+    /// Halton sequence with random digit scrambling per pixel
+    Halton,
+    /// Sobol sequence with Owen scrambling per pixel
+    Sobol,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SamplerKind::Random),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("Unknown sampler '{s}'")),
+        }
+    }
+}
+
+/// Bases of the dimensions of the Halton sequence
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Degree, coefficients and initial direction numbers of the primitive
+/// polynomials of the Sobol dimensions after the first, from Joe and Kuo,
+/// "Constructing Sobol sequences with better two-dimensional projections"
+/// (2008)
+const SOBOL_POLYNOMIALS: [(u32, u32, &[u32]); 15] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+];
+
+/// Number of dimensions of the Sobol sequence
+const SOBOL_DIMENSIONS: usize = SOBOL_POLYNOMIALS.len() + 1;
+
+/// Direction numbers of each Sobol dimension, one per bit of the index
+fn sobol_matrices() -> &'static [[u32; 32]; SOBOL_DIMENSIONS] {
+    static MATRICES: OnceLock<[[u32; 32]; SOBOL_DIMENSIONS]> = OnceLock::new();
+    MATRICES.get_or_init(|| {
+        let mut matrices = [[0; 32]; SOBOL_DIMENSIONS];
+        for (k, v) in matrices[0].iter_mut().enumerate() {
+            *v = 1 << (31 - k);
+        }
+        for (dimension, &(degree, coefficients, initial)) in SOBOL_POLYNOMIALS.iter().enumerate() {
+            let v = &mut matrices[dimension + 1];
+            let s = degree as usize;
+            for k in 0..32 {
+                v[k] = if k < s {
+                    initial[k] << (31 - k)
+                } else {
+                    let mut next = v[k - s] ^ (v[k - s] >> s);
+                    for l in 1..s {
+                        if (coefficients >> (s - 1 - l)) & 1 == 1 {
+                            next ^= v[k - l];
+                        }
+                    }
+                    next
+                };
+            }
+        }
+        matrices
+    })
+}
+
+/// Dimension of the Sobol sequence as 32 bit fixed point
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let matrix = &sobol_matrices()[dimension];
+    (0..32)
+        .filter(|bit| (index >> bit) & 1 == 1)
+        .fold(0, |value, bit| value ^ matrix[bit])
+}
+
+/// Owen scrambling of a 32 bit fixed point number, with the hash of Laine
+/// and Karras, "Stratified sampling for stochastic transparency" (2011).
+/// Points of a sequence keep their stratification.
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+/// Digits of `index` in the given base, mirrored around the radix point.
+/// Each digit is shifted by an amount drawn from `key` and its position,
+/// including the zeros past the last digit of `index`.
+fn scrambled_radical_inverse(mut index: u32, base: u32, key: u64) -> f64 {
+    let inverse = 1.0 / base as f64;
+    let (mut value, mut scale) = (0.0, inverse);
+    let mut position = 0u64;
+    while scale > f64::EPSILON {
+        let shift = mix(key ^ position.wrapping_mul(0x9e37_79b9_7f4a_7c15)) % base as u64;
+        value += ((index % base) as u64 + shift) as f64 % base as f64 * scale;
+        index /= base;
+        scale *= inverse;
+        position += 1;
+    }
+    value.min(1.0 - f64::EPSILON / 2.0)
+}
+
+/// Hash of a 64 bit number, the finalizer of SplitMix64
+fn mix(mut key: u64) -> u64 {
+    key ^= key >> 30;
+    key = key.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    key ^= key >> 27;
+    key = key.wrapping_mul(0x94d0_49bb_1331_11eb);
+    key ^ key >> 31
+}
+
+/// Random numbers of one sample of a pixel. Each number drawn is the next
+/// dimension of the sample's point of the sequence.
+pub struct PixelSampler {
+    kind: SamplerKind,
+    sample: u32,
+    dimension: usize,
+    scramble: u64, // Key of the scrambling of the pixel
+    rng: StdRng,   // Dimensions past the end of the sequence
+}
+
+impl PixelSampler {
+    pub fn new(kind: SamplerKind, seed: u64, pixel: (u32, u32), sample: u32) -> Self {
+        let scramble = match kind {
+            SamplerKind::Random => 0,
+            _ => sampling::sample_rng(seed, RngStream::Scramble, pixel, 0).gen(),
+        };
+        Self {
+            kind,
+            sample,
+            dimension: 0,
+            scramble,
+            rng: sampling::sample_rng(seed, RngStream::Camera, pixel, sample),
+        }
+    }
+
+    /// Scrambling key of a dimension
+    fn key(&self, dimension: usize) -> u64 {
+        mix(self.scramble ^ (dimension as u64).wrapping_mul(0xd6e8_feb8_6659_fd93))
+    }
+
+    /// Next dimension of the point of the sample as 64 bit fixed point, None
+    /// past the end of the sequence
+    fn next_dimension(&mut self) -> Option<u64> {
+        let dimension = self.dimension;
+        let value = match self.kind {
+            SamplerKind::Random => return None,
+            SamplerKind::Halton => {
+                let base = *PRIMES.get(dimension)?;
+                let value = scrambled_radical_inverse(self.sample, base, self.key(dimension));
+                (value * 2.0f64.powi(64)) as u64
+            }
+            SamplerKind::Sobol if dimension < SOBOL_DIMENSIONS => {
+                let value =
+                    owen_scramble(sobol(self.sample, dimension), self.key(dimension) as u32);
+                // Low bits are random so that numbers are continuous
+                (value as u64) << 32 | self.rng.next_u32() as u64
+            }
+            SamplerKind::Sobol => return None,
+        };
+        self.dimension += 1;
+        Some(value)
+    }
+}
+
+impl RngCore for PixelSampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self.next_dimension() {
+            Some(value) => value,
+            None => self.rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// First two dimensions of the first `count` samples of a pixel
+    fn points(kind: SamplerKind, count: u32) -> Vec<(f64, f64)> {
+        (0..count)
+            .map(|sample| {
+                let mut sampler = PixelSampler::new(kind, 5, (3, 4), sample);
+                (sampler.gen(), sampler.gen())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_stratified() {
+        // Any power of two of Sobol points has one in each cell of a grid
+        // of that many cells, whatever its shape
+        let sobol = points(SamplerKind::Sobol, 16);
+        for (columns, rows) in [(16, 1), (4, 4), (2, 8), (1, 16)] {
+            let mut cells = vec![0; 16];
+            for (x, y) in &sobol {
+                let cell = (y * rows as f64) as usize * columns + (x * columns as f64) as usize;
+                cells[cell] += 1;
+            }
+            assert_eq!(vec![1; 16], cells, "{columns}×{rows}");
+        }
+
+        // Halton points are stratified along each dimension, in its base
+        let halton = points(SamplerKind::Halton, 9);
+        let mut cells = (vec![0; 8], vec![0; 9]);
+        for (x, y) in &halton[..8] {
+            cells.0[(x * 8.0) as usize] += 1;
+            cells.1[(y * 9.0) as usize] += 1;
+        }
+        cells.1[(halton[8].1 * 9.0) as usize] += 1;
+        assert_eq!((vec![1; 8], vec![1; 9]), cells);
+    }
+
+    #[test]
+    fn pixels_are_scrambled() {
+        let pixel = |kind, i| PixelSampler::new(kind, 5, (i, 0), 0).gen::<f64>();
+        assert_ne!(pixel(SamplerKind::Sobol, 0), pixel(SamplerKind::Sobol, 1));
+        assert_ne!(pixel(SamplerKind::Halton, 0), pixel(SamplerKind::Halton, 1));
+
+        // Past the end of the sequence, and with the random sampler, the
+        // numbers are those of the sample's generator
+        let mut random = PixelSampler::new(SamplerKind::Random, 5, (3, 4), 2);
+        let mut rng = sampling::sample_rng(5, RngStream::Camera, (3, 4), 2);
+        assert_eq!(rng.gen::<u64>(), random.gen::<u64>());
+        let mut sobol = PixelSampler::new(SamplerKind::Sobol, 5, (3, 4), 2);
+        let values: Vec<f64> = (0..SOBOL_DIMENSIONS + 4).map(|_| sobol.gen()).collect();
+        assert!(values.iter().all(|value| (0.0..1.0).contains(value)));
+        assert_eq!("sobol".parse(), Ok(SamplerKind::Sobol));
+    }
+}
@@ -0,0 +1,332 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::f64::consts::PI;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Build an orthonormal basis (t, b, n) around the unit vector `n`, following
+/// Duff et al., "Building an Orthonormal Basis, Revisited" (2017)
+pub fn orthonormal_basis(n: &glm::DVec3) -> (glm::DVec3, glm::DVec3) {
+    let sign = 1.0_f64.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    let t = glm::DVec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let bt = glm::DVec3::new(b, sign + n.y * n.y * a, -n.y);
+    (t, bt)
+}
+
+/// Express a world direction in the local frame where `n` is the z axis
+pub fn to_local(n: &glm::DVec3, v: &glm::DVec3) -> glm::DVec3 {
+    let (t, b) = orthonormal_basis(n);
+    glm::DVec3::new(v.dot(&t), v.dot(&b), v.dot(n))
+}
+
+/// Express a direction in the local frame of `n` in world coordinates
+pub fn to_world(n: &glm::DVec3, v: &glm::DVec3) -> glm::DVec3 {
+    let (t, b) = orthonormal_basis(n);
+    v.x * t + v.y * b + v.z * n
+}
+
+/// Sample a direction in the local hemisphere (z up) with a cosine-weighted
+/// distribution. Returns the direction and its pdf with respect to solid angle.
+pub fn cosine_hemisphere(rng: &mut impl Rng) -> (glm::DVec3, f64) {
+    let [x, y]: [f64; 2] = rng.sample(rand_distr::UnitDisc);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    (glm::DVec3::new(x, y, z), z / PI)
+}
+
+/// Sample a scattering direction from the Henyey-Greenstein phase function
+/// with asymmetry `g` for light travelling along `direction`. Positive `g`
+/// scatters forward, negative backward and 0 is isotropic. The phase function
+/// is sampled exactly, so the pdf cancels the phase function.
+pub fn henyey_greenstein(direction: &glm::DVec3, g: f64, rng: &mut impl Rng) -> glm::DVec3 {
+    let (u, v): (f64, f64) = (rng.gen(), rng.gen());
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let phi = 2.0 * PI * v;
+    let local = glm::DVec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+    to_world(direction, &local)
+}
+
+/// Density, per unit solid angle, of the Henyey-Greenstein phase function
+/// at the cosine of the angle between the incident and scattered directions
+pub fn henyey_greenstein_pdf(cos_theta: f64, g: f64) -> f64 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+/// Power heuristic weight of a sample drawn with density `pdf` when the same
+/// path can also be found by a technique with density `other`
+pub fn power_heuristic(pdf: f64, other: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Independent streams of random numbers drawn from the seed of a render
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RngStream {
+    /// Paths traced from the camera
+    Camera,
+    /// Paths of an iteration of the path guiding training
+    Training(u32),
+    /// Photons shot from an emitter of the caustics pass
+    Photons(u32),
+    /// Paths gathering the photon map
+    Gather,
+    /// Seed of a pass of a progressive render
+    Pass(u32),
+    /// Scrambling of the low-discrepancy sequence of a pixel
+    Scramble,
+    /// Hemisphere rays of the records of an irradiance cache
+    IrradianceCache,
+    /// Seed of a frame of an animation
+    Frame(u32),
+}
+
+impl RngStream {
+    fn key(&self) -> (u32, u32) {
+        match *self {
+            Self::Camera => (0, 0),
+            Self::Training(iteration) => (1, iteration),
+            Self::Photons(emitter) => (2, emitter),
+            Self::Gather => (3, 0),
+            Self::Pass(pass) => (4, pass),
+            Self::Scramble => (5, 0),
+            Self::IrradianceCache => (6, 0),
+            Self::Frame(frame) => (7, frame),
+        }
+    }
+}
+
+/// Random numbers of one sample of a pixel. Each sample gets its own
+/// generator, keyed by the seed, the stream, the pixel and the sample index,
+/// so that seeded renders don't depend on how the work is split between
+/// threads.
+pub fn sample_rng(seed: u64, stream: RngStream, (i, j): (u32, u32), sample: u32) -> StdRng {
+    let (kind, index) = stream.key();
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    for (n, word) in [kind, index, i, j, sample].into_iter().enumerate() {
+        key[8 + 4 * n..12 + 4 * n].copy_from_slice(&word.to_le_bytes());
+    }
+    StdRng::from_seed(key)
+}
+
+/// Seed of the `frame`th frame of an animation rendered with `seed`, so that
+/// the frames don't repeat the noise of each other
+pub fn frame_seed(seed: u64, frame: u32) -> u64 {
+    sample_rng(seed, RngStream::Frame(frame), (0, 0), 0).gen()
+}
+
+/// Element at `index` of a pseudo-random permutation of [0, len) chosen by
+/// `pattern`, following Kensler, "Correlated Multi-Jittered Sampling" (2013)
+fn permute(mut index: u32, len: u32, pattern: u32) -> u32 {
+    let mut mask = len - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        index ^= pattern;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= pattern >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= pattern >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= pattern >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | pattern >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= mask;
+        index ^= index >> 5;
+        if index < len {
+            return (index + pattern) % len;
+        }
+    }
+}
+
+/// Hashed number in [0, 1) for `index`, from the same paper as `permute`
+fn hashed_float(mut index: u32, pattern: u32) -> f64 {
+    index ^= pattern;
+    index ^= index >> 17;
+    index ^= index >> 10;
+    index = index.wrapping_mul(0xb36534e5);
+    index ^= index >> 12;
+    index ^= index >> 21;
+    index = index.wrapping_mul(0x93fc4795);
+    index ^= 0xdf6e307f;
+    index ^= index >> 17;
+    index = index.wrapping_mul(1 | pattern >> 18);
+    index as f64 / (u32::MAX as f64 + 1.0)
+}
+
+/// Sub-pixel offset in [-0.5, 0.5) of the `sample`th of `count` samples of a
+/// pixel. The pixel is split into a grid of at least `count` cells and each
+/// sample falls at a random point of a different cell, in an order chosen by
+/// `pattern`. Samples past `count` start over with another order.
+pub fn stratified_offset(sample: u32, count: u32, pattern: u32) -> (f64, f64) {
+    let count = count.max(1);
+    let columns = (count as f64).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+    let pattern = pattern ^ (sample / count).wrapping_mul(0x9e37_79b9);
+    let cell = permute(sample % count, columns * rows, pattern);
+    let (x, y) = (
+        (cell % columns) as f64 + hashed_float(sample, pattern ^ 0x51633e2d),
+        (cell / columns) as f64 + hashed_float(sample, pattern ^ 0x68bc21eb),
+    );
+    (x / columns as f64 - 0.5, y / rows as f64 - 0.5)
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn basis_is_orthonormal() {
+        let normals = [
+            glm::DVec3::x(),
+            glm::DVec3::y(),
+            glm::DVec3::z(),
+            -glm::DVec3::z(),
+            glm::DVec3::new(1.0, -2.0, 3.0).normalize(),
+        ];
+        for n in normals {
+            let (t, b) = orthonormal_basis(&n);
+            assert_relative_eq!(1.0, t.norm(), epsilon = 1e-12);
+            assert_relative_eq!(1.0, b.norm(), epsilon = 1e-12);
+            assert_relative_eq!(0.0, t.dot(&b), epsilon = 1e-12);
+            assert_relative_eq!(0.0, t.dot(&n), epsilon = 1e-12);
+            assert_relative_eq!(0.0, b.dot(&n), epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn local_world_round_trip() {
+        let n = glm::DVec3::new(0.3, 0.5, -0.8).normalize();
+        let v = glm::DVec3::new(-1.0, 2.0, 0.5);
+        assert_relative_eq!(v, to_world(&n, &to_local(&n, &v)), epsilon = 1e-12);
+        assert_relative_eq!(1.0, to_local(&n, &n).z, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn cosine_samples_in_upper_hemisphere() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let (v, pdf) = cosine_hemisphere(&mut rng);
+            assert!(v.z >= 0.0);
+            assert_relative_eq!(1.0, v.norm(), epsilon = 1e-12);
+            assert_relative_eq!(v.z / PI, pdf);
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_mean_cosine() {
+        // The mean cosine of the Henyey-Greenstein distribution is g
+        let mut rng = rand::thread_rng();
+        let direction = glm::DVec3::new(1.0, 2.0, -1.0).normalize();
+        for g in [-0.6, 0.0, 0.8] {
+            let n = 50_000;
+            let mean = (0..n)
+                .map(|_| henyey_greenstein(&direction, g, &mut rng).dot(&direction))
+                .sum::<f64>()
+                / n as f64;
+            assert_relative_eq!(g, mean, epsilon = 0.02);
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_pdf_is_normalized() {
+        for g in [-0.6, 0.0, 0.8] {
+            let n = 100_000;
+            let integral = (0..n)
+                .map(|k| {
+                    let cos_theta = -1.0 + 2.0 * (k as f64 + 0.5) / n as f64;
+                    2.0 * PI * henyey_greenstein_pdf(cos_theta, g) * 2.0 / n as f64
+                })
+                .sum::<f64>();
+            assert_relative_eq!(1.0, integral, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn stratified_offsets() {
+        // One sample in each cell of a 4×4 grid
+        let mut cells = [0; 16];
+        for sample in 0..16 {
+            let (x, y) = stratified_offset(sample, 16, 1234);
+            assert!((-0.5..0.5).contains(&x));
+            assert!((-0.5..0.5).contains(&y));
+            let cell = ((y + 0.5) * 4.0) as usize * 4 + ((x + 0.5) * 4.0) as usize;
+            cells[cell] += 1;
+        }
+        assert_eq!([1; 16], cells);
+
+        // Other pixels visit the cells in another order, and budgets that
+        // aren't squares stay in the pixel
+        assert_ne!(
+            stratified_offset(0, 16, 1234),
+            stratified_offset(0, 16, 4321)
+        );
+        for sample in 0..20 {
+            let (x, y) = stratified_offset(sample, 7, 99);
+            assert!((-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y));
+        }
+    }
+
+    #[test]
+    fn sample_streams() {
+        let draw = |stream, pixel, sample| sample_rng(7, stream, pixel, sample).gen::<u64>();
+        assert_eq!(
+            draw(RngStream::Camera, (1, 2), 3),
+            draw(RngStream::Camera, (1, 2), 3)
+        );
+        let others = [
+            draw(RngStream::Camera, (2, 1), 3),
+            draw(RngStream::Camera, (1, 2), 4),
+            draw(RngStream::Training(0), (1, 2), 3),
+            draw(RngStream::Gather, (1, 2), 3),
+            sample_rng(8, RngStream::Camera, (1, 2), 3).gen(),
+        ];
+        for other in others {
+            assert_ne!(draw(RngStream::Camera, (1, 2), 3), other);
+        }
+        assert_eq!(frame_seed(7, 1), frame_seed(7, 1));
+        assert_ne!(frame_seed(7, 0), frame_seed(7, 1));
+    }
+}
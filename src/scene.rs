@@ -17,12 +17,38 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::animation::ObjectAnimation;
+use crate::atmosphere::Atmosphere;
+use crate::camera::CameraConfig;
 use crate::color::Color;
+use crate::config::Settings;
+use crate::contact::ContactShadow;
+use crate::cubemap::CubeMap;
+use crate::linking::LightLink;
+use crate::loader;
 use crate::object::Object;
+use crate::shape::Bounds;
+use crate::volume::Volume;
+
 #[derive(Default)]
 pub struct Scene {
     pub objects: Vec<Object>,
     pub background_color: Color,
+    pub environment: Option<Arc<CubeMap>>, // Image based lighting, replaces the background color
+    pub settings: Settings,                // Renderer settings given by the scene file
+    pub volumes: Vec<Volume>,              // Participating media
+    pub atmosphere: Option<Atmosphere>,    // Sky and aerial perspective, replaces the background
+    pub cameras: Vec<CameraConfig>,        // Viewpoints given by the scene file
+    pub animations: Vec<ObjectAnimation>,  // Objects moved by keyframes, see `set_time`
+    pub light_links: Vec<LightLink>,       // Lights restricted to some objects
+    pub contact_shadows: Vec<ContactShadow>, // Objects grounded in the previews
+    pub warnings: Vec<String>,             // Problems found while loading that didn't stop it
 }
 
 impl Scene {
@@ -38,9 +64,248 @@ impl Scene {
     pub fn get_objects(&self) -> &Vec<Object> {
         self.objects.as_ref()
     }
+
+    /// Move the animated objects to where they are at `time`, in seconds.
+    /// Their shapes are shared with the previous frame, and only the objects
+    /// that moved are updated. Returns how many did.
+    pub fn set_time(&mut self, time: f64) -> usize {
+        let mut moved = 0;
+        for animation in &mut self.animations {
+            if let Some(instance) = animation.update(time) {
+                self.objects[animation.object].shape = Box::new(instance);
+                moved += 1;
+            }
+        }
+        moved
+    }
+
+    /// Let the shapes adapt to a frame seen from `eye`, e.g. pick their level
+    /// of detail. Call it for every frame whose camera moves, after
+    /// `set_time`. Shapes shared by animated objects keep their level.
+    pub fn set_viewpoint(&mut self, eye: &glm::DVec3) {
+        for object in &mut self.objects {
+            object.shape.set_viewpoint(eye);
+        }
+    }
+
+    /// Keyframes of an object, if it is animated
+    pub fn animation(&self, object: usize) -> Option<&ObjectAnimation> {
+        self.animations
+            .iter()
+            .find(|animation| animation.object == object)
+    }
+
+    /// Link of a light, if its effect is restricted
+    pub fn light_link(&self, light: usize) -> Option<&LightLink> {
+        self.light_links.iter().find(|link| link.light == light)
+    }
+
+    pub fn contact_shadow(&self, object: usize) -> Option<&ContactShadow> {
+        self.contact_shadows
+            .iter()
+            .find(|shadow| shadow.object == object)
+    }
+
+    /// Whether the light of object `light` reaches object `object`
+    pub fn illuminates(&self, light: usize, object: usize) -> bool {
+        self.light_link(light)
+            .is_none_or(|link| link.illuminates(object))
+    }
+
+    /// Whether object `object` blocks the light of object `light`
+    pub fn is_shadowed_by(&self, light: usize, object: usize) -> bool {
+        self.light_link(light)
+            .is_none_or(|link| link.is_shadowed_by(object))
+    }
+
+    /// Write the scene as a scene file, see `loader::save_scene`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        loader::save_scene(self, path)
+    }
+
+    /// Bounds of the bounded objects. Unbounded shapes, like planes, are left
+    /// out so that the result can be used to frame the scene.
+    pub fn bounds(&self) -> Bounds {
+        self.objects
+            .iter()
+            .map(Object::bounds)
+            .filter(Bounds::is_finite)
+            .fold(Bounds::empty(), |bounds, object| bounds.union(&object))
+    }
+
+    /// Centroid of the bounded objects, weighting all objects the same
+    pub fn centroid(&self) -> Option<glm::DVec3> {
+        let centroids: Vec<glm::DVec3> = self.objects.iter().filter_map(Object::centroid).collect();
+        (!centroids.is_empty())
+            .then(|| centroids.iter().sum::<glm::DVec3>() / centroids.len() as f64)
+    }
+
+    pub fn summary(&self) -> SceneSummary {
+        SceneSummary {
+            objects: self.objects.len(),
+            emitters: self
+                .objects
+                .iter()
+                .filter(|object| {
+                    object
+                        .all_materials()
+                        .any(|material| material.emittance > 0.0)
+                })
+                .count(),
+            specular: self
+                .objects
+                .iter()
+                .filter(|object| {
+                    object
+                        .all_materials()
+                        .any(|material| material.specular().is_some())
+                })
+                .count(),
+            background: self.background_color,
+            environment_size: self
+                .environment
+                .as_ref()
+                .map(|environment| environment.size()),
+        }
+    }
+
+    /// Radiance arriving from the background along a ray direction
+    pub fn background(&self, direction: &glm::DVec3) -> Color {
+        if let Some(atmosphere) = &self.atmosphere {
+            return atmosphere.sky(direction);
+        }
+        match &self.environment {
+            Some(environment) => environment.sample(direction),
+            None => self.background_color,
+        }
+    }
+}
+
+/// Overview of the contents of a scene
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneSummary {
+    pub objects: usize,
+    pub emitters: usize,
+    pub specular: usize,
+    pub background: Color,
+    pub environment_size: Option<u32>, // Face size of the environment cube map
+}
+
+impl SceneSummary {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "objects": self.objects,
+            "emitters": self.emitters,
+            "specular": self.specular,
+            "background": [self.background.x, self.background.y, self.background.z],
+            "environment": self.environment_size.map(|size| json!({ "size": size })),
+        })
+    }
+}
+
+impl fmt::Display for SceneSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Objects:     {}", self.objects)?;
+        writeln!(f, "Emitters:    {}", self.emitters)?;
+        writeln!(f, "Specular:    {}", self.specular)?;
+        writeln!(
+            f,
+            "Background:  ({}, {}, {})",
+            self.background.x, self.background.y, self.background.z
+        )?;
+        match self.environment_size {
+            Some(size) => write!(f, "Environment: {size}×{size} cube map"),
+            None => write!(f, "Environment: none"),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::material::{Bsdf, Material, Specular};
+    use crate::shape::{Plane, Sphere, Triangle};
+
+    #[test]
+    fn summary() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 2.0, 3.0);
+        for (emittance, specular) in [(0.0, None), (2.0, None), (0.0, Some(Specular::Mirror))] {
+            scene.add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+                material: Material {
+                    emittance,
+                    bsdf: specular.map(|specular| Arc::new(specular) as Arc<dyn Bsdf>),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        }
+
+        let summary = scene.summary();
+        assert_eq!(
+            (3, 1, 1),
+            (summary.objects, summary.emitters, summary.specular)
+        );
+        assert_eq!(
+            json!({
+                "objects": 3,
+                "emitters": 1,
+                "specular": 1,
+                "background": [1.0, 2.0, 3.0],
+                "environment": null,
+            }),
+            summary.to_json()
+        );
+    }
+
+    #[test]
+    fn bounds() {
+        let mut scene = Scene::new();
+        assert!(scene.bounds().is_empty());
+        assert_eq!(None, scene.centroid());
+
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(1.0, 2.0, 3.0), 1.0)),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Triangle::new(
+                    glm::DVec3::new(-3.0, 0.0, 0.0),
+                    glm::DVec3::new(0.0, 3.0, 0.0),
+                    glm::DVec3::new(0.0, 0.0, -3.0),
+                )),
+                material: Material::default(),
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material::default(),
+                materials: Vec::new(),
+            });
+
+        assert!(!scene.objects[2].bounds().is_finite());
+        assert_eq!(None, scene.objects[2].centroid());
+        assert_eq!(
+            Some(glm::DVec3::new(-1.0, 1.0, -1.0)),
+            scene.objects[1].centroid()
+        );
+
+        // The plane doesn't count
+        let bounds = scene.bounds();
+        assert_eq!(glm::DVec3::new(-3.0, 0.0, -3.0), bounds.min);
+        assert_eq!(glm::DVec3::new(2.0, 3.0, 4.0), bounds.max);
+        assert!(bounds.contains(&glm::DVec3::new(1.0, 2.0, 3.0)));
+        assert!(!bounds.contains(&glm::DVec3::new(1.0, 2.0, 5.0)));
+        assert_eq!(Some(glm::DVec3::new(0.0, 1.5, 1.0)), scene.centroid());
+
+        let (center, radius) = bounds.bounding_sphere();
+        assert_eq!(glm::DVec3::new(-0.5, 1.5, 0.5), center);
+        assert_eq!(0.5 * glm::DVec3::new(5.0, 3.0, 7.0).norm(), radius);
+    }
 }
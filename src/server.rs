@@ -0,0 +1,936 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Headless render server, driven over HTTP with JSON requests.
+//!
+//! - `POST /jobs`: submit a job, returns its id. The body is a JSON object
+//!   with the scene and the camera, both in the format of the scene files:
+//!   `{"scene": {...}, "camera": {"position": [x, y, z], "direction":
+//!   [x, y, z], "resolution": [w, h], "fov": degrees}}`. Optional
+//!   `samples_per_pixel` and `max_depth` override the scene settings,
+//!   `priority` (0 by default, higher goes first) orders the jobs and
+//!   `threads` limits the render threads of the job.
+//! - `GET /jobs`: progress of every job
+//! - `GET /jobs/<id>`: progress of a job, with the list of finished tiles
+//! - `GET /jobs/<id>/preview`: PNG of the image so far
+//! - `GET /jobs/<id>/tiles/<n>`: PNG of the n-th finished tile
+//! - `GET /jobs/<id>/image`: PNG of the final image, once the job is done
+//! - `GET /jobs/<id>/stream`: WebSocket with the progress of a job, see below
+//!
+//! The stream starts with the tiles finished so far and goes on with each
+//! new one. Every tile is sent as a text message, `{"type": "tile", "x",
+//! "y", "width", "height", "tiles_done", "tiles_total"}` in image pixels,
+//! followed by a binary message with the JPEG of the tile. The query
+//! options `scale=<n>` and `quality=<1-100>` shrink the tiles by an integer
+//! factor and set the JPEG quality. When the job ends, a `{"type":
+//! "status", ...}` message with its progress is sent and the stream is
+//! closed.
+//!
+//! Jobs are rendered in passes of a few samples per pixel, which accumulate
+//! in the image of the job. After each pass, the worker goes on with the
+//! job of highest priority: a high priority preview pauses the renders of
+//! lower priority, which resume where they were once it is done. Scenes are
+//! parsed when their job starts, a job with an invalid scene fails with the
+//! error.
+//!
+//! Jobs are held to the `JobLimits` of the server: images that are too
+//! large are refused when they are submitted, and scenes over the memory
+//! budget or that name files outside of the asset root fail when they
+//! start.
+
+use std::io::{Cursor, Read, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{ImageFormat, RgbImage};
+use serde_json::{json, Value};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::camera::Camera;
+use crate::film::{Film, FilmPrecision, ToneMapper};
+use crate::loader::{self, FileLoader};
+use crate::preprocess::{Bytes, PreprocessReport};
+use crate::render::{PathTracer, TILE_SIZE};
+use crate::scene::Scene;
+use crate::texture::{self, TextureCache};
+use crate::tile::{self, Tile, TileOrder};
+
+/// Samples per pixel of each pass of a job
+const SAMPLES_PER_PASS: u32 = 4;
+
+/// Limits on the jobs a server accepts
+#[derive(Debug, Clone)]
+pub struct JobLimits {
+    pub max_resolution: u32,  // Of the width and the height of images
+    pub memory_budget: usize, // Bytes the scene and film of a job may take
+    pub asset_root: PathBuf,  // Directory the files named by scenes are read from
+    pub texture_dir: PathBuf, // Directory of the tiled texture files
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        Self {
+            max_resolution: 16384,
+            memory_budget: 4 << 30,
+            asset_root: PathBuf::from("."),
+            texture_dir: std::env::temp_dir().join("light-textures"),
+        }
+    }
+}
+
+/// Stage of a render job
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Rendering,
+    Paused, // Started, waiting for jobs of higher priority
+    Done,
+    Failed(String),
+}
+
+impl JobStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Rendering => "rendering",
+            Self::Paused => "paused",
+            Self::Done => "done",
+            Self::Failed(_) => "failed",
+        }
+    }
+}
+
+/// Render job and its image so far
+struct Job {
+    status: JobStatus,
+    priority: i32,
+    film: Film,
+    tiles: Vec<Tile>, // Finished tiles of all the passes, in the order they were finished
+    total_tiles: usize, // Of all the passes, known once the job starts
+    subscribers: Vec<mpsc::Sender<Tile>>, // Streams waiting for new tiles
+}
+
+impl Job {
+    fn to_json(&self, id: usize) -> Value {
+        let mut job = json!({
+            "id": id,
+            "status": self.status.name(),
+            "priority": self.priority,
+            "tiles_done": self.tiles.len(),
+            "tiles_total": self.total_tiles,
+            "progress": self.tiles.len() as f64 / self.total_tiles.max(1) as f64,
+        });
+        if let JobStatus::Failed(error) = &self.status {
+            job["error"] = json!(error);
+        }
+        job
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(
+            self.status,
+            JobStatus::Queued | JobStatus::Rendering | JobStatus::Paused
+        )
+    }
+
+    /// Image of a tile of the film
+    fn tile_image(&self, tile: &Tile, tone_mapper: ToneMapper) -> RgbImage {
+        RgbImage::from_fn(tile.width, tile.height, |i, j| {
+            let color = self.film.pixel(tile.x + i, tile.y + j).color();
+            image::Rgb([
+                tone_mapper.map(color.x),
+                tone_mapper.map(color.y),
+                tone_mapper.map(color.z),
+            ])
+        })
+    }
+}
+
+/// Options of a preview stream, given in the query of its URL
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StreamOptions {
+    scale: u32,  // Tiles are shrunk by this factor
+    quality: u8, // Of the JPEG encoding
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            scale: 1,
+            quality: 80,
+        }
+    }
+}
+
+impl StreamOptions {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut options = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = |e| format!("Invalid {name}: {e}");
+            match name {
+                "scale" => options.scale = value.parse().map_err(invalid)?,
+                "quality" => options.quality = value.parse().map_err(invalid)?,
+                _ => return Err(format!("Unknown option '{name}'")),
+            }
+        }
+        if options.scale == 0 || !(1..=100).contains(&options.quality) {
+            return Err("The scale must be positive and the quality in [1, 100]".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// Send the tiles of a job through a WebSocket as they are finished, until
+/// the job ends or the client goes away
+fn stream_job<S: Read + Write>(
+    id: usize,
+    job: &Mutex<Job>,
+    options: StreamOptions,
+    tone_mapper: ToneMapper,
+    socket: &mut WebSocket<S>,
+) -> Result<(), String> {
+    let tiles = {
+        let (sender, tiles) = mpsc::channel();
+        let mut job = job.lock().unwrap();
+        for tile in &job.tiles {
+            let _ = sender.send(*tile);
+        }
+        // Once the job ends, its subscribers are dropped and the stream
+        // ends after the tiles already sent
+        if job.is_running() {
+            job.subscribers.push(sender);
+        }
+        tiles
+    };
+
+    for (n, tile) in tiles.iter().enumerate() {
+        let (header, image) = {
+            let job = job.lock().unwrap();
+            let header = json!({
+                "type": "tile",
+                "x": tile.x,
+                "y": tile.y,
+                "width": tile.width,
+                "height": tile.height,
+                "tiles_done": n + 1,
+                "tiles_total": job.total_tiles,
+            });
+            (header, job.tile_image(&tile, tone_mapper))
+        };
+        let image = match options.scale {
+            1 => image,
+            scale => imageops::resize(
+                &image,
+                image.width().div_ceil(scale),
+                image.height().div_ceil(scale),
+                FilterType::Triangle,
+            ),
+        };
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, options.quality)
+            .encode_image(&image)
+            .map_err(|e| e.to_string())?;
+
+        socket
+            .send(Message::Text(header.to_string()))
+            .map_err(|e| e.to_string())?;
+        socket
+            .send(Message::Binary(jpeg))
+            .map_err(|e| e.to_string())?;
+    }
+
+    let mut status = job.lock().unwrap().to_json(id);
+    status["type"] = json!("status");
+    socket
+        .send(Message::Text(status.to_string()))
+        .map_err(|e| e.to_string())?;
+    socket.close(None).map_err(|e| e.to_string())?;
+    socket.flush().map_err(|e| e.to_string())
+}
+
+/// Job waiting for the render worker. The scene is parsed by the worker,
+/// which owns it while rendering.
+struct JobRequest {
+    id: usize,
+    scene: String, // Source of the scene
+    camera: Camera,
+    samples_per_pixel: Option<u32>,
+    max_depth: Option<u32>,
+    priority: i32,
+    threads: Option<usize>, // Render threads of the job, all of them if None
+    film_memory: usize,     // Bytes
+}
+
+/// Job started by the render worker
+struct StartedJob {
+    scene: Scene,
+    pool: Option<rayon::ThreadPool>,
+    max_depth: u32,
+    samples_left: u32, // Samples per pixel still to render
+}
+
+/// Job of the render worker, with its state between passes
+struct ActiveJob {
+    request: JobRequest,
+    job: Arc<Mutex<Job>>,
+    started: Option<StartedJob>,
+}
+
+impl ActiveJob {
+    fn start(&self, limits: &JobLimits) -> Result<StartedJob, String> {
+        let textures = Arc::new(TextureCache::with_directory(
+            texture::budget_share(limits.memory_budget),
+            &limits.texture_dir,
+        ));
+        let mut scene = FileLoader::new()
+            .texture_cache(textures.clone())
+            .asset_root(Some(limits.asset_root.clone()))
+            .parse_scene(&self.request.scene)
+            .map_err(|e| format!("Invalid scene: {e}"))?;
+        PreprocessReport::new(&scene)
+            .texture_cache(&textures)
+            .check_memory(self.request.film_memory, limits.memory_budget)?;
+        scene.set_viewpoint(&self.request.camera.position());
+        let pool = self
+            .request
+            .threads
+            .map(|threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|e| e.to_string())
+            })
+            .transpose()?;
+        let samples_per_pixel = self
+            .request
+            .samples_per_pixel
+            .or(scene.settings.samples_per_pixel)
+            .unwrap_or(16);
+        let max_depth = self
+            .request
+            .max_depth
+            .or(scene.settings.max_depth)
+            .unwrap_or(5);
+
+        let (w, h) = self.request.camera.resolution();
+        let tiles = tile::generate_tiles(w, h, TILE_SIZE, TileOrder::default()).len();
+        let passes = samples_per_pixel.div_ceil(SAMPLES_PER_PASS) as usize;
+        self.job.lock().unwrap().total_tiles = tiles * passes;
+
+        Ok(StartedJob {
+            scene,
+            pool,
+            max_depth,
+            samples_left: samples_per_pixel,
+        })
+    }
+
+    /// Render the next pass of the job, starting it if needed. Returns
+    /// whether the job is over.
+    fn render_pass(&mut self, limits: &JobLimits) -> Result<bool, String> {
+        if self.started.is_none() {
+            self.started = Some(self.start(limits)?);
+        }
+        let Some(started) = &mut self.started else {
+            return Ok(true);
+        };
+
+        let samples = started.samples_left.min(SAMPLES_PER_PASS);
+        let mut renderer = PathTracer::new();
+        renderer
+            .samples_per_pixel(samples)
+            .max_depth(started.max_depth);
+        let render = || {
+            renderer.render_tiles_with(&started.scene, &self.request.camera, &|tile, pixels| {
+                let mut job = self.job.lock().unwrap();
+                job.film.merge_tile(tile, pixels);
+                job.tiles.push(*tile);
+                job.subscribers
+                    .retain(|subscriber| subscriber.send(*tile).is_ok());
+            })
+        };
+        match &started.pool {
+            Some(pool) => pool.install(render),
+            None => render(),
+        }
+
+        started.samples_left -= samples;
+        Ok(started.samples_left == 0)
+    }
+}
+
+/// Render worker: renders the jobs pass by pass, always going on with the
+/// job of highest priority. Among jobs of the same priority, the oldest
+/// goes first.
+fn run_worker(
+    requests: mpsc::Receiver<JobRequest>,
+    jobs: &Mutex<Vec<Arc<Mutex<Job>>>>,
+    limits: &JobLimits,
+) {
+    let mut active: Vec<ActiveJob> = Vec::new();
+    loop {
+        // Wait for work when idle, otherwise only pick up the new jobs
+        if active.is_empty() {
+            let Ok(request) = requests.recv() else {
+                return;
+            };
+            active.push(ActiveJob {
+                job: Arc::clone(&jobs.lock().unwrap()[request.id]),
+                request,
+                started: None,
+            });
+        }
+        for request in requests.try_iter() {
+            active.push(ActiveJob {
+                job: Arc::clone(&jobs.lock().unwrap()[request.id]),
+                request,
+                started: None,
+            });
+        }
+
+        let Some(next) = (0..active.len()).max_by_key(|&n| {
+            let request = &active[n].request;
+            (request.priority, std::cmp::Reverse(request.id))
+        }) else {
+            continue;
+        };
+        for (n, other) in active.iter().enumerate() {
+            if n != next && other.started.is_some() {
+                other.job.lock().unwrap().status = JobStatus::Paused;
+            }
+        }
+
+        active[next].job.lock().unwrap().status = JobStatus::Rendering;
+        let status = match active[next].render_pass(limits) {
+            Ok(false) => continue,
+            Ok(true) => JobStatus::Done,
+            Err(e) => JobStatus::Failed(e),
+        };
+        let finished = active.remove(next);
+        let mut job = finished.job.lock().unwrap();
+        job.status = status;
+        job.subscribers.clear();
+    }
+}
+
+/// Answer to a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn png(image: &RgbImage) -> Self {
+        let mut body = Cursor::new(Vec::new());
+        match image.write_to(&mut body, ImageFormat::Png) {
+            Ok(()) => Self {
+                status: 200,
+                content_type: "image/png",
+                body: body.into_inner(),
+            },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+}
+
+pub struct RenderServer {
+    jobs: Arc<Mutex<Vec<Arc<Mutex<Job>>>>>,
+    queue: mpsc::Sender<JobRequest>,
+    tone_mapper: ToneMapper,
+    limits: JobLimits,
+}
+
+impl RenderServer {
+    /// Server with a worker that renders the submitted jobs, within the
+    /// default limits
+    pub fn new(tone_mapper: ToneMapper) -> Self {
+        Self::with_limits(tone_mapper, JobLimits::default())
+    }
+
+    pub fn with_limits(tone_mapper: ToneMapper, limits: JobLimits) -> Self {
+        let jobs: Arc<Mutex<Vec<Arc<Mutex<Job>>>>> = Arc::default();
+        let (queue, requests) = mpsc::channel::<JobRequest>();
+
+        let worker_jobs = Arc::clone(&jobs);
+        let worker_limits = limits.clone();
+        thread::spawn(move || run_worker(requests, &worker_jobs, &worker_limits));
+
+        Self {
+            jobs,
+            queue,
+            tone_mapper,
+            limits,
+        }
+    }
+
+    /// Serve requests on an address like "127.0.0.1:8080" until the process
+    /// is stopped
+    pub fn serve(&self, address: &str) -> Result<(), String> {
+        let server = tiny_http::Server::http(address).map_err(|e| format!("{address}: {e}"))?;
+        self.serve_on(&server);
+        Ok(())
+    }
+
+    fn serve_on(&self, server: &tiny_http::Server) {
+        for request in server.incoming_requests() {
+            let key = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+                .map(|header| header.value.to_string());
+            let mut request = match key {
+                Some(key) => match self.open_stream(request, &key) {
+                    Some(request) => request,
+                    None => continue,
+                },
+                None => request,
+            };
+            let mut body = String::new();
+            let response = match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => self.handle(request.method().as_str(), request.url(), &body),
+                Err(e) => Response::error(400, &e.to_string()),
+            };
+            let content_type = tiny_http::Header::from_bytes("Content-Type", response.content_type)
+                .expect("Valid header");
+            let reply = tiny_http::Response::from_data(response.body)
+                .with_status_code(response.status)
+                .with_header(content_type);
+            // The client may be gone, which doesn't concern the other ones
+            let _ = request.respond(reply);
+        }
+    }
+
+    /// Accept a WebSocket request for the stream of a job, which is served
+    /// by a thread of its own. Requests for anything else are given back.
+    fn open_stream(&self, request: tiny_http::Request, key: &str) -> Option<tiny_http::Request> {
+        let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let ["jobs", id, "stream"] = parts[..] else {
+            return Some(request);
+        };
+        let Some((id, job)) = id.parse().ok().and_then(|id| Some((id, self.job(id)?))) else {
+            return Some(request);
+        };
+        let options = match StreamOptions::parse(query) {
+            Ok(options) => options,
+            Err(e) => {
+                let reply = tiny_http::Response::from_string(json!({ "error": e }).to_string())
+                    .with_status_code(400);
+                let _ = request.respond(reply);
+                return None;
+            }
+        };
+
+        let accept = tiny_http::Header::from_bytes(
+            "Sec-WebSocket-Accept",
+            tungstenite::handshake::derive_accept_key(key.as_bytes()),
+        )
+        .expect("Valid header");
+        let stream = request.upgrade(
+            "websocket",
+            tiny_http::Response::empty(101).with_header(accept),
+        );
+        let tone_mapper = self.tone_mapper;
+        thread::spawn(move || {
+            let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+            // A client that went away only ends its own stream
+            let _ = stream_job(id, &job, options, tone_mapper, &mut socket);
+        });
+        None
+    }
+
+    /// Answer a request given its method, URL and body
+    pub fn handle(&self, method: &str, url: &str, body: &str) -> Response {
+        let path: Vec<&str> = url
+            .split('?')
+            .next()
+            .unwrap_or("")
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect();
+
+        match (method, path.as_slice()) {
+            ("POST", ["jobs"]) => match self.submit(body) {
+                Ok(id) => Response::json(201, json!({ "id": id })),
+                Err(e) => Response::error(400, &e),
+            },
+            ("GET", ["jobs"]) => {
+                let jobs = self.jobs.lock().unwrap();
+                let jobs: Vec<Value> = jobs
+                    .iter()
+                    .enumerate()
+                    .map(|(id, job)| job.lock().unwrap().to_json(id))
+                    .collect();
+                Response::json(200, Value::Array(jobs))
+            }
+            ("GET", ["jobs", id, rest @ ..]) => {
+                let Some((id, job)) = id.parse().ok().and_then(|id| Some((id, self.job(id)?)))
+                else {
+                    return Response::error(404, &format!("No job {id}"));
+                };
+                let job = job.lock().unwrap();
+                match rest {
+                    [] => {
+                        let mut value = job.to_json(id);
+                        value["tiles"] = job
+                            .tiles
+                            .iter()
+                            .map(|tile| json!([tile.x, tile.y, tile.width, tile.height]))
+                            .collect();
+                        Response::json(200, value)
+                    }
+                    ["preview"] => Response::png(&job.film.to_image_with(self.tone_mapper)),
+                    ["tiles", n] => match n.parse::<usize>().ok().and_then(|n| job.tiles.get(n)) {
+                        Some(tile) => Response::png(&job.tile_image(tile, self.tone_mapper)),
+                        None => Response::error(404, &format!("Tile {n} is not finished")),
+                    },
+                    ["image"] if job.status == JobStatus::Done => {
+                        Response::png(&job.film.to_image_with(self.tone_mapper))
+                    }
+                    ["image"] => Response::error(409, "The job isn't done"),
+                    _ => Response::error(404, "Unknown endpoint"),
+                }
+            }
+            (_, ["jobs", ..]) => Response::error(405, "Method not allowed"),
+            _ => Response::error(404, "Unknown endpoint"),
+        }
+    }
+
+    fn job(&self, id: usize) -> Option<Arc<Mutex<Job>>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Parse a job and queue it. Returns its id.
+    fn submit(&self, body: &str) -> Result<usize, String> {
+        let root: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+        let scene = loader::field(&root, "scene")?.to_string();
+        let config =
+            loader::parse_camera_within(loader::field(&root, "camera")?, &self.limits.asset_root)
+                .map_err(|e| format!("Invalid camera: {e}"))?;
+        let (w, h) = config.resolution;
+        let max = self.limits.max_resolution;
+        if w == 0 || h == 0 || w > max || h > max {
+            return Err(format!(
+                "The resolution must be from 1 to {max} on each side"
+            ));
+        }
+        let budget = self.limits.memory_budget;
+        let film_memory = (w as usize)
+            .checked_mul(h as usize)
+            .and_then(|pixels| pixels.checked_mul(FilmPrecision::default().pixel_size()))
+            .filter(|&bytes| bytes <= budget)
+            .ok_or_else(|| format!("The image is over the memory budget of {}", Bytes(budget)))?;
+        let camera = Camera::new(&config);
+
+        let count = |name: &str| -> Result<Option<u32>, String> {
+            root.get(name)
+                .map(|value| {
+                    value
+                        .as_u64()
+                        .and_then(|n| u32::try_from(n).ok())
+                        .ok_or(format!("'{name}' must be a positive integer"))
+                })
+                .transpose()
+        };
+        let samples_per_pixel = count("samples_per_pixel")?;
+        let max_depth = count("max_depth")?;
+        let threads = match count("threads")? {
+            Some(0) => return Err("'threads' must be a positive integer".to_string()),
+            threads => threads.map(|threads| threads as usize),
+        };
+        let priority = match root.get("priority") {
+            Some(priority) => priority
+                .as_i64()
+                .and_then(|priority| i32::try_from(priority).ok())
+                .ok_or("'priority' must be an integer")?,
+            None => 0,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.len();
+        jobs.push(Arc::new(Mutex::new(Job {
+            status: JobStatus::Queued,
+            priority,
+            film: Film::new(w, h),
+            tiles: Vec::new(),
+            total_tiles: 0,
+            subscribers: Vec::new(),
+        })));
+        self.queue
+            .send(JobRequest {
+                id,
+                scene,
+                camera,
+                samples_per_pixel,
+                max_depth,
+                priority,
+                threads,
+                film_memory,
+            })
+            .map_err(|_| "The render worker stopped".to_string())?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tungstenite::stream::MaybeTlsStream;
+
+    use super::*;
+
+    fn job_body(width: u32) -> String {
+        json!({
+            "scene": {
+                "background": [200, 100, 50],
+                "objects": []
+            },
+            "camera": {
+                "position": [0, 0, 0],
+                "direction": [0, 0, 1],
+                "resolution": [width, 16]
+            },
+            "samples_per_pixel": 1
+        })
+        .to_string()
+    }
+
+    fn status(server: &RenderServer, id: usize) -> Value {
+        let response = server.handle("GET", &format!("/jobs/{id}"), "");
+        assert_eq!(200, response.status);
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn render_a_job() {
+        let server = RenderServer::new(ToneMapper::default());
+        let response = server.handle("POST", "/jobs", &job_body(48));
+        assert_eq!(201, response.status);
+        let id = serde_json::from_slice::<Value>(&response.body).unwrap()["id"]
+            .as_u64()
+            .unwrap() as usize;
+
+        let start = Instant::now();
+        while status(&server, id)["status"] != "done" {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let job = status(&server, id);
+        assert_eq!(2, job["tiles_total"]);
+        assert_eq!(1.0, job["progress"]);
+
+        let image = server.handle("GET", &format!("/jobs/{id}/image"), "");
+        assert_eq!("image/png", image.content_type);
+        let image = image::load_from_memory(&image.body).unwrap().into_rgb8();
+        assert_eq!((48, 16), image.dimensions());
+        assert_eq!(&image::Rgb([229, 168, 122]), image.get_pixel(40, 8));
+
+        let tile = server.handle("GET", &format!("/jobs/{id}/tiles/1"), "");
+        let tile = image::load_from_memory(&tile.body).unwrap();
+        assert!(tile.width() == 32 || tile.width() == 16);
+        assert_eq!(16, tile.height());
+    }
+
+    #[test]
+    fn high_priority_jobs_go_first() {
+        let server = RenderServer::new(ToneMapper::default());
+        let submit = |body: &Value| server.handle("POST", "/jobs", &body.to_string()).status;
+
+        // A long render, then a quick preview with a single thread
+        let mut long: Value = serde_json::from_str(&job_body(48)).unwrap();
+        long["samples_per_pixel"] = json!(200);
+        assert_eq!(201, submit(&long));
+        let start = Instant::now();
+        while status(&server, 0)["status"] != "rendering" {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut preview: Value = serde_json::from_str(&job_body(48)).unwrap();
+        preview["priority"] = json!(10);
+        preview["threads"] = json!(1);
+        assert_eq!(201, submit(&preview));
+
+        let start = Instant::now();
+        while status(&server, 1)["status"] != "done" {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let long = status(&server, 0);
+        assert_ne!("done", long["status"]);
+        assert_eq!(2 * 50, long["tiles_total"]);
+        assert_eq!(10, status(&server, 1)["priority"]);
+
+        preview["threads"] = json!(0);
+        assert_eq!(400, submit(&preview));
+    }
+
+    #[test]
+    fn stream_tiles() {
+        let server = Arc::new(RenderServer::new(ToneMapper::default()));
+        let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = http.server_addr().to_ip().unwrap();
+        thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.serve_on(&http)
+        });
+        assert_eq!(201, server.handle("POST", "/jobs", &job_body(48)).status);
+
+        let url = format!("ws://{address}/jobs/0/stream?scale=2&quality=90");
+        let (mut socket, _) = tungstenite::connect(url).unwrap();
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(30)))
+                .unwrap();
+        }
+        let mut headers = Vec::new();
+        let mut tiles = Vec::new();
+        loop {
+            match socket.read().unwrap() {
+                Message::Text(text) => headers.push(serde_json::from_str::<Value>(&text).unwrap()),
+                Message::Binary(jpeg) => tiles.push(image::load_from_memory(&jpeg).unwrap()),
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        // Two tiles, each with its header, then the status
+        assert_eq!(3, headers.len());
+        assert_eq!(2, tiles.len());
+        assert_eq!("tile", headers[0]["type"]);
+        assert_eq!(2, headers[1]["tiles_done"]);
+        assert_eq!("status", headers[2]["type"]);
+        assert_eq!("done", headers[2]["status"]);
+        for (header, tile) in headers.iter().zip(&tiles) {
+            let width = header["width"].as_u64().unwrap() as u32;
+            assert_eq!((width / 2, 8), (tile.width(), tile.height()));
+        }
+        let color = tiles[0].to_rgb8().get_pixel(4, 4).0;
+        assert!(color[0].abs_diff(229) < 8 && color[2].abs_diff(122) < 8);
+    }
+
+    #[test]
+    fn stream_options() {
+        assert_eq!(Ok(StreamOptions::default()), StreamOptions::parse(""));
+        assert_eq!(
+            Ok(StreamOptions {
+                scale: 4,
+                quality: 60
+            }),
+            StreamOptions::parse("quality=60&scale=4")
+        );
+        assert!(StreamOptions::parse("scale=0").is_err());
+        assert!(StreamOptions::parse("quality=101").is_err());
+        assert!(StreamOptions::parse("size=2").is_err());
+    }
+
+    #[test]
+    fn jobs_within_limits() {
+        let dir = std::env::temp_dir().join(format!("light-server-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        RgbImage::new(1, 1).save(dir.join("secret.png")).unwrap();
+        RgbImage::new(1, 1)
+            .save(dir.join("assets/wood.png"))
+            .unwrap();
+        let server = RenderServer::with_limits(
+            ToneMapper::default(),
+            JobLimits {
+                max_resolution: 32,
+                memory_budget: 2 * 16 * 16 * FilmPrecision::default().pixel_size(),
+                asset_root: dir.join("assets"),
+                texture_dir: dir.join("textures"),
+            },
+        );
+        let submit = |body: &Value| server.handle("POST", "/jobs", &body.to_string()).status;
+
+        // Too wide, then too many pixels for the budget
+        let mut job: Value = serde_json::from_str(&job_body(48)).unwrap();
+        assert_eq!(400, submit(&job));
+        job["camera"]["resolution"] = json!([32, 32]);
+        assert_eq!(400, submit(&job));
+
+        // Files are read from the asset root, and not from outside of it
+        job["camera"]["resolution"] = json!([16, 16]);
+        let sphere = |texture: &str| {
+            json!([{
+                "shape": { "type": "sphere", "center": [0, 0, 5], "radius": 1 },
+                "material": { "type": "diffuse", "texture": texture }
+            }])
+        };
+        job["scene"]["objects"] = sphere("wood.png");
+        assert_eq!(201, submit(&job));
+        job["scene"]["objects"] = sphere("../secret.png");
+        assert_eq!(201, submit(&job));
+
+        let start = Instant::now();
+        while status(&server, 1)["status"] != "failed" || status(&server, 0)["status"] != "done" {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(status(&server, 1)["error"]
+            .as_str()
+            .unwrap()
+            .contains("is outside of"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_requests() {
+        let server = RenderServer::new(ToneMapper::default());
+        assert_eq!(400, server.handle("POST", "/jobs", "{}").status);
+        assert_eq!(400, server.handle("POST", "/jobs", &job_body(0)).status);
+        assert_eq!(404, server.handle("GET", "/jobs/3", "").status);
+        assert_eq!(404, server.handle("GET", "/jobs/x/image", "").status);
+        assert_eq!(404, server.handle("GET", "/scenes", "").status);
+        assert_eq!(405, server.handle("DELETE", "/jobs", "").status);
+
+        // Scenes are only parsed once the job starts
+        let body = job_body(16).replace("\"objects\":[]", "\"objects\":3");
+        assert_eq!(201, server.handle("POST", "/jobs", &body).status);
+        let start = Instant::now();
+        while status(&server, 0)["status"] != "failed" {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(status(&server, 0)["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid scene"));
+    }
+}
@@ -17,16 +17,25 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use glm;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
 
 use crate::algebra;
 use crate::light::Ray;
+use crate::sampling;
 
 #[derive(Debug, PartialEq)]
 pub struct HitRecord {
     pub ray_t: f64,
     pub point: glm::DVec3,
     pub normal: glm::DVec3,
+    pub uv: glm::DVec2,  // Surface parametrization at the hit point
+    pub uv_density: f64, // Change of the uv per unit of distance on the surface, 0 if unknown
+    pub material: usize, // Index of the material of the face, see `Object::material_at`
 }
 
 impl Default for HitRecord {
@@ -35,6 +44,9 @@ impl Default for HitRecord {
             ray_t: f64::INFINITY,
             point: glm::DVec3::zeros(),
             normal: glm::DVec3::zeros(),
+            uv: glm::DVec2::zeros(),
+            uv_density: 0.0,
+            material: 0,
         }
     }
 }
@@ -45,8 +57,199 @@ impl HitRecord {
     }
 }
 
+/// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: glm::DVec3,
+    pub max: glm::DVec3,
+}
+
+impl Bounds {
+    /// Bounds that contain nothing, the identity of `union`
+    pub fn empty() -> Self {
+        Self {
+            min: glm::DVec3::repeat(f64::INFINITY),
+            max: glm::DVec3::repeat(f64::NEG_INFINITY),
+        }
+    }
+
+    /// Bounds of the whole space, for unbounded shapes
+    pub fn infinite() -> Self {
+        Self {
+            min: glm::DVec3::repeat(f64::NEG_INFINITY),
+            max: glm::DVec3::repeat(f64::INFINITY),
+        }
+    }
+
+    pub fn from_points<'a, I: IntoIterator<Item = &'a glm::DVec3>>(points: I) -> Self {
+        points
+            .into_iter()
+            .fold(Self::empty(), |bounds, point| Self {
+                min: bounds.min.inf(point),
+                max: bounds.max.sup(point),
+            })
+    }
+
+    pub fn union(&self, other: &Bounds) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min
+            .iter()
+            .zip(self.max.iter())
+            .any(|(min, max)| min > max)
+    }
+
+    pub fn is_finite(&self) -> bool {
+        !self.is_empty()
+            && self
+                .min
+                .iter()
+                .chain(self.max.iter())
+                .all(|c| c.is_finite())
+    }
+
+    pub fn contains(&self, point: &glm::DVec3) -> bool {
+        (0..3).all(|axis| self.min[axis] <= point[axis] && point[axis] <= self.max[axis])
+    }
+
+    pub fn size(&self) -> glm::DVec3 {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> glm::DVec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    /// Distances along a ray where it enters and leaves the box, clipped to
+    /// the positive part of the ray
+    pub fn intersect(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let (mut near, mut far) = (0.0_f64, f64::INFINITY);
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.direction[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inverse;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inverse;
+            // NaN comparisons keep the current range, for rays in a face plane
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near <= far).then_some((near, far))
+    }
+
+    /// Sphere around the box, as its center and radius
+    pub fn bounding_sphere(&self) -> (glm::DVec3, f64) {
+        (self.center(), 0.5 * self.size().norm())
+    }
+}
+
+/// A point sampled uniformly on the surface of a shape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceSample {
+    pub point: glm::DVec3,
+    pub normal: glm::DVec3, // Outward normal
+    pub area: f64,          // Total area of the surface
+}
+
+/// A point of a shape sampled as seen from another point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VisibleSample {
+    pub point: glm::DVec3,
+    pub normal: glm::DVec3, // Outward normal
+    pub pdf: f64,           // Density with respect to solid angle at the viewpoint
+}
+
+/// Density with respect to solid angle at `origin` of sampling `point`, whose
+/// normal is `normal`, uniformly on a surface of the given area
+fn area_pdf(area: f64, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+    let to_point = point - origin;
+    let distance2 = to_point.norm_squared();
+    let cos = normal.dot(&to_point).abs() / distance2.sqrt();
+    distance2 / (area * cos)
+}
+
 pub trait Shape {
     fn intersect(&self, ray: &Ray) -> Option<HitRecord>;
+
+    /// Name of the kind of shape, for reports
+    fn kind(&self) -> &'static str {
+        "shape"
+    }
+
+    /// Box containing the shape. Unbounded shapes return infinite bounds.
+    fn bounds(&self) -> Bounds {
+        Bounds::infinite()
+    }
+
+    /// Center of mass of the surface, if the shape is bounded
+    fn centroid(&self) -> Option<glm::DVec3> {
+        let bounds = self.bounds();
+        bounds.is_finite().then(|| bounds.center())
+    }
+
+    /// Sample a point uniformly by area from two uniform numbers in [0, 1).
+    /// Unbounded shapes can't be sampled and return None.
+    fn sample_surface(&self, _u: glm::DVec2) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Sample a point of the surface as seen from `origin`, from two uniform
+    /// numbers in [0, 1). The surface is sampled by area unless the shape
+    /// knows better, e.g. the part of it that `origin` can see.
+    fn sample_from(&self, origin: &glm::DVec3, u: glm::DVec2) -> Option<VisibleSample> {
+        let sample = self.sample_surface(u)?;
+        Some(VisibleSample {
+            point: sample.point,
+            normal: sample.normal,
+            pdf: area_pdf(sample.area, origin, &sample.point, &sample.normal),
+        })
+    }
+
+    /// Density with respect to solid angle at `origin` of `sample_from`
+    /// picking `point`, whose normal is `normal`. Zero for shapes that can't
+    /// be sampled.
+    fn pdf_from(&self, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+        self.sample_surface(glm::DVec2::zeros())
+            .map_or(0.0, |sample| area_pdf(sample.area, origin, point, normal))
+    }
+
+    /// Number of triangles this shape is made of
+    fn triangle_count(&self) -> usize {
+        0
+    }
+
+    /// Area of the smallest triangle, if the shape has any
+    fn min_triangle_area(&self) -> Option<f64> {
+        None
+    }
+
+    /// Estimated memory used by the shape, in bytes
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    /// Description of the shape in the scene file format, with its `type`.
+    /// Shapes that can't be written to a scene file return None.
+    fn to_json(&self) -> Option<Value> {
+        None
+    }
+
+    /// Problem found while rendering that made the shape look different,
+    /// e.g. a model that couldn't be loaded when the first ray reached it
+    fn warning(&self) -> Option<String> {
+        None
+    }
+
+    /// Adapt the shape to a frame seen from `eye`, e.g. to pick a level of
+    /// detail. Called before rendering, not while rays are traced.
+    fn set_viewpoint(&mut self, _eye: &glm::DVec3) {}
+}
+
+fn vec3_to_json(v: &glm::DVec3) -> Value {
+    json!([v.x, v.y, v.z])
 }
 
 /// Returns the closest positive distance (facing the direction of a Ray)
@@ -79,47 +282,113 @@ impl Triangle {
             normal: (c - a).cross(&(b - a)).normalize(),
         }
     }
+
+    pub fn area(&self) -> f64 {
+        0.5 * (self.vb - self.va).cross(&(self.vc - self.va)).norm()
+    }
+}
+
+/// Change of the uv per unit of distance over a triangle, from the areas it
+/// covers in uv and in space
+fn uv_density(uv_area: f64, area: f64) -> f64 {
+    if area > 0.0 {
+        (uv_area / area).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Möller-Trumbore ray-triangle intersection. Returns the distance along the
+/// ray and the barycentric coordinates of the hit relative to `b` and `c`.
+fn intersect_triangle(
+    ray: &Ray,
+    a: &glm::DVec3,
+    b: &glm::DVec3,
+    c: &glm::DVec3,
+) -> Option<(f64, f64, f64)> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+
+    let h = ray.direction.cross(&edge2);
+    let det = edge1.dot(&h);
+
+    if det.abs() < f64::EPSILON {
+        return None; // The ray is parallel to this triangle.
+    }
+
+    let f = 1.0 / det;
+    let s = ray.origin - a;
+    let u = f * s.dot(&h);
+
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(&edge1);
+    let v = f * ray.direction.dot(&q);
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = f * edge2.dot(&q);
+    (t > f64::EPSILON).then_some((t, u, v))
 }
 
 impl Shape for Triangle {
     fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
-        let edge1 = self.vb - self.va;
-        let edge2 = self.vc - self.va;
-
-        let h = ray.direction.cross(&edge2);
-        let a = edge1.dot(&h);
+        let (t, u, v) = intersect_triangle(ray, &self.va, &self.vb, &self.vc)?;
+        Some(HitRecord {
+            ray_t: t,
+            point: ray.point_at(t),
+            normal: self.normal,
+            uv: glm::DVec2::new(u, v), // Barycentric coordinates
+            uv_density: uv_density(0.5, self.area()),
+            material: 0,
+        })
+    }
 
-        if a.abs() < f64::EPSILON {
-            return None; // The ray is parallel to this triangle.
-        }
+    fn kind(&self) -> &'static str {
+        "triangle"
+    }
 
-        let f = 1.0 / a;
-        let s = ray.origin - self.va;
-        let u = f * s.dot(&h);
+    fn bounds(&self) -> Bounds {
+        Bounds::from_points([&self.va, &self.vb, &self.vc])
+    }
 
-        if u < 0.0 || u > 1.0 {
-            return None;
-        }
+    fn centroid(&self) -> Option<glm::DVec3> {
+        Some((self.va + self.vb + self.vc) / 3.0)
+    }
 
-        let q = s.cross(&edge1);
-        let v = f * ray.direction.dot(&q);
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        let su = u.x.sqrt();
+        let (b1, b2) = (1.0 - su, u.y * su);
+        let edge1 = self.vb - self.va;
+        let edge2 = self.vc - self.va;
+        Some(SurfaceSample {
+            point: self.va + b1 * edge1 + b2 * edge2,
+            normal: self.normal,
+            area: self.area(),
+        })
+    }
 
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
+    fn triangle_count(&self) -> usize {
+        1
+    }
 
-        let t = f * edge2.dot(&q);
+    fn min_triangle_area(&self) -> Option<f64> {
+        Some(self.area())
+    }
 
-        if t > f64::EPSILON {
-            let hit_point = ray.origin + t * ray.direction;
-            return Some(HitRecord {
-                ray_t: t,
-                point: hit_point,
-                normal: self.normal,
-            });
-        } else {
-            return None;
-        }
+    fn to_json(&self) -> Option<Value> {
+        Some(json!({
+            "type": "triangle",
+            "vertices": [
+                vec3_to_json(&self.va),
+                vec3_to_json(&self.vb),
+                vec3_to_json(&self.vc),
+            ],
+        }))
     }
 }
 
@@ -136,9 +405,50 @@ impl Sphere {
 
     pub fn normal(&self, intersection: &glm::DVec3, direction: &glm::DVec3) -> glm::DVec3 {
         // -(d*n)n / |(d*n)n|
-        let surf_normal = &self.center - intersection;
+        let surf_normal = self.center - intersection;
         (direction.dot(&surf_normal) * surf_normal).normalize()
     }
+
+    /// Spherical (longitude, latitude) coordinates of a point on the surface,
+    /// both in [0, 1]
+    pub fn uv(&self, point: &glm::DVec3) -> glm::DVec2 {
+        let p = (point - self.center) / self.radius;
+        glm::DVec2::new(
+            0.5 + p.z.atan2(p.x) / (2.0 * PI),
+            0.5 - p.y.clamp(-1.0, 1.0).asin() / PI,
+        )
+    }
+
+    /// Cone of directions under which the sphere is seen from a point
+    /// outside of it, None from the inside
+    fn cone_from(&self, origin: &glm::DVec3) -> Option<Cone> {
+        let to_center = self.center - origin;
+        let distance = to_center.norm();
+        if distance <= self.radius {
+            return None;
+        }
+        let sin2_max = (self.radius / distance).powi(2);
+        Some(Cone {
+            axis: to_center / distance,
+            distance,
+            // 1 - cos, without the cancellation of small cones
+            one_minus_cos_max: sin2_max / (1.0 + (1.0 - sin2_max).sqrt()),
+        })
+    }
+}
+
+/// Cone of directions from a point towards a sphere
+struct Cone {
+    axis: glm::DVec3,
+    distance: f64, // From the apex to the center of the sphere
+    one_minus_cos_max: f64,
+}
+
+impl Cone {
+    /// Density of sampling the directions of the cone uniformly
+    fn pdf(&self) -> f64 {
+        1.0 / (2.0 * PI * self.one_minus_cos_max)
+    }
 }
 
 impl Shape for Sphere {
@@ -155,12 +465,7 @@ impl Shape for Sphere {
         match solutions {
             None => None,
             Some(sols) => {
-                let t = closest_facing_solution(sols);
-                if t.is_none() {
-                    return None;
-                }
-
-                let t = t.unwrap();
+                let t = closest_facing_solution(sols)?;
                 let point = ray.point_at(t);
                 let normal = self.normal(&point, &ray.direction);
 
@@ -168,10 +473,89 @@ impl Shape for Sphere {
                     ray_t: t,
                     point,
                     normal,
+                    uv: self.uv(&point),
+                    // Geometric mean of the densities around and along, at
+                    // the equator
+                    uv_density: 1.0 / (PI * self.radius * 2.0_f64.sqrt()),
+                    material: 0,
                 })
             }
         }
     }
+
+    fn kind(&self) -> &'static str {
+        "sphere"
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: self.center - glm::DVec3::repeat(self.radius),
+            max: self.center + glm::DVec3::repeat(self.radius),
+        }
+    }
+
+    fn centroid(&self) -> Option<glm::DVec3> {
+        Some(self.center)
+    }
+
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        let z = 1.0 - 2.0 * u.x;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let normal = glm::DVec3::new(r * phi.cos(), r * phi.sin(), z);
+        Some(SurfaceSample {
+            point: self.center + self.radius * normal,
+            normal,
+            area: 4.0 * PI * self.radius * self.radius,
+        })
+    }
+
+    /// Sample the cone of directions under which the sphere is seen, which
+    /// only picks points `origin` can see. Points inside the sphere sample it
+    /// by area.
+    fn sample_from(&self, origin: &glm::DVec3, u: glm::DVec2) -> Option<VisibleSample> {
+        let Some(cone) = self.cone_from(origin) else {
+            let sample = self.sample_surface(u)?;
+            return Some(VisibleSample {
+                point: sample.point,
+                normal: sample.normal,
+                pdf: area_pdf(sample.area, origin, &sample.point, &sample.normal),
+            });
+        };
+
+        let cos_theta = 1.0 - u.x * cone.one_minus_cos_max;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let local = glm::DVec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let direction = sampling::to_world(&cone.axis, &local);
+
+        // Closest intersection along the direction, which grazes the sphere
+        // at the edge of the cone
+        let half_chord = (self.radius.powi(2) - (cone.distance * sin_theta).powi(2))
+            .max(0.0)
+            .sqrt();
+        let point = origin + (cone.distance * cos_theta - half_chord) * direction;
+        Some(VisibleSample {
+            point,
+            normal: (point - self.center).normalize(),
+            pdf: cone.pdf(),
+        })
+    }
+
+    fn pdf_from(&self, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+        match self.cone_from(origin) {
+            Some(cone) => cone.pdf(),
+            None => area_pdf(4.0 * PI * self.radius.powi(2), origin, point, normal),
+        }
+    }
+
+    fn to_json(&self) -> Option<Value> {
+        Some(json!({
+            "type": "sphere",
+            "center": vec3_to_json(&self.center),
+            "radius": self.radius,
+        }))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -194,146 +578,1458 @@ impl Shape for Plane {
             let t = p0_to_origin.dot(&self.normal) / denom;
             if t >= 0.0 {
                 let hit_point = ray.origin + t * ray.direction;
+                let normal = self.normal.normalize();
+                let (tangent, bitangent) = sampling::orthonormal_basis(&normal);
+                let offset = hit_point - self.position;
                 return Some(HitRecord {
                     ray_t: t,
                     point: hit_point,
-                    normal: self.normal,
+                    normal,
+                    uv: glm::DVec2::new(offset.dot(&tangent), offset.dot(&bitangent)),
+                    uv_density: 1.0,
+                    material: 0,
                 });
             }
         }
         None
     }
+
+    fn kind(&self) -> &'static str {
+        "plane"
+    }
+
+    fn to_json(&self) -> Option<Value> {
+        Some(json!({
+            "type": "plane",
+            "position": vec3_to_json(&self.position),
+            "normal": vec3_to_json(&self.normal),
+        }))
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use approx::assert_relative_eq;
+/// A shape available at several resolutions, so that distant objects are
+/// intersected against a cheaper representation.
+///
+/// One level is picked per frame from the distance between the viewpoint
+/// and the bounds of the shape, see `set_viewpoint`. Every ray of the frame
+/// sees that level, so shadow and bounce rays hit the same surface as camera
+/// rays. The finest level is used until a viewpoint is set.
+pub struct LevelOfDetail {
+    levels: Vec<(f64, Box<dyn Shape + Send + Sync>)>, // (max distance, shape), finest first
+    coarsest: Box<dyn Shape + Send + Sync>,
+    selected: usize, // Level intersected, 0 being the finest
+}
 
-    #[test]
-    fn closest_sol() {
-        assert_eq!(Some(1.0), closest_facing_solution((1.0, 2.0)));
-        assert_eq!(Some(2.0), closest_facing_solution((-1.0, 2.0)));
-        assert_eq!(Some(0.0), closest_facing_solution((-1.0, 0.0)));
-        assert_eq!(Some(0.0), closest_facing_solution((0.0, 0.0)));
-        assert_eq!(None, closest_facing_solution((-2.0, -1.0)));
+impl LevelOfDetail {
+    pub fn new(coarsest: Box<dyn Shape + Send + Sync>) -> Self {
+        Self {
+            levels: Vec::new(),
+            coarsest,
+            selected: 0,
+        }
     }
 
-    #[test]
-    fn intersect_sphere() {
-        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
-        let ray = Ray::new(
-            glm::DVec3::new(0.0, 0.0, 20.0),
-            glm::DVec3::new(0.0, 0.0, -1.0),
-        );
+    /// Register a level used when the viewpoint is closer than
+    /// `max_distance` to the shape
+    pub fn add_level(
+        &mut self,
+        max_distance: f64,
+        shape: Box<dyn Shape + Send + Sync>,
+    ) -> &mut Self {
+        let index = self
+            .levels
+            .partition_point(|(distance, _)| *distance <= max_distance);
+        self.levels.insert(index, (max_distance, shape));
+        self
+    }
 
-        let hit = sphere.intersect(&ray).expect("Expected some HitRecord");
+    pub fn num_levels(&self) -> usize {
+        self.levels.len() + 1
+    }
 
-        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 10.0), hit.point);
-        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, -1.0), hit.normal);
+    fn shapes(&self) -> impl Iterator<Item = &(dyn Shape + Send + Sync)> {
+        self.levels
+            .iter()
+            .map(|(_, shape)| shape.as_ref())
+            .chain(std::iter::once(self.coarsest.as_ref()))
     }
 
-    #[test]
-    fn intersect_from_inside_sphere() {
-        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
-        let ray = Ray::new(
-            glm::DVec3::new(0.0, 0.0, 0.0),
-            glm::DVec3::new(0.0, 0.0, 1.0),
-        );
+    /// Index of the level used from `distance`, 0 being the finest
+    pub fn select_level(&self, distance: f64) -> usize {
+        self.levels
+            .iter()
+            .position(|(max_distance, _)| distance < *max_distance)
+            .unwrap_or(self.levels.len())
+    }
 
-        let hit_record = sphere.intersect(&ray).expect("Expected some HitRecord");
+    /// Index of the level intersected in this frame
+    pub fn selected_level(&self) -> usize {
+        self.selected
+    }
+}
 
-        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 10.0), hit_record.point);
-        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 1.0), hit_record.normal);
+impl Shape for LevelOfDetail {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        match self.levels.get(self.selected) {
+            Some((_, shape)) => shape.intersect(ray),
+            None => self.coarsest.intersect(ray),
+        }
     }
 
-    #[test]
-    fn no_intersect_behind_sphere() {
-        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
-        let ray = Ray::new(
-            glm::DVec3::new(0.0, 0.0, 20.0),
-            glm::DVec3::new(0.0, 0.0, 1.0),
-        );
+    fn kind(&self) -> &'static str {
+        "level of detail"
+    }
 
-        let hit = sphere.intersect(&ray);
-        assert_eq!(hit, None);
+    fn bounds(&self) -> Bounds {
+        self.shapes().fold(Bounds::empty(), |bounds, shape| {
+            bounds.union(&shape.bounds())
+        })
     }
 
-    #[test]
-    fn test_plane_intersection_hit() {
-        let plane = Plane {
-            position: glm::DVec3::new(0.0, 0.0, 0.0),
-            normal: glm::DVec3::new(0.0, 1.0, 0.0),
-        };
+    fn centroid(&self) -> Option<glm::DVec3> {
+        self.coarsest.centroid()
+    }
 
-        let ray = Ray {
-            origin: glm::DVec3::new(0.0, -1.0, 0.0),
-            direction: glm::DVec3::new(0.0, 1.0, 0.0),
-        };
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        self.coarsest.sample_surface(u)
+    }
 
-        let hit_record = plane.intersect(&ray);
+    fn sample_from(&self, origin: &glm::DVec3, u: glm::DVec2) -> Option<VisibleSample> {
+        self.coarsest.sample_from(origin, u)
+    }
 
-        assert!(hit_record.is_some());
-        let hit_record = hit_record.unwrap();
-        assert_eq!(hit_record.ray_t, 1.0);
-        assert_eq!(hit_record.point, glm::DVec3::new(0.0, 0.0, 0.0));
-        assert_eq!(hit_record.normal, glm::DVec3::new(0.0, 1.0, 0.0));
+    fn pdf_from(&self, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+        self.coarsest.pdf_from(origin, point, normal)
     }
 
-    #[test]
-    fn test_plane_intersection_miss() {
-        let plane = Plane {
-            position: glm::DVec3::new(0.0, 0.0, 0.0),
-            normal: glm::DVec3::new(0.0, 1.0, 0.0),
-        };
+    /// Triangles of all levels, since all of them are kept in memory
+    fn triangle_count(&self) -> usize {
+        self.shapes().map(|shape| shape.triangle_count()).sum()
+    }
 
-        let ray = Ray {
-            origin: glm::DVec3::new(0.0, 1.0, 0.0),
-            direction: glm::DVec3::new(0.0, 1.0, 0.0),
-        };
+    fn min_triangle_area(&self) -> Option<f64> {
+        self.shapes()
+            .filter_map(|shape| shape.min_triangle_area())
+            .reduce(f64::min)
+    }
 
-        let hit_record = plane.intersect(&ray);
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self
+                .shapes()
+                .map(|shape| shape.memory_size())
+                .sum::<usize>()
+    }
 
-        assert!(hit_record.is_none());
+    /// Only if all the levels can be saved
+    fn to_json(&self) -> Option<Value> {
+        let levels = self
+            .levels
+            .iter()
+            .map(|(max_distance, shape)| {
+                Some(json!({ "max_distance": max_distance, "shape": shape.to_json()? }))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(json!({
+            "type": "lod",
+            "coarsest": self.coarsest.to_json()?,
+            "levels": levels,
+        }))
     }
 
-    #[test]
-    fn test_triangle_intersection_hit() {
-        let triangle = Triangle::new(
-            glm::DVec3::new(0.0, 0.0, 0.0),
-            glm::DVec3::new(1.0, 0.0, 0.0),
-            glm::DVec3::new(0.0, 1.0, 0.0),
-        );
+    /// Pick the level from the distance to the closest point of the bounds,
+    /// which is zero from inside them
+    fn set_viewpoint(&mut self, eye: &glm::DVec3) {
+        let bounds = self.bounds();
+        let closest = glm::clamp_vec(eye, &bounds.min, &bounds.max);
+        self.selected = self.select_level(glm::distance(eye, &closest));
+    }
+}
 
-        let ray = Ray {
-            origin: glm::DVec3::new(0.1, 0.1, -1.0),
-            direction: glm::DVec3::new(0.0, 0.0, 1.0),
-        };
+/// Placement of a shape in the world: a uniform scale, then a rotation and a
+/// translation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: glm::DVec3,
+    pub rotation: glm::DQuat,
+    pub scale: f64,
+}
 
-        let hit_record = triangle.intersect(&ray);
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: glm::DVec3::zeros(),
+            rotation: glm::DQuat::identity(),
+            scale: 1.0,
+        }
+    }
+}
 
-        assert!(hit_record.is_some());
-        let hit_record = hit_record.unwrap();
-        assert!(hit_record.ray_t > 0.0);
-        assert_eq!(hit_record.point, glm::DVec3::new(0.1, 0.1, 0.0));
-        assert_eq!(hit_record.normal, triangle.normal);
+impl Transform {
+    pub fn point(&self, point: &glm::DVec3) -> glm::DVec3 {
+        self.translation + self.direction(point) * self.scale
     }
 
-    #[test]
-    fn test_triangle_intersection_miss() {
-        let triangle = Triangle::new(
-            glm::DVec3::new(0.0, 0.0, 0.0),
-            glm::DVec3::new(1.0, 0.0, 0.0),
-            glm::DVec3::new(0.0, 1.0, 0.0),
-        );
+    /// Rotate a direction, which is left unscaled
+    pub fn direction(&self, direction: &glm::DVec3) -> glm::DVec3 {
+        glm::quat_rotate_vec3(&self.rotation, direction)
+    }
 
-        let ray = Ray {
-            origin: glm::DVec3::new(1.0, 1.0, -1.0),
-            direction: glm::DVec3::new(0.0, 0.0, 1.0),
+    pub fn inverse_point(&self, point: &glm::DVec3) -> glm::DVec3 {
+        self.inverse_direction(&(point - self.translation)) / self.scale
+    }
+
+    pub fn inverse_direction(&self, direction: &glm::DVec3) -> glm::DVec3 {
+        glm::quat_rotate_vec3(&glm::quat_conjugate(&self.rotation), direction)
+    }
+}
+
+/// A shape moved by a transform. The shape is shared, so that placing it
+/// somewhere else is cheap and doesn't copy its geometry.
+pub struct Instance {
+    shape: Arc<dyn Shape + Send + Sync>,
+    transform: Transform,
+}
+
+impl Instance {
+    pub fn new(shape: Arc<dyn Shape + Send + Sync>, transform: Transform) -> Self {
+        Self { shape, transform }
+    }
+
+    pub fn shape(&self) -> &Arc<dyn Shape + Send + Sync> {
+        &self.shape
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+}
+
+impl Shape for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let local = Ray::new(
+            self.transform.inverse_point(&ray.origin),
+            self.transform.inverse_direction(&ray.direction),
+        );
+        let hit = self.shape.intersect(&local)?;
+        Some(HitRecord {
+            ray_t: hit.ray_t * self.transform.scale,
+            point: self.transform.point(&hit.point),
+            normal: self.transform.direction(&hit.normal),
+            uv_density: hit.uv_density / self.transform.scale,
+            ..hit
+        })
+    }
+
+    fn kind(&self) -> &'static str {
+        "instance"
+    }
+
+    fn bounds(&self) -> Bounds {
+        let bounds = self.shape.bounds();
+        if bounds.is_empty() || !bounds.is_finite() {
+            return bounds;
+        }
+        let (min, max) = (bounds.min, bounds.max);
+        let corners = (0..8).map(|n| {
+            let corner = glm::DVec3::new(
+                if n & 1 == 0 { min.x } else { max.x },
+                if n & 2 == 0 { min.y } else { max.y },
+                if n & 4 == 0 { min.z } else { max.z },
+            );
+            self.transform.point(&corner)
+        });
+        Bounds::from_points(&corners.collect::<Vec<_>>())
+    }
+
+    fn centroid(&self) -> Option<glm::DVec3> {
+        self.shape
+            .centroid()
+            .map(|centroid| self.transform.point(&centroid))
+    }
+
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        let sample = self.shape.sample_surface(u)?;
+        Some(SurfaceSample {
+            point: self.transform.point(&sample.point),
+            normal: self.transform.direction(&sample.normal),
+            area: sample.area * self.transform.scale.powi(2),
+        })
+    }
+
+    /// Sampled in the space of the shape. The transform keeps angles, so the
+    /// density is the same in both spaces.
+    fn sample_from(&self, origin: &glm::DVec3, u: glm::DVec2) -> Option<VisibleSample> {
+        let sample = self
+            .shape
+            .sample_from(&self.transform.inverse_point(origin), u)?;
+        Some(VisibleSample {
+            point: self.transform.point(&sample.point),
+            normal: self.transform.direction(&sample.normal),
+            pdf: sample.pdf,
+        })
+    }
+
+    fn pdf_from(&self, origin: &glm::DVec3, point: &glm::DVec3, normal: &glm::DVec3) -> f64 {
+        self.shape.pdf_from(
+            &self.transform.inverse_point(origin),
+            &self.transform.inverse_point(point),
+            &self.transform.inverse_direction(normal),
+        )
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.shape.triangle_count()
+    }
+
+    fn min_triangle_area(&self) -> Option<f64> {
+        self.shape
+            .min_triangle_area()
+            .map(|area| area * self.transform.scale.powi(2))
+    }
+
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self) + self.shape.memory_size()
+    }
+
+    fn warning(&self) -> Option<String> {
+        self.shape.warning()
+    }
+}
+
+/// Faces of a mesh with no material of their own, which are shaded with the
+/// material of the object
+pub const NO_MATERIAL: usize = usize::MAX;
+
+/// Maximum number of triangles in a leaf of the hierarchy of a mesh
+const MESH_LEAF_SIZE: usize = 4;
+
+/// Triangle of a mesh, as indices into its vertex buffers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshFace {
+    pub positions: [u32; 3],
+    pub normals: Option<[u32; 3]>,
+    pub uvs: Option<[u32; 3]>,
+    pub material: usize, // Index of the material, or NO_MATERIAL
+}
+
+impl MeshFace {
+    pub fn new(positions: [u32; 3]) -> Self {
+        Self {
+            positions,
+            normals: None,
+            uvs: None,
+            material: NO_MATERIAL,
+        }
+    }
+}
+
+/// Node of the bounding volume hierarchy of a mesh. Leaves hold `count`
+/// faces from `first`. Interior nodes have `count` 0, their first child
+/// right after them and the second one at `first`.
+#[derive(Debug, Clone, Copy)]
+struct MeshNode {
+    bounds: Bounds,
+    first: usize,
+    count: usize,
+}
+
+/// Triangle mesh with indexed vertex buffers, e.g. a model imported from a
+/// Wavefront OBJ file. Faces front face counterclockwise, as in OBJ files.
+#[derive(Debug, Default)]
+pub struct Mesh {
+    positions: Vec<glm::DVec3>,
+    normals: Vec<glm::DVec3>,
+    uvs: Vec<glm::DVec2>,
+    faces: Vec<MeshFace>,
+    nodes: Vec<MeshNode>,
+    areas: Vec<f64>, // Cumulative area of the faces, for sampling
+    material_names: Vec<String>,
+    material_libraries: Vec<String>,
+}
+
+impl Mesh {
+    /// Mesh from positions and triangles of indices into them
+    pub fn new(positions: Vec<glm::DVec3>, triangles: Vec<[u32; 3]>) -> Result<Self, String> {
+        let faces = triangles.into_iter().map(MeshFace::new).collect();
+        Self::with_attributes(positions, Vec::new(), Vec::new(), faces)
+    }
+
+    /// Mesh with vertex normals, used for smooth shading, and texture
+    /// coordinates
+    pub fn with_attributes(
+        positions: Vec<glm::DVec3>,
+        normals: Vec<glm::DVec3>,
+        uvs: Vec<glm::DVec2>,
+        faces: Vec<MeshFace>,
+    ) -> Result<Self, String> {
+        let out_of_range = |indices: Option<[u32; 3]>, len: usize| {
+            indices.is_some_and(|indices| indices.iter().any(|i| *i as usize >= len))
+        };
+        for (n, face) in faces.iter().enumerate() {
+            if out_of_range(Some(face.positions), positions.len())
+                || out_of_range(face.normals, normals.len())
+                || out_of_range(face.uvs, uvs.len())
+            {
+                return Err(format!("Face {n} refers to a vertex that doesn't exist"));
+            }
+        }
+
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            faces,
+            ..Default::default()
+        };
+        mesh.build_hierarchy();
+        mesh.areas = mesh
+            .faces
+            .iter()
+            .scan(0.0, |sum, face| {
+                *sum += mesh.face_area(face);
+                Some(*sum)
+            })
+            .collect();
+        Ok(mesh)
+    }
+
+    /// Load a Wavefront OBJ file. Polygons are split into triangles and the
+    /// `usemtl` names are kept as material indices of the faces.
+    pub fn from_obj<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+        Self::parse_obj(&source).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse_obj(source: &str) -> Result<Self, String> {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut faces = Vec::new();
+        let mut material_names: Vec<String> = Vec::new();
+        let mut material_libraries = Vec::new();
+        let mut material = NO_MATERIAL;
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut tokens = line.split_whitespace();
+            let Some(keyword) = tokens.next() else {
+                continue;
+            };
+            let args: Vec<&str> = tokens.collect();
+            let error = |e: String| format!("Line {}: {e}", number + 1);
+            let numbers = |count: usize| -> Result<Vec<f64>, String> {
+                let values = args
+                    .iter()
+                    .take(count)
+                    .map(|value| {
+                        value
+                            .parse::<f64>()
+                            .map_err(|_| format!("Expected a number, found '{value}'"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if values.len() < count {
+                    return Err(format!("Expected {count} numbers"));
+                }
+                Ok(values)
+            };
+
+            match keyword {
+                "v" => {
+                    let v = numbers(3).map_err(error)?;
+                    positions.push(glm::DVec3::new(v[0], v[1], v[2]));
+                }
+                "vn" => {
+                    let v = numbers(3).map_err(error)?;
+                    normals.push(glm::DVec3::new(v[0], v[1], v[2]));
+                }
+                "vt" => {
+                    let v = numbers(2).map_err(error)?;
+                    uvs.push(glm::DVec2::new(v[0], v[1]));
+                }
+                "f" => {
+                    let corners = args
+                        .iter()
+                        .map(|corner| {
+                            parse_obj_corner(corner, positions.len(), uvs.len(), normals.len())
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(error)?;
+                    if corners.len() < 3 {
+                        return Err(error("A face needs at least 3 vertices".to_string()));
+                    }
+                    // Fan around the first corner
+                    for k in 1..corners.len() - 1 {
+                        let [a, b, c] = [corners[0], corners[k], corners[k + 1]];
+                        let all = |get: fn(&ObjCorner) -> Option<u32>| {
+                            Some([get(&a)?, get(&b)?, get(&c)?])
+                        };
+                        faces.push(MeshFace {
+                            positions: [a.position, b.position, c.position],
+                            normals: all(|corner| corner.normal),
+                            uvs: all(|corner| corner.uv),
+                            material,
+                        });
+                    }
+                }
+                "usemtl" => {
+                    let name = args.join(" ");
+                    material = match material_names.iter().position(|n| *n == name) {
+                        Some(index) => index,
+                        None => {
+                            material_names.push(name);
+                            material_names.len() - 1
+                        }
+                    };
+                }
+                "mtllib" => material_libraries.extend(args.iter().map(|s| s.to_string())),
+                _ => {} // Groups, smoothing groups, lines...
+            }
+        }
+
+        // Models can be large, don't keep the spare capacity of the buffers
+        positions.shrink_to_fit();
+        normals.shrink_to_fit();
+        uvs.shrink_to_fit();
+        faces.shrink_to_fit();
+        let mut mesh = Self::with_attributes(positions, normals, uvs, faces)?;
+        mesh.nodes.shrink_to_fit();
+        mesh.material_names = material_names;
+        mesh.material_libraries = material_libraries;
+        Ok(mesh)
+    }
+
+    pub fn positions(&self) -> &[glm::DVec3] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> &[glm::DVec3] {
+        &self.normals
+    }
+
+    pub fn uvs(&self) -> &[glm::DVec2] {
+        &self.uvs
+    }
+
+    pub fn faces(&self) -> &[MeshFace] {
+        &self.faces
+    }
+
+    /// Names of the materials of the faces, by index
+    pub fn material_names(&self) -> &[String] {
+        &self.material_names
+    }
+
+    /// MTL files named by the OBJ file, relative to it
+    pub fn material_libraries(&self) -> &[String] {
+        &self.material_libraries
+    }
+
+    /// Memory a mesh with these numbers of vertex attributes and triangles
+    /// takes, before building it. The hierarchy is counted as if all its
+    /// leaves held the fewest faces a median split leaves in them.
+    pub fn estimate_memory(
+        positions: usize,
+        normals: usize,
+        uvs: usize,
+        triangles: usize,
+    ) -> usize {
+        let nodes = 2 * triangles.div_ceil(MESH_LEAF_SIZE / 2);
+        std::mem::size_of::<Self>()
+            + (positions + normals) * std::mem::size_of::<glm::DVec3>()
+            + uvs * std::mem::size_of::<glm::DVec2>()
+            + triangles * (std::mem::size_of::<MeshFace>() + std::mem::size_of::<f64>())
+            + nodes * std::mem::size_of::<MeshNode>()
+    }
+
+    fn vertices(&self, face: &MeshFace) -> [glm::DVec3; 3] {
+        face.positions.map(|i| self.positions[i as usize])
+    }
+
+    fn face_area(&self, face: &MeshFace) -> f64 {
+        let [a, b, c] = self.vertices(face);
+        0.5 * (b - a).cross(&(c - a)).norm()
+    }
+
+    fn face_normal(&self, face: &MeshFace) -> glm::DVec3 {
+        let [a, b, c] = self.vertices(face);
+        (b - a).cross(&(c - a)).normalize()
+    }
+
+    fn face_bounds(&self, face: &MeshFace) -> Bounds {
+        Bounds::from_points(&self.vertices(face))
+    }
+
+    /// Split the faces recursively at the median of their centroids along
+    /// the longest axis
+    fn build_hierarchy(&mut self) {
+        self.nodes.clear();
+        if !self.faces.is_empty() {
+            self.build_node(0, self.faces.len());
+        }
+    }
+
+    fn build_node(&mut self, first: usize, count: usize) -> usize {
+        let faces = &self.faces[first..first + count];
+        let bounds = faces.iter().fold(Bounds::empty(), |bounds, face| {
+            bounds.union(&self.face_bounds(face))
+        });
+        let index = self.nodes.len();
+        self.nodes.push(MeshNode {
+            bounds,
+            first,
+            count,
+        });
+        if count <= MESH_LEAF_SIZE {
+            return index;
+        }
+
+        let centroid = |mesh: &Self, face: &MeshFace| {
+            let [a, b, c] = mesh.vertices(face);
+            a + b + c
+        };
+        let centroids =
+            Bounds::from_points(&faces.iter().map(|f| centroid(self, f)).collect::<Vec<_>>());
+        let axis = centroids.size().imax();
+        let half = count / 2;
+        let mut faces = std::mem::take(&mut self.faces);
+        faces[first..first + count].select_nth_unstable_by(half, |f, g| {
+            centroid(self, f)[axis].total_cmp(&centroid(self, g)[axis])
+        });
+        self.faces = faces;
+
+        self.build_node(first, half);
+        let second = self.build_node(first + half, count - half);
+        self.nodes[index] = MeshNode {
+            bounds,
+            first: second,
+            count: 0,
+        };
+        index
+    }
+
+    /// Closest face hit by a ray, with its distance and barycentric
+    /// coordinates
+    fn closest_face(&self, ray: &Ray) -> Option<(usize, f64, f64, f64)> {
+        let mut closest = None;
+        let mut closest_t = f64::INFINITY;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            match node.bounds.intersect(ray) {
+                Some((near, _)) if near < closest_t => {}
+                _ => continue,
+            }
+            if node.count == 0 {
+                stack.push(node.first);
+                stack.push(index + 1);
+                continue;
+            }
+            for n in node.first..node.first + node.count {
+                let [a, b, c] = self.vertices(&self.faces[n]);
+                if let Some((t, u, v)) = intersect_triangle(ray, &a, &b, &c) {
+                    if t < closest_t {
+                        closest_t = t;
+                        closest = Some((n, t, u, v));
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+/// Indices of the position, texture coordinates and normal of a face corner
+#[derive(Debug, Clone, Copy)]
+struct ObjCorner {
+    position: u32,
+    uv: Option<u32>,
+    normal: Option<u32>,
+}
+
+/// Parse `v`, `v/vt`, `v//vn` or `v/vt/vn`. Indices start at 1, negative
+/// ones count back from the last vertex.
+fn parse_obj_corner(
+    corner: &str,
+    positions: usize,
+    uvs: usize,
+    normals: usize,
+) -> Result<ObjCorner, String> {
+    let index = |value: Option<&str>, len: usize| -> Result<Option<u32>, String> {
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            return Ok(None);
+        };
+        let index: i64 = value
+            .parse()
+            .map_err(|_| format!("Invalid index '{value}'"))?;
+        let resolved = if index < 0 {
+            len as i64 + index
+        } else {
+            index - 1
+        };
+        if resolved < 0 || resolved >= len as i64 {
+            return Err(format!("Index {index} is out of range"));
+        }
+        Ok(Some(resolved as u32))
+    };
+
+    let mut parts = corner.split('/');
+    let position = index(parts.next(), positions)?.ok_or("Missing vertex index")?;
+    Ok(ObjCorner {
+        position,
+        uv: index(parts.next(), uvs)?,
+        normal: index(parts.next(), normals)?,
+    })
+}
+
+impl Mesh {
+    /// Hit of a ray at distance t on face n, at barycentric coordinates
+    /// (u, v) relative to its second and third vertices. Backends that find
+    /// the face themselves, e.g. Embree, share the shading attributes.
+    pub(crate) fn face_hit(&self, ray: &Ray, n: usize, t: f64, u: f64, v: f64) -> HitRecord {
+        let face = &self.faces[n];
+        let weights = [1.0 - u - v, u, v];
+
+        let normal = match face.normals {
+            Some(normals) => {
+                let normal = (0..3).fold(glm::DVec3::zeros(), |sum, k| {
+                    sum + weights[k] * self.normals[normals[k] as usize]
+                });
+                if normal.norm() > 0.0 {
+                    normal.normalize()
+                } else {
+                    self.face_normal(face)
+                }
+            }
+            None => self.face_normal(face),
         };
+        let (uv, uv_area) = match face.uvs {
+            Some(uvs) => {
+                let [a, b, c] = uvs.map(|k| self.uvs[k as usize]);
+                let uv = weights[0] * a + weights[1] * b + weights[2] * c;
+                let (ab, ac) = (b - a, c - a);
+                (uv, 0.5 * (ab.x * ac.y - ab.y * ac.x).abs())
+            }
+            None => (glm::DVec2::new(u, v), 0.5), // Barycentric coordinates
+        };
+        let [a, b, c] = self.vertices(face);
+        let area = 0.5 * (b - a).cross(&(c - a)).norm();
+
+        HitRecord {
+            ray_t: t,
+            point: ray.point_at(t),
+            normal,
+            uv,
+            uv_density: uv_density(uv_area, area),
+            material: face.material,
+        }
+    }
+
+    /// Distance along a ray to the plane of face n, if it isn't parallel
+    #[cfg(feature = "embree")]
+    pub(crate) fn face_distance(&self, ray: &Ray, n: usize) -> Option<f64> {
+        let [a, b, c] = self.vertices(&self.faces[n]);
+        let normal = (b - a).cross(&(c - a));
+        let cos = ray.direction.dot(&normal);
+        (cos != 0.0).then(|| (a - ray.origin).dot(&normal) / cos)
+    }
+}
+
+impl Shape for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        let (n, t, u, v) = self.closest_face(ray)?;
+        Some(self.face_hit(ray, n, t, u, v))
+    }
+
+    fn kind(&self) -> &'static str {
+        "mesh"
+    }
+
+    fn bounds(&self) -> Bounds {
+        self.nodes
+            .first()
+            .map_or(Bounds::empty(), |root| root.bounds)
+    }
+
+    /// Centroid of the faces weighted by their area
+    fn centroid(&self) -> Option<glm::DVec3> {
+        let total = *self.areas.last()?;
+        if total <= 0.0 {
+            return None;
+        }
+        let sum = self.faces.iter().fold(glm::DVec3::zeros(), |sum, face| {
+            let [a, b, c] = self.vertices(face);
+            sum + self.face_area(face) * (a + b + c) / 3.0
+        });
+        Some(sum / total)
+    }
+
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        let total = *self.areas.last()?;
+        if total <= 0.0 {
+            return None;
+        }
+
+        // Pick a face by area and reuse the rest of `u.x` inside it
+        let target = u.x * total;
+        let n = self
+            .areas
+            .partition_point(|area| *area <= target)
+            .min(self.faces.len() - 1);
+        let start = if n > 0 { self.areas[n - 1] } else { 0.0 };
+        let area = self.areas[n] - start;
+        let ux = ((target - start) / area).clamp(0.0, 1.0);
+
+        let face = &self.faces[n];
+        let [a, b, c] = self.vertices(face);
+        let su = ux.sqrt();
+        let (b1, b2) = (1.0 - su, u.y * su);
+        Some(SurfaceSample {
+            point: a + b1 * (b - a) + b2 * (c - a),
+            normal: self.face_normal(face),
+            area: total,
+        })
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    fn min_triangle_area(&self) -> Option<f64> {
+        self.faces
+            .iter()
+            .map(|face| self.face_area(face))
+            .reduce(f64::min)
+    }
+
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self)
+            + self.positions.capacity() * std::mem::size_of::<glm::DVec3>()
+            + self.normals.capacity() * std::mem::size_of::<glm::DVec3>()
+            + self.uvs.capacity() * std::mem::size_of::<glm::DVec2>()
+            + self.faces.capacity() * std::mem::size_of::<MeshFace>()
+            + self.nodes.capacity() * std::mem::size_of::<MeshNode>()
+            + self.areas.capacity() * std::mem::size_of::<f64>()
+    }
+
+    /// Vertex buffers and faces. Vertex normals, texture coordinates and
+    /// face materials are only written if the mesh has them.
+    fn to_json(&self) -> Option<Value> {
+        let mut value = json!({
+            "type": "mesh",
+            "positions": self.positions.iter().map(vec3_to_json).collect::<Vec<_>>(),
+            "triangles": self.faces.iter().map(|face| face.positions).collect::<Vec<_>>(),
+        });
+        if self.faces.iter().any(|face| face.normals.is_some()) {
+            value["normals"] = self.normals.iter().map(vec3_to_json).collect();
+            value["normal_triangles"] = self.faces.iter().map(|face| json!(face.normals)).collect();
+        }
+        if self.faces.iter().any(|face| face.uvs.is_some()) {
+            value["uvs"] = self.uvs.iter().map(|uv| json!([uv.x, uv.y])).collect();
+            value["uv_triangles"] = self.faces.iter().map(|face| json!(face.uvs)).collect();
+        }
+        if self.faces.iter().any(|face| face.material != NO_MATERIAL) {
+            value["face_materials"] = self
+                .faces
+                .iter()
+                .map(|face| (face.material != NO_MATERIAL).then_some(face.material))
+                .collect();
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn closest_sol() {
+        assert_eq!(Some(1.0), closest_facing_solution((1.0, 2.0)));
+        assert_eq!(Some(2.0), closest_facing_solution((-1.0, 2.0)));
+        assert_eq!(Some(0.0), closest_facing_solution((-1.0, 0.0)));
+        assert_eq!(Some(0.0), closest_facing_solution((0.0, 0.0)));
+        assert_eq!(None, closest_facing_solution((-2.0, -1.0)));
+    }
+
+    #[test]
+    fn intersect_sphere() {
+        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
+        let ray = Ray::new(
+            glm::DVec3::new(0.0, 0.0, 20.0),
+            glm::DVec3::new(0.0, 0.0, -1.0),
+        );
+
+        let hit = sphere.intersect(&ray).expect("Expected some HitRecord");
+
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 10.0), hit.point);
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, -1.0), hit.normal);
+    }
+
+    #[test]
+    fn intersect_from_inside_sphere() {
+        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
+        let ray = Ray::new(
+            glm::DVec3::new(0.0, 0.0, 0.0),
+            glm::DVec3::new(0.0, 0.0, 1.0),
+        );
+
+        let hit_record = sphere.intersect(&ray).expect("Expected some HitRecord");
+
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 10.0), hit_record.point);
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, 1.0), hit_record.normal);
+    }
+
+    #[test]
+    fn no_intersect_behind_sphere() {
+        let sphere = Sphere::new(glm::DVec3::new(0.0, 0.0, 0.0), 10.0);
+        let ray = Ray::new(
+            glm::DVec3::new(0.0, 0.0, 20.0),
+            glm::DVec3::new(0.0, 0.0, 1.0),
+        );
+
+        let hit = sphere.intersect(&ray);
+        assert_eq!(hit, None);
+    }
+
+    #[test]
+    fn test_plane_intersection_hit() {
+        let plane = Plane {
+            position: glm::DVec3::new(0.0, 0.0, 0.0),
+            normal: glm::DVec3::new(0.0, 1.0, 0.0),
+        };
+
+        let ray = Ray::new(
+            glm::DVec3::new(0.0, -1.0, 0.0),
+            glm::DVec3::new(0.0, 1.0, 0.0),
+        );
+
+        let hit_record = plane.intersect(&ray);
+
+        assert!(hit_record.is_some());
+        let hit_record = hit_record.unwrap();
+        assert_eq!(hit_record.ray_t, 1.0);
+        assert_eq!(hit_record.point, glm::DVec3::new(0.0, 0.0, 0.0));
+        assert_eq!(hit_record.normal, glm::DVec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_plane_intersection_miss() {
+        let plane = Plane {
+            position: glm::DVec3::new(0.0, 0.0, 0.0),
+            normal: glm::DVec3::new(0.0, 1.0, 0.0),
+        };
+
+        let ray = Ray::new(
+            glm::DVec3::new(0.0, 1.0, 0.0),
+            glm::DVec3::new(0.0, 1.0, 0.0),
+        );
+
+        let hit_record = plane.intersect(&ray);
+
+        assert!(hit_record.is_none());
+    }
+
+    #[test]
+    fn test_triangle_intersection_hit() {
+        let triangle = Triangle::new(
+            glm::DVec3::new(0.0, 0.0, 0.0),
+            glm::DVec3::new(1.0, 0.0, 0.0),
+            glm::DVec3::new(0.0, 1.0, 0.0),
+        );
+
+        let ray = Ray::new(
+            glm::DVec3::new(0.1, 0.1, -1.0),
+            glm::DVec3::new(0.0, 0.0, 1.0),
+        );
+
+        let hit_record = triangle.intersect(&ray);
+
+        assert!(hit_record.is_some());
+        let hit_record = hit_record.unwrap();
+        assert!(hit_record.ray_t > 0.0);
+        assert_eq!(hit_record.point, glm::DVec3::new(0.1, 0.1, 0.0));
+        assert_eq!(hit_record.normal, triangle.normal);
+    }
+
+    #[test]
+    fn test_triangle_intersection_miss() {
+        let triangle = Triangle::new(
+            glm::DVec3::new(0.0, 0.0, 0.0),
+            glm::DVec3::new(1.0, 0.0, 0.0),
+            glm::DVec3::new(0.0, 1.0, 0.0),
+        );
+
+        let ray = Ray::new(
+            glm::DVec3::new(1.0, 1.0, -1.0),
+            glm::DVec3::new(0.0, 0.0, 1.0),
+        );
 
         let hit_record = triangle.intersect(&ray);
 
         assert!(hit_record.is_none());
     }
+
+    #[test]
+    fn sample_surfaces() {
+        let sphere = Sphere::new(glm::DVec3::new(1.0, 2.0, 3.0), 2.0);
+        let triangle = Triangle::new(
+            glm::DVec3::zeros(),
+            glm::DVec3::new(2.0, 0.0, 0.0),
+            glm::DVec3::new(0.0, 2.0, 0.0),
+        );
+
+        for u in [(0.0, 0.0), (0.3, 0.7), (0.99, 0.5)] {
+            let u = glm::DVec2::new(u.0, u.1);
+
+            let sample = sphere.sample_surface(u).unwrap();
+            assert_relative_eq!(2.0, (sample.point - sphere.center).norm(), epsilon = 1e-12);
+            assert_relative_eq!(sample.normal, (sample.point - sphere.center) / 2.0);
+            assert_relative_eq!(16.0 * PI, sample.area);
+
+            let sample = triangle.sample_surface(u).unwrap();
+            assert_eq!(0.0, sample.point.z);
+            assert!(sample.point.x >= 0.0 && sample.point.y >= 0.0);
+            assert!(sample.point.x + sample.point.y <= 2.0 + 1e-12);
+            assert_relative_eq!(2.0, sample.area);
+        }
+
+        let plane = Plane {
+            position: glm::DVec3::zeros(),
+            normal: glm::DVec3::y(),
+        };
+        assert_eq!(None, plane.sample_surface(glm::DVec2::zeros()));
+    }
+
+    #[test]
+    fn sample_spheres_by_solid_angle() {
+        let sphere = Sphere::new(glm::DVec3::zeros(), 2.0);
+        let origin = glm::DVec3::new(0.0, 0.0, -5.0);
+        let solid_angle = 2.0 * PI * (1.0 - (1.0 - 4.0 / 25.0_f64).sqrt());
+
+        for u in [(0.0, 0.0), (0.3, 0.7), (0.99, 0.5), (1.0, 0.2)] {
+            let sample = sphere
+                .sample_from(&origin, glm::DVec2::new(u.0, u.1))
+                .unwrap();
+            assert_relative_eq!(2.0, sample.point.norm(), epsilon = 1e-9);
+            assert_relative_eq!(sample.normal, sample.point / 2.0, epsilon = 1e-9);
+            // Only the side facing the origin is sampled
+            assert!(sample.normal.dot(&(origin - sample.point)) >= -1e-9);
+            assert_relative_eq!(1.0 / solid_angle, sample.pdf, epsilon = 1e-12);
+            assert_relative_eq!(
+                sample.pdf,
+                sphere.pdf_from(&origin, &sample.point, &sample.normal)
+            );
+        }
+
+        // From the inside, the whole sphere is sampled by area
+        let sample = sphere
+            .sample_from(&glm::DVec3::zeros(), glm::DVec2::new(0.3, 0.7))
+            .unwrap();
+        assert_relative_eq!(1.0 / (4.0 * PI), sample.pdf, epsilon = 1e-12);
+
+        // Other shapes are sampled by area, and moving a shape keeps its
+        // density
+        let triangle = Triangle::new(
+            glm::DVec3::new(-1.0, 0.0, 0.0),
+            glm::DVec3::new(1.0, 0.0, 0.0),
+            glm::DVec3::new(0.0, 2.0, 0.0),
+        );
+        let sample = triangle
+            .sample_from(&origin, glm::DVec2::new(0.0, 0.0))
+            .unwrap();
+        let distance2 = (sample.point - origin).norm_squared();
+        let cos = sample.normal.dot(&(origin - sample.point)).abs() / distance2.sqrt();
+        assert_relative_eq!(distance2 / (2.0 * cos), sample.pdf, epsilon = 1e-12);
+
+        let instance = Instance::new(
+            Arc::new(Sphere::new(glm::DVec3::zeros(), 1.0)),
+            Transform {
+                scale: 2.0,
+                ..Default::default()
+            },
+        );
+        let sample = instance
+            .sample_from(&origin, glm::DVec2::new(0.3, 0.7))
+            .unwrap();
+        assert_relative_eq!(1.0 / solid_angle, sample.pdf, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn lod_level_selection() {
+        let mut lod = LevelOfDetail::new(Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)));
+        lod.add_level(50.0, Box::new(Sphere::new(glm::DVec3::zeros(), 0.9)))
+            .add_level(10.0, Box::new(Sphere::new(glm::DVec3::zeros(), 0.8)));
+
+        assert_eq!(3, lod.num_levels());
+        assert_eq!(0, lod.select_level(5.0));
+        assert_eq!(1, lod.select_level(10.0));
+        assert_eq!(1, lod.select_level(30.0));
+        assert_eq!(2, lod.select_level(100.0));
+
+        // From the distance to the bounds, which holds all the levels
+        assert_eq!(0, lod.selected_level());
+        lod.set_viewpoint(&glm::DVec3::new(0.0, 0.0, 40.0));
+        assert_eq!(1, lod.selected_level());
+        lod.set_viewpoint(&glm::DVec3::new(0.0, 0.5, 0.0));
+        assert_eq!(0, lod.selected_level());
+        lod.set_viewpoint(&glm::DVec3::new(60.0, 0.0, 0.0));
+        assert_eq!(2, lod.selected_level());
+    }
+
+    #[test]
+    fn obj_mesh() {
+        let source = r#"
+            mtllib scene.mtl
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 1
+            usemtl red
+            f 1/1/1 2/2/1 3/3/1 4/4/1
+            usemtl blue
+            f -4 -2 -1
+        "#;
+        let mesh = Mesh::parse_obj(source).unwrap();
+        assert_eq!(3, mesh.triangle_count());
+        assert_eq!(["red", "blue"], mesh.material_names());
+        assert_eq!(["scene.mtl"], mesh.material_libraries());
+        assert_eq!(Some([0, 1, 2]), mesh.faces()[0].uvs);
+        assert_eq!(None, mesh.faces()[2].normals);
+        assert_eq!(1, mesh.faces()[2].material);
+        assert_eq!(
+            Bounds {
+                min: glm::DVec3::zeros(),
+                max: glm::DVec3::new(1.0, 1.0, 0.0),
+            },
+            mesh.bounds()
+        );
+
+        // Texture coordinates and vertex normals are interpolated
+        let ray = Ray::new(glm::DVec3::new(0.25, 0.75, 1.0), -glm::DVec3::z());
+        let hit = mesh.intersect(&ray).expect("Expected some HitRecord");
+        assert_relative_eq!(1.0, hit.ray_t, epsilon = 1e-12);
+        assert_relative_eq!(glm::DVec2::new(0.25, 0.75), hit.uv, epsilon = 1e-12);
+        assert_eq!(glm::DVec3::z(), hit.normal);
+        assert_eq!(0, hit.material);
+
+        // The last face covers the second one again
+        assert_relative_eq!(
+            glm::DVec3::new(4.0 / 9.0, 5.0 / 9.0, 0.0),
+            mesh.centroid().unwrap(),
+            epsilon = 1e-12
+        );
+        let sample = mesh.sample_surface(glm::DVec2::new(0.9, 0.3)).unwrap();
+        assert_relative_eq!(1.5, sample.area, epsilon = 1e-12);
+        assert_eq!(0.0, sample.point.z);
+
+        for invalid in ["v 0 0 0\nf 1 2 3", "v 0 0\n", "v 0 0 0\nv 1 0 0\nf 1 2"] {
+            assert!(Mesh::parse_obj(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn lod_intersects_selected_level() {
+        let mut lod = LevelOfDetail::new(Box::new(Sphere::new(glm::DVec3::zeros(), 1.0)));
+        lod.add_level(10.0, Box::new(Sphere::new(glm::DVec3::zeros(), 0.5)));
+
+        let near_ray = Ray::new(glm::DVec3::new(0.0, 0.0, 5.0), -glm::DVec3::z());
+        let far_ray = Ray::new(glm::DVec3::new(0.0, 0.0, 100.0), -glm::DVec3::z());
+        lod.set_viewpoint(&near_ray.origin);
+        let hit = lod.intersect(&near_ray).expect("Expected some HitRecord");
+        assert_relative_eq!(4.5, hit.ray_t);
+
+        // Every ray of the frame sees the level of the viewpoint, wherever
+        // it starts
+        let hit = lod.intersect(&far_ray).expect("Expected some HitRecord");
+        assert_relative_eq!(99.5, hit.ray_t);
+
+        lod.set_viewpoint(&far_ray.origin);
+        let hit = lod.intersect(&near_ray).expect("Expected some HitRecord");
+        assert_relative_eq!(4.0, hit.ray_t);
+        let hit = lod.intersect(&far_ray).expect("Expected some HitRecord");
+        assert_relative_eq!(99.0, hit.ray_t);
+    }
+
+    #[test]
+    fn instances_move_shared_shapes() {
+        let sphere: Arc<dyn Shape + Send + Sync> = Arc::new(Sphere::new(glm::DVec3::zeros(), 1.0));
+        let instance = Instance::new(
+            Arc::clone(&sphere),
+            Transform {
+                translation: glm::DVec3::new(0.0, 0.0, -10.0),
+                rotation: glm::quat_angle_axis(1.0, &glm::DVec3::y()),
+                scale: 2.0,
+            },
+        );
+
+        let ray = Ray::new(glm::DVec3::new(0.0, 0.0, 5.0), -glm::DVec3::z());
+        let hit = instance.intersect(&ray).expect("Expected some HitRecord");
+        assert_relative_eq!(13.0, hit.ray_t, epsilon = 1e-9);
+        assert_relative_eq!(glm::DVec3::new(0.0, 0.0, -8.0), hit.point, epsilon = 1e-9);
+        assert_relative_eq!(-glm::DVec3::z(), hit.normal, epsilon = 1e-9);
+
+        let bounds = instance.bounds();
+        assert!(bounds.contains(&glm::DVec3::new(0.0, 1.9, -10.0)));
+        assert!(!bounds.contains(&glm::DVec3::new(0.0, 2.1, -10.0)));
+        assert_relative_eq!(
+            glm::DVec3::new(0.0, 0.0, -10.0),
+            instance.centroid().unwrap(),
+            epsilon = 1e-9
+        );
+        let sample = instance.sample_surface(glm::DVec2::new(0.3, 0.6)).unwrap();
+        assert_relative_eq!(16.0 * PI, sample.area, epsilon = 1e-9);
+        assert_relative_eq!(
+            2.0,
+            glm::distance(&sample.point, &instance.centroid().unwrap()),
+            epsilon = 1e-9
+        );
+        assert_eq!(2, Arc::strong_count(&sphere));
+    }
+}
+
+/// Randomized tests against independent formulas and geometric invariants
+#[cfg(test)]
+mod fuzz_test {
+    use approx::assert_relative_eq;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    const ITERATIONS: usize = 2000;
+    const TOLERANCE: f64 = 1e-7;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(0x11647)
+    }
+
+    fn random_point(rng: &mut StdRng, extent: f64) -> glm::DVec3 {
+        glm::DVec3::new(
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+            rng.gen_range(-extent..extent),
+        )
+    }
+
+    fn random_direction(rng: &mut StdRng) -> glm::DVec3 {
+        let [x, y, z]: [f64; 3] = rng.sample(rand_distr::UnitSphere);
+        glm::DVec3::new(x, y, z)
+    }
+
+    /// Geometric ray-sphere solution, independent from the quadratic solver
+    fn sphere_distance(sphere: &Sphere, ray: &Ray) -> Option<f64> {
+        let l = sphere.center - ray.origin;
+        let tca = l.dot(&ray.direction);
+        let d2 = l.norm_squared() - tca * tca;
+        let r2 = sphere.radius * sphere.radius;
+        if d2 > r2 {
+            return None;
+        }
+        let thc = (r2 - d2).sqrt();
+        [tca - thc, tca + thc].into_iter().find(|t| *t >= 0.0)
+    }
+
+    fn check_hit_invariants(hit: &HitRecord, ray: &Ray) {
+        assert!(hit.ray_t >= 0.0);
+        assert!(hit.ray_t.is_finite());
+        assert_relative_eq!(1.0, hit.normal.norm(), epsilon = TOLERANCE);
+        assert_relative_eq!(ray.point_at(hit.ray_t), hit.point, epsilon = TOLERANCE);
+    }
+
+    #[test]
+    fn sphere_matches_geometric_solution() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let sphere = Sphere::new(random_point(&mut rng, 10.0), rng.gen_range(0.1..5.0));
+            let ray = Ray::new(random_point(&mut rng, 20.0), random_direction(&mut rng));
+
+            let hit = sphere.intersect(&ray);
+            let expected = sphere_distance(&sphere, &ray);
+
+            match (hit, expected) {
+                (Some(hit), Some(t)) => {
+                    check_hit_invariants(&hit, &ray);
+                    assert_relative_eq!(t, hit.ray_t, epsilon = TOLERANCE, max_relative = 1e-6);
+                    assert_relative_eq!(
+                        sphere.radius,
+                        glm::distance(&hit.point, &sphere.center),
+                        epsilon = TOLERANCE,
+                        max_relative = 1e-6
+                    );
+                }
+                (None, None) => {}
+                (hit, expected) => panic!("Mismatch: {hit:?} vs {expected:?} for {ray:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_grazing_hits() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let sphere = Sphere::new(random_point(&mut rng, 10.0), rng.gen_range(0.1..5.0));
+            let direction = random_direction(&mut rng);
+            let (tangent, _) = sampling::orthonormal_basis(&direction);
+
+            // Rays passing just inside and just outside the silhouette
+            let inside = sphere.center + tangent * sphere.radius * (1.0 - 1e-6) - direction * 100.0;
+            let outside =
+                sphere.center + tangent * sphere.radius * (1.0 + 1e-6) - direction * 100.0;
+
+            let ray = Ray::new(inside, direction);
+            let hit = sphere.intersect(&ray).expect("Expected a grazing hit");
+            check_hit_invariants(&hit, &ray);
+
+            assert_eq!(None, sphere.intersect(&Ray::new(outside, direction)));
+        }
+    }
+
+    #[test]
+    fn sphere_distance_is_monotonic() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let sphere = Sphere::new(glm::DVec3::zeros(), rng.gen_range(0.1..5.0));
+            let direction = random_direction(&mut rng);
+            let origin = -direction * rng.gen_range(6.0..50.0);
+            let step = rng.gen_range(0.0..10.0);
+
+            let near = sphere.intersect(&Ray::new(origin, direction)).unwrap();
+            let far = sphere
+                .intersect(&Ray::new(origin - step * direction, direction))
+                .unwrap();
+            assert_relative_eq!(near.ray_t + step, far.ray_t, epsilon = TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn triangle_hits_barycentric_targets() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let triangle = Triangle::new(
+                random_point(&mut rng, 10.0),
+                random_point(&mut rng, 10.0),
+                random_point(&mut rng, 10.0),
+            );
+            let edge1 = triangle.vb - triangle.va;
+            let edge2 = triangle.vc - triangle.va;
+            let normal = edge1.cross(&edge2);
+            if normal.norm() < 1e-3 {
+                continue; // Nearly degenerate, covered below
+            }
+
+            // Aim at a point strictly inside or strictly outside
+            let (u, v): (f64, f64) = (rng.gen_range(-0.5..1.5), rng.gen_range(-0.5..1.5));
+            let margin = 1e-4;
+            let inside = u > margin && v > margin && u + v < 1.0 - margin;
+            let outside = u < -margin || v < -margin || u + v > 1.0 + margin;
+
+            let target = triangle.va + u * edge1 + v * edge2;
+            let origin =
+                target + normal.normalize() * rng.gen_range(0.5..20.0) + random_direction(&mut rng);
+            let ray = Ray::new(origin, target - origin);
+
+            let hit = triangle.intersect(&ray);
+            if inside {
+                let hit = hit.expect("Expected a hit inside the triangle");
+                check_hit_invariants(&hit, &ray);
+                assert_relative_eq!(target, hit.point, epsilon = 1e-6);
+                assert_relative_eq!(glm::DVec2::new(u, v), hit.uv, epsilon = 1e-6);
+            } else if outside {
+                assert_eq!(None, hit);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_triangles_never_hit() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let a = random_point(&mut rng, 10.0);
+            let b = random_point(&mut rng, 10.0);
+            // Collinear and coincident vertices
+            let triangles = [
+                Triangle::new(a, b, a + rng.gen_range(-2.0..2.0) * (b - a)),
+                Triangle::new(a, a, b),
+                Triangle::new(a, a, a),
+            ];
+
+            let ray = Ray::new(random_point(&mut rng, 20.0), random_direction(&mut rng));
+            for triangle in triangles {
+                assert_eq!(None, triangle.intersect(&ray));
+            }
+        }
+    }
+
+    #[test]
+    fn plane_hits_lie_on_plane() {
+        let mut rng = rng();
+        for _ in 0..ITERATIONS {
+            let plane = Plane {
+                position: random_point(&mut rng, 10.0),
+                normal: random_direction(&mut rng) * rng.gen_range(0.1..10.0),
+            };
+            let ray = Ray::new(random_point(&mut rng, 20.0), random_direction(&mut rng));
+
+            let origin_side = (ray.origin - plane.position).dot(&plane.normal);
+            let approaching = ray.direction.dot(&plane.normal) * origin_side < 0.0;
+
+            match plane.intersect(&ray) {
+                Some(hit) => {
+                    assert!(approaching);
+                    check_hit_invariants(&hit, &ray);
+                    let n = plane.normal.normalize();
+                    assert_relative_eq!(0.0, (hit.point - plane.position).dot(&n), epsilon = 1e-6);
+                }
+                None => assert!(!approaching || ray.direction.dot(&plane.normal).abs() < 1e-12),
+            }
+        }
+    }
+
+    #[test]
+    fn mesh_matches_its_triangles() {
+        let mut rng = rng();
+        let positions: Vec<glm::DVec3> = (0..60).map(|_| random_point(&mut rng, 5.0)).collect();
+        let triangles: Vec<[u32; 3]> = (0..20).map(|k| [3 * k, 3 * k + 1, 3 * k + 2]).collect();
+        let mesh = Mesh::new(positions.clone(), triangles.clone()).unwrap();
+        let separate: Vec<Triangle> = triangles
+            .iter()
+            .map(|[a, b, c]| {
+                Triangle::new(
+                    positions[*a as usize],
+                    positions[*b as usize],
+                    positions[*c as usize],
+                )
+            })
+            .collect();
+
+        for _ in 0..ITERATIONS {
+            let ray = Ray::new(random_point(&mut rng, 10.0), random_direction(&mut rng));
+            let expected = separate
+                .iter()
+                .filter_map(|triangle| triangle.intersect(&ray))
+                .map(|hit| hit.ray_t)
+                .reduce(f64::min);
+            match (mesh.intersect(&ray), expected) {
+                (Some(hit), Some(t)) => {
+                    check_hit_invariants(&hit, &ray);
+                    assert_relative_eq!(t, hit.ray_t, epsilon = TOLERANCE);
+                }
+                (None, None) => {}
+                (hit, expected) => panic!("Mismatch: {hit:?} vs {expected:?} for {ray:?}"),
+            }
+        }
+    }
 }
@@ -0,0 +1,173 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::color::Color;
+
+const LAMBDA_MIN: f64 = 380.0; // [nm]
+const LAMBDA_MAX: f64 = 780.0; // [nm]
+const LAMBDA_STEP: f64 = 5.0; // [nm]
+
+/// Relative spectral power distribution of CIE standard illuminant D65,
+/// tabulated every 10 nm from 380 nm to 780 nm
+const D65_SPD: [f64; 41] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.79, 107.689, 104.405, 104.046, 100.0, 96.3342, 95.788,
+    88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778, 78.2842,
+    69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054, 63.3828,
+];
+
+/// Correlated color temperature of CIE standard illuminant A [K]
+const ILLUMINANT_A_TEMPERATURE: f64 = 2856.0;
+
+/// Light source color, specified physically rather than as raw RGB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Illuminant {
+    /// Ideal blackbody radiator at the given temperature [K]
+    Blackbody(f64),
+    /// CIE standard illuminant D65 (average daylight)
+    D65,
+    /// CIE standard illuminant A (incandescent tungsten)
+    A,
+}
+
+impl Illuminant {
+    /// Relative spectral power at wavelength `lambda` [nm]
+    pub fn spectral_power(&self, lambda: f64) -> f64 {
+        match self {
+            Self::Blackbody(temperature) => planck(lambda, *temperature),
+            Self::A => planck(lambda, ILLUMINANT_A_TEMPERATURE),
+            Self::D65 => {
+                let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, (D65_SPD.len() - 1) as f64);
+                let i = (x.floor() as usize).min(D65_SPD.len() - 2);
+                let t = x - (i as f64);
+                (1.0 - t) * D65_SPD[i] + t * D65_SPD[i + 1]
+            }
+        }
+    }
+
+    /// CIE XYZ tristimulus values, normalized to Y = 1
+    pub fn xyz(&self) -> glm::DVec3 {
+        let xyz = spectrum_to_xyz(|lambda| self.spectral_power(lambda));
+        xyz / xyz.y
+    }
+
+    /// Linear sRGB color, normalized so that its largest component is 1
+    pub fn color(&self) -> Color {
+        let rgb = xyz_to_linear_srgb(&self.xyz()).map(|c| c.max(0.0));
+        rgb / rgb.max()
+    }
+}
+
+/// Spectral radiance of a blackbody at wavelength `lambda` [nm] and
+/// temperature [K], following Planck's law. Units are arbitrary but
+/// consistent across wavelengths.
+pub fn planck(lambda: f64, temperature: f64) -> f64 {
+    const C2: f64 = 1.4387769e7; // Second radiation constant hc/k [nm K]
+
+    let lambda_um = lambda * 1e-3;
+    1.0 / (lambda_um.powi(5) * ((C2 / (lambda * temperature)).exp_m1()))
+}
+
+/// Linear sRGB radiance of a blackbody at the given temperature [K], in the
+/// units of `planck`
+pub fn blackbody_rgb(temperature: f64) -> Color {
+    if temperature <= 0.0 {
+        return Color::zeros();
+    }
+    let xyz = spectrum_to_xyz(|lambda| planck(lambda, temperature));
+    xyz_to_linear_srgb(&xyz).map(|c| c.max(0.0))
+}
+
+/// Piecewise gaussian used by the CIE color matching function fit
+fn lobe(lambda: f64, mean: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+    let sigma = if lambda < mean { sigma_low } else { sigma_high };
+    let t = (lambda - mean) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// CIE 1931 2° color matching functions, using the multi-lobe fit by
+/// Wyman, Sloan and Shirley (2013)
+pub fn color_matching(lambda: f64) -> glm::DVec3 {
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+    glm::DVec3::new(x, y, z)
+}
+
+/// Integrate a spectral power distribution against the color matching
+/// functions over the visible range
+pub fn spectrum_to_xyz<F: Fn(f64) -> f64>(spd: F) -> glm::DVec3 {
+    let steps = ((LAMBDA_MAX - LAMBDA_MIN) / LAMBDA_STEP) as usize;
+    (0..=steps)
+        .map(|n| LAMBDA_MIN + (n as f64) * LAMBDA_STEP)
+        .map(|lambda| spd(lambda) * color_matching(lambda) * LAMBDA_STEP)
+        .sum()
+}
+
+/// Convert CIE XYZ to linear sRGB (D65 white point)
+pub fn xyz_to_linear_srgb(xyz: &glm::DVec3) -> Color {
+    let m = glm::DMat3::new(
+        3.2404542, -1.5371385, -0.4985314, //
+        -0.9692660, 1.8760108, 0.0415560, //
+        0.0556434, -0.2040259, 1.0572252,
+    );
+    m * xyz
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn d65_is_white() {
+        let color = Illuminant::D65.color();
+        assert_relative_eq!(Color::new(1.0, 1.0, 1.0), color, epsilon = 0.05);
+    }
+
+    #[test]
+    fn d65_chromaticity() {
+        let xyz = Illuminant::D65.xyz();
+        let sum = xyz.x + xyz.y + xyz.z;
+        assert_relative_eq!(0.3127, xyz.x / sum, epsilon = 0.005);
+        assert_relative_eq!(0.3290, xyz.y / sum, epsilon = 0.005);
+    }
+
+    #[test]
+    fn tungsten_is_warm_and_hot_blackbody_is_cold() {
+        let warm = Illuminant::A.color();
+        assert!(warm.x > warm.y && warm.y > warm.z);
+
+        let cold = Illuminant::Blackbody(12000.0).color();
+        assert!(cold.z > cold.y && cold.y > cold.x);
+    }
+
+    #[test]
+    fn planck_peak_follows_wien_law() {
+        // Wien's displacement law: peak at b / T, with b = 2.898e6 nm K
+        let temperature = 5000.0;
+        let peak = (300..1000)
+            .map(|lambda| lambda as f64)
+            .max_by(|a, b| planck(*a, temperature).total_cmp(&planck(*b, temperature)))
+            .unwrap();
+        assert_relative_eq!(2.898e6 / temperature, peak, epsilon = 1.0);
+    }
+}
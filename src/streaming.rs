@@ -0,0 +1,411 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Meshes streamed from disk. Large OBJ models can be left on disk until a
+//! ray first enters their bounding box, so that the parts of a scene no ray
+//! reaches never take any memory. The first ray to arrive loads the mesh
+//! while rays from other tiles wait for it. Models that can no longer be
+//! read by then are missed by every ray, see `LazyMesh::warning`.
+//!
+//! The lazy meshes of a scene share a `MeshResidency`, which unloads the
+//! meshes rays haven't entered for the longest when the loaded ones go over
+//! its budget. They are loaded again by the next ray that needs them.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_json::Value;
+
+use crate::light::Ray;
+use crate::shape::{Bounds, HitRecord, Mesh, Shape, SurfaceSample};
+
+/// Memory budget of the lazy meshes of a scene. Loading a mesh that takes
+/// the loaded ones over the budget unloads others in clock order: a sweep
+/// over the loaded meshes spares the ones a ray entered since its last pass
+/// and unloads the first one no ray did. The mesh just loaded is always
+/// kept, even if it's larger than the budget on its own.
+#[derive(Debug)]
+pub struct MeshResidency {
+    budget: usize, // Bytes
+    loaded: Mutex<Loaded>,
+}
+
+#[derive(Debug, Default)]
+struct Loaded {
+    meshes: Vec<(Arc<Slot>, usize)>, // With their size in bytes
+    hand: usize,
+    bytes: usize,
+}
+
+impl Default for MeshResidency {
+    /// No budget, meshes stay loaded
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl MeshResidency {
+    /// Residency with a memory budget in bytes
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            loaded: Mutex::new(Loaded::default()),
+        }
+    }
+
+    /// Bytes of the meshes loaded right now
+    pub fn resident(&self) -> usize {
+        self.loaded.lock().unwrap().bytes
+    }
+
+    /// Count a mesh that was just loaded, and return the meshes to unload to
+    /// make room for it. They are unloaded by the caller, without holding
+    /// the lock of the residency, as a slot being loaded holds its own lock.
+    fn admit(&self, slot: Arc<Slot>, size: usize) -> Vec<Arc<Slot>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let mut evicted = Vec::new();
+        while loaded.bytes + size > self.budget && !loaded.meshes.is_empty() {
+            if loaded.hand >= loaded.meshes.len() {
+                loaded.hand = 0;
+            }
+            let hand = loaded.hand;
+            if loaded.meshes[hand]
+                .0
+                .referenced
+                .swap(false, Ordering::Relaxed)
+            {
+                loaded.hand += 1;
+                continue;
+            }
+            let (victim, victim_size) = loaded.meshes.swap_remove(hand);
+            loaded.bytes -= victim_size;
+            evicted.push(victim);
+        }
+        loaded.meshes.push((slot, size));
+        loaded.bytes += size;
+        evicted
+    }
+}
+
+/// Geometry of a lazy mesh, shared with the residency that may unload it
+#[derive(Debug, Default)]
+struct Slot {
+    state: RwLock<SlotState>,
+    referenced: AtomicBool, // Entered by a ray since the last sweep
+}
+
+#[derive(Debug, Default)]
+enum SlotState {
+    #[default]
+    OnDisk,
+    Loaded(Arc<Mesh>),
+    Failed(String),
+}
+
+impl Slot {
+    fn unload(&self) {
+        let mut state = self.state.write().unwrap();
+        if matches!(*state, SlotState::Loaded(_)) {
+            *state = SlotState::OnDisk;
+        }
+    }
+}
+
+/// OBJ model loaded the first time a ray enters its bounds. Only its bounds,
+/// size and material names are read up front. The mesh stays in memory once
+/// loaded unless its `MeshResidency` unloads it to make room for others.
+#[derive(Debug)]
+pub struct LazyMesh {
+    path: PathBuf,
+    bounds: Bounds,
+    triangles: usize,
+    size: usize, // Bytes of the loaded mesh, estimated when opened
+    material_names: Vec<String>,
+    material_libraries: Vec<String>,
+    slot: Arc<Slot>,
+    residency: Arc<MeshResidency>,
+}
+
+impl LazyMesh {
+    /// Scan a Wavefront OBJ file for the bounds of its vertices, the number
+    /// of its triangles and the materials of its faces, without keeping any
+    /// geometry. Its memory is counted by `residency` while it's loaded.
+    pub fn open<P: AsRef<Path>>(path: P, residency: Arc<MeshResidency>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let error = |e: String| format!("{}: {e}", path.display());
+        let file =
+            File::open(path).map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+
+        let mut bounds = Bounds::empty();
+        let (mut positions, mut normals, mut uvs, mut triangles) = (0, 0, 0, 0);
+        let mut material_names: Vec<String> = Vec::new();
+        let mut material_libraries = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| error(e.to_string()))?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let v = tokens
+                        .take(3)
+                        .map(|value| value.parse::<f64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .ok()
+                        .filter(|v| v.len() == 3)
+                        .ok_or_else(|| error(format!("Line {}: Expected 3 numbers", number + 1)))?;
+                    let point = glm::DVec3::new(v[0], v[1], v[2]);
+                    bounds = bounds.union(&Bounds::from_points([&point]));
+                    positions += 1;
+                }
+                Some("vn") => normals += 1,
+                Some("vt") => uvs += 1,
+                // Polygons are split into a fan of triangles
+                Some("f") => triangles += tokens.count().saturating_sub(2),
+                // Same order of first use as the faces of the loaded mesh
+                Some("usemtl") => {
+                    let name = tokens.collect::<Vec<_>>().join(" ");
+                    if !material_names.contains(&name) {
+                        material_names.push(name);
+                    }
+                }
+                Some("mtllib") => material_libraries.extend(tokens.map(str::to_string)),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            bounds,
+            triangles,
+            size: Mesh::estimate_memory(positions, normals, uvs, triangles),
+            material_names,
+            material_libraries,
+            slot: Arc::default(),
+            residency,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        matches!(*self.slot.state.read().unwrap(), SlotState::Loaded(_))
+    }
+
+    /// Names of the materials of the faces, by index, as in
+    /// `Mesh::material_names`
+    pub fn material_names(&self) -> &[String] {
+        &self.material_names
+    }
+
+    /// MTL files named by the OBJ file, relative to it
+    pub fn material_libraries(&self) -> &[String] {
+        &self.material_libraries
+    }
+
+    /// The mesh, loading it if it isn't in memory. None if it couldn't be
+    /// loaded: the file was readable when the scene was loaded, so it was
+    /// changed or removed behind our back.
+    pub fn mesh(&self) -> Option<Arc<Mesh>> {
+        if !self.slot.referenced.load(Ordering::Relaxed) {
+            self.slot.referenced.store(true, Ordering::Relaxed);
+        }
+        match &*self.slot.state.read().unwrap() {
+            SlotState::Loaded(mesh) => return Some(mesh.clone()),
+            SlotState::Failed(_) => return None,
+            SlotState::OnDisk => {}
+        }
+
+        // Rays that arrive while the mesh loads wait for it here
+        let mut state = self.slot.state.write().unwrap();
+        match &*state {
+            SlotState::Loaded(mesh) => return Some(mesh.clone()),
+            SlotState::Failed(_) => return None,
+            SlotState::OnDisk => {}
+        }
+        let mesh = match Mesh::from_obj(&self.path) {
+            Ok(mesh) => Arc::new(mesh),
+            Err(e) => {
+                *state = SlotState::Failed(e);
+                return None;
+            }
+        };
+        *state = SlotState::Loaded(mesh.clone());
+        drop(state);
+        for evicted in self.residency.admit(self.slot.clone(), mesh.memory_size()) {
+            evicted.unload();
+        }
+        Some(mesh)
+    }
+}
+
+impl Shape for LazyMesh {
+    fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.bounds.intersect(ray)?;
+        self.mesh()?.intersect(ray)
+    }
+
+    fn kind(&self) -> &'static str {
+        "lazy mesh"
+    }
+
+    /// Bounds of all the vertices of the file, whether faces use them or not
+    fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    /// Lights need the whole surface, sampling loads the mesh
+    fn sample_surface(&self, u: glm::DVec2) -> Option<SurfaceSample> {
+        self.mesh()?.sample_surface(u)
+    }
+
+    /// Counted when the file is opened, loaded or not
+    fn triangle_count(&self) -> usize {
+        self.triangles
+    }
+
+    /// Only known while loaded
+    fn min_triangle_area(&self) -> Option<f64> {
+        match &*self.slot.state.read().unwrap() {
+            SlotState::Loaded(mesh) => mesh.min_triangle_area(),
+            _ => None,
+        }
+    }
+
+    /// Memory of the mesh once loaded, estimated before loading it so that
+    /// memory budgets count it up front
+    fn memory_size(&self) -> usize {
+        std::mem::size_of_val(self) + self.size
+    }
+
+    /// Saved as the mesh it loads
+    fn to_json(&self) -> Option<Value> {
+        self.mesh()?.to_json()
+    }
+
+    /// Why the mesh couldn't be loaded, if a ray tried to
+    fn warning(&self) -> Option<String> {
+        match &*self.slot.state.read().unwrap() {
+            SlotState::Failed(error) => Some(format!(
+                "Couldn't load a lazy mesh, its rays missed it: {error}"
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn load_on_first_hit() {
+        let path = std::env::temp_dir().join(format!("light-lazy-{}.obj", std::process::id()));
+        fs::write(
+            &path,
+            "mtllib model.mtl\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             usemtl red\nf 1 2 3\nusemtl blue\nf 1 3 4\nusemtl red\n",
+        )
+        .unwrap();
+
+        let lazy = LazyMesh::open(&path, Arc::default()).unwrap();
+        assert!(!lazy.is_loaded());
+        assert_eq!(["red", "blue"], lazy.material_names());
+        assert_eq!(["model.mtl"], lazy.material_libraries());
+        assert_eq!(glm::DVec3::zeros(), lazy.bounds().min);
+        assert_eq!(glm::DVec3::new(1.0, 1.0, 0.0), lazy.bounds().max);
+        assert_eq!(2, lazy.triangle_count());
+        let loaded = Mesh::from_obj(&path).unwrap();
+        assert!(lazy.memory_size() >= loaded.memory_size());
+
+        // Rays that miss the bounds leave the file on disk
+        let miss = Ray::new(glm::DVec3::new(5.0, 5.0, 1.0), -glm::DVec3::z());
+        assert!(lazy.intersect(&miss).is_none());
+        assert!(!lazy.is_loaded());
+
+        let ray = Ray::new(glm::DVec3::new(0.25, 0.75, 1.0), -glm::DVec3::z());
+        let hit = lazy.intersect(&ray).unwrap();
+        assert!(lazy.is_loaded());
+        let expected = loaded.intersect(&ray).unwrap();
+        assert_eq!(expected.point, hit.point);
+        assert_eq!(expected.material, hit.material);
+
+        assert!(lazy.warning().is_none());
+
+        fs::write(&path, "v 0 0 x\n").unwrap();
+        assert!(LazyMesh::open(&path, Arc::default()).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(LazyMesh::open(&path, Arc::default()).is_err());
+    }
+
+    #[test]
+    fn removed_file_is_missed() {
+        let path = std::env::temp_dir().join(format!("light-lost-{}.obj", std::process::id()));
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 3\n").unwrap();
+        let lazy = LazyMesh::open(&path, Arc::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let ray = Ray::new(glm::DVec3::new(0.75, 0.25, 1.0), -glm::DVec3::z());
+        assert!(lazy.intersect(&ray).is_none());
+        assert!(!lazy.is_loaded());
+        assert!(lazy.mesh().is_none());
+        assert!(lazy.sample_surface(glm::DVec2::new(0.5, 0.5)).is_none());
+        assert!(lazy
+            .warning()
+            .unwrap()
+            .contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn unload_over_budget() {
+        let path = |name: &str| {
+            let path = std::env::temp_dir()
+                .join(format!("light-resident-{name}-{}.obj", std::process::id()));
+            fs::write(&path, "v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 3\n").unwrap();
+            path
+        };
+        let (first, second) = (path("first"), path("second"));
+        let size = Mesh::from_obj(&first).unwrap().memory_size();
+        let residency = Arc::new(MeshResidency::new(size));
+        let meshes = [&first, &second].map(|path| LazyMesh::open(path, residency.clone()).unwrap());
+
+        // Only one of them fits, the other one is unloaded
+        let ray = Ray::new(glm::DVec3::new(0.75, 0.25, 1.0), -glm::DVec3::z());
+        assert!(meshes[0].intersect(&ray).is_some());
+        assert!(meshes[1].intersect(&ray).is_some());
+        assert!(!meshes[0].is_loaded());
+        assert!(meshes[1].is_loaded());
+        assert_eq!(size, residency.resident());
+
+        // And loaded again by the next ray
+        assert!(meshes[0].intersect(&ray).is_some());
+        assert!(meshes[0].is_loaded());
+        assert!(!meshes[1].is_loaded());
+        assert_eq!(size, residency.resident());
+
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::f64::consts::PI;
+
+/// Date and time of day. `utc_offset` is the time zone in hours (e.g. +1.0
+/// for CET), so that local clock times can be used directly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u32,  // 1..=12
+    pub day: u32,    // 1..=31
+    pub hour: u32,   // 0..=23
+    pub minute: u32, // 0..=59
+    pub second: f64,
+    pub utc_offset: f64, // [h]
+}
+
+/// Geographic location in degrees. Latitude is positive to the north and
+/// longitude positive to the east of Greenwich.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Position of the sun in the sky
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SunPosition {
+    pub azimuth: f64,   // [rad] clockwise from north
+    pub elevation: f64, // [rad] above the horizon
+}
+
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+impl DateTime {
+    /// Day of the year, starting at 1 on January 1st
+    pub fn day_of_year(&self) -> u32 {
+        const CUMULATIVE_DAYS: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+
+        let month = self.month.clamp(1, 12) as usize;
+        let leap_day = if month > 2 && is_leap_year(self.year) {
+            1
+        } else {
+            0
+        };
+
+        CUMULATIVE_DAYS[month - 1] + self.day + leap_day
+    }
+
+    /// Local clock time in minutes since midnight
+    fn minutes(&self) -> f64 {
+        (self.hour as f64) * 60.0 + (self.minute as f64) + self.second / 60.0
+    }
+}
+
+impl SunPosition {
+    /// Compute the position of the sun using the NOAA general solar position
+    /// equations. Accuracy is within a fraction of a degree, which is more
+    /// than enough for shadow studies.
+    pub fn compute(location: &GeoLocation, time: &DateTime) -> Self {
+        let days_in_year = if is_leap_year(time.year) {
+            366.0
+        } else {
+            365.0
+        };
+        let utc_hour = time.minutes() / 60.0 - time.utc_offset;
+
+        // Fractional year
+        let gamma = (2.0 * PI / days_in_year)
+            * ((time.day_of_year() as f64) - 1.0 + (utc_hour - 12.0) / 24.0);
+
+        // Equation of time [min] and solar declination [rad]
+        let eq_time = 229.18
+            * (0.000075 + 0.001868 * gamma.cos()
+                - 0.032077 * gamma.sin()
+                - 0.014615 * (2.0 * gamma).cos()
+                - 0.040849 * (2.0 * gamma).sin());
+        let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+            - 0.006758 * (2.0 * gamma).cos()
+            + 0.000907 * (2.0 * gamma).sin()
+            - 0.002697 * (3.0 * gamma).cos()
+            + 0.00148 * (3.0 * gamma).sin();
+
+        // True solar time [min] and hour angle [rad]
+        let time_offset = eq_time + 4.0 * location.longitude - 60.0 * time.utc_offset;
+        let solar_time = time.minutes() + time_offset;
+        let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+        let latitude = location.latitude.to_radians();
+        let sin_elevation = latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos();
+        let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+
+        // Azimuth measured from the south towards the west, then shifted to
+        // be measured clockwise from the north
+        let azimuth_south = hour_angle
+            .sin()
+            .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+        let azimuth = (azimuth_south + PI).rem_euclid(2.0 * PI);
+
+        Self { azimuth, elevation }
+    }
+
+    /// Unit vector pointing towards the sun. The world frame has +y up,
+    /// north towards -z and east towards +x.
+    pub fn direction(&self) -> glm::DVec3 {
+        let (sin_az, cos_az) = self.azimuth.sin_cos();
+        let (sin_el, cos_el) = self.elevation.sin_cos();
+        glm::DVec3::new(sin_az * cos_el, sin_el, -cos_az * cos_el)
+    }
+
+    pub fn is_above_horizon(&self) -> bool {
+        self.elevation > 0.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn noon(year: i32, month: u32, day: u32) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour: 12,
+            minute: 0,
+            second: 0.0,
+            utc_offset: 0.0,
+        }
+    }
+
+    #[test]
+    fn day_of_year() {
+        assert_eq!(1, noon(2023, 1, 1).day_of_year());
+        assert_eq!(59, noon(2023, 2, 28).day_of_year());
+        assert_eq!(60, noon(2023, 3, 1).day_of_year());
+        assert_eq!(61, noon(2024, 3, 1).day_of_year());
+        assert_eq!(366, noon(2024, 12, 31).day_of_year());
+    }
+
+    #[test]
+    fn noon_elevation_at_solstice() {
+        // At solar noon the elevation is 90° - latitude + declination
+        let location = GeoLocation {
+            latitude: 40.0,
+            longitude: 0.0,
+        };
+        let sun = SunPosition::compute(&location, &noon(2023, 6, 21));
+
+        assert_relative_eq!(73.44, sun.elevation.to_degrees(), epsilon = 0.5);
+        // Northern hemisphere: the sun is due south at noon
+        assert_relative_eq!(180.0, sun.azimuth.to_degrees(), epsilon = 2.0);
+    }
+
+    #[test]
+    fn sun_rises_in_the_east() {
+        let location = GeoLocation {
+            latitude: 0.0,
+            longitude: 0.0,
+        };
+        let morning = DateTime {
+            hour: 8,
+            ..noon(2023, 3, 20)
+        };
+        let sun = SunPosition::compute(&location, &morning);
+
+        assert!(sun.is_above_horizon());
+        assert!(sun.direction().x > 0.0);
+        assert_relative_eq!(1.0, sun.direction().norm(), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn sun_below_horizon_at_midnight() {
+        let location = GeoLocation {
+            latitude: 48.85,
+            longitude: 2.35,
+        };
+        let midnight = DateTime {
+            hour: 0,
+            utc_offset: 1.0,
+            ..noon(2023, 1, 15)
+        };
+        let sun = SunPosition::compute(&location, &midnight);
+
+        assert!(!sun.is_above_horizon());
+        assert!(sun.direction().y < 0.0);
+    }
+}
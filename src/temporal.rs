@@ -0,0 +1,280 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Temporal accumulation for animations. The samples of the previous frame
+//! are reprojected into the next one wherever both frames see the same
+//! surface, so that a frame starts from the history of its pixels instead
+//! of from zero.
+//!
+//! Pixels are matched through the surface seen at their centers: a pixel
+//! takes the samples of the pixel of the previous frame its surface point
+//! projects to, as long as that pixel saw the same object at about the same
+//! depth, with about the same normal. Everything else, like disocclusions or
+//! the background, starts afresh. View dependent effects, like reflections,
+//! lag behind the camera by up to the history length.
+
+use rayon::prelude::*;
+
+use crate::camera::{Camera, CameraConfig};
+use crate::film::{Film, Pixel};
+use crate::render::{get_closest_hit, PathTracer};
+use crate::scene::Scene;
+
+/// Settings of the temporal accumulation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemporalReuse {
+    pub max_history: u32,      // Most samples carried over per pixel
+    pub depth_tolerance: f64,  // Relative difference of the depths of matched points
+    pub normal_tolerance: f64, // Least cosine between the normals of matched points
+}
+
+impl Default for TemporalReuse {
+    fn default() -> Self {
+        Self {
+            max_history: 64,
+            depth_tolerance: 0.05,
+            normal_tolerance: 0.95,
+        }
+    }
+}
+
+/// Surface seen at the center of a pixel
+#[derive(Debug, Clone, Copy)]
+struct Surface {
+    point: glm::DVec3,
+    normal: glm::DVec3,
+    object: usize,
+}
+
+/// Frame kept for the next one
+struct History {
+    camera: Camera,
+    surfaces: Vec<Option<Surface>>, // Scanline order
+    film: Film,
+}
+
+/// Renders the frames of an animation, reusing the samples of each frame in
+/// the next one
+pub struct TemporalAccumulator {
+    settings: TemporalReuse,
+    history: Option<History>,
+    reused: f64,
+}
+
+impl TemporalAccumulator {
+    pub fn new(settings: TemporalReuse) -> Self {
+        Self {
+            settings,
+            history: None,
+            reused: 0.0,
+        }
+    }
+
+    /// Render the next frame with `renderer` and add the history of its
+    /// pixels. The result is kept as the history of the following frame.
+    pub fn render_frame(
+        &mut self,
+        renderer: &PathTracer,
+        scene: &Scene,
+        config: &CameraConfig,
+    ) -> &Film {
+        let camera = Camera::new(config);
+        let surfaces = surfaces(scene, &camera);
+        let mut film = renderer.render_film(scene, &camera);
+
+        let mut reused = 0;
+        if let Some(history) = &self.history {
+            film.update_pixels(|index, pixel| {
+                let Some(surface) = &surfaces[index] else {
+                    return;
+                };
+                let Some(mut previous) = self.matching_pixel(history, surface) else {
+                    return;
+                };
+                // Keep the mean and variance of the history while capping it
+                if previous.samples > self.settings.max_history {
+                    let scale = self.settings.max_history as f64 / previous.samples as f64;
+                    previous.sum *= scale;
+                    previous.sum_sq *= scale;
+                    previous.samples = self.settings.max_history;
+                }
+                // Not the overlay, which the frame has of its own
+                pixel.sum += previous.sum;
+                pixel.sum_sq += previous.sum_sq;
+                pixel.samples += previous.samples;
+                reused += 1;
+            });
+        }
+        self.reused = reused as f64 / surfaces.len() as f64;
+
+        &self
+            .history
+            .insert(History {
+                camera,
+                surfaces,
+                film,
+            })
+            .film
+    }
+
+    /// Bytes of a frame of `camera` and its history: a film and the surface
+    /// seen by each pixel for both
+    pub fn frame_memory(renderer: &PathTracer, camera: &Camera) -> usize {
+        let (w, h) = camera.resolution();
+        let surfaces = w as usize * h as usize * std::mem::size_of::<Option<Surface>>();
+        2 * (renderer.film_memory(camera, false) + surfaces)
+    }
+
+    /// Fraction of the pixels of the last frame that reused the history
+    pub fn reused_fraction(&self) -> f64 {
+        self.reused
+    }
+
+    /// Pixel of the previous frame that saw the same surface point
+    fn matching_pixel(&self, history: &History, surface: &Surface) -> Option<Pixel> {
+        let (i, j) = history.camera.project(&surface.point)?;
+        let (w, _) = history.camera.resolution();
+        let previous = history.surfaces[(j * w + i) as usize]?;
+
+        let origin = history.camera.position();
+        let depth = glm::distance(&origin, &surface.point);
+        let previous_depth = glm::distance(&origin, &previous.point);
+        let matches = previous.object == surface.object
+            && (depth - previous_depth).abs() <= self.settings.depth_tolerance * depth
+            && previous.normal.dot(&surface.normal) >= self.settings.normal_tolerance;
+        matches.then(|| history.film.pixel(i, j))
+    }
+}
+
+/// Surface seen at the center of each pixel, in scanline order
+fn surfaces(scene: &Scene, camera: &Camera) -> Vec<Option<Surface>> {
+    let (w, h) = camera.resolution();
+    (0..w * h)
+        .into_par_iter()
+        .map(|index| {
+            let ray = camera.center_ray(index % w, index / w)?;
+            let (record, _, object) = get_closest_hit(&scene.objects, &ray)?;
+            Some(Surface {
+                point: record.point,
+                normal: record.normal,
+                object,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::Plane;
+
+    /// Diffuse floor under a uniform sky, which reflects albedo × sky
+    fn floor_scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 1.0, 1.0);
+        scene.add_object(Object {
+            shape: Box::new(Plane {
+                position: glm::DVec3::zeros(),
+                normal: glm::DVec3::y(),
+            }),
+            material: Material {
+                color: Color::new(100.0, 50.0, 10.0),
+                ..Default::default()
+            },
+            materials: Vec::new(),
+        });
+        scene
+    }
+
+    fn camera_at(x: f64) -> CameraConfig {
+        CameraConfig {
+            position: glm::DVec3::new(x, 5.0, 0.0),
+            direction: -glm::DVec3::y(),
+            resolution: (16, 16),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn still_camera_accumulates() {
+        let scene = floor_scene();
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(4).max_depth(1);
+        let mut accumulator = TemporalAccumulator::new(TemporalReuse {
+            max_history: 6,
+            ..Default::default()
+        });
+        // The squares of the history come along with its samples, so that the
+        // spread of the samples, small on the floor, stays small
+        let consistent = |film: &Film| {
+            film.pixels().all(|pixel| {
+                let n = pixel.samples as f64;
+                let mean_sq = pixel.sum_sq.mean() / n;
+                let sq_mean = (pixel.sum / n).map(|c| c * c).mean();
+                (mean_sq - sq_mean).abs() <= 0.05 * sq_mean
+            })
+        };
+
+        let film = accumulator.render_frame(&renderer, &scene, &camera_at(0.0));
+        assert!(film.pixels().all(|pixel| pixel.samples == 4));
+        assert_eq!(0.0, accumulator.reused_fraction());
+
+        let film = accumulator.render_frame(&renderer, &scene, &camera_at(0.0));
+        assert!(film.pixels().all(|pixel| pixel.samples == 8));
+        assert!(consistent(film));
+        assert_eq!(1.0, accumulator.reused_fraction());
+
+        // The history is capped, keeping its average
+        let film = accumulator.render_frame(&renderer, &scene, &camera_at(0.0));
+        assert!(film.pixels().all(|pixel| pixel.samples == 10));
+        assert!(consistent(film));
+        let mean: Color =
+            film.pixels().map(|p| p.color()).sum::<Color>() / film.pixels().count() as f64;
+        assert_relative_eq!(
+            Color::new(100.0, 50.0, 10.0) / 255.0,
+            mean,
+            max_relative = 0.03
+        );
+    }
+
+    #[test]
+    fn moving_camera_reuses_what_it_saw() {
+        let scene = floor_scene();
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(2).max_depth(1);
+        let mut accumulator = TemporalAccumulator::new(TemporalReuse::default());
+        accumulator.render_frame(&renderer, &scene, &camera_at(0.0));
+
+        // Part of the floor is new to the second frame
+        let film = accumulator.render_frame(&renderer, &scene, &camera_at(1.0));
+        let fresh = film.pixels().filter(|pixel| pixel.samples == 2).count();
+        let reused = accumulator.reused_fraction();
+        assert!(reused > 0.5 && reused < 1.0, "{reused}");
+        assert_eq!(((1.0 - reused) * 256.0).round() as usize, fresh);
+
+        // Nothing in common
+        accumulator.render_frame(&renderer, &scene, &camera_at(100.0));
+        assert_eq!(0.0, accumulator.reused_fraction());
+    }
+}
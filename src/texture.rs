@@ -0,0 +1,695 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::UNIX_EPOCH;
+
+use image::RgbImage;
+
+use crate::color::Color;
+use crate::compression::{CompressedTile, TextureFormat};
+
+/// Side of the square tiles textures are split into, in texels
+pub const TILE_SIZE: u32 = 64;
+const TILE_BYTES: usize = (TILE_SIZE * TILE_SIZE * 3) as usize;
+const MAGIC: &[u8; 4] = b"LTEX";
+const HEADER_SIZE: u64 = 16;
+
+/// Default memory budget of a texture cache
+pub const DEFAULT_BUDGET: usize = 256 << 20;
+
+/// Budget of the texture cache of a render with a memory budget in bytes: a
+/// quarter of it, up to the default budget
+pub fn budget_share(memory_budget: usize) -> usize {
+    (memory_budget / 4).min(DEFAULT_BUDGET)
+}
+
+/// Index of a texture in a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct TileKey {
+    texture: usize,
+    level: u32,
+    x: u32,
+    y: u32,
+}
+
+/// Resolution of each level of a mip chain, finest first
+fn mip_levels(width: u32, height: u32) -> Vec<(u32, u32)> {
+    let mut levels = vec![(width, height)];
+    while let Some(&(w, h)) = levels.last().filter(|(w, h)| *w > 1 || *h > 1) {
+        levels.push(((w / 2).max(1), (h / 2).max(1)));
+    }
+    levels
+}
+
+fn tiles_across(size: u32) -> u32 {
+    size.div_ceil(TILE_SIZE)
+}
+
+/// Halve an image with a box filter
+fn downsample(image: &RgbImage) -> RgbImage {
+    let (width, height) = ((image.width() / 2).max(1), (image.height() / 2).max(1));
+    RgbImage::from_fn(width, height, |x, y| {
+        let mut sum = [0_u32; 3];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let sx = (2 * x + dx).min(image.width() - 1);
+            let sy = (2 * y + dy).min(image.height() - 1);
+            for (total, channel) in sum.iter_mut().zip(image.get_pixel(sx, sy).0) {
+                *total += channel as u32;
+            }
+        }
+        image::Rgb(sum.map(|total| ((total + 2) / 4) as u8))
+    })
+}
+
+/// Write an image and its mip chain as a tiled file, so that single tiles can
+/// be read without decoding the whole image. Tiles are stored level by level,
+/// in scanline order, padded to the full tile size.
+fn write_tiled<W: Write>(image: RgbImage, writer: &mut W) -> Result<(), String> {
+    let levels = mip_levels(image.width(), image.height());
+    let mut header = MAGIC.to_vec();
+    for value in [image.width(), image.height(), levels.len() as u32] {
+        header.extend(value.to_le_bytes());
+    }
+    writer.write_all(&header).map_err(|e| e.to_string())?;
+
+    let mut level = image;
+    loop {
+        for ty in 0..tiles_across(level.height()) {
+            for tx in 0..tiles_across(level.width()) {
+                let mut tile = vec![0_u8; TILE_BYTES];
+                for y in 0..TILE_SIZE.min(level.height() - ty * TILE_SIZE) {
+                    for x in 0..TILE_SIZE.min(level.width() - tx * TILE_SIZE) {
+                        let pixel = level.get_pixel(tx * TILE_SIZE + x, ty * TILE_SIZE + y);
+                        let offset = 3 * (y * TILE_SIZE + x) as usize;
+                        tile[offset..offset + 3].copy_from_slice(&pixel.0);
+                    }
+                }
+                writer.write_all(&tile).map_err(|e| e.to_string())?;
+            }
+        }
+        if level.width() == 1 && level.height() == 1 {
+            return Ok(());
+        }
+        level = downsample(&level);
+    }
+}
+
+#[derive(Debug)]
+struct TextureFile {
+    file: Mutex<File>,
+    levels: Vec<(u32, u32)>,
+    tile_offsets: Vec<u64>, // Offset of the first tile of each level
+    format: TextureFormat,  // Storage of resident tiles
+    source: PathBuf,        // Image the tiled file was made from
+}
+
+impl TextureFile {
+    fn open(path: &Path, source: &Path, format: TextureFormat) -> Result<Self, String> {
+        let mut file = File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut header = [0_u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        if &header[..4] != MAGIC {
+            return Err(format!("{} is not a tiled texture", path.display()));
+        }
+        let word = |i: usize| u32::from_le_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+        let levels = mip_levels(word(1), word(2));
+        if levels.len() != word(3) as usize {
+            return Err(format!("{} has a broken mip chain", path.display()));
+        }
+
+        let mut tile_offsets = Vec::with_capacity(levels.len());
+        let mut offset = HEADER_SIZE;
+        for &(width, height) in &levels {
+            tile_offsets.push(offset);
+            offset += (tiles_across(width) * tiles_across(height)) as u64 * TILE_BYTES as u64;
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            levels,
+            tile_offsets,
+            format,
+            source: source.to_path_buf(),
+        })
+    }
+
+    fn read_tile(&self, level: u32, x: u32, y: u32) -> Result<CompressedTile, String> {
+        let (width, _) = self.levels[level as usize];
+        let index = (y * tiles_across(width) + x) as u64;
+        let offset = self.tile_offsets[level as usize] + index * TILE_BYTES as u64;
+        let mut tile = vec![0_u8; TILE_BYTES];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut tile))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(CompressedTile::encode(
+            self.format,
+            &tile,
+            TILE_SIZE as usize,
+        ))
+    }
+}
+
+/// Hit and miss counters of a cache
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub failures: u64,   // Tiles that couldn't be read, looked up as black
+    pub resident: usize, // Bytes of tile data in memory
+}
+
+#[derive(Debug)]
+struct ResidentTile {
+    texels: CompressedTile,
+    referenced: AtomicBool, // Looked up since the hand last passed
+}
+
+#[derive(Debug, Default)]
+struct ResidentTiles {
+    tiles: HashMap<TileKey, ResidentTile>,
+    ring: Vec<TileKey>, // Resident tiles in the order the hand visits them
+    hand: usize,
+    bytes: usize,
+}
+
+/// Texture storage that keeps only recently used tiles in memory.
+///
+/// Images are converted once into tiled files holding the whole mip chain,
+/// stored in a cache directory and reused by later runs. Tiles are then read
+/// on demand, evicting tiles that weren't used recently when the resident
+/// tiles exceed the memory budget: a clock hand sweeps the resident tiles,
+/// sparing the ones looked up since it last passed and evicting the first
+/// one that wasn't. Resident tiles can be compressed, see `TextureFormat`,
+/// so that more of them fit in the budget.
+///
+/// Lookups of resident tiles only share a read lock, and tiles are read from
+/// disk without holding any lock of the cache, so render threads don't wait
+/// on each other's misses.
+#[derive(Debug)]
+pub struct TextureCache {
+    budget: AtomicUsize, // Bytes
+    directory: PathBuf,
+    files: RwLock<Vec<Arc<TextureFile>>>,
+    resident: RwLock<ResidentTiles>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    failures: AtomicU64,
+    error: Mutex<Option<String>>, // First failure
+}
+
+impl Default for TextureCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET)
+    }
+}
+
+impl TextureCache {
+    /// Cache with a memory budget in bytes, storing tiled files in the
+    /// temporary directory
+    pub fn new(budget: usize) -> Self {
+        Self::with_directory(budget, std::env::temp_dir().join("light-textures"))
+    }
+
+    pub fn with_directory<P: AsRef<Path>>(budget: usize, directory: P) -> Self {
+        Self {
+            budget: AtomicUsize::new(budget),
+            directory: directory.as_ref().to_path_buf(),
+            files: RwLock::new(Vec::new()),
+            resident: RwLock::new(ResidentTiles::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Change the memory budget, e.g. to the share of a budget that is only
+    /// known once the scene is loaded. Resident tiles over it are evicted as
+    /// new ones are read.
+    pub fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, Ordering::Relaxed);
+    }
+
+    /// Most memory the resident tiles can take, give or take a tile: the
+    /// budget, or nothing if no texture was added
+    pub fn max_resident(&self) -> usize {
+        if self.files.read().unwrap().is_empty() {
+            0
+        } else {
+            self.budget.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Tiled file of an image. The name is a hash of the path, size and
+    /// modification time of the image, so edited images are converted again.
+    /// The hash doesn't change between builds, so that later runs find the
+    /// files.
+    fn tiled_path(&self, path: &Path) -> Result<PathBuf, String> {
+        let metadata = fs::metadata(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let canonical = fs::canonicalize(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let hash = [
+            canonical.as_os_str().as_encoded_bytes(),
+            &metadata.len().to_le_bytes(),
+            &modified.as_secs().to_le_bytes(),
+            &modified.subsec_nanos().to_le_bytes(),
+        ]
+        .iter()
+        .fold(FNV_OFFSET, |hash, bytes| fnv1a(hash, bytes));
+        Ok(self.directory.join(format!("{hash:016x}.ltx")))
+    }
+
+    /// Register an image file, converting it to a tiled file if needed. No
+    /// texels stay in memory.
+    pub fn add<P: AsRef<Path>>(&self, path: P) -> Result<TextureId, String> {
+        self.add_with_format(path, TextureFormat::default())
+    }
+
+    /// Register an image file whose resident tiles are kept in `format`
+    pub fn add_with_format<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: TextureFormat,
+    ) -> Result<TextureId, String> {
+        let path = path.as_ref();
+        let tiled = self.tiled_path(path)?;
+        if !tiled.exists() {
+            let image = image::open(path)
+                .map_err(|e| format!("Couldn't load {}: {e}", path.display()))?
+                .into_rgb8();
+            fs::create_dir_all(&self.directory).map_err(|e| e.to_string())?;
+
+            // Write to a temporary name first, a half written file must not
+            // be mistaken for a converted one
+            let partial = tiled.with_extension(format!("{}.part", std::process::id()));
+            let mut writer = BufWriter::new(File::create(&partial).map_err(|e| e.to_string())?);
+            write_tiled(image, &mut writer)?;
+            writer.flush().map_err(|e| e.to_string())?;
+            fs::rename(&partial, &tiled).map_err(|e| e.to_string())?;
+        }
+
+        let file = TextureFile::open(&tiled, path, format)?;
+        let mut files = self.files.write().unwrap();
+        files.push(Arc::new(file));
+        Ok(TextureId(files.len() - 1))
+    }
+
+    fn file(&self, id: TextureId) -> Arc<TextureFile> {
+        self.files.read().unwrap()[id.0].clone()
+    }
+
+    /// Resolution of a mip level, 0 being the full image
+    pub fn level_size(&self, id: TextureId, level: u32) -> (u32, u32) {
+        self.file(id).levels[level as usize]
+    }
+
+    /// Image file a texture was added from, and the storage of its tiles
+    pub fn source(&self, id: TextureId) -> (PathBuf, TextureFormat) {
+        let file = self.file(id);
+        (file.source.clone(), file.format)
+    }
+
+    pub fn num_levels(&self, id: TextureId) -> u32 {
+        self.file(id).levels.len() as u32
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            resident: self.resident.read().unwrap().bytes,
+        }
+    }
+
+    /// First tile that couldn't be read, e.g. because its tiled file was
+    /// removed or truncated during the render
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+
+    /// Texel of a mip level, loading its tile if it isn't resident. Texels
+    /// of tiles that can't be read are black, see `error`.
+    pub fn texel(&self, id: TextureId, level: u32, x: u32, y: u32) -> Color {
+        let key = TileKey {
+            texture: id.0,
+            level,
+            x: x / TILE_SIZE,
+            y: y / TILE_SIZE,
+        };
+        if let Some(tile) = self.resident.read().unwrap().tiles.get(&key) {
+            // Most lookups find the flag set, don't write to it again
+            if !tile.referenced.load(Ordering::Relaxed) {
+                tile.referenced.store(true, Ordering::Relaxed);
+            }
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return tile_texel(&tile.texels, x, y);
+        }
+
+        // Threads that miss the same tile at once all read it, the first one
+        // to finish keeps it
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let texels = match self.file(id).read_tile(level, key.x, key.y) {
+            Ok(texels) => texels,
+            // The tiled file was validated when added, a failure here
+            // means it was removed or truncated behind our back
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                self.error
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(|| format!("Couldn't read texture tile: {e}"));
+                return Color::zeros();
+            }
+        };
+        let texel = tile_texel(&texels, x, y);
+        self.insert(key, texels);
+        texel
+    }
+
+    /// Make a tile resident, evicting tiles with the clock hand to stay
+    /// within the budget. Each step of the hand is O(1), and it passes each
+    /// tile at most twice per eviction. The new tile is always kept, even if
+    /// the budget is below one tile.
+    fn insert(&self, key: TileKey, texels: CompressedTile) {
+        let mut resident = self.resident.write().unwrap();
+        if resident.tiles.contains_key(&key) {
+            return;
+        }
+        let size = texels.memory_size();
+        let budget = self.budget.load(Ordering::Relaxed);
+        while resident.bytes + size > budget && !resident.ring.is_empty() {
+            if resident.hand >= resident.ring.len() {
+                resident.hand = 0;
+            }
+            let hand = resident.hand;
+            let candidate = resident.ring[hand];
+            let tile = &resident.tiles[&candidate];
+            if tile.referenced.swap(false, Ordering::Relaxed) {
+                resident.hand += 1;
+                continue;
+            }
+
+            // The last tile of the ring takes the place of the evicted one
+            let evicted = resident.tiles.remove(&candidate).unwrap();
+            resident.ring.swap_remove(hand);
+            resident.bytes -= evicted.texels.memory_size();
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        resident.ring.push(key);
+        resident.tiles.insert(
+            key,
+            ResidentTile {
+                texels,
+                referenced: AtomicBool::new(false),
+            },
+        );
+        resident.bytes += size;
+    }
+}
+
+/// Offset basis of the 64-bit FNV-1a hash
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Add bytes to a 64-bit FNV-1a hash
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Texel (x, y) of a mip level, from the tile that holds it
+fn tile_texel(texels: &CompressedTile, x: u32, y: u32) -> Color {
+    let [r, g, b] = texels.texel(
+        (x % TILE_SIZE) as usize,
+        (y % TILE_SIZE) as usize,
+        TILE_SIZE as usize,
+    );
+    Color::new(r as f64, g as f64, b as f64)
+}
+
+/// Handle to a texture of a cache, usable as a material color
+#[derive(Clone)]
+pub struct Texture {
+    cache: Arc<TextureCache>,
+    id: TextureId,
+}
+
+impl fmt::Debug for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Texture").field(&self.id).finish()
+    }
+}
+
+impl Texture {
+    pub fn new(cache: Arc<TextureCache>, id: TextureId) -> Self {
+        Self { cache, id }
+    }
+
+    /// Image file of the texture and the storage of its tiles
+    pub fn source(&self) -> (PathBuf, TextureFormat) {
+        self.cache.source(self.id)
+    }
+
+    /// Mip level whose texels are as wide as `footprint`, in uv units, so
+    /// that a lookup covers about one texel. A point gives the full
+    /// resolution.
+    pub fn lod(&self, footprint: f64) -> f64 {
+        let (width, height) = self.cache.level_size(self.id, 0);
+        (footprint * width.max(height) as f64).log2().max(0.0)
+    }
+
+    /// Bilinear lookup at texture coordinates, which repeat outside [0, 1).
+    /// `lod` picks the mip level, 0 being the full resolution.
+    pub fn sample(&self, uv: glm::DVec2, lod: f64) -> Color {
+        let levels = &self.cache.file(self.id).levels;
+        let level = (lod.max(0.0).round() as usize).min(levels.len() - 1);
+        let (width, height) = levels[level];
+        let level = level as u32;
+
+        let x = uv.x.rem_euclid(1.0) * width as f64 - 0.5;
+        let y = uv.y.rem_euclid(1.0) * height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let wrap = |value: f64, size: u32| value.rem_euclid(size as f64) as u32;
+        let texel = |dx: f64, dy: f64| {
+            self.cache
+                .texel(self.id, level, wrap(x0 + dx, width), wrap(y0 + dy, height))
+        };
+
+        (1.0 - fy) * ((1.0 - fx) * texel(0.0, 0.0) + fx * texel(1.0, 0.0))
+            + fy * ((1.0 - fx) * texel(0.0, 1.0) + fx * texel(1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("light-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn mip_chain() {
+        assert_eq!(vec![(1, 1)], mip_levels(1, 1));
+        assert_eq!(vec![(5, 2), (2, 1), (1, 1)], mip_levels(5, 2));
+    }
+
+    #[test]
+    fn tiles_on_demand() {
+        let dir = test_dir("textures");
+        let path = dir.join("gradient.png");
+        let image = RgbImage::from_fn(150, 70, |x, y| image::Rgb([x as u8, y as u8, 7]));
+        image.save(&path).unwrap();
+
+        // Room for two tiles only
+        let cache = TextureCache::with_directory(2 * TILE_BYTES, dir.join("cache"));
+        let id = cache.add(&path).unwrap();
+        assert_eq!(CacheStats::default(), cache.stats());
+        assert_eq!(8, cache.num_levels(id));
+        assert_eq!((75, 35), cache.level_size(id, 1));
+
+        for (x, y) in [(3, 4), (149, 69), (70, 10), (64, 64)] {
+            assert_eq!(
+                Color::new(x as f64, y as f64, 7.0),
+                cache.texel(id, 0, x, y)
+            );
+        }
+        assert_eq!(
+            CacheStats {
+                hits: 0,
+                misses: 4,
+                evictions: 2,
+                failures: 0,
+                resident: 2 * TILE_BYTES,
+            },
+            cache.stats()
+        );
+        cache.texel(id, 0, 65, 65);
+        assert_eq!(1, cache.stats().hits);
+
+        // Box filtered mip level
+        assert_eq!(Color::new(1.0, 1.0, 7.0), cache.texel(id, 1, 0, 0));
+
+        // A second cache reuses the tiled file
+        let cache = Arc::new(TextureCache::with_directory(
+            DEFAULT_BUDGET,
+            dir.join("cache"),
+        ));
+        let texture = Texture::new(cache.clone(), cache.add(&path).unwrap());
+        let center = glm::DVec2::new(10.5 / 150.0, 20.5 / 70.0);
+        assert_relative_eq!(Color::new(10.0, 20.0, 7.0), texture.sample(center, 0.0));
+        let between = glm::DVec2::new(11.0 / 150.0, 20.5 / 70.0);
+        assert_relative_eq!(Color::new(10.5, 20.0, 7.0), texture.sample(between, 0.0));
+        assert_eq!(0.0, texture.lod(0.0));
+        assert_eq!(0.0, texture.lod(0.5 / 150.0));
+        assert_relative_eq!(2.0, texture.lod(4.0 / 150.0));
+        assert_eq!(1, fs::read_dir(dir.join("cache")).unwrap().count());
+
+        // Palette tiles take less than half the memory, with a small error
+        let cache = TextureCache::with_directory(DEFAULT_BUDGET, dir.join("cache"));
+        let id = cache
+            .add_with_format(&path, TextureFormat::Palette)
+            .unwrap();
+        assert_relative_eq!(
+            Color::new(100.0, 30.0, 7.0),
+            cache.texel(id, 0, 100, 30),
+            epsilon = 4.0
+        );
+        assert!(cache.stats().resident < TILE_BYTES / 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clock_spares_used_tiles() {
+        let dir = test_dir("clock-textures");
+        let path = dir.join("gradient.png");
+        let image = RgbImage::from_fn(256, 64, |x, y| image::Rgb([x as u8, y as u8, 7]));
+        image.save(&path).unwrap();
+
+        // Room for two of the four tiles, the first one is looked up again
+        let cache = TextureCache::with_directory(2 * TILE_BYTES, dir.join("cache"));
+        let id = cache.add(&path).unwrap();
+        cache.texel(id, 0, 0, 0);
+        cache.texel(id, 0, 64, 0);
+        cache.texel(id, 0, 1, 1);
+        cache.texel(id, 0, 128, 0);
+        cache.texel(id, 0, 2, 2);
+        assert_eq!(2, cache.stats().hits);
+
+        // The tile that wasn't used since the hand passed is evicted
+        cache.texel(id, 0, 192, 0);
+        cache.texel(id, 0, 3, 3);
+        assert_eq!(3, cache.stats().hits);
+        assert_eq!(2, cache.stats().evictions);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stable_tiled_paths() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a(FNV_OFFSET, b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a(FNV_OFFSET, b"a"));
+        assert_eq!(
+            fnv1a(FNV_OFFSET, b"ab"),
+            fnv1a(fnv1a(FNV_OFFSET, b"a"), b"b")
+        );
+    }
+
+    #[test]
+    fn concurrent_lookups() {
+        let dir = test_dir("concurrent-textures");
+        let path = dir.join("gradient.png");
+        let image = RgbImage::from_fn(256, 256, |x, y| image::Rgb([x as u8, y as u8, 7]));
+        image.save(&path).unwrap();
+
+        // Less room than tiles, so that threads evict each other's tiles
+        let cache = TextureCache::with_directory(3 * TILE_BYTES, dir.join("cache"));
+        let id = cache.add(&path).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for n in 0..2000_u32 {
+                        let (x, y) = ((n * 37 + thread * 11) % 256, (n * 53) % 256);
+                        let texel = cache.texel(id, 0, x, y);
+                        assert_eq!(Color::new(x as f64, y as f64, 7.0), texel);
+                    }
+                });
+            }
+        });
+
+        let stats = cache.stats();
+        assert_eq!(8000, stats.hits + stats.misses);
+        assert!(stats.resident <= 3 * TILE_BYTES);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_tiles_are_black() {
+        let dir = test_dir("unreadable-textures");
+        let path = dir.join("gradient.png");
+        let image = RgbImage::from_fn(150, 70, |x, y| image::Rgb([x as u8, y as u8, 7]));
+        image.save(&path).unwrap();
+
+        let cache = TextureCache::with_directory(DEFAULT_BUDGET, dir.join("cache"));
+        let id = cache.add(&path).unwrap();
+        let tiled = cache.tiled_path(&path).unwrap();
+        File::options()
+            .write(true)
+            .open(tiled)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+
+        assert_eq!(Color::zeros(), cache.texel(id, 0, 3, 4));
+        assert_eq!(Color::zeros(), cache.texel(id, 0, 3, 4));
+        assert_eq!(2, cache.stats().failures);
+        assert_eq!(0, cache.stats().resident);
+        assert!(cache.error().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/// Rectangular region of the image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    /// Pixel coordinates covered by the tile, in scanline order
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (self.y..self.y + self.height)
+            .flat_map(move |j| (self.x..self.x + self.width).map(move |i| (i, j)))
+    }
+
+    pub fn num_pixels(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+}
+
+/// Order in which tiles are scheduled for rendering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileOrder {
+    /// Rows from top to bottom, left to right
+    #[default]
+    Scanline,
+    /// Square spiral starting at the center of the image, so that the
+    /// subject is usually revealed first
+    Spiral,
+    /// Hilbert space-filling curve, which keeps consecutive tiles adjacent
+    Hilbert,
+}
+
+/// Split an image into tiles of at most `tile_size` pixels per side, sorted
+/// in the given order
+pub fn generate_tiles(width: u32, height: u32, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let tiles_x = width.div_ceil(tile_size);
+    let tiles_y = height.div_ceil(tile_size);
+
+    let tile_at = |tx: u32, ty: u32| {
+        let (x, y) = (tx * tile_size, ty * tile_size);
+        Tile {
+            x,
+            y,
+            width: tile_size.min(width - x),
+            height: tile_size.min(height - y),
+        }
+    };
+
+    let coordinates: Vec<(u32, u32)> = match order {
+        TileOrder::Scanline => (0..tiles_y)
+            .flat_map(|ty| (0..tiles_x).map(move |tx| (tx, ty)))
+            .collect(),
+        TileOrder::Spiral => spiral(tiles_x, tiles_y),
+        TileOrder::Hilbert => hilbert(tiles_x, tiles_y),
+    };
+
+    coordinates
+        .into_iter()
+        .map(|(tx, ty)| tile_at(tx, ty))
+        .collect()
+}
+
+/// Grid coordinates visited by a square spiral around the center cell
+fn spiral(nx: u32, ny: u32) -> Vec<(u32, u32)> {
+    const DIRECTIONS: [(i64, i64); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+    let total = (nx * ny) as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = (((nx as i64) - 1) / 2, ((ny as i64) - 1) / 2);
+    let mut direction = 0;
+    let mut run_length = 1;
+
+    let visit = |x: i64, y: i64, cells: &mut Vec<(u32, u32)>| {
+        if (0..nx as i64).contains(&x) && (0..ny as i64).contains(&y) {
+            cells.push((x as u32, y as u32));
+        }
+    };
+
+    visit(x, y, &mut cells);
+    while cells.len() < total {
+        // Each run length is used twice: right, down, then left, up, ...
+        for _ in 0..2 {
+            let (dx, dy) = DIRECTIONS[direction];
+            for _ in 0..run_length {
+                x += dx;
+                y += dy;
+                visit(x, y, &mut cells);
+            }
+            direction = (direction + 1) % 4;
+        }
+        run_length += 1;
+    }
+
+    cells
+}
+
+/// Grid coordinates along a Hilbert curve covering the grid. The curve is
+/// built on the enclosing power of two square and cells outside the grid
+/// are skipped.
+fn hilbert(nx: u32, ny: u32) -> Vec<(u32, u32)> {
+    let n = nx.max(ny).max(1).next_power_of_two();
+    (0..(n as u64) * (n as u64))
+        .map(|d| hilbert_d2xy(n, d))
+        .filter(|(x, y)| *x < nx && *y < ny)
+        .collect()
+}
+
+/// Convert a distance along the Hilbert curve of an n×n grid to (x, y)
+fn hilbert_d2xy(n: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0_u64, 0_u64);
+    let mut t = d;
+    let mut s = 1_u64;
+    while s < n as u64 {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x as u32, y as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_covers_image(tiles: &[Tile], width: u32, height: u32) {
+        let mut covered = vec![0; (width * height) as usize];
+        for tile in tiles {
+            for (i, j) in tile.pixels() {
+                covered[(j * width + i) as usize] += 1;
+            }
+        }
+        assert!(covered.iter().all(|count| *count == 1));
+    }
+
+    #[test]
+    fn all_orders_cover_the_image() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            for (width, height) in [(100, 60), (64, 64), (7, 33), (1, 1)] {
+                let tiles = generate_tiles(width, height, 16, order);
+                assert_covers_image(&tiles, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn scanline_order() {
+        let tiles = generate_tiles(40, 20, 16, TileOrder::Scanline);
+        let origins: Vec<(u32, u32)> = tiles.iter().map(|t| (t.x, t.y)).collect();
+        assert_eq!(
+            vec![(0, 0), (16, 0), (32, 0), (0, 16), (16, 16), (32, 16)],
+            origins
+        );
+        assert_eq!(8, tiles[2].width);
+        assert_eq!(4, tiles[5].height);
+    }
+
+    #[test]
+    fn spiral_starts_at_center() {
+        let tiles = generate_tiles(5 * 16, 5 * 16, 16, TileOrder::Spiral);
+        assert_eq!((32, 32), (tiles[0].x, tiles[0].y));
+        assert_eq!((48, 32), (tiles[1].x, tiles[1].y));
+        assert_eq!((48, 48), (tiles[2].x, tiles[2].y));
+
+        // The ring of 8 tiles around the center comes before the outer ring
+        for tile in &tiles[1..9] {
+            assert!((16..=48).contains(&tile.x) && (16..=48).contains(&tile.y));
+        }
+    }
+
+    #[test]
+    fn hilbert_tiles_are_adjacent() {
+        let tiles = generate_tiles(8 * 16, 8 * 16, 16, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let dx = (pair[0].x as i64 - pair[1].x as i64).abs();
+            let dy = (pair[0].y as i64 - pair[1].y as i64).abs();
+            assert_eq!(16, dx + dy);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Noise measurements: render a scene several times with independent random
+//! streams and estimate the per-pixel variance of the estimator. For an
+//! unbiased integrator the mean variance is the expected MSE of a render, so
+//! MSE against spp curves can be compared between samplers and integrators
+//! without a reference image.
+
+use std::io::Write;
+
+use image::{Rgb, RgbImage};
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::Film;
+use crate::render::PathTracer;
+use crate::scene::Scene;
+
+/// Running mean and variance of every pixel over a set of renders
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelStats {
+    width: u32,
+    height: u32,
+    runs: u32,
+    mean: Vec<Color>,
+    m2: Vec<Color>, // Sum of squared deviations from the mean (Welford)
+}
+
+impl PixelStats {
+    pub fn new(width: u32, height: u32) -> Self {
+        let len = (width * height) as usize;
+        Self {
+            width,
+            height,
+            runs: 0,
+            mean: vec![Color::zeros(); len],
+            m2: vec![Color::zeros(); len],
+        }
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn runs(&self) -> u32 {
+        self.runs
+    }
+
+    /// Add one render to the statistics
+    pub fn add(&mut self, film: &Film) {
+        assert_eq!(self.resolution(), film.resolution());
+        self.runs += 1;
+        let n = self.runs as f64;
+        for ((mean, m2), pixel) in self.mean.iter_mut().zip(&mut self.m2).zip(film.pixels()) {
+            let color = pixel.color();
+            let delta = color - *mean;
+            *mean += delta / n;
+            *m2 += delta.component_mul(&(color - *mean));
+        }
+    }
+
+    pub fn mean(&self, i: u32, j: u32) -> Color {
+        self.mean[(j * self.width + i) as usize]
+    }
+
+    /// Unbiased sample variance of a pixel, averaged over the color channels
+    pub fn variance(&self, i: u32, j: u32) -> f64 {
+        self.variance_at((j * self.width + i) as usize)
+    }
+
+    fn variance_at(&self, index: usize) -> f64 {
+        if self.runs < 2 {
+            return 0.0;
+        }
+        self.m2[index].mean() / (self.runs - 1) as f64
+    }
+
+    /// Mean of the per-pixel variances, i.e. the expected MSE of one render
+    pub fn mean_variance(&self) -> f64 {
+        let len = self.mean.len();
+        (0..len).map(|n| self.variance_at(n)).sum::<f64>() / len.max(1) as f64
+    }
+
+    /// Image of the per-pixel variance, from black (none) to white (the
+    /// highest variance of the image)
+    pub fn heatmap(&self) -> RgbImage {
+        let max = (0..self.mean.len())
+            .map(|n| self.variance_at(n))
+            .fold(0.0, f64::max);
+        RgbImage::from_fn(self.width, self.height, |i, j| {
+            let t = if max > 0.0 {
+                self.variance(i, j) / max
+            } else {
+                0.0
+            };
+            let value = (255.0 * t.sqrt()).round() as u8;
+            Rgb([value, value, value])
+        })
+    }
+
+    /// Write `i,j,r,g,b,variance` lines with the mean color and variance of
+    /// every pixel
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        writeln!(writer, "i,j,r,g,b,variance").map_err(|e| e.to_string())?;
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let mean = self.mean(i, j);
+                writeln!(
+                    writer,
+                    "{i},{j},{},{},{},{}",
+                    mean.x,
+                    mean.y,
+                    mean.z,
+                    self.variance(i, j)
+                )
+                .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Point of a convergence curve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvergencePoint {
+    pub spp: u32,
+    pub mse: f64,
+}
+
+/// Write a convergence curve as `spp,mse` lines
+pub fn write_convergence_csv<W: Write>(
+    points: &[ConvergencePoint],
+    writer: &mut W,
+) -> Result<(), String> {
+    writeln!(writer, "spp,mse").map_err(|e| e.to_string())?;
+    for point in points {
+        writeln!(writer, "{},{}", point.spp, point.mse).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Renders a scene `runs` times for every sample count in `spp` and measures
+/// the noise of the results
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseHarness {
+    pub runs: u32,
+    pub spp: Vec<u32>,
+}
+
+impl Default for NoiseHarness {
+    fn default() -> Self {
+        Self {
+            runs: 8,
+            spp: vec![1, 2, 4, 8, 16, 32, 64],
+        }
+    }
+}
+
+impl NoiseHarness {
+    pub fn new(runs: u32, spp: Vec<u32>) -> Self {
+        Self {
+            runs: runs.max(2),
+            spp,
+        }
+    }
+
+    /// Per-pixel statistics of `runs` renders made by `render`, which is
+    /// given the index of the run
+    pub fn measure<F>(&self, resolution: (u32, u32), mut render: F) -> PixelStats
+    where
+        F: FnMut(u32) -> Film,
+    {
+        let mut stats = PixelStats::new(resolution.0, resolution.1);
+        for run in 0..self.runs {
+            stats.add(&render(run));
+        }
+        stats
+    }
+
+    /// Convergence curve of any integrator. `render` is given the sample
+    /// count and the index of the run.
+    pub fn convergence_with<F>(
+        &self,
+        resolution: (u32, u32),
+        mut render: F,
+    ) -> Vec<ConvergencePoint>
+    where
+        F: FnMut(u32, u32) -> Film,
+    {
+        self.spp
+            .iter()
+            .map(|&spp| ConvergencePoint {
+                spp,
+                mse: self
+                    .measure(resolution, |run| render(spp, run))
+                    .mean_variance(),
+            })
+            .collect()
+    }
+
+    /// Convergence curve of a path tracer. Its sample count is restored
+    /// afterwards.
+    pub fn convergence(
+        &self,
+        renderer: &mut PathTracer,
+        scene: &Scene,
+        camera: &Camera,
+    ) -> Vec<ConvergencePoint> {
+        let spp = renderer.spp;
+        let points = self.convergence_with(camera.resolution(), |spp, _| {
+            renderer.samples_per_pixel(spp).render_film(scene, camera)
+        });
+        renderer.samples_per_pixel(spp);
+        points
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView, FocusMode};
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::shape::{Plane, Sphere};
+
+    fn camera(focus_mode: FocusMode) -> Camera {
+        Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 1.0, 0.0),
+            direction: glm::DVec3::z(),
+            resolution: (16, 16),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            focus_mode,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn welford_statistics() {
+        let mut stats = PixelStats::new(1, 1);
+        for value in [1.0, 2.0, 3.0, 6.0] {
+            let mut film = Film::new(1, 1);
+            film.add_sample(0, 0, Color::repeat(value), Default::default());
+            stats.add(&film);
+        }
+        assert_eq!(4, stats.runs());
+        assert_eq!(Color::repeat(3.0), stats.mean(0, 0));
+        assert_eq!(14.0 / 3.0, stats.variance(0, 0));
+
+        let mut csv = Vec::new();
+        stats.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            format!("i,j,r,g,b,variance\n0,0,3,3,3,{}\n", 14.0 / 3.0),
+            String::from_utf8(csv).unwrap()
+        );
+    }
+
+    #[test]
+    fn mse_decreases_with_spp() {
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(100.0, 100.0, 100.0);
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 1.0, 4.0), 1.0)),
+                material: Material {
+                    color: Color::new(255.0, 255.0, 255.0),
+                    emittance: 1.0,
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(0.5, 0.5, 0.5),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(3).max_depth(3);
+        let harness = NoiseHarness::new(6, vec![1, 16]);
+        // The noise comes from the lens sampling
+        let camera = camera(FocusMode::FocalPlane {
+            focal_distance: 10.0,
+            aperture: 0.5,
+        });
+        let points = harness.convergence(&mut renderer, &scene, &camera);
+        assert_eq!(3, renderer.spp);
+        assert_eq!(
+            vec![1, 16],
+            points.iter().map(|p| p.spp).collect::<Vec<_>>()
+        );
+        assert!(points[0].mse > 0.0);
+        assert!(points[1].mse < points[0].mse);
+
+        let mut csv = Vec::new();
+        write_convergence_csv(&points, &mut csv).unwrap();
+        assert_eq!(3, String::from_utf8(csv).unwrap().lines().count());
+
+        // A scene without randomness has no noise
+        let stats = harness.measure((16, 16), |_| renderer.render_film(&Scene::new(), &camera));
+        assert_eq!(0.0, stats.mean_variance());
+        assert_eq!(Rgb([0, 0, 0]), *stats.heatmap().get_pixel(3, 3));
+    }
+}
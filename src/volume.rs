@@ -0,0 +1,558 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Participating media: boxes filled with a scattering medium whose density
+//! may vary in space, like clouds or smoke.
+//!
+//! Free paths are sampled with delta tracking and transmittance is estimated
+//! with ratio tracking. Both use the maximum density of the medium as a
+//! majorant and are unbiased for any density field below it.
+//!
+//! Light scattered towards a ray can also be estimated at distances sampled
+//! equi-angularly around a light, which finds the bright regions of the
+//! medium next to small lights much more often than free paths do.
+
+use rand::Rng;
+
+use crate::color::{self, Color};
+use crate::light::Ray;
+use crate::sampling;
+use crate::shape::Bounds;
+use crate::spectrum;
+
+const BLACKBODY_TABLE_SIZE: usize = 256;
+const REFERENCE_TEMPERATURE: f64 = 1500.0; // Kelvin
+
+/// Non-negative quantity varying over the box of a volume, like its density
+/// or temperature. Points are given in local coordinates in [0, 1]³.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarField {
+    Constant(f64),
+    Noise(NoiseField),
+    Grid(VoxelGrid),
+}
+
+impl ScalarField {
+    pub fn value(&self, p: &glm::DVec3) -> f64 {
+        match self {
+            ScalarField::Constant(value) => *value,
+            ScalarField::Noise(noise) => noise.value(p),
+            ScalarField::Grid(grid) => grid.value(p),
+        }
+    }
+
+    /// Upper bound of the values
+    pub fn max_value(&self) -> f64 {
+        match self {
+            ScalarField::Constant(value) => *value,
+            ScalarField::Noise(noise) => noise.amplitude,
+            ScalarField::Grid(grid) => grid.max,
+        }
+    }
+}
+
+/// Fractal value noise, scaled to [0, amplitude]
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseField {
+    pub amplitude: f64,
+    pub frequency: f64, // Noise cells across the box
+    pub octaves: u32,
+    pub seed: u32,
+}
+
+impl NoiseField {
+    pub fn value(&self, p: &glm::DVec3) -> f64 {
+        let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, self.frequency);
+        for octave in 0..self.octaves.max(1) {
+            sum += amplitude * value_noise(&(frequency * p), self.seed.wrapping_add(octave));
+            total += amplitude;
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        self.amplitude * sum / total
+    }
+}
+
+/// Smoothly interpolated random values in [0, 1] at the integer lattice
+fn value_noise(p: &glm::DVec3, seed: u32) -> f64 {
+    let cell = p.map(f64::floor);
+    let t = (p - cell).map(|t| t * t * (3.0 - 2.0 * t));
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        lattice_value(
+            cell.x as i64 + dx,
+            cell.y as i64 + dy,
+            cell.z as i64 + dz,
+            seed,
+        )
+    };
+    let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), t.x);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), t.x);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), t.x);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), t.x);
+    lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+}
+
+fn lattice_value(x: i64, y: i64, z: i64, seed: u32) -> f64 {
+    let mut h = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (z as u64).wrapping_mul(0x1656_67B1_9E37_79F9)
+        ^ (seed as u64);
+    // Finalizer of SplitMix64
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^= h >> 31;
+    (h >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// Values sampled at the centers of the cells of a regular grid and
+/// interpolated trilinearly
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    values: Vec<f64>, // x varies fastest, then y, then z
+    max: f64,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], values: Vec<f64>) -> Result<Self, String> {
+        let cells: usize = resolution.iter().product();
+        if cells == 0 || values.len() != cells {
+            return Err(format!(
+                "A {}×{}×{} grid needs {cells} values, found {}",
+                resolution[0],
+                resolution[1],
+                resolution[2],
+                values.len()
+            ));
+        }
+        if values.iter().any(|value| value.is_nan() || *value < 0.0) {
+            return Err("Grid values must be positive".to_string());
+        }
+        let max = values.iter().copied().fold(0.0, f64::max);
+        Ok(Self {
+            resolution,
+            values,
+            max,
+        })
+    }
+
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    /// Values of the cells, x varying fastest, then y, then z
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    fn cell(&self, x: usize, y: usize, z: usize) -> f64 {
+        let [nx, ny, _] = self.resolution;
+        self.values[(z * ny + y) * nx + x]
+    }
+
+    pub fn value(&self, p: &glm::DVec3) -> f64 {
+        let mut index = [0; 3];
+        let mut next = [0; 3];
+        let mut t = [0.0; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let x = (p[axis] * n as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+            index[axis] = x as usize;
+            next[axis] = (index[axis] + 1).min(n - 1);
+            t[axis] = x - index[axis] as f64;
+        }
+
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let pick = |axis: usize| corner >> axis & 1 == 1;
+            let mut weight = 1.0;
+            let mut cell = [0; 3];
+            for axis in 0..3 {
+                let (c, w) = if pick(axis) {
+                    (next[axis], t[axis])
+                } else {
+                    (index[axis], 1.0 - t[axis])
+                };
+                cell[axis] = c;
+                weight *= w;
+            }
+            value += weight * self.cell(cell[0], cell[1], cell[2]);
+        }
+        value
+    }
+}
+
+/// Light emitted by a hot medium, like fire, following the blackbody color
+/// of its temperature
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlackbodyEmission {
+    pub temperature: ScalarField, // Kelvin
+    pub intensity: f64,           // Scale of the radiance, 1 gives luminance 1 at 1500 K
+    table: Vec<Color>,            // Radiance from 0 K to the maximum temperature
+}
+
+impl BlackbodyEmission {
+    pub fn new(temperature: ScalarField, intensity: f64) -> Self {
+        // Computing the spectrum for every collision would be too slow
+        let max = temperature.max_value();
+        let reference = spectrum::blackbody_rgb(REFERENCE_TEMPERATURE);
+        let scale = 255.0 * intensity / color::luminance(&reference);
+        let table = (0..BLACKBODY_TABLE_SIZE)
+            .map(|n| {
+                let t = n as f64 / (BLACKBODY_TABLE_SIZE - 1) as f64;
+                scale * spectrum::blackbody_rgb(t * max)
+            })
+            .collect();
+        Self {
+            temperature,
+            intensity,
+            table,
+        }
+    }
+
+    /// Emitted radiance at a point in the local coordinates of the volume
+    pub fn radiance(&self, p: &glm::DVec3) -> Color {
+        let max = self.temperature.max_value();
+        if max <= 0.0 {
+            return Color::zeros();
+        }
+        let x =
+            (self.temperature.value(p) / max).clamp(0.0, 1.0) * (BLACKBODY_TABLE_SIZE - 1) as f64;
+        let i = (x as usize).min(BLACKBODY_TABLE_SIZE - 2);
+        let t = x - i as f64;
+        (1.0 - t) * self.table[i] + t * self.table[i + 1]
+    }
+}
+
+/// Box filled with a scattering medium
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub bounds: Bounds,
+    pub density: ScalarField, // Extinction coefficient, per unit length
+    pub color: Color,         // Single scattering albedo, white doesn't absorb
+    pub anisotropy: f64,      // Henyey-Greenstein g, 0 is isotropic
+    pub emission: Option<BlackbodyEmission>,
+}
+
+/// Point where a ray collides with a medium
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collision {
+    pub distance: f64,
+    pub volume: usize, // Index of the volume in the scene
+}
+
+impl Volume {
+    pub fn new(bounds: Bounds, density: ScalarField) -> Self {
+        Self {
+            bounds,
+            density,
+            color: Color::new(255.0, 255.0, 255.0),
+            anisotropy: 0.0,
+            emission: None,
+        }
+    }
+
+    fn local(&self, point: &glm::DVec3) -> glm::DVec3 {
+        (point - self.bounds.min).component_div(&self.bounds.size())
+    }
+
+    /// Density at a point in world space
+    pub fn density_at(&self, point: &glm::DVec3) -> f64 {
+        self.density.value(&self.local(point))
+    }
+
+    /// Radiance collected at a collision. A medium emits as much as it
+    /// absorbs, so the emission is weighted by the absorbed fraction and a
+    /// white medium doesn't glow.
+    pub fn emitted(&self, point: &glm::DVec3) -> Color {
+        match &self.emission {
+            Some(emission) => (Color::new(1.0, 1.0, 1.0) - self.color / 255.0)
+                .component_mul(&emission.radiance(&self.local(point))),
+            None => Color::zeros(),
+        }
+    }
+
+    /// Delta tracking: distance to the first real collision along the ray,
+    /// if it happens before `max_distance`
+    pub fn sample_distance(&self, ray: &Ray, max_distance: f64, rng: &mut impl Rng) -> Option<f64> {
+        let majorant = self.density.max_value();
+        if majorant <= 0.0 {
+            return None;
+        }
+        let (near, far) = self.bounds.intersect(ray)?;
+        let far = far.min(max_distance);
+
+        let mut t = near;
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if t >= far {
+                return None;
+            }
+            if rng.gen::<f64>() * majorant < self.density_at(&ray.point_at(t)) {
+                return Some(t);
+            }
+        }
+    }
+
+    /// Ratio tracking: unbiased estimate of the fraction of light that goes
+    /// through the medium along the ray, up to `max_distance`
+    pub fn transmittance(&self, ray: &Ray, max_distance: f64, rng: &mut impl Rng) -> f64 {
+        let majorant = self.density.max_value();
+        let Some((near, far)) = self.bounds.intersect(ray).filter(|_| majorant > 0.0) else {
+            return 1.0;
+        };
+        let far = far.min(max_distance);
+
+        let (mut t, mut transmittance) = (near, 1.0);
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if t >= far {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density_at(&ray.point_at(t)) / majorant;
+        }
+    }
+
+    /// Fraction of the extinction at a point that is scattered, per unit
+    /// length. Zero outside the box.
+    pub fn scattering_at(&self, point: &glm::DVec3) -> Color {
+        if self.bounds.contains(point) {
+            self.density_at(point) * self.color / 255.0
+        } else {
+            Color::zeros()
+        }
+    }
+
+    /// Phase function: density of the light travelling along `direction`
+    /// that scatters towards `outgoing`, per unit solid angle
+    pub fn phase(&self, direction: &glm::DVec3, outgoing: &glm::DVec3) -> f64 {
+        sampling::henyey_greenstein_pdf(direction.dot(outgoing), self.anisotropy)
+    }
+
+    /// Direction after scattering light that travels along `direction`
+    pub fn sample_phase(&self, direction: &glm::DVec3, rng: &mut impl Rng) -> glm::DVec3 {
+        sampling::henyey_greenstein(direction, self.anisotropy, rng)
+    }
+}
+
+/// Closest collision of a ray with any of the volumes before `max_distance`.
+/// Overlapping media add up, as the closest of their independent collisions
+/// follows their summed density.
+pub fn sample_collision(
+    volumes: &[Volume],
+    ray: &Ray,
+    max_distance: f64,
+    rng: &mut impl Rng,
+) -> Option<Collision> {
+    let mut closest: Option<Collision> = None;
+    for (id, volume) in volumes.iter().enumerate() {
+        let limit = closest.map_or(max_distance, |c| c.distance);
+        if let Some(distance) = volume.sample_distance(ray, limit, rng) {
+            closest = Some(Collision {
+                distance,
+                volume: id,
+            });
+        }
+    }
+    closest
+}
+
+/// Estimated transmittance of all the volumes along a ray
+pub fn transmittance(volumes: &[Volume], ray: &Ray, max_distance: f64, rng: &mut impl Rng) -> f64 {
+    volumes
+        .iter()
+        .map(|volume| volume.transmittance(ray, max_distance, rng))
+        .product()
+}
+
+/// Part of a ray before `max_distance` that goes through any of the volumes,
+/// as the distances where it enters the first one and leaves the last one
+pub fn segment(volumes: &[Volume], ray: &Ray, max_distance: f64) -> Option<(f64, f64)> {
+    volumes
+        .iter()
+        .filter_map(|volume| volume.bounds.intersect(ray))
+        .map(|(near, far)| (near, far.min(max_distance)))
+        .filter(|(near, far)| near < far)
+        .reduce(|(a, b), (near, far)| (a.min(near), b.max(far)))
+}
+
+/// Equi-angular sampling: a distance in `[near, far]` along the ray, with a
+/// density proportional to the inverse squared distance to `point`, and its
+/// pdf. The samples are spread uniformly over the angle that the segment
+/// subtends from the point.
+pub fn equiangular_distance(
+    ray: &Ray,
+    point: &glm::DVec3,
+    near: f64,
+    far: f64,
+    u: f64,
+) -> (f64, f64) {
+    // Distance along the ray to the foot of the perpendicular from the point
+    let foot = (point - ray.origin).dot(&ray.direction);
+    let height = glm::distance(&ray.point_at(foot), point).max(1e-9);
+    let theta_near = ((near - foot) / height).atan();
+    let theta_far = ((far - foot) / height).atan();
+
+    let offset = height * (theta_near + u * (theta_far - theta_near)).tan();
+    let pdf = height / ((theta_far - theta_near) * (height * height + offset * offset));
+    ((foot + offset).clamp(near, far), pdf)
+}
+
+#[cfg(test)]
+mod test {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn unit_box() -> Bounds {
+        Bounds {
+            min: glm::DVec3::zeros(),
+            max: glm::DVec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn homogeneous_transmittance() {
+        // Beer-Lambert law through 1 unit of medium
+        let volume = Volume::new(unit_box(), ScalarField::Constant(1.5));
+        let ray = Ray::new(glm::DVec3::new(0.5, 0.5, -2.0), glm::DVec3::z());
+        let mut rng = rand::thread_rng();
+        let n = 40_000;
+
+        let escaped = (0..n)
+            .filter(|_| {
+                volume
+                    .sample_distance(&ray, f64::INFINITY, &mut rng)
+                    .is_none()
+            })
+            .count();
+        assert_relative_eq!((-1.5_f64).exp(), escaped as f64 / n as f64, epsilon = 0.01);
+
+        // A surface inside the medium stops the ray earlier
+        let ratio = (0..n)
+            .map(|_| volume.transmittance(&ray, 2.5, &mut rng))
+            .sum::<f64>()
+            / n as f64;
+        assert_relative_eq!((-0.75_f64).exp(), ratio, epsilon = 0.01);
+
+        // Rays that miss the box go through
+        let miss = Ray::new(glm::DVec3::new(2.0, 0.5, -2.0), glm::DVec3::z());
+        assert_eq!(None, volume.sample_distance(&miss, f64::INFINITY, &mut rng));
+        assert_eq!(1.0, volume.transmittance(&miss, f64::INFINITY, &mut rng));
+    }
+
+    #[test]
+    fn heterogeneous_transmittance() {
+        // Density growing linearly along the ray, from 0 to 2: the optical
+        // depth is 1
+        let grid = VoxelGrid::new([1, 1, 3], vec![0.0, 1.0, 2.0]).unwrap();
+        assert_relative_eq!(0.5, grid.value(&glm::DVec3::new(0.5, 0.5, 1.0 / 3.0)));
+        let volume = Volume::new(
+            Bounds {
+                min: glm::DVec3::new(0.0, 0.0, -1.0 / 6.0),
+                max: glm::DVec3::new(1.0, 1.0, 7.0 / 6.0),
+            },
+            ScalarField::Grid(grid),
+        );
+        let volumes = [volume];
+        let ray = Ray::new(glm::DVec3::new(0.5, 0.5, -1.0), glm::DVec3::z());
+        let mut rng = rand::thread_rng();
+        let n = 40_000;
+
+        let ratio = (0..n)
+            .map(|_| transmittance(&volumes, &ray, f64::INFINITY, &mut rng))
+            .sum::<f64>()
+            / n as f64;
+        let escaped = (0..n)
+            .filter(|_| sample_collision(&volumes, &ray, f64::INFINITY, &mut rng).is_none())
+            .count() as f64
+            / n as f64;
+        // The edges are clamped: 1/6 of a unit at density 0 and 2
+        let expected = (-(1.0 + 2.0 / 6.0_f64)).exp();
+        assert_relative_eq!(expected, ratio, epsilon = 0.01);
+        assert_relative_eq!(expected, escaped, epsilon = 0.01);
+    }
+
+    #[test]
+    fn noise_is_bounded() {
+        let noise = NoiseField {
+            amplitude: 3.0,
+            frequency: 4.0,
+            octaves: 4,
+            seed: 7,
+        };
+        let mut rng = rand::thread_rng();
+        let values: Vec<f64> = (0..1000)
+            .map(|_| noise.value(&glm::DVec3::new(rng.gen(), rng.gen(), rng.gen())))
+            .collect();
+        assert!(values.iter().all(|v| (0.0..=3.0).contains(v)));
+        // Not constant, and the same point always has the same density
+        assert!(values.iter().any(|v| (v - values[0]).abs() > 0.1));
+        let p = glm::DVec3::new(0.3, 0.6, 0.9);
+        assert_eq!(noise.value(&p), noise.value(&p));
+    }
+
+    #[test]
+    fn blackbody_emission() {
+        let emission = BlackbodyEmission::new(ScalarField::Constant(1500.0), 2.0);
+        let p = glm::DVec3::new(0.5, 0.5, 0.5);
+        let radiance = emission.radiance(&p);
+        assert_relative_eq!(2.0 * 255.0, color::luminance(&radiance), epsilon = 1e-6);
+        // Fire is red
+        assert!(radiance.x > radiance.y && radiance.y > radiance.z);
+
+        // Hotter is brighter and whiter
+        let grid = VoxelGrid::new([2, 1, 1], vec![1000.0, 3000.0]).unwrap();
+        let fire = BlackbodyEmission::new(ScalarField::Grid(grid), 1.0);
+        let cold = fire.radiance(&glm::DVec3::new(0.0, 0.5, 0.5));
+        let hot = fire.radiance(&glm::DVec3::new(1.0, 0.5, 0.5));
+        assert!(color::luminance(&hot) > 100.0 * color::luminance(&cold));
+        assert!(hot.z / hot.x > cold.z / cold.x);
+
+        // Only absorbing media glow
+        let mut volume = Volume::new(unit_box(), ScalarField::Constant(1.0));
+        volume.emission = Some(emission);
+        assert_eq!(Color::zeros(), volume.emitted(&p));
+        volume.color = Color::zeros();
+        assert_eq!(radiance, volume.emitted(&p));
+    }
+
+    #[test]
+    fn equiangular_sampling() {
+        let ray = Ray::new(glm::DVec3::zeros(), glm::DVec3::z());
+        let light = glm::DVec3::new(0.0, 1.0, 2.0);
+        let (near, far) = (1.0, 5.0);
+        let mut rng = rand::thread_rng();
+        let n = 40_000;
+        let mut length = 0.0;
+        for _ in 0..n {
+            let (t, pdf) = equiangular_distance(&ray, &light, near, far, rng.gen());
+            assert!((near..=far).contains(&t));
+            length += 1.0 / pdf;
+
+            // The inverse squared distance to the light is estimated exactly
+            let irradiance = 1.0 / glm::distance2(&ray.point_at(t), &light);
+            let angle = 1.0_f64.atan() + 3.0_f64.atan();
+            assert_relative_eq!(angle, irradiance / pdf, epsilon = 1e-9);
+        }
+        assert_relative_eq!(far - near, length / n as f64, epsilon = 0.05);
+    }
+}
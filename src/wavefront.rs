@@ -0,0 +1,260 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Wavefront render loop: instead of tracing one path at a time, all the
+//! paths of a tile are kept in a queue and advanced together, one bounce per
+//! iteration. Each iteration runs separate phases over the whole queue:
+//!
+//! 1. Intersect the rays with the scene
+//! 2. Shade the hits, accumulating emission and scattering new rays
+//! 3. Retire the finished paths and compact the queue
+//!
+//! Each phase runs the same code over many rays, which keeps caches warm and
+//! maps directly onto GPU kernels.
+
+use bumpalo::Bump;
+use rayon::prelude::*;
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::film::Pixel;
+use crate::light::Ray;
+use crate::material::ShadingContext;
+use crate::render::{self, get_closest_hit, PathState, PathTracer};
+use crate::sampler::PixelSampler;
+use crate::scene::Scene;
+use crate::shape::HitRecord;
+use crate::tile::Tile;
+use crate::volume;
+
+/// A path in flight
+struct PathItem {
+    pixel: usize, // Index of the pixel in the tile
+    ray: Ray,
+    throughput: Color,
+    radiance: Color,
+    state: PathState,
+    done: bool,
+    rng: PixelSampler, // Random numbers of the sample, see `PathTracer::start_sample`
+}
+
+/// Generate phase: one path per sample of each pixel of the tile
+fn generate(
+    renderer: &PathTracer,
+    camera: &Camera,
+    tile: &Tile,
+    sample_counts: Option<&[u32]>,
+    seed: u64,
+) -> Vec<PathItem> {
+    let (w, _) = camera.resolution();
+    let mut queue = Vec::new();
+    for (pixel, (i, j)) in tile.pixels().enumerate() {
+        let spp = match sample_counts {
+            Some(counts) => counts[(j * w + i) as usize],
+            None => renderer.spp,
+        };
+        for sample in 0..spp {
+            let (mut rng, offset) = renderer.start_sample((i, j), sample, seed);
+            let ray = camera
+                .cast_ray_jittered(i, j, offset, &mut rng)
+                .expect("Expected a Ray");
+            queue.push(PathItem {
+                pixel,
+                ray,
+                throughput: Color::new(1.0, 1.0, 1.0),
+                radiance: Color::zeros(),
+                state: PathState::default(),
+                done: false,
+                rng,
+            });
+        }
+    }
+    queue
+}
+
+/// Shade phase for one path, given the result of the intersect phase
+fn shade(
+    renderer: &PathTracer,
+    scene: &Scene,
+    path: &mut PathItem,
+    hit: Option<(HitRecord, usize)>,
+    scratch: &Bump,
+) {
+    let rng = &mut path.rng;
+    let distance = hit
+        .as_ref()
+        .map_or(f64::INFINITY, |(record, _)| record.ray_t);
+    let inscattered = renderer.inscattered_light(scene, &path.ray, distance, path.state, rng);
+    path.radiance += renderer.contribution(&path.throughput, &inscattered, path.state.depth() + 1);
+    if let Some(collision) = volume::sample_collision(&scene.volumes, &path.ray, distance, rng) {
+        let volume = &scene.volumes[collision.volume];
+        let emitted = volume.emitted(&path.ray.point_at(collision.distance));
+        path.radiance += renderer.contribution(&path.throughput, &emitted, path.state.depth());
+        match renderer.scatter_in_volume(&path.ray, volume, collision.distance, path.state, rng) {
+            Some(bounce) => {
+                path.throughput = path.throughput.component_mul(&bounce.weight);
+                path.ray = bounce.ray;
+                path.state = bounce.state;
+            }
+            None => path.done = true,
+        }
+        return;
+    }
+
+    let Some((record, id)) = hit else {
+        let background = scene.background(&path.ray.direction);
+        path.radiance += renderer.contribution(&path.throughput, &background, path.state.depth());
+        path.done = true;
+        return;
+    };
+
+    path.throughput = path
+        .throughput
+        .component_mul(&path.state.transmittance(record.ray_t));
+    if let Some(atmosphere) = &scene.atmosphere {
+        let air = atmosphere.scattering(&path.ray.origin, &path.ray.direction, record.ray_t);
+        path.radiance +=
+            renderer.contribution(&path.throughput, &air.inscattered, path.state.depth() + 1);
+        path.throughput = path.throughput.component_mul(&air.transmittance);
+    }
+
+    let material = scene.objects[id].material_at(record.material);
+    let mut ctx = ShadingContext::new(&record, id)
+        .viewed_along(&path.ray.direction)
+        .with_footprint(path.ray.footprint_at(record.ray_t) * record.uv_density);
+    if let Some(bevel) = &material.bevel {
+        ctx.normal = bevel.normal(&scene.objects, &ctx, scratch, rng);
+    }
+    let emitted = renderer.emitted(scene, material, &ctx, path.state)
+        * render::emission_weight(scene, &path.ray, &record, id, path.state);
+    path.radiance += renderer.contribution(&path.throughput, &emitted, path.state.depth());
+    if let Some(light) = renderer.sample_light(scene, material, &ctx, path.state, rng) {
+        let direct = light.reflected(&path.ray, material, &ctx, None);
+        path.radiance += renderer.contribution(&path.throughput, &direct, path.state.depth() + 1);
+    }
+
+    match renderer.scatter(&path.ray, material, &ctx, path.state, None, rng) {
+        Some(bounce) => {
+            path.throughput = path.throughput.component_mul(&bounce.weight);
+            path.ray = bounce.ray;
+            path.state = bounce.state;
+        }
+        None => path.done = true,
+    }
+}
+
+/// Render the pixels of a tile, in the order of `Tile::pixels`
+pub(crate) fn render_tile(
+    renderer: &PathTracer,
+    scene: &Scene,
+    camera: &Camera,
+    tile: &Tile,
+    sample_counts: Option<&[u32]>,
+    seed: u64,
+) -> Vec<Pixel> {
+    let mut pixels = vec![Pixel::default(); tile.num_pixels()];
+    let mut queue = generate(renderer, camera, tile, sample_counts, seed);
+
+    while !queue.is_empty() {
+        let hits: Vec<Option<(HitRecord, usize)>> = queue
+            .par_iter()
+            .map(|path| {
+                get_closest_hit(&scene.objects, &path.ray).map(|(record, _, id)| (record, id))
+            })
+            .collect();
+
+        queue
+            .par_iter_mut()
+            .zip(hits)
+            .for_each_init(Bump::new, |scratch, (path, hit)| {
+                shade(renderer, scene, path, hit, scratch);
+                scratch.reset();
+            });
+
+        queue.retain(|path| {
+            if path.done {
+                pixels[path.pixel].add_sample(path.radiance, renderer.non_finite_policy);
+            }
+            !path.done
+        });
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::camera::{CameraConfig, FieldOfView, FocusMode};
+    use crate::material::{Material, Specular};
+    use crate::object::Object;
+    use crate::render::RenderLoop;
+    use crate::shape::{Plane, Sphere};
+
+    #[test]
+    fn matches_megakernel() {
+        // Every bounce is deterministic in this scene, so both loops must
+        // produce the same image
+        let mut scene = Scene::new();
+        scene.background_color = Color::new(1.0, 2.0, 3.0);
+        scene
+            .add_object(Object {
+                shape: Box::new(Sphere::new(glm::DVec3::new(0.0, 1.0, 5.0), 1.0)),
+                material: Material {
+                    color: Color::new(255.0, 128.0, 0.0),
+                    bsdf: Some(Arc::new(Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            })
+            .add_object(Object {
+                shape: Box::new(Plane {
+                    position: glm::DVec3::zeros(),
+                    normal: glm::DVec3::y(),
+                }),
+                material: Material {
+                    color: Color::new(0.5, 0.5, 0.5),
+                    emittance: 2.0,
+                    bsdf: Some(Arc::new(Specular::Mirror)),
+                    ..Default::default()
+                },
+                materials: Vec::new(),
+            });
+        let camera = Camera::new(&CameraConfig {
+            position: glm::DVec3::new(0.0, 1.0, 0.0),
+            direction: glm::DVec3::z(),
+            resolution: (40, 30),
+            fov: FieldOfView::Horizontal(90.0_f64.to_radians()),
+            focus_mode: FocusMode::PinHole,
+            ..Default::default()
+        });
+
+        let mut renderer = PathTracer::new();
+        renderer.samples_per_pixel(2).max_depth(3).seed(Some(1));
+        let megakernel = renderer.render_film(&scene, &camera);
+        renderer.render_loop(RenderLoop::Wavefront);
+        let wavefront = renderer.render_film(&scene, &camera);
+
+        for (a, b) in megakernel.pixels().zip(wavefront.pixels()) {
+            assert_eq!(a.color(), b.color());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * light is a path tracer written in Rust for educational purposes
+ *
+ * Copyright (C) 2024  Javier Lancha Vázquez
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! X-ray views: the number of surfaces each camera ray goes through, with no
+//! materials or lights. Parts where surfaces pile up show bright, which
+//! reveals the inside of models. A ray that starts outside of every object
+//! crosses closed surfaces an even number of times, so pixels with odd
+//! counts point at holes in meshes that should be watertight.
+
+use image::{Rgb, RgbImage};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::camera::Camera;
+use crate::light::Ray;
+use crate::object::Object;
+use crate::render::{get_closest_hit, SURFACE_OFFSET};
+use crate::scene::Scene;
+
+/// Largest number of surfaces counted along a ray
+pub const MAX_CROSSINGS: u32 = 1024;
+
+/// Number of surfaces a ray goes through, up to `MAX_CROSSINGS`
+pub fn count_crossings(objects: &[Object], ray: &Ray) -> u32 {
+    let mut probe = Ray {
+        origin: ray.origin,
+        direction: ray.direction,
+        time: ray.time,
+        footprint: ray.footprint,
+        spread: ray.spread,
+    };
+    let mut crossings = 0;
+    while crossings < MAX_CROSSINGS {
+        let Some((record, ..)) = get_closest_hit(objects, &probe) else {
+            break;
+        };
+        crossings += 1;
+        probe.origin = probe.point_at(record.ray_t + SURFACE_OFFSET);
+    }
+    crossings
+}
+
+/// Surfaces crossed by the ray through the center of each pixel
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossingMap {
+    width: u32,
+    height: u32,
+    counts: Vec<u32>, // Scanline order
+}
+
+impl CrossingMap {
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn count(&self, i: u32, j: u32) -> u32 {
+        self.counts[(j * self.width + i) as usize]
+    }
+
+    /// Pixels whose ray crosses an odd number of surfaces
+    pub fn odd_pixels(&self) -> usize {
+        self.counts.iter().filter(|&&count| count % 2 == 1).count()
+    }
+
+    /// Image of the crossings, as if each surface absorbed a fraction
+    /// `density` of the rays: pixels with no surface are black and get
+    /// brighter the more surfaces they cross. Pixels with odd counts are
+    /// shown in red if `highlight_odd` is set.
+    pub fn to_image(&self, density: f64, highlight_odd: bool) -> RgbImage {
+        let density = density.clamp(0.0, 1.0);
+        RgbImage::from_fn(self.width, self.height, |i, j| {
+            let count = self.count(i, j);
+            let value = (255.0 * (1.0 - (1.0 - density).powi(count as i32))).round() as u8;
+            if highlight_odd && count % 2 == 1 {
+                Rgb([255, value / 2, value / 2])
+            } else {
+                Rgb([value; 3])
+            }
+        })
+    }
+}
+
+/// Count the surfaces crossed through each pixel of the camera
+pub fn render_xray(scene: &Scene, camera: &Camera) -> CrossingMap {
+    let (w, h) = camera.resolution();
+    let counts = (0..w * h)
+        .into_par_iter()
+        .map(|index| {
+            let ray = camera
+                .center_ray(index % w, index / w)
+                .expect("Expected a Ray");
+            count_crossings(&scene.objects, &ray)
+        })
+        .collect();
+
+    CrossingMap {
+        width: w,
+        height: h,
+        counts,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::camera::{CameraConfig, FieldOfView};
+    use crate::material::Material;
+    use crate::shape::{Sphere, Triangle};
+
+    use super::*;
+
+    fn object(shape: Box<dyn crate::shape::Shape + Send + Sync>) -> Object {
+        Object {
+            shape,
+            material: Material::default(),
+            materials: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn crossings_of_nested_surfaces() {
+        let mut scene = Scene::new();
+        scene
+            .add_object(object(Box::new(Sphere::new(glm::DVec3::zeros(), 2.0))))
+            .add_object(object(Box::new(Sphere::new(glm::DVec3::zeros(), 1.0))));
+        let ray = |y| Ray::new(glm::DVec3::new(0.0, y, -5.0), glm::DVec3::z());
+        assert_eq!(4, count_crossings(&scene.objects, &ray(0.0)));
+        assert_eq!(2, count_crossings(&scene.objects, &ray(1.5)));
+        assert_eq!(0, count_crossings(&scene.objects, &ray(3.0)));
+    }
+
+    #[test]
+    fn open_surfaces_have_odd_counts() {
+        let mut scene = Scene::new();
+        scene
+            .add_object(object(Box::new(Sphere::new(
+                glm::DVec3::new(0.0, 0.0, 10.0),
+                2.0,
+            ))))
+            .add_object(object(Box::new(Triangle::new(
+                glm::DVec3::new(-1.0, -1.0, 5.0),
+                glm::DVec3::new(1.0, -1.0, 5.0),
+                glm::DVec3::new(0.0, 1.0, 5.0),
+            ))));
+        let camera = Camera::new(&CameraConfig {
+            direction: glm::DVec3::z(),
+            resolution: (9, 9),
+            fov: FieldOfView::Horizontal(60.0_f64.to_radians()),
+            ..Default::default()
+        });
+
+        let map = render_xray(&scene, &camera);
+        assert_eq!(3, map.count(4, 4));
+        assert_eq!(0, map.count(0, 0));
+        assert!(map.odd_pixels() > 0);
+
+        let image = map.to_image(0.5, true);
+        assert_eq!(Rgb([0, 0, 0]), *image.get_pixel(0, 0));
+        assert_eq!(Rgb([255, 111, 111]), *image.get_pixel(4, 4));
+        assert_eq!(
+            Rgb([223, 223, 223]),
+            *map.to_image(0.5, false).get_pixel(4, 4)
+        );
+    }
+}